@@ -1,3 +1,6 @@
 fn main() {
-    embuild::espidf::sysenv::output();
+    // Host builds, such as the simulator, have no esp-idf to link against
+    if std::env::var("CARGO_FEATURE_ESP").is_ok() {
+        embuild::espidf::sysenv::output();
+    }
 }
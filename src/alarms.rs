@@ -0,0 +1,531 @@
+//! Alarms on the weight, e.g. a water tank running low. An alarm trips on a
+//! stable weight past its threshold and stays latched, signalled and shown
+//! until it is acknowledged, whether or not the weight comes back. Once
+//! acknowledged it only trips again after the weight went back past the
+//! threshold by the hysteresis. The states are kept across restarts, so an
+//! alarm acknowledged or latched before a restart does not fire right away.
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::storage::{Storage, StorageService, StoreError};
+
+/// Number of alarms that can be configured
+pub const MAX_ALARMS: usize = 4;
+/// Hysteresis of an alarm set up without one
+pub const DEFAULT_HYSTERESIS_GRAMS: f32 = 10.0;
+
+pub const ALARMS_NAMESPACE: &str = "alarms";
+const STATES_KEY: &str = "states";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlarmKind {
+    Above,
+    Below,
+}
+
+impl AlarmKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AlarmKind::Above => "above",
+            AlarmKind::Below => "below",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "above" => Some(AlarmKind::Above),
+            "below" => Some(AlarmKind::Below),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AlarmConfig {
+    pub kind: AlarmKind,
+    pub grams: f32,
+    /// Distance the weight has to go back past the threshold to re-arm
+    pub hysteresis_grams: f32,
+}
+
+impl AlarmConfig {
+    /// Whether the weight trips the alarm
+    pub fn holds(&self, grams: f32) -> bool {
+        match self.kind {
+            AlarmKind::Above => grams > self.grams,
+            AlarmKind::Below => grams < self.grams,
+        }
+    }
+
+    /// Whether the weight went back far enough to re-arm the alarm
+    pub fn clears(&self, grams: f32) -> bool {
+        match self.kind {
+            AlarmKind::Above => grams <= self.grams - self.hysteresis_grams,
+            AlarmKind::Below => grams >= self.grams + self.hysteresis_grams,
+        }
+    }
+
+    /// E.g. `below 500g`
+    pub fn describe(&self) -> String {
+        format!("{} {}g", self.kind.name(), self.grams)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlarmState {
+    #[default]
+    Armed,
+    /// Tripped and waiting to be acknowledged
+    Latched,
+    /// Acknowledged while the weight was still past the threshold, waiting
+    /// for it to clear
+    Acknowledged,
+}
+
+impl AlarmState {
+    pub fn name(self) -> &'static str {
+        match self {
+            AlarmState::Armed => "armed",
+            AlarmState::Latched => "latched",
+            AlarmState::Acknowledged => "acknowledged",
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            AlarmState::Armed => 0,
+            AlarmState::Latched => 1,
+            AlarmState::Acknowledged => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => AlarmState::Latched,
+            2 => AlarmState::Acknowledged,
+            _ => AlarmState::Armed,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlarmEvent {
+    /// The stable weight went past the threshold
+    Tripped {
+        slot: usize,
+        config: AlarmConfig,
+        grams: f32,
+    },
+    /// Still latched after the re-notify interval
+    Renotify {
+        slot: usize,
+        config: AlarmConfig,
+    },
+    Acknowledged {
+        slot: usize,
+        config: AlarmConfig,
+    },
+    /// The weight cleared the threshold after an acknowledgement
+    Rearmed {
+        slot: usize,
+        config: AlarmConfig,
+    },
+}
+
+impl AlarmEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlarmEvent::Tripped { .. } => "tripped",
+            AlarmEvent::Renotify { .. } => "renotify",
+            AlarmEvent::Acknowledged { .. } => "acknowledged",
+            AlarmEvent::Rearmed { .. } => "rearmed",
+        }
+    }
+
+    pub fn slot(&self) -> usize {
+        match *self {
+            AlarmEvent::Tripped { slot, .. }
+            | AlarmEvent::Renotify { slot, .. }
+            | AlarmEvent::Acknowledged { slot, .. }
+            | AlarmEvent::Rearmed { slot, .. } => slot,
+        }
+    }
+
+    pub fn config(&self) -> AlarmConfig {
+        match *self {
+            AlarmEvent::Tripped { config, .. }
+            | AlarmEvent::Renotify { config, .. }
+            | AlarmEvent::Acknowledged { config, .. }
+            | AlarmEvent::Rearmed { config, .. } => config,
+        }
+    }
+
+    /// Whether the states changed, and are worth persisting
+    pub fn changes_state(&self) -> bool {
+        !matches!(self, AlarmEvent::Renotify { .. })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Alarm {
+    config: AlarmConfig,
+    state: AlarmState,
+    /// Time the latched alarm was last signalled
+    notified: Instant,
+}
+
+/// The configured alarms, evaluated against the stable weight
+pub struct Alarms {
+    slots: [Option<Alarm>; MAX_ALARMS],
+    renotify: Option<Duration>,
+}
+
+impl Alarms {
+    /// The configured alarms in the states they were left in. A latched
+    /// alarm counts as just signalled, it is signalled again only after the
+    /// re-notify interval.
+    pub fn new(
+        configs: &[Option<AlarmConfig>; MAX_ALARMS],
+        states: &[AlarmState; MAX_ALARMS],
+        renotify: Option<Duration>,
+    ) -> Self {
+        let now = Instant::now();
+        let mut slots = [None; MAX_ALARMS];
+        for (slot, (config, state)) in slots.iter_mut().zip(configs.iter().zip(states)) {
+            *slot = config.map(|config| Alarm {
+                config,
+                state: *state,
+                notified: now,
+            });
+        }
+        Self { slots, renotify }
+    }
+
+    /// Take over changed settings, an alarm whose configuration changed is
+    /// armed again
+    pub fn configure(
+        &mut self,
+        configs: &[Option<AlarmConfig>; MAX_ALARMS],
+        renotify: Option<Duration>,
+    ) {
+        let mut states = self.states();
+        for ((state, config), alarm) in states.iter_mut().zip(configs).zip(&self.slots) {
+            if alarm.map(|alarm| alarm.config) != *config {
+                *state = AlarmState::Armed;
+            }
+        }
+        *self = Self::new(configs, &states, renotify);
+    }
+
+    /// Follow a stable weight
+    pub fn on_stable(&mut self, grams: f32, now: Instant) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for (slot, alarm) in self.configured() {
+            let config = alarm.config;
+            match alarm.state {
+                AlarmState::Armed if config.holds(grams) => {
+                    alarm.state = AlarmState::Latched;
+                    alarm.notified = now;
+                    events.push(AlarmEvent::Tripped {
+                        slot,
+                        config,
+                        grams,
+                    });
+                }
+                AlarmState::Acknowledged if config.clears(grams) => {
+                    alarm.state = AlarmState::Armed;
+                    events.push(AlarmEvent::Rearmed { slot, config });
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// Signal the latched alarms again once the re-notify interval elapsed
+    pub fn poll(&mut self, now: Instant) -> Vec<AlarmEvent> {
+        let Some(renotify) = self.renotify else {
+            return Vec::new();
+        };
+        let mut events = Vec::new();
+        for (slot, alarm) in self.configured() {
+            if alarm.state == AlarmState::Latched && now.duration_since(alarm.notified) >= renotify
+            {
+                alarm.notified = now;
+                events.push(AlarmEvent::Renotify {
+                    slot,
+                    config: alarm.config,
+                });
+            }
+        }
+        events
+    }
+
+    /// Acknowledge the latched alarms. One the weight, if known, has not
+    /// cleared yet waits for it to before it can trip again.
+    pub fn acknowledge(&mut self, grams: Option<f32>) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for (slot, alarm) in self.configured() {
+            if alarm.state != AlarmState::Latched {
+                continue;
+            }
+            alarm.state = match grams {
+                Some(grams) if !alarm.config.clears(grams) => AlarmState::Acknowledged,
+                _ => AlarmState::Armed,
+            };
+            events.push(AlarmEvent::Acknowledged {
+                slot,
+                config: alarm.config,
+            });
+        }
+        events
+    }
+
+    pub fn is_latched(&self) -> bool {
+        self.latched().next().is_some()
+    }
+
+    /// Slots and configurations of the latched alarms
+    pub fn latched(&self) -> impl Iterator<Item = (usize, AlarmConfig)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, alarm)| match alarm {
+                Some(alarm) if alarm.state == AlarmState::Latched => Some((slot, alarm.config)),
+                _ => None,
+            })
+    }
+
+    /// Slots, configurations and states of the configured alarms
+    pub fn list(&self) -> impl Iterator<Item = (usize, AlarmConfig, AlarmState)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, alarm)| alarm.map(|alarm| (slot, alarm.config, alarm.state)))
+    }
+
+    pub fn states(&self) -> [AlarmState; MAX_ALARMS] {
+        self.slots
+            .map(|alarm| alarm.map_or(AlarmState::Armed, |alarm| alarm.state))
+    }
+
+    fn configured(&mut self) -> impl Iterator<Item = (usize, &mut Alarm)> {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(slot, alarm)| alarm.as_mut().map(|alarm| (slot, alarm)))
+    }
+}
+
+/// The alarm states in NVS, written whenever they change
+#[derive(Clone)]
+pub struct AlarmStore {
+    storage: Storage,
+}
+
+impl AlarmStore {
+    pub fn open(storage: &StorageService) -> Result<Self, StoreError> {
+        Ok(Self {
+            storage: storage.open(ALARMS_NAMESPACE)?,
+        })
+    }
+
+    /// The states left at the last change, all armed when there are none
+    pub fn states(&self) -> [AlarmState; MAX_ALARMS] {
+        let mut states = [AlarmState::Armed; MAX_ALARMS];
+        if let Some(bytes) = self.storage.get_checked_blob(STATES_KEY) {
+            for (state, byte) in states.iter_mut().zip(bytes) {
+                *state = AlarmState::from_byte(byte);
+            }
+        }
+        states
+    }
+
+    pub fn save(&self, states: &[AlarmState; MAX_ALARMS]) {
+        let bytes = states.map(AlarmState::to_byte);
+        if let Err(err) = self.storage.put_checked_blob(STATES_KEY, &bytes) {
+            warn!("Failed to save the alarm states: {:?}", err);
+        }
+    }
+}
+
+/// Migration putting the states stored before the checksums into their
+/// envelope
+pub fn seal_blobs(storage: &mut Storage) -> Result<(), StoreError> {
+    storage.seal_blob(STATES_KEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RENOTIFY: Duration = Duration::from_secs(60);
+    const LOW: AlarmConfig = AlarmConfig {
+        kind: AlarmKind::Below,
+        grams: 500.0,
+        hysteresis_grams: 10.0,
+    };
+    const HIGH: AlarmConfig = AlarmConfig {
+        kind: AlarmKind::Above,
+        grams: 2000.0,
+        hysteresis_grams: 50.0,
+    };
+
+    fn alarms(states: &[AlarmState; MAX_ALARMS]) -> Alarms {
+        Alarms::new(&[Some(LOW), None, Some(HIGH), None], states, Some(RENOTIFY))
+    }
+
+    /// Names and slots of the events
+    fn names(events: &[AlarmEvent]) -> Vec<(&'static str, usize)> {
+        events
+            .iter()
+            .map(|event| (event.name(), event.slot()))
+            .collect()
+    }
+
+    #[test]
+    fn trips_on_the_threshold_and_stays_latched() {
+        let mut alarms = alarms(&[AlarmState::Armed; MAX_ALARMS]);
+        let now = Instant::now();
+        assert_eq!(names(&alarms.on_stable(500.0, now)), []);
+        assert_eq!(
+            alarms.on_stable(499.0, now),
+            [AlarmEvent::Tripped {
+                slot: 0,
+                config: LOW,
+                grams: 499.0
+            }]
+        );
+        assert_eq!(names(&alarms.on_stable(300.0, now)), []);
+        // The weight coming back does not release it
+        assert_eq!(names(&alarms.on_stable(1000.0, now)), []);
+        assert_eq!(alarms.latched().collect::<Vec<_>>(), [(0, LOW)]);
+        assert_eq!(names(&alarms.on_stable(2001.0, now)), [("tripped", 2)]);
+        assert_eq!(
+            alarms.states(),
+            [
+                AlarmState::Latched,
+                AlarmState::Armed,
+                AlarmState::Latched,
+                AlarmState::Armed
+            ]
+        );
+    }
+
+    #[test]
+    fn renotifies_while_latched() {
+        let mut alarms = alarms(&[AlarmState::Armed; MAX_ALARMS]);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(names(&alarms.poll(at(600))), []);
+        alarms.on_stable(400.0, at(0));
+        assert_eq!(names(&alarms.poll(at(59))), []);
+        assert_eq!(names(&alarms.poll(at(60))), [("renotify", 0)]);
+        assert_eq!(names(&alarms.poll(at(61))), []);
+        assert_eq!(names(&alarms.poll(at(119))), []);
+        assert_eq!(names(&alarms.poll(at(120))), [("renotify", 0)]);
+        // A late poll counts the interval from when it signalled
+        assert_eq!(names(&alarms.poll(at(200))), [("renotify", 0)]);
+        assert_eq!(names(&alarms.poll(at(259))), []);
+
+        alarms.acknowledge(Some(400.0));
+        assert_eq!(names(&alarms.poll(at(1000))), []);
+    }
+
+    #[test]
+    fn renotify_off() {
+        let mut alarms = Alarms::new(
+            &[Some(LOW), None, None, None],
+            &[AlarmState::Armed; MAX_ALARMS],
+            None,
+        );
+        let start = Instant::now();
+        alarms.on_stable(400.0, start);
+        assert_eq!(names(&alarms.poll(start + RENOTIFY * 10)), []);
+        assert!(alarms.is_latched());
+    }
+
+    #[test]
+    fn acknowledged_alarm_rearms_past_the_hysteresis() {
+        let mut alarms = alarms(&[AlarmState::Armed; MAX_ALARMS]);
+        let now = Instant::now();
+        alarms.on_stable(400.0, now);
+        assert_eq!(
+            names(&alarms.acknowledge(Some(400.0))),
+            [("acknowledged", 0)]
+        );
+        assert!(!alarms.is_latched());
+        assert_eq!(alarms.states()[0], AlarmState::Acknowledged);
+        // Still low, or back within the hysteresis: no new trip
+        assert_eq!(names(&alarms.on_stable(300.0, now)), []);
+        assert_eq!(names(&alarms.on_stable(509.0, now)), []);
+        assert_eq!(names(&alarms.on_stable(450.0, now)), []);
+        assert_eq!(names(&alarms.on_stable(510.0, now)), [("rearmed", 0)]);
+        assert_eq!(names(&alarms.on_stable(450.0, now)), [("tripped", 0)]);
+    }
+
+    #[test]
+    fn above_alarm_rearms_under_the_hysteresis() {
+        let mut alarms = alarms(&[AlarmState::Armed; MAX_ALARMS]);
+        let now = Instant::now();
+        // The low alarm trips too
+        alarms.on_stable(0.0, now);
+        alarms.acknowledge(Some(0.0));
+        assert_eq!(
+            names(&alarms.on_stable(2100.0, now)),
+            [("rearmed", 0), ("tripped", 2)]
+        );
+        alarms.acknowledge(Some(2100.0));
+        assert_eq!(names(&alarms.on_stable(1951.0, now)), []);
+        assert_eq!(names(&alarms.on_stable(2100.0, now)), []);
+        assert_eq!(names(&alarms.on_stable(1950.0, now)), [("rearmed", 2)]);
+    }
+
+    #[test]
+    fn acknowledged_after_clearing_is_armed_right_away() {
+        let mut alarms = alarms(&[AlarmState::Armed; MAX_ALARMS]);
+        let now = Instant::now();
+        alarms.on_stable(400.0, now);
+        alarms.acknowledge(Some(800.0));
+        assert_eq!(alarms.states()[0], AlarmState::Armed);
+        assert_eq!(names(&alarms.on_stable(400.0, now)), [("tripped", 0)]);
+        // Nor is the weight waited for when it is not known
+        alarms.acknowledge(None);
+        assert_eq!(alarms.states()[0], AlarmState::Armed);
+        // Nothing latched, nothing to acknowledge
+        assert_eq!(names(&alarms.acknowledge(Some(400.0))), []);
+    }
+
+    #[test]
+    fn states_survive_a_restart() {
+        let mut alarms = alarms(&[
+            AlarmState::Latched,
+            AlarmState::Armed,
+            AlarmState::Acknowledged,
+            AlarmState::Armed,
+        ]);
+        let start = Instant::now();
+        // Neither fires again right away
+        assert!(alarms.is_latched());
+        assert_eq!(names(&alarms.on_stable(2100.0, start)), []);
+        assert_eq!(names(&alarms.poll(start + RENOTIFY / 2)), []);
+        assert_eq!(names(&alarms.poll(start + RENOTIFY)), [("renotify", 0)]);
+        assert_eq!(names(&alarms.on_stable(1000.0, start)), [("rearmed", 2)]);
+    }
+
+    #[test]
+    fn changed_alarm_is_armed_again() {
+        let mut alarms = alarms(&[AlarmState::Armed; MAX_ALARMS]);
+        let now = Instant::now();
+        alarms.on_stable(2100.0, now);
+        alarms.on_stable(0.0, now);
+        let high = AlarmConfig {
+            grams: 3000.0,
+            ..HIGH
+        };
+        alarms.configure(&[Some(LOW), None, Some(high), None], Some(RENOTIFY));
+        assert_eq!(alarms.latched().collect::<Vec<_>>(), [(0, LOW)]);
+    }
+}
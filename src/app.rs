@@ -0,0 +1,4650 @@
+use std::{
+    sync::{mpsc::Receiver, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use embedded_graphics::{prelude::Point, text::TextStyle};
+use log::{debug, info, warn};
+use ssd1306::{prelude::WriteOnlyDataCommand, size::DisplaySize};
+
+#[cfg(feature = "battery")]
+use crate::battery::BatteryHandle;
+#[cfg(feature = "sdcard")]
+use crate::datalog::sdcard::SdCardLog;
+#[cfg(feature = "dispense")]
+use crate::dispense::{tuned_compensation, DispenseOutcome, DispenseResult, Dispenser};
+#[cfg(feature = "espnow")]
+use crate::espnow::{EspNowHandle, EspNowRole};
+#[cfg(any(feature = "mqtt", feature = "webhook"))]
+use crate::events::WeightSink;
+#[cfg(feature = "mdns")]
+use crate::mdns::MdnsHandle;
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttHandle;
+#[cfg(feature = "wifi")]
+use crate::wifi::{WifiHandle, WifiState};
+use crate::{
+    alarms::{AlarmEvent, AlarmStore, Alarms},
+    brew::{BrewConfig, BrewTimer, FlowMeter},
+    button::{ButtonAction, DisconnectWatch, TimedButtonEvent},
+    cal_transfer::HX711_GAIN,
+    certified::{self, RESTRICTIONS},
+    command_channel::{
+        AlarmSetting, AutoHoldSetting, BatterySetting, BrewSetting, ButtonSetting, BuzzerSetting,
+        CalReminderAction, CalReminderSetting, ClockSetting, Command, CommandOutcome,
+        CommandRequest, CommandSender, CreepCommand, DemoCommand, DispenseSetting, EspNowSetting,
+        FlashSetting, HeapSetting, InputSetting, LedSetting, LinearityCommand, LockSetting,
+        LogSetting, LowPowerSetting, MirrorSetting, ModbusSetting, MqttSetting, NegativeSetting,
+        NoiseCommand, NoiseSetting, RecipeSetting, RemoteCalibration, ScheduleSetting,
+        SdCardSetting, SensorSetting, SoakCommand, SoftTareAction, StaleSetting, StartupSetting,
+        TraceCommand, VolumeSetting, WebhookSetting,
+    },
+    console::USAGE,
+    counters::{self, Counter},
+    creep::{CreepError, CreepReport, CREEP_TABLE_HEADER},
+    deadband::{Deadband, DeadbandSetting},
+    demo::DemoPattern,
+    device,
+    diagnostics::DiagSnapshot,
+    display,
+    error::FirmwareError,
+    espnow::{format_mac, Aggregate, MAX_PEERS, PAIRING_WINDOW},
+    events::AppEvent,
+    feedback::{Feedback, FeedbackDispatcher},
+    filter::Sample,
+    format::KiloSwitch,
+    frame::{Frame, FrameDiff},
+    frame_rate::FrameGovernor,
+    governor,
+    hold::HoldState,
+    i18n::{self, tr, trf, Language, StringId},
+    imu,
+    linearity::{LinearityReport, LINEARITY_TABLE_HEADER, MIN_LINEARITY_WEIGHTS},
+    lock::{Click, LockHandle, MAX_PATTERN_LEN, MAX_PIN, PIN_DIGITS},
+    logger,
+    menu::*,
+    negative::{NegativeEvent, NegativeWatch},
+    noise::NoiseReport,
+    pages::{
+        clock_time, gesture_hint_text, IdlePolicy, PageId, PageInput, Screen, PAGE_IDLE_TIMEOUT,
+        PAGE_TITLE_TIME, PAGE_TURN_PERIOD, TOAST_TIME,
+    },
+    power::{self, IdleStage, IdleStages, ScheduleGate, WakeCheck, MAX_SCHEDULE_WINDOWS},
+    procedure::{
+        Admission, CalibrationStatus, LogPrompter, Procedure, ProcedureError, ProcedureResult,
+        ProcedureState, Prompter, TareGate, UiRequest,
+    },
+    quality::Quality,
+    quiesce::bumped_samples,
+    recipe::{Recipe, RecipeStep, RecipeUpdate, MAX_DOSE_GRAMS, MIN_DOSE_GRAMS},
+    scale::*,
+    selftest::{self, Outcome},
+    sensor::SensorKind,
+    session::{SessionStore, SessionTracker},
+    settings::{Settings, SettingsStore, StartupMode},
+    setup::{Setup, SetupState},
+    shutdown::{self, ShutdownReason},
+    sink::{SinkManager, SinkPolicy, Throttle},
+    snapshot::{SharedSnapshot, Snapshot},
+    soak::{Soak, SoakReport, SOAK_CSV_HEADER},
+    status::{draw_progress_bar, draw_status_icons, StatusIcon},
+    storage::{self, StorageService},
+    stream::{CsvStreamer, CSV_SINK, STREAM_DEFAULT_RATE},
+    tare::{DisplayMode, MAX_SOFT_TARES},
+    text_drawer::*,
+    time::Timestamp,
+    trace::{self, TracePoint, REPLAY_CSV_HEADER, TRACE_CSV_HEADER},
+    volume::{Substance, Volume, MAX_DENSITY, MIN_DENSITY},
+    watchdog::WatchdogGuard,
+    weight_gesture::InputMode,
+};
+#[cfg(feature = "esp")]
+use crate::{
+    datalog::{DataLogHandle, Record, DATALOG_CSV_HEADER},
+    history::HISTORY_CSV_HEADER,
+    ota::{self, OtaHandle},
+    reset::ResetLog,
+};
+
+const DISPLAY_REINIT_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest time the main loop sleeps without any event, bounding the latency
+/// of the console commands and the gesture timeouts
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// Readings queued for longer are dropped instead of shown
+const STALE_READING: Duration = Duration::from_millis(500);
+/// Events waiting for the main loop, later ones are dropped
+pub const APP_EVENT_QUEUE_LEN: usize = 32;
+
+/// Inverted flashes of the display once the target is reached, and on an
+/// overload, faster to tell them apart. Their periods are a few ticks long.
+const TARGET_FLASHES: u32 = 2;
+const TARGET_FLASH_PERIOD: Duration = Duration::from_millis(250);
+const OVERLOAD_FLASHES: u32 = 4;
+const OVERLOAD_FLASH_PERIOD: Duration = Duration::from_millis(120);
+
+/// Longest wait for a reading after a wake from the light sleep, the
+/// HX711 converts every 100ms
+const WAKE_READING_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Time the button needs to be held at power-on before a factory reset is offered
+const FACTORY_RESET_HOLD: Duration = Duration::from_secs(3);
+const FACTORY_RESET_COUNTDOWN_SECS: u32 = 5;
+/// Time given to the button task to debounce the level at power-on
+const FACTORY_RESET_SETTLE: Duration = Duration::from_millis(200);
+const FACTORY_RESET_POLL: Duration = Duration::from_millis(50);
+const MENU_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Time without a click after which the PIN or pattern entry gives up
+const UNLOCK_ENTRY_TIMEOUT: Duration = Duration::from_secs(15);
+/// Pause after the last click that ends a pattern
+const PATTERN_END: Duration = Duration::from_secs(2);
+/// Time the outcome of an unlock attempt is shown
+const UNLOCK_RESULT: Duration = Duration::from_millis(1500);
+
+const LOW_BATTERY_MESSAGE: Duration = Duration::from_millis(3000);
+/// Time an abnormal reset is shown at startup
+#[cfg(feature = "esp")]
+const RESET_TOAST: Duration = Duration::from_millis(2000);
+/// Age of the diagnostics figures before they are collected again
+const DIAG_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+/// Interval changed session stats are saved at
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Interval the stable weight is saved at when it moved, to be checked
+/// after a restart
+const LAST_WEIGHT_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const RESOLUTIONS_GRAMS: [f32; 4] = [0.1, 1.0, 5.0, 10.0];
+const RESOLUTION_LABELS: [&str; 4] = ["0.1g", "1g", "5g", "10g"];
+const UNIT_LABELS: [&str; 4] = ["g", "kg", "oz", "lb"];
+/// Display rotations in quarter turns
+const ROTATION_LABELS: [&str; 4] = ["0", "90", "180", "270"];
+/// Time the summary of the setup shows without a press
+const SETUP_SUMMARY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Background services the main loop hands requests to
+#[derive(Default)]
+pub struct Services {
+    /// Latest reading, shared with the tasks reporting it
+    pub snapshot: SharedSnapshot,
+    /// Beeps and lights following what happens
+    pub feedback: FeedbackDispatcher,
+    /// Weight log on flash, unless disabled
+    #[cfg(feature = "esp")]
+    pub datalog: Option<DataLogHandle>,
+    /// NVS storage shared by the namespaces
+    pub storage: Option<StorageService>,
+    /// Reset counters, unless their storage failed
+    #[cfg(feature = "esp")]
+    pub resets: Option<ResetLog>,
+    /// Alarm states kept across restarts, unless their storage failed
+    pub alarm_store: Option<AlarmStore>,
+    /// Session stats kept across restarts, unless their storage failed
+    pub session_store: Option<SessionStore>,
+    /// Firmware updates received over HTTP
+    #[cfg(feature = "esp")]
+    pub ota: OtaHandle,
+    /// Guards the calibration and the settings, shared with the HTTP server
+    pub lock: LockHandle,
+    #[cfg(feature = "sdcard")]
+    pub sdcard: Option<SdCardLog>,
+    #[cfg(feature = "battery")]
+    pub battery: Option<BatteryHandle>,
+    #[cfg(feature = "dispense")]
+    pub dispenser: Option<Dispenser>,
+    #[cfg(feature = "wifi")]
+    pub wifi: Option<WifiHandle>,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<MqttHandle>,
+    #[cfg(feature = "mqtt")]
+    pub mqtt_sink: Option<WeightSink>,
+    #[cfg(feature = "webhook")]
+    pub webhook_sink: Option<WeightSink>,
+    #[cfg(feature = "mdns")]
+    pub mdns: Option<MdnsHandle>,
+    #[cfg(feature = "espnow")]
+    pub espnow: Option<EspNowHandle>,
+}
+
+impl Services {
+    /// Icons describing the state of the services, for the status strip
+    fn status_icons(&self, utc_offset_minutes: i16) -> Vec<StatusIcon> {
+        let mut icons = Vec::new();
+        if let Some((hours, minutes)) =
+            Timestamp::now_with_offset(utc_offset_minutes).hours_minutes()
+        {
+            icons.push(StatusIcon::Clock { hours, minutes });
+        }
+        if let Some(percent) = self.battery_percent() {
+            icons.push(StatusIcon::Battery {
+                quarters: ((u32::from(percent) + 12) / 25) as u8,
+            });
+        }
+        #[cfg(feature = "sdcard")]
+        if self.sdcard.as_ref().is_some_and(SdCardLog::is_suspended) {
+            icons.push(StatusIcon::SdCardSuspended);
+        }
+        if governor::is_degraded() {
+            icons.push(StatusIcon::HeapLow);
+        }
+        #[cfg(feature = "wifi")]
+        if let Some(wifi) = &self.wifi {
+            icons.push(match wifi.state() {
+                WifiState::Connected => StatusIcon::WifiConnected,
+                WifiState::Connecting => StatusIcon::WifiConnecting,
+                WifiState::Disconnected => StatusIcon::WifiOffline,
+                WifiState::Provisioning => StatusIcon::AccessPoint,
+            });
+        }
+        icons
+    }
+
+    /// State of the services, for the text pages
+    fn status(&self, settings: &Settings) -> ServiceStatus {
+        #[allow(unused_mut)]
+        let mut status = ServiceStatus {
+            log_records: self.log_records(),
+            battery: self.battery_voltage().zip(self.battery_percent()),
+            hostname: device::hostname(settings.hostname()),
+            ..ServiceStatus::default()
+        };
+        #[cfg(feature = "wifi")]
+        if let Some(wifi) = &self.wifi {
+            status.wifi = Some(match wifi.state() {
+                WifiState::Connected => "connected",
+                WifiState::Connecting => "connecting",
+                WifiState::Disconnected => "offline",
+                WifiState::Provisioning => "setup AP",
+            });
+        }
+        #[cfg(feature = "mqtt")]
+        {
+            status.mqtt = self.mqtt.is_some();
+        }
+        status
+    }
+
+    /// Records in the weight log, unless disabled
+    fn log_records(&self) -> Option<usize> {
+        #[cfg(feature = "esp")]
+        if let Some(datalog) = &self.datalog {
+            return Some(datalog.len());
+        }
+        None
+    }
+
+    /// Progress of the firmware update being received, if any
+    fn update_progress(&self) -> Option<f32> {
+        #[cfg(feature = "esp")]
+        if let Some(progress) = self.ota.progress() {
+            return Some(progress);
+        }
+        None
+    }
+
+    fn battery_voltage(&self) -> Option<f32> {
+        #[cfg(feature = "battery")]
+        if let Some(battery) = &self.battery {
+            return battery.voltage();
+        }
+        None
+    }
+
+    fn battery_percent(&self) -> Option<u8> {
+        #[cfg(feature = "battery")]
+        if let Some(battery) = &self.battery {
+            return battery.percent();
+        }
+        None
+    }
+
+    /// Whether the battery is below the cutoff voltage
+    fn battery_low(&self) -> bool {
+        #[cfg(feature = "battery")]
+        if let Some(battery) = &self.battery {
+            return battery.is_low();
+        }
+        false
+    }
+
+    /// Sum of the corners, on the aggregator
+    fn aggregate(&self) -> Option<Aggregate> {
+        #[cfg(feature = "espnow")]
+        if let Some(espnow) = &self.espnow {
+            return espnow.aggregate();
+        }
+        None
+    }
+
+    /// Whether the scale sums corners, with the pages for them in the cycle
+    fn is_aggregating(&self) -> bool {
+        #[cfg(feature = "espnow")]
+        if let Some(espnow) = &self.espnow {
+            return espnow.role() == EspNowRole::Aggregator;
+        }
+        false
+    }
+
+    /// Open the ESP-NOW pairing. Returns false when ESP-NOW is not running.
+    fn pair_espnow(&self) -> bool {
+        #[cfg(feature = "espnow")]
+        if let Some(espnow) = &self.espnow {
+            espnow.pair();
+            return true;
+        }
+        false
+    }
+
+    fn is_pairing(&self) -> bool {
+        #[cfg(feature = "espnow")]
+        if let Some(espnow) = &self.espnow {
+            return espnow.is_pairing();
+        }
+        false
+    }
+
+    /// Hand the ESP-NOW settings over to its task
+    fn configure_espnow(&self, _settings: &Settings) {
+        #[cfg(feature = "espnow")]
+        if let Some(espnow) = &self.espnow {
+            espnow.configure(_settings);
+        }
+    }
+
+    /// Start dispensing `target_grams`. Fails when the dispenser is not
+    /// running or refuses the target.
+    fn start_dispense(&self, _target_grams: f32, _compensation_grams: f32) -> Result<(), String> {
+        #[cfg(feature = "dispense")]
+        if let Some(dispenser) = &self.dispenser {
+            return dispenser
+                .start_dispense(_target_grams, _compensation_grams)
+                .map_err(|err| err.to_string());
+        }
+        Err("Dispenser is not running".to_string())
+    }
+
+    fn is_dispensing(&self) -> bool {
+        #[cfg(feature = "dispense")]
+        if let Some(dispenser) = &self.dispenser {
+            return dispenser.is_running();
+        }
+        false
+    }
+
+    /// Force the dispenser output off. Returns false when the dispenser is
+    /// not running.
+    fn abort_dispense(&self) -> bool {
+        #[cfg(feature = "dispense")]
+        if let Some(dispenser) = &self.dispenser {
+            dispenser.abort();
+            return true;
+        }
+        false
+    }
+
+    /// The sinks the samples go out to: the CSV stream, off until asked
+    /// for, the SD card with every sample, the weight log at its interval,
+    /// and the MQTT and webhook tasks. The logs leave out the demo mode
+    /// unless told otherwise.
+    fn output_sinks(&self) -> SinkManager {
+        let mut sinks = SinkManager::new();
+        sinks.register(
+            CsvStreamer::start(),
+            SinkPolicy {
+                throttle: STREAM_DEFAULT_RATE.into(),
+                loggable_only: false,
+            },
+        );
+        #[cfg(feature = "sdcard")]
+        if let Some(sdcard) = &self.sdcard {
+            sinks.register(
+                sdcard.clone(),
+                SinkPolicy {
+                    throttle: Throttle::EverySample,
+                    loggable_only: true,
+                },
+            );
+        }
+        #[cfg(feature = "esp")]
+        if let Some(datalog) = &self.datalog {
+            sinks.register(
+                datalog.clone(),
+                SinkPolicy {
+                    throttle: Throttle::Interval(datalog.interval()),
+                    loggable_only: true,
+                },
+            );
+        }
+        // Offered every sample, its threshold holds back the changes too
+        // small or too soon, but not a change of the stability
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt_sink) = &self.mqtt_sink {
+            sinks.register(
+                mqtt_sink.clone(),
+                SinkPolicy {
+                    throttle: Throttle::EverySample,
+                    loggable_only: false,
+                },
+            );
+        }
+        #[cfg(feature = "webhook")]
+        if let Some(webhook_sink) = &self.webhook_sink {
+            sinks.register(
+                webhook_sink.clone(),
+                SinkPolicy {
+                    throttle: Throttle::EverySample,
+                    loggable_only: false,
+                },
+            );
+        }
+        sinks
+    }
+
+    /// Connect to a network with new credentials. Returns false when Wi-Fi
+    /// is not running.
+    fn connect_wifi(&self, _ssid: &str, _password: &str) -> bool {
+        #[cfg(feature = "wifi")]
+        if let Some(wifi) = &self.wifi {
+            wifi.connect(_ssid, _password);
+            return true;
+        }
+        false
+    }
+
+    /// Advertise the scale under a new hostname. Returns false when mDNS is
+    /// not running.
+    fn set_hostname(&self, _hostname: &str) -> bool {
+        #[cfg(feature = "mdns")]
+        if let Some(mdns) = &self.mdns {
+            mdns.set_hostname(_hostname);
+            return true;
+        }
+        false
+    }
+
+    /// Publish an alarm event, if MQTT is running
+    fn publish_alarm(&self, _event: AlarmEvent) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_alarm(_event);
+        }
+    }
+
+    /// Remove the scale from Home Assistant. Returns false when MQTT is not
+    /// running.
+    fn decommission(&self) -> bool {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.decommission();
+            return true;
+        }
+        false
+    }
+}
+
+/// State of the services shown on the text pages
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ServiceStatus {
+    /// Records in the weight log, unless disabled
+    log_records: Option<usize>,
+    /// Voltage and charge of the battery, if monitored
+    battery: Option<(f32, u8)>,
+    /// State of the Wi-Fi connection, unless Wi-Fi is not running
+    wifi: Option<&'static str>,
+    /// Whether the MQTT task is running
+    mqtt: bool,
+    hostname: String,
+}
+
+/// State the settings menu reads and edits
+struct MenuContext<'m> {
+    scale: &'m mut Scale,
+    settings: &'m mut Settings,
+    /// Read by the network entries only
+    #[cfg_attr(not(feature = "wifi"), allow(dead_code))]
+    services: &'m Services,
+    /// Mode picked from the menu, entered once it is closed
+    mode: Option<ModeRequest>,
+    /// Whether unlocking was picked from the menu of the locked scale
+    unlock: bool,
+}
+
+/// Mode of the main loop, prompt or action of the main loop that can be
+/// picked from the menu
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ModeRequest {
+    /// Freeze the weight on the weight page
+    Hold,
+    Brew,
+    Recipe,
+    Calibrate,
+    /// Check the linearity with the saved known weights
+    Linearity,
+    /// Measure the noise floor of the empty scale
+    NoiseTest,
+    NewSession,
+    /// Walk through the first-boot setup again
+    Setup,
+    /// Erase the calibration and the settings, through the command channel
+    FactoryReset,
+}
+
+/// What the main loop shows and what the button does
+enum Mode {
+    Weighing,
+    /// Brew timer, replacing the plain weight
+    Brew(BrewTimer),
+    /// Pour-over assistant, taking over the button and the display
+    Recipe(Recipe),
+}
+
+/// Tare or calibration the main loop advances in place of the weighing
+struct RunningProcedure {
+    procedure: Procedure,
+    /// Whether it was started from the console, which gets the response
+    reply: bool,
+}
+
+/// State of the main loop, which the display is rendered from
+struct AppState {
+    start_time: Instant,
+    /// Destinations of the samples, the CSV stream among them
+    sinks: SinkManager,
+    mode: Mode,
+    /// Filtered weight rounded to the resolution, none until the first reading
+    grams: Option<f32>,
+    unit: Unit,
+    /// Substance the weight shows as the volume of, if any
+    volume: Option<Volume>,
+    /// Holds the weight shown within the noise
+    deadband: Deadband,
+    resolution: f32,
+    /// Weight reported while a soft tare is stacked, labelled on the weight
+    /// page
+    tare_mode: Option<DisplayMode>,
+    /// Weight frozen on the weight page, if held
+    hold: HoldState,
+    /// Whether the sensor stopped converting, the weight shows as stale
+    stale: bool,
+    /// Quality of the weight shown, stale along with the sensor
+    quality: Quality,
+    /// Last time the sensor was reset after it stopped converting
+    sensor_reset: Option<Instant>,
+    /// Whether a weight in grams is shown in kilograms
+    kilo: KiloSwitch,
+    /// Flow rate of the weight, whether or not the brew timer runs
+    flow: FlowMeter,
+    icons: Vec<StatusIcon>,
+    status: ServiceStatus,
+    /// Diagnostics figures along with the time they were collected, while
+    /// their page is shown
+    diag: Option<(Instant, DiagSnapshot)>,
+    /// Sum of the corners, while its pages are shown
+    aggregate: Option<Aggregate>,
+    /// Page shown, kept until the restart
+    page: PageId,
+    page_since: Instant,
+    /// Turns of the lines of a text page that does not fit
+    page_turn: u64,
+    /// Whether the status strip shows the title of the page
+    title_shown: bool,
+    /// Message taking over the status strip for a moment, with the time it
+    /// showed at
+    toast: Option<(String, Instant)>,
+    /// Hint for the press on the platform in progress, over the toasts
+    gesture_hint: Option<String>,
+    /// Time of the last button gesture, the weight page comes back when idle
+    last_gesture: Instant,
+    /// When the clock takes over from the weight page
+    idle: IdlePolicy,
+    /// When the display dims, turns off and the scale goes into deep sleep
+    idle_stages: IdleStages,
+    alarms: Alarms,
+    /// Weighings of the current session
+    sessions: SessionTracker,
+    /// Weighings going on without a press, for a disconnected button
+    button_watch: DisconnectWatch,
+    /// Time the session stats were last saved
+    sessions_saved: Instant,
+    /// Stable weight above the tare saved last, along with the time it was
+    /// last considered for saving
+    last_weight: Option<f32>,
+    last_weight_saved: Instant,
+    /// Weight saved before the restart and the distance from it the first
+    /// stable weight may be at, until that one came
+    weight_check: Option<(f32, f32)>,
+    /// Whether the display is behind the state
+    dirty: bool,
+    /// Whether the whole screen has to be redrawn instead of the regions of
+    /// the page, after anything else drew on it
+    full_redraw: bool,
+    /// Operations of the page drawn last, only those that changed are drawn
+    /// again
+    frame: Frame,
+    /// Paces the redraws of the moving weight
+    frames: FrameGovernor,
+    watchdog: WatchdogGuard,
+    /// Progress of a running firmware update
+    update: Option<f32>,
+    /// Tare or calibration taking over the display and the readings
+    procedure: Option<RunningProcedure>,
+    /// Where the last calibration is at, shared for the remote side
+    calibration: CalibrationStatus,
+    /// Refuses the tares asked for right after one, and holds a calibration
+    /// or check asked for during a tare until it is done
+    tare_gate: TareGate<Command>,
+    /// Hints to tare again when the scale keeps reading below zero
+    negative: NegativeWatch,
+    /// Sends the scale to sleep outside of the windows of the schedule
+    schedule: ScheduleGate,
+    /// Display rotation in quarter turns before the setup, while its summary
+    /// waits for the calibration it started
+    setup_summary: Option<u8>,
+    /// Outcome of the last noise test, on the diagnostics page
+    noise: Option<NoiseReport>,
+    /// Pattern `demo on` starts the demo signal with, the last one picked
+    demo_pattern: DemoPattern,
+    /// Whether the demo weight goes into the weight log and to the SD card
+    log_demo: bool,
+    /// Trace recorded or loaded last, the one `trace replay` weighs again
+    trace: Vec<TracePoint>,
+    /// Soak test running, tares and calibrations wait for its end
+    soak: Option<Soak>,
+    /// Drift of the last soak that ended
+    soak_report: Option<SoakReport>,
+    /// Queues the commands of the menu and the boot gesture, which go
+    /// through the same checks as those of the frontends
+    commands: CommandSender,
+    /// Feedback for the display to flash on, handed over by the feedback
+    /// thread
+    flash: Arc<Mutex<Option<Feedback>>>,
+}
+
+impl AppState {
+    /// Switch to the page, showing its title for a moment
+    fn show_page(&mut self, page: PageId) {
+        self.page = page;
+        self.page_since = Instant::now();
+        self.page_turn = 0;
+        self.title_shown = true;
+        self.full_redraw = true;
+        self.dirty = true;
+        if page != PageId::Diagnostics {
+            self.diag = None;
+        }
+        if !page.is_aggregate() {
+            self.aggregate = None;
+        }
+    }
+
+    /// Switch between the weight page and the idle clock, without a title
+    fn show_idle_page(&mut self, page: PageId) {
+        self.show_page(page);
+        self.title_shown = false;
+    }
+}
+
+/// Run the application: tare (and calibrate if needed) at startup, then
+/// handle the events of the sampling task and the button forever
+pub fn run<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    mut scale: Scale,
+    mut settings_store: SettingsStore,
+    app_events: Receiver<AppEvent>,
+    commands: Receiver<CommandRequest>,
+    command_sender: CommandSender,
+    services: Services,
+) -> Result<(), FirmwareError>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let start_time = Instant::now();
+
+    // Run by the main loop once it starts
+    if check_factory_reset(&mut scale, text_drawer, &services.lock)? {
+        queue_command(&command_sender, Command::FactoryReset);
+    }
+    #[cfg(feature = "esp")]
+    if let Some(resets) = &services.resets {
+        show_reset_toast(text_drawer, resets)?;
+    }
+    let results = selftest::run(&mut scale, &settings_store, text_drawer)?;
+    if let Some(failed) = results
+        .iter()
+        .find(|result| result.outcome == Outcome::Fatal)
+    {
+        return Err(FirmwareError::SelfTest {
+            check: failed.check,
+            detail: failed.detail,
+        });
+    }
+
+    // Watched from here on, the factory reset prompt above ends in a restart
+    // anyway
+    let watchdog = WatchdogGuard::subscribe("main");
+
+    let mut state = AppState {
+        start_time,
+        sinks: services.output_sinks(),
+        mode: Mode::Weighing,
+        grams: None,
+        unit: scale.unit(),
+        volume: settings_store.settings().volume(),
+        deadband: Deadband::new(settings_store.settings().deadband()),
+        resolution: scale.resolution(),
+        tare_mode: None,
+        hold: HoldState::Live,
+        stale: false,
+        quality: Quality::default(),
+        sensor_reset: None,
+        kilo: KiloSwitch::default(),
+        flow: FlowMeter::default(),
+        icons: Vec::new(),
+        status: services.status(settings_store.settings()),
+        diag: None,
+        aggregate: None,
+        page: PageId::default(),
+        page_since: start_time,
+        page_turn: 0,
+        title_shown: false,
+        toast: None,
+        gesture_hint: None,
+        last_gesture: start_time,
+        idle: IdlePolicy::new(
+            settings_store.settings().idle_clock(),
+            settings_store.settings().idle_clock_timeout(),
+        ),
+        idle_stages: IdleStages::new(settings_store.settings().idle_timeouts(), start_time),
+        alarms: Alarms::new(
+            settings_store.settings().alarms(),
+            &services
+                .alarm_store
+                .as_ref()
+                .map(AlarmStore::states)
+                .unwrap_or_default(),
+            settings_store.settings().alarm_renotify(),
+        ),
+        sessions: services
+            .session_store
+            .as_ref()
+            .map(SessionStore::load)
+            .unwrap_or_default(),
+        sessions_saved: start_time,
+        button_watch: DisconnectWatch::new(
+            settings_store.settings().button_disconnect(),
+            counters::value(Counter::ButtonPresses),
+            start_time,
+        ),
+        last_weight: services
+            .session_store
+            .as_ref()
+            .and_then(SessionStore::last_weight),
+        last_weight_saved: start_time,
+        weight_check: None,
+        dirty: true,
+        full_redraw: true,
+        frame: Frame::default(),
+        frames: FrameGovernor::new(settings_store.settings().max_fps(), start_time),
+        watchdog,
+        update: None,
+        procedure: None,
+        calibration: CalibrationStatus::Idle,
+        tare_gate: TareGate::new(settings_store.settings().tare_cooldown()),
+        negative: NegativeWatch::new(settings_store.settings().negative_hint()),
+        noise: scale.noise().copied(),
+        schedule: ScheduleGate::new(settings_store.settings().schedule(), start_time),
+        setup_summary: None,
+        demo_pattern: DemoPattern::default(),
+        log_demo: false,
+        trace: Vec::new(),
+        soak: None,
+        soak_report: None,
+        commands: command_sender,
+        flash: Arc::default(),
+    };
+    let flash = state.flash.clone();
+    services.feedback.subscribe(move |feedback| {
+        if matches!(feedback, Feedback::TargetReached | Feedback::Overload) {
+            *flash
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(feedback);
+        }
+    });
+    // The setup is driven from the display, a headless scale waits for one
+    if !settings_store.settings().setup_done() && !display::is_headless() {
+        run_setup(
+            &mut scale,
+            text_drawer,
+            &mut settings_store,
+            &mut state,
+            &services,
+        )?;
+    }
+    // Unless the setup started a calibration
+    if state.procedure.is_none() {
+        if let Some(procedure) =
+            startup_procedure(&mut scale, settings_store.settings(), &mut state)
+        {
+            start_procedure(procedure, &mut state, &services, false);
+        }
+    }
+    let mut last_reinit_attempt = Instant::now();
+    let mut last_mirror_attempt = Instant::now();
+
+    loop {
+        // Every event, or at least every tick, passes here
+        state.watchdog.feed();
+        if let Some(turns) = state.setup_summary {
+            if state.procedure.is_none() {
+                state.setup_summary = None;
+                finish_setup(
+                    turns,
+                    &mut scale,
+                    text_drawer,
+                    &mut settings_store,
+                    &mut state,
+                    &services,
+                )?;
+            }
+        }
+        // The idle stages are polled here, so their changes come in as events
+        // like the others
+        // Back in time for a weight frame that waits, unless nothing is drawn
+        let drawing =
+            state.procedure.is_none() && state.idle_stages.stage() < IdleStage::DisplayOff;
+        let wait = match state.frames.due_in(Instant::now()) {
+            Some(due) if drawing => due.min(TICK_INTERVAL),
+            _ => TICK_INTERVAL,
+        };
+        let event = match state.idle_stages.poll(Instant::now()) {
+            Some(stage) => AppEvent::Idle(stage),
+            None => match app_events.recv_timeout(wait) {
+                Ok(event) => event,
+                // Nothing came in for a while, timeouts may still be due
+                Err(_) => AppEvent::Tick,
+            },
+        };
+        match event {
+            // Queued while the loop was busy, e.g. in the menu
+            AppEvent::Reading { at, .. } if at.elapsed() >= STALE_READING => {}
+            AppEvent::Idle(stage) => enter_idle_stage(
+                stage,
+                text_drawer,
+                &mut settings_store,
+                &mut state,
+                &services,
+            ),
+            _ if state.procedure.is_some() => {
+                advance_procedure(event, &mut scale, text_drawer, &mut state, &services)?;
+            }
+            AppEvent::Reading { raw, disturbed, .. } => {
+                let sample = scale.process_reading(raw, disturbed);
+                handle_sample(&sample, &scale, &mut state, &services);
+            }
+            AppEvent::Button(TimedButtonEvent { event, at }) => {
+                debug!("Button {:?} handled after {:?}", event, at.elapsed());
+            }
+            AppEvent::Tick => {}
+        }
+        let events = state.alarms.poll(Instant::now());
+        handle_alarm_events(events, &mut state, &services);
+        // Nothing goes idle while something runs or waits on the user
+        if state.procedure.is_some()
+            || state.alarms.is_latched()
+            || services.is_dispensing()
+            || state.update.is_some()
+        {
+            state.idle_stages.on_activity(Instant::now());
+        }
+
+        // Display failures are not fatal, keep weighing and try to bring the
+        // panel back every now and then
+        if text_drawer.is_offline() && last_reinit_attempt.elapsed() >= DISPLAY_REINIT_INTERVAL {
+            last_reinit_attempt = Instant::now();
+            match text_drawer.reinit() {
+                Ok(()) => {
+                    info!(
+                        "Display reinitialized after {} errors",
+                        text_drawer.error_count()
+                    );
+                    state.dirty = true;
+                    state.full_redraw = true;
+                }
+                Err(err) => warn!("Display reinit failed: {:?}", err),
+            }
+        }
+        if text_drawer.is_mirror_down() && last_mirror_attempt.elapsed() >= DISPLAY_REINIT_INTERVAL
+        {
+            last_mirror_attempt = Instant::now();
+            if text_drawer.reinit_mirror().is_ok() {
+                info!("Mirror display back");
+                state.dirty = true;
+                state.full_redraw = true;
+            }
+        }
+
+        let running = state.procedure.as_ref().map(|running| &running.procedure);
+        if let Some(command) = state.tare_gate.take_queued(running) {
+            handle_command(
+                command,
+                &mut scale,
+                text_drawer,
+                &mut settings_store,
+                &mut state,
+                &services,
+            )?;
+        }
+        while let Ok(request) = commands.try_recv() {
+            state.idle_stages.on_activity(Instant::now());
+            let (command, reply) = request.into_parts();
+            let outcome = handle_command(
+                command,
+                &mut scale,
+                text_drawer,
+                &mut settings_store,
+                &mut state,
+                &services,
+            )?;
+            reply.send(outcome);
+            // Prompts may have drawn over the page
+            state.dirty = true;
+            state.full_redraw = true;
+        }
+
+        if services.battery_low() {
+            low_battery_shutdown(text_drawer, &mut settings_store, &mut state, &services);
+        }
+
+        // Any press stops the dispenser, and starts no gesture
+        if services.is_dispensing() && scale.is_button_pressed() {
+            services.abort_dispense();
+            scale.clear_button_events();
+        }
+        #[cfg(feature = "dispense")]
+        if let Some(result) = services.dispenser.as_ref().and_then(Dispenser::take_result) {
+            report_dispense(&result, &mut settings_store);
+        }
+
+        // The presses of a running procedure are its own
+        let button_action = match state.procedure {
+            Some(_) => None,
+            None => scale.poll_button_action(),
+        };
+        let display_off = state.idle_stages.stage() >= IdleStage::DisplayOff;
+        if button_action.is_some() {
+            state.last_gesture = Instant::now();
+            state.idle.wake();
+            state.idle_stages.on_activity(Instant::now());
+            state.schedule.on_button(Instant::now());
+        }
+        // A gesture on the display turned off only turns it back on
+        let button_action = button_action.filter(|_| !display_off);
+        // A gesture while an alarm is latched only acknowledges it, one on
+        // the clock only brings the weight back
+        if button_action.is_some() && state.alarms.is_latched() {
+            acknowledge_alarms(&mut state, &services);
+        } else if let (Some(_), PageId::Clock) = (button_action, state.page) {
+            state.show_idle_page(PageId::Weight);
+        } else if let Some(button_action) = button_action {
+            handle_button(
+                button_action,
+                &mut scale,
+                text_drawer,
+                &mut settings_store,
+                &mut state,
+                &services,
+            )?;
+            // The menu and the prompts keep the loop for a while
+            state.idle_stages.on_activity(Instant::now());
+            state.dirty = true;
+            state.full_redraw = true;
+        }
+
+        if matches!(state.mode, Mode::Weighing)
+            && !matches!(state.page, PageId::Weight | PageId::Total | PageId::Clock)
+            && state.last_gesture.elapsed() >= PAGE_IDLE_TIMEOUT
+        {
+            state.show_page(PageId::Weight);
+        }
+        let clock_due = matches!(state.mode, Mode::Weighing)
+            && state.idle.is_idle()
+            && !state.alarms.is_latched()
+            && clock_time(&state.icons).is_some();
+        match state.page {
+            PageId::Weight if clock_due => state.show_idle_page(PageId::Clock),
+            PageId::Clock if !clock_due => state.show_idle_page(PageId::Weight),
+            _ => {}
+        }
+        let doze_due = settings_store
+            .settings()
+            .low_power()
+            .is_some_and(|after| state.idle.idle_for().is_some_and(|idle| idle >= after));
+        if doze_due
+            && matches!(state.mode, Mode::Weighing)
+            && state.procedure.is_none()
+            && !state.alarms.is_latched()
+            && !services.is_dispensing()
+            // The load cell the light sleep waits on is not read
+            && !scale.is_demo()
+        {
+            doze(
+                &mut scale,
+                text_drawer,
+                &app_events,
+                settings_store.settings(),
+                &mut state,
+            );
+        }
+        let local_secs =
+            Timestamp::now_with_offset(settings_store.settings().utc_offset_minutes()).local_secs();
+        if let Some(wake_after) = state.schedule.sleep_for(local_secs, Instant::now()) {
+            if state.procedure.is_none() && !services.is_dispensing() {
+                sleep_until_window(
+                    wake_after,
+                    text_drawer,
+                    &mut settings_store,
+                    &mut state,
+                    &services,
+                );
+            }
+        }
+        if state.title_shown && state.page_since.elapsed() >= PAGE_TITLE_TIME {
+            state.title_shown = false;
+            state.dirty = true;
+        }
+        if state.procedure.is_none() {
+            if let Some(reason) = scale.take_calibration_toast() {
+                info!("Recalibrating is suggested: {:?}", reason);
+                state.toast = Some((reason.describe(), Instant::now()));
+                state.dirty = true;
+            }
+        }
+        if let Some(result) = scale.take_creep_result() {
+            report_creep(result, &mut state);
+        }
+        if let Some(points) = scale.take_trace() {
+            info!("Trace of {} readings recorded", points.len());
+            state.trace = points;
+            print_trace(&state);
+        }
+        if state.procedure.is_none() {
+            check_sensor(&mut scale, settings_store.settings(), &mut state);
+        }
+        if state
+            .soak
+            .as_ref()
+            .is_some_and(|soak| soak.is_over(Instant::now()))
+        {
+            finish_soak(&mut state);
+        }
+        if matches!(&state.toast, Some((_, shown)) if shown.elapsed() >= TOAST_TIME) {
+            state.toast = None;
+            state.dirty = true;
+        }
+        let gesture_hint = scale.gesture_hint().map(gesture_hint_text);
+        if gesture_hint != state.gesture_hint {
+            state.gesture_hint = gesture_hint;
+            state.dirty = true;
+        }
+
+        // Covers the Wi-Fi, MQTT and battery state along with the clock
+        let mut icons = services.status_icons(settings_store.settings().utc_offset_minutes());
+        if scale.calibration_reminder().is_some() {
+            icons.push(StatusIcon::Recalibrate);
+        }
+        if scale.is_demo() {
+            icons.push(StatusIcon::Demo);
+        }
+        if state.soak.is_some() {
+            icons.push(StatusIcon::Soak);
+        }
+        if scale.is_certified() {
+            icons.push(StatusIcon::Certified);
+        }
+        if scale.is_button_stuck() {
+            icons.push(StatusIcon::ButtonStuck);
+        }
+        state
+            .button_watch
+            .on_presses(counters::value(Counter::ButtonPresses), Instant::now());
+        if text_drawer.is_mirror_down() {
+            icons.push(StatusIcon::MirrorDown);
+        }
+        if icons != state.icons {
+            state.icons = icons;
+            state.dirty = true;
+        }
+        if state.page.is_text() {
+            let turn =
+                (state.page_since.elapsed().as_millis() / PAGE_TURN_PERIOD.as_millis()) as u64;
+            if turn != state.page_turn {
+                state.page_turn = turn;
+                state.dirty = true;
+            }
+            let status = services.status(settings_store.settings());
+            if status != state.status {
+                state.status = status;
+                state.dirty = true;
+            }
+        }
+        let diag_due = !matches!(&state.diag, Some((collected, _))
+            if collected.elapsed() < DIAG_REFRESH_INTERVAL);
+        if state.page == PageId::Diagnostics && diag_due {
+            state.diag = Some((Instant::now(), DiagSnapshot::collect()));
+            state.dirty = true;
+        }
+        if state.page.is_aggregate() {
+            let aggregate = services.aggregate();
+            // Moving along with the corners, so paced like the weight
+            if aggregate != state.aggregate {
+                state.aggregate = aggregate;
+                state.frames.on_update();
+            }
+        }
+        if state.sessions_saved.elapsed() >= SESSION_SAVE_INTERVAL {
+            state.sessions_saved = Instant::now();
+            save_sessions(&mut state, &services);
+        }
+        if state.last_weight_saved.elapsed() >= LAST_WEIGHT_SAVE_INTERVAL {
+            state.last_weight_saved = Instant::now();
+            if settings_store.settings().startup_mode() == StartupMode::RestoreLastWeight {
+                save_last_weight(&scale, &mut state, &services);
+            }
+        }
+        let update = services.update_progress();
+        if update != state.update {
+            state.update = update;
+            state.dirty = true;
+            state.full_redraw = true;
+        }
+
+        // Drawn once the display is back on, the moving weight no faster
+        // than the frame rate
+        let display_off = state.idle_stages.stage() >= IdleStage::DisplayOff;
+        let now = Instant::now();
+        let due = state.dirty || state.frames.is_due(now);
+        if due && state.procedure.is_none() && !display_off {
+            render(text_drawer, &mut state)?;
+            state.frames.on_frame(now);
+            state.dirty = false;
+            state.full_redraw = false;
+        }
+        let flash = state
+            .flash
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(feedback) = flash.filter(|_| !display_off) {
+            flash_display(feedback, text_drawer, settings_store.settings());
+        }
+        // Renders in between leave the flash alone, it only inverts the panel
+        if text_drawer.is_flashing() {
+            text_drawer.tick()?;
+        }
+    }
+}
+
+/// Follow a new sample with the outputs and the state
+fn handle_sample(sample: &Sample, scale: &Scale, state: &mut AppState, services: &Services) {
+    let loggable = !scale.is_demo() || state.log_demo;
+    state.sinks.offer(sample, loggable, Instant::now());
+
+    let grams = scale.round_to_resolution(sample.grams_filtered);
+    services.snapshot.set(Snapshot {
+        grams,
+        grams_raw: sample.grams_raw,
+        stable: sample.stable,
+        quality: sample.quality,
+        stale_after: scale.stale_reading(),
+        unit: scale.unit(),
+        volume: state.volume,
+        scale_factor: scale.scale_factor(),
+        offset: scale.offset(),
+        calibration_weight: scale.calibration_weight(),
+        battery_voltage: services.battery_voltage(),
+        battery_percent: services.battery_percent(),
+        calibration: state.calibration,
+        linearity: scale.linearity().copied(),
+        last_reading: Some(scale.last_reading()),
+        demo: scale.is_demo(),
+        log_demo: state.log_demo,
+        idle: state.idle_stages.stage(),
+    });
+
+    if state.grams.is_none() {
+        // The scale started and reads, keep an updated firmware from now on
+        #[cfg(feature = "esp")]
+        ota::confirm_running_image();
+    }
+    if let (Some((expected, tolerance)), Some(gross)) = (state.weight_check, scale.stable_gross()) {
+        state.weight_check = None;
+        check_restored_weight(expected, gross, tolerance, state);
+    }
+    let tare_mode = (scale.soft_tare_depth() > 0).then(|| scale.display_mode());
+    let hold = scale.hold_state();
+    // Only the display holds the weight within the noise
+    let shown =
+        scale.round_to_resolution(state.deadband.apply(sample.grams_filtered, sample.stable));
+    let changed = state.grams != Some(shown) || state.tare_mode != tare_mode;
+    state.dirty |= state.hold != hold || state.quality != sample.quality;
+    state.quality = sample.quality;
+    state.grams = Some(shown);
+    state.tare_mode = tare_mode;
+    state.hold = hold;
+    state.unit = scale.unit();
+    state.resolution = scale.resolution();
+    state.kilo.update(shown);
+    state
+        .idle
+        .on_weight(shown, sample.stable, changed, Instant::now());
+    // The noise of a stable weight is no activity
+    if changed && !sample.stable {
+        state.idle_stages.on_activity(Instant::now());
+    }
+    state.flow.add(sample.grams_filtered, Instant::now());
+    if let Some(soak) = &mut state.soak {
+        let temperature = imu::temperature();
+        if let Some(point) = soak.on_sample(
+            sample.grams_filtered,
+            sample.raw,
+            temperature,
+            Instant::now(),
+        ) {
+            println!("{}", point.to_csv());
+            #[cfg(feature = "esp")]
+            if let Some(datalog) = &services.datalog {
+                let record = Record::now(point.grams, sample.stable, sample.quality).soak();
+                if let Err(err) = datalog.append(record) {
+                    warn!("Failed to log the soak: {:?}", err);
+                }
+            }
+        }
+    }
+    // A disturbed or overloaded weight neither trips nor clears an alarm
+    if sample.quality.is_good() {
+        let events = state.alarms.on_stable(grams, Instant::now());
+        handle_alarm_events(events, state, services);
+    }
+    if sample.stable && state.sessions.on_stable(grams) {
+        debug!("Weighing {} counted", state.sessions.session_stats().count);
+        state.button_watch.on_weighing();
+        state.dirty |= state.page == PageId::Session;
+    }
+    // Held weights and the brew or recipe modes are no leftover tare
+    let event = match scale.gross() {
+        Some(gross) if matches!(state.mode, Mode::Weighing) && hold == HoldState::Live => state
+            .negative
+            .on_weight(gross, sample.stable, Instant::now()),
+        _ => state.negative.reset(),
+    };
+    handle_negative_event(event, scale, state, services);
+    match &mut state.mode {
+        // The timer runs on every sample, so it is always redrawn
+        Mode::Brew(brew) => {
+            brew.on_weight(sample.grams_filtered, Instant::now());
+            state.frames.on_update();
+        }
+        Mode::Recipe(recipe) => {
+            recipe.on_weight(grams);
+            if changed {
+                state.frames.on_update();
+            }
+        }
+        Mode::Weighing => {
+            if changed && state.sinks.throttle(CSV_SINK) == Some(Throttle::Off) {
+                debug!("Weight: {}g", shown);
+            }
+            // The flow rate moves on every sample
+            if changed || state.page == PageId::Flow {
+                state.frames.on_update();
+            }
+        }
+    }
+}
+
+/// Show or clear the hint to tare again, or tare right away
+fn handle_negative_event(
+    event: Option<NegativeEvent>,
+    scale: &Scale,
+    state: &mut AppState,
+    services: &Services,
+) {
+    match event {
+        Some(NegativeEvent::ShowHint) => {
+            info!("Reading below zero, hinting to tare again");
+            state.dirty = true;
+        }
+        Some(NegativeEvent::ClearHint) => state.dirty = true,
+        Some(NegativeEvent::Tare) => {
+            info!("Reading below zero, taring again");
+            request_tare(scale, state, services, false);
+        }
+        None => {}
+    }
+}
+
+/// Print the trace kept, as `trace load` takes it back
+fn print_trace(state: &AppState) {
+    println!("{}", TRACE_CSV_HEADER);
+    for point in &state.trace {
+        println!("{}", point.to_csv());
+        // A long trace takes longer than the timeout to print
+        state.watchdog.feed();
+    }
+}
+
+/// Weigh the trace kept again and print the outcome of every reading
+fn replay_trace(
+    window: Option<usize>,
+    band: Option<f32>,
+    scale: &Scale,
+    settings: &Settings,
+    state: &AppState,
+    services: &Services,
+) {
+    let mut pipeline = scale.replay_pipeline(window, band);
+    let rows = trace::replay(
+        &state.trace,
+        &mut pipeline,
+        scale.resolution(),
+        services.feedback.target(),
+        settings.capacity_grams(),
+    );
+    println!("{}", REPLAY_CSV_HEADER);
+    let mut first_stable = None;
+    let mut last = None;
+    for row in rows {
+        println!("{}", row.to_csv());
+        state.watchdog.feed();
+        if row.weighed.stable && first_stable.is_none() {
+            first_stable = Some(row.point.ms);
+        }
+        last = Some(row.weighed.gross);
+    }
+    let filter = &pipeline.filter;
+    println!(
+        "window={} band={} first_stable_ms={} last_grams={:.3}",
+        filter.window(),
+        filter.stable_band(),
+        first_stable.map_or("none".to_string(), |ms| ms.to_string()),
+        last.unwrap_or_default()
+    );
+}
+
+/// Signal, publish and persist what the alarms did
+fn handle_alarm_events(events: Vec<AlarmEvent>, state: &mut AppState, services: &Services) {
+    if events.is_empty() {
+        return;
+    }
+    for &event in &events {
+        let description = event.config().describe();
+        match event {
+            AlarmEvent::Tripped { slot, grams, .. } => {
+                warn!("Alarm {} ({}) tripped at {}g", slot + 1, description, grams);
+            }
+            _ => info!(
+                "Alarm {} ({}) {}",
+                event.slot() + 1,
+                description,
+                event.name()
+            ),
+        }
+        if matches!(
+            event,
+            AlarmEvent::Tripped { .. } | AlarmEvent::Renotify { .. }
+        ) {
+            services.feedback.notify(Feedback::AlarmTripped);
+        }
+        services.publish_alarm(event);
+    }
+    let acknowledged = events
+        .iter()
+        .any(|event| matches!(event, AlarmEvent::Acknowledged { .. }));
+    if acknowledged && !state.alarms.is_latched() {
+        services.feedback.notify(Feedback::AlarmCleared);
+    }
+    if events.iter().any(AlarmEvent::changes_state) {
+        if let Some(store) = &services.alarm_store {
+            store.save(&state.alarms.states());
+        }
+    }
+    state.dirty = true;
+}
+
+/// Save the session stats if they changed
+fn save_sessions(state: &mut AppState, services: &Services) {
+    if !state.sessions.take_unsaved() {
+        return;
+    }
+    if let Some(store) = &services.session_store {
+        store.save(&state.sessions);
+    }
+}
+
+/// Archive the current session and start a new one, saved right away
+fn new_session(state: &mut AppState, services: &Services) {
+    let ended = state.sessions.session_stats();
+    info!(
+        "Session ended after {} weighings, {}g in total",
+        ended.count, ended.total_grams
+    );
+    state.sessions.new_session();
+    save_sessions(state, services);
+    state.dirty = true;
+}
+
+/// Acknowledge the latched alarms. Returns false when none was latched.
+fn acknowledge_alarms(state: &mut AppState, services: &Services) -> bool {
+    let events = state.alarms.acknowledge(state.grams);
+    let acknowledged = !events.is_empty();
+    handle_alarm_events(events, state, services);
+    acknowledged
+}
+
+/// Act on a button gesture, depending on the mode
+fn handle_button<DI, SIZE>(
+    button_action: ButtonAction,
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) -> Result<(), FirmwareError>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    match &mut state.mode {
+        // A press cancels the timer or dismisses its result instead of
+        // acting on the scale
+        Mode::Brew(_) => {
+            state.mode = Mode::Weighing;
+            info!("Brew timer closed");
+        }
+        Mode::Recipe(recipe) => match recipe.on_button(button_action) {
+            RecipeUpdate::Continue => {}
+            RecipeUpdate::Tare => {
+                services.feedback.set_target(recipe.target_grams());
+                start_procedure(scale.begin_tare(), state, services, false);
+            }
+            RecipeUpdate::Exit => {
+                state.mode = Mode::Weighing;
+                services
+                    .feedback
+                    .set_target(settings_store.settings().target_grams());
+                info!("Recipe closed");
+            }
+        },
+        Mode::Weighing => match ScaleAction::from(button_action) {
+            // A press only releases a held weight
+            ScaleAction::Tare if scale.release_hold() => {
+                state.hold = HoldState::Live;
+                state.dirty = true;
+            }
+            ScaleAction::Tare => {
+                request_tare(scale, state, services, false);
+            }
+            ScaleAction::NextPage => state.show_page(state.page.next(services.is_aggregating())),
+            ScaleAction::OpenMenu => {
+                let mode = run_menu(
+                    scale,
+                    text_drawer,
+                    settings_store,
+                    services,
+                    &state.watchdog,
+                )?;
+                state
+                    .idle_stages
+                    .configure(settings_store.settings().idle_timeouts());
+                state.volume = settings_store.settings().volume();
+                // The setup asks for the calibration itself
+                if (scale.needs_calibration() && mode != Some(ModeRequest::Setup))
+                    || mode == Some(ModeRequest::Calibrate)
+                {
+                    start_procedure(scale.begin_calibration(), state, services, false);
+                }
+                match mode {
+                    Some(ModeRequest::Hold) => {
+                        hold_weight(scale, state);
+                    }
+                    Some(ModeRequest::Brew) => arm_brew(scale, settings_store, state, services),
+                    Some(ModeRequest::Recipe) => start_recipe(settings_store, state),
+                    Some(ModeRequest::NewSession) => new_session(state, services),
+                    Some(ModeRequest::Linearity) => {
+                        let settings = settings_store.settings();
+                        if let Err(err) =
+                            start_linearity(&[], scale, settings, state, services, false)
+                        {
+                            state.toast = Some((err, Instant::now()));
+                        }
+                    }
+                    Some(ModeRequest::NoiseTest) => {
+                        let thresholds = settings_store.settings().noise_thresholds();
+                        let procedure = scale.begin_noise_test(thresholds);
+                        start_procedure(procedure, state, services, false);
+                    }
+                    Some(ModeRequest::Setup) => {
+                        run_setup(scale, text_drawer, settings_store, state, services)?;
+                    }
+                    Some(ModeRequest::FactoryReset) => {
+                        queue_command(&state.commands, Command::FactoryReset);
+                    }
+                    Some(ModeRequest::Calibrate) | None => {}
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Freeze the weight and bring up the weight page showing it. Returns the
+/// weight held.
+fn hold_weight(scale: &mut Scale, state: &mut AppState) -> Option<f32> {
+    let grams = scale.hold();
+    match grams {
+        Some(grams) => {
+            info!("Holding {}g", grams);
+            state.hold = scale.hold_state();
+            state.show_page(PageId::Weight);
+        }
+        None if scale.is_certified() => warn!("No hold in certified mode"),
+        None => warn!("Not enough readings to hold the weight"),
+    }
+    grams
+}
+
+/// Draw the state, once the first reading is in. Only the regions of the
+/// page that changed are redrawn unless `full_redraw` is set. Returns what
+/// the page changed, none for the screens drawn as a whole.
+fn render<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    state: &mut AppState,
+) -> Result<Option<FrameDiff>, TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    if state.full_redraw {
+        state.frame.invalidate();
+    }
+    if let Some(fraction) = state.update {
+        state.frame.invalidate();
+        return draw_update(text_drawer, fraction).map(|()| None);
+    }
+    if state.grams.is_none() {
+        return Ok(None);
+    }
+    if let Mode::Recipe(recipe) = &state.mode {
+        state.frame.invalidate();
+        return draw_recipe(text_drawer, recipe, &state.icons).map(|()| None);
+    }
+    let screen = Screen {
+        layout: *text_drawer.layout(),
+        line_height: text_drawer.line_height(),
+    };
+    let ops = state
+        .page
+        .ops(&page_input(state, text_drawer.error_count()), &screen);
+    let diff = state.frame.draw(text_drawer, &ops)?;
+    // What the panel shows is not known after a failed flush
+    if let Err(err) = text_drawer.flush() {
+        state.frame.invalidate();
+        return Err(err);
+    }
+    Ok(Some(diff))
+}
+
+/// What the pages show of the state, `draw_errors` being the failed draws
+/// of the display
+fn page_input(state: &AppState, draw_errors: u32) -> PageInput<'_> {
+    PageInput {
+        grams: state.grams,
+        hold: state.hold,
+        quality: state.quality,
+        unit: state.unit,
+        resolution: state.resolution,
+        kilo: state.kilo.is_kilo(),
+        volume: state.volume,
+        tare_mode: state.tare_mode,
+        brew: match &state.mode {
+            Mode::Brew(brew) => Some(brew),
+            _ => None,
+        },
+        grams_per_sec: state.flow.grams_per_sec(),
+        alarm: state
+            .alarms
+            .latched()
+            .next()
+            .map(|(slot, config)| (slot, config.describe())),
+        gesture_hint: state.gesture_hint.as_deref(),
+        toast: state.toast.as_ref().map(|(toast, _)| toast.as_str()),
+        negative_hint: state.negative.is_hinting(),
+        title_shown: state.title_shown,
+        icons: &state.icons,
+        page_turn: state.page_turn,
+        uptime: state.start_time.elapsed(),
+        battery: state.status.battery,
+        log_records: state.status.log_records,
+        dropped: state.sinks.stats().map(|(_, stats)| stats.dropped).sum(),
+        draw_errors,
+        bumps: imu::is_running().then(|| (imu::bumps(), bumped_samples())),
+        session: state.sessions.session_stats(),
+        wifi: state.status.wifi,
+        hostname: &state.status.hostname,
+        mqtt: state.status.mqtt,
+        diagnostics: state
+            .diag
+            .as_ref()
+            .map(|(_, diag)| diag.display_lines())
+            .unwrap_or_default(),
+        deadband: state.deadband.describe(),
+        sink_errors: state
+            .sinks
+            .stats()
+            .filter(|(_, stats)| stats.errors > 0)
+            .map(|(name, stats)| stats.describe(name))
+            .collect(),
+        button_note: state.button_watch.describe(Instant::now()),
+        noise: state.noise.as_ref().map(NoiseReport::describe),
+        audit: certified::audit(),
+        aggregate: state.aggregate.as_ref(),
+    }
+}
+
+/// Let the user know the firmware stopped on `err`: on the display when it
+/// works, through the feedback devices anyway
+pub fn show_fatal_error<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    feedback: &FeedbackDispatcher,
+    err: &FirmwareError,
+) where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    feedback.notify(Feedback::Fault);
+    let prompt = text_drawer.layout().prompt.top_left;
+    // The description may not fit on the smaller panel
+    let drawn = match err {
+        // Stays up for good, name what to look at
+        FirmwareError::SelfTest { check, detail } => text_drawer.draw_text_clear_flush(
+            &trf(StringId::CheckFailed, &[&check.label(), &detail]),
+            prompt,
+        ),
+        _ => text_drawer
+            .draw_text_clear_flush(&trf(StringId::Error, &[&err]), prompt)
+            .or_else(|_| text_drawer.draw_text_clear_flush(tr(StringId::ErrorRestarting), prompt)),
+    };
+    if let Err(err) = drawn {
+        warn!("Failed to show the error: {:?}", err);
+    }
+}
+
+/// Procedure to run at boot, none when the saved tare is restored instead
+fn startup_procedure(
+    scale: &mut Scale,
+    settings: &Settings,
+    state: &mut AppState,
+) -> Option<Procedure> {
+    // The calibration tares the empty scale first
+    if scale.needs_calibration() {
+        return Some(scale.begin_calibration());
+    }
+    let mode = settings.startup_mode();
+    if mode == StartupMode::TareOnBoot {
+        return Some(scale.begin_tare());
+    }
+    if !scale.restore_offset() {
+        warn!("No tare saved yet, taring the scale");
+        return Some(scale.begin_tare());
+    }
+    if mode == StartupMode::RestoreLastWeight {
+        state.weight_check = state
+            .last_weight
+            .map(|grams| (grams, settings.startup_tolerance_grams()));
+    }
+    None
+}
+
+/// Warn when the first stable weight after a restart is not the one saved
+/// before it, the load changed while the power was off or the zero moved
+fn check_restored_weight(expected: f32, grams: f32, tolerance: f32, state: &mut AppState) {
+    let moved = grams - expected;
+    if moved.abs() <= tolerance {
+        info!("Restored weight {}g matches the saved {}g", grams, expected);
+        return;
+    }
+    warn!(
+        "Restored weight {}g is {}g off the saved {}g",
+        grams, moved, expected
+    );
+    let text = trf(StringId::MovedOff, &[&format_args!("{:+.0}", moved)]);
+    state.toast = Some((text.to_string(), Instant::now()));
+    state.dirty = true;
+}
+
+/// Save the stable weight above the tare once it moved, not before the one
+/// saved before the restart was checked
+fn save_last_weight(scale: &Scale, state: &mut AppState, services: &Services) {
+    if state.procedure.is_some() || state.weight_check.is_some() || scale.is_demo() {
+        return;
+    }
+    let (Some(grams), Some(store)) = (scale.stable_gross(), &services.session_store) else {
+        return;
+    };
+    if matches!(state.last_weight, Some(saved) if (grams - saved).abs() < scale.resolution()) {
+        return;
+    }
+    store.save_last_weight(grams);
+    state.last_weight = Some(grams);
+}
+
+/// Flag the weight as stale once the sensor stopped converting, and reset
+/// the sensor every time it stays silent for the lost time
+fn check_sensor(scale: &mut Scale, settings: &Settings, state: &mut AppState) {
+    let age = scale.last_reading_age();
+    let stale = age >= settings.stale_reading();
+    if stale != state.stale {
+        if stale {
+            warn!("No reading from the sensor for {:?}", age);
+            state.quality = Quality::Stale;
+        } else {
+            info!("The sensor reads again");
+            state.sensor_reset = None;
+            counters::increment(Counter::SensorRecoveries);
+        }
+        state.stale = stale;
+        state.dirty = true;
+    }
+    let Some(lost) = settings.sensor_lost() else {
+        return;
+    };
+    let reset_due = !matches!(state.sensor_reset, Some(at) if at.elapsed() < lost);
+    if age >= lost && reset_due {
+        warn!("Resetting the sensor, silent for {:?}", age);
+        state.sensor_reset = Some(Instant::now());
+        if let Err(err) = scale.reset_sensor() {
+            warn!("Failed to reset the sensor: {}", err);
+        }
+        state.toast = Some((tr(StringId::SensorLost).to_string(), Instant::now()));
+        state.dirty = true;
+    }
+}
+
+/// Tell about an abnormal last reset for a moment
+#[cfg(feature = "esp")]
+fn show_reset_toast<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    resets: &ResetLog,
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let reason = resets.reason();
+    if !reason.is_abnormal() {
+        return Ok(());
+    }
+    let prompt = text_drawer.layout().prompt.top_left;
+    text_drawer.draw_text_clear_flush(
+        &format!(
+            "Recovered from\n{} (x{})",
+            reason.description(),
+            resets.count(reason)
+        ),
+        prompt,
+    )?;
+    thread::sleep(RESET_TOAST);
+    Ok(())
+}
+
+/// Tare as asked by the user, unless a tare runs or was just done
+fn request_tare(
+    scale: &Scale,
+    state: &mut AppState,
+    services: &Services,
+    reply: bool,
+) -> CommandOutcome {
+    if state.soak.is_some() {
+        return refuse_during_soak(state, reply);
+    }
+    let running = state.procedure.as_ref().map(|running| &running.procedure);
+    let reason = match state.tare_gate.admit(true, running, Instant::now()) {
+        Admission::Start => {
+            start_procedure(scale.begin_tare(), state, services, reply);
+            return CommandOutcome::Started;
+        }
+        Admission::AlreadyTared => {
+            info!("Tared moments ago, ignoring the tare");
+            state.toast = Some((tr(StringId::AlreadyTared).to_string(), Instant::now()));
+            state.dirty = true;
+            "already tared"
+        }
+        Admission::Queue | Admission::Busy => "a tare or calibration is running",
+    };
+    if reply {
+        println!("ERR {}", reason);
+    }
+    CommandOutcome::Refused(reason.to_string())
+}
+
+/// Refuse a tare or a calibration while a soak runs, as it would move the
+/// weight the drift is taken from
+fn refuse_during_soak(state: &mut AppState, reply: bool) -> CommandOutcome {
+    let reason = "a soak test is running";
+    info!("Refused, {}", reason);
+    state.toast = Some((tr(StringId::SoakRunning).to_string(), Instant::now()));
+    state.dirty = true;
+    if reply {
+        println!("ERR {}", reason);
+    }
+    CommandOutcome::Refused(reason.to_string())
+}
+
+/// Refuse a command that would alter the readings in certified mode, e.g.
+/// the factory reset of the menu, showing why on the display too
+fn refuse_while_certified(state: &mut AppState) -> CommandOutcome {
+    state.toast = Some((tr(StringId::CertifiedOn).to_string(), Instant::now()));
+    state.dirty = true;
+    refuse("certified mode is on")
+}
+
+/// Hand the readings and the display to a tare or a calibration, letting
+/// the feedback devices know while it takes. Ignored while another one runs
+/// or a soak test.
+fn start_procedure(procedure: Procedure, state: &mut AppState, services: &Services, reply: bool) {
+    if state.procedure.is_some() {
+        warn!("A tare or calibration is already running");
+        return;
+    }
+    if state.soak.is_some() {
+        refuse_during_soak(state, reply);
+        return;
+    }
+    // The weight goes to zero without anything being taken off, the
+    // linearity check keeps the tare it started with, the noise test does
+    // not tare at all
+    if !procedure.is_linearity() && !procedure.is_noise() {
+        state.sessions.on_tare();
+    }
+    services.feedback.notify(
+        if procedure.is_calibration() || procedure.is_linearity() || procedure.is_noise() {
+            Feedback::Calibrating
+        } else {
+            Feedback::Taring
+        },
+    );
+    if procedure.is_calibration() {
+        set_calibration_status(procedure.status(), state, services);
+    }
+    state.procedure = Some(RunningProcedure { procedure, reply });
+}
+
+/// Keep the calibration status, sharing it right away as the snapshot is
+/// not refreshed while a procedure runs
+fn set_calibration_status(status: CalibrationStatus, state: &mut AppState, services: &Services) {
+    if state.calibration == status {
+        return;
+    }
+    state.calibration = status;
+    let mut snapshot = services.snapshot.get();
+    snapshot.calibration = status;
+    services.snapshot.set(snapshot);
+}
+
+/// Go on with a remote calibration waiting for a step, showing its next
+/// prompt. Returns whether there was one waiting.
+fn step_remote_calibration<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    state: &mut AppState,
+    services: &Services,
+) -> Result<bool, TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let Some(running) = &mut state.procedure else {
+        return Ok(false);
+    };
+    if !running.procedure.is_remote() || !running.procedure.step() {
+        return Ok(false);
+    }
+    if let ProcedureState::Running(Some(request)) = running.procedure.advance(None) {
+        show_ui_request(text_drawer, &request)?;
+    }
+    let status = running.procedure.status();
+    set_calibration_status(status, state, services);
+    Ok(true)
+}
+
+/// Advance the running procedure with the event, showing its prompts, and
+/// apply its result once it is over
+fn advance_procedure<DI, SIZE>(
+    event: AppEvent,
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    state: &mut AppState,
+    services: &Services,
+) -> Result<(), FirmwareError>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let Some(running) = &mut state.procedure else {
+        return Ok(());
+    };
+    let result = match running.procedure.advance(Some(event)) {
+        ProcedureState::Running(request) => {
+            match request {
+                Some(request) => show_ui_request(text_drawer, &request)?,
+                None => text_drawer.tick()?,
+            }
+            if running.procedure.is_calibration() {
+                let status = running.procedure.status();
+                set_calibration_status(status, state, services);
+            }
+            return Ok(());
+        }
+        ProcedureState::Done(result) => Ok(result),
+        ProcedureState::Failed(err) => Err(err),
+    };
+    let is_calibration = running.procedure.is_calibration();
+    let is_linearity = running.procedure.is_linearity();
+    let is_noise = running.procedure.is_noise();
+    let reply = running.reply;
+    state.procedure = None;
+    state.dirty = true;
+    state.full_redraw = true;
+    text_drawer.stop_spinner()?;
+
+    match result {
+        Ok(result) => {
+            scale.finish(result);
+            if let ProcedureResult::Tared { .. } = result {
+                state.tare_gate.tared(Instant::now());
+                state.negative.reset();
+                state.deadband.reset();
+            }
+            if let ProcedureResult::Calibrated { scale_factor, .. } = result {
+                set_calibration_status(CalibrationStatus::Done { scale_factor }, state, services);
+            }
+            if let ProcedureResult::Linearity(report) = &result {
+                // The table goes out over serial however the check started
+                print_linearity(report);
+                state.toast = Some((report.describe(), Instant::now()));
+            }
+            if let ProcedureResult::Noise(report) = result {
+                print_noise(&report);
+                if let Some(rms_grams) = report.rms_grams() {
+                    state
+                        .deadband
+                        .on_noise_test(rms_grams, scale.filter_window());
+                }
+                state.toast = Some((report.describe(), Instant::now()));
+                state.noise = Some(report);
+            }
+            if reply {
+                match result {
+                    ProcedureResult::Tared { .. }
+                    | ProcedureResult::Linearity(_)
+                    | ProcedureResult::Noise(_) => println!("OK"),
+                    ProcedureResult::Calibrated { scale_factor, .. } => {
+                        println!("OK factor={}", scale_factor)
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            scale.clear_button_events();
+            if is_calibration {
+                set_calibration_status(CalibrationStatus::Failed(err), state, services);
+            }
+            if err == ProcedureError::Cancelled {
+                let text = tr(if is_linearity || is_noise {
+                    StringId::CheckCancelled
+                } else {
+                    StringId::CalibrationCancelled
+                });
+                state.toast = Some((text.to_string(), Instant::now()));
+            } else if err == ProcedureError::Disturbed {
+                state.toast = Some((tr(StringId::ScaleMoved).to_string(), Instant::now()));
+                services.feedback.notify(Feedback::CalibrationFailed);
+            } else if is_calibration || is_linearity || is_noise {
+                services.feedback.notify(Feedback::CalibrationFailed);
+            }
+            if reply {
+                println!("ERR {}", err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Start checking the linearity with the known weights, the saved ones when
+/// none are given. Returns why it did not start.
+fn start_linearity(
+    weights: &[f32],
+    scale: &mut Scale,
+    settings: &Settings,
+    state: &mut AppState,
+    services: &Services,
+    reply: bool,
+) -> Result<(), String> {
+    let weights = if weights.is_empty() {
+        settings.linearity_weights()
+    } else {
+        weights.to_vec()
+    };
+    if weights.len() < MIN_LINEARITY_WEIGHTS {
+        return Err(format!("{} weights needed", MIN_LINEARITY_WEIGHTS));
+    }
+    let heaviest = weights.iter().copied().fold(0.0, f32::max);
+    let full_scale_grams = settings.capacity_grams().unwrap_or(heaviest);
+    start_procedure(
+        scale.begin_linearity(&weights, full_scale_grams),
+        state,
+        services,
+        reply,
+    );
+    Ok(())
+}
+
+/// Print the table of a linearity check and its largest deviation
+fn print_linearity(report: &LinearityReport) {
+    println!("{}", LINEARITY_TABLE_HEADER);
+    for line in report.table() {
+        println!("{}", line);
+    }
+    println!(
+        "max_deviation={:.2}g max_deviation_percent={:.3} full_scale={}g",
+        report.max_deviation_grams(),
+        report.max_deviation_percent(),
+        report.full_scale_grams
+    );
+}
+
+/// Print the spread of a noise test and its verdict
+fn print_noise(report: &NoiseReport) {
+    println!(
+        "noise verdict={} samples={} rms_counts={:.1} p2p_counts={}",
+        report.verdict.name(),
+        report.samples,
+        report.rms_counts,
+        report.peak_to_peak_counts
+    );
+    if let (Some(rms), Some(p2p)) = (report.rms_grams(), report.peak_to_peak_grams()) {
+        println!("rms_grams={:.4} p2p_grams={:.4}", rms, p2p);
+    }
+    println!(
+        "epoch_s={} boot={}",
+        report.measured.epoch_s, report.measured.boot
+    );
+}
+
+/// Flash the display inverted on the feedback, if the settings ask for it
+fn flash_display<DI, SIZE>(
+    feedback: Feedback,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings: &Settings,
+) where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    match feedback {
+        Feedback::TargetReached if settings.flash_target() => {
+            text_drawer.flash_invert(TARGET_FLASHES, TARGET_FLASH_PERIOD)
+        }
+        Feedback::Overload if settings.flash_overload() => {
+            text_drawer.flash_invert(OVERLOAD_FLASHES, OVERLOAD_FLASH_PERIOD)
+        }
+        _ => {}
+    }
+}
+
+/// End the soak, printing and showing its drift
+fn finish_soak(state: &mut AppState) {
+    let Some(soak) = state.soak.take() else {
+        return;
+    };
+    let report = soak.report();
+    info!(
+        "Soak over after {:.2}h: {}",
+        report.hours,
+        report.describe()
+    );
+    print_soak(&report);
+    state.toast = Some((report.describe(), Instant::now()));
+    state.dirty = true;
+    state.soak_report = Some(report);
+}
+
+/// Print the drift of a soak
+fn print_soak(report: &SoakReport) {
+    let format_drift =
+        |drift: Option<f32>| drift.map_or("none".to_string(), |drift| format!("{:+.4}", drift));
+    println!("soak points={} hours={:.2}", report.points, report.hours);
+    if let Some((low, high)) = report.grams {
+        println!("grams_min={:.3} grams_max={:.3}", low, high);
+    }
+    if let Some((low, high)) = report.temperature_c {
+        println!("temperature_min_c={:.2} temperature_max_c={:.2}", low, high);
+    }
+    println!(
+        "drift_g_per_hour={} drift_g_per_c={}",
+        format_drift(report.drift_per_hour),
+        format_drift(report.drift_per_degree)
+    );
+}
+
+/// The certified mode, its audit counter and what it locks
+fn print_certified() {
+    let audit = certified::audit();
+    println!(
+        "certified={} audit={} corrupt={}",
+        if audit.active { "on" } else { "off" },
+        audit.count,
+        audit.corrupt
+    );
+    if audit.active {
+        println!("restrictions={}", RESTRICTIONS.join(","));
+    }
+}
+
+/// Print the trace of a creep measurement and the model fitted to it, for
+/// `creep set`
+fn report_creep(result: Result<CreepReport, CreepError>, state: &mut AppState) {
+    let text = match result {
+        Ok(report) => {
+            println!("{}", CREEP_TABLE_HEADER);
+            for line in report.table() {
+                println!("{}", line);
+            }
+            let model = report.model;
+            info!(
+                "Creep after a step of {:.0}g: {}",
+                report.step_grams,
+                model.describe()
+            );
+            println!(
+                "step={:.0}g time_constant_s={:.0} percent={:.3}, apply with creep set {:.0} {:.3}",
+                report.step_grams,
+                model.time_constant.as_secs_f32(),
+                model.fraction * 100.0,
+                model.time_constant.as_secs_f32(),
+                model.fraction * 100.0
+            );
+            tr(StringId::CreepMeasured).to_string()
+        }
+        Err(err) => {
+            warn!("Creep measurement failed: {}", err);
+            err.to_string()
+        }
+    };
+    state.toast = Some((text, Instant::now()));
+    state.dirty = true;
+}
+
+/// The sensor fitted and the gain it runs at, which an imported calibration
+/// has to match
+fn sensor_gain(settings: &Settings) -> (SensorKind, u8) {
+    match settings.sensor() {
+        SensorKind::Hx711 => (SensorKind::Hx711, HX711_GAIN),
+        SensorKind::Nau7802 => (SensorKind::Nau7802, settings.nau7802_gain()),
+    }
+}
+
+/// Show a prompt of the running procedure in place of the page, or on the
+/// console without a panel
+fn show_ui_request<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    request: &UiRequest,
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    if display::is_headless() {
+        LogPrompter.prompt(request);
+        return Ok(());
+    }
+    let prompt = text_drawer.layout().prompt.top_left;
+    text_drawer.stop_spinner()?;
+    match request {
+        UiRequest::Prompt(text) => text_drawer.draw_text_clear_flush(text, prompt),
+        UiRequest::Busy(text) => {
+            text_drawer.draw_text_clear_flush(text, prompt)?;
+            // Place the spinner right after the prompt
+            let width = text_drawer
+                .measure_text(&format!("{} ", text), &TextStyle::default())
+                .width;
+            text_drawer.start_spinner(prompt + Point::new(width as i32, 0))
+        }
+    }
+}
+
+/// Tare and arm the brew timer, shown on the flow page
+fn arm_brew(
+    scale: &Scale,
+    settings_store: &SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) {
+    start_procedure(scale.begin_tare(), state, services, false);
+    state.mode = Mode::Brew(BrewTimer::arm(BrewConfig::from_settings(
+        settings_store.settings(),
+    )));
+    state.show_page(PageId::Flow);
+    info!("Brew timer armed");
+}
+
+/// Hand the button and the display to the recipe assistant
+fn start_recipe(settings_store: &SettingsStore, state: &mut AppState) {
+    let settings = settings_store.settings();
+    state.mode = Mode::Recipe(Recipe::new(
+        settings.recipe_dose_grams(),
+        settings.recipe_ratio(),
+    ));
+    info!("Recipe started");
+}
+
+/// Print the reason a command is refused, as its outcome
+fn refuse(reason: impl Into<String>) -> CommandOutcome {
+    let reason = reason.into();
+    println!("ERR {}", reason);
+    CommandOutcome::Refused(reason)
+}
+
+/// Execute a command of any frontend, printing its response. The outcome
+/// tells the frontend waiting for it whether the command ran or why not.
+fn handle_command<DI, SIZE>(
+    command: Command,
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) -> Result<CommandOutcome, FirmwareError>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let (pin, command) = match command {
+        Command::WithPin(pin, command) => (Some(pin), *command),
+        command => (None, command),
+    };
+    if command.is_guarded() {
+        if let Err(err) = services.lock.authorize(pin) {
+            println!("ERR {}", err);
+            return Ok(CommandOutcome::Locked(err));
+        }
+    }
+    let running = state.procedure.as_ref().map(|running| &running.procedure);
+    let queue = state.tare_gate.admit(false, running, Instant::now()) == Admission::Queue;
+    let starts_procedure = matches!(
+        command,
+        Command::Calibrate { .. }
+            | Command::RemoteCalibration(RemoteCalibration::Start(_))
+            | Command::Linearity(LinearityCommand::Start(_))
+            | Command::Noise(NoiseCommand::Test)
+    );
+    match command {
+        // The weight the drift is taken from stays as it is until the end
+        Command::Tare
+        | Command::SoftTare(_)
+        | Command::Calibrate { .. }
+        | Command::RemoteCalibration(RemoteCalibration::Start(_))
+        | Command::ImportCalibration(_)
+        | Command::Linearity(LinearityCommand::Start(_))
+        | Command::Noise(NoiseCommand::Test)
+        | Command::Creep(CreepCommand::Measure)
+            if state.soak.is_some() =>
+        {
+            return Ok(refuse_during_soak(state, true));
+        }
+        // Nothing alters the readings on its own while certified, an import
+        // or a factory reset could turn any of it back on
+        Command::Hold
+        | Command::SetResolution(_)
+        | Command::SetAutoHold(_)
+        | Command::Creep(CreepCommand::Measure | CreepCommand::Set(_))
+        | Command::ImportSettings(_)
+        | Command::FactoryReset
+            if scale.is_certified() =>
+        {
+            return Ok(refuse_while_certified(state));
+        }
+        // Started once the tare is done instead of interleaving with it, the
+        // response is printed then
+        Command::Calibrate { .. }
+        | Command::RemoteCalibration(RemoteCalibration::Start(_))
+        | Command::ImportCalibration(_)
+        | Command::Linearity(LinearityCommand::Start(_))
+        | Command::Noise(NoiseCommand::Test)
+            if queue =>
+        {
+            let command = match pin {
+                Some(pin) => Command::WithPin(pin, Box::new(command)),
+                None => command,
+            };
+            if state.tare_gate.queue(command).is_err() {
+                return Ok(refuse("a calibration is already waiting for the tare"));
+            }
+            info!("Waiting for the tare to finish");
+            return Ok(CommandOutcome::Queued);
+        }
+        // The response is printed once the procedure is over
+        Command::Calibrate { .. }
+        | Command::RemoteCalibration(RemoteCalibration::Start(_))
+        | Command::ImportCalibration(_)
+        | Command::Linearity(LinearityCommand::Start(_))
+        | Command::Noise(NoiseCommand::Test)
+        | Command::Demo(DemoCommand::Start(_) | DemoCommand::Stop)
+        | Command::Soak(SoakCommand::Start { .. })
+            if state.procedure.is_some() =>
+        {
+            return Ok(refuse("a tare or calibration is running"));
+        }
+        Command::Calibrate { .. }
+        | Command::RemoteCalibration(RemoteCalibration::Start(_))
+        | Command::ImportCalibration(_)
+        | Command::Linearity(LinearityCommand::Start(_))
+        | Command::Noise(NoiseCommand::Test)
+        | Command::Creep(CreepCommand::Measure)
+            if scale.is_demo() =>
+        {
+            return Ok(refuse("demo mode is on"));
+        }
+        Command::Tare => return Ok(request_tare(scale, state, services, true)),
+        Command::SoftTare(action) => match action {
+            SoftTareAction::Push if scale.soft_tare() => println!("OK"),
+            SoftTareAction::Push => println!(
+                "ERR no reading yet or {} soft tares stacked",
+                MAX_SOFT_TARES
+            ),
+            SoftTareAction::Pop if scale.untare() => println!("OK"),
+            SoftTareAction::Pop => println!("ERR no soft tare"),
+            SoftTareAction::Toggle => println!("{}", scale.toggle_net_gross().label()),
+            SoftTareAction::Clear => {
+                scale.clear_soft_tare();
+                println!("OK");
+            }
+        },
+        Command::Hold => match hold_weight(scale, state) {
+            Some(grams) => println!("{}", grams),
+            None => println!("ERR not enough readings"),
+        },
+        Command::ReleaseHold => {
+            scale.release_hold();
+            state.hold = HoldState::Live;
+            println!("OK");
+        }
+        Command::Calibrate { weight_grams: None } => {
+            start_procedure(scale.begin_calibration(), state, services, true)
+        }
+        Command::Calibrate {
+            weight_grams: Some(grams),
+        } => start_procedure(
+            scale.begin_calibration_with_weight(grams),
+            state,
+            services,
+            true,
+        ),
+        Command::RemoteCalibration(RemoteCalibration::Start(grams)) => {
+            start_procedure(scale.begin_remote_calibration(grams), state, services, true)
+        }
+        Command::RemoteCalibration(RemoteCalibration::Step) => {
+            if !step_remote_calibration(text_drawer, state, services)? {
+                return Ok(refuse("no remote calibration is waiting for a step"));
+            }
+            println!("OK");
+        }
+        Command::RemoteCalibration(RemoteCalibration::Status) => match state.calibration {
+            CalibrationStatus::WaitingWeight { grams } => {
+                println!("calibration={} weight={}g", state.calibration.name(), grams)
+            }
+            CalibrationStatus::Done { scale_factor } => {
+                println!(
+                    "calibration={} factor={}",
+                    state.calibration.name(),
+                    scale_factor
+                )
+            }
+            CalibrationStatus::Failed(err) => {
+                println!("calibration={} error={}", state.calibration.name(), err)
+            }
+            status => println!("calibration={}", status.name()),
+        },
+        Command::ExportCalibration => {
+            let (sensor, gain) = sensor_gain(settings_store.settings());
+            match scale.export_calibration(sensor, gain) {
+                Some(record) => println!("{}", record.encode()),
+                None => println!("ERR not calibrated"),
+            }
+        }
+        Command::ImportCalibration(record) => {
+            let (sensor, gain) = sensor_gain(settings_store.settings());
+            match scale.import_calibration(&record, sensor, gain) {
+                Ok(()) => {
+                    info!(
+                        "Imported the calibration with factor {}, made with {}g",
+                        record.scale_factor, record.weight_grams
+                    );
+                    let scale_factor = record.scale_factor;
+                    set_calibration_status(
+                        CalibrationStatus::Done { scale_factor },
+                        state,
+                        services,
+                    );
+                    state.dirty = true;
+                    state.full_redraw = true;
+                    println!("OK");
+                }
+                Err(err) => return Ok(refuse(err.to_string())),
+            }
+        }
+        Command::ImportSettings(mut settings) => {
+            settings.keep_secrets(settings_store.settings());
+            info!("Imported the settings");
+            *settings_store.settings_mut() = *settings;
+            let settings = settings_store.settings();
+            i18n::set_language(settings.language());
+            scale.apply_settings(settings);
+            state.dirty = true;
+            state.full_redraw = true;
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::Linearity(LinearityCommand::Start(weights)) => {
+            let settings = settings_store.settings();
+            if let Err(err) = start_linearity(&weights, scale, settings, state, services, true) {
+                return Ok(refuse(err.to_string()));
+            }
+        }
+        Command::Linearity(LinearityCommand::Cancel) => match &state.procedure {
+            Some(running) if running.procedure.is_linearity() => {
+                // The start is still waiting for its response
+                if running.reply {
+                    println!("ERR cancelled");
+                }
+                state.procedure = None;
+                state.dirty = true;
+                state.full_redraw = true;
+                text_drawer.stop_spinner()?;
+                scale.clear_button_events();
+                state.toast = Some((tr(StringId::CheckCancelled).to_string(), Instant::now()));
+                println!("OK");
+            }
+            _ => println!("ERR no linearity check is running"),
+        },
+        Command::Linearity(LinearityCommand::Report) => match scale.linearity() {
+            Some(report) => print_linearity(report),
+            None => println!("ERR no linearity check yet"),
+        },
+        Command::Noise(NoiseCommand::Test) => {
+            let thresholds = settings_store.settings().noise_thresholds();
+            start_procedure(scale.begin_noise_test(thresholds), state, services, true);
+        }
+        Command::Noise(NoiseCommand::Report) => match scale.noise() {
+            Some(report) => print_noise(report),
+            None => println!("ERR no noise test yet"),
+        },
+        Command::Creep(CreepCommand::Show) => match scale.creep_model() {
+            Some(model) => println!(
+                "creep time_constant_s={:.0} percent={:.3}",
+                model.time_constant.as_secs_f32(),
+                model.fraction * 100.0
+            ),
+            None => println!("creep=off"),
+        },
+        Command::Creep(CreepCommand::Measure) => {
+            scale.start_creep_measure();
+            info!("Measuring the creep, waiting for a step load");
+            state.toast = Some((tr(StringId::PlaceTheLoad).to_string(), Instant::now()));
+            println!("OK");
+        }
+        Command::Creep(CreepCommand::Set(model)) => match scale.set_creep_model(model) {
+            Ok(()) => println!("OK"),
+            Err(err) => println!("ERR {}", err),
+        },
+        Command::Trace(TraceCommand::Record(secs)) => {
+            scale.start_trace(Duration::from_secs(secs.into()));
+            info!("Recording a trace of {}s", secs);
+            println!("OK");
+        }
+        // Taken by the console task, which sends the points along
+        Command::Trace(TraceCommand::Load) => {}
+        Command::Trace(TraceCommand::Loaded(points)) => {
+            println!("OK {} points", points.len());
+            state.trace = points;
+        }
+        Command::Trace(TraceCommand::Dump) => print_trace(state),
+        Command::Trace(TraceCommand::Replay { .. }) if state.trace.is_empty() => {
+            println!("ERR no trace, record or load one")
+        }
+        Command::Trace(TraceCommand::Replay { window, band }) => replay_trace(
+            window,
+            band,
+            scale,
+            settings_store.settings(),
+            state,
+            services,
+        ),
+        Command::Raw => match scale.read_raw() {
+            Some(raw) => println!("raw={}", raw),
+            None => println!("ERR sensor not ready"),
+        },
+        Command::Factor => {
+            match scale.scale_factor() {
+                Some(scale_factor) => {
+                    println!("factor={} offset={}", scale_factor, scale.offset())
+                }
+                None => println!("factor=none offset={}", scale.offset()),
+            }
+            let (days, drift_grams) = scale.calibration_age();
+            println!("calibration_age_days={} drift={:.2}g", days, drift_grams);
+            if let Some(reason) = scale.calibration_reminder() {
+                println!("reminder={:?}", reason);
+            }
+        }
+        Command::Stats => {
+            println!("uptime_s={}", state.start_time.elapsed().as_secs());
+            println!("unit={}", scale.unit().symbol());
+            match state.volume {
+                Some(volume) => println!(
+                    "volume={} density={}",
+                    volume.substance.name(),
+                    volume.density
+                ),
+                None => println!("volume=off"),
+            }
+            println!(
+                "deadband={:.3} derived={} noise={}",
+                state.deadband.effective(),
+                state
+                    .deadband
+                    .derived()
+                    .map_or("none".to_string(), |grams| format!("{:.3}", grams)),
+                state
+                    .deadband
+                    .noise()
+                    .map_or("none".to_string(), |grams| format!("{:.3}", grams)),
+            );
+            if let DeadbandSetting::Auto { k } = state.deadband.setting() {
+                println!("deadband_k={}", k);
+            }
+            println!("resolution={}", scale.resolution());
+            println!("calibration_weight={}", scale.calibration_weight());
+            println!("locked={}", services.lock.is_locked());
+            match scale.demo_pattern() {
+                Some(pattern) => println!("demo={}", pattern.describe()),
+                None => println!("demo=off"),
+            }
+            println!("display_errors={}", text_drawer.error_count());
+            println!("display_offline={}", text_drawer.is_offline());
+            if let Some(mirror) = settings_store.settings().mirror() {
+                println!(
+                    "mirror=0x{:02X} mirror_down={}",
+                    mirror.address,
+                    text_drawer.is_mirror_down()
+                );
+            }
+            for (name, stats) in state.sinks.stats() {
+                println!(
+                    "sink_{}={} dropped={} errors={} backoff={}",
+                    name, stats.delivered, stats.dropped, stats.errors, stats.backing_off
+                );
+            }
+            if let Some(records) = services.log_records() {
+                println!("log_records={}", records);
+            }
+            #[cfg(feature = "esp")]
+            if let Some(resets) = &services.resets {
+                println!("reset_reason={}", resets.reason().name());
+                for (reason, count) in resets.counts() {
+                    println!("resets_{}={}", reason.name(), count);
+                }
+                if let Some(message) = resets.last_panic() {
+                    println!("last_panic={:?}", message);
+                }
+            }
+            if let Some(voltage) = services.battery_voltage() {
+                println!("battery_v={:.2}", voltage);
+            }
+            println!("reading_age_ms={}", scale.last_reading_age().as_millis());
+            if let Some(reading) = scale.poll_reading() {
+                println!("quality={}", reading.quality.name());
+            }
+            if let Some(celsius) = imu::temperature() {
+                println!("temperature_c={:.1}", celsius);
+            }
+            let audit = certified::audit();
+            println!("certified={} audit={}", audit.active, audit.count);
+            if imu::is_running() {
+                println!("bumps={}", imu::bumps());
+                println!("bumped_samples={}", bumped_samples());
+            }
+            #[cfg(feature = "sdcard")]
+            if let Some(sdcard) = &services.sdcard {
+                println!("sd_suspended={}", sdcard.is_suspended());
+                println!("sd_dropped={}", sdcard.dropped());
+            }
+        }
+        Command::Diagnostics => {
+            let diag = DiagSnapshot::collect();
+            println!("uptime_s={}", diag.uptime.as_secs());
+            println!("free_heap={}", diag.free_heap);
+            println!("min_free_heap={}", diag.min_free_heap);
+            println!("largest_free_block={}", diag.largest_free_block);
+            println!("quiesced_samples={}", diag.quiesced_samples);
+            println!("fps={:.1}", diag.fps);
+            println!("skipped_frames={}", diag.skipped_frames);
+            for (counter, value) in diag.counters {
+                println!("{}={}", counter.name(), value);
+            }
+            for (subsystem, suppression) in &diag.suppressed {
+                println!("suppressed_{}={}", subsystem.name(), suppression.name());
+                println!(
+                    "suppressed_{}_free_heap={}",
+                    subsystem.name(),
+                    suppression.free_heap()
+                );
+            }
+            for task in &diag.tasks {
+                println!(
+                    "stack_free_{}={}",
+                    task.name.replace(' ', "_"),
+                    task.free_min
+                );
+            }
+        }
+        Command::Counters => {
+            for (counter, value) in counters::values() {
+                println!("{}={}", counter.name(), value);
+            }
+        }
+        Command::ResetCounter(counter) => match counters::reset(counter) {
+            true => println!("OK"),
+            false => println!("ERR failed to reset the {} counter", counter.name()),
+        },
+        Command::WhoAmI => {
+            let identity = device::identity();
+            println!("device_id={}", identity.device_id());
+            println!("boot={}", identity.boot());
+            println!("seq={}", device::last_seq());
+            println!(
+                "hostname={}",
+                device::hostname(settings_store.settings().hostname())
+            );
+        }
+        Command::SetUnit(unit) => {
+            scale.set_unit(unit);
+            settings_store.settings_mut().set_unit(unit);
+            save_settings(settings_store);
+        }
+        Command::SetVolume(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                VolumeSetting::Shown(shown) => settings.set_volume_shown(shown),
+                VolumeSetting::Substance(substance) => settings.set_substance(substance),
+                VolumeSetting::Density(density) => {
+                    settings.set_custom_density(density);
+                    settings.set_substance(Substance::Custom);
+                }
+            }
+            // The weight stays as it is, only how it shows changes
+            state.volume = settings.volume();
+            state.dirty = true;
+            save_settings(settings_store);
+        }
+        Command::SetDeadband(setting) => {
+            settings_store.settings_mut().set_deadband(setting);
+            state.deadband.configure(setting);
+            save_settings(settings_store);
+        }
+        Command::SetResolution(grams) => {
+            scale.set_resolution(grams);
+            settings_store.settings_mut().set_resolution(grams);
+            save_settings(settings_store);
+        }
+        Command::SetCalibrationWeight(grams) => {
+            scale.set_calibration_weight(grams);
+            settings_store.settings_mut().set_calibration_weight(grams);
+            save_settings(settings_store);
+        }
+        Command::Stream(rate) => {
+            println!("OK");
+            state.sinks.set_throttle(CSV_SINK, rate.into());
+        }
+        Command::SetWifi { ssid, password } => {
+            settings_store
+                .settings_mut()
+                .set_wifi_credentials(&ssid, &password);
+            save_settings(settings_store);
+            if !services.connect_wifi(&ssid, &password) {
+                println!("Restart to apply");
+            }
+        }
+        Command::SetHostname(hostname) => {
+            settings_store
+                .settings_mut()
+                .set_hostname(hostname.as_deref());
+            save_settings(settings_store);
+            let hostname = device::hostname(hostname.as_deref());
+            if !services.set_hostname(&hostname) {
+                println!("Restart to apply");
+            }
+        }
+        Command::SetUtcOffset(minutes) => {
+            settings_store
+                .settings_mut()
+                .set_utc_offset_minutes(minutes);
+            save_settings(settings_store);
+        }
+        Command::SetMqtt(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                MqttSetting::Broker(url) => settings.set_mqtt_broker_url(&url),
+                MqttSetting::Credentials { username, password } => {
+                    settings.set_mqtt_credentials(&username, &password)
+                }
+                MqttSetting::TopicPrefix(prefix) => settings.set_mqtt_topic_prefix(&prefix),
+                MqttSetting::IntervalSecs(secs) => {
+                    settings.set_mqtt_interval(Some(Duration::from_secs(secs.into())))
+                }
+                MqttSetting::MinDeltaGrams(grams) => settings.set_mqtt_min_delta_grams(grams),
+                MqttSetting::DiagIntervalSecs(secs) => {
+                    settings.set_mqtt_diag_interval(Some(Duration::from_secs(secs.into())))
+                }
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetWebhook(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                WebhookSetting::Url(url) => settings.set_webhook_url(url.as_deref()),
+                WebhookSetting::ThresholdGrams(grams) => {
+                    settings.set_webhook_threshold_grams(grams)
+                }
+                WebhookSetting::EmptySecs(secs) => settings.set_webhook_empty_secs(secs),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetHeap(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                HeapSetting::ReserveKb(kb) => settings.set_heap_reserve_kb(kb),
+                HeapSetting::CriticalKb(kb) => settings.set_heap_critical_kb(kb),
+            }
+            governor::configure(settings.heap_reserve_kb(), settings.heap_critical_kb());
+            save_settings(settings_store);
+        }
+        Command::SetLog(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                LogSetting::IntervalSecs(secs) => {
+                    settings.set_datalog_interval(Some(Duration::from_secs(secs.into())))
+                }
+                LogSetting::Retention(records) => settings.set_datalog_retention(records),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetSdCard(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                SdCardSetting::Enabled(enabled) => settings.set_sd_card_enabled(enabled),
+                SdCardSetting::Pins(pins) => settings.set_sd_card_pins(pins),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetBattery(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                BatterySetting::Divider(ratio) => settings.set_battery_divider(ratio),
+                BatterySetting::CutoffVolts(volts) => settings.set_battery_cutoff(volts),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetBuzzer(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                BuzzerSetting::Enabled(enabled) => settings.set_buzzer_enabled(enabled),
+                BuzzerSetting::Volume(percent) => settings.set_buzzer_volume(percent),
+                BuzzerSetting::Pin(pin) => settings.set_buzzer_pin(pin),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetSensor(SensorSetting::Rate(rate)) => match scale.set_sample_rate(rate) {
+            Ok(()) => println!("OK"),
+            Err(err) => println!("ERR {}", err),
+        },
+        Command::SetSensor(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                SensorSetting::Kind(sensor) => settings.set_sensor(sensor),
+                SensorSetting::Nau7802Gain(gain) => settings.set_nau7802_gain(gain),
+                SensorSetting::RatePin(pin) => settings.set_hx711_rate_pin(pin),
+                // Applied by the scale, which saves it
+                SensorSetting::Rate(_) => return Ok(CommandOutcome::Done),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetQuiesce(mode) => {
+            settings_store.settings_mut().set_quiesce(mode);
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetAutoHold(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                AutoHoldSetting::BandGrams(grams) => settings.set_auto_hold_band(grams),
+                AutoHoldSetting::Secs(secs) => settings.set_auto_hold_time(secs),
+            }
+            scale.apply_settings(settings);
+            save_settings(settings_store);
+        }
+        Command::SetInput(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                InputSetting::Mode(InputMode::Weight) if settings.capacity_grams().is_none() => {
+                    return Ok(refuse("set the capacity first"));
+                }
+                InputSetting::Mode(mode) => settings.set_input_mode(mode),
+                InputSetting::ThresholdPercent(percent) => {
+                    settings.set_gesture_threshold_percent(percent)
+                }
+                InputSetting::Press(press) => settings.set_gesture_press(press),
+                InputSetting::Window(window) => settings.set_gesture_window(window),
+            }
+            scale.apply_settings(settings);
+            save_settings(settings_store);
+        }
+        Command::SetBumpThreshold(threshold_g) => {
+            settings_store
+                .settings_mut()
+                .set_bump_threshold(threshold_g);
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetLowPower(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                LowPowerSetting::Enabled(enabled) => settings.set_low_power(enabled),
+                LowPowerSetting::AfterSecs(secs) => settings.set_low_power_time(secs),
+                LowPowerSetting::WakeGrams(grams) => settings.set_wake_grams(grams),
+            }
+            save_settings(settings_store);
+        }
+        Command::SetIdle(stage, secs) => {
+            if stage == IdleStage::DeepSleep && secs > 0 {
+                if let Err(err) = settings_store.settings().board_pins().validate_wake() {
+                    return Ok(refuse(format!("{}, `set pin button <gpio>` first", err)));
+                }
+            }
+            let settings = settings_store.settings_mut();
+            settings.set_idle_timeout(stage, secs);
+            state.idle_stages.configure(settings.idle_timeouts());
+            save_settings(settings_store);
+        }
+        Command::Schedule => {
+            let schedule = settings_store.settings().schedule();
+            if schedule.is_empty() {
+                println!("schedule=off");
+            }
+            for (index, window) in schedule.windows().iter().enumerate() {
+                println!("window{}={}", index + 1, window);
+            }
+            let offset = settings_store.settings().utc_offset_minutes();
+            match Timestamp::now_with_offset(offset).local_secs() {
+                Some(local_secs) if !schedule.is_empty() => {
+                    println!("active={}", schedule.is_active(local_secs));
+                    if let Some(wait) = schedule.until_next_start(local_secs) {
+                        println!("next_start_s={}", wait.as_secs());
+                    }
+                }
+                Some(_) => {}
+                None => println!("time=unknown"),
+            }
+        }
+        Command::Setup if display::is_headless() => {
+            return Ok(refuse("no display"));
+        }
+        Command::Setup if state.procedure.is_some() => {
+            return Ok(refuse("a tare or calibration is running"));
+        }
+        Command::Setup => {
+            run_setup(scale, text_drawer, settings_store, state, services)?;
+            println!("OK");
+        }
+        Command::SetSchedule(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                ScheduleSetting::Add(window) => {
+                    if !settings.add_schedule_window(window) {
+                        return Ok(refuse(format!(
+                            "at most {} windows, `set schedule off` clears them",
+                            MAX_SCHEDULE_WINDOWS
+                        )));
+                    }
+                }
+                ScheduleSetting::Clear => settings.clear_schedule(),
+            }
+            state.schedule.configure(settings.schedule());
+            save_settings(settings_store);
+        }
+        Command::SetLanguage(language) => {
+            settings_store.settings_mut().set_language(language);
+            i18n::set_language(language);
+            state.dirty = true;
+            save_settings(settings_store);
+        }
+        Command::SetMaxFps(fps) => {
+            settings_store.settings_mut().set_max_fps(fps);
+            state.frames.configure(fps);
+            save_settings(settings_store);
+        }
+        Command::SetFlash(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                FlashSetting::Target(enabled) => settings.set_flash_target(enabled),
+                FlashSetting::Overload(enabled) => settings.set_flash_overload(enabled),
+            }
+            save_settings(settings_store);
+        }
+        Command::SetMirror(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                MirrorSetting::Off => settings.set_mirror_enabled(false),
+                MirrorSetting::Address(address) => {
+                    settings.set_mirror_address(address);
+                    settings.set_mirror_enabled(true);
+                }
+                MirrorSetting::QuarterTurns(turns) => settings.set_mirror_quarter_turns(turns),
+                MirrorSetting::Pins(pins) => settings.set_mirror_pins(pins),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetStartup(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                StartupSetting::Mode(mode) => settings.set_startup_mode(mode),
+                StartupSetting::ToleranceGrams(grams) => settings.set_startup_tolerance(grams),
+            }
+            save_settings(settings_store);
+        }
+        Command::SetStale(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                StaleSetting::Millis(millis) => {
+                    settings.set_stale_reading(millis);
+                    scale.set_stale_reading(settings.stale_reading());
+                }
+                StaleSetting::LostSecs(secs) => settings.set_sensor_lost(secs),
+            }
+            save_settings(settings_store);
+        }
+        Command::SetButton(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                ButtonSetting::StuckSecs(secs) => {
+                    settings.set_button_stuck(secs);
+                    println!("Restart to apply");
+                }
+                ButtonSetting::DisconnectHours(hours) => {
+                    settings.set_button_disconnect(hours);
+                    state.button_watch.set_after(settings.button_disconnect());
+                }
+            }
+            save_settings(settings_store);
+        }
+        Command::SetModbus(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                ModbusSetting::Address(address) => settings.set_modbus_address(address),
+                ModbusSetting::Baud(baud) => settings.set_modbus_baud(baud),
+                ModbusSetting::Pins(pins) => settings.set_modbus_pins(pins),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::EspNow => {
+            let settings = settings_store.settings();
+            println!("role={}", settings.espnow_role().name());
+            println!("stale_ms={}", settings.espnow_stale().as_millis());
+            match settings.espnow_aggregator() {
+                Some(mac) => println!("aggregator={}", format_mac(&mac)),
+                None => println!("aggregator=none"),
+            }
+            for (index, peer) in settings.espnow_peers().iter().enumerate() {
+                println!("peer{}={}", index + 1, peer);
+            }
+            println!("pairing={}", services.is_pairing());
+            if let Some(aggregate) = services.aggregate() {
+                println!("total={:.1}", aggregate.grams);
+                println!("valid={}", aggregate.valid);
+                for corner in &aggregate.corners {
+                    match (corner.grams, corner.age_s) {
+                        (Some(grams), Some(age_s)) => {
+                            println!(
+                                "{}={:.1} age_s={} fresh={}",
+                                corner.label, grams, age_s, corner.fresh
+                            )
+                        }
+                        _ => println!("{}=none", corner.label),
+                    }
+                }
+            }
+        }
+        Command::EspNowPair => {
+            if !services.pair_espnow() {
+                return Ok(refuse("ESP-NOW is not running, `set espnow role` first"));
+            }
+            println!("Pairing for {}s", PAIRING_WINDOW.as_secs());
+        }
+        Command::SetEspNow(setting) => {
+            let restart = matches!(setting, EspNowSetting::Role(_));
+            let settings = settings_store.settings_mut();
+            match setting {
+                EspNowSetting::Role(role) => settings.set_espnow_role(role),
+                EspNowSetting::AddPeer(mac, label) => {
+                    if !settings.add_espnow_peer(mac, label.as_deref()) {
+                        return Ok(refuse(format!(
+                            "{} is paired already or there are {} peers",
+                            format_mac(&mac),
+                            MAX_PEERS
+                        )));
+                    }
+                }
+                EspNowSetting::RemovePeer(index) => {
+                    if settings.remove_espnow_peer(index).is_none() {
+                        return Ok(refuse(format!("no peer {}", index + 1)));
+                    }
+                }
+                EspNowSetting::Label(index, label) => {
+                    if !settings.set_espnow_label(index, &label) {
+                        return Ok(refuse(format!("no peer {}", index + 1)));
+                    }
+                }
+                EspNowSetting::StaleMs(ms) => {
+                    settings.set_espnow_stale(Duration::from_millis(ms.into()))
+                }
+                EspNowSetting::Aggregator(mac) => settings.set_espnow_aggregator(mac),
+            }
+            services.configure_espnow(settings_store.settings());
+            save_settings(settings_store);
+            if restart {
+                println!("Restart to apply");
+            }
+        }
+        Command::SetTareCooldown(cooldown) => {
+            settings_store.settings_mut().set_tare_cooldown(cooldown);
+            state.tare_gate.set_cooldown(cooldown);
+            save_settings(settings_store);
+        }
+        Command::SetNegative(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                NegativeSetting::Grams(grams) => settings.set_negative_hint_grams(grams),
+                NegativeSetting::Secs(secs) => settings.set_negative_hint_time(secs),
+                NegativeSetting::AutoTare(enabled) => settings.set_negative_auto_tare(enabled),
+            }
+            state.negative.configure(settings.negative_hint());
+            save_settings(settings_store);
+        }
+        Command::SetNoise(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                NoiseSetting::WarnCounts(counts) => settings.set_noise_warn_counts(counts),
+                NoiseSetting::FailCounts(counts) => settings.set_noise_fail_counts(counts),
+            }
+            save_settings(settings_store);
+        }
+        Command::SetPanicHold(secs) => {
+            settings_store
+                .settings_mut()
+                .set_panic_hold(Some(Duration::from_secs(secs.into())));
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetTarget(grams) => {
+            settings_store.settings_mut().set_target_grams(grams);
+            // The recipe assistant sets its own target, until it is closed
+            if !matches!(state.mode, Mode::Recipe(_)) {
+                services.feedback.set_target(grams);
+            }
+            save_settings(settings_store);
+        }
+        Command::SetClock(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                ClockSetting::Enabled(enabled) => settings.set_idle_clock(enabled),
+                ClockSetting::IdleSecs(secs) => {
+                    settings.set_idle_clock_timeout(Duration::from_secs(secs.into()))
+                }
+            }
+            state
+                .idle
+                .configure(settings.idle_clock(), settings.idle_clock_timeout());
+            save_settings(settings_store);
+        }
+        Command::SetAlarm(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                AlarmSetting::Slot(slot, alarm) => settings.set_alarm(slot, alarm),
+                AlarmSetting::RenotifySecs(secs) => settings
+                    .set_alarm_renotify((secs > 0).then(|| Duration::from_secs(secs.into()))),
+            }
+            state
+                .alarms
+                .configure(settings.alarms(), settings.alarm_renotify());
+            if let Some(store) = &services.alarm_store {
+                store.save(&state.alarms.states());
+            }
+            // A removed alarm may have been the last latched one
+            if !state.alarms.is_latched() {
+                services.feedback.notify(Feedback::AlarmCleared);
+            }
+            save_settings(settings_store);
+        }
+        Command::SetCalReminder(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                CalReminderSetting::Days(days) => settings.set_cal_reminder_days(days),
+                CalReminderSetting::DriftGrams(grams) => settings.set_cal_drift_grams(grams),
+            }
+            scale.apply_settings(settings);
+            save_settings(settings_store);
+        }
+        Command::CalReminder(action) => {
+            match action {
+                CalReminderAction::Snooze => scale.snooze_calibration_reminder(),
+                CalReminderAction::Dismiss => scale.dismiss_calibration_reminder(),
+            }
+            state.toast = None;
+            println!("OK");
+        }
+        Command::SetCapacity(grams) => {
+            settings_store.settings_mut().set_capacity_grams(grams);
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetLed(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                LedSetting::Backend(backend) => settings.set_led_backend(backend),
+                LedSetting::Pin(pin) => settings.set_led_pin(pin),
+            }
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetDispense(setting) => {
+            let settings = settings_store.settings_mut();
+            let applies_live = matches!(setting, DispenseSetting::CompensationGrams(_));
+            match setting {
+                DispenseSetting::Pin(pin) => settings.set_dispense_pin(pin),
+                DispenseSetting::TimeoutSecs(secs) => settings.set_dispense_timeout_secs(secs),
+                DispenseSetting::MaxGrams(grams) => settings.set_dispense_max_grams(grams),
+                DispenseSetting::CompensationGrams(grams) => {
+                    settings.set_dispense_compensation_grams(grams)
+                }
+            }
+            save_settings(settings_store);
+            if applies_live {
+                println!("OK");
+            } else {
+                println!("Restart to apply");
+            }
+        }
+        Command::Dispense(grams) => {
+            let compensation = settings_store.settings().dispense_compensation_grams();
+            match services.start_dispense(grams, compensation) {
+                Ok(()) => println!("OK"),
+                Err(err) => println!("ERR {}", err),
+            }
+        }
+        Command::StopDispense => {
+            if services.abort_dispense() {
+                println!("OK");
+            } else {
+                println!("ERR Dispenser is not running");
+            }
+        }
+        Command::SetBrew(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                BrewSetting::StartGrams(grams) => settings.set_brew_start_grams(grams),
+                BrewSetting::StopGramsPerSec(rate) => settings.set_brew_stop_grams_per_sec(rate),
+                BrewSetting::StopGrace(grace) => settings.set_brew_stop_grace(grace),
+            }
+            save_settings(settings_store);
+            println!("OK");
+        }
+        Command::Brew(true) => {
+            arm_brew(scale, settings_store, state, services);
+            println!("OK");
+        }
+        Command::Brew(false) => {
+            if matches!(state.mode, Mode::Brew(_)) {
+                state.mode = Mode::Weighing;
+            }
+            println!("OK");
+        }
+        Command::SetRecipe(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                RecipeSetting::DoseGrams(grams) => settings.set_recipe_dose_grams(grams),
+                RecipeSetting::Ratio(ratio) => settings.set_recipe_ratio(ratio),
+            }
+            save_settings(settings_store);
+            println!("OK");
+        }
+        Command::SetUpdateToken(token) => {
+            settings_store.settings_mut().set_update_token(&token);
+            save_settings(settings_store);
+            println!("Restart to apply");
+        }
+        Command::SetPin(pin, gpio) => {
+            let mut pins = settings_store.settings().board_pins();
+            pins.set(pin, gpio);
+            match settings_store.settings().validate_pins(&pins) {
+                Ok(()) => {
+                    settings_store.settings_mut().set_board_pins(pins);
+                    save_settings(settings_store);
+                    println!("Restart to apply");
+                }
+                Err(err) => println!("ERR {}", err),
+            }
+        }
+        Command::Recipe => {
+            start_recipe(settings_store, state);
+            println!("OK");
+        }
+        Command::Identify => {
+            services.feedback.notify(Feedback::Identify);
+            println!("OK");
+        }
+        Command::Demo(DemoCommand::Start(pattern)) => {
+            if let Some(pattern) = pattern {
+                state.demo_pattern = pattern;
+            }
+            info!("Demo signal: {}", state.demo_pattern.describe());
+            scale.start_demo(state.demo_pattern.clone());
+            println!("OK");
+        }
+        Command::Demo(DemoCommand::Stop) => {
+            if scale.stop_demo() {
+                println!("OK");
+            } else {
+                println!("ERR demo mode is off");
+            }
+        }
+        Command::Demo(DemoCommand::Log(log)) => {
+            state.log_demo = log;
+            println!("OK");
+        }
+        Command::Soak(SoakCommand::Start { .. }) if state.soak.is_some() => {
+            println!("ERR a soak test is already running");
+        }
+        Command::Soak(SoakCommand::Start { interval, duration }) => {
+            let soak = Soak::new(interval, duration, Instant::now());
+            info!(
+                "Soak started, a point every {}s {}",
+                soak.interval().as_secs(),
+                soak.duration()
+                    .map_or("until stopped".to_string(), |duration| {
+                        format!("for {:.1}h", duration.as_secs_f32() / 3600.0)
+                    })
+            );
+            if imu::temperature().is_none() {
+                info!("No temperature source, the drift is only taken over time");
+            }
+            state.soak = Some(soak);
+            state.dirty = true;
+            println!("OK");
+            println!("{}", SOAK_CSV_HEADER);
+        }
+        Command::Soak(SoakCommand::Stop) => {
+            if state.soak.is_some() {
+                finish_soak(state);
+            } else {
+                println!("ERR no soak test running");
+            }
+        }
+        Command::Soak(SoakCommand::Report) => match (&state.soak, &state.soak_report) {
+            (Some(soak), _) => print_soak(&soak.report()),
+            (None, Some(report)) => print_soak(report),
+            (None, None) => println!("ERR no soak test yet"),
+        },
+        Command::Certified(None) => print_certified(),
+        Command::Certified(Some(on)) => {
+            let settings = settings_store.settings_mut();
+            settings.set_certified(on);
+            scale.apply_settings(settings);
+            state.hold = scale.hold_state();
+            state.dirty = true;
+            save_settings(settings_store);
+        }
+        Command::LogLevel(None) => println!("loglevel={}", logger::level()),
+        Command::LogLevel(Some(level)) => {
+            logger::set_level(level);
+            settings_store.settings_mut().set_log_level(level);
+            save_settings(settings_store);
+            println!("OK");
+        }
+        Command::Logs => {
+            for line in logger::recent_lines() {
+                println!("{}", line);
+            }
+        }
+        #[cfg(feature = "esp")]
+        Command::Dump => match &services.datalog {
+            // Blocks the main loop, which is fine for a maintenance command
+            Some(datalog) => {
+                println!("{}", DATALOG_CSV_HEADER);
+                for record in datalog.iter_records() {
+                    println!("{}", record.to_csv());
+                    // Printing the whole log takes longer than the timeout
+                    state.watchdog.feed();
+                }
+            }
+            None => println!("ERR logging is disabled"),
+        },
+        #[cfg(feature = "esp")]
+        Command::History { granularity, count } => match &services.datalog {
+            Some(datalog) => match datalog.history(granularity, count) {
+                Ok(bins) => {
+                    println!("{}", HISTORY_CSV_HEADER);
+                    for bin in bins {
+                        println!("{}", bin.to_csv());
+                    }
+                }
+                Err(err) => println!("ERR failed to read the history: {:?}", err),
+            },
+            None => println!("ERR logging is disabled"),
+        },
+        #[cfg(not(feature = "esp"))]
+        Command::Dump | Command::History { .. } | Command::ClearLog => {
+            println!("ERR logging is disabled")
+        }
+        Command::Average(window) => {
+            let average = scale.time_weighted_average(window);
+            match average.grams {
+                Some(grams) => println!(
+                    "Average {:.1}g over {} min, {:.1}% covered",
+                    grams,
+                    window.as_secs() / 60,
+                    average.coverage_percent()
+                ),
+                None => println!("ERR no readings in the last {} min", window.as_secs() / 60),
+            }
+        }
+        #[cfg(feature = "esp")]
+        Command::ClearResets => match &services.resets {
+            Some(resets) => match resets.clear() {
+                Ok(()) => println!("OK"),
+                Err(err) => println!("ERR failed to clear the reset counters: {:?}", err),
+            },
+            None => println!("ERR reset counters unavailable"),
+        },
+        #[cfg(not(feature = "esp"))]
+        Command::ClearResets => println!("ERR reset counters unavailable"),
+        Command::StorageDump => match services.storage.as_ref().map(storage::dump) {
+            Some(Ok(entries)) => {
+                for entry in entries {
+                    let size = entry
+                        .size
+                        .map_or_else(|| "?".to_string(), |size| size.to_string());
+                    println!("{}/{} {} {}", entry.namespace, entry.key, entry.kind, size);
+                }
+            }
+            Some(Err(err)) => println!("ERR failed to list the storage: {:?}", err),
+            None => println!("ERR storage unavailable"),
+        },
+        Command::StorageFlush => match services.storage.as_ref().map(StorageService::flush) {
+            Some(Ok(())) => println!("OK"),
+            Some(Err(err)) => println!("ERR failed to write the storage: {:?}", err),
+            None => println!("ERR storage unavailable"),
+        },
+        Command::Alarms => {
+            for (slot, config, alarm_state) in state.alarms.list() {
+                println!(
+                    "alarm {}: {} hysteresis={}g {}",
+                    slot + 1,
+                    config.describe(),
+                    config.hysteresis_grams,
+                    alarm_state.name()
+                );
+            }
+            let renotify = settings_store.settings().alarm_renotify();
+            println!(
+                "renotify={}s",
+                renotify.map_or(0, |interval| interval.as_secs())
+            );
+        }
+        Command::Session => {
+            let mut sessions = vec![("current", state.sessions.session_stats())];
+            sessions.extend(
+                state
+                    .sessions
+                    .history()
+                    .iter()
+                    .map(|stats| ("ended", *stats)),
+            );
+            for (which, stats) in sessions {
+                let started = stats
+                    .started()
+                    .map_or_else(|| "unknown".to_string(), |started| started.to_string());
+                let average = stats.average_grams().unwrap_or_default();
+                println!(
+                    "{} since={} count={} total={:.1}g avg={:.1}g max={:.1}g",
+                    which, started, stats.count, stats.total_grams, average, stats.max_grams
+                );
+            }
+        }
+        Command::NewSession => {
+            new_session(state, services);
+            println!("OK");
+        }
+        Command::AcknowledgeAlarms => {
+            if !acknowledge_alarms(state, services) {
+                return Ok(refuse("no alarm is latched"));
+            }
+            println!("OK");
+        }
+        #[cfg(feature = "esp")]
+        Command::ClearLog => match &services.datalog {
+            Some(datalog) => match datalog.clear_log() {
+                Ok(()) => println!("OK"),
+                Err(err) => println!("ERR failed to clear the log: {:?}", err),
+            },
+            None => println!("ERR logging is disabled"),
+        },
+        Command::Decommission => {
+            if services.decommission() {
+                println!("OK");
+            } else {
+                println!("ERR MQTT is not running");
+            }
+        }
+        Command::SetLock(setting) => {
+            let settings = settings_store.settings_mut();
+            match setting {
+                LockSetting::Enabled(true) if settings.lock().pin.is_none() => {
+                    return Ok(refuse("set a PIN first"));
+                }
+                LockSetting::Enabled(enabled) => settings.set_lock(enabled),
+                LockSetting::Pin(pin) => settings.set_lock_pin(pin),
+                LockSetting::Pattern(pattern) => settings.set_lock_pattern(pattern),
+                LockSetting::RelockSecs(secs) => settings.set_lock_relock_secs(secs),
+            }
+            let config = settings.lock();
+            services
+                .lock
+                .with(|lock| lock.configure(config, Instant::now()));
+            save_settings(settings_store);
+        }
+        Command::Lock => {
+            services.lock.with(|lock| lock.relock());
+            println!("OK");
+        }
+        Command::Unlock(pin) => match services
+            .lock
+            .with(|lock| lock.unlock_with_pin(pin, Instant::now()))
+        {
+            Ok(()) => println!("OK"),
+            Err(err) => println!("ERR {}", err),
+        },
+        Command::WithPin(..) => println!("ERR one PIN per command"),
+        Command::FactoryReset => {
+            println!("OK");
+            factory_reset(scale, text_drawer, settings_store);
+        }
+        Command::Sleep => {
+            let button = settings_store.settings().board_pins().button;
+            if !power::can_wake_from_deep_sleep(button) {
+                return Ok(refuse(format!(
+                    "the button on GPIO{} cannot wake the scale",
+                    button
+                )));
+            }
+            println!("OK");
+            deep_sleep(
+                ShutdownReason::Sleep,
+                text_drawer,
+                settings_store,
+                state,
+                services,
+            );
+        }
+        Command::Restart(reason) => {
+            println!("OK");
+            restart(reason, text_drawer, settings_store, state, services);
+        }
+        Command::Help => println!("{}", USAGE),
+    }
+    Ok(if starts_procedure {
+        CommandOutcome::Started
+    } else {
+        CommandOutcome::Done
+    })
+}
+
+/// Print how a dispense went and tune the compensation after a completed one
+#[cfg(feature = "dispense")]
+fn report_dispense(result: &DispenseResult, settings_store: &mut SettingsStore) {
+    println!(
+        "Dispense {:?}: {:.1}g of {:.1}g in {:.1}s",
+        result.outcome,
+        result.dispensed_grams,
+        result.target_grams,
+        result.duration.as_secs_f32()
+    );
+    if result.outcome != DispenseOutcome::Completed {
+        return;
+    }
+    let settings = settings_store.settings_mut();
+    let compensation = tuned_compensation(settings.dispense_compensation_grams(), result);
+    settings.set_dispense_compensation_grams(compensation);
+    save_settings(settings_store);
+    info!("Dispense compensation tuned to {:.1}g", compensation);
+}
+
+/// Sleep lightly while the scale stays empty, until the weight moves past
+/// the wake threshold or the button is pressed. The press that woke the
+/// scale starts no gesture.
+fn doze<DI, SIZE>(
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    app_events: &Receiver<AppEvent>,
+    settings: &Settings,
+    state: &mut AppState,
+) where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    // Only the HX711 tells when a conversion is ready through a pin
+    if settings.sensor() != SensorKind::Hx711 {
+        return;
+    }
+    let baseline = scale.stable_gross().unwrap_or_default();
+    info!("Dozing off at {}g", baseline);
+    if let Err(err) = text_drawer.set_display_on(false) {
+        warn!("Failed to turn the display off: {:?}", err);
+    }
+    let pins = settings.board_pins();
+    let mut check = WakeCheck::new(baseline, settings.wake_grams());
+    let woken_by = loop {
+        state.watchdog.feed();
+        let slept = scale.between_readings(|| power::light_sleep(pins.hx711_dt, pins.button));
+        if let Err(err) = slept {
+            warn!("Light sleep failed: {:?}", err);
+            break "error";
+        }
+        // The readings of the conversion that woke the chip and the next
+        // ones, the button task debounces a press meanwhile
+        check.restart();
+        let moved = loop {
+            match app_events.recv_timeout(WAKE_READING_TIMEOUT) {
+                Ok(AppEvent::Reading { raw, .. }) => {
+                    if let Some(moved) = check.on_reading(scale.gross_grams(raw)) {
+                        break moved;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break false,
+            }
+        };
+        if moved {
+            break "load";
+        }
+        if scale.is_button_pressed() {
+            while scale.is_button_pressed() {
+                state.watchdog.feed();
+                thread::sleep(MENU_POLL_INTERVAL);
+            }
+            scale.clear_button_events();
+            break "button";
+        }
+    };
+    info!("Woken up by the {}", woken_by);
+    if let Err(err) = text_drawer.set_display_on(true) {
+        warn!("Failed to turn the display on: {:?}", err);
+    }
+    state.idle.wake();
+    state.idle_stages.on_activity(Instant::now());
+    state.last_gesture = Instant::now();
+    state.dirty = true;
+    state.full_redraw = true;
+}
+
+/// Persist what is still pending, tell the user and power down to protect the
+/// cell. A press of the button wakes the scale, which shuts down again right
+/// away unless the battery was charged. A button that cannot wake the chip
+/// leaves it asleep until a reset, the cell comes first.
+fn low_battery_shutdown<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) -> !
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    warn!("Battery below the cutoff voltage, shutting down");
+    services.feedback.notify(Feedback::BatteryLow);
+    save_main_loop_state(settings_store, state, services);
+    // The deep sleep does not run the shutdown handlers a restart does
+    shutdown::flush(ShutdownReason::LowBattery);
+
+    let position = text_drawer.layout().weight.top_left;
+    if let Err(err) = text_drawer.draw_text_clear_flush(tr(StringId::LowBattery), position) {
+        warn!("Failed to show the low battery message: {:?}", err);
+    }
+    thread::sleep(LOW_BATTERY_MESSAGE);
+    let _ = text_drawer.clear().and_then(|()| text_drawer.flush());
+
+    power::deep_sleep(settings_store.settings().board_pins().button)
+}
+
+/// Dim the display, turn it off or go into deep sleep once the idle scale
+/// reached the stage, or bring the display back with the activity
+fn enter_idle_stage<DI, SIZE>(
+    stage: IdleStage,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    info!("Idle stage: {}", stage.name());
+    services.feedback.notify(Feedback::Idle(stage));
+    let brightness = match stage {
+        IdleStage::Active => settings_store.settings().brightness(),
+        IdleStage::Dimmed => 0,
+        IdleStage::DisplayOff => {
+            if let Err(err) = text_drawer.set_display_on(false) {
+                warn!("Failed to turn the display off: {:?}", err);
+            }
+            return;
+        }
+        IdleStage::DeepSleep => {
+            let button = settings_store.settings().board_pins().button;
+            // Set before the rule on the pins, turned off rather than
+            // sleeping for good
+            if !power::can_wake_from_deep_sleep(button) {
+                warn!(
+                    "The button on GPIO{} cannot wake the scale, only turning the display off",
+                    button
+                );
+                if let Err(err) = text_drawer.set_display_on(false) {
+                    warn!("Failed to turn the display off: {:?}", err);
+                }
+                return;
+            }
+            deep_sleep(
+                ShutdownReason::Idle,
+                text_drawer,
+                settings_store,
+                state,
+                services,
+            )
+        }
+    };
+    if let Err(err) = text_drawer
+        .set_brightness(brightness)
+        .and_then(|()| text_drawer.set_display_on(true))
+    {
+        warn!("Failed to change the display brightness: {:?}", err);
+    }
+    state.dirty = true;
+    state.full_redraw = true;
+}
+
+/// Save the state and go into deep sleep until the button wakes the scale
+fn deep_sleep<DI, SIZE>(
+    reason: ShutdownReason,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) -> !
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    save_main_loop_state(settings_store, state, services);
+    // The deep sleep does not run the shutdown handlers a restart does
+    shutdown::flush(reason);
+    let _ = text_drawer.clear().and_then(|()| text_drawer.flush());
+    power::deep_sleep(settings_store.settings().board_pins().button)
+}
+
+/// Go into deep sleep outside of the windows of the schedule, the timer
+/// wakes the scale at the start of the next one
+fn sleep_until_window<DI, SIZE>(
+    wake_after: Duration,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) -> !
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    info!(
+        "Outside of the schedule, sleeping for {}s",
+        wake_after.as_secs()
+    );
+    save_main_loop_state(settings_store, state, services);
+    // The deep sleep does not run the shutdown handlers a restart does
+    shutdown::flush(ShutdownReason::Schedule);
+    let _ = text_drawer.clear().and_then(|()| text_drawer.flush());
+    power::deep_sleep_for(settings_store.settings().board_pins().button, wake_after)
+}
+
+/// Save what only the main loop holds, ahead of the shutdown hooks
+fn save_main_loop_state(
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) {
+    save_sessions(state, services);
+    state.sinks.flush();
+    if let Err(err) = settings_store.save() {
+        warn!("Failed to save settings: {:?}", err);
+    }
+}
+
+/// Save the state, show the restart and restart through the shutdown hooks
+fn restart<DI, SIZE>(
+    reason: ShutdownReason,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) -> !
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    save_main_loop_state(settings_store, state, services);
+    let prompt = text_drawer.layout().prompt.top_left;
+    if let Err(err) = text_drawer.draw_text_clear_flush(tr(StringId::Restarting), prompt) {
+        warn!("Failed to show the restart: {:?}", err);
+    }
+    shutdown::restart(reason)
+}
+
+fn save_settings(settings_store: &mut SettingsStore) {
+    match settings_store.save() {
+        Ok(()) => println!("OK"),
+        Err(err) => println!("ERR failed to save settings: {:?}", err),
+    }
+}
+
+/// Hand a command of the main loop itself to the command channel, run
+/// along with those of the frontends
+fn queue_command(commands: &CommandSender, command: Command) {
+    if commands.send(command).is_err() {
+        warn!("The command channel is closed");
+    }
+}
+
+/// Erase the calibration and the settings, then restart with the defaults.
+/// Nothing of the main loop is saved, it would write the settings back.
+fn factory_reset<DI, SIZE>(
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+) -> !
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    info!("Factory reset");
+    let prompt = text_drawer.layout().prompt.top_left;
+    if let Err(err) = text_drawer.draw_text_clear_flush(tr(StringId::FactoryResetting), prompt) {
+        warn!("Failed to show the factory reset: {:?}", err);
+    }
+    if let Err(err) = scale.reset_calibration() {
+        warn!("Failed to erase calibration: {:?}", err);
+    }
+    if let Err(err) = settings_store.erase() {
+        warn!("Failed to erase settings: {:?}", err);
+    }
+    shutdown::restart(ShutdownReason::FactoryReset)
+}
+
+/// Holding the button during power-on offers a factory reset, which is
+/// confirmed by releasing the button before the countdown ends. Keeping it
+/// held cancels the reset. Returns whether it was confirmed, the reset
+/// itself is a `Command::FactoryReset` like that of the menu.
+fn check_factory_reset<DI, SIZE>(
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    lock: &LockHandle,
+) -> Result<bool, TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    // The button task only reports a press once the level is stable, so a
+    // bounce at power-up never counts as held
+    thread::sleep(FACTORY_RESET_SETTLE);
+    if !button_held_for(scale, FACTORY_RESET_HOLD) {
+        return Ok(false);
+    }
+
+    info!("Button held at boot, offering factory reset");
+    let prompt = text_drawer.layout().prompt.top_left;
+    for remaining in (1..=FACTORY_RESET_COUNTDOWN_SECS).rev() {
+        text_drawer.draw_text_clear_flush(&trf(StringId::ReleaseToReset, &[&remaining]), prompt)?;
+
+        if !button_held_for(scale, Duration::from_secs(1)) {
+            // A locked scale is only reset by whoever can unlock it
+            if lock.is_locked() && !unlock_with_button(scale, text_drawer, lock, None)? {
+                break;
+            }
+            info!("Factory reset confirmed");
+            text_drawer.draw_text_clear_flush(tr(StringId::FactoryResetting), prompt)?;
+            scale.clear_button_events();
+            return Ok(true);
+        }
+    }
+
+    info!("Factory reset cancelled");
+    text_drawer.draw_text_clear_flush(tr(StringId::ResetCancelled), prompt)?;
+    scale.clear_button_events();
+    Ok(false)
+}
+
+/// Whether the button stays pressed for the whole duration
+fn button_held_for(scale: &Scale, duration: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        if !scale.is_button_pressed() {
+            return false;
+        }
+        thread::sleep(FACTORY_RESET_POLL);
+    }
+    true
+}
+
+fn soft_tare_menu<'m>() -> MenuItem<MenuContext<'m>> {
+    MenuItem::Submenu {
+        label: tr(StringId::SoftTare),
+        items: vec![
+            MenuItem::Action {
+                label: tr(StringId::Tare),
+                run: |ctx| {
+                    ctx.scale.soft_tare();
+                },
+            },
+            MenuItem::Action {
+                label: tr(StringId::Untare),
+                run: |ctx| {
+                    ctx.scale.untare();
+                },
+            },
+            MenuItem::Action {
+                label: tr(StringId::NetGross),
+                run: |ctx| {
+                    ctx.scale.toggle_net_gross();
+                },
+            },
+            MenuItem::Action {
+                label: tr(StringId::Clear),
+                run: |ctx| ctx.scale.clear_soft_tare(),
+            },
+        ],
+    }
+}
+
+fn unit_menu<'m>() -> MenuItem<MenuContext<'m>> {
+    MenuItem::Choice {
+        label: tr(StringId::Units),
+        options: &UNIT_LABELS,
+        get: |ctx| {
+            Unit::ALL
+                .iter()
+                .position(|&unit| unit == ctx.scale.unit())
+                .unwrap_or(0)
+        },
+        set: |ctx, index| {
+            ctx.scale.set_unit(Unit::ALL[index]);
+            ctx.settings.set_unit(Unit::ALL[index]);
+        },
+    }
+}
+
+fn cal_weight_menu<'m>() -> MenuItem<MenuContext<'m>> {
+    MenuItem::Number {
+        label: tr(StringId::CalWeight),
+        digits: 4,
+        decimals: 0,
+        min: 100.0,
+        max: 5000.0,
+        get: |ctx| ctx.scale.calibration_weight(),
+        set: |ctx, grams| {
+            ctx.scale.set_calibration_weight(grams);
+            ctx.settings.set_calibration_weight(grams);
+        },
+    }
+}
+
+fn language_menu<'m>() -> MenuItem<MenuContext<'m>> {
+    MenuItem::Choice {
+        label: tr(StringId::Language),
+        options: &Language::NAMES,
+        get: |ctx| usize::from(ctx.settings.language().index()),
+        set: |ctx, index| {
+            let language = Language::ALL[index];
+            ctx.settings.set_language(language);
+            i18n::set_language(language);
+        },
+    }
+}
+
+/// Steps of the setup, the language first so the others show in it
+fn setup_steps<'m>() -> Vec<MenuItem<MenuContext<'m>>> {
+    vec![
+        language_menu(),
+        unit_menu(),
+        MenuItem::Choice {
+            label: tr(StringId::Rotation),
+            options: &ROTATION_LABELS,
+            get: |ctx| usize::from(ctx.settings.display_quarter_turns()),
+            set: |ctx, turns| ctx.settings.set_display_quarter_turns(turns as u8),
+        },
+        cal_weight_menu(),
+        MenuItem::Toggle {
+            label: tr(StringId::CalibrateNow),
+            get: |ctx| ctx.mode == Some(ModeRequest::Calibrate),
+            set: |ctx, calibrate| ctx.mode = calibrate.then_some(ModeRequest::Calibrate),
+        },
+    ]
+}
+
+/// Menu of the locked scale, the weighing along with the way to unlock
+fn build_locked_menu<'m>() -> Menu<MenuContext<'m>> {
+    Menu::new(vec![
+        MenuItem::Action {
+            label: tr(StringId::Hold),
+            run: |ctx| ctx.mode = Some(ModeRequest::Hold),
+        },
+        soft_tare_menu(),
+        MenuItem::Action {
+            label: tr(StringId::BrewTimer),
+            run: |ctx| ctx.mode = Some(ModeRequest::Brew),
+        },
+        MenuItem::Action {
+            label: tr(StringId::NewSession),
+            run: |ctx| ctx.mode = Some(ModeRequest::NewSession),
+        },
+        MenuItem::Action {
+            label: tr(StringId::Unlock),
+            run: |ctx| ctx.unlock = true,
+        },
+    ])
+}
+
+fn build_menu<'m>() -> Menu<MenuContext<'m>> {
+    #[allow(unused_mut)]
+    let mut items: Vec<MenuItem<MenuContext<'m>>> = vec![
+        // First, so two long presses hold the weight
+        MenuItem::Action {
+            label: tr(StringId::Hold),
+            run: |ctx| ctx.mode = Some(ModeRequest::Hold),
+        },
+        soft_tare_menu(),
+        unit_menu(),
+        MenuItem::Submenu {
+            label: tr(StringId::Volume),
+            items: vec![
+                MenuItem::Toggle {
+                    label: tr(StringId::ShowMl),
+                    get: |ctx| ctx.settings.volume_shown(),
+                    set: |ctx, shown| ctx.settings.set_volume_shown(shown),
+                },
+                MenuItem::Choice {
+                    label: tr(StringId::Substance),
+                    options: &Substance::LABELS,
+                    get: |ctx| usize::from(ctx.settings.substance().index()),
+                    set: |ctx, index| ctx.settings.set_substance(Substance::ALL[index]),
+                },
+                MenuItem::Number {
+                    label: tr(StringId::Density),
+                    digits: 3,
+                    decimals: 2,
+                    min: MIN_DENSITY,
+                    max: MAX_DENSITY,
+                    get: |ctx| ctx.settings.custom_density(),
+                    set: |ctx, density| {
+                        ctx.settings.set_custom_density(density);
+                        ctx.settings.set_substance(Substance::Custom);
+                    },
+                },
+            ],
+        },
+        MenuItem::Choice {
+            label: tr(StringId::Resolution),
+            options: &RESOLUTION_LABELS,
+            get: |ctx| {
+                RESOLUTIONS_GRAMS
+                    .iter()
+                    .position(|&resolution| resolution == ctx.scale.resolution())
+                    .unwrap_or(1)
+            },
+            set: |ctx, index| {
+                // Locked while certified
+                if ctx.scale.is_certified() {
+                    return;
+                }
+                ctx.scale.set_resolution(RESOLUTIONS_GRAMS[index]);
+                ctx.settings.set_resolution(RESOLUTIONS_GRAMS[index]);
+            },
+        },
+        cal_weight_menu(),
+        MenuItem::Numeric {
+            label: tr(StringId::Brightness),
+            min: 0,
+            max: MAX_BRIGHTNESS_LEVEL as i32,
+            step: 1,
+            get: |ctx| ctx.settings.brightness() as i32,
+            set: |ctx, level| ctx.settings.set_brightness(level as u8),
+        },
+        language_menu(),
+        MenuItem::Submenu {
+            label: tr(StringId::Idle),
+            items: vec![
+                MenuItem::Numeric {
+                    label: tr(StringId::DimSecs),
+                    min: 0,
+                    max: 600,
+                    step: 10,
+                    get: |ctx| idle_timeout_secs(ctx.settings.idle_timeouts().dim) as i32,
+                    set: |ctx, secs| {
+                        ctx.settings
+                            .set_idle_timeout(IdleStage::Dimmed, secs as u32)
+                    },
+                },
+                MenuItem::Numeric {
+                    label: tr(StringId::OffMins),
+                    min: 0,
+                    max: 120,
+                    step: 1,
+                    get: |ctx| idle_timeout_mins(ctx.settings.idle_timeouts().display_off),
+                    set: |ctx, mins| {
+                        ctx.settings
+                            .set_idle_timeout(IdleStage::DisplayOff, mins as u32 * 60)
+                    },
+                },
+                MenuItem::Numeric {
+                    label: tr(StringId::SleepMins),
+                    min: 0,
+                    max: 240,
+                    step: 5,
+                    get: |ctx| idle_timeout_mins(ctx.settings.idle_timeouts().deep_sleep),
+                    set: |ctx, mins| {
+                        ctx.settings
+                            .set_idle_timeout(IdleStage::DeepSleep, mins as u32 * 60)
+                    },
+                },
+            ],
+        },
+        MenuItem::Action {
+            label: tr(StringId::Calibrate),
+            run: |ctx| ctx.mode = Some(ModeRequest::Calibrate),
+        },
+        MenuItem::Submenu {
+            label: tr(StringId::Linearity),
+            items: vec![
+                MenuItem::Action {
+                    label: tr(StringId::Start),
+                    run: |ctx| ctx.mode = Some(ModeRequest::Linearity),
+                },
+                MenuItem::Number {
+                    label: tr(StringId::Weight1),
+                    digits: 4,
+                    decimals: 0,
+                    min: 0.0,
+                    max: 5000.0,
+                    get: |ctx| ctx.settings.linearity_weight(0),
+                    set: |ctx, grams| ctx.settings.set_linearity_weight(0, grams),
+                },
+                MenuItem::Number {
+                    label: tr(StringId::Weight2),
+                    digits: 4,
+                    decimals: 0,
+                    min: 0.0,
+                    max: 5000.0,
+                    get: |ctx| ctx.settings.linearity_weight(1),
+                    set: |ctx, grams| ctx.settings.set_linearity_weight(1, grams),
+                },
+                MenuItem::Number {
+                    label: tr(StringId::Weight3),
+                    digits: 4,
+                    decimals: 0,
+                    min: 0.0,
+                    max: 5000.0,
+                    get: |ctx| ctx.settings.linearity_weight(2),
+                    set: |ctx, grams| ctx.settings.set_linearity_weight(2, grams),
+                },
+                MenuItem::Number {
+                    label: tr(StringId::Weight4),
+                    digits: 4,
+                    decimals: 0,
+                    min: 0.0,
+                    max: 5000.0,
+                    get: |ctx| ctx.settings.linearity_weight(3),
+                    set: |ctx, grams| ctx.settings.set_linearity_weight(3, grams),
+                },
+            ],
+        },
+        MenuItem::Action {
+            label: tr(StringId::NoiseTest),
+            run: |ctx| ctx.mode = Some(ModeRequest::NoiseTest),
+        },
+        MenuItem::Submenu {
+            label: tr(StringId::CalReminder),
+            items: vec![
+                MenuItem::Action {
+                    label: tr(StringId::Snooze7d),
+                    run: |ctx| ctx.scale.snooze_calibration_reminder(),
+                },
+                MenuItem::Action {
+                    label: tr(StringId::Dismiss),
+                    run: |ctx| ctx.scale.dismiss_calibration_reminder(),
+                },
+                MenuItem::Numeric {
+                    label: tr(StringId::AfterDays),
+                    min: 0,
+                    max: 360,
+                    step: 30,
+                    get: |ctx| ctx.settings.cal_reminder_days() as i32,
+                    set: |ctx, days| {
+                        ctx.settings.set_cal_reminder_days(days as u32);
+                        ctx.scale.apply_settings(ctx.settings);
+                    },
+                },
+            ],
+        },
+        MenuItem::Action {
+            label: tr(StringId::BrewTimer),
+            run: |ctx| ctx.mode = Some(ModeRequest::Brew),
+        },
+        MenuItem::Action {
+            label: tr(StringId::NewSession),
+            run: |ctx| ctx.mode = Some(ModeRequest::NewSession),
+        },
+        MenuItem::Submenu {
+            label: tr(StringId::Recipe),
+            items: vec![
+                MenuItem::Action {
+                    label: tr(StringId::Start),
+                    run: |ctx| ctx.mode = Some(ModeRequest::Recipe),
+                },
+                MenuItem::Numeric {
+                    label: tr(StringId::DoseGrams),
+                    min: MIN_DOSE_GRAMS as i32,
+                    max: MAX_DOSE_GRAMS as i32,
+                    step: 1,
+                    get: |ctx| ctx.settings.recipe_dose_grams().round() as i32,
+                    set: |ctx, grams| ctx.settings.set_recipe_dose_grams(grams as f32),
+                },
+                MenuItem::Numeric {
+                    label: tr(StringId::Ratio),
+                    min: 10,
+                    max: 20,
+                    step: 1,
+                    get: |ctx| ctx.settings.recipe_ratio().round() as i32,
+                    set: |ctx, ratio| ctx.settings.set_recipe_ratio(ratio as f32),
+                },
+            ],
+        },
+        MenuItem::Action {
+            label: tr(StringId::Setup),
+            run: |ctx| ctx.mode = Some(ModeRequest::Setup),
+        },
+        MenuItem::Submenu {
+            label: tr(StringId::Reset),
+            items: vec![MenuItem::Action {
+                label: tr(StringId::FactoryReset),
+                run: |ctx| ctx.mode = Some(ModeRequest::FactoryReset),
+            }],
+        },
+    ];
+
+    #[cfg(feature = "wifi")]
+    items.insert(
+        items.len() - 1,
+        MenuItem::Action {
+            label: tr(StringId::WifiSetup),
+            run: |ctx| {
+                if let Some(wifi) = &ctx.services.wifi {
+                    wifi.start_provisioning();
+                }
+            },
+        },
+    );
+    // On the aggregator first, then on each corner
+    #[cfg(feature = "espnow")]
+    items.insert(
+        items.len() - 1,
+        MenuItem::Action {
+            label: tr(StringId::PairScales),
+            run: |ctx| {
+                ctx.services.pair_espnow();
+            },
+        },
+    );
+
+    Menu::new(items)
+}
+
+/// Timeout of an idle stage in the menu, 0 for a skipped one
+fn idle_timeout_secs(timeout: Option<Duration>) -> u64 {
+    timeout.map_or(0, |timeout| timeout.as_secs())
+}
+
+/// Same in minutes, rounded up so a timeout set from the console in seconds
+/// does not show as skipped
+fn idle_timeout_mins(timeout: Option<Duration>) -> i32 {
+    idle_timeout_secs(timeout).div_ceil(60) as i32
+}
+
+/// Run the settings menu until it is closed, then persist the edited settings.
+/// The weight display is paused in the meantime. Returns the mode picked from
+/// the menu, if any.
+fn run_menu<DI, SIZE>(
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    services: &Services,
+    watchdog: &WatchdogGuard,
+) -> Result<Option<ModeRequest>, TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let mut menu = if services.lock.is_locked() {
+        build_locked_menu()
+    } else {
+        build_menu()
+    };
+    let mut ctx = MenuContext {
+        scale,
+        settings: settings_store.settings_mut(),
+        services,
+        mode: None,
+        unlock: false,
+    };
+
+    menu.render(&ctx, text_drawer)?;
+    loop {
+        watchdog.feed();
+        if let Some(action) = ctx.scale.poll_button_action() {
+            let state = menu.handle(action, &mut ctx);
+            if ctx.unlock {
+                ctx.unlock = false;
+                if unlock_with_button(ctx.scale, text_drawer, &services.lock, Some(watchdog))? {
+                    menu = build_menu();
+                }
+                menu.render(&ctx, text_drawer)?;
+                continue;
+            }
+            text_drawer.set_brightness(ctx.settings.brightness())?;
+            // Entering a mode leaves the menu right away
+            if state == MenuState::Closed || ctx.mode.is_some() {
+                break;
+            }
+            menu.render(&ctx, text_drawer)?;
+        }
+        thread::sleep(MENU_POLL_INTERVAL);
+    }
+    let mode = ctx.mode;
+    // The relock counts from the last use of the menu
+    services.lock.touch();
+
+    if let Err(err) = settings_store.save() {
+        warn!("Failed to save settings: {:?}", err);
+    }
+    Ok(mode)
+}
+
+/// Walk through the setup with the button, each step saving its value, then
+/// calibrate when that was picked. The summary shows once the calibration
+/// ended, see `finish_setup`. The calibration is offered by default only
+/// to a scale that was never calibrated.
+fn run_setup<DI, SIZE>(
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    info!("Running the setup");
+    let turns = settings_store.settings().display_quarter_turns();
+    scale.clear_button_events();
+    let mut ctx = MenuContext {
+        mode: scale.needs_calibration().then_some(ModeRequest::Calibrate),
+        scale,
+        settings: settings_store.settings_mut(),
+        services,
+        unlock: false,
+    };
+    let mut setup = Setup::new(setup_steps, &ctx);
+
+    setup.render(text_drawer)?;
+    loop {
+        state.watchdog.feed();
+        if let Some(action) = ctx.scale.poll_button_action() {
+            if setup.handle(action, &mut ctx) == SetupState::Done {
+                break;
+            }
+            setup.render(text_drawer)?;
+        }
+        thread::sleep(MENU_POLL_INTERVAL);
+    }
+    let calibrate = ctx.mode == Some(ModeRequest::Calibrate);
+
+    if let Err(err) = settings_store.save() {
+        warn!("Failed to save settings: {:?}", err);
+    }
+    state.setup_summary = Some(turns);
+    state.full_redraw = true;
+    state.dirty = true;
+    if calibrate {
+        start_procedure(scale.begin_calibration(), state, services, false);
+    }
+    Ok(())
+}
+
+/// Show what the setup ended with until a press or `SETUP_SUMMARY_TIMEOUT`,
+/// then mark it done. The display only turns at boot, so the scale restarts
+/// when the rotation changed from `turns`.
+fn finish_setup<DI, SIZE>(
+    turns: u8,
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    settings_store: &mut SettingsStore,
+    state: &mut AppState,
+    services: &Services,
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let settings = settings_store.settings_mut();
+    settings.set_setup_done(true);
+    let calibration = match scale.scale_factor() {
+        Some(factor) => format!("factor {:.2}", factor),
+        None => tr(StringId::NotCalibrated).to_string(),
+    };
+    let text = format!(
+        "{}\n{} {}\n{} {:.0}g\n{}",
+        tr(StringId::SetupDone),
+        Language::NAMES[usize::from(settings.language().index())],
+        scale.unit().symbol(),
+        tr(StringId::CalWeight),
+        scale.calibration_weight(),
+        calibration,
+    );
+    let rotated = settings.display_quarter_turns() != turns;
+    if let Err(err) = settings_store.save() {
+        warn!("Failed to save settings: {:?}", err);
+    }
+    info!("Setup done");
+
+    scale.clear_button_events();
+    let prompt = text_drawer.layout().prompt.top_left;
+    text_drawer.draw_text_clear_flush(&text, prompt)?;
+    let shown = Instant::now();
+    while shown.elapsed() < SETUP_SUMMARY_TIMEOUT && scale.poll_button_action().is_none() {
+        state.watchdog.feed();
+        thread::sleep(MENU_POLL_INTERVAL);
+    }
+    if rotated {
+        restart(
+            ShutdownReason::Setup,
+            text_drawer,
+            settings_store,
+            state,
+            services,
+        );
+    }
+    state.full_redraw = true;
+    state.dirty = true;
+    Ok(())
+}
+
+/// Unlock from the button, with the click pattern when one is set or else
+/// with the PIN entered digit by digit. The outcome shows for a moment.
+/// Returns whether the scale is unlocked, false too when the entry was given
+/// up.
+fn unlock_with_button<DI, SIZE>(
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    lock: &LockHandle,
+    watchdog: Option<&WatchdogGuard>,
+) -> Result<bool, TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    scale.clear_button_events();
+    let result = if lock.with(|lock| lock.config().pattern.is_some()) {
+        let Some(clicks) = enter_clicks(scale, text_drawer, watchdog)? else {
+            return Ok(false);
+        };
+        lock.with(|lock| lock.unlock_with_clicks(&clicks, Instant::now()))
+    } else {
+        let Some(pin) = enter_pin(scale, text_drawer, watchdog)? else {
+            return Ok(false);
+        };
+        lock.with(|lock| lock.unlock_with_pin(pin, Instant::now()))
+    };
+    let text = match result {
+        Ok(()) => {
+            info!("Unlocked from the button");
+            tr(StringId::Unlocked).to_string()
+        }
+        Err(err) => {
+            warn!("Failed to unlock from the button: {}", err);
+            err.to_string()
+        }
+    };
+    let prompt = text_drawer.layout().prompt.top_left;
+    text_drawer.draw_text_clear_flush(&text, prompt)?;
+    thread::sleep(UNLOCK_RESULT);
+    scale.clear_button_events();
+    Ok(result.is_ok())
+}
+
+/// PIN entered with the number widget, none when given up
+fn enter_pin<DI, SIZE>(
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    watchdog: Option<&WatchdogGuard>,
+) -> Result<Option<u16>, TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let prompt = text_drawer.layout().prompt.top_left;
+    let line_height = text_drawer.line_height() as i32;
+    let mut entry = NumberEntry::new(PIN_DIGITS, 0, 0.0, f32::from(MAX_PIN), 0.0);
+    let mut last_click = Instant::now();
+    let mut dirty = true;
+    while last_click.elapsed() < UNLOCK_ENTRY_TIMEOUT {
+        if let Some(watchdog) = watchdog {
+            watchdog.feed();
+        }
+        if dirty {
+            text_drawer.draw_text_clear(tr(StringId::EnterPin), prompt)?;
+            entry.render(prompt + Point::new(0, line_height), text_drawer)?;
+            text_drawer.flush()?;
+            dirty = false;
+        }
+        if let Some(action) = scale.poll_button_action() {
+            last_click = Instant::now();
+            dirty = true;
+            if let Some(pin) = entry.handle(action) {
+                return Ok(Some(pin as u16));
+            }
+        }
+        thread::sleep(MENU_POLL_INTERVAL);
+    }
+    Ok(None)
+}
+
+/// Clicks of a pattern, ended by a pause. None when nothing was clicked.
+fn enter_clicks<DI, SIZE>(
+    scale: &mut Scale,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    watchdog: Option<&WatchdogGuard>,
+) -> Result<Option<Vec<Click>>, TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let prompt = text_drawer.layout().prompt.top_left;
+    let mut clicks = Vec::new();
+    let mut last_click = Instant::now();
+    let mut dirty = true;
+    loop {
+        let pause = if clicks.is_empty() {
+            UNLOCK_ENTRY_TIMEOUT
+        } else {
+            PATTERN_END
+        };
+        if last_click.elapsed() >= pause || clicks.len() > MAX_PATTERN_LEN {
+            break;
+        }
+        if let Some(watchdog) = watchdog {
+            watchdog.feed();
+        }
+        if dirty {
+            // The clicks are not shown, only counted
+            let text = trf(StringId::EnterPattern, &[&"*".repeat(clicks.len())]);
+            text_drawer.draw_text_clear_flush(&text, prompt)?;
+            dirty = false;
+        }
+        if let Some(action) = scale.poll_button_action() {
+            last_click = Instant::now();
+            dirty = true;
+            match action {
+                ButtonAction::Press => clicks.push(Click::Short),
+                ButtonAction::LongPress => clicks.push(Click::Long),
+                ButtonAction::DoublePress => clicks.extend([Click::Short, Click::Short]),
+            }
+        }
+        thread::sleep(MENU_POLL_INTERVAL);
+    }
+    Ok((!clicks.is_empty()).then_some(clicks))
+}
+
+/// Show the progress of a firmware update instead of the weight
+fn draw_update<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    fraction: f32,
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let prompt = text_drawer.layout().prompt.top_left;
+    text_drawer.clear()?;
+    draw_progress_bar(text_drawer, fraction)?;
+    text_drawer.draw_text(
+        &trf(
+            StringId::Updating,
+            &[&format_args!("{:.0}", fraction * 100.0)],
+        ),
+        prompt,
+    )?;
+    text_drawer.flush()
+}
+
+/// Screen of the current recipe step
+fn draw_recipe<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    recipe: &Recipe,
+    icons: &[StatusIcon],
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let layout = *text_drawer.layout();
+    text_drawer.clear()?;
+    // Weight steps show the weight big with a detail below it, or both on
+    // one line on short displays
+    let (weight, detail) = match recipe.step() {
+        RecipeStep::EnterDose { dose_grams } => {
+            let prompt = format!(
+                "Dose {:.1}g\n1:{} = {:.0}g",
+                dose_grams,
+                recipe.ratio(),
+                dose_grams * recipe.ratio()
+            );
+            text_drawer.draw_text(&prompt, layout.prompt.top_left)?;
+            return text_drawer.flush();
+        }
+        RecipeStep::Confirm { coffee_grams } => {
+            let prompt = format!("Coffee {:.1}g\nPress to pour", coffee_grams);
+            text_drawer.draw_text(&prompt, layout.prompt.top_left)?;
+            return text_drawer.flush();
+        }
+        RecipeStep::Done {
+            coffee_grams,
+            water_grams,
+        } => {
+            let prompt = format!(
+                "Done {:.0}g\n1:{:.1}",
+                water_grams,
+                water_grams / coffee_grams
+            );
+            text_drawer.draw_text(&prompt, layout.prompt.top_left)?;
+            return text_drawer.flush();
+        }
+        RecipeStep::WeighCoffee { dose_grams } => (
+            format!("{:.1}", recipe.grams()),
+            format!("of {:.1}g", dose_grams),
+        ),
+        RecipeStep::Brewing { target_grams, .. } => (
+            format!("{:.0}", recipe.grams()),
+            format!("{:.0}g left", (target_grams - recipe.grams()).max(0.0)),
+        ),
+    };
+
+    match (layout.unit, layout.flow_rate) {
+        (Some(unit_region), Some(flow_rate_region)) => {
+            text_drawer.draw_text(&weight, layout.weight.top_left)?;
+            text_drawer.draw_text("g", unit_region.top_left)?;
+            text_drawer.draw_text(&detail, flow_rate_region.top_left)?;
+        }
+        _ => {
+            text_drawer.draw_text(&format!("{}g {}", weight, detail), layout.weight.top_left)?;
+        }
+    }
+    match recipe.progress() {
+        Some(progress) => draw_progress_bar(text_drawer, progress)?,
+        None => draw_status_icons(text_drawer, icons)?,
+    }
+    text_drawer.flush()
+}
@@ -0,0 +1,175 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU8, Ordering},
+        mpsc::channel,
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use esp_idf_hal::{
+    adc::{
+        attenuation::DB_11,
+        oneshot::{config::AdcChannelConfig, AdcChannelDriver, AdcDriver},
+        ADC1,
+    },
+    gpio::Gpio34,
+};
+use log::warn;
+
+use crate::settings::Settings;
+
+const BATTERY_TASK_STACK_SIZE: usize = 3 * 1024;
+const SAMPLE_PERIOD: Duration = Duration::from_secs(5);
+/// Conversions averaged per sample
+const READS_PER_SAMPLE: u32 = 16;
+/// Consecutive samples below the cutoff before the battery counts as low, so
+/// a short dip under load does not shut the scale down
+const LOW_SAMPLES: u8 = 3;
+
+/// Resting voltage of a LiPo cell against its approximate charge
+const DISCHARGE_CURVE: [(f32, u8); 21] = [
+    (3.27, 0),
+    (3.61, 5),
+    (3.69, 10),
+    (3.71, 15),
+    (3.73, 20),
+    (3.75, 25),
+    (3.77, 30),
+    (3.79, 35),
+    (3.80, 40),
+    (3.82, 45),
+    (3.84, 50),
+    (3.85, 55),
+    (3.87, 60),
+    (3.91, 65),
+    (3.95, 70),
+    (3.98, 75),
+    (4.02, 80),
+    (4.08, 85),
+    (4.11, 90),
+    (4.15, 95),
+    (4.20, 100),
+];
+
+/// Approximate charge in percent of a LiPo cell at the given voltage
+pub fn percent_from_voltage(volts: f32) -> u8 {
+    let (first_volts, first_percent) = DISCHARGE_CURVE[0];
+    if volts <= first_volts {
+        return first_percent;
+    }
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let [(low_volts, low_percent), (high_volts, high_percent)] = [pair[0], pair[1]];
+        if volts <= high_volts {
+            let fraction = (volts - low_volts) / (high_volts - low_volts);
+            let span = f32::from(high_percent - low_percent);
+            return low_percent + (fraction * span).round() as u8;
+        }
+    }
+    100
+}
+
+/// Latest battery measurement, updated by the sampling task
+#[derive(Clone)]
+pub struct BatteryHandle {
+    /// Pack voltage in millivolts, 0 until the first sample
+    millivolts: Arc<AtomicU32>,
+    low_samples: Arc<AtomicU8>,
+}
+
+impl BatteryHandle {
+    /// Pack voltage, `None` until the first sample
+    pub fn voltage(&self) -> Option<f32> {
+        match self.millivolts.load(Ordering::Relaxed) {
+            0 => None,
+            millivolts => Some(millivolts as f32 / 1000.0),
+        }
+    }
+
+    pub fn percent(&self) -> Option<u8> {
+        self.voltage().map(percent_from_voltage)
+    }
+
+    /// Whether the voltage stayed below the cutoff for a few samples
+    pub fn is_low(&self) -> bool {
+        self.low_samples.load(Ordering::Relaxed) >= LOW_SAMPLES
+    }
+}
+
+/// Start sampling the battery through the divider on GPIO34
+pub fn start_battery_task(
+    adc: ADC1,
+    pin: Gpio34,
+    settings: &Settings,
+) -> anyhow::Result<BatteryHandle> {
+    let divider = settings.battery_divider();
+    let cutoff_millivolts = (settings.battery_cutoff() * 1000.0) as u32;
+    let handle = BatteryHandle {
+        millivolts: Arc::new(AtomicU32::new(0)),
+        low_samples: Arc::new(AtomicU8::new(0)),
+    };
+
+    // The channel borrows the driver, so both are created on the task, which
+    // reports back whether that worked
+    let (ready_tx, ready_rx) = channel();
+    let task_handle = handle.clone();
+    std::thread::Builder::new()
+        .name("battery".to_string())
+        .stack_size(BATTERY_TASK_STACK_SIZE)
+        .spawn(move || {
+            let config = AdcChannelConfig {
+                attenuation: DB_11,
+                calibration: true,
+                ..Default::default()
+            };
+            let adc = match AdcDriver::new(adc) {
+                Ok(adc) => adc,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                }
+            };
+            let mut channel = match AdcChannelDriver::new(&adc, pin, &config) {
+                Ok(channel) => channel,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(()));
+
+            loop {
+                let mut sum = 0;
+                let mut reads = 0;
+                for _ in 0..READS_PER_SAMPLE {
+                    match adc.read(&mut channel) {
+                        Ok(millivolts) => {
+                            sum += u32::from(millivolts);
+                            reads += 1;
+                        }
+                        Err(err) => warn!("Battery ADC read failed: {:?}", err),
+                    }
+                }
+                if reads > 0 {
+                    let millivolts = (sum as f32 / reads as f32 * divider) as u32;
+                    task_handle.millivolts.store(millivolts, Ordering::Relaxed);
+                    let low_samples = &task_handle.low_samples;
+                    if millivolts < cutoff_millivolts {
+                        let _ =
+                            low_samples.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                                Some(n.saturating_add(1))
+                            });
+                    } else {
+                        low_samples.store(0, Ordering::Relaxed);
+                    }
+                }
+                std::thread::sleep(SAMPLE_PERIOD);
+            }
+        })?;
+
+    ready_rx
+        .recv()
+        .map_err(|_| anyhow!("Battery task exited"))??;
+    Ok(handle)
+}
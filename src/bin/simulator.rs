@@ -0,0 +1,471 @@
+//! The scale on the host: the load cell is driven from the keyboard or a
+//! script, the panel is a window and the settings live in memory. The main
+//! loop of `app` runs as on the scale, with the sampling task, the button
+//! task, the console on stdin and the storage service, so the pages, the
+//! menu, the commands and the idle stages all run here. What needs esp-idf
+//! (the networking, the weight log, OTA and the reset counters) is left
+//! out of the `Services`.
+//!
+//! Keys: up/down add or remove 10g, page up/down 100g, `t` is the button,
+//! escape quits. A script given as the only argument holds lines of
+//! `<seconds> <grams>`, `<seconds> press` or `<seconds> release`.
+//!
+//! Built for the scale, e.g. by `--all-features`, it is left without the
+//! window, which needs SDL2.
+
+// Only the window is left out for the scale
+#![cfg_attr(target_os = "espidf", allow(dead_code, unused_imports))]
+
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::sync_channel,
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use display_interface::{DataFormat, DisplayError as InterfaceError, WriteOnlyDataCommand};
+use embedded_graphics::{
+    mono_font::iso_8859_1::FONT_7X13_BOLD, pixelcolor::BinaryColor, prelude::*,
+};
+#[cfg(not(target_os = "espidf"))]
+use embedded_graphics_simulator::{
+    sdl2::Keycode, BinaryColorTheme, OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent,
+    Window,
+};
+use esp32::{
+    alarms::AlarmStore,
+    app::{self, Services},
+    button::ButtonPin,
+    certified,
+    command_channel::command_channel,
+    console, counters, display,
+    error::FirmwareError,
+    feedback::start_feedback_task,
+    i18n,
+    lock::LockHandle,
+    logger,
+    scale::{watch_scale_button, Scale},
+    sensor::{LoadSensor, SensorError},
+    session::SessionStore,
+    settings::SettingsStore,
+    storage::{self, MemoryStore, StorageService},
+    text_drawer::TextDrawer,
+};
+use log::{error, info, warn, LevelFilter};
+use ssd1306::{prelude::*, Ssd1306};
+
+const WIDTH: u32 = 128;
+const HEIGHT: u32 = 64;
+const WINDOW_SCALE: u32 = 3;
+/// Period the window is redrawn and its events handled at
+const WINDOW_PERIOD: Duration = Duration::from_millis(20);
+/// Period of the simulated HX711 readings, 10 samples per second
+const READING_PERIOD: Duration = Duration::from_millis(100);
+/// Address the simulated panel answers at
+const PANEL_ADDRESS: u8 = 0x3C;
+
+/// Raw counts of the empty platform
+const SENSOR_ZERO_COUNTS: i32 = 84_000;
+/// Raw counts per gram of the simulated load cell
+const SENSOR_COUNTS_PER_GRAM: f32 = 420.0;
+/// Peak noise of a reading in counts
+const SENSOR_NOISE_COUNTS: u32 = 60;
+const SMALL_STEP_GRAMS: f32 = 10.0;
+const LARGE_STEP_GRAMS: f32 = 100.0;
+
+/// Panel memory in the SSD1306 layout: a byte is a column of 8 pixels of a
+/// page, the least significant bit on top
+struct Frame {
+    bytes: Vec<u8>,
+}
+
+impl Frame {
+    fn new() -> Self {
+        Self {
+            bytes: vec![0; (WIDTH * HEIGHT / 8) as usize],
+        }
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> BinaryColor {
+        let byte = self.bytes[((y / 8) * WIDTH + x) as usize];
+        BinaryColor::from(byte >> (y % 8) & 1 != 0)
+    }
+}
+
+/// What the window and the application share: the panel memory, the load
+/// on the platform and the button
+#[derive(Clone)]
+struct Bench {
+    frame: Arc<Mutex<Frame>>,
+    load_grams: Arc<Mutex<f32>>,
+    pressed: Arc<AtomicBool>,
+}
+
+impl Bench {
+    fn new() -> Self {
+        Self {
+            frame: Arc::new(Mutex::new(Frame::new())),
+            load_grams: Arc::new(Mutex::new(0.0)),
+            pressed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn frame(&self) -> MutexGuard<'_, Frame> {
+        self.frame
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn load(&self) -> MutexGuard<'_, f32> {
+        self.load_grams
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn set_load(&self, grams: f32) {
+        let mut load = self.load();
+        *load = grams.max(0.0);
+        info!("Load: {}g", *load);
+    }
+
+    fn add_load(&self, grams: f32) {
+        let load = *self.load();
+        self.set_load(load + grams);
+    }
+
+    fn set_pressed(&self, pressed: bool) {
+        self.pressed.store(pressed, Ordering::Relaxed);
+    }
+}
+
+/// Interface of the driver writing into a `Frame` the way the controller
+/// does, following the column and page windows set before each flush. Only
+/// horizontal addressing without rotation is decoded.
+struct SimulatedPanel {
+    frame: Arc<Mutex<Frame>>,
+    columns: (u8, u8),
+    pages: (u8, u8),
+    column: u8,
+    page: u8,
+}
+
+impl SimulatedPanel {
+    fn new(frame: Arc<Mutex<Frame>>) -> Self {
+        Self {
+            frame,
+            columns: (0, WIDTH as u8 - 1),
+            pages: (0, (HEIGHT / 8) as u8 - 1),
+            column: 0,
+            page: 0,
+        }
+    }
+
+    fn write(&mut self, frame: &mut Frame, byte: u8) {
+        let index = usize::from(self.page) * WIDTH as usize + usize::from(self.column);
+        if let Some(cell) = frame.bytes.get_mut(index) {
+            *cell = byte;
+        }
+        if self.column < self.columns.1 {
+            self.column += 1;
+            return;
+        }
+        self.column = self.columns.0;
+        self.page = if self.page < self.pages.1 {
+            self.page + 1
+        } else {
+            self.pages.0
+        };
+    }
+}
+
+impl WriteOnlyDataCommand for SimulatedPanel {
+    fn send_commands(&mut self, cmd: DataFormat<'_>) -> Result<(), InterfaceError> {
+        let DataFormat::U8(bytes) = cmd else {
+            return Err(InterfaceError::DataFormatNotImplemented);
+        };
+        // Each command comes in its own call, only the addressing matters
+        match *bytes {
+            [0x21, start, end] => {
+                self.columns = (start, end);
+                self.column = start;
+            }
+            [0x22, start, end] => {
+                self.pages = (start, end);
+                self.page = start;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn send_data(&mut self, buf: DataFormat<'_>) -> Result<(), InterfaceError> {
+        let DataFormat::U8(bytes) = buf else {
+            return Err(InterfaceError::DataFormatNotImplemented);
+        };
+        let frame = self.frame.clone();
+        let mut frame = frame
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for &byte in bytes {
+            self.write(&mut frame, byte);
+        }
+        Ok(())
+    }
+}
+
+/// Simulated HX711 under the load of the bench, converting every
+/// `READING_PERIOD`
+struct Sensor {
+    load_grams: Arc<Mutex<f32>>,
+    noise_state: u32,
+    last_read: Instant,
+}
+
+impl LoadSensor for Sensor {
+    fn is_ready(&mut self) -> bool {
+        self.last_read.elapsed() >= READING_PERIOD
+    }
+
+    fn read(&mut self) -> Result<i32, SensorError> {
+        self.last_read = Instant::now();
+        let load_grams = *self
+            .load_grams
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Linear congruential noise, good enough to exercise the filter
+        self.noise_state = self
+            .noise_state
+            .wrapping_mul(1_103_515_245)
+            .wrapping_add(12_345);
+        let noise = (self.noise_state >> 16) % (2 * SENSOR_NOISE_COUNTS + 1);
+        let load = (load_grams * SENSOR_COUNTS_PER_GRAM).round() as i32;
+        Ok(SENSOR_ZERO_COUNTS + load + noise as i32 - SENSOR_NOISE_COUNTS as i32)
+    }
+}
+
+/// The `t` key, low while held like the button of the scale
+struct Key(Arc<AtomicBool>);
+
+impl ButtonPin for Key {
+    fn is_high(&mut self) -> bool {
+        !self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A change of the load or of the button at a time from the start
+enum ScriptStep {
+    Load(f32),
+    Button(bool),
+}
+
+fn load_script(path: &str) -> Result<Vec<(Duration, ScriptStep)>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut steps = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || format!("{}:{}: invalid line '{}'", path, number + 1, line);
+        let (at, what) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let at = at.parse::<f32>().map_err(|_| invalid())?;
+        let step = match what.trim() {
+            "press" => ScriptStep::Button(true),
+            "release" => ScriptStep::Button(false),
+            grams => ScriptStep::Load(grams.parse().map_err(|_| invalid())?),
+        };
+        steps.push((Duration::from_secs_f32(at.max(0.0)), step));
+    }
+    steps.sort_by_key(|(at, _)| *at);
+    Ok(steps)
+}
+
+/// Play the script against the bench, on a thread of its own
+fn start_script(bench: Bench, steps: Vec<(Duration, ScriptStep)>) {
+    thread::spawn(move || {
+        let start = Instant::now();
+        for (at, step) in steps {
+            thread::sleep(at.saturating_sub(start.elapsed()));
+            match step {
+                ScriptStep::Load(grams) => bench.set_load(grams),
+                ScriptStep::Button(pressed) => bench.set_pressed(pressed),
+            }
+        }
+    });
+}
+
+/// Bring up the services the way `main` does on the scale, over the bench,
+/// then run the application until it fails
+fn start(bench: Bench) -> Result<(), FirmwareError> {
+    display::set_detected(Some(PANEL_ADDRESS));
+    let storage_service = StorageService::start_with(MemoryStore::default())?;
+    storage::migrate(&storage_service);
+    if let Err(err) = counters::start(&storage_service) {
+        warn!("Failed to start the counters: {:?}", err);
+    }
+    let settings_store = SettingsStore::new(&storage_service).map_err(FirmwareError::Nvs)?;
+    let settings = settings_store.settings().clone();
+    logger::set_level(settings.log_level());
+    if let Err(err) = certified::start(&storage_service, settings.certified()) {
+        warn!("Failed to load the certified audit counter: {:?}", err);
+    }
+    i18n::set_language(settings.language());
+
+    let (app_event_sender, app_events) = sync_channel(app::APP_EVENT_QUEUE_LEN);
+    let button_event_handle = watch_scale_button(
+        Key(bench.pressed.clone()),
+        &settings,
+        app_event_sender.clone(),
+    );
+    let sensor = Sensor {
+        load_grams: bench.load_grams.clone(),
+        noise_state: 1,
+        last_read: Instant::now(),
+    };
+    let mut scale = Scale::builder(
+        Box::new(sensor),
+        settings.sensor(),
+        button_event_handle,
+        &storage_service,
+    )
+    .settings(&settings)
+    .build()?;
+
+    let (command_sender, commands) = command_channel();
+    console::start_console_task(command_sender.clone());
+
+    let mut services = Services {
+        storage: Some(storage_service.clone()),
+        lock: LockHandle::new(settings.lock()),
+        ..Services::default()
+    };
+    if let Err(err) = start_feedback_task(services.feedback.clone(), scale.subscribe(), &settings) {
+        warn!("Failed to start the feedback task: {:?}", err);
+    }
+    match AlarmStore::open(&storage_service) {
+        Ok(alarm_store) => services.alarm_store = Some(alarm_store),
+        Err(err) => warn!("Failed to open the alarm states: {:?}", err),
+    }
+    match SessionStore::open(&storage_service) {
+        Ok(session_store) => services.session_store = Some(session_store),
+        Err(err) => warn!("Failed to open the session stats: {:?}", err),
+    }
+
+    scale.start_sampling(app_event_sender)?;
+
+    let display = Ssd1306::new(
+        SimulatedPanel::new(bench.frame.clone()),
+        DisplaySize128x64,
+        DisplayRotation::Rotate0,
+    )
+    .into_buffered_graphics_mode();
+    let mut text_drawer = TextDrawer::new(display, &FONT_7X13_BOLD);
+    if let Err(err) = text_drawer.reinit() {
+        warn!("Failed to initialize display: {:?}", err);
+    }
+    if let Err(err) = text_drawer.set_brightness(settings.brightness()) {
+        warn!("Failed to set display brightness: {:?}", err);
+    }
+
+    let feedback = services.feedback.clone();
+    let result = app::run(
+        &mut text_drawer,
+        scale,
+        settings_store,
+        app_events,
+        commands,
+        command_sender,
+        services,
+    );
+    if let Err(err) = &result {
+        app::show_fatal_error(&mut text_drawer, &feedback, err);
+    }
+    result
+}
+
+fn main() {
+    logger::init(LevelFilter::Info);
+
+    let steps = match std::env::args().nth(1) {
+        Some(path) => match load_script(&path) {
+            Ok(steps) => steps,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let bench = Bench::new();
+    start_script(bench.clone(), steps);
+    // The window has to stay on the main thread, the application runs on
+    // one of its own and is left showing its error once it stops
+    let app_bench = bench.clone();
+    thread::spawn(move || {
+        if let Err(err) = start(app_bench) {
+            error!("The application stopped: {}", err);
+        }
+    });
+
+    run_window(&bench);
+}
+
+/// Show the panel in a window and drive the bench from the keyboard, until
+/// the window is closed
+#[cfg(not(target_os = "espidf"))]
+fn run_window(bench: &Bench) {
+    let mut screen = SimulatorDisplay::<BinaryColor>::new(Size::new(WIDTH, HEIGHT));
+    let output_settings = OutputSettingsBuilder::new()
+        .theme(BinaryColorTheme::OledBlue)
+        .scale(WINDOW_SCALE)
+        .build();
+    let mut window = Window::new("Scale", &output_settings);
+
+    'running: loop {
+        window.update(&screen);
+        for event in window.events() {
+            match event {
+                SimulatorEvent::Quit => break 'running,
+                SimulatorEvent::KeyDown {
+                    keycode,
+                    repeat: false,
+                    ..
+                } => match keycode {
+                    Keycode::Escape => break 'running,
+                    Keycode::Up => bench.add_load(SMALL_STEP_GRAMS),
+                    Keycode::Down => bench.add_load(-SMALL_STEP_GRAMS),
+                    Keycode::PageUp => bench.add_load(LARGE_STEP_GRAMS),
+                    Keycode::PageDown => bench.add_load(-LARGE_STEP_GRAMS),
+                    Keycode::T => bench.set_pressed(true),
+                    _ => {}
+                },
+                SimulatorEvent::KeyUp {
+                    keycode: Keycode::T,
+                    ..
+                } => bench.set_pressed(false),
+                _ => {}
+            }
+        }
+
+        let frame = bench.frame();
+        let pixels = (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+            .map(|(x, y)| Pixel(Point::new(x as i32, y as i32), frame.pixel(x, y)));
+        screen
+            .draw_iter(pixels)
+            .expect("the simulator display never fails");
+        drop(frame);
+
+        thread::sleep(WINDOW_PERIOD);
+    }
+}
+
+#[cfg(target_os = "espidf")]
+fn run_window(_bench: &Bench) {
+    error!("The simulator has no window on the scale");
+}
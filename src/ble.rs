@@ -0,0 +1,403 @@
+//! Weight Scale GATT service over BLE, along with the configuration
+//! service of `config`. The encoding of the Weight Measurement builds
+//! anywhere, the service itself needs the `ble` feature.
+
+#[cfg(feature = "ble")]
+mod config;
+
+#[cfg(feature = "ble")]
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "ble")]
+use esp32_nimble::{
+    utilities::BleUuid, uuid128, BLEAdvertisementData, BLEDevice, NimbleProperties,
+};
+#[cfg(feature = "ble")]
+use log::{info, warn};
+
+#[cfg(feature = "ble")]
+use self::config::{
+    notification, outcome_status, read_chunk, ControlOp, SettingsUpload, STATUS_OK, UPLOAD_TAG,
+};
+use crate::unit::Unit;
+#[cfg(feature = "ble")]
+use crate::{
+    command_channel::{Command, CommandSender},
+    events::WeightEvent,
+    governor::{self, Subsystem},
+    settings,
+    snapshot::SharedSnapshot,
+};
+
+#[cfg(feature = "ble")]
+const DEVICE_NAME: &str = "ESP32 Scale";
+#[cfg(feature = "ble")]
+const WEIGHT_SCALE_SERVICE: BleUuid = BleUuid::from_uuid16(0x181D);
+#[cfg(feature = "ble")]
+const WEIGHT_MEASUREMENT: BleUuid = BleUuid::from_uuid16(0x2A9D);
+/// Unfiltered weight in grams as a little endian f32
+#[cfg(feature = "ble")]
+const LIVE_WEIGHT: BleUuid = uuid128!("5c3e0a01-7f3b-4b8e-9d1a-2f6c8e4b7a10");
+/// Any write tares the scale
+#[cfg(feature = "ble")]
+const TARE: BleUuid = uuid128!("5c3e0a02-7f3b-4b8e-9d1a-2f6c8e4b7a10");
+#[cfg(feature = "ble")]
+const CONFIG_SERVICE: BleUuid = uuid128!("5c3e0b00-7f3b-4b8e-9d1a-2f6c8e4b7a10");
+/// Written with the offset and length of a chunk, read back with it
+#[cfg(feature = "ble")]
+const SETTINGS_BLOB: BleUuid = uuid128!("5c3e0b01-7f3b-4b8e-9d1a-2f6c8e4b7a10");
+/// Takes the chunks of the settings to apply
+#[cfg(feature = "ble")]
+const SETTINGS_UPLOAD: BleUuid = uuid128!("5c3e0b02-7f3b-4b8e-9d1a-2f6c8e4b7a10");
+/// Takes the operations, notifies their status
+#[cfg(feature = "ble")]
+const CONTROL_POINT: BleUuid = uuid128!("5c3e0b03-7f3b-4b8e-9d1a-2f6c8e4b7a10");
+
+#[cfg(feature = "ble")]
+const BLE_TASK_STACK_SIZE: usize = 4 * 1024;
+#[cfg(feature = "ble")]
+const CONFIG_TASK_STACK_SIZE: usize = 4 * 1024;
+/// Period the configuration task checks whether BLE is stopping at
+#[cfg(feature = "ble")]
+const CONFIG_POLL_PERIOD: Duration = Duration::from_millis(500);
+/// Period the live weight is notified at
+#[cfg(feature = "ble")]
+const LIVE_WEIGHT_INTERVAL: Duration = Duration::from_millis(200);
+/// Heap the NimBLE host and controller take once up, along with the task
+/// stacks. The governor admits BLE with this much over its reserve, and
+/// checks the reserve is still free once BLE is up.
+#[cfg(feature = "ble")]
+const BLE_HEAP_NEED: u32 = 56 * 1024;
+
+/// Weight Measurement resolutions of the SIG format
+const SI_RESOLUTION_KG: f32 = 0.005;
+const IMPERIAL_RESOLUTION_LB: f32 = 0.01;
+const FLAG_IMPERIAL: u8 = 0x01;
+
+/// Encode a weight in the SIG Weight Measurement format: flags followed by
+/// the weight as u16 in steps of 5g, or of 0.01lb for imperial units
+pub fn weight_measurement(grams: f32, unit: Unit) -> [u8; 3] {
+    let (flags, steps) = match unit {
+        Unit::Grams | Unit::Kilograms => (0, Unit::Kilograms.from_grams(grams) / SI_RESOLUTION_KG),
+        Unit::Ounces | Unit::Pounds => (
+            FLAG_IMPERIAL,
+            Unit::Pounds.from_grams(grams) / IMPERIAL_RESOLUTION_LB,
+        ),
+    };
+    let [low, high] = (steps.round().clamp(0.0, u16::MAX as f32) as u16).to_le_bytes();
+    [flags, low, high]
+}
+
+/// What a connection set up on the configuration service, forgotten when
+/// it disconnects
+#[cfg(feature = "ble")]
+#[derive(Debug)]
+struct ConfigSession {
+    /// Offset and length of the chunk the next read of the blob returns
+    read_request: Vec<u8>,
+    upload: SettingsUpload,
+    pin: Option<u16>,
+}
+
+#[cfg(feature = "ble")]
+impl ConfigSession {
+    fn new() -> Self {
+        Self {
+            read_request: Vec::new(),
+            upload: SettingsUpload::default(),
+            pin: None,
+        }
+    }
+}
+
+#[cfg(feature = "ble")]
+fn lock_session(session: &Mutex<ConfigSession>) -> MutexGuard<'_, ConfigSession> {
+    session
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Start the Weight Scale GATT service. Stable readings are indicated
+/// through the standard Weight Measurement characteristic, the unfiltered
+/// weight is notified through a custom one and writing the tare
+/// characteristic queues a tare like the console does. Along with it a
+/// custom configuration service reads and writes the settings and runs the
+/// operations of the control point, see `config`. Not started when the
+/// heap governor refuses it, and torn down once it asks for the heap back.
+#[cfg(feature = "ble")]
+pub fn start_ble(
+    snapshot: SharedSnapshot,
+    events: Receiver<WeightEvent>,
+    commands: CommandSender,
+) -> anyhow::Result<()> {
+    let (stop_tx, stop_rx) = channel();
+    if !governor::admit(Subsystem::Ble, BLE_HEAP_NEED, move || {
+        let _ = stop_tx.send(());
+    }) {
+        return Ok(());
+    }
+    let device = BLEDevice::take();
+    let server = device.get_server();
+    let session = Arc::new(Mutex::new(ConfigSession::new()));
+    server.on_connect(|_, desc| info!("BLE client connected: {:?}", desc.address()));
+    let disconnected_session = session.clone();
+    server.on_disconnect(move |desc, _| {
+        info!("BLE client disconnected: {:?}", desc.address());
+        *lock_session(&disconnected_session) = ConfigSession::new();
+    });
+
+    let service = server.create_service(WEIGHT_SCALE_SERVICE);
+    let measurement = service.lock().create_characteristic(
+        WEIGHT_MEASUREMENT,
+        NimbleProperties::READ | NimbleProperties::NOTIFY | NimbleProperties::INDICATE,
+    );
+    let live_weight = service.lock().create_characteristic(
+        LIVE_WEIGHT,
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+    );
+    let tare = service
+        .lock()
+        .create_characteristic(TARE, NimbleProperties::WRITE);
+    let tare_commands = commands.clone();
+    tare.lock().on_write(move |_| {
+        if tare_commands.send(Command::Tare).is_err() {
+            warn!("Tare requested over BLE, but the command channel is closed");
+        }
+    });
+
+    // The writes are taken in the callbacks, the control point is run on a
+    // task of its own since a command waits for the main loop
+    let (control_tx, control_rx) = channel();
+    let config_service = server.create_service(CONFIG_SERVICE);
+    let settings_blob = config_service.lock().create_characteristic(
+        SETTINGS_BLOB,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+    let request_session = session.clone();
+    settings_blob.lock().on_write(move |args| {
+        lock_session(&request_session).read_request = args.recv_data().to_vec();
+    });
+    let read_session = session.clone();
+    settings_blob.lock().on_read(move |value, _| {
+        let request = lock_session(&read_session).read_request.clone();
+        match read_chunk(&settings::published_blob(), &request) {
+            Ok(chunk) => {
+                value.set_value(&chunk);
+            }
+            Err(err) => warn!("Bad read request of the settings over BLE: {}", err),
+        }
+    });
+    let upload = config_service
+        .lock()
+        .create_characteristic(SETTINGS_UPLOAD, NimbleProperties::WRITE);
+    let upload_session = session.clone();
+    let upload_control_tx = control_tx.clone();
+    upload.lock().on_write(move |args| {
+        if let Err(err) = lock_session(&upload_session).upload.write(args.recv_data()) {
+            warn!("Settings upload over BLE started over: {}", err);
+            let _ = upload_control_tx.send(ControlMessage::UploadFailed(err.status()));
+        }
+    });
+    let control_point = config_service.lock().create_characteristic(
+        CONTROL_POINT,
+        NimbleProperties::WRITE | NimbleProperties::NOTIFY,
+    );
+    control_point.lock().on_write(move |args| {
+        let _ = control_tx.send(ControlMessage::Write(args.recv_data().to_vec()));
+    });
+    let stopping = Arc::new(AtomicBool::new(false));
+    let notify_control_point = control_point.clone();
+    let config_task = start_config_task(
+        session,
+        move |status| {
+            notify_control_point.lock().set_value(&status).notify();
+        },
+        control_rx,
+        commands,
+        stopping.clone(),
+    )?;
+
+    let advertising = device.get_advertising();
+    advertising.lock().set_data(
+        BLEAdvertisementData::new()
+            .name(DEVICE_NAME)
+            .add_service_uuid(WEIGHT_SCALE_SERVICE)
+            .add_service_uuid(CONFIG_SERVICE),
+    )?;
+    advertising.lock().start()?;
+
+    // Below the reserve, NimBLE took more than it was admitted with
+    governor::started(Subsystem::Ble);
+
+    std::thread::Builder::new()
+        .name("ble".to_string())
+        .stack_size(BLE_TASK_STACK_SIZE)
+        .spawn(move || {
+            let mut last_live_weight = Instant::now();
+            loop {
+                if stop_rx.try_recv().is_ok() {
+                    break;
+                }
+                match events.recv_timeout(LIVE_WEIGHT_INTERVAL) {
+                    Ok(WeightEvent::Stable { grams }) => {
+                        let unit = snapshot.get().unit;
+                        measurement
+                            .lock()
+                            .set_value(&weight_measurement(grams, unit))
+                            .notify();
+                    }
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+
+                // Notifying without subscribers only updates the value
+                if last_live_weight.elapsed() >= LIVE_WEIGHT_INTERVAL {
+                    last_live_weight = Instant::now();
+                    let grams_raw = snapshot.get().grams_raw;
+                    live_weight
+                        .lock()
+                        .set_value(&grams_raw.to_le_bytes())
+                        .notify();
+                }
+            }
+            // Nothing may touch the characteristics once the stack is down
+            stopping.store(true, Ordering::Relaxed);
+            let _ = config_task.join();
+            drop((measurement, live_weight, control_point));
+            match BLEDevice::deinit() {
+                Ok(()) => info!("BLE stopped"),
+                Err(err) => warn!("Failed to stop BLE: {:?}", err),
+            }
+        })?;
+
+    Ok(())
+}
+
+/// What the configuration task is handed from the callbacks
+#[cfg(feature = "ble")]
+enum ControlMessage {
+    /// Bytes written to the control point
+    Write(Vec<u8>),
+    /// Status of a chunk of the upload that failed
+    UploadFailed(u8),
+}
+
+/// Run the operations written to the control point, notifying the status
+/// of each. A guarded one goes along with the PIN of the connection.
+#[cfg(feature = "ble")]
+fn start_config_task(
+    session: Arc<Mutex<ConfigSession>>,
+    notify: impl Fn([u8; 2]) + Send + 'static,
+    messages: Receiver<ControlMessage>,
+    commands: CommandSender,
+    stopping: Arc<AtomicBool>,
+) -> std::io::Result<std::thread::JoinHandle<()>> {
+    std::thread::Builder::new()
+        .name("ble_config".to_string())
+        .stack_size(CONFIG_TASK_STACK_SIZE)
+        .spawn(move || {
+            while !stopping.load(Ordering::Relaxed) {
+                let request = match messages.recv_timeout(CONFIG_POLL_PERIOD) {
+                    Ok(ControlMessage::Write(request)) => request,
+                    Ok(ControlMessage::UploadFailed(status)) => {
+                        notify([UPLOAD_TAG, status]);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+                let status = match ControlOp::parse(&request) {
+                    Ok(ControlOp::Pin(pin)) => {
+                        lock_session(&session).pin = Some(pin);
+                        STATUS_OK
+                    }
+                    Ok(ControlOp::Apply { len, crc }) => {
+                        let uploaded = lock_session(&session).upload.finish(len, crc);
+                        match uploaded {
+                            Ok(settings) => run(
+                                &session,
+                                &commands,
+                                Command::ImportSettings(Box::new(settings)),
+                            ),
+                            Err(err) => {
+                                warn!("Settings uploaded over BLE rejected: {}", err);
+                                err.status()
+                            }
+                        }
+                    }
+                    Ok(op) => match op.command() {
+                        Some(command) => run(&session, &commands, command),
+                        None => STATUS_OK,
+                    },
+                    Err(err) => err.status(),
+                };
+                notify(notification(&request, status));
+            }
+        })
+}
+
+/// Run the command on the main loop, along with the PIN of the connection
+/// if it has one, and return the status of the outcome
+#[cfg(feature = "ble")]
+fn run(session: &Mutex<ConfigSession>, commands: &CommandSender, command: Command) -> u8 {
+    let command = match lock_session(session).pin {
+        Some(pin) if command.is_guarded() => Command::WithPin(pin, Box::new(command)),
+        _ => command,
+    };
+    outcome_status(commands.request(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(bytes: [u8; 3]) -> u16 {
+        u16::from_le_bytes([bytes[1], bytes[2]])
+    }
+
+    #[test]
+    fn flags_follow_the_unit() {
+        assert_eq!(weight_measurement(1000.0, Unit::Grams), [0x00, 200, 0]);
+        assert_eq!(weight_measurement(1000.0, Unit::Kilograms), [0x00, 200, 0]);
+        // 1 lb in steps of 0.01 lb
+        assert_eq!(weight_measurement(453.59237, Unit::Pounds), [0x01, 100, 0]);
+        assert_eq!(weight_measurement(453.59237, Unit::Ounces), [0x01, 100, 0]);
+    }
+
+    #[test]
+    fn rounds_to_the_resolution() {
+        // Steps of 5g
+        assert_eq!(steps(weight_measurement(1234.0, Unit::Grams)), 247);
+        assert_eq!(steps(weight_measurement(2.4, Unit::Grams)), 0);
+        assert_eq!(steps(weight_measurement(2.6, Unit::Grams)), 1);
+        assert_eq!(steps(weight_measurement(7.4, Unit::Grams)), 1);
+        // Steps of 0.01 lb, 4.54g
+        assert_eq!(steps(weight_measurement(2.2, Unit::Pounds)), 0);
+        assert_eq!(steps(weight_measurement(2.4, Unit::Pounds)), 1);
+        assert_eq!(steps(weight_measurement(1000.0, Unit::Pounds)), 220);
+    }
+
+    #[test]
+    fn saturates_at_both_ends() {
+        assert_eq!(weight_measurement(-50.0, Unit::Grams), [0x00, 0, 0]);
+        assert_eq!(weight_measurement(-50.0, Unit::Pounds), [0x01, 0, 0]);
+        // The largest weight carried is 327.675 kg, or 655.35 lb
+        assert_eq!(steps(weight_measurement(327_675.0, Unit::Grams)), u16::MAX);
+        assert_eq!(
+            weight_measurement(400_000.0, Unit::Kilograms),
+            [0x00, 0xFF, 0xFF]
+        );
+        assert_eq!(steps(weight_measurement(297_000.0, Unit::Pounds)), 65_477);
+        assert_eq!(
+            weight_measurement(400_000.0, Unit::Pounds),
+            [0x01, 0xFF, 0xFF]
+        );
+    }
+}
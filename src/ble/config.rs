@@ -0,0 +1,249 @@
+//! Protocol of the configuration service, for a companion app changing the
+//! settings without Wi-Fi. The settings blob is longer than the MTU, so it
+//! goes in chunks both ways.
+//!
+//! Reading: the app writes the offset and the length it wants, two u16 LE,
+//! to the blob characteristic and reads back the total length and the
+//! offset, two u16 LE, followed by the chunk. The blob is the one last
+//! saved, without the passwords, the update token and the lock PIN.
+//!
+//! Writing: the app writes each chunk to the upload characteristic as its
+//! offset, u16 LE, followed by the bytes, in order from offset 0, which
+//! starts the upload over. Nothing is applied before the apply operation on
+//! the control point, carrying the length and the CRC32 of the whole blob;
+//! the blob is then decoded and replaces the settings at once, or not at
+//! all.
+//!
+//! Control point: an opcode followed by its arguments. Every write is
+//! answered with a notification of the opcode and a status code. A chunk
+//! of the upload out of order or past the longest settings is notified
+//! under `UPLOAD_TAG`, the upload starting over.
+
+use thiserror::Error;
+
+use crate::{
+    command_channel::{Command, CommandError, CommandOutcome, RemoteCalibration},
+    envelope::crc32,
+    lock::LockError,
+    settings::{Settings, SETTINGS_MAX_LEN, SETTINGS_VERSION},
+    shutdown::ShutdownReason,
+};
+
+/// Bytes of a chunk read at most, fitting a 185 byte MTU with the header
+pub const MAX_CHUNK_LEN: usize = 176;
+
+const OP_TARE: u8 = 0x01;
+const OP_CALIBRATE: u8 = 0x02;
+const OP_CALIBRATE_STEP: u8 = 0x03;
+const OP_REBOOT: u8 = 0x04;
+const OP_PIN: u8 = 0x05;
+const OP_APPLY: u8 = 0x06;
+/// Stands in for the opcode in the notification of a failed upload
+pub const UPLOAD_TAG: u8 = 0x80;
+
+/// Status codes of the control point notifications
+pub const STATUS_OK: u8 = 0x00;
+pub const STATUS_STARTED: u8 = 0x01;
+pub const STATUS_QUEUED: u8 = 0x02;
+pub const STATUS_LOCKED: u8 = 0x10;
+pub const STATUS_WRONG_PIN: u8 = 0x11;
+pub const STATUS_RATE_LIMITED: u8 = 0x12;
+pub const STATUS_REFUSED: u8 = 0x20;
+pub const STATUS_UNKNOWN_OP: u8 = 0x30;
+pub const STATUS_MALFORMED: u8 = 0x31;
+pub const STATUS_OUT_OF_ORDER: u8 = 0x32;
+pub const STATUS_TOO_LONG: u8 = 0x33;
+pub const STATUS_BAD_LENGTH: u8 = 0x34;
+pub const STATUS_BAD_CHECKSUM: u8 = 0x35;
+pub const STATUS_BAD_SETTINGS: u8 = 0x36;
+pub const STATUS_TIMEOUT: u8 = 0x40;
+pub const STATUS_UNAVAILABLE: u8 = 0x41;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("Unknown opcode {0:#04x}")]
+    UnknownOp(u8),
+    #[error("Malformed write")]
+    Malformed,
+    #[error("Chunk at {got}, expected {expected}")]
+    OutOfOrder { expected: usize, got: usize },
+    #[error("Settings longer than {SETTINGS_MAX_LEN} bytes")]
+    TooLong,
+    #[error("Uploaded {uploaded} bytes, {expected} announced")]
+    BadLength { expected: usize, uploaded: usize },
+    #[error("Checksum mismatch")]
+    BadChecksum,
+    #[error("Not settings of version {SETTINGS_VERSION} or older")]
+    BadSettings,
+}
+
+impl ConfigError {
+    pub fn status(self) -> u8 {
+        match self {
+            ConfigError::UnknownOp(_) => STATUS_UNKNOWN_OP,
+            ConfigError::Malformed => STATUS_MALFORMED,
+            ConfigError::OutOfOrder { .. } => STATUS_OUT_OF_ORDER,
+            ConfigError::TooLong => STATUS_TOO_LONG,
+            ConfigError::BadLength { .. } => STATUS_BAD_LENGTH,
+            ConfigError::BadChecksum => STATUS_BAD_CHECKSUM,
+            ConfigError::BadSettings => STATUS_BAD_SETTINGS,
+        }
+    }
+}
+
+/// Status code of what became of a command on the main loop
+pub fn outcome_status(outcome: Result<CommandOutcome, CommandError>) -> u8 {
+    match outcome {
+        Ok(CommandOutcome::Done) => STATUS_OK,
+        Ok(CommandOutcome::Started) => STATUS_STARTED,
+        Ok(CommandOutcome::Queued) => STATUS_QUEUED,
+        Ok(CommandOutcome::Locked(LockError::Locked)) => STATUS_LOCKED,
+        Ok(CommandOutcome::Locked(LockError::WrongPin | LockError::WrongPattern)) => {
+            STATUS_WRONG_PIN
+        }
+        Ok(CommandOutcome::Locked(LockError::RateLimited(_))) => STATUS_RATE_LIMITED,
+        Ok(CommandOutcome::Refused(_)) => STATUS_REFUSED,
+        Err(CommandError::Timeout) => STATUS_TIMEOUT,
+        Err(CommandError::Disconnected) => STATUS_UNAVAILABLE,
+    }
+}
+
+/// Chunk of the blob asked for by a read request, behind the header
+pub fn read_chunk(blob: &[u8], request: &[u8]) -> Result<Vec<u8>, ConfigError> {
+    let (offset, len) = match *request {
+        [] => (0, MAX_CHUNK_LEN),
+        [a, b] => (usize::from(u16::from_le_bytes([a, b])), MAX_CHUNK_LEN),
+        [a, b, c, d] => (
+            usize::from(u16::from_le_bytes([a, b])),
+            usize::from(u16::from_le_bytes([c, d])).min(MAX_CHUNK_LEN),
+        ),
+        _ => return Err(ConfigError::Malformed),
+    };
+    let start = offset.min(blob.len());
+    let end = (start + len).min(blob.len());
+    let mut chunk = Vec::with_capacity(4 + end - start);
+    chunk.extend_from_slice(&(blob.len() as u16).to_le_bytes());
+    chunk.extend_from_slice(&(start as u16).to_le_bytes());
+    chunk.extend_from_slice(&blob[start..end]);
+    Ok(chunk)
+}
+
+/// Settings blob put together from the chunks written
+#[derive(Debug, Default)]
+pub struct SettingsUpload {
+    bytes: Vec<u8>,
+}
+
+impl SettingsUpload {
+    /// Take a chunk, its offset first. Offset 0 starts over, any other has
+    /// to follow the chunk before it.
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), ConfigError> {
+        let [a, b, data @ ..] = chunk else {
+            return Err(ConfigError::Malformed);
+        };
+        let offset = usize::from(u16::from_le_bytes([*a, *b]));
+        if offset == 0 {
+            self.bytes.clear();
+        }
+        if offset != self.bytes.len() {
+            let expected = self.bytes.len();
+            self.bytes.clear();
+            return Err(ConfigError::OutOfOrder {
+                expected,
+                got: offset,
+            });
+        }
+        if offset + data.len() > SETTINGS_MAX_LEN {
+            self.bytes.clear();
+            return Err(ConfigError::TooLong);
+        }
+        self.bytes.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// The settings uploaded, checked against the length and the CRC32
+    /// announced. The upload is over either way.
+    pub fn finish(&mut self, len: usize, crc: u32) -> Result<Settings, ConfigError> {
+        let bytes = std::mem::take(&mut self.bytes);
+        if bytes.len() != len {
+            return Err(ConfigError::BadLength {
+                expected: len,
+                uploaded: bytes.len(),
+            });
+        }
+        if crc32(&bytes) != crc {
+            return Err(ConfigError::BadChecksum);
+        }
+        match Settings::decode(&bytes) {
+            Some((settings, version)) if version <= SETTINGS_VERSION => Ok(settings),
+            _ => Err(ConfigError::BadSettings),
+        }
+    }
+}
+
+/// Operation written to the control point
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlOp {
+    Tare,
+    /// Start the remote calibration with the known weight in grams
+    Calibrate(f32),
+    /// Go on with the remote calibration
+    CalibrateStep,
+    Reboot,
+    /// PIN letting the guarded operations of the connection through a
+    /// locked scale
+    Pin(u16),
+    /// Apply the settings uploaded, of the length and the CRC32
+    Apply {
+        len: usize,
+        crc: u32,
+    },
+}
+
+impl ControlOp {
+    pub fn parse(bytes: &[u8]) -> Result<Self, ConfigError> {
+        let (&opcode, args) = bytes.split_first().ok_or(ConfigError::Malformed)?;
+        let op = match (opcode, args) {
+            (OP_TARE, []) => ControlOp::Tare,
+            (OP_CALIBRATE, &[a, b, c, d]) => {
+                let grams = f32::from_le_bytes([a, b, c, d]);
+                if !(grams.is_finite() && grams > 0.0) {
+                    return Err(ConfigError::Malformed);
+                }
+                ControlOp::Calibrate(grams)
+            }
+            (OP_CALIBRATE_STEP, []) => ControlOp::CalibrateStep,
+            (OP_REBOOT, []) => ControlOp::Reboot,
+            (OP_PIN, &[a, b]) => ControlOp::Pin(u16::from_le_bytes([a, b])),
+            (OP_APPLY, &[a, b, c, d, e, f]) => ControlOp::Apply {
+                len: usize::from(u16::from_le_bytes([a, b])),
+                crc: u32::from_le_bytes([c, d, e, f]),
+            },
+            (OP_TARE | OP_CALIBRATE | OP_CALIBRATE_STEP | OP_REBOOT | OP_PIN | OP_APPLY, _) => {
+                return Err(ConfigError::Malformed)
+            }
+            (opcode, _) => return Err(ConfigError::UnknownOp(opcode)),
+        };
+        Ok(op)
+    }
+
+    /// The command for the main loop, none for the PIN and the settings
+    /// applied by the service
+    pub fn command(self) -> Option<Command> {
+        match self {
+            ControlOp::Tare => Some(Command::Tare),
+            ControlOp::Calibrate(grams) => {
+                Some(Command::RemoteCalibration(RemoteCalibration::Start(grams)))
+            }
+            ControlOp::CalibrateStep => Some(Command::RemoteCalibration(RemoteCalibration::Step)),
+            ControlOp::Reboot => Some(Command::Restart(ShutdownReason::Command)),
+            ControlOp::Pin(_) | ControlOp::Apply { .. } => None,
+        }
+    }
+}
+
+/// Notification answering a write to the control point, the opcode, or
+/// zero for a write too short to have one, and the status
+pub fn notification(request: &[u8], status: u8) -> [u8; 2] {
+    [request.first().copied().unwrap_or(0), status]
+}
@@ -0,0 +1,178 @@
+//! Brew timer for coffee: armed after a tare, it starts on the first drip
+//! and stops once the flow ceased for a grace period, keeping the final time
+//! and weight.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::settings::Settings;
+
+/// Span of weight the flow rate is measured over
+const FLOW_WINDOW: Duration = Duration::from_secs(1);
+
+/// Thresholds of the brew timer, from the settings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BrewConfig {
+    /// Weight starting the timer
+    pub start_grams: f32,
+    /// Flow rate below which the flow counts as stopped
+    pub stop_grams_per_sec: f32,
+    /// Time the flow has to stay stopped for the timer to stop
+    pub stop_grace: Duration,
+}
+
+impl BrewConfig {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            start_grams: settings.brew_start_grams(),
+            stop_grams_per_sec: settings.brew_stop_grams_per_sec(),
+            stop_grace: settings.brew_stop_grace(),
+        }
+    }
+}
+
+/// Flow rate over a sliding window of weights
+#[derive(Debug, Default)]
+pub struct FlowMeter {
+    samples: VecDeque<(Instant, f32)>,
+}
+
+impl FlowMeter {
+    pub fn add(&mut self, grams: f32, now: Instant) {
+        self.samples.push_back((now, grams));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > FLOW_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Grams per second over the window, 0 until two weights are known
+    pub fn grams_per_sec(&self) -> f32 {
+        let (Some((first_at, first)), Some((last_at, last))) =
+            (self.samples.front(), self.samples.back())
+        else {
+            return 0.0;
+        };
+        let secs = last_at.duration_since(*first_at).as_secs_f32();
+        if secs > 0.0 {
+            (last - first) / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrewState {
+    /// Waiting for the first drip
+    Armed,
+    Running {
+        started: Instant,
+        /// Since when the flow has been stopped, if it is
+        stopped_since: Option<Instant>,
+    },
+    /// Frozen on the final time and weight
+    Finished { elapsed: Duration, grams: f32 },
+}
+
+#[derive(Debug)]
+pub struct BrewTimer {
+    config: BrewConfig,
+    state: BrewState,
+    flow: FlowMeter,
+    grams: f32,
+}
+
+impl BrewTimer {
+    /// Arm the timer, for a freshly tared scale
+    pub fn arm(config: BrewConfig) -> Self {
+        Self {
+            config,
+            state: BrewState::Armed,
+            flow: FlowMeter::default(),
+            grams: 0.0,
+        }
+    }
+
+    pub fn state(&self) -> BrewState {
+        self.state
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, BrewState::Finished { .. })
+    }
+
+    /// Follow the filtered weight
+    pub fn on_weight(&mut self, grams: f32, now: Instant) {
+        if self.is_finished() {
+            return;
+        }
+        self.grams = grams;
+        self.flow.add(grams, now);
+        let flow_stopped = self.flow.grams_per_sec() < self.config.stop_grams_per_sec;
+
+        self.state = match self.state {
+            BrewState::Armed if grams >= self.config.start_grams => BrewState::Running {
+                started: now,
+                stopped_since: None,
+            },
+            BrewState::Running {
+                started,
+                stopped_since,
+            } => match (flow_stopped, stopped_since) {
+                (true, Some(since)) if now.duration_since(since) >= self.config.stop_grace => {
+                    // The brew ended when the flow stopped, not after the grace
+                    BrewState::Finished {
+                        elapsed: since.duration_since(started),
+                        grams,
+                    }
+                }
+                (true, since) => BrewState::Running {
+                    started,
+                    stopped_since: since.or(Some(now)),
+                },
+                (false, _) => BrewState::Running {
+                    started,
+                    stopped_since: None,
+                },
+            },
+            state => state,
+        };
+    }
+
+    /// Time shown on the timer
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        match self.state {
+            BrewState::Armed => Duration::ZERO,
+            BrewState::Running { started, .. } => now.duration_since(started),
+            BrewState::Finished { elapsed, .. } => elapsed,
+        }
+    }
+
+    /// Weight shown along with the timer
+    pub fn grams(&self) -> f32 {
+        match self.state {
+            BrewState::Finished { grams, .. } => grams,
+            _ => self.grams,
+        }
+    }
+
+    /// Current flow rate, 0 once finished
+    pub fn grams_per_sec(&self) -> f32 {
+        match self.state {
+            BrewState::Finished { .. } => 0.0,
+            _ => self.flow.grams_per_sec().max(0.0),
+        }
+    }
+}
+
+/// Format a timer as `m:ss.s`
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let tenths = elapsed.as_millis() / 100;
+    format!("{}:{:02}.{}", tenths / 600, tenths / 10 % 60, tenths % 10)
+}
@@ -1,79 +1,214 @@
 use std::time::{Duration, Instant};
 
-use esp_idf_hal::delay::FreeRtos;
+#[cfg(feature = "esp")]
 use esp_idf_hal::gpio::{Input, InputPin, Level, OutputPin, PinDriver, Pull};
+#[cfg(feature = "esp")]
 use esp_idf_sys::EspError;
-use log::{error, info};
-use std::sync::mpsc::{channel, Receiver, Sender};
 
-const CONFIG_ESP32_BUTTON_LONG_PRESS_DURATION_MS: Duration = Duration::from_millis(3000);
+use crate::counters::{self, Counter};
+use crate::watchdog::WatchdogGuard;
+use log::{error, info, warn};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 const CONFIG_ESP32_POLLING_PERIOD_MS: Duration = Duration::from_millis(10);
 
 const HISTORY_MASK: u16 = 0b1111_0000_0011_1111;
 
-#[derive(PartialEq, Eq)]
+const DOUBLE_PRESS_WINDOW_MS: Duration = Duration::from_millis(400);
+
+/// Period `wait_for_event` calls back at while no event arrives
+const WAIT_POLL_PERIOD: Duration = Duration::from_millis(500);
+
+/// Time held down after which the button is taken as stuck, by default
+pub const DEFAULT_BUTTON_STUCK_S: u32 = 30;
+pub const MAX_BUTTON_STUCK_S: u32 = 600;
+/// Time a stuck button has to stay released to count again
+const STUCK_RECOVERY: Duration = Duration::from_secs(1);
+/// Hours without a press while weighings go on after which a disconnected
+/// button is suspected, by default
+pub const DEFAULT_BUTTON_DISCONNECT_H: u32 = 12;
+pub const MAX_BUTTON_DISCONNECT_H: u32 = 24 * 7;
+/// Weighings counted without a press before a disconnect is suspected
+const DISCONNECT_MIN_WEIGHINGS: u32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ButtonEvent {
     Up,
     Down,
     Held,
+    /// Held down past the stuck time, e.g. shorted. No other event follows
+    /// before it stays released for a moment, which ends with an `Up`.
+    StuckDetected,
+}
+
+/// A button event along with the time it was detected by the button task
+#[derive(Clone, Copy, Debug)]
+pub struct TimedButtonEvent {
+    pub event: ButtonEvent,
+    pub at: Instant,
+}
+
+/// High level gesture recognized from a sequence of button events
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ButtonAction {
+    Press,
+    LongPress,
+    DoublePress,
+}
+
+/// Turns raw button events into gestures. A press is only reported once the
+/// double press window expired without a second press.
+#[derive(Default)]
+pub struct GestureDetector {
+    last_event: Option<ButtonEvent>,
+    pending_press: Option<Instant>,
 }
 
+/// Debounces the raw pin level and turns it into button events
 #[derive(Default)]
-struct Button {
+pub struct Button {
     inverted: bool,
+    long_press: Duration,
     history: u16,
     down_time: Option<Instant>,
     next_long_time: Option<Instant>,
+    /// Time held down after which the button is stuck, none to never take
+    /// it as stuck
+    stuck_after: Option<Duration>,
+    stuck: bool,
+    /// Since when a stuck button is released
+    released_since: Option<Instant>,
 }
 
 pub struct ButtonEventHandle {
-    event_queue: Receiver<ButtonEvent>,
+    event_queue: Receiver<TimedButtonEvent>,
+    pressed: Arc<AtomicBool>,
+    stuck: Arc<AtomicBool>,
+}
+
+/// Suspects a disconnected button from weighings going on for hours
+/// without a single press. Informational only, the button may simply not
+/// be needed.
+#[derive(Debug)]
+pub struct DisconnectWatch {
+    /// Time without a press after which it is suspected, none never
+    after: Option<Duration>,
+    /// Presses counted when the watch last saw them change
+    presses: u32,
+    since: Instant,
+    weighings: u32,
 }
 
 impl Button {
-    pub fn new(inverted: bool) -> Self {
+    pub fn new(inverted: bool, long_press: Duration, stuck_after: Option<Duration>) -> Self {
         Self {
             inverted,
+            long_press,
             history: if inverted { 0xFFFF } else { 0x0000 },
+            stuck_after,
             ..Default::default()
         }
     }
 
-    fn start_task<T: InputPin + OutputPin>(
+    /// Feed the current pin level, returning the event it completes, if any
+    pub fn update(&mut self, level_high: bool, now: Instant) -> Option<ButtonEvent> {
+        self.button_update(level_high);
+
+        if self.stuck {
+            return self.update_stuck(level_high != self.inverted, now);
+        }
+        if let (Some(down_time), Some(stuck_after)) = (self.down_time, self.stuck_after) {
+            if now.duration_since(down_time) >= stuck_after {
+                warn!("Button stuck, held down for {:?}", stuck_after);
+                self.stuck = true;
+                self.released_since = None;
+                self.next_long_time = None;
+                return Some(ButtonEvent::StuckDetected);
+            }
+        }
+        if self.down_time.is_some() && self.button_up() {
+            self.down_time = None;
+            info!("Button Up");
+            Some(ButtonEvent::Up)
+        } else if let (Some(_down_time), Some(next_long_time)) =
+            (self.down_time, self.next_long_time)
+        {
+            if now >= next_long_time {
+                info!("Button Held");
+                self.next_long_time = None;
+                Some(ButtonEvent::Held)
+            } else {
+                None
+            }
+        } else if self.down_time.is_none() && self.button_down() {
+            self.down_time = Some(now);
+            self.next_long_time = Some(now + self.long_press);
+            info!("Button Down");
+            counters::increment(Counter::ButtonPresses);
+            Some(ButtonEvent::Down)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the button is currently pressed, after debouncing
+    pub fn is_pressed(&self) -> bool {
+        self.down_time.is_some()
+    }
+
+    pub fn is_stuck(&self) -> bool {
+        self.stuck
+    }
+
+    /// Ignore a stuck button until it stays released for the recovery
+    /// time, then release it
+    fn update_stuck(&mut self, active: bool, now: Instant) -> Option<ButtonEvent> {
+        if active {
+            self.released_since = None;
+            return None;
+        }
+        let released_since = *self.released_since.get_or_insert(now);
+        if now.duration_since(released_since) < STUCK_RECOVERY {
+            return None;
+        }
+        info!("Button recovered");
+        self.stuck = false;
+        self.released_since = None;
+        self.down_time = None;
+        self.history = if self.inverted { 0xFFFF } else { 0x0000 };
+        Some(ButtonEvent::Up)
+    }
+
+    fn start_task(
         mut self,
-        pin: PinDriver<'static, T, Input>,
-        event_sender: Sender<ButtonEvent>,
+        mut pin: impl ButtonPin,
+        event_sender: Sender<TimedButtonEvent>,
+        pressed: Arc<AtomicBool>,
+        stuck: Arc<AtomicBool>,
+        on_event: impl Fn(TimedButtonEvent) + Send + 'static,
     ) {
-        std::thread::spawn(move || loop {
-            self.button_update(&pin);
-
-            if self.down_time.is_some() && self.button_up() {
-                self.down_time = None;
-                info!("Button Up");
-                event_sender.send(ButtonEvent::Up).unwrap();
-            } else if let (Some(_down_time), Some(next_long_time)) =
-                (self.down_time, self.next_long_time)
-            {
-                if Instant::now() >= next_long_time {
-                    info!("Button Held");
-                    self.next_long_time = None;
-                    event_sender.send(ButtonEvent::Held).unwrap();
+        std::thread::spawn(move || {
+            let watchdog = WatchdogGuard::subscribe("button");
+            loop {
+                watchdog.feed();
+                let now = Instant::now();
+                if let Some(event) = self.update(pin.is_high(), now) {
+                    pressed.store(self.is_pressed(), Ordering::Relaxed);
+                    stuck.store(self.is_stuck(), Ordering::Relaxed);
+                    let event = TimedButtonEvent { event, at: now };
+                    if event_sender.send(event).is_err() {
+                        error!("Button event receiver dropped");
+                        return;
+                    }
+                    on_event(event);
                 }
-            } else if self.down_time.is_none() && self.button_down() {
-                self.down_time = Some(Instant::now());
-                self.next_long_time =
-                    Some(self.down_time.unwrap() + CONFIG_ESP32_BUTTON_LONG_PRESS_DURATION_MS);
-                info!("Button Down");
-                event_sender.send(ButtonEvent::Down).unwrap();
-            }
 
-            FreeRtos::delay_ms(
-                CONFIG_ESP32_POLLING_PERIOD_MS
-                    .as_millis()
-                    .try_into()
-                    .unwrap(),
-            );
+                std::thread::sleep(CONFIG_ESP32_POLLING_PERIOD_MS);
+            }
         });
     }
 
@@ -111,27 +246,81 @@ impl Button {
         }
     }
 
-    fn button_update<T: InputPin>(&mut self, button_pin: &PinDriver<T, Input>) {
-        let level_value: u16 = (button_pin.get_level() == Level::High).into();
+    fn button_update(&mut self, level_high: bool) {
+        let level_value: u16 = level_high.into();
         self.history = (self.history << 1) | level_value;
     }
 }
 
+impl GestureDetector {
+    /// Feed a button event, returning the gesture it completes, if any
+    pub fn on_event(&mut self, event: ButtonEvent, at: Instant) -> Option<ButtonAction> {
+        // A long press swallows a pending short press, so does a stuck
+        // button
+        if matches!(event, ButtonEvent::Held | ButtonEvent::StuckDetected) {
+            self.pending_press = None;
+        }
+        let expired_press = self.poll(at);
+
+        let last_event = self.last_event.replace(event);
+        let action = match event {
+            ButtonEvent::Down => None,
+            ButtonEvent::Held => {
+                (last_event == Some(ButtonEvent::Down)).then_some(ButtonAction::LongPress)
+            }
+            ButtonEvent::Up if last_event == Some(ButtonEvent::Down) => {
+                match self.pending_press.take() {
+                    Some(_) => Some(ButtonAction::DoublePress),
+                    None => {
+                        self.pending_press = Some(at);
+                        None
+                    }
+                }
+            }
+            ButtonEvent::Up | ButtonEvent::StuckDetected => None,
+        };
+
+        expired_press.or(action)
+    }
+
+    /// Report a pending press once the double press window has expired
+    pub fn poll(&mut self, now: Instant) -> Option<ButtonAction> {
+        match self.pending_press {
+            Some(pressed_at) if now.duration_since(pressed_at) > DOUBLE_PRESS_WINDOW_MS => {
+                self.pending_press = None;
+                Some(ButtonAction::Press)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
 impl ButtonEventHandle {
     pub fn get_event(&self) -> Option<ButtonEvent> {
+        self.get_timed_event().map(|timed_event| timed_event.event)
+    }
+
+    pub fn get_timed_event(&self) -> Option<TimedButtonEvent> {
         self.event_queue.try_recv().ok()
     }
 
-    /// Wait for a specific event to occur
-    pub fn wait_for_event(&self, event: ButtonEvent) {
+    /// Wait for a specific event to occur, calling `while_waiting` every now
+    /// and then, e.g. to feed the watchdog
+    pub fn wait_for_event(&self, event: ButtonEvent, mut while_waiting: impl FnMut()) {
         loop {
-            match self.event_queue.recv() {
+            while_waiting();
+            match self.event_queue.recv_timeout(WAIT_POLL_PERIOD) {
                 Ok(received_event) => {
-                    if received_event == event {
+                    if received_event.event == event {
                         break;
                     }
                 }
-                Err(_) => {
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
                     error!("Error receiving event");
                     break;
                 }
@@ -142,19 +331,221 @@ impl ButtonEventHandle {
     pub fn clear_events(&self) {
         while self.event_queue.try_recv().is_ok() {}
     }
+
+    /// Whether the button is currently held down
+    pub fn is_pressed(&self) -> bool {
+        self.pressed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the button is stuck, its events ignored until it recovers
+    pub fn is_stuck(&self) -> bool {
+        self.stuck.load(Ordering::Relaxed)
+    }
+}
+
+impl DisconnectWatch {
+    pub fn new(after: Option<Duration>, presses: u32, now: Instant) -> Self {
+        Self {
+            after,
+            presses,
+            since: now,
+            weighings: 0,
+        }
+    }
+
+    pub fn set_after(&mut self, after: Option<Duration>) {
+        self.after = after;
+    }
+
+    /// Follow the presses counted, starting over when they changed
+    pub fn on_presses(&mut self, presses: u32, now: Instant) {
+        if presses != self.presses {
+            *self = Self::new(self.after, presses, now);
+        }
+    }
+
+    pub fn on_weighing(&mut self) {
+        self.weighings = self.weighings.saturating_add(1);
+    }
+
+    /// Whether the button went unpressed for the time while weighings
+    /// went on
+    pub fn is_suspected(&self, now: Instant) -> bool {
+        self.after.is_some_and(|after| {
+            self.weighings >= DISCONNECT_MIN_WEIGHINGS && now.duration_since(self.since) >= after
+        })
+    }
+
+    /// One line for the diagnostics, none unless suspected
+    pub fn describe(&self, now: Instant) -> Option<String> {
+        self.is_suspected(now).then(|| {
+            format!(
+                "Button idle {}h, {} weighed",
+                now.duration_since(self.since).as_secs() / 3600,
+                self.weighings
+            )
+        })
+    }
+}
+
+/// Level of the button's input, polled by its task
+pub trait ButtonPin: Send + 'static {
+    fn is_high(&mut self) -> bool;
+}
+
+#[cfg(feature = "esp")]
+impl<T: InputPin + OutputPin> ButtonPin for PinDriver<'static, T, Input> {
+    fn is_high(&mut self) -> bool {
+        self.get_level() == Level::High
+    }
 }
 
+/// Start the task debouncing the button on a GPIO, pulled up when
+/// `inverted`. See `watch_button`.
+#[cfg(feature = "esp")]
 pub fn start_button_task<T: InputPin + OutputPin>(
     mut pin: PinDriver<'static, T, Input>,
     inverted: bool,
+    long_press: Duration,
+    stuck_after: Option<Duration>,
+    on_event: impl Fn(TimedButtonEvent) + Send + 'static,
 ) -> Result<ButtonEventHandle, EspError> {
+    pin.set_pull(if inverted { Pull::Up } else { Pull::Down })?;
+    Ok(watch_button(
+        pin,
+        inverted,
+        long_press,
+        stuck_after,
+        on_event,
+    ))
+}
+
+/// Start the task debouncing the button. The events are queued on the
+/// returned handle, `on_event` is called after each one is queued.
+pub fn watch_button(
+    pin: impl ButtonPin,
+    inverted: bool,
+    long_press: Duration,
+    stuck_after: Option<Duration>,
+    on_event: impl Fn(TimedButtonEvent) + Send + 'static,
+) -> ButtonEventHandle {
     let (tx, rx) = channel();
 
-    let button = Button::new(inverted);
+    let button = Button::new(inverted, long_press, stuck_after);
 
-    pin.set_pull(if inverted { Pull::Up } else { Pull::Down })?;
+    let pressed = Arc::new(AtomicBool::new(false));
+    let stuck = Arc::new(AtomicBool::new(false));
+    button.start_task(pin, tx, pressed.clone(), stuck.clone(), on_event);
+
+    ButtonEventHandle {
+        event_queue: rx,
+        pressed,
+        stuck,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    button.start_task(pin, tx);
+    const TICK: Duration = Duration::from_millis(10);
 
-    Ok(ButtonEventHandle { event_queue: rx })
+    /// Feed the levels a tick apart from `*now`, returning the events
+    fn feed(button: &mut Button, levels: &[bool], now: &mut Instant) -> Vec<ButtonEvent> {
+        levels
+            .iter()
+            .filter_map(|&level| {
+                *now += TICK;
+                button.update(level, *now)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn debounces_the_level() {
+        let mut now = Instant::now();
+        let mut button = Button::new(false, Duration::from_secs(1), None);
+        let bouncing = [
+            true, false, true, true, false, true, true, true, true, false,
+        ];
+        assert_eq!(feed(&mut button, &bouncing, &mut now), []);
+        assert_eq!(feed(&mut button, &[false; 10], &mut now), []);
+        assert_eq!(feed(&mut button, &[true; 6], &mut now), [ButtonEvent::Down]);
+        assert!(button.is_pressed());
+        assert_eq!(feed(&mut button, &[false, true, false, true], &mut now), []);
+        assert_eq!(feed(&mut button, &[true; 10], &mut now), []);
+        assert_eq!(feed(&mut button, &[false; 6], &mut now), [ButtonEvent::Up]);
+        assert!(!button.is_pressed());
+    }
+
+    #[test]
+    fn inverted_level() {
+        let mut now = Instant::now();
+        let mut button = Button::new(true, Duration::from_secs(1), None);
+        assert_eq!(feed(&mut button, &[true; 20], &mut now), []);
+        assert_eq!(
+            feed(&mut button, &[false; 6], &mut now),
+            [ButtonEvent::Down]
+        );
+        assert_eq!(feed(&mut button, &[true; 6], &mut now), [ButtonEvent::Up]);
+    }
+
+    #[test]
+    fn held_then_stuck() {
+        let mut now = Instant::now();
+        let mut button = Button::new(
+            false,
+            Duration::from_millis(500),
+            Some(Duration::from_secs(2)),
+        );
+        let events = feed(&mut button, &[true; 300], &mut now);
+        assert_eq!(
+            events,
+            [
+                ButtonEvent::Down,
+                ButtonEvent::Held,
+                ButtonEvent::StuckDetected
+            ]
+        );
+        assert!(button.is_stuck());
+        // Released for the recovery time, a blip of a press starting it over
+        assert_eq!(feed(&mut button, &[false; 50], &mut now), []);
+        assert_eq!(feed(&mut button, &[true], &mut now), []);
+        assert_eq!(
+            feed(&mut button, &[false; 101], &mut now),
+            [ButtonEvent::Up]
+        );
+        assert!(!button.is_stuck());
+        assert!(!button.is_pressed());
+    }
+
+    #[test]
+    fn gestures() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut gestures = GestureDetector::default();
+
+        // A press shows once the double press window passed
+        assert_eq!(gestures.on_event(ButtonEvent::Down, at(0)), None);
+        assert_eq!(gestures.on_event(ButtonEvent::Up, at(100)), None);
+        assert_eq!(gestures.poll(at(400)), None);
+        assert_eq!(gestures.poll(at(501)), Some(ButtonAction::Press));
+
+        assert_eq!(gestures.on_event(ButtonEvent::Down, at(1000)), None);
+        assert_eq!(gestures.on_event(ButtonEvent::Up, at(1100)), None);
+        assert_eq!(gestures.on_event(ButtonEvent::Down, at(1200)), None);
+        assert_eq!(
+            gestures.on_event(ButtonEvent::Up, at(1300)),
+            Some(ButtonAction::DoublePress)
+        );
+        assert_eq!(gestures.poll(at(2000)), None);
+
+        assert_eq!(gestures.on_event(ButtonEvent::Down, at(3000)), None);
+        assert_eq!(
+            gestures.on_event(ButtonEvent::Held, at(4000)),
+            Some(ButtonAction::LongPress)
+        );
+        assert_eq!(gestures.on_event(ButtonEvent::Up, at(4500)), None);
+        assert_eq!(gestures.poll(at(6000)), None);
+    }
 }
@@ -0,0 +1,173 @@
+use std::{
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    time::Duration,
+};
+
+use esp_idf_hal::{
+    gpio::AnyOutputPin,
+    ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver, Resolution, CHANNEL0, TIMER0},
+    prelude::*,
+};
+use log::warn;
+
+use crate::{
+    feedback::{Feedback, FeedbackDispatcher},
+    settings::Settings,
+};
+
+const BUZZER_TASK_STACK_SIZE: usize = 3 * 1024;
+/// Patterns waiting to be played, later ones are dropped
+const PATTERN_QUEUE_LEN: usize = 4;
+
+/// A tone, or a pause when the frequency is 0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tone {
+    pub hz: u32,
+    pub ms: u32,
+}
+
+const fn tone(hz: u32, ms: u32) -> Tone {
+    Tone { hz, ms }
+}
+
+/// Sequence of tones played in one go
+pub type Pattern = &'static [Tone];
+
+pub const BEEP: Pattern = &[tone(2700, 60)];
+pub const DOUBLE_BEEP: Pattern = &[tone(3000, 80), tone(0, 80), tone(3000, 80)];
+pub const LONG_BEEP: Pattern = &[tone(2000, 800)];
+pub const RISING: Pattern = &[tone(2700, 60), tone(0, 60), tone(3400, 120)];
+pub const FALLING: Pattern = &[tone(1800, 150), tone(0, 50), tone(1200, 300)];
+pub const TRIPLE_LOW: Pattern = &[
+    tone(1500, 200),
+    tone(0, 100),
+    tone(1500, 200),
+    tone(0, 100),
+    tone(1500, 200),
+];
+pub const ALARM: Pattern = &[
+    tone(3400, 200),
+    tone(2400, 200),
+    tone(3400, 200),
+    tone(2400, 200),
+    tone(3400, 200),
+    tone(2400, 200),
+];
+pub const TRIPLE_HIGH: Pattern = &[
+    tone(3400, 100),
+    tone(0, 100),
+    tone(3400, 100),
+    tone(0, 100),
+    tone(3400, 100),
+];
+
+/// Pattern signalling the feedback, if it is worth a sound
+pub fn pattern_for(feedback: Feedback) -> Option<Pattern> {
+    match feedback {
+        Feedback::Tared => Some(BEEP),
+        Feedback::Calibrated => Some(RISING),
+        Feedback::CalibrationFailed | Feedback::Fault => Some(FALLING),
+        Feedback::TargetReached => Some(DOUBLE_BEEP),
+        Feedback::Overload => Some(LONG_BEEP),
+        Feedback::BatteryLow => Some(TRIPLE_LOW),
+        Feedback::Identify => Some(TRIPLE_HIGH),
+        Feedback::AlarmTripped => Some(ALARM),
+        Feedback::Settling
+        | Feedback::Settled
+        | Feedback::Taring
+        | Feedback::Calibrating
+        | Feedback::AlarmCleared
+        | Feedback::Idle(_) => None,
+    }
+}
+
+/// Handle to the task playing patterns on the piezo
+#[derive(Clone)]
+pub struct Buzzer {
+    patterns: SyncSender<Pattern>,
+}
+
+impl Buzzer {
+    /// Queue a pattern without waiting for it to play. Dropped while the
+    /// buzzer is busy with others.
+    pub fn play(&self, pattern: Pattern) {
+        let _ = self.patterns.try_send(pattern);
+    }
+}
+
+/// Start the buzzer on the configured pin and have it follow the feedback.
+/// Returns `None` when it is disabled in the settings.
+pub fn start_buzzer_task(
+    timer: TIMER0,
+    channel: CHANNEL0,
+    settings: &Settings,
+    feedback: &FeedbackDispatcher,
+) -> anyhow::Result<Option<Buzzer>> {
+    if !settings.buzzer_enabled() {
+        return Ok(None);
+    }
+    // The pin comes from the settings, so it can only be picked at runtime
+    let pin = unsafe { AnyOutputPin::new(settings.buzzer_pin().into()) };
+    let volume = settings.buzzer_volume();
+
+    let (patterns_tx, patterns_rx) = sync_channel(PATTERN_QUEUE_LEN);
+    std::thread::Builder::new()
+        .name("buzzer".to_string())
+        .stack_size(BUZZER_TASK_STACK_SIZE)
+        .spawn(move || buzzer_task(timer, channel, pin, volume, patterns_rx))?;
+
+    let buzzer = Buzzer {
+        patterns: patterns_tx,
+    };
+    let listener = buzzer.clone();
+    feedback.subscribe(move |feedback| {
+        if let Some(pattern) = pattern_for(feedback) {
+            listener.play(pattern);
+        }
+    });
+    Ok(Some(buzzer))
+}
+
+fn buzzer_task(
+    mut timer: TIMER0,
+    mut channel: CHANNEL0,
+    mut pin: AnyOutputPin,
+    volume: u8,
+    patterns: Receiver<Pattern>,
+) {
+    while let Ok(pattern) = patterns.recv() {
+        for tone in pattern {
+            if tone.hz > 0 {
+                if let Err(err) = play_tone(&mut timer, &mut channel, &mut pin, volume, *tone) {
+                    warn!("Failed to play tone: {:?}", err);
+                }
+            } else {
+                std::thread::sleep(Duration::from_millis(tone.ms.into()));
+            }
+        }
+    }
+}
+
+/// Drive the piezo for the duration of the tone. The LEDC timer is set up
+/// per tone for its frequency and released afterwards, silencing the pin.
+fn play_tone(
+    timer: &mut TIMER0,
+    channel: &mut CHANNEL0,
+    pin: &mut AnyOutputPin,
+    volume: u8,
+    tone: Tone,
+) -> anyhow::Result<()> {
+    let timer = LedcTimerDriver::new(
+        timer,
+        &TimerConfig::new()
+            .frequency(tone.hz.Hz().into())
+            .resolution(Resolution::Bits10),
+    )?;
+    let mut driver = LedcDriver::new(channel, &timer, pin)?;
+    // A square wave is the loudest, so full volume is half the period
+    let duty = driver.get_max_duty() / 2 * u32::from(volume.min(100)) / 100;
+    driver.set_duty(duty)?;
+    std::thread::sleep(Duration::from_millis(tone.ms.into()));
+    driver.set_duty(0)?;
+    Ok(())
+}
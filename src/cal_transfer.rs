@@ -0,0 +1,199 @@
+//! Calibration moved from one board to another, for a board replaced under
+//! the same load cell and frame. The old board prints its calibration as a
+//! line of hex with `cal export`, the new one takes it with `cal import` or
+//! a PUT of `/calibration`, instead of being calibrated again.
+//!
+//! The blob is the format version, the sensor and its gain, the scale
+//! factor, the offset, when it was calibrated and with what weight, all
+//! little endian, followed by their CRC32.
+
+use thiserror::Error;
+
+use crate::{envelope::crc32, sensor::SensorKind};
+
+/// Version of the blob layout, bumped whenever it changes
+pub const CAL_BLOB_VERSION: u8 = 1;
+/// Magnitude of the grams per count a calibration can sensibly end up with,
+/// from a 200kg platform down to a 100g cell
+pub const SCALE_FACTOR_RANGE: (f32, f32) = (1e-5, 1.0);
+/// Gain of channel A of the HX711, the only one the scale uses
+pub const HX711_GAIN: u8 = 128;
+const ENCODED_LEN: usize = 27;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum CalTransferError {
+    #[error("Not a hex string")]
+    NotHex,
+    #[error("Blob of {0} bytes instead of {ENCODED_LEN}")]
+    Length(usize),
+    #[error("Checksum mismatch, the blob was mistyped or cut short")]
+    Checksum,
+    #[error("Blob version {0} is not supported, {CAL_BLOB_VERSION} is")]
+    Version(u8),
+    #[error("Unknown sensor {0}")]
+    UnknownSensor(u8),
+    #[error("Scale factor {0} is out of range")]
+    Factor(f32),
+    #[error("Calibrated with the {found}, the scale has the {expected}")]
+    Sensor {
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[error("Calibrated at a gain of {found}, the sensor runs at {expected}")]
+    Gain { expected: u8, found: u8 },
+}
+
+/// Whether a scale factor is one a working load cell ends up with
+pub fn factor_in_range(scale_factor: f32) -> bool {
+    let (min, max) = SCALE_FACTOR_RANGE;
+    scale_factor.is_finite() && (min..=max).contains(&scale_factor.abs())
+}
+
+/// The calibration of a scale, as exported
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationRecord {
+    pub sensor: SensorKind,
+    /// PGA gain the factor was found at
+    pub gain: u8,
+    pub scale_factor: f32,
+    pub offset: i32,
+    /// Seconds since the epoch, 0 when the clock was not synchronized
+    pub calibrated_epoch_s: u64,
+    /// Known weight it was calibrated with
+    pub weight_grams: f32,
+}
+
+impl CalibrationRecord {
+    /// The blob as a line of lowercase hex
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        bytes.push(CAL_BLOB_VERSION);
+        bytes.push(self.sensor.index());
+        bytes.push(self.gain);
+        bytes.extend_from_slice(&self.scale_factor.to_le_bytes());
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        bytes.extend_from_slice(&self.calibrated_epoch_s.to_le_bytes());
+        bytes.extend_from_slice(&self.weight_grams.to_le_bytes());
+        bytes.extend_from_slice(&crc32(&bytes).to_le_bytes());
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Check the blob and read the calibration out of it. Whether it fits
+    /// the scale is up to `check_sensor`.
+    pub fn decode(hex: &str) -> Result<Self, CalTransferError> {
+        let bytes = decode_hex(hex.trim())?;
+        if bytes.len() != ENCODED_LEN {
+            return Err(CalTransferError::Length(bytes.len()));
+        }
+        let (data, crc) = bytes.split_at(ENCODED_LEN - 4);
+        if crc32(data).to_le_bytes() != crc {
+            return Err(CalTransferError::Checksum);
+        }
+        if data[0] != CAL_BLOB_VERSION {
+            return Err(CalTransferError::Version(data[0]));
+        }
+        let sensor =
+            SensorKind::from_index(data[1]).ok_or(CalTransferError::UnknownSensor(data[1]))?;
+        let word = |at: usize| [data[at], data[at + 1], data[at + 2], data[at + 3]];
+        let scale_factor = f32::from_le_bytes(word(3));
+        if !factor_in_range(scale_factor) {
+            return Err(CalTransferError::Factor(scale_factor));
+        }
+        let mut epoch = [0; 8];
+        epoch.copy_from_slice(&data[11..19]);
+        Ok(Self {
+            sensor,
+            gain: data[2],
+            scale_factor,
+            offset: i32::from_le_bytes(word(7)),
+            calibrated_epoch_s: u64::from_le_bytes(epoch),
+            weight_grams: f32::from_le_bytes(word(19)),
+        })
+    }
+
+    /// Whether it was calibrated with the sensor the scale has, at the gain
+    /// it runs at. The counts of another sensor or gain do not compare.
+    pub fn check_sensor(&self, sensor: SensorKind, gain: u8) -> Result<(), CalTransferError> {
+        if self.sensor != sensor {
+            return Err(CalTransferError::Sensor {
+                expected: sensor.name(),
+                found: self.sensor.name(),
+            });
+        }
+        if self.gain != gain {
+            return Err(CalTransferError::Gain {
+                expected: gain,
+                found: self.gain,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, CalTransferError> {
+    // from_str_radix would take a sign too, e.g. "+f"
+    if hex.len() % 2 != 0 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(CalTransferError::NotHex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).map_err(|_| CalTransferError::NotHex))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORD: CalibrationRecord = CalibrationRecord {
+        sensor: SensorKind::Nau7802,
+        gain: HX711_GAIN,
+        scale_factor: -0.002_375,
+        offset: -81_234,
+        calibrated_epoch_s: 1_714_564_800,
+        weight_grams: 500.0,
+    };
+
+    #[test]
+    fn round_trip() {
+        let hex = RECORD.encode();
+        assert_eq!(hex.len(), ENCODED_LEN * 2);
+        assert_eq!(CalibrationRecord::decode(&hex), Ok(RECORD));
+        assert_eq!(
+            CalibrationRecord::decode(&format!(" {}\r\n", hex.to_uppercase())),
+            Ok(RECORD)
+        );
+    }
+
+    #[test]
+    fn bad_crc() {
+        let mut hex = RECORD.encode();
+        // The low digit of the offset, mistyped
+        let at = 2 * 7 + 1;
+        let digit = if &hex[at..at + 1] == "0" { "1" } else { "0" };
+        hex.replace_range(at..at + 1, digit);
+        assert_eq!(
+            CalibrationRecord::decode(&hex),
+            Err(CalTransferError::Checksum)
+        );
+        let hex = RECORD.encode();
+        assert_eq!(
+            CalibrationRecord::decode(&hex[..hex.len() - 2]),
+            Err(CalTransferError::Length(ENCODED_LEN - 1))
+        );
+    }
+
+    #[test]
+    fn rejects_signs() {
+        let mut hex = RECORD.encode();
+        hex.replace_range(0..2, "+1");
+        assert_eq!(
+            CalibrationRecord::decode(&hex),
+            Err(CalTransferError::NotHex)
+        );
+        assert_eq!(decode_hex("+f"), Err(CalTransferError::NotHex));
+        assert_eq!(decode_hex("0g"), Err(CalTransferError::NotHex));
+        assert_eq!(decode_hex("abc"), Err(CalTransferError::NotHex));
+        assert_eq!(decode_hex("0aFf"), Ok(vec![0x0a, 0xff]));
+    }
+}
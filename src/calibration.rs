@@ -0,0 +1,267 @@
+//! When to suggest recalibrating. Load cells drift, so the scale keeps track
+//! of how old the calibration is and of the drift the zero tracking absorbed
+//! since, and reminds once either goes past its limit. The reminder can be
+//! snoozed for a while or dismissed until the next calibration.
+
+use std::time::{Duration, Instant};
+
+use crate::time::Timestamp;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// Days a snoozed reminder stays quiet
+pub const SNOOZE_DAYS: u64 = 7;
+/// Shortest time between two steps of the zero tracking
+const ZERO_TRACKING_PERIOD: Duration = Duration::from_secs(1);
+
+const ENCODED_LEN: usize = 43;
+
+/// A point in time as far as the scale can tell: the wall clock when
+/// synchronized, and the boot it happened in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Moment {
+    /// Seconds since the epoch, 0 when the clock was not synchronized
+    pub epoch_s: u64,
+    pub boot: u32,
+}
+
+impl Moment {
+    pub fn now(boot: u32) -> Self {
+        let epoch_s = match Timestamp::now() {
+            Timestamp::WallClock { since_epoch, .. } => since_epoch.as_secs(),
+            Timestamp::Uptime(_) => 0,
+        };
+        Self { epoch_s, boot }
+    }
+
+    /// Days from `earlier` to this moment. Without the wall clock at both
+    /// ends every boot in between counts as a day.
+    pub fn days_since(&self, earlier: &Moment) -> u64 {
+        if self.epoch_s > 0 && earlier.epoch_s > 0 {
+            self.epoch_s.saturating_sub(earlier.epoch_s) / SECS_PER_DAY
+        } else {
+            u64::from(self.boot.saturating_sub(earlier.boot))
+        }
+    }
+}
+
+/// Follows the slow drift of the zero: a stable weight closer to zero than
+/// half the resolution is taken as zero, one step a period at most
+#[derive(Debug, Default)]
+pub struct ZeroTracker {
+    last_step: Option<Instant>,
+}
+
+impl ZeroTracker {
+    /// Weight in grams to move the zero by, if any
+    pub fn on_sample(
+        &mut self,
+        grams: f32,
+        stable: bool,
+        resolution: f32,
+        now: Instant,
+    ) -> Option<f32> {
+        if !stable || grams == 0.0 || grams.abs() >= resolution / 2.0 {
+            return None;
+        }
+        if self
+            .last_step
+            .is_some_and(|last_step| now.duration_since(last_step) < ZERO_TRACKING_PERIOD)
+        {
+            return None;
+        }
+        self.last_step = Some(now);
+        Some(grams)
+    }
+}
+
+/// Why recalibrating is suggested
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReminderReason {
+    /// The calibration is this many days old
+    Age { days: u64 },
+    /// The zero tracking absorbed this much drift since the calibration
+    Drift { grams: f32 },
+}
+
+impl ReminderReason {
+    /// Short enough for the status strip
+    pub fn describe(&self) -> String {
+        match self {
+            ReminderReason::Age { days } => format!("Recal: {} days", days),
+            ReminderReason::Drift { grams } => format!("Recal: drift {:.1}g", grams),
+        }
+    }
+}
+
+/// What the reminder keeps across restarts
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReminderState {
+    pub calibrated: Moment,
+    /// Net drift in grams absorbed by the zero tracking since the calibration
+    pub drift_grams: f32,
+    /// Last time the reminder showed as a toast
+    pub reminded: Option<Moment>,
+    pub snoozed: Option<Moment>,
+    /// Dismissed until the next calibration
+    pub dismissed: bool,
+}
+
+impl ReminderState {
+    /// State of a calibration made at `calibrated`
+    pub fn new(calibrated: Moment) -> Self {
+        Self {
+            calibrated,
+            ..Self::default()
+        }
+    }
+}
+
+impl crate::storage::Stored for ReminderState {
+    fn encode(&self) -> Vec<u8> {
+        fn push_moment(bytes: &mut Vec<u8>, moment: Option<Moment>) {
+            bytes.push(u8::from(moment.is_some()));
+            let moment = moment.unwrap_or_default();
+            bytes.extend_from_slice(&moment.epoch_s.to_le_bytes());
+            bytes.extend_from_slice(&moment.boot.to_le_bytes());
+        }
+
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        push_moment(&mut bytes, Some(self.calibrated));
+        bytes.extend_from_slice(&self.drift_grams.to_le_bytes());
+        push_moment(&mut bytes, self.reminded);
+        push_moment(&mut bytes, self.snoozed);
+        bytes.push(u8::from(self.dismissed));
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        fn take<'b>(bytes: &mut &'b [u8], len: usize) -> Option<&'b [u8]> {
+            if bytes.len() < len {
+                return None;
+            }
+            let (taken, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Some(taken)
+        }
+        fn take_moment(bytes: &mut &[u8]) -> Option<Option<Moment>> {
+            let present = take(bytes, 1)?[0] != 0;
+            let epoch_s = u64::from_le_bytes(take(bytes, 8)?.try_into().ok()?);
+            let boot = u32::from_le_bytes(take(bytes, 4)?.try_into().ok()?);
+            Some(present.then_some(Moment { epoch_s, boot }))
+        }
+
+        if bytes.len() != ENCODED_LEN {
+            return None;
+        }
+        let mut bytes = bytes;
+        let calibrated = take_moment(&mut bytes)?.unwrap_or_default();
+        let drift_grams = f32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+        let reminded = take_moment(&mut bytes)?;
+        let snoozed = take_moment(&mut bytes)?;
+        let dismissed = take(&mut bytes, 1)?[0] != 0;
+        Some(Self {
+            calibrated,
+            drift_grams: if drift_grams.is_finite() {
+                drift_grams
+            } else {
+                0.0
+            },
+            reminded,
+            snoozed,
+            dismissed,
+        })
+    }
+}
+
+/// Decides when to suggest recalibrating
+#[derive(Debug)]
+pub struct CalibrationReminder {
+    state: ReminderState,
+    /// Age in days past which recalibrating is suggested, 0 disables it
+    max_age_days: u32,
+    /// Drift in grams past which recalibrating is suggested, 0 disables it
+    max_drift_grams: f32,
+}
+
+impl CalibrationReminder {
+    pub fn new(state: ReminderState, max_age_days: u32, max_drift_grams: f32) -> Self {
+        Self {
+            state,
+            max_age_days,
+            max_drift_grams,
+        }
+    }
+
+    pub fn configure(&mut self, max_age_days: u32, max_drift_grams: f32) {
+        self.max_age_days = max_age_days;
+        self.max_drift_grams = max_drift_grams;
+    }
+
+    pub fn state(&self) -> &ReminderState {
+        &self.state
+    }
+
+    /// Why recalibrating is suggested right now, if it is
+    pub fn due(&self, now: &Moment) -> Option<ReminderReason> {
+        if self.state.dismissed {
+            return None;
+        }
+        if let Some(snoozed) = &self.state.snoozed {
+            if now.days_since(snoozed) < SNOOZE_DAYS {
+                return None;
+            }
+        }
+        let days = now.days_since(&self.state.calibrated);
+        if self.max_age_days > 0 && days >= u64::from(self.max_age_days) {
+            return Some(ReminderReason::Age { days });
+        }
+        let grams = self.state.drift_grams.abs();
+        if self.max_drift_grams > 0.0 && grams >= self.max_drift_grams {
+            return Some(ReminderReason::Drift { grams });
+        }
+        None
+    }
+
+    /// The reminder to show as a toast, once a day while it is due
+    pub fn take_toast(&mut self, now: &Moment) -> Option<ReminderReason> {
+        let reason = self.due(now)?;
+        if self
+            .state
+            .reminded
+            .is_some_and(|reminded| now.days_since(&reminded) < 1)
+        {
+            return None;
+        }
+        self.state.reminded = Some(*now);
+        Some(reason)
+    }
+
+    /// Start over after a calibration
+    pub fn calibrated(&mut self, now: Moment) {
+        self.state = ReminderState::new(now);
+    }
+
+    /// Date a calibration made in this boot before the clock was
+    /// synchronized, off by no more than the uptime. Returns whether it was.
+    pub fn date_calibration(&mut self, now: &Moment) -> bool {
+        let calibrated = &mut self.state.calibrated;
+        if calibrated.epoch_s > 0 || now.epoch_s == 0 || calibrated.boot != now.boot {
+            return false;
+        }
+        calibrated.epoch_s = now.epoch_s;
+        true
+    }
+
+    /// Add drift absorbed by the zero tracking
+    pub fn absorb(&mut self, grams: f32) {
+        self.state.drift_grams += grams;
+    }
+
+    pub fn snooze(&mut self, now: Moment) {
+        self.state.snoozed = Some(now);
+    }
+
+    pub fn dismiss(&mut self) {
+        self.state.dismissed = true;
+    }
+}
@@ -0,0 +1,363 @@
+//! Certified mode, for weighings others rely on. While it is on the
+//! resolution is locked, and the zero tracking, the creep compensation and
+//! the hold are inert, so nothing alters a reading behind the operator's
+//! back.
+//!
+//! An audit counter in NVS goes up every time the mode is entered or left,
+//! and every time the calibration changes while it is on. It is written
+//! through right away and never reset. It carries a corruption check, a
+//! CRC32 over the device ID, the count and the flags: a counter damaged in
+//! flash or copied over from another board reads as corrupt, and stays so.
+//! The check is not keyed, anyone with the flash and the device ID can
+//! recompute it, so it does not hold against a deliberate edit. The mode as
+//! last counted is stored along, settings erased behind its back still
+//! count as leaving it at the next boot.
+
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use log::{info, warn};
+
+use crate::{
+    device,
+    envelope::crc32,
+    hold::{AutoHold, Hold},
+    pipeline::Pipeline,
+    storage::{Storage, StorageService, StoreError},
+};
+
+const AUDIT_NAMESPACE: &str = "audit";
+const AUDIT_KEY: &str = "certified";
+
+/// What the mode locks, by the names reported on the console and in the
+/// JSON
+pub const RESTRICTIONS: [&str; 4] = ["resolution", "zero_tracking", "creep", "hold"];
+
+const AUDIT_LEN: usize = 9;
+const FLAG_ACTIVE: u8 = 1 << 0;
+const FLAG_CORRUPT: u8 = 1 << 1;
+
+/// What the audit counter counts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    Entered,
+    Left,
+    /// The calibration changed while the mode is on
+    Calibrated,
+}
+
+impl AuditEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditEvent::Entered => "entered",
+            AuditEvent::Left => "left",
+            AuditEvent::Calibrated => "calibrated",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Audit {
+    pub count: u32,
+    /// Whether the mode is on, as last counted
+    pub active: bool,
+    /// The stored counter failed its corruption check at some point
+    pub corrupt: bool,
+}
+
+impl Audit {
+    /// Count, the mode and the flags along with their corruption check for
+    /// the device
+    pub fn encode(&self, device_id: &str) -> [u8; AUDIT_LEN] {
+        let flags = self.flags();
+        let mut bytes = [0; AUDIT_LEN];
+        bytes[..4].copy_from_slice(&self.count.to_le_bytes());
+        bytes[4] = flags;
+        bytes[5..].copy_from_slice(&check(device_id, self.count, flags).to_le_bytes());
+        bytes
+    }
+
+    /// Counter stored by `encode`. One failing its check, or for another
+    /// device, is corrupt, keeping whatever count it has.
+    pub fn decode(bytes: &[u8], device_id: &str) -> Self {
+        let Ok(bytes) = <[u8; AUDIT_LEN]>::try_from(bytes) else {
+            return Self {
+                corrupt: true,
+                ..Self::default()
+            };
+        };
+        let count = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let flags = bytes[4];
+        let stored = u32::from_le_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
+        Self {
+            count,
+            active: flags & FLAG_ACTIVE != 0,
+            corrupt: flags & FLAG_CORRUPT != 0 || stored != check(device_id, count, flags),
+        }
+    }
+
+    /// Count the event, moving the mode along with it
+    pub fn count(&mut self, event: AuditEvent) {
+        self.count = self.count.saturating_add(1);
+        match event {
+            AuditEvent::Entered => self.active = true,
+            AuditEvent::Left => self.active = false,
+            AuditEvent::Calibrated => {}
+        }
+    }
+
+    /// Event to count for the mode to become `active`, none when it already
+    /// is
+    pub fn change_to(&self, active: bool) -> Option<AuditEvent> {
+        match (self.active, active) {
+            (false, true) => Some(AuditEvent::Entered),
+            (true, false) => Some(AuditEvent::Left),
+            _ => None,
+        }
+    }
+
+    /// One line for the console and the diagnostics page
+    pub fn describe(&self) -> String {
+        format!(
+            "CERT {} audit {}{}",
+            if self.active { "on" } else { "off" },
+            self.count,
+            if self.corrupt { " CORRUPT" } else { "" }
+        )
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.active {
+            flags |= FLAG_ACTIVE;
+        }
+        if self.corrupt {
+            flags |= FLAG_CORRUPT;
+        }
+        flags
+    }
+}
+
+/// CRC32 of the counter along with the device ID, not keyed
+fn check(device_id: &str, count: u32, flags: u8) -> u32 {
+    let mut bytes = device_id.as_bytes().to_vec();
+    bytes.extend_from_slice(&count.to_le_bytes());
+    bytes.push(flags);
+    crc32(&bytes)
+}
+
+static AUDIT: Mutex<Audit> = Mutex::new(Audit {
+    count: 0,
+    active: false,
+    corrupt: false,
+});
+
+fn audit_mut() -> MutexGuard<'static, Audit> {
+    AUDIT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Audit counter as last counted
+pub fn audit() -> Audit {
+    *audit_mut()
+}
+
+/// Whether the mode is on
+pub fn is_active() -> bool {
+    audit_mut().active
+}
+
+/// Put the pipeline under the mode or take it back out: the zero stays
+/// where the last tare put it and no creep is taken off
+pub fn lock_pipeline(pipeline: &mut Pipeline, active: bool) {
+    pipeline.set_zero_tracking(!active);
+    pipeline.set_creep_compensation(!active);
+}
+
+/// Auto-hold in force with the setting, none while the mode is on
+pub fn auto_hold(setting: Option<AutoHold>, active: bool) -> Option<AutoHold> {
+    setting.filter(|_| !active)
+}
+
+/// Freeze the weight with `hold`, returning it, unless the mode is on
+pub fn manual_hold(hold: &mut Hold, active: bool) -> Option<f32> {
+    if active {
+        return None;
+    }
+    hold.hold()
+}
+
+/// Resolution in force once `requested` is asked for, the `current` one
+/// while the mode is on
+pub fn resolution(current: f32, requested: f32, active: bool) -> f32 {
+    if active {
+        current
+    } else {
+        requested
+    }
+}
+
+/// Count the event, writing the counter right away through `write`, which
+/// returns whether it succeeded
+pub fn count_with(event: AuditEvent, write: impl FnOnce(&Audit) -> bool) -> bool {
+    let mut audit = audit_mut();
+    audit.count(event);
+    write(&audit)
+}
+
+static STORAGE: OnceLock<Storage> = OnceLock::new();
+
+/// Read the counter back, counting the mode as entered or left when the
+/// settings disagree with it
+pub fn start(storage_service: &StorageService, active: bool) -> Result<(), StoreError> {
+    let storage = storage_service.open(AUDIT_NAMESPACE)?;
+    if let Some(bytes) = storage.get_blob(AUDIT_KEY) {
+        let audit = Audit::decode(&bytes, device::identity().device_id());
+        if audit.corrupt {
+            warn!("The certified audit counter failed its corruption check");
+        }
+        *audit_mut() = audit;
+    }
+    let _ = STORAGE.set(storage);
+    set_active(active);
+    Ok(())
+}
+
+/// Enter or leave the mode, counted when it changes
+pub fn set_active(active: bool) {
+    if let Some(event) = audit().change_to(active) {
+        record(event);
+    }
+}
+
+/// Count a change of the calibration, only while the mode is on
+pub fn calibration_changed() {
+    if is_active() {
+        record(AuditEvent::Calibrated);
+    }
+}
+
+fn record(event: AuditEvent) {
+    info!("Certified mode {}", event.name());
+    count_with(event, |audit| {
+        let Some(storage) = STORAGE.get() else {
+            return false;
+        };
+        let bytes = audit.encode(device::identity().device_id());
+        match storage
+            .set_blob(AUDIT_KEY, &bytes)
+            .and_then(|()| storage.flush())
+        {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("Failed to write the audit counter: {:?}", err);
+                false
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{
+        creep::{CreepCompensator, CreepModel},
+        filter::WeightFilter,
+        hold::HoldState,
+        pipeline::Weighed,
+    };
+
+    const RESOLUTION: f32 = 1.0;
+
+    fn pipeline(creep: Option<CreepModel>, active: bool) -> Pipeline {
+        let mut pipeline = Pipeline::new(WeightFilter::new(4, 2.0), CreepCompensator::new(creep));
+        lock_pipeline(&mut pipeline, active);
+        pipeline
+    }
+
+    /// Ten seconds of a steady `grams` at 10 SPS
+    fn weigh(pipeline: &mut Pipeline, grams: f32) -> Vec<Weighed> {
+        let start = Instant::now();
+        (0..100)
+            .map(|i| {
+                let at = start + Duration::from_millis(100 * i);
+                pipeline.push(grams, 1.0, true, RESOLUTION, at)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zero_tracking_is_inert() {
+        // A drift well within half the resolution of the zero
+        let tracked = weigh(&mut pipeline(None, false), 0.3);
+        assert!(tracked.iter().any(|weighed| weighed.zero.is_some()));
+
+        let locked = weigh(&mut pipeline(None, true), 0.3);
+        assert!(locked.iter().all(|weighed| weighed.zero.is_none()));
+    }
+
+    #[test]
+    fn creep_compensation_is_inert() {
+        let model = CreepModel {
+            time_constant: Duration::from_secs(5),
+            fraction: 0.02,
+        };
+        let compensated = weigh(&mut pipeline(Some(model), false), 1000.0);
+        assert!(compensated.last().unwrap().creep > 10.0);
+
+        let mut locked = pipeline(Some(model), true);
+        for weighed in weigh(&mut locked, 1000.0) {
+            assert_eq!(weighed.creep, 0.0);
+            assert_eq!(weighed.gross, weighed.filtered);
+        }
+        // Taken back out, the creep counts from there
+        lock_pipeline(&mut locked, false);
+        assert!(weigh(&mut locked, 1000.0).last().unwrap().creep > 10.0);
+    }
+
+    #[test]
+    fn auto_hold_is_inert() {
+        let setting = Some(AutoHold {
+            band_grams: 1.0,
+            duration: Duration::from_secs(1),
+        });
+        let held = |active| {
+            let mut hold = Hold::new(auto_hold(setting, active));
+            let start = Instant::now();
+            (0..50).any(|i| hold.on_sample(500.0, start + Duration::from_millis(100 * i)))
+        };
+        assert!(held(false));
+        assert!(!held(true));
+    }
+
+    #[test]
+    fn manual_hold_is_inert() {
+        let held = |active| {
+            let mut hold = Hold::new(None);
+            let start = Instant::now();
+            for i in 0..20 {
+                hold.on_sample(500.0, start + Duration::from_millis(100 * i));
+            }
+            (manual_hold(&mut hold, active), hold.state())
+        };
+        assert_eq!(
+            held(false),
+            (
+                Some(500.0),
+                HoldState::Held {
+                    grams: 500.0,
+                    auto: false
+                }
+            )
+        );
+        assert_eq!(held(true), (None, HoldState::Live));
+    }
+
+    #[test]
+    fn resolution_is_locked() {
+        assert_eq!(resolution(1.0, 0.1, false), 0.1);
+        assert_eq!(resolution(1.0, 0.1, true), 1.0);
+    }
+}
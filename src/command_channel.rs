@@ -0,0 +1,700 @@
+//! Channel carrying the commands of every frontend, the console, HTTP, BLE
+//! and the Wi-Fi provisioning, to the main loop. The frontends only hold a
+//! `CommandSender`, the main loop is the one place running the commands,
+//! checking the lock and the PIN and refusing a command the running tare or
+//! calibration does not allow.
+//!
+//! The commands are the `Command` enum, which the console parses its lines
+//! into and the other frontends build directly. A frontend fires a command
+//! and moves on with `send`, or waits for its outcome with `request`, as the
+//! HTTP handlers do to answer with it.
+
+use std::{
+    sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender},
+    time::Duration,
+};
+
+use log::LevelFilter;
+use thiserror::Error;
+
+use crate::{
+    alarms::AlarmConfig,
+    cal_transfer::CalibrationRecord,
+    counters::Counter,
+    creep::CreepModel,
+    deadband::DeadbandSetting,
+    demo::DemoPattern,
+    espnow::{EspNowRole, Mac},
+    history::Granularity,
+    i18n::Language,
+    lock::{ClickPattern, LockError},
+    power::{ActiveWindow, IdleStage},
+    quiesce::QuiesceMode,
+    sensor::{SampleRate, SensorKind},
+    settings::{BoardPin, LedBackend, ModbusPins, SdCardPins, Settings, StartupMode},
+    shutdown::ShutdownReason,
+    stream::StreamRate,
+    trace::TracePoint,
+    unit::Unit,
+    volume::Substance,
+    weight_gesture::InputMode,
+};
+
+/// Time a frontend waits for the main loop to get to its command, it is
+/// behind a display flush at worst
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A command of any frontend, executed by the main loop
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Tare,
+    SoftTare(SoftTareAction),
+    Hold,
+    ReleaseHold,
+    Calibrate {
+        weight_grams: Option<f32>,
+    },
+    RemoteCalibration(RemoteCalibration),
+    ExportCalibration,
+    /// Calibration of another board, checked against the sensor when applied
+    ImportCalibration(CalibrationRecord),
+    Linearity(LinearityCommand),
+    Noise(NoiseCommand),
+    Raw,
+    Factor,
+    Stats,
+    Diagnostics,
+    Counters,
+    ResetCounter(Counter),
+    WhoAmI,
+    SetUnit(Unit),
+    SetVolume(VolumeSetting),
+    SetDeadband(DeadbandSetting),
+    SetResolution(f32),
+    SetCalibrationWeight(f32),
+    Stream(StreamRate),
+    SetWifi {
+        ssid: String,
+        password: String,
+    },
+    /// `None` goes back to the hostname derived from the device ID
+    SetHostname(Option<String>),
+    SetUtcOffset(i16),
+    SetMqtt(MqttSetting),
+    SetWebhook(WebhookSetting),
+    SetLog(LogSetting),
+    SetSdCard(SdCardSetting),
+    SetBattery(BatterySetting),
+    SetBuzzer(BuzzerSetting),
+    SetSensor(SensorSetting),
+    SetHeap(HeapSetting),
+    /// Seconds a panic stays on the display, 0 leaves the display alone
+    SetPanicHold(u32),
+    /// Time after a tare within which another one is refused
+    SetTareCooldown(Duration),
+    SetNegative(NegativeSetting),
+    SetNoise(NoiseSetting),
+    /// What becomes of the readings converted during a display flush
+    SetQuiesce(QuiesceMode),
+    SetAutoHold(AutoHoldSetting),
+    SetInput(InputSetting),
+    SetModbus(ModbusSetting),
+    /// Print the ESP-NOW role, the peers and the total of the aggregator
+    EspNow,
+    /// Open the ESP-NOW pairing, on the aggregator and then on each corner
+    EspNowPair,
+    SetEspNow(EspNowSetting),
+    /// Acceleration in g that counts as a bump, none leaves the IMU alone
+    SetBumpThreshold(Option<f32>),
+    SetStale(StaleSetting),
+    SetButton(ButtonSetting),
+    SetStartup(StartupSetting),
+    SetLowPower(LowPowerSetting),
+    /// Timeout of an idle stage in seconds, 0 skips the stage
+    SetIdle(IdleStage, u32),
+    Schedule,
+    SetSchedule(ScheduleSetting),
+    /// Run the first-boot setup on the display
+    Setup,
+    SetLanguage(Language),
+    SetMaxFps(u8),
+    SetFlash(FlashSetting),
+    SetMirror(MirrorSetting),
+    SetTarget(Option<f32>),
+    SetClock(ClockSetting),
+    SetAlarm(AlarmSetting),
+    SetCalReminder(CalReminderSetting),
+    SetCapacity(Option<f32>),
+    SetLed(LedSetting),
+    SetDispense(DispenseSetting),
+    SetBrew(BrewSetting),
+    SetRecipe(RecipeSetting),
+    /// Move one of the board pins, taking effect after a restart
+    SetPin(BoardPin, u8),
+    /// Token authorizing firmware updates, empty disables them
+    SetUpdateToken(String),
+    Identify,
+    Demo(DemoCommand),
+    Soak(SoakCommand),
+    /// Enter or leave the certified mode, print it when none
+    Certified(Option<bool>),
+    Creep(CreepCommand),
+    Trace(TraceCommand),
+    /// Print the log level, or change it
+    LogLevel(Option<LevelFilter>),
+    Logs,
+    Brew(bool),
+    Recipe,
+    Dispense(f32),
+    StopDispense,
+    Dump,
+    History {
+        granularity: Granularity,
+        count: u32,
+    },
+    /// Time-weighted average of the weight over the window ending now
+    Average(Duration),
+    ClearLog,
+    ClearResets,
+    StorageDump,
+    StorageFlush,
+    Alarms,
+    AcknowledgeAlarms,
+    Session,
+    NewSession,
+    CalReminder(CalReminderAction),
+    Decommission,
+    /// Erase the calibration and the settings, then restart with the
+    /// defaults
+    FactoryReset,
+    /// Go into deep sleep until the button wakes the scale
+    Sleep,
+    /// Settings replacing the current ones at once, from the BLE
+    /// configuration service. The secrets left out when read keep their
+    /// current values.
+    ImportSettings(Box<Settings>),
+    /// Save what is pending and restart
+    Restart(ShutdownReason),
+    SetLock(LockSetting),
+    Lock,
+    Unlock(u16),
+    /// Command along with the PIN letting it through a locked scale
+    WithPin(u16, Box<Command>),
+    Help,
+}
+
+impl Command {
+    /// Whether the command needs the scale unlocked or the PIN: the
+    /// calibration, the settings and what erases data, but not the tare,
+    /// the weighing or the target
+    pub fn is_guarded(&self) -> bool {
+        match self {
+            Command::Calibrate { .. }
+            | Command::RemoteCalibration(RemoteCalibration::Start(_))
+            | Command::ImportCalibration(_)
+            | Command::ImportSettings(_)
+            | Command::ClearLog
+            | Command::ClearResets
+            | Command::Creep(CreepCommand::Measure | CreepCommand::Set(_))
+            | Command::Soak(SoakCommand::Start { .. })
+            | Command::Certified(Some(_))
+            | Command::Setup
+            | Command::Decommission
+            | Command::FactoryReset => true,
+            Command::SetTarget(_) => false,
+            Command::SetUnit(_)
+            | Command::SetVolume(_)
+            | Command::SetDeadband(_)
+            | Command::SetResolution(_)
+            | Command::SetCalibrationWeight(_)
+            | Command::SetWifi { .. }
+            | Command::SetHostname(_)
+            | Command::SetUtcOffset(_)
+            | Command::SetMqtt(_)
+            | Command::SetWebhook(_)
+            | Command::SetHeap(_)
+            | Command::SetLog(_)
+            | Command::SetSdCard(_)
+            | Command::SetBattery(_)
+            | Command::SetBuzzer(_)
+            | Command::SetSensor(_)
+            | Command::SetPanicHold(_)
+            | Command::SetTareCooldown(_)
+            | Command::SetNegative(_)
+            | Command::SetNoise(_)
+            | Command::SetQuiesce(_)
+            | Command::SetAutoHold(_)
+            | Command::SetInput(_)
+            | Command::SetModbus(_)
+            | Command::EspNowPair
+            | Command::SetEspNow(_)
+            | Command::SetBumpThreshold(_)
+            | Command::SetStale(_)
+            | Command::SetButton(_)
+            | Command::SetStartup(_)
+            | Command::SetLowPower(_)
+            | Command::SetIdle(..)
+            | Command::SetSchedule(_)
+            | Command::SetLanguage(_)
+            | Command::SetMaxFps(_)
+            | Command::SetFlash(_)
+            | Command::SetMirror(_)
+            | Command::ResetCounter(_)
+            | Command::SetClock(_)
+            | Command::SetAlarm(_)
+            | Command::SetCalReminder(_)
+            | Command::SetCapacity(_)
+            | Command::SetLed(_)
+            | Command::SetDispense(_)
+            | Command::SetBrew(_)
+            | Command::SetRecipe(_)
+            | Command::SetPin(..)
+            | Command::SetUpdateToken(_)
+            | Command::SetLock(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// MQTT settings, taking effect after a restart
+#[derive(Clone, Debug, PartialEq)]
+pub enum MqttSetting {
+    Broker(String),
+    Credentials { username: String, password: String },
+    TopicPrefix(String),
+    IntervalSecs(u32),
+    MinDeltaGrams(f32),
+    DiagIntervalSecs(u32),
+}
+
+/// Webhook settings, taking effect after a restart
+#[derive(Clone, Debug, PartialEq)]
+pub enum WebhookSetting {
+    /// `None` turns the webhook off
+    Url(Option<String>),
+    ThresholdGrams(f32),
+    EmptySecs(u32),
+}
+
+/// Heap governor thresholds in kB, taking effect right away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapSetting {
+    ReserveKb(u32),
+    CriticalKb(u32),
+}
+
+/// Weight log settings, taking effect after a restart
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogSetting {
+    IntervalSecs(u32),
+    Retention(u32),
+}
+
+/// SD card settings, taking effect after a restart
+#[derive(Clone, Debug, PartialEq)]
+pub enum SdCardSetting {
+    Enabled(bool),
+    Pins(SdCardPins),
+}
+
+/// Second panel mirroring the display, taking effect after a restart
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MirrorSetting {
+    Off,
+    /// Mirror to the panel at the address
+    Address(u8),
+    QuarterTurns(u8),
+    /// SDA and SCL of the second I2C controller, none for the bus of the
+    /// display
+    Pins(Option<(u8, u8)>),
+}
+
+/// Modbus slave settings, taking effect after a restart
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModbusSetting {
+    Address(u8),
+    Baud(u32),
+    Pins(ModbusPins),
+}
+
+/// ESP-NOW settings, the role takes effect after a restart and the rest
+/// right away
+#[derive(Clone, Debug, PartialEq)]
+pub enum EspNowSetting {
+    Role(EspNowRole),
+    /// Corner summed by the aggregator, labelled after its position when
+    /// none
+    AddPeer(Mac, Option<String>),
+    /// Index of the peer, from 0
+    RemovePeer(usize),
+    Label(usize, String),
+    StaleMs(u32),
+    /// Aggregator a corner sends its weight to, none stops sending
+    Aggregator(Option<Mac>),
+}
+
+/// Battery settings, taking effect after a restart
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatterySetting {
+    Divider(f32),
+    CutoffVolts(f32),
+}
+
+/// Buzzer settings, taking effect after a restart
+#[derive(Clone, Debug, PartialEq)]
+pub enum BuzzerSetting {
+    Enabled(bool),
+    Volume(u8),
+    Pin(u8),
+}
+
+/// Load cell sensor settings, taking effect after a restart
+#[derive(Clone, Debug, PartialEq)]
+pub enum SensorSetting {
+    Kind(SensorKind),
+    /// PGA gain of the NAU7802
+    Nau7802Gain(u8),
+    /// Conversion rate of the HX711, taking effect right away
+    Rate(SampleRate),
+    /// GPIO wired to the RATE pin of the HX711
+    RatePin(Option<u8>),
+}
+
+/// Idle clock settings, taking effect right away
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClockSetting {
+    Enabled(bool),
+    IdleSecs(u32),
+}
+
+/// Alarm settings, taking effect right away
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlarmSetting {
+    /// Alarm of a slot counted from 0, `None` to remove it
+    Slot(usize, Option<AlarmConfig>),
+    RenotifySecs(u32),
+}
+
+/// Calibration driven from the console or over HTTP instead of the button
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RemoteCalibration {
+    /// Start with the known weight in grams
+    Start(f32),
+    /// Go on in place of a press
+    Step,
+    Status,
+}
+
+/// Linearity check of the load cell across several known weights
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinearityCommand {
+    /// Start with the known weights in grams, the saved ones when empty
+    Start(Vec<f32>),
+    Cancel,
+    Report,
+}
+
+/// Noise floor of the empty scale
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoiseCommand {
+    Test,
+    Report,
+}
+
+/// Windows the scale is awake in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScheduleSetting {
+    Add(ActiveWindow),
+    /// Awake all the time
+    Clear,
+}
+
+/// Verdicts of the noise test, taking effect at the next one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseSetting {
+    WarnCounts(f32),
+    FailCounts(f32),
+}
+
+/// Creep compensation of the load cell, stored with the calibration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CreepCommand {
+    Show,
+    /// Record a trace after the next step load and fit a model to it
+    Measure,
+    /// Compensate with the model, none to stop
+    Set(Option<CreepModel>),
+}
+
+/// Traces of the readings, to tune the filter on
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceCommand {
+    /// Record for the seconds
+    Record(u32),
+    /// Read the trace from the console, taken by the console task, which
+    /// hands the points over with `Loaded`
+    Load,
+    Loaded(Vec<TracePoint>),
+    Dump,
+    /// Replay through a filter with the window and the stable band, those
+    /// of the scale otherwise
+    Replay {
+        window: Option<usize>,
+        band: Option<f32>,
+    },
+}
+
+/// Demo mode, a signal generator standing in for the load cell
+#[derive(Clone, Debug, PartialEq)]
+pub enum DemoCommand {
+    /// Start with the pattern, the last one when none, or switch to it
+    Start(Option<DemoPattern>),
+    Stop,
+    /// Whether the demo weight goes into the weight log and to the SD card
+    Log(bool),
+}
+
+/// Soak test logging the drift of a fixed weight
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoakCommand {
+    /// Take a point every interval, until stopped when there is no duration
+    Start {
+        interval: Duration,
+        duration: Option<Duration>,
+    },
+    Stop,
+    Report,
+}
+
+/// Software tares stacked on top of the zero of the tare
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoftTareAction {
+    Push,
+    Pop,
+    /// Switch between the net and the gross weight
+    Toggle,
+    Clear,
+}
+
+/// Auto-hold of a restless load, taking effect right away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutoHoldSetting {
+    /// Spread of the readings, `None` disables the auto-hold
+    BandGrams(Option<f32>),
+    Secs(u32),
+}
+
+/// What the scale is operated with, taking effect right away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputSetting {
+    Mode(InputMode),
+    ThresholdPercent(u8),
+    Press(Duration),
+    Window(Duration),
+}
+
+/// Volume the weight shows as, taking effect right away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VolumeSetting {
+    Shown(bool),
+    Substance(Substance),
+    /// Density in g/ml of a custom substance, which it switches to
+    Density(f32),
+}
+
+/// Hint to tare again when the scale reads below zero, taking effect right
+/// away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NegativeSetting {
+    /// Weight below zero that counts, `None` disables the hint
+    Grams(Option<f32>),
+    Secs(u32),
+    AutoTare(bool),
+}
+
+/// Light sleep of the empty scale, taking effect right away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LowPowerSetting {
+    Enabled(bool),
+    AfterSecs(u32),
+    WakeGrams(f32),
+}
+
+/// Lock of the calibration and the settings, taking effect right away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockSetting {
+    Enabled(bool),
+    Pin(u16),
+    Pattern(Option<ClickPattern>),
+    RelockSecs(u32),
+}
+
+/// What the scale does at boot, taking effect at the next one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StartupSetting {
+    Mode(StartupMode),
+    ToleranceGrams(f32),
+}
+
+/// Plausibility checks of the button
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ButtonSetting {
+    /// Seconds held down before it is stuck, `None` never, after a restart
+    StuckSecs(Option<u32>),
+    /// Hours weighing without a press before a disconnect is noted, `None`
+    /// never
+    DisconnectHours(Option<u32>),
+}
+
+/// Limits of the time without a reading, taking effect right away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StaleSetting {
+    /// Milliseconds before the weight shows as stale
+    Millis(u32),
+    /// Seconds before the sensor is reset, `None` never resets it
+    LostSecs(Option<u32>),
+}
+
+/// Recalibration reminder limits, taking effect right away
+#[derive(Clone, Debug, PartialEq)]
+pub enum CalReminderSetting {
+    Days(u32),
+    DriftGrams(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalReminderAction {
+    Snooze,
+    Dismiss,
+}
+
+/// Status LED settings, taking effect after a restart
+#[derive(Clone, Debug, PartialEq)]
+pub enum LedSetting {
+    Backend(LedBackend),
+    Pin(u8),
+}
+
+/// Events the display flashes inverted on
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlashSetting {
+    Target(bool),
+    Overload(bool),
+}
+
+/// Dispenser settings. The compensation applies to the next dispense, the
+/// others after a restart.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DispenseSetting {
+    Pin(u8),
+    TimeoutSecs(u32),
+    MaxGrams(f32),
+    CompensationGrams(f32),
+}
+
+/// Brew timer thresholds, applying to the next brew
+#[derive(Clone, Debug, PartialEq)]
+pub enum BrewSetting {
+    StartGrams(f32),
+    StopGramsPerSec(f32),
+    StopGrace(Duration),
+}
+
+/// Recipe assistant settings, applying to the next recipe
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecipeSetting {
+    DoseGrams(f32),
+    Ratio(f32),
+}
+
+/// What became of a command on the main loop
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandOutcome {
+    /// Executed, its response printed on the console
+    Done,
+    /// A tare or calibration started, running on with the readings
+    Started,
+    /// Waiting for the running tare to finish
+    Queued,
+    /// Guarded while the scale is locked
+    Locked(LockError),
+    /// Refused with the reason, as printed on the console
+    Refused(String),
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("The main loop is gone")]
+    Disconnected,
+    /// The command is still queued and runs once the main loop gets to it
+    #[error("No reply from the main loop yet")]
+    Timeout,
+}
+
+/// A command along with where its outcome goes
+#[derive(Debug)]
+pub struct CommandRequest {
+    command: Command,
+    reply: CommandReply,
+}
+
+impl CommandRequest {
+    pub fn into_parts(self) -> (Command, CommandReply) {
+        (self.command, self.reply)
+    }
+}
+
+/// Where the outcome of a command goes, nowhere when fired and forgotten
+#[derive(Debug)]
+pub struct CommandReply(Option<SyncSender<CommandOutcome>>);
+
+impl CommandReply {
+    /// Hand the outcome back to the frontend, if it waits for it
+    pub fn send(self, outcome: CommandOutcome) {
+        if let Some(reply) = self.0 {
+            // The frontend may have stopped waiting
+            let _ = reply.try_send(outcome);
+        }
+    }
+}
+
+/// End of the channel the frontends hold, cheap to clone
+#[derive(Clone, Debug)]
+pub struct CommandSender {
+    requests: Sender<CommandRequest>,
+}
+
+impl CommandSender {
+    /// Queue the command without waiting for it
+    pub fn send(&self, command: Command) -> Result<(), CommandError> {
+        self.requests
+            .send(CommandRequest {
+                command,
+                reply: CommandReply(None),
+            })
+            .map_err(|_| CommandError::Disconnected)
+    }
+
+    /// Queue the command and wait for its outcome
+    pub fn request(&self, command: Command) -> Result<CommandOutcome, CommandError> {
+        let (reply, outcome) = sync_channel(1);
+        self.requests
+            .send(CommandRequest {
+                command,
+                reply: CommandReply(Some(reply)),
+            })
+            .map_err(|_| CommandError::Disconnected)?;
+        outcome
+            .recv_timeout(REPLY_TIMEOUT)
+            .map_err(|err| match err {
+                RecvTimeoutError::Timeout => CommandError::Timeout,
+                RecvTimeoutError::Disconnected => CommandError::Disconnected,
+            })
+    }
+}
+
+/// The sender for the frontends and the receiver for the main loop
+pub fn command_channel() -> (CommandSender, Receiver<CommandRequest>) {
+    let (requests, receiver) = channel();
+    (CommandSender { requests }, receiver)
+}
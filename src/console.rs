@@ -0,0 +1,1965 @@
+use std::{
+    io::{stdin, stdout, Read, Write},
+    time::Duration,
+};
+
+use log::error;
+use thiserror::Error;
+
+use crate::{
+    alarms::{AlarmConfig, AlarmKind, DEFAULT_HYSTERESIS_GRAMS, MAX_ALARMS},
+    button::{MAX_BUTTON_DISCONNECT_H, MAX_BUTTON_STUCK_S},
+    cal_transfer::CalibrationRecord,
+    command_channel::{
+        AlarmSetting, AutoHoldSetting, BatterySetting, BrewSetting, ButtonSetting, BuzzerSetting,
+        CalReminderAction, CalReminderSetting, ClockSetting, Command, CommandSender, CreepCommand,
+        DemoCommand, DispenseSetting, EspNowSetting, FlashSetting, HeapSetting, InputSetting,
+        LedSetting, LinearityCommand, LockSetting, LogSetting, LowPowerSetting, MirrorSetting,
+        ModbusSetting, MqttSetting, NegativeSetting, NoiseCommand, NoiseSetting, RecipeSetting,
+        RemoteCalibration, ScheduleSetting, SdCardSetting, SensorSetting, SoakCommand,
+        SoftTareAction, StaleSetting, StartupSetting, TraceCommand, VolumeSetting, WebhookSetting,
+    },
+    counters::Counter,
+    creep::{CreepModel, MAX_CREEP_PERCENT, MAX_CREEP_TIME_CONSTANT_S},
+    deadband::{DeadbandSetting, DEFAULT_DEADBAND_K, MAX_DEADBAND_GRAMS, MAX_DEADBAND_K},
+    demo::{DemoPattern, ScriptStep, MAX_DEMO_GRAMS, MAX_DEMO_SECS, MAX_SCRIPT_STEPS},
+    espnow::{parse_mac, EspNowRole, MAX_STALE_MS, MIN_STALE_MS},
+    frame_rate::MAX_FPS,
+    governor::MAX_HEAP_THRESHOLD_KB,
+    history::Granularity,
+    hold::MAX_AUTO_HOLD_S,
+    i18n::Language,
+    line_editor::LineEditor,
+    linearity::MAX_LINEARITY_WEIGHTS,
+    lock::{parse_pin, ClickPattern},
+    modbus::{MAX_MODBUS_ADDRESS, MODBUS_BAUD_RATES},
+    negative::MAX_NEGATIVE_DELAY_S,
+    noise::MAX_NOISE_COUNTS,
+    panic_screen::MAX_PANIC_HOLD_S,
+    power::{
+        ActiveWindow, IdleStage, Weekdays, MAX_IDLE_TIMEOUT_S, MINUTES_PER_DAY, MIN_IDLE_TIMEOUT_S,
+    },
+    procedure::MAX_TARE_COOLDOWN,
+    quiesce::QuiesceMode,
+    sensor::{
+        SampleRate, SensorKind, MAX_SENSOR_LOST_S, MAX_STALE_READING_MS, MIN_STALE_READING_MS,
+        NAU7802_GAINS,
+    },
+    settings::{
+        BoardPin, LedBackend, ModbusPins, SdCardPins, Settings, StartupMode, MAX_OUTPUT_GPIO,
+        WEBHOOK_URL_MAX_LEN,
+    },
+    shutdown::ShutdownReason,
+    soak::{DEFAULT_SOAK_INTERVAL, MAX_SOAK_DURATION, MAX_SOAK_INTERVAL, MIN_SOAK_INTERVAL},
+    stream::StreamRate,
+    time_average::{DEFAULT_AVERAGE_WINDOW, MAX_AVERAGE_WINDOW},
+    trace::{TracePoint, MAX_REPLAY_WINDOW, MAX_TRACE_POINTS, MAX_TRACE_SECS},
+    unit::Unit,
+    volume::{Substance, MAX_DENSITY, MIN_DENSITY},
+    weight_gesture::{
+        InputMode, MAX_GESTURE_PRESS, MAX_GESTURE_THRESHOLD_PERCENT, MAX_GESTURE_WINDOW,
+        MIN_GESTURE_THRESHOLD_PERCENT,
+    },
+};
+
+/// Delay between reads while no input is available
+const CONSOLE_POLL_PERIOD: Duration = Duration::from_millis(50);
+/// Bytes taken from stdin at once
+const CONSOLE_READ_LEN: usize = 32;
+
+/// First words of the commands, completed with tab
+const COMMAND_NAMES: &[&str] = &[
+    "alarm",
+    "alarms",
+    "average",
+    "brew",
+    "cal",
+    "calreminder",
+    "certified",
+    "clear",
+    "counters",
+    "creep",
+    "decommission",
+    "demo",
+    "diag",
+    "dispense",
+    "dump",
+    "espnow",
+    "factor",
+    "factoryreset",
+    "help",
+    "history",
+    "hold",
+    "identify",
+    "linearity",
+    "lock",
+    "loglevel",
+    "logs",
+    "noise",
+    "pin",
+    "raw",
+    "reboot",
+    "recipe",
+    "schedule",
+    "session",
+    "set",
+    "setup",
+    "sleep",
+    "soak",
+    "stats",
+    "status",
+    "storage",
+    "stream",
+    "tare",
+    "trace",
+    "unlock",
+    "untare",
+    "whoami",
+];
+
+pub const USAGE: &str = "\
+Commands:
+  tare              tare the scale
+  tare soft         tare in software on top of the tares already stacked
+  untare            remove the last soft tare
+  tare toggle       switch between the net and the gross weight
+  tare clear        remove all the soft tares
+  hold              freeze the weight estimated over the last seconds
+  hold release      back to the live weight
+  cal               calibrate using the button prompts
+  cal <grams>       calibrate with a known weight already on the tared scale
+  cal start <grams> calibrate through steps instead of presses, a press cancels
+  cal step          go on once the scale is empty or the weight is on it
+  cal status        print the step the calibration waits on
+  cal export        print the calibration as a line of hex, for `cal import` on another board
+  cal import <hex>  take over the calibration exported by another board
+  linearity [grams...] check the linearity with the saved or these known weights
+  linearity cancel  stop the running check
+  linearity report  print the table of the last check
+  noise test        measure the noise of the empty scale over 200 readings
+  noise             print the last noise test
+  espnow            print the ESP-NOW role, the peers and the total of the corners
+  espnow pair       open the pairing for a minute, on the aggregator first, then each corner
+  schedule          print the windows the scale is awake in and whether it is in one
+  setup             walk through the first-boot setup again on the display
+  raw               print a raw reading
+  factor            print the calibration factor and tare offset
+  stats             print runtime statistics
+  diag              print the heap and the stack usage of the tasks
+  counters          print the lifetime counters: tares, calibrations, boots, presses, recoveries, corrupt
+  counters reset <name> start a counter from zero again
+  whoami            print the device ID, the boot and the last sequence number
+  set unit <unit>   set the display unit (g, kg, oz, lb)
+  set volume <on|off>         show the weight as milliliters of the substance
+  set volume <substance>      water, milk, oil, flour or custom
+  set volume density <g/ml>   a custom substance of this density, 0.1 to 5
+  set deadband auto [k]       move the shown weight past k times its noise, 3 by default
+  set deadband <grams>        move it past this band instead, 0 follows every change
+  set resolution <grams>
+  set calweight <grams>
+  set wifi <ssid> [password]
+  set hostname <name|default> reachable as <name>.local
+  set tz <offset>             time zone offset for display, e.g. +02:00 or -5
+  set mqtt broker <url>       e.g. mqtt://192.168.1.10:1883
+  set mqtt user <name> <password>
+  set mqtt prefix <topic>
+  set mqtt interval <seconds> republish the stable weight, 0 disables it
+  set mqtt delta <grams>      change that is published right away
+  set mqtt diag <seconds>     publish the diagnostics, 0 disables it
+  set webhook url <url|off>   POST to the URL when something lands on the empty scale
+  set webhook threshold <grams> stable weight that counts as an arrival, 50g by default
+  set webhook empty <seconds> time the scale stays empty before the next arrival, 60s by default
+  set log interval <seconds>  log the weight to flash, 0 disables it
+  set log keep <records>      records kept before the oldest are overwritten
+  set sd <on|off>             log every sample to the SD card
+  set sd pins <sck> <mosi> <miso> <cs>
+  set target <grams|off>      beep when the weight reaches the target
+  set clock <on|off>          show the clock while the scale is idle
+  set clock idle <seconds>    time empty and still before the clock shows
+  set capacity <grams|off>    warn about an overload past the capacity
+  set alarm <n> <above|below> <grams> [hysteresis]
+                              alarm 1 to 4, latched until acknowledged
+  set alarm <n> off
+  set calreminder days <days> suggest recalibrating past this age, 0 disables it
+  set calreminder drift <grams> or past this zero drift, 0 disables it
+  set alarm renotify <seconds> signal a latched alarm again, 0 disables it
+  set buzzer <on|off>
+  set buzzer volume <percent>
+  set buzzer pin <gpio>
+  set led <off|gpio|ws2812>   status LED type
+  set led pin <gpio>
+  set brew start <grams>      weight starting the brew timer
+  set brew flow <grams/s>     flow rate below which the brew stops
+  set brew grace <seconds>    time without flow before the timer stops
+  set recipe dose <grams>     coffee dose the recipe assistant starts from
+  set recipe ratio <ratio>    water per coffee, e.g. 16 or 1:16
+  set dispense pin <gpio>     relay or SSR output of the dispenser
+  set dispense timeout <seconds>
+  set dispense max <grams>    weight on the scale forcing the output off
+  set dispense compensation <grams>
+  set battery divider <ratio> pack voltage over ADC pin voltage
+  set battery cutoff <volts>  shut down below this pack voltage
+  set pin <name> <gpio>       hx711_dt, hx711_sck, button, sda or scl
+  set sensor <hx711|nau7802>  load cell ADC, the NAU7802 shares the display I2C bus
+  set sensor gain <gain>      NAU7802 gain: 1, 2, 4, 8, 16, 32, 64 or 128
+  set sensor rate <10|80>     HX711 conversions per second, with a rate pin
+  set sensor ratepin <gpio|off> GPIO wired to the RATE pin of the HX711
+  set panic <seconds>         time a panic stays on the display, 0 disables it
+  set tarecooldown <seconds>  time after a tare within which another is refused, 2s by default
+  set negative <grams|off>    hint to tare again below minus this, 10g by default
+  set negative time <seconds> time below it before the hint shows, 5s by default
+  set negative autotare <on|off> tare once stable instead of hinting
+  set noise warn <counts>     RMS noise the noise test warns from, 100 by default
+  set noise fail <counts>     RMS noise it fails from, 400 by default
+  set quiesce <off|discard|weight> readings converted during a display flush
+  set autohold <grams|off>    hold once the readings stay within this band
+  set autohold time <seconds> time they must stay in it, 2s by default
+  set input <button|weight>   operate with presses on the platform, needs the capacity set
+  set input threshold <percent> share of the capacity a press goes past, 80 by default
+  set input press <seconds>   time a press holds the weight down, 2s by default
+  set input window <seconds>  time after a press within which the next counts, 5s by default
+  set bump <g|off>            vertical acceleration of a bump with an MPU6050, e.g. 0.05
+  set heap reserve <kB>       free heap BLE, WebSocket and MQTT buffering must leave to start, 48 by default
+  set heap critical <kB>      free heap below which they are stopped, 24 by default
+  set stale <ms>              time without a reading before the weight shows a ?
+  set stale lost <s|off>      time without a reading before the sensor is reset
+  set button stuck <s|off>    time held down before the button counts as stuck, 30s by default
+  set button disconnect <h|off> hours weighing without a press before a disconnect is noted, 12 by default
+  set startup <tare|restore|verify> tare at boot, or keep the saved tare
+  set startup tolerance <grams> weight change after a restart that verify warns about
+  set lowpower <on|off>       sleep lightly while empty, the HX711 wakes the scale
+  set lowpower after <seconds> time empty and still before sleeping, 60s by default
+  set lowpower wake <grams>   weight change that wakes the scale, 20g by default
+  set idle <dim|display|sleep> <seconds|off>
+                              time without activity before the display dims, turns
+                              off, or the scale goes into deep sleep, 10s to 24h
+  set schedule <days> <HH:MM>-<HH:MM> add a window the scale is awake in, e.g. mon-fri 08:00-18:00,
+                              it sleeps outside of the windows once the time is known
+  set schedule off            stay awake all the time
+  set language <en|de>        language of the display
+  set fps <1-30>              redraws of the moving weight per second, 5 by default
+  set flash <target|overload> <on|off> flash the display inverted on the event
+  set mirror <0x3C|0x3D|off>  show the display on a second panel at the address
+  set mirror rotation <0|90|180|270> turn the second panel on its own
+  set mirror bus <display|sda scl> on the bus of the display or on its own pins
+  set modbus address <1-247> address of the Modbus RTU slave
+  set modbus baud <rate>      2400 to 115200, 8 data bits, even parity
+  set modbus pins <tx> <rx> [de] UART pins, de drives an RS-485 transceiver
+  set espnow role <off|corner|aggregator> send the weight or sum the corners, after a restart
+  set espnow peer add <mac> [label] corner summed by the aggregator, up to 8
+  set espnow peer remove <n>  forget the corner numbered as in `espnow`
+  set espnow label <n> <label> name of the corner on the breakdown page, 6 characters
+  set espnow stale <ms>       time without a packet before a corner is stale, 2000ms by default
+  set espnow aggregator <mac|none> aggregator a corner sends its weight to
+  set update token <token|off> allow firmware updates over HTTP
+  set lock <on|off>           guard the calibration and the settings, needs a PIN
+  set lock pin <pin>          4 digit PIN unlocking the scale
+  set lock pattern <clicks|off> unlock from the button with e.g. short-short-long or ssl
+  set lock relock <seconds>   time unused before the scale locks again, 300s by default
+  stream on         stream every weight sample as CSV
+  stream <hz>       stream weight samples as CSV at the given rate
+  stream off        stop streaming
+  dump              print the weight log as CSV
+  history <hour|day> [count] print the min, max and mean of the last hours or days
+  average [minutes] print the time-weighted average weight of the last hour or minutes, up to 720
+  clear log         erase the weight log
+  clear resets      reset the reset counters and forget the last panic
+  storage dump      list the stored keys and their sizes
+  storage flush     write out the pending storage writes now
+  alarms            print the alarms and their states
+  session           print the weighings of the current and the last sessions
+  session new       archive the current session and start a new one
+  calreminder snooze quiet the recalibration reminder for 7 days
+  calreminder dismiss quiet it until the next calibration
+  alarm ack         acknowledge the latched alarms
+  brew              tare and start the brew timer on the first drip
+  brew off          back to plain weighing
+  recipe            start the pour-over recipe assistant
+  dispense <grams>  add the weight through the dispenser output
+  dispense stop     turn the dispenser output off
+  identify          flash the status LED and beep to find this scale
+  creep             show the creep compensation
+  creep measure     record the creep for 2 minutes once the next load settled
+  creep set <seconds> <percent> take off a creep of the share of the load, with
+                    the time constant
+  creep off         stop compensating the creep
+  trace record <seconds> record the readings for up to 60 seconds, then print them
+  trace load        paste a trace as printed, ending with an empty line
+  trace dump        print the trace kept, recorded or loaded
+  trace replay [window] [band] weigh the trace again with the filter of the
+                    scale, or a window of samples and a stable band in grams
+  demo on           weigh a generated signal instead of the load cell, DEMO shows
+  demo off          back to the load cell
+  demo ramp <from> <to> <seconds> weight going from a value to another
+  demo step <grams> weight jumping to a value
+  demo noise <setpoint> <amplitude> weight wandering around a value
+  demo script <seconds>:<grams>... weights from their times on, e.g. 2:0 4:250
+  demo log <on|off> let the demo weight into the weight log and the SD card
+  soak start [seconds] [hours] log the drift of a fixed weight every 60 seconds,
+                    until stopped or for the hours; tare and calibration wait
+  soak stop         end the soak and print its drift
+  soak              print the drift of the soak so far
+  certified         print the certified mode, its audit counter and restrictions
+  certified <on|off> lock the resolution and turn the zero tracking, the creep
+                    compensation and the hold off, counted by the audit counter
+  loglevel          print the log level
+  loglevel <level>  off, error, warn, info, debug or trace
+  logs              print the latest log lines
+  decommission      remove the scale from Home Assistant
+  reboot            save what is pending and restart
+  sleep             go into deep sleep until a press wakes the scale
+  factoryreset      erase the calibration and the settings, then restart
+  lock              lock the scale again right away
+  unlock <pin>      unlock the scale until it relocks
+  pin <pin> <command> run a guarded command while the scale is locked
+  help              print this message";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ParseError {
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
+    #[error("Missing argument for {0}")]
+    MissingArgument(&'static str),
+    #[error("Invalid argument for {0}: {1}")]
+    InvalidArgument(&'static str, String),
+}
+
+/// Parse a strictly positive, finite number
+fn parse_positive(command: &'static str, arg: Option<&str>) -> Result<f32, ParseError> {
+    let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+    arg.parse::<f32>()
+        .ok()
+        .filter(|grams| grams.is_finite() && *grams > 0.0)
+        .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+}
+
+/// A strictly positive number, or `off`
+fn parse_positive_or_off(
+    command: &'static str,
+    arg: Option<&str>,
+) -> Result<Option<f32>, ParseError> {
+    match arg {
+        Some(arg) if arg.eq_ignore_ascii_case("off") => Ok(None),
+        arg => parse_positive(command, arg).map(Some),
+    }
+}
+
+fn parse_volume_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<VolumeSetting, ParseError> {
+    match words.next() {
+        Some(arg) if arg.eq_ignore_ascii_case("density") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("volume density"))?;
+            arg.parse::<f32>()
+                .ok()
+                .filter(|density| (MIN_DENSITY..=MAX_DENSITY).contains(density))
+                .map(VolumeSetting::Density)
+                .ok_or_else(|| ParseError::InvalidArgument("volume density", arg.to_string()))
+        }
+        Some(arg) if arg.eq_ignore_ascii_case("on") => Ok(VolumeSetting::Shown(true)),
+        Some(arg) if arg.eq_ignore_ascii_case("off") => Ok(VolumeSetting::Shown(false)),
+        Some(arg) => Substance::from_name(arg)
+            .map(VolumeSetting::Substance)
+            .ok_or_else(|| ParseError::InvalidArgument("volume", arg.to_string())),
+        None => Err(ParseError::MissingArgument("volume")),
+    }
+}
+
+fn parse_deadband_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<DeadbandSetting, ParseError> {
+    let arg = words
+        .next()
+        .ok_or(ParseError::MissingArgument("deadband"))?;
+    if arg.eq_ignore_ascii_case("auto") {
+        let k = match words.next() {
+            Some(arg) => arg
+                .parse::<f32>()
+                .ok()
+                .filter(|k| *k > 0.0 && *k <= MAX_DEADBAND_K)
+                .ok_or_else(|| ParseError::InvalidArgument("deadband auto", arg.to_string()))?,
+            None => DEFAULT_DEADBAND_K,
+        };
+        return Ok(DeadbandSetting::Auto { k });
+    }
+    arg.parse::<f32>()
+        .ok()
+        .filter(|grams| (0.0..=MAX_DEADBAND_GRAMS).contains(grams))
+        .map(DeadbandSetting::Manual)
+        .ok_or_else(|| ParseError::InvalidArgument("deadband", arg.to_string()))
+}
+
+fn parse_unit(arg: Option<&str>) -> Result<Unit, ParseError> {
+    let arg = arg.ok_or(ParseError::MissingArgument("unit"))?;
+    Unit::ALL
+        .into_iter()
+        .find(|unit| unit.symbol().eq_ignore_ascii_case(arg))
+        .ok_or_else(|| ParseError::InvalidArgument("unit", arg.to_string()))
+}
+
+fn parse_word(command: &'static str, arg: Option<&str>) -> Result<String, ParseError> {
+    arg.map(str::to_string)
+        .ok_or(ParseError::MissingArgument(command))
+}
+
+fn parse_mqtt_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<MqttSetting, ParseError> {
+    let setting = match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("broker") => MqttSetting::Broker(parse_word("mqtt broker", words.next())?),
+        Some("user") => MqttSetting::Credentials {
+            username: parse_word("mqtt user", words.next())?,
+            password: words.next().unwrap_or_default().to_string(),
+        },
+        Some("prefix") => MqttSetting::TopicPrefix(parse_word("mqtt prefix", words.next())?),
+        Some("interval") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("mqtt interval"))?;
+            MqttSetting::IntervalSecs(
+                arg.parse()
+                    .map_err(|_| ParseError::InvalidArgument("mqtt interval", arg.to_string()))?,
+            )
+        }
+        Some("delta") => MqttSetting::MinDeltaGrams(parse_positive("mqtt delta", words.next())?),
+        Some("diag") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("mqtt diag"))?;
+            MqttSetting::DiagIntervalSecs(
+                arg.parse()
+                    .map_err(|_| ParseError::InvalidArgument("mqtt diag", arg.to_string()))?,
+            )
+        }
+        Some(setting) => return Err(ParseError::UnknownCommand(format!("set mqtt {}", setting))),
+        None => return Err(ParseError::MissingArgument("set mqtt")),
+    };
+    Ok(setting)
+}
+
+fn parse_webhook_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<WebhookSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("url") => match words.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("off") => Ok(WebhookSetting::Url(None)),
+            Some(url)
+                if (url.starts_with("http://") || url.starts_with("https://"))
+                    && url.len() <= WEBHOOK_URL_MAX_LEN =>
+            {
+                Ok(WebhookSetting::Url(Some(url.to_string())))
+            }
+            Some(url) => Err(ParseError::InvalidArgument("webhook url", url.to_string())),
+            None => Err(ParseError::MissingArgument("webhook url")),
+        },
+        Some("threshold") => Ok(WebhookSetting::ThresholdGrams(parse_positive(
+            "webhook threshold",
+            words.next(),
+        )?)),
+        Some("empty") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("webhook empty"))?;
+            arg.parse()
+                .map(WebhookSetting::EmptySecs)
+                .map_err(|_| ParseError::InvalidArgument("webhook empty", arg.to_string()))
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set webhook {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set webhook")),
+    }
+}
+
+fn parse_heap_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<HeapSetting, ParseError> {
+    let setting = words.next().map(str::to_ascii_lowercase);
+    let parse_kb = |command: &'static str, arg: Option<&str>| {
+        let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+        arg.parse::<u32>()
+            .ok()
+            .filter(|kb| *kb <= MAX_HEAP_THRESHOLD_KB)
+            .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+    };
+    match setting.as_deref() {
+        Some("reserve") => Ok(HeapSetting::ReserveKb(parse_kb(
+            "heap reserve",
+            words.next(),
+        )?)),
+        Some("critical") => Ok(HeapSetting::CriticalKb(parse_kb(
+            "heap critical",
+            words.next(),
+        )?)),
+        Some(setting) => Err(ParseError::UnknownCommand(format!("set heap {}", setting))),
+        None => Err(ParseError::MissingArgument("set heap")),
+    }
+}
+
+fn parse_log_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<LogSetting, ParseError> {
+    let setting = words.next().map(str::to_ascii_lowercase);
+    let mut parse_count = |command: &'static str| -> Result<u32, ParseError> {
+        let arg = words.next().ok_or(ParseError::MissingArgument(command))?;
+        arg.parse()
+            .map_err(|_| ParseError::InvalidArgument(command, arg.to_string()))
+    };
+    match setting.as_deref() {
+        Some("interval") => Ok(LogSetting::IntervalSecs(parse_count("log interval")?)),
+        Some("keep") => Ok(LogSetting::Retention(parse_count("log keep")?)),
+        Some(setting) => Err(ParseError::UnknownCommand(format!("set log {}", setting))),
+        None => Err(ParseError::MissingArgument("set log")),
+    }
+}
+
+fn parse_sd_card_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<SdCardSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("on") => Ok(SdCardSetting::Enabled(true)),
+        Some("off") => Ok(SdCardSetting::Enabled(false)),
+        Some("pins") => {
+            let mut pins = [0u8; 4];
+            for pin in &mut pins {
+                let arg = words.next().ok_or(ParseError::MissingArgument("sd pins"))?;
+                *pin = arg
+                    .parse()
+                    .map_err(|_| ParseError::InvalidArgument("sd pins", arg.to_string()))?;
+            }
+            let [sck, mosi, miso, cs] = pins;
+            Ok(SdCardSetting::Pins(SdCardPins {
+                sck,
+                mosi,
+                miso,
+                cs,
+            }))
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!("set sd {}", setting))),
+        None => Err(ParseError::MissingArgument("set sd")),
+    }
+}
+
+fn parse_battery_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<BatterySetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("divider") => Ok(BatterySetting::Divider(parse_positive(
+            "battery divider",
+            words.next(),
+        )?)),
+        Some("cutoff") => Ok(BatterySetting::CutoffVolts(parse_positive(
+            "battery cutoff",
+            words.next(),
+        )?)),
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set battery {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set battery")),
+    }
+}
+
+fn parse_buzzer_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<BuzzerSetting, ParseError> {
+    let setting = words.next().map(str::to_ascii_lowercase);
+    let mut parse_byte = |command: &'static str, max: u8| -> Result<u8, ParseError> {
+        let arg = words.next().ok_or(ParseError::MissingArgument(command))?;
+        arg.parse()
+            .ok()
+            .filter(|value| *value <= max)
+            .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+    };
+    match setting.as_deref() {
+        Some("on") => Ok(BuzzerSetting::Enabled(true)),
+        Some("off") => Ok(BuzzerSetting::Enabled(false)),
+        Some("volume") => Ok(BuzzerSetting::Volume(parse_byte("buzzer volume", 100)?)),
+        Some("pin") => Ok(BuzzerSetting::Pin(parse_byte("buzzer pin", 39)?)),
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set buzzer {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set buzzer")),
+    }
+}
+
+fn parse_sensor_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<SensorSetting, ParseError> {
+    match words.next() {
+        Some(word) if word.eq_ignore_ascii_case("gain") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("sensor gain"))?;
+            arg.parse()
+                .ok()
+                .filter(|gain| NAU7802_GAINS.contains(gain))
+                .map(SensorSetting::Nau7802Gain)
+                .ok_or_else(|| ParseError::InvalidArgument("sensor gain", arg.to_string()))
+        }
+        Some(word) if word.eq_ignore_ascii_case("rate") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("sensor rate"))?;
+            arg.parse()
+                .ok()
+                .and_then(SampleRate::from_hz)
+                .map(SensorSetting::Rate)
+                .ok_or_else(|| ParseError::InvalidArgument("sensor rate", arg.to_string()))
+        }
+        Some(word) if word.eq_ignore_ascii_case("ratepin") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("sensor ratepin"))?;
+            if arg.eq_ignore_ascii_case("off") {
+                return Ok(SensorSetting::RatePin(None));
+            }
+            arg.parse()
+                .ok()
+                .filter(|pin| *pin <= MAX_OUTPUT_GPIO)
+                .map(|pin| SensorSetting::RatePin(Some(pin)))
+                .ok_or_else(|| ParseError::InvalidArgument("sensor ratepin", arg.to_string()))
+        }
+        Some(name) => SensorKind::from_name(name)
+            .map(SensorSetting::Kind)
+            .ok_or_else(|| ParseError::InvalidArgument("set sensor", name.to_string())),
+        None => Err(ParseError::MissingArgument("set sensor")),
+    }
+}
+
+fn parse_clock_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<ClockSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("on") => Ok(ClockSetting::Enabled(true)),
+        Some("off") => Ok(ClockSetting::Enabled(false)),
+        Some("idle") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("clock idle"))?;
+            arg.parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .map(ClockSetting::IdleSecs)
+                .ok_or_else(|| ParseError::InvalidArgument("clock idle", arg.to_string()))
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!("set clock {}", setting))),
+        None => Err(ParseError::MissingArgument("set clock")),
+    }
+}
+
+fn parse_alarm_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<AlarmSetting, ParseError> {
+    let arg = words
+        .next()
+        .ok_or(ParseError::MissingArgument("set alarm"))?;
+    if arg.eq_ignore_ascii_case("renotify") {
+        let arg = words
+            .next()
+            .ok_or(ParseError::MissingArgument("alarm renotify"))?;
+        return arg
+            .parse()
+            .map(AlarmSetting::RenotifySecs)
+            .map_err(|_| ParseError::InvalidArgument("alarm renotify", arg.to_string()));
+    }
+    let slot = arg
+        .parse::<usize>()
+        .ok()
+        .filter(|slot| (1..=MAX_ALARMS).contains(slot))
+        .ok_or_else(|| ParseError::InvalidArgument("set alarm", arg.to_string()))?
+        - 1;
+    let arg = words
+        .next()
+        .ok_or(ParseError::MissingArgument("set alarm"))?;
+    if arg.eq_ignore_ascii_case("off") {
+        return Ok(AlarmSetting::Slot(slot, None));
+    }
+    let kind = AlarmKind::from_name(&arg.to_ascii_lowercase())
+        .ok_or_else(|| ParseError::InvalidArgument("set alarm", arg.to_string()))?;
+    let grams = parse_positive("set alarm", words.next())?;
+    let hysteresis_grams = match words.next() {
+        Some(arg) => parse_positive("alarm hysteresis", Some(arg))?,
+        None => DEFAULT_HYSTERESIS_GRAMS,
+    };
+    Ok(AlarmSetting::Slot(
+        slot,
+        Some(AlarmConfig {
+            kind,
+            grams,
+            hysteresis_grams,
+        }),
+    ))
+}
+
+fn parse_modbus_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<ModbusSetting, ParseError> {
+    let setting = words.next().map(str::to_ascii_lowercase);
+    let mut parse_number = |command: &'static str| {
+        let arg = words.next().ok_or(ParseError::MissingArgument(command))?;
+        arg.parse::<u32>()
+            .map_err(|_| ParseError::InvalidArgument(command, arg.to_string()))
+    };
+    match setting.as_deref() {
+        Some("address") => {
+            let address = parse_number("modbus address")?;
+            u8::try_from(address)
+                .ok()
+                .filter(|address| (1..=MAX_MODBUS_ADDRESS).contains(address))
+                .map(ModbusSetting::Address)
+                .ok_or_else(|| ParseError::InvalidArgument("modbus address", address.to_string()))
+        }
+        Some("baud") => match parse_number("modbus baud")? {
+            baud if MODBUS_BAUD_RATES.contains(&baud) => Ok(ModbusSetting::Baud(baud)),
+            baud => Err(ParseError::InvalidArgument("modbus baud", baud.to_string())),
+        },
+        Some("pins") => {
+            let mut pins = [None; 3];
+            for (index, pin) in pins.iter_mut().enumerate() {
+                // The driver enable pin is optional
+                let Some(arg) = words.next() else {
+                    if index < 2 {
+                        return Err(ParseError::MissingArgument("modbus pins"));
+                    }
+                    break;
+                };
+                *pin = Some(
+                    arg.parse::<u8>()
+                        .ok()
+                        .filter(|pin| *pin <= 39)
+                        .ok_or_else(|| {
+                            ParseError::InvalidArgument("modbus pins", arg.to_string())
+                        })?,
+                );
+            }
+            let [Some(tx), Some(rx), de] = pins else {
+                return Err(ParseError::MissingArgument("modbus pins"));
+            };
+            Ok(ModbusSetting::Pins(ModbusPins { tx, rx, de }))
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set modbus {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set modbus")),
+    }
+}
+
+fn parse_espnow_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<EspNowSetting, ParseError> {
+    let setting = words.next().map(str::to_ascii_lowercase);
+    // Peers are numbered from 1 as `espnow` prints them
+    let parse_index = |command: &'static str, arg: Option<&str>| {
+        let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+        arg.parse::<usize>()
+            .ok()
+            .and_then(|number| number.checked_sub(1))
+            .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+    };
+    match setting.as_deref() {
+        Some("role") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("espnow role"))?;
+            EspNowRole::from_name(arg)
+                .map(EspNowSetting::Role)
+                .ok_or_else(|| ParseError::InvalidArgument("espnow role", arg.to_string()))
+        }
+        Some("label") => {
+            let index = parse_index("espnow label", words.next())?;
+            let label = words.collect::<Vec<_>>().join(" ");
+            if label.is_empty() {
+                return Err(ParseError::MissingArgument("espnow label"));
+            }
+            Ok(EspNowSetting::Label(index, label))
+        }
+        Some("stale") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("espnow stale"))?;
+            arg.parse::<u32>()
+                .ok()
+                .filter(|ms| (MIN_STALE_MS..=MAX_STALE_MS).contains(ms))
+                .map(EspNowSetting::StaleMs)
+                .ok_or_else(|| ParseError::InvalidArgument("espnow stale", arg.to_string()))
+        }
+        Some("aggregator") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("espnow aggregator"))?;
+            if arg.eq_ignore_ascii_case("none") {
+                return Ok(EspNowSetting::Aggregator(None));
+            }
+            parse_mac(arg)
+                .map(|mac| EspNowSetting::Aggregator(Some(mac)))
+                .ok_or_else(|| ParseError::InvalidArgument("espnow aggregator", arg.to_string()))
+        }
+        Some("peer") => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("add") => {
+                let arg = words
+                    .next()
+                    .ok_or(ParseError::MissingArgument("espnow peer add"))?;
+                let mac = parse_mac(arg).ok_or_else(|| {
+                    ParseError::InvalidArgument("espnow peer add", arg.to_string())
+                })?;
+                let label = words.collect::<Vec<_>>().join(" ");
+                Ok(EspNowSetting::AddPeer(
+                    mac,
+                    (!label.is_empty()).then_some(label),
+                ))
+            }
+            Some("remove") => {
+                parse_index("espnow peer remove", words.next()).map(EspNowSetting::RemovePeer)
+            }
+            Some(word) => Err(ParseError::UnknownCommand(format!(
+                "set espnow peer {}",
+                word
+            ))),
+            None => Err(ParseError::MissingArgument("set espnow peer")),
+        },
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set espnow {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set espnow")),
+    }
+}
+
+fn parse_auto_hold_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<AutoHoldSetting, ParseError> {
+    match words.next() {
+        Some(arg) if arg.eq_ignore_ascii_case("time") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("autohold time"))?;
+            arg.parse()
+                .ok()
+                .filter(|secs| (1..=MAX_AUTO_HOLD_S).contains(secs))
+                .map(AutoHoldSetting::Secs)
+                .ok_or_else(|| ParseError::InvalidArgument("autohold time", arg.to_string()))
+        }
+        arg => parse_positive_or_off("autohold", arg).map(AutoHoldSetting::BandGrams),
+    }
+}
+
+fn parse_input_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<InputSetting, ParseError> {
+    let arg = words
+        .next()
+        .ok_or(ParseError::MissingArgument("set input"))?;
+    match arg.to_ascii_lowercase().as_str() {
+        "threshold" => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("input threshold"))?;
+            arg.parse()
+                .ok()
+                .filter(|percent| {
+                    (MIN_GESTURE_THRESHOLD_PERCENT..=MAX_GESTURE_THRESHOLD_PERCENT)
+                        .contains(percent)
+                })
+                .map(InputSetting::ThresholdPercent)
+                .ok_or_else(|| ParseError::InvalidArgument("input threshold", arg.to_string()))
+        }
+        "press" => parse_gesture_time("input press", MAX_GESTURE_PRESS, words.next())
+            .map(InputSetting::Press),
+        "window" => parse_gesture_time("input window", MAX_GESTURE_WINDOW, words.next())
+            .map(InputSetting::Window),
+        _ => InputMode::from_name(arg)
+            .map(InputSetting::Mode)
+            .ok_or_else(|| ParseError::InvalidArgument("set input", arg.to_string())),
+    }
+}
+
+/// A time of the weight gestures in seconds, up to `max`
+fn parse_gesture_time(
+    command: &'static str,
+    max: Duration,
+    arg: Option<&str>,
+) -> Result<Duration, ParseError> {
+    let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+    arg.parse::<f32>()
+        .ok()
+        .filter(|secs| *secs > 0.0 && *secs <= max.as_secs_f32())
+        .map(Duration::from_secs_f32)
+        .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+}
+
+fn parse_negative_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<NegativeSetting, ParseError> {
+    match words.next() {
+        Some(arg) if arg.eq_ignore_ascii_case("time") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("negative time"))?;
+            arg.parse()
+                .ok()
+                .filter(|secs| *secs <= MAX_NEGATIVE_DELAY_S)
+                .map(NegativeSetting::Secs)
+                .ok_or_else(|| ParseError::InvalidArgument("negative time", arg.to_string()))
+        }
+        Some(arg) if arg.eq_ignore_ascii_case("autotare") => {
+            match words.next().map(str::to_ascii_lowercase).as_deref() {
+                Some("on") => Ok(NegativeSetting::AutoTare(true)),
+                Some("off") => Ok(NegativeSetting::AutoTare(false)),
+                Some(arg) => Err(ParseError::InvalidArgument(
+                    "negative autotare",
+                    arg.to_string(),
+                )),
+                None => Err(ParseError::MissingArgument("negative autotare")),
+            }
+        }
+        arg => parse_positive_or_off("negative", arg).map(NegativeSetting::Grams),
+    }
+}
+
+fn parse_noise_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<NoiseSetting, ParseError> {
+    let setting = match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("warn") => NoiseSetting::WarnCounts,
+        Some("fail") => NoiseSetting::FailCounts,
+        Some(setting) => return Err(ParseError::UnknownCommand(format!("set noise {}", setting))),
+        None => return Err(ParseError::MissingArgument("set noise")),
+    };
+    let counts = parse_positive("set noise", words.next())?;
+    if counts > MAX_NOISE_COUNTS {
+        return Err(ParseError::InvalidArgument(
+            "set noise",
+            format!("at most {}", MAX_NOISE_COUNTS),
+        ));
+    }
+    Ok(setting(counts))
+}
+
+fn parse_low_power_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<LowPowerSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("on") => Ok(LowPowerSetting::Enabled(true)),
+        Some("off") => Ok(LowPowerSetting::Enabled(false)),
+        Some("after") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("lowpower after"))?;
+            arg.parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .map(LowPowerSetting::AfterSecs)
+                .ok_or_else(|| ParseError::InvalidArgument("lowpower after", arg.to_string()))
+        }
+        Some("wake") => {
+            parse_positive("lowpower wake", words.next()).map(LowPowerSetting::WakeGrams)
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set lowpower {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set lowpower")),
+    }
+}
+
+fn parse_schedule_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<ScheduleSetting, ParseError> {
+    let days = words
+        .next()
+        .ok_or(ParseError::MissingArgument("set schedule"))?;
+    if days.eq_ignore_ascii_case("off") {
+        return Ok(ScheduleSetting::Clear);
+    }
+    let days = Weekdays::from_names(days)
+        .ok_or_else(|| ParseError::InvalidArgument("set schedule", days.to_string()))?;
+    let times = words
+        .next()
+        .ok_or(ParseError::MissingArgument("set schedule"))?;
+    let invalid = || ParseError::InvalidArgument("set schedule", times.to_string());
+    let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+    let start = parse_time_of_day(start).ok_or_else(invalid)?;
+    let end = parse_time_of_day(end).ok_or_else(invalid)?;
+    ActiveWindow::new(days, start, end)
+        .map(ScheduleSetting::Add)
+        .ok_or_else(invalid)
+}
+
+/// Minutes since midnight from `HH:MM`, `24:00` as the midnight ending a day
+fn parse_time_of_day(arg: &str) -> Option<u16> {
+    let (hours, minutes) = arg.split_once(':')?;
+    let hours: u16 = hours.parse().ok()?;
+    let minutes: u16 = minutes.parse().ok()?;
+    if minutes >= 60 {
+        return None;
+    }
+    hours
+        .checked_mul(60)
+        .and_then(|minute| minute.checked_add(minutes))
+        .filter(|&minute| minute <= MINUTES_PER_DAY)
+        .map(|minute| minute % MINUTES_PER_DAY)
+}
+
+fn parse_idle_setting<'l>(mut words: impl Iterator<Item = &'l str>) -> Result<Command, ParseError> {
+    let stage = match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("dim") => IdleStage::Dimmed,
+        Some("display") => IdleStage::DisplayOff,
+        Some("sleep") => IdleStage::DeepSleep,
+        Some(stage) => return Err(ParseError::UnknownCommand(format!("set idle {}", stage))),
+        None => return Err(ParseError::MissingArgument("set idle")),
+    };
+    let arg = words
+        .next()
+        .ok_or(ParseError::MissingArgument("set idle"))?;
+    if arg.eq_ignore_ascii_case("off") {
+        return Ok(Command::SetIdle(stage, 0));
+    }
+    arg.parse()
+        .ok()
+        .filter(|secs| (MIN_IDLE_TIMEOUT_S..=MAX_IDLE_TIMEOUT_S).contains(secs))
+        .map(|secs| Command::SetIdle(stage, secs))
+        .ok_or_else(|| ParseError::InvalidArgument("set idle", arg.to_string()))
+}
+
+fn parse_lock_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<LockSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("on") => Ok(LockSetting::Enabled(true)),
+        Some("off") => Ok(LockSetting::Enabled(false)),
+        Some("pin") => parse_lock_pin("lock pin", words.next()).map(LockSetting::Pin),
+        Some("pattern") => match words.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("off") => Ok(LockSetting::Pattern(None)),
+            Some(arg) => ClickPattern::from_name(arg)
+                .map(|pattern| LockSetting::Pattern(Some(pattern)))
+                .ok_or_else(|| ParseError::InvalidArgument("lock pattern", arg.to_string())),
+            None => Err(ParseError::MissingArgument("lock pattern")),
+        },
+        Some("relock") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("lock relock"))?;
+            arg.parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .map(LockSetting::RelockSecs)
+                .ok_or_else(|| ParseError::InvalidArgument("lock relock", arg.to_string()))
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!("set lock {}", setting))),
+        None => Err(ParseError::MissingArgument("set lock")),
+    }
+}
+
+/// A weight of the demo signal, which may be zero or negative
+fn parse_demo_grams(command: &'static str, arg: Option<&str>) -> Result<f32, ParseError> {
+    let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+    arg.parse::<f32>()
+        .ok()
+        .filter(|grams| grams.is_finite() && grams.abs() <= MAX_DEMO_GRAMS)
+        .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+}
+
+/// A time of the demo signal in seconds, up to `MAX_DEMO_SECS`
+fn parse_demo_secs(command: &'static str, arg: Option<&str>) -> Result<Duration, ParseError> {
+    let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+    arg.parse::<f32>()
+        .ok()
+        .filter(|secs| (0.0..=MAX_DEMO_SECS).contains(secs))
+        .map(Duration::from_secs_f32)
+        .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+}
+
+fn parse_creep_command<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<CreepCommand, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        None => Ok(CreepCommand::Show),
+        Some("measure") => Ok(CreepCommand::Measure),
+        Some("off") => Ok(CreepCommand::Set(None)),
+        Some("set") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("creep set"))?;
+            let secs = arg
+                .parse::<f32>()
+                .ok()
+                .filter(|secs| (0.0..=MAX_CREEP_TIME_CONSTANT_S).contains(secs))
+                .ok_or_else(|| ParseError::InvalidArgument("creep set", arg.to_string()))?;
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("creep set"))?;
+            let percent = arg
+                .parse::<f32>()
+                .ok()
+                .filter(|percent| percent.is_finite() && percent.abs() <= MAX_CREEP_PERCENT)
+                .ok_or_else(|| ParseError::InvalidArgument("creep set", arg.to_string()))?;
+            Ok(CreepCommand::Set(Some(CreepModel {
+                time_constant: Duration::from_secs_f32(secs),
+                fraction: percent / 100.0,
+            })))
+        }
+        Some(arg) => Err(ParseError::UnknownCommand(format!("creep {}", arg))),
+    }
+}
+
+fn parse_soak_command<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<SoakCommand, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        None => Ok(SoakCommand::Report),
+        Some("stop") => Ok(SoakCommand::Stop),
+        Some("start") => {
+            let interval = match words.next() {
+                Some(arg) => arg
+                    .parse::<u64>()
+                    .ok()
+                    .map(Duration::from_secs)
+                    .filter(|interval| (MIN_SOAK_INTERVAL..=MAX_SOAK_INTERVAL).contains(interval))
+                    .ok_or_else(|| ParseError::InvalidArgument("soak start", arg.to_string()))?,
+                None => DEFAULT_SOAK_INTERVAL,
+            };
+            let duration = match words.next() {
+                Some(arg) => Some(
+                    arg.parse::<f32>()
+                        .ok()
+                        .map(|hours| hours * 3600.0)
+                        .filter(|secs| *secs > 0.0 && *secs <= MAX_SOAK_DURATION.as_secs_f32())
+                        .map(Duration::from_secs_f32)
+                        .ok_or_else(|| {
+                            ParseError::InvalidArgument("soak start", arg.to_string())
+                        })?,
+                ),
+                None => None,
+            };
+            Ok(SoakCommand::Start { interval, duration })
+        }
+        Some(arg) => Err(ParseError::UnknownCommand(format!("soak {}", arg))),
+    }
+}
+
+fn parse_trace_command<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<TraceCommand, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("record") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("trace record"))?;
+            arg.parse()
+                .ok()
+                .filter(|secs| (1..=MAX_TRACE_SECS).contains(secs))
+                .map(TraceCommand::Record)
+                .ok_or_else(|| ParseError::InvalidArgument("trace record", arg.to_string()))
+        }
+        Some("load") => Ok(TraceCommand::Load),
+        Some("dump") => Ok(TraceCommand::Dump),
+        Some("replay") => {
+            let window = match words.next() {
+                Some(arg) => Some(
+                    arg.parse()
+                        .ok()
+                        .filter(|window| (1..=MAX_REPLAY_WINDOW).contains(window))
+                        .ok_or_else(|| {
+                            ParseError::InvalidArgument("trace replay", arg.to_string())
+                        })?,
+                ),
+                None => None,
+            };
+            let band = match words.next() {
+                Some(arg) => Some(parse_positive("trace replay", Some(arg))?),
+                None => None,
+            };
+            Ok(TraceCommand::Replay { window, band })
+        }
+        Some(arg) => Err(ParseError::UnknownCommand(format!("trace {}", arg))),
+        None => Err(ParseError::MissingArgument("trace")),
+    }
+}
+
+fn parse_demo_command<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<DemoCommand, ParseError> {
+    let pattern = match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("on") => return Ok(DemoCommand::Start(None)),
+        Some("off") => return Ok(DemoCommand::Stop),
+        Some("log") => {
+            return match words.next().map(str::to_ascii_lowercase).as_deref() {
+                Some("on") => Ok(DemoCommand::Log(true)),
+                Some("off") => Ok(DemoCommand::Log(false)),
+                Some(arg) => Err(ParseError::InvalidArgument("demo log", arg.to_string())),
+                None => Err(ParseError::MissingArgument("demo log")),
+            }
+        }
+        Some("ramp") => DemoPattern::Ramp {
+            from_grams: parse_demo_grams("demo ramp", words.next())?,
+            to_grams: parse_demo_grams("demo ramp", words.next())?,
+            duration: parse_demo_secs("demo ramp", words.next())?,
+        },
+        Some("step") => DemoPattern::Step {
+            grams: parse_demo_grams("demo step", words.next())?,
+        },
+        Some("noise") => DemoPattern::Noise {
+            setpoint_grams: parse_demo_grams("demo noise", words.next())?,
+            amplitude_grams: parse_demo_grams("demo noise", words.next())?.abs(),
+        },
+        Some("script") => {
+            let steps = words
+                .map(|arg| {
+                    let (secs, grams) = arg.split_once(':').ok_or_else(|| {
+                        ParseError::InvalidArgument("demo script", arg.to_string())
+                    })?;
+                    Ok(ScriptStep {
+                        at: parse_demo_secs("demo script", Some(secs))?,
+                        grams: parse_demo_grams("demo script", Some(grams))?,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if steps.is_empty() {
+                return Err(ParseError::MissingArgument("demo script"));
+            }
+            if steps.len() > MAX_SCRIPT_STEPS {
+                return Err(ParseError::InvalidArgument(
+                    "demo script",
+                    format!("at most {} steps", MAX_SCRIPT_STEPS),
+                ));
+            }
+            if steps.windows(2).any(|pair| pair[1].at < pair[0].at) {
+                return Err(ParseError::InvalidArgument(
+                    "demo script",
+                    "steps out of order".to_string(),
+                ));
+            }
+            DemoPattern::Script(steps)
+        }
+        Some(arg) => return Err(ParseError::UnknownCommand(format!("demo {}", arg))),
+        None => return Err(ParseError::MissingArgument("demo")),
+    };
+    Ok(DemoCommand::Start(Some(pattern)))
+}
+
+/// PIN argument of the command, see `parse_pin`
+fn parse_lock_pin(command: &'static str, arg: Option<&str>) -> Result<u16, ParseError> {
+    let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+    parse_pin(arg).ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+}
+
+fn parse_startup_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<StartupSetting, ParseError> {
+    match words.next() {
+        Some(arg) if arg.eq_ignore_ascii_case("tolerance") => {
+            parse_positive("startup tolerance", words.next()).map(StartupSetting::ToleranceGrams)
+        }
+        Some(arg) => StartupMode::from_name(arg)
+            .map(StartupSetting::Mode)
+            .ok_or_else(|| ParseError::InvalidArgument("startup", arg.to_string())),
+        None => Err(ParseError::MissingArgument("startup")),
+    }
+}
+
+fn parse_stale_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<StaleSetting, ParseError> {
+    match words.next() {
+        Some(arg) if arg.eq_ignore_ascii_case("lost") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("stale lost"))?;
+            if arg.eq_ignore_ascii_case("off") {
+                return Ok(StaleSetting::LostSecs(None));
+            }
+            arg.parse()
+                .ok()
+                .filter(|secs| (1..=MAX_SENSOR_LOST_S).contains(secs))
+                .map(|secs| StaleSetting::LostSecs(Some(secs)))
+                .ok_or_else(|| ParseError::InvalidArgument("stale lost", arg.to_string()))
+        }
+        Some(arg) => arg
+            .parse()
+            .ok()
+            .filter(|millis| (MIN_STALE_READING_MS..=MAX_STALE_READING_MS).contains(millis))
+            .map(StaleSetting::Millis)
+            .ok_or_else(|| ParseError::InvalidArgument("stale", arg.to_string())),
+        None => Err(ParseError::MissingArgument("stale")),
+    }
+}
+
+fn parse_button_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<ButtonSetting, ParseError> {
+    let (command, max, setting): (_, _, fn(Option<u32>) -> ButtonSetting) =
+        match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("stuck") => ("button stuck", MAX_BUTTON_STUCK_S, ButtonSetting::StuckSecs),
+            Some("disconnect") => (
+                "button disconnect",
+                MAX_BUTTON_DISCONNECT_H,
+                ButtonSetting::DisconnectHours,
+            ),
+            Some(arg) => return Err(ParseError::InvalidArgument("set button", arg.to_string())),
+            None => return Err(ParseError::MissingArgument("set button")),
+        };
+    let arg = words.next().ok_or(ParseError::MissingArgument(command))?;
+    if arg.eq_ignore_ascii_case("off") {
+        return Ok(setting(None));
+    }
+    arg.parse()
+        .ok()
+        .filter(|value| (1..=max).contains(value))
+        .map(|value| setting(Some(value)))
+        .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+}
+
+fn parse_cal_reminder_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<CalReminderSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("days") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("calreminder days"))?;
+            arg.parse()
+                .map(CalReminderSetting::Days)
+                .map_err(|_| ParseError::InvalidArgument("calreminder days", arg.to_string()))
+        }
+        Some("drift") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("calreminder drift"))?;
+            arg.parse::<f32>()
+                .ok()
+                .filter(|grams| grams.is_finite() && *grams >= 0.0)
+                .map(CalReminderSetting::DriftGrams)
+                .ok_or_else(|| ParseError::InvalidArgument("calreminder drift", arg.to_string()))
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set calreminder {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set calreminder")),
+    }
+}
+
+fn parse_led_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<LedSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("pin") => {
+            let arg = words.next().ok_or(ParseError::MissingArgument("led pin"))?;
+            arg.parse()
+                .ok()
+                .filter(|pin| *pin <= 39)
+                .map(LedSetting::Pin)
+                .ok_or_else(|| ParseError::InvalidArgument("led pin", arg.to_string()))
+        }
+        Some(setting) => LedBackend::ALL
+            .into_iter()
+            .find(|backend| backend.name() == setting)
+            .map(LedSetting::Backend)
+            .ok_or_else(|| ParseError::UnknownCommand(format!("set led {}", setting))),
+        None => Err(ParseError::MissingArgument("set led")),
+    }
+}
+
+fn parse_mirror_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<MirrorSetting, ParseError> {
+    let parse_pin = |arg: Option<&str>| -> Result<u8, ParseError> {
+        let arg = arg.ok_or(ParseError::MissingArgument("mirror bus"))?;
+        arg.parse()
+            .ok()
+            .filter(|pin| *pin <= MAX_OUTPUT_GPIO)
+            .ok_or_else(|| ParseError::InvalidArgument("mirror bus", arg.to_string()))
+    };
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("off") => Ok(MirrorSetting::Off),
+        Some("rotation") => {
+            let arg = words
+                .next()
+                .ok_or(ParseError::MissingArgument("mirror rotation"))?;
+            match arg {
+                "0" => Ok(MirrorSetting::QuarterTurns(0)),
+                "90" => Ok(MirrorSetting::QuarterTurns(1)),
+                "180" => Ok(MirrorSetting::QuarterTurns(2)),
+                "270" => Ok(MirrorSetting::QuarterTurns(3)),
+                _ => Err(ParseError::InvalidArgument(
+                    "mirror rotation",
+                    arg.to_string(),
+                )),
+            }
+        }
+        Some("bus") => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("display") => Ok(MirrorSetting::Pins(None)),
+            Some(arg) => {
+                let sda = parse_pin(Some(arg))?;
+                let scl = parse_pin(words.next())?;
+                Ok(MirrorSetting::Pins(Some((sda, scl))))
+            }
+            None => Err(ParseError::MissingArgument("mirror bus")),
+        },
+        Some(arg) => {
+            let address = u8::from_str_radix(arg.trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|address| matches!(address, 0x3C | 0x3D))
+                .ok_or_else(|| ParseError::InvalidArgument("set mirror", arg.to_string()))?;
+            Ok(MirrorSetting::Address(address))
+        }
+        None => Err(ParseError::MissingArgument("set mirror")),
+    }
+}
+
+fn parse_flash_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<FlashSetting, ParseError> {
+    let event = words.next().map(str::to_ascii_lowercase);
+    let mut parse_enabled = |command: &'static str| -> Result<bool, ParseError> {
+        match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("on") => Ok(true),
+            Some("off") => Ok(false),
+            Some(arg) => Err(ParseError::InvalidArgument(command, arg.to_string())),
+            None => Err(ParseError::MissingArgument(command)),
+        }
+    };
+    match event.as_deref() {
+        Some("target") => Ok(FlashSetting::Target(parse_enabled("flash target")?)),
+        Some("overload") => Ok(FlashSetting::Overload(parse_enabled("flash overload")?)),
+        Some(event) => Err(ParseError::UnknownCommand(format!("set flash {}", event))),
+        None => Err(ParseError::MissingArgument("set flash")),
+    }
+}
+
+fn parse_dispense_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<DispenseSetting, ParseError> {
+    let setting = words.next().map(str::to_ascii_lowercase);
+    let arg = words.next();
+    let parse_integer = |command: &'static str| {
+        let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+        arg.parse::<u32>()
+            .map_err(|_| ParseError::InvalidArgument(command, arg.to_string()))
+    };
+    match setting.as_deref() {
+        Some("pin") => match parse_integer("dispense pin")? {
+            pin @ 0..=39 => Ok(DispenseSetting::Pin(pin as u8)),
+            pin => Err(ParseError::InvalidArgument("dispense pin", pin.to_string())),
+        },
+        Some("timeout") => Ok(DispenseSetting::TimeoutSecs(parse_integer(
+            "dispense timeout",
+        )?)),
+        Some("max") => Ok(DispenseSetting::MaxGrams(parse_positive(
+            "dispense max",
+            arg,
+        )?)),
+        Some("compensation") => {
+            let command = "dispense compensation";
+            let arg = arg.ok_or(ParseError::MissingArgument(command))?;
+            arg.parse::<f32>()
+                .ok()
+                .filter(|grams| grams.is_finite() && *grams >= 0.0)
+                .map(DispenseSetting::CompensationGrams)
+                .ok_or_else(|| ParseError::InvalidArgument(command, arg.to_string()))
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set dispense {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set dispense")),
+    }
+}
+
+fn parse_brew_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<BrewSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("start") => Ok(BrewSetting::StartGrams(parse_positive(
+            "brew start",
+            words.next(),
+        )?)),
+        Some("flow") => Ok(BrewSetting::StopGramsPerSec(parse_positive(
+            "brew flow",
+            words.next(),
+        )?)),
+        Some("grace") => Ok(BrewSetting::StopGrace(Duration::from_secs_f32(
+            parse_positive("brew grace", words.next())?,
+        ))),
+        Some(setting) => Err(ParseError::UnknownCommand(format!("set brew {}", setting))),
+        None => Err(ParseError::MissingArgument("set brew")),
+    }
+}
+
+fn parse_recipe_setting<'l>(
+    mut words: impl Iterator<Item = &'l str>,
+) -> Result<RecipeSetting, ParseError> {
+    match words.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("dose") => Ok(RecipeSetting::DoseGrams(parse_positive(
+            "recipe dose",
+            words.next(),
+        )?)),
+        Some("ratio") => {
+            // The water part of `1:16`, or a plain `16`
+            let arg = words.next();
+            let ratio = arg.map(|arg| arg.strip_prefix("1:").unwrap_or(arg));
+            Ok(RecipeSetting::Ratio(parse_positive("recipe ratio", ratio)?))
+        }
+        Some(setting) => Err(ParseError::UnknownCommand(format!(
+            "set recipe {}",
+            setting
+        ))),
+        None => Err(ParseError::MissingArgument("set recipe")),
+    }
+}
+
+/// Parse a time zone offset given as `[+-]hh[:mm]`
+fn parse_utc_offset(arg: Option<&str>) -> Result<i16, ParseError> {
+    let arg = arg.ok_or(ParseError::MissingArgument("tz"))?;
+    let invalid = || ParseError::InvalidArgument("tz", arg.to_string());
+    let (sign, offset) = match arg.strip_prefix('-') {
+        Some(offset) => (-1, offset),
+        None => (1, arg.strip_prefix('+').unwrap_or(arg)),
+    };
+    let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+    let hours: u8 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u8 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 14 || minutes >= 60 {
+        return Err(invalid());
+    }
+    Ok(sign * (i16::from(hours) * 60 + i16::from(minutes)))
+}
+
+fn parse_stream_rate(arg: Option<&str>) -> Result<StreamRate, ParseError> {
+    match arg.map(str::to_ascii_lowercase).as_deref() {
+        Some("on") => Ok(StreamRate::EverySample),
+        Some("off") => Ok(StreamRate::Off),
+        arg => parse_positive("stream", arg).map(StreamRate::Hz),
+    }
+}
+
+/// Parse a console line. Returns `Ok(None)` for blank lines.
+pub fn parse_command(line: &str) -> Result<Option<Command>, ParseError> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+
+    let command = match name.to_ascii_lowercase().as_str() {
+        "tare" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("soft") => Command::SoftTare(SoftTareAction::Push),
+            Some("toggle") => Command::SoftTare(SoftTareAction::Toggle),
+            Some("clear") => Command::SoftTare(SoftTareAction::Clear),
+            Some(arg) => return Err(ParseError::UnknownCommand(format!("tare {}", arg))),
+            None => Command::Tare,
+        },
+        "untare" => Command::SoftTare(SoftTareAction::Pop),
+        "hold" => match words.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("release") => Command::ReleaseHold,
+            Some(arg) => return Err(ParseError::UnknownCommand(format!("hold {}", arg))),
+            None => Command::Hold,
+        },
+        "cal" => match words.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("start") => Command::RemoteCalibration(
+                RemoteCalibration::Start(parse_positive("cal start", words.next())?),
+            ),
+            Some(arg) if arg.eq_ignore_ascii_case("step") => {
+                Command::RemoteCalibration(RemoteCalibration::Step)
+            }
+            Some(arg) if arg.eq_ignore_ascii_case("status") => {
+                Command::RemoteCalibration(RemoteCalibration::Status)
+            }
+            Some(arg) if arg.eq_ignore_ascii_case("export") => Command::ExportCalibration,
+            Some(arg) if arg.eq_ignore_ascii_case("import") => {
+                let blob = words
+                    .next()
+                    .ok_or(ParseError::MissingArgument("cal import"))?;
+                let record = CalibrationRecord::decode(blob)
+                    .map_err(|err| ParseError::InvalidArgument("cal import", err.to_string()))?;
+                Command::ImportCalibration(record)
+            }
+            Some(arg) => Command::Calibrate {
+                weight_grams: Some(parse_positive("cal", Some(arg))?),
+            },
+            None => Command::Calibrate { weight_grams: None },
+        },
+        "linearity" => match words.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("cancel") => {
+                Command::Linearity(LinearityCommand::Cancel)
+            }
+            Some(arg) if arg.eq_ignore_ascii_case("report") => {
+                Command::Linearity(LinearityCommand::Report)
+            }
+            first => {
+                let weights = first
+                    .into_iter()
+                    .chain(words.by_ref())
+                    .map(|arg| parse_positive("linearity", Some(arg)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if weights.len() > MAX_LINEARITY_WEIGHTS {
+                    return Err(ParseError::InvalidArgument(
+                        "linearity",
+                        format!("at most {} weights", MAX_LINEARITY_WEIGHTS),
+                    ));
+                }
+                Command::Linearity(LinearityCommand::Start(weights))
+            }
+        },
+        "noise" => match words.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("test") => Command::Noise(NoiseCommand::Test),
+            Some(arg) => return Err(ParseError::UnknownCommand(format!("noise {}", arg))),
+            None => Command::Noise(NoiseCommand::Report),
+        },
+        "lock" => Command::Lock,
+        "unlock" => Command::Unlock(parse_lock_pin("unlock", words.next())?),
+        "pin" => {
+            let pin = parse_lock_pin("pin", words.next())?;
+            let rest: Vec<&str> = words.collect();
+            match parse_command(&rest.join(" "))? {
+                Some(command) => Command::WithPin(pin, Box::new(command)),
+                None => return Err(ParseError::MissingArgument("pin")),
+            }
+        }
+        "raw" => Command::Raw,
+        "factor" => Command::Factor,
+        "stats" | "status" => Command::Stats,
+        "diag" => Command::Diagnostics,
+        "counters" => match words.next() {
+            None => Command::Counters,
+            Some(word) if word.eq_ignore_ascii_case("reset") => {
+                let arg = words
+                    .next()
+                    .ok_or(ParseError::MissingArgument("counters reset"))?;
+                let counter = Counter::from_name(arg).ok_or_else(|| {
+                    ParseError::InvalidArgument("counters reset", arg.to_string())
+                })?;
+                Command::ResetCounter(counter)
+            }
+            Some(word) => return Err(ParseError::UnknownCommand(format!("counters {}", word))),
+        },
+        "whoami" => Command::WhoAmI,
+        "espnow" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            None => Command::EspNow,
+            Some("pair") => Command::EspNowPair,
+            Some(word) => return Err(ParseError::UnknownCommand(format!("espnow {}", word))),
+        },
+        "schedule" => Command::Schedule,
+        "setup" => Command::Setup,
+        "stream" => Command::Stream(parse_stream_rate(words.next())?),
+        "dump" => Command::Dump,
+        "history" => {
+            let arg = words.next().ok_or(ParseError::MissingArgument("history"))?;
+            let granularity = Granularity::from_name(&arg.to_ascii_lowercase())
+                .ok_or_else(|| ParseError::InvalidArgument("history", arg.to_string()))?;
+            let count = match words.next() {
+                Some(arg) => arg
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|count| (1..=granularity.kept()).contains(count))
+                    .ok_or_else(|| ParseError::InvalidArgument("history", arg.to_string()))?,
+                None => granularity.default_count(),
+            };
+            Command::History { granularity, count }
+        }
+        "average" => match words.next() {
+            Some(arg) => arg
+                .parse::<u64>()
+                .ok()
+                .and_then(|minutes| minutes.checked_mul(60))
+                .map(Duration::from_secs)
+                .filter(|window| !window.is_zero() && *window <= MAX_AVERAGE_WINDOW)
+                .map(Command::Average)
+                .ok_or_else(|| ParseError::InvalidArgument("average", arg.to_string()))?,
+            None => Command::Average(DEFAULT_AVERAGE_WINDOW),
+        },
+        "clear" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("log") => Command::ClearLog,
+            Some("resets") => Command::ClearResets,
+            Some(what) => return Err(ParseError::UnknownCommand(format!("clear {}", what))),
+            None => return Err(ParseError::MissingArgument("clear")),
+        },
+        "storage" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("dump") => Command::StorageDump,
+            Some("flush") => Command::StorageFlush,
+            Some(what) => return Err(ParseError::UnknownCommand(format!("storage {}", what))),
+            None => return Err(ParseError::MissingArgument("storage")),
+        },
+        "alarms" => Command::Alarms,
+        "calreminder" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("snooze") => Command::CalReminder(CalReminderAction::Snooze),
+            Some("dismiss") => Command::CalReminder(CalReminderAction::Dismiss),
+            Some(what) => return Err(ParseError::UnknownCommand(format!("calreminder {}", what))),
+            None => return Err(ParseError::MissingArgument("calreminder")),
+        },
+        "session" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            None => Command::Session,
+            Some("new") => Command::NewSession,
+            Some(what) => return Err(ParseError::UnknownCommand(format!("session {}", what))),
+        },
+        "alarm" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("ack") => Command::AcknowledgeAlarms,
+            Some(what) => return Err(ParseError::UnknownCommand(format!("alarm {}", what))),
+            None => return Err(ParseError::MissingArgument("alarm")),
+        },
+        "identify" => Command::Identify,
+        "demo" => Command::Demo(parse_demo_command(words)?),
+        "soak" => Command::Soak(parse_soak_command(words)?),
+        "certified" => {
+            Command::Certified(match words.next().map(str::to_ascii_lowercase).as_deref() {
+                None => None,
+                Some("on") => Some(true),
+                Some("off") => Some(false),
+                Some(arg) => return Err(ParseError::InvalidArgument("certified", arg.to_string())),
+            })
+        }
+        "creep" => Command::Creep(parse_creep_command(words)?),
+        "trace" => Command::Trace(parse_trace_command(words)?),
+        "loglevel" => Command::LogLevel(match words.next() {
+            Some(arg) => Some(
+                arg.parse()
+                    .map_err(|_| ParseError::InvalidArgument("loglevel", arg.to_string()))?,
+            ),
+            None => None,
+        }),
+        "logs" => Command::Logs,
+        "brew" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            None | Some("on") => Command::Brew(true),
+            Some("off") => Command::Brew(false),
+            Some(arg) => return Err(ParseError::InvalidArgument("brew", arg.to_string())),
+        },
+        "recipe" => Command::Recipe,
+        "dispense" => match words.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("stop") => Command::StopDispense,
+            arg => Command::Dispense(parse_positive("dispense", arg)?),
+        },
+        "decommission" => Command::Decommission,
+        "reboot" => Command::Restart(ShutdownReason::Command),
+        "sleep" => Command::Sleep,
+        "factoryreset" => Command::FactoryReset,
+        "help" | "?" => Command::Help,
+        "set" => match words.next().map(str::to_ascii_lowercase).as_deref() {
+            Some("unit") => Command::SetUnit(parse_unit(words.next())?),
+            Some("volume") => Command::SetVolume(parse_volume_setting(words)?),
+            Some("deadband") => Command::SetDeadband(parse_deadband_setting(words)?),
+            Some("resolution") => {
+                Command::SetResolution(parse_positive("resolution", words.next())?)
+            }
+            Some("calweight") => {
+                Command::SetCalibrationWeight(parse_positive("calweight", words.next())?)
+            }
+            Some("wifi") => Command::SetWifi {
+                ssid: parse_word("wifi", words.next())?,
+                password: words.next().unwrap_or_default().to_string(),
+            },
+            Some("hostname") => {
+                let hostname = parse_word("hostname", words.next())?;
+                if hostname == "default" {
+                    Command::SetHostname(None)
+                } else if !Settings::is_valid_hostname(&hostname) {
+                    return Err(ParseError::InvalidArgument("hostname", hostname));
+                } else {
+                    Command::SetHostname(Some(hostname.to_ascii_lowercase()))
+                }
+            }
+            Some("tz") => Command::SetUtcOffset(parse_utc_offset(words.next())?),
+            Some("mqtt") => Command::SetMqtt(parse_mqtt_setting(words)?),
+            Some("webhook") => Command::SetWebhook(parse_webhook_setting(words)?),
+            Some("heap") => Command::SetHeap(parse_heap_setting(words)?),
+            Some("log") => Command::SetLog(parse_log_setting(words)?),
+            Some("sd") => Command::SetSdCard(parse_sd_card_setting(words)?),
+            Some("battery") => Command::SetBattery(parse_battery_setting(words)?),
+            Some("buzzer") => Command::SetBuzzer(parse_buzzer_setting(words)?),
+            Some("sensor") => Command::SetSensor(parse_sensor_setting(words)?),
+            Some("quiesce") => {
+                let arg = words
+                    .next()
+                    .ok_or(ParseError::MissingArgument("set quiesce"))?;
+                let mode = QuiesceMode::from_name(arg)
+                    .ok_or_else(|| ParseError::InvalidArgument("set quiesce", arg.to_string()))?;
+                Command::SetQuiesce(mode)
+            }
+            Some("bump") => Command::SetBumpThreshold(parse_positive_or_off("bump", words.next())?),
+            Some("stale") => Command::SetStale(parse_stale_setting(words)?),
+            Some("button") => Command::SetButton(parse_button_setting(words)?),
+            Some("startup") => Command::SetStartup(parse_startup_setting(words)?),
+            Some("lowpower") => Command::SetLowPower(parse_low_power_setting(words)?),
+            Some("idle") => parse_idle_setting(words)?,
+            Some("schedule") => Command::SetSchedule(parse_schedule_setting(words)?),
+            Some("language") => {
+                let arg = words
+                    .next()
+                    .ok_or(ParseError::MissingArgument("set language"))?;
+                let language = Language::from_code(arg)
+                    .ok_or_else(|| ParseError::InvalidArgument("set language", arg.to_string()))?;
+                Command::SetLanguage(language)
+            }
+            Some("fps") => {
+                let arg = words.next().ok_or(ParseError::MissingArgument("set fps"))?;
+                let fps = arg
+                    .parse()
+                    .ok()
+                    .filter(|fps| (1..=MAX_FPS).contains(fps))
+                    .ok_or_else(|| ParseError::InvalidArgument("set fps", arg.to_string()))?;
+                Command::SetMaxFps(fps)
+            }
+            Some("flash") => Command::SetFlash(parse_flash_setting(words)?),
+            Some("mirror") => Command::SetMirror(parse_mirror_setting(words)?),
+            Some("lock") => Command::SetLock(parse_lock_setting(words)?),
+            Some("modbus") => Command::SetModbus(parse_modbus_setting(words)?),
+            Some("espnow") => Command::SetEspNow(parse_espnow_setting(words)?),
+            Some("autohold") => Command::SetAutoHold(parse_auto_hold_setting(words)?),
+            Some("input") => Command::SetInput(parse_input_setting(words)?),
+            Some("negative") => Command::SetNegative(parse_negative_setting(words)?),
+            Some("noise") => Command::SetNoise(parse_noise_setting(words)?),
+            Some("panic") => {
+                let arg = words
+                    .next()
+                    .ok_or(ParseError::MissingArgument("set panic"))?;
+                let secs = arg
+                    .parse()
+                    .ok()
+                    .filter(|secs| *secs <= MAX_PANIC_HOLD_S)
+                    .ok_or_else(|| ParseError::InvalidArgument("set panic", arg.to_string()))?;
+                Command::SetPanicHold(secs)
+            }
+            Some("tarecooldown") => {
+                let arg = words
+                    .next()
+                    .ok_or(ParseError::MissingArgument("set tarecooldown"))?;
+                let cooldown = arg
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|secs| (0.0..=MAX_TARE_COOLDOWN.as_secs_f32()).contains(secs))
+                    .map(Duration::from_secs_f32)
+                    .ok_or_else(|| {
+                        ParseError::InvalidArgument("set tarecooldown", arg.to_string())
+                    })?;
+                Command::SetTareCooldown(cooldown)
+            }
+            Some("target") => Command::SetTarget(parse_positive_or_off("target", words.next())?),
+            Some("clock") => Command::SetClock(parse_clock_setting(words)?),
+            Some("alarm") => Command::SetAlarm(parse_alarm_setting(words)?),
+            Some("calreminder") => Command::SetCalReminder(parse_cal_reminder_setting(words)?),
+            Some("capacity") => {
+                Command::SetCapacity(parse_positive_or_off("capacity", words.next())?)
+            }
+            Some("led") => Command::SetLed(parse_led_setting(words)?),
+            Some("brew") => Command::SetBrew(parse_brew_setting(words)?),
+            Some("recipe") => Command::SetRecipe(parse_recipe_setting(words)?),
+            Some("dispense") => Command::SetDispense(parse_dispense_setting(words)?),
+            Some("update") => match words.next().map(str::to_ascii_lowercase).as_deref() {
+                Some("token") => match parse_word("update token", words.next())? {
+                    token if token.eq_ignore_ascii_case("off") => {
+                        Command::SetUpdateToken(String::new())
+                    }
+                    token => Command::SetUpdateToken(token),
+                },
+                Some(setting) => {
+                    return Err(ParseError::UnknownCommand(format!(
+                        "set update {}",
+                        setting
+                    )))
+                }
+                None => return Err(ParseError::MissingArgument("set update")),
+            },
+            Some("pin") => {
+                let name = words.next().ok_or(ParseError::MissingArgument("set pin"))?;
+                let pin = BoardPin::from_name(&name.to_ascii_lowercase())
+                    .ok_or_else(|| ParseError::InvalidArgument("set pin", name.to_string()))?;
+                let arg = words.next().ok_or(ParseError::MissingArgument("set pin"))?;
+                let gpio = arg
+                    .parse()
+                    .ok()
+                    .filter(|gpio| *gpio <= 39)
+                    .ok_or_else(|| ParseError::InvalidArgument("set pin", arg.to_string()))?;
+                Command::SetPin(pin, gpio)
+            }
+            Some(setting) => return Err(ParseError::UnknownCommand(format!("set {}", setting))),
+            None => return Err(ParseError::MissingArgument("set")),
+        },
+        _ => return Err(ParseError::UnknownCommand(name.to_string())),
+    };
+
+    Ok(Some(command))
+}
+
+/// Take a line of a trace being pasted. Returns false once the empty line
+/// ends it.
+fn load_trace_line(points: &mut Vec<TracePoint>, line: &str) -> bool {
+    if line.trim().is_empty() {
+        return false;
+    }
+    // The header, or anything else pasted along, is skipped, and the points
+    // past the bound
+    if let Some(point) = TracePoint::from_csv(line) {
+        if points.len() < MAX_TRACE_POINTS {
+            points.push(point);
+        }
+    }
+    true
+}
+
+/// Start a task reading commands from the serial console. Parsed commands
+/// are handed to the main loop through the channel, so the scale is never
+/// accessed from two threads.
+pub fn start_console_task(commands: CommandSender) {
+    std::thread::spawn(move || console_task(commands));
+}
+
+fn console_task(commands: CommandSender) {
+    let mut stdin = stdin().lock();
+    // The history is kept off the small stack of the task
+    let mut editor = Box::new(LineEditor::new(COMMAND_NAMES));
+    let mut bytes = [0; CONSOLE_READ_LEN];
+    // Points of a trace being pasted
+    let mut loading: Option<Vec<TracePoint>> = None;
+    loop {
+        // stdin is non-blocking on the esp-idf console, the bytes are taken
+        // as they come and echoed as they are edited
+        let count = match stdin.read(&mut bytes) {
+            Ok(count) if count > 0 => count,
+            _ => {
+                std::thread::sleep(CONSOLE_POLL_PERIOD);
+                continue;
+            }
+        };
+        let mut out = stdout().lock();
+        for &byte in &bytes[..count] {
+            let line = match editor.feed(byte, &mut out) {
+                Ok(Some(line)) => line,
+                Ok(None) => continue,
+                Err(err) => {
+                    error!("Console echo failed: {}", err);
+                    continue;
+                }
+            };
+            let command = match loading.take() {
+                Some(mut points) => {
+                    if load_trace_line(&mut points, line) {
+                        loading = Some(points);
+                        continue;
+                    }
+                    Ok(Some(Command::Trace(TraceCommand::Loaded(points))))
+                }
+                None => parse_command(line),
+            };
+            match command {
+                Ok(Some(Command::Trace(TraceCommand::Load))) => {
+                    println!("Paste the trace, then an empty line");
+                    loading = Some(Vec::new());
+                }
+                Ok(Some(command)) => {
+                    if commands.send(command).is_err() {
+                        error!("Console command receiver dropped");
+                        return;
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => println!("{}\n{}", err, USAGE),
+            }
+        }
+        // The echo goes out without waiting for a newline
+        let _ = out.flush();
+    }
+}
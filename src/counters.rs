@@ -0,0 +1,317 @@
+//! Lifetime counters, for the maintenance of a fleet of scales: how often
+//! each one was tared, calibrated, booted, pressed, had its sensor come
+//! back and found a stored blob corrupt. They only ever go up, and live in NVS across restarts and updates.
+//!
+//! A button press is far too frequent to write the flash on, so the
+//! increments add up in memory and are committed at most once a minute, and
+//! before a restart or a deep sleep. Only the increments of the last minute
+//! are lost to a brown-out.
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use log::warn;
+use thiserror::Error;
+
+use crate::{
+    shutdown,
+    storage::{Storage, StorageService, StoreError},
+};
+
+const COUNTERS_NAMESPACE: &str = "counters";
+/// Time the increments add up for before they are written
+const COMMIT_INTERVAL: Duration = Duration::from_secs(60);
+const COMMIT_TASK_STACK_SIZE: usize = 3 * 1024;
+
+const COUNTERS: usize = Counter::ALL.len();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    /// Tares taken with the button, the console or remotely, the soft tares
+    /// left out
+    Tares,
+    Calibrations,
+    Boots,
+    /// Every press of the button, before it is told apart as a gesture
+    ButtonPresses,
+    /// The sensor reading again after going silent
+    SensorRecoveries,
+    /// Blobs in NVS failing their length or CRC, read as missing
+    CorruptBlobs,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 6] = [
+        Counter::Tares,
+        Counter::Calibrations,
+        Counter::Boots,
+        Counter::ButtonPresses,
+        Counter::SensorRecoveries,
+        Counter::CorruptBlobs,
+    ];
+
+    /// Name on the console and in the JSON, also the key in NVS
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Tares => "tares",
+            Counter::Calibrations => "calibrations",
+            Counter::Boots => "boots",
+            Counter::ButtonPresses => "presses",
+            Counter::SensorRecoveries => "recoveries",
+            Counter::CorruptBlobs => "corrupt",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|counter| counter.name().eq_ignore_ascii_case(name))
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Count {
+    /// Total as last written
+    committed: u32,
+    /// Increments since
+    pending: u32,
+}
+
+impl Count {
+    const ZERO: Count = Count {
+        committed: 0,
+        pending: 0,
+    };
+
+    fn total(&self) -> u32 {
+        self.committed.saturating_add(self.pending)
+    }
+}
+
+static COUNTS: Mutex<[Count; COUNTERS]> = Mutex::new([Count::ZERO; COUNTERS]);
+/// Held through a whole commit, so two never write the same total
+static COMMITTING: Mutex<()> = Mutex::new(());
+
+fn counts() -> std::sync::MutexGuard<'static, [Count; COUNTERS]> {
+    COUNTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Count one more, written with the next commit
+pub fn increment(counter: Counter) {
+    let mut counts = counts();
+    let count = &mut counts[counter.index()];
+    count.pending = count.pending.saturating_add(1);
+}
+
+/// Total so far, the increments not written yet included
+pub fn value(counter: Counter) -> u32 {
+    counts()[counter.index()].total()
+}
+
+pub fn values() -> [(Counter, u32); COUNTERS] {
+    let counts = counts();
+    Counter::ALL.map(|counter| (counter, counts[counter.index()].total()))
+}
+
+/// Take the total read back at startup, the increments made before still
+/// count on top of it
+pub fn load(counter: Counter, total: u32) {
+    counts()[counter.index()].committed = total;
+}
+
+/// Write the totals with increments pending through `write`, which returns
+/// whether it succeeded. Increments made during the write wait for the next
+/// commit, and those of a total that failed to write stay pending. Returns
+/// the number of totals written.
+pub fn commit_with(mut write: impl FnMut(Counter, u32) -> bool) -> usize {
+    let _committing = COMMITTING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let taken = counts().map(|count| count.pending);
+    let mut written = 0;
+    for counter in Counter::ALL {
+        let pending = taken[counter.index()];
+        if pending == 0 {
+            continue;
+        }
+        let total = counts()[counter.index()].committed.saturating_add(pending);
+        if !write(counter, total) {
+            continue;
+        }
+        let mut counts = counts();
+        let count = &mut counts[counter.index()];
+        count.committed = total;
+        count.pending -= pending;
+        written += 1;
+    }
+    written
+}
+
+/// Start the counter from zero again, writing it right away through
+/// `write`. Returns whether it was written.
+pub fn reset_with(counter: Counter, write: impl FnOnce(Counter, u32) -> bool) -> bool {
+    let _committing = COMMITTING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    counts()[counter.index()] = Count::ZERO;
+    write(counter, 0)
+}
+
+static STORAGE: OnceLock<Storage> = OnceLock::new();
+
+#[derive(Error, Debug)]
+pub enum CountersError {
+    #[error("Failed to open the counters storage: {0}")]
+    Storage(StoreError),
+    #[error("Failed to start the counters task: {0}")]
+    Task(std::io::Error),
+}
+
+/// Read the totals back, count this boot and start committing the
+/// increments every minute and before a restart
+pub fn start(storage_service: &StorageService) -> Result<(), CountersError> {
+    let storage = storage_service
+        .open(COUNTERS_NAMESPACE)
+        .map_err(CountersError::Storage)?;
+    for counter in Counter::ALL {
+        if let Some(total) = storage.get_u32(counter.name()) {
+            load(counter, total);
+        }
+    }
+    increment(Counter::Boots);
+    if STORAGE.set(storage).is_err() {
+        return Ok(());
+    }
+    // Registered after the storage, whose flush already ran by then
+    shutdown::register("counters", || {
+        commit();
+        if let Some(Err(err)) = STORAGE.get().map(Storage::flush) {
+            warn!("Failed to write the counters: {:?}", err);
+        }
+    });
+    std::thread::Builder::new()
+        .name("counters".to_string())
+        .stack_size(COMMIT_TASK_STACK_SIZE)
+        .spawn(|| loop {
+            std::thread::sleep(COMMIT_INTERVAL);
+            commit();
+        })
+        .map_err(CountersError::Task)?;
+    Ok(())
+}
+
+/// Write the totals with increments pending, to the write cache of the
+/// storage
+pub fn commit() {
+    let Some(storage) = STORAGE.get() else {
+        return;
+    };
+    commit_with(|counter, total| write(storage, counter, total));
+}
+
+/// Start the counter from zero again. Returns whether it was written.
+pub fn reset(counter: Counter) -> bool {
+    let Some(storage) = STORAGE.get() else {
+        return false;
+    };
+    reset_with(counter, |counter, total| write(storage, counter, total))
+}
+
+fn write(storage: &Storage, counter: Counter, total: u32) -> bool {
+    match storage.set_u32(counter.name(), total) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("Failed to write the {} counter: {:?}", counter.name(), err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The counts are global, the tests take turns with them
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn fresh() -> std::sync::MutexGuard<'static, ()> {
+        let serial = SERIAL
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *counts() = [Count::ZERO; COUNTERS];
+        serial
+    }
+
+    #[test]
+    fn increments_are_batched() {
+        let _serial = fresh();
+        load(Counter::Tares, 40);
+        for _ in 0..5 {
+            increment(Counter::Tares);
+        }
+        increment(Counter::Boots);
+        assert_eq!(value(Counter::Tares), 45);
+
+        let mut writes = Vec::new();
+        let written = commit_with(|counter, total| {
+            writes.push((counter, total));
+            true
+        });
+        assert_eq!(written, 2);
+        assert_eq!(writes, [(Counter::Tares, 45), (Counter::Boots, 1)]);
+
+        // Nothing pending, nothing written
+        assert_eq!(commit_with(|_, _| panic!("nothing to write")), 0);
+        assert_eq!(value(Counter::Tares), 45);
+    }
+
+    #[test]
+    fn none_are_lost_across_a_commit() {
+        let _serial = fresh();
+        increment(Counter::ButtonPresses);
+        increment(Counter::ButtonPresses);
+        increment(Counter::Calibrations);
+
+        // Presses during the write and a total failing to write stay pending
+        let written = commit_with(|counter, total| match counter {
+            Counter::ButtonPresses => {
+                assert_eq!(total, 2);
+                increment(Counter::ButtonPresses);
+                true
+            }
+            _ => false,
+        });
+        assert_eq!(written, 1);
+        assert_eq!(value(Counter::ButtonPresses), 3);
+        assert_eq!(value(Counter::Calibrations), 1);
+
+        let mut writes = Vec::new();
+        commit_with(|counter, total| {
+            writes.push((counter, total));
+            true
+        });
+        assert_eq!(
+            writes,
+            [(Counter::Calibrations, 1), (Counter::ButtonPresses, 3)]
+        );
+        assert_eq!(commit_with(|_, _| panic!("nothing to write")), 0);
+    }
+
+    #[test]
+    fn reset_starts_from_zero() {
+        let _serial = fresh();
+        load(Counter::CorruptBlobs, 7);
+        increment(Counter::CorruptBlobs);
+        assert!(reset_with(Counter::CorruptBlobs, |_, total| total == 0));
+        assert_eq!(value(Counter::CorruptBlobs), 0);
+        assert_eq!(commit_with(|_, _| panic!("nothing to write")), 0);
+    }
+}
@@ -0,0 +1,281 @@
+//! Creep of the load cell: under a load placed at once, a cheap cell keeps
+//! reading a little more over the next minutes, and a little less once the
+//! load is taken off. The creep is modeled as a first order response to the
+//! load, approaching a share of it with a time constant, and the predicted
+//! creep is taken off the weight.
+//!
+//! The two figures come from a trace: `creep measure` records the weight for
+//! two minutes after the next step load settled, and fits the share from the
+//! rise over the trace and the time constant from the time it took to reach
+//! 63% of it.
+
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+/// Longest time constant taken
+pub const MAX_CREEP_TIME_CONSTANT_S: f32 = 600.0;
+/// Largest share of the load taken, in percent
+pub const MAX_CREEP_PERCENT: f32 = 5.0;
+/// Time the trace is recorded for after the step settled
+pub const CREEP_TRACE_TIME: Duration = Duration::from_secs(120);
+/// Period of the points of the trace
+const CREEP_TRACE_PERIOD: Duration = Duration::from_secs(1);
+/// Change of the weight that counts as the step
+const MIN_CREEP_STEP_GRAMS: f32 = 50.0;
+/// Time a step is waited for before the measurement is given up
+const CREEP_STEP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Points averaged into the weight at the end of the trace
+const END_POINTS: usize = 5;
+/// Share of the rise of a first order response after one time constant
+const ONE_TIME_CONSTANT: f32 = 0.632;
+
+const ENCODED_LEN: usize = 8;
+
+/// Creep approaching `fraction` of the load with the time constant
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CreepModel {
+    pub time_constant: Duration,
+    pub fraction: f32,
+}
+
+impl CreepModel {
+    pub fn describe(&self) -> String {
+        format!(
+            "time constant {:.0}s, {:.3}% of the load",
+            self.time_constant.as_secs_f32(),
+            self.fraction * 100.0
+        )
+    }
+}
+
+impl crate::storage::Stored for CreepModel {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        bytes.extend_from_slice(&self.time_constant.as_secs_f32().to_le_bytes());
+        bytes.extend_from_slice(&self.fraction.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; ENCODED_LEN] = bytes.try_into().ok()?;
+        let f32_at = |at: usize| bytes[at..at + 4].try_into().ok().map(f32::from_le_bytes);
+        let secs = f32_at(0)?;
+        let fraction = f32_at(4)?;
+        let valid = (0.0..=MAX_CREEP_TIME_CONSTANT_S).contains(&secs)
+            && (0.0..=MAX_CREEP_PERCENT / 100.0).contains(&fraction.abs());
+        valid.then(|| CreepModel {
+            time_constant: Duration::from_secs_f32(secs),
+            fraction,
+        })
+    }
+}
+
+/// Predicts the creep of the weight read so far
+#[derive(Debug, Default)]
+pub struct CreepCompensator {
+    model: Option<CreepModel>,
+    /// Creep predicted at the last sample, along with its time
+    creep: Option<(f32, Instant)>,
+}
+
+impl CreepCompensator {
+    pub fn new(model: Option<CreepModel>) -> Self {
+        Self { model, creep: None }
+    }
+
+    pub fn model(&self) -> Option<CreepModel> {
+        self.model
+    }
+
+    /// Compensate with another model, none to stop
+    pub fn configure(&mut self, model: Option<CreepModel>) {
+        self.model = model;
+        self.reset();
+    }
+
+    /// Forget the creep predicted, e.g. after a tare: the weight on the
+    /// scale then counts as having settled
+    pub fn reset(&mut self) {
+        self.creep = None;
+    }
+
+    /// Creep in grams to take off `gross`, the weight above the zero read
+    /// now. Nothing without a model.
+    pub fn on_sample(&mut self, gross: f32, now: Instant) -> f32 {
+        let Some(model) = self.model else {
+            return 0.0;
+        };
+        let (creep, at) = self.creep.unwrap_or((0.0, now));
+        // The load is the weight without its creep
+        let target = (gross - creep) * model.fraction;
+        let elapsed = now.saturating_duration_since(at).as_secs_f32();
+        let decay = if model.time_constant.is_zero() {
+            0.0
+        } else {
+            (-elapsed / model.time_constant.as_secs_f32()).exp()
+        };
+        let creep = target + (creep - target) * decay;
+        self.creep = Some((creep, now));
+        creep
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreepError {
+    #[error("No step load placed")]
+    NoStep,
+    #[error("The weight did not creep the way of the load")]
+    NoCreep,
+    #[error("Cancelled by a tare")]
+    Cancelled,
+}
+
+/// Weight read some time after the step settled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CreepPoint {
+    pub secs: f32,
+    pub grams: f32,
+}
+
+/// Trace of a measurement along with the model fitted to it
+#[derive(Clone, Debug, PartialEq)]
+pub struct CreepReport {
+    pub step_grams: f32,
+    pub points: Vec<CreepPoint>,
+    pub model: CreepModel,
+}
+
+impl CreepReport {
+    /// Lines of the trace in CSV, with the creep since the step settled
+    pub fn table(&self) -> Vec<String> {
+        let start = self.points.first().map_or(0.0, |point| point.grams);
+        self.points
+            .iter()
+            .map(|point| {
+                format!(
+                    "{:.0},{:.2},{:.2}",
+                    point.secs,
+                    point.grams,
+                    point.grams - start
+                )
+            })
+            .collect()
+    }
+}
+
+/// Header of the table `CreepReport::table` prints
+pub const CREEP_TABLE_HEADER: &str = "secs,grams,creep_grams";
+
+#[derive(Debug)]
+enum TraceState {
+    /// Waiting for the weight before the step to settle
+    Settling,
+    /// Waiting for a step away from the settled weight
+    WaitingStep { baseline: f32 },
+    Recording {
+        baseline: f32,
+        started: Instant,
+        points: Vec<CreepPoint>,
+    },
+}
+
+/// Records the weight after the next step load, the weight before the
+/// creep compensation
+#[derive(Debug)]
+pub struct CreepTrace {
+    state: TraceState,
+    started: Instant,
+}
+
+impl CreepTrace {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            state: TraceState::Settling,
+            started: now,
+        }
+    }
+
+    /// Whether the step was placed and the trace is being recorded
+    pub fn is_recording(&self) -> bool {
+        matches!(self.state, TraceState::Recording { .. })
+    }
+
+    /// Follow the weight above the zero, returning the outcome once the
+    /// trace is over
+    pub fn on_sample(
+        &mut self,
+        gross: f32,
+        stable: bool,
+        now: Instant,
+    ) -> Option<Result<CreepReport, CreepError>> {
+        match &mut self.state {
+            TraceState::Settling | TraceState::WaitingStep { .. }
+                if now.duration_since(self.started) >= CREEP_STEP_TIMEOUT =>
+            {
+                return Some(Err(CreepError::NoStep));
+            }
+            TraceState::Settling if stable => {
+                self.state = TraceState::WaitingStep { baseline: gross };
+            }
+            TraceState::WaitingStep { baseline }
+                if stable && (gross - *baseline).abs() >= MIN_CREEP_STEP_GRAMS =>
+            {
+                self.state = TraceState::Recording {
+                    baseline: *baseline,
+                    started: now,
+                    points: vec![CreepPoint {
+                        secs: 0.0,
+                        grams: gross,
+                    }],
+                };
+            }
+            TraceState::Recording {
+                baseline,
+                started,
+                points,
+            } => {
+                let elapsed = now.duration_since(*started);
+                let next = CREEP_TRACE_PERIOD * points.len() as u32;
+                if elapsed >= next {
+                    points.push(CreepPoint {
+                        secs: elapsed.as_secs_f32(),
+                        grams: gross,
+                    });
+                }
+                if elapsed >= CREEP_TRACE_TIME {
+                    return Some(fit(*baseline, std::mem::take(points)));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Share of the step the weight rose by over the trace, and time it took
+/// to rise by 63% of that
+fn fit(baseline: f32, points: Vec<CreepPoint>) -> Result<CreepReport, CreepError> {
+    let start = points.first().ok_or(CreepError::NoStep)?.grams;
+    let step_grams = start - baseline;
+    let tail = &points[points.len().saturating_sub(END_POINTS)..];
+    let end = tail.iter().map(|point| point.grams).sum::<f32>() / tail.len() as f32;
+    let rise = end - start;
+    // Creep goes the way of the load
+    if rise * step_grams <= 0.0 {
+        return Err(CreepError::NoCreep);
+    }
+    let fraction = (rise / step_grams).min(MAX_CREEP_PERCENT / 100.0);
+    let secs = points
+        .iter()
+        .find(|point| (point.grams - start) / rise >= ONE_TIME_CONSTANT)
+        .map_or(0.0, |point| point.secs);
+    Ok(CreepReport {
+        step_grams,
+        points,
+        model: CreepModel {
+            time_constant: Duration::from_secs_f32(secs),
+            fraction,
+        },
+    })
+}
@@ -0,0 +1,476 @@
+//! Weight log kept on a dedicated NVS partition, for unattended logging
+//! without a network.
+//!
+//! Records are grouped into chunks of `RECORDS_PER_CHUNK`, each chunk stored
+//! as one blob. The chunks form a ring sized by the retention setting: once
+//! it is full, the oldest chunk is dropped to make room for a new one.
+//!
+//! Flash wear: the chunk being filled is kept in RAM and written out every
+//! `FLUSH_EVERY` records, so a power loss costs fewer than that many records.
+//! Each flush rewrites the chunk (up to 480 bytes) plus the small metadata
+//! blob, about 600 bytes with the NVS entry overhead. At the default 10 min
+//! interval that is roughly 14KiB a day. NVS spreads the writes over all
+//! pages of the 128KiB partition, so each flash sector is erased about once
+//! every 9 days, some 4000 cycles over a century against the 100k the flash
+//! is rated for.
+//!
+//! The hourly and daily aggregates of `crate::history` are kept up to date
+//! as the records are appended.
+
+#[cfg(feature = "sdcard")]
+pub mod sdcard;
+
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use esp_idf_svc::nvs::{EspCustomNvsPartition, EspNvs, NvsCustom};
+use esp_idf_sys::EspError;
+use log::{info, warn};
+
+use crate::{
+    filter::Sample,
+    history::{Bin, Granularity, History},
+    quality::Quality,
+    settings::Settings,
+    shutdown,
+    sink::{OutputSink, SinkError, SinkResult},
+    time::{self, Timestamp},
+};
+
+/// Name of the log among the sinks
+pub const DATALOG_SINK: &str = "datalog";
+/// Name of the NVS partition in `partitions.csv`
+pub const DATALOG_PARTITION: &str = "datalog";
+const DATALOG_NAMESPACE: &str = "datalog";
+const META_KEY: &str = "meta";
+const META_VERSION: u8 = 1;
+const META_LEN: usize = 6;
+
+/// Header of the CSV produced by `Record::to_csv`
+pub const DATALOG_CSV_HEADER: &str = "time,grams,stable,quality,tag";
+
+pub const RECORDS_PER_CHUNK: usize = 48;
+/// Records collected in RAM before they are written to flash
+pub const FLUSH_EVERY: usize = 6;
+/// Most records the partition holds with room to spare for NVS
+pub const MAX_RETENTION: u32 = 8064;
+const RECORD_LEN: usize = 10;
+
+const FLAG_STABLE: u8 = 1 << 0;
+/// The time is seconds since the epoch rather than since boot
+const FLAG_WALL_CLOCK: u8 = 1 << 1;
+/// Logged by a soak test rather than at the logging interval
+const FLAG_SOAK: u8 = 1 << 2;
+
+/// Single logged weight
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Seconds since the epoch once the clock is synchronized, since boot
+    /// before that
+    pub seconds: u32,
+    pub milligrams: i32,
+    pub stable: bool,
+    pub wall_clock: bool,
+    /// Quality of the weight when it was logged
+    pub quality: Quality,
+    /// Logged by a soak test
+    pub soak: bool,
+}
+
+impl Record {
+    /// Record the weight with the current time
+    pub fn now(grams: f32, stable: bool, quality: Quality) -> Self {
+        let (seconds, wall_clock) = match Timestamp::now() {
+            Timestamp::Uptime(uptime) => (uptime.as_secs(), false),
+            Timestamp::WallClock { since_epoch, .. } => (since_epoch.as_secs(), true),
+        };
+        Self {
+            seconds: seconds.try_into().unwrap_or(u32::MAX),
+            milligrams: (grams * 1000.0).round() as i32,
+            stable,
+            wall_clock,
+            quality,
+            soak: false,
+        }
+    }
+
+    /// Tag the record as logged by a soak test
+    pub fn soak(self) -> Self {
+        Self { soak: true, ..self }
+    }
+
+    pub fn grams(&self) -> f32 {
+        self.milligrams as f32 / 1000.0
+    }
+
+    pub fn timestamp(&self) -> Timestamp {
+        let time = Duration::from_secs(self.seconds.into());
+        if self.wall_clock {
+            Timestamp::WallClock {
+                since_epoch: time,
+                offset_minutes: 0,
+            }
+        } else {
+            Timestamp::Uptime(time)
+        }
+    }
+
+    /// CSV line matching `DATALOG_CSV_HEADER`, without the line break
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{:.3},{},{},{}",
+            self.timestamp(),
+            self.grams(),
+            u8::from(self.stable),
+            self.quality.name(),
+            if self.soak { "soak" } else { "" }
+        )
+    }
+
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.stable {
+            flags |= FLAG_STABLE;
+        }
+        if self.wall_clock {
+            flags |= FLAG_WALL_CLOCK;
+        }
+        if self.soak {
+            flags |= FLAG_SOAK;
+        }
+        bytes.extend_from_slice(&self.seconds.to_le_bytes());
+        bytes.extend_from_slice(&self.milligrams.to_le_bytes());
+        bytes.push(flags);
+        // 0 for the records logged before the quality was
+        bytes.push(self.quality.index() + 1);
+    }
+
+    fn decode(bytes: &[u8; RECORD_LEN]) -> Self {
+        let flags = bytes[8];
+        let stable = flags & FLAG_STABLE != 0;
+        let quality = bytes[9]
+            .checked_sub(1)
+            .and_then(Quality::from_index)
+            .unwrap_or(if stable {
+                Quality::Good
+            } else {
+                Quality::Settling
+            });
+        Self {
+            seconds: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            milligrams: i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            stable,
+            wall_clock: flags & FLAG_WALL_CLOCK != 0,
+            quality,
+            soak: flags & FLAG_SOAK != 0,
+        }
+    }
+}
+
+fn chunk_key(chunk: u16) -> String {
+    format!("c{}", chunk)
+}
+
+/// The ring of chunks in the partition
+pub struct DataLog {
+    nvs: EspNvs<NvsCustom>,
+    chunk_count: u16,
+    /// Chunk being filled
+    head: u16,
+    /// Whether the ring went around, so all other chunks are full
+    wrapped: bool,
+    /// Content of the head chunk, including the records not yet flushed
+    head_records: Vec<Record>,
+    unflushed: usize,
+    history: History<EspNvs<NvsCustom>>,
+}
+
+impl DataLog {
+    /// Open the log, keeping at least `retention` records. Changing the
+    /// retention starts a new log.
+    pub fn open(partition: EspCustomNvsPartition, retention: u32) -> Result<Self, EspError> {
+        let retention = retention.clamp(RECORDS_PER_CHUNK as u32, MAX_RETENTION) as usize;
+        // The head chunk is only partially filled, so keep one chunk more
+        let chunk_count = (retention.div_ceil(RECORDS_PER_CHUNK) + 1) as u16;
+
+        let mut log = Self {
+            nvs: EspNvs::new(partition.clone(), DATALOG_NAMESPACE, true)?,
+            chunk_count,
+            head: 0,
+            wrapped: false,
+            head_records: Vec::with_capacity(RECORDS_PER_CHUNK),
+            unflushed: 0,
+            history: History::open(partition)?,
+        };
+
+        let mut meta = [0u8; META_LEN];
+        match log.nvs.get_blob(META_KEY, &mut meta)? {
+            Some(&[META_VERSION, count_lo, count_hi, head_lo, head_hi, wrapped])
+                if u16::from_le_bytes([count_lo, count_hi]) == chunk_count =>
+            {
+                log.head = u16::from_le_bytes([head_lo, head_hi]) % chunk_count;
+                log.wrapped = wrapped != 0;
+                log.head_records = log.read_chunk(log.head)?;
+            }
+            Some(_) => {
+                // The aggregates do not depend on the layout and stay
+                warn!("Data log layout changed, starting a new log");
+                log.clear_records()?;
+            }
+            None => log.write_meta()?,
+        }
+        Ok(log)
+    }
+
+    /// Add a record, writing to flash every `FLUSH_EVERY` records
+    pub fn append(&mut self, record: Record) -> Result<(), EspError> {
+        if self.head_records.len() >= RECORDS_PER_CHUNK {
+            self.advance()?;
+        }
+        self.head_records.push(record);
+        self.unflushed += 1;
+        // The record was taken right before
+        self.history
+            .add(record.timestamp(), record.milligrams, time::uptime())?;
+        if self.unflushed >= FLUSH_EVERY || self.head_records.len() >= RECORDS_PER_CHUNK {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the records collected in RAM, and the bins they went to
+    pub fn flush(&mut self) -> Result<(), EspError> {
+        self.history.flush()?;
+        if self.unflushed == 0 {
+            return Ok(());
+        }
+        let mut bytes = Vec::with_capacity(self.head_records.len() * RECORD_LEN);
+        for record in &self.head_records {
+            record.encode(&mut bytes);
+        }
+        self.nvs.set_blob(&chunk_key(self.head), &bytes)?;
+        self.unflushed = 0;
+        Ok(())
+    }
+
+    /// Move on from the full head chunk to the next one, which is the oldest
+    /// once the ring is full. The head only moves once its chunk is on
+    /// flash, so a power loss never leaves the metadata pointing past it.
+    fn advance(&mut self) -> Result<(), EspError> {
+        self.head = (self.head + 1) % self.chunk_count;
+        if self.head == 0 {
+            self.wrapped = true;
+        }
+        self.nvs.remove(&chunk_key(self.head))?;
+        self.head_records.clear();
+        self.write_meta()
+    }
+
+    /// Drop all records, and the aggregates of them
+    pub fn clear(&mut self) -> Result<(), EspError> {
+        self.history.clear()?;
+        self.clear_records()
+    }
+
+    fn clear_records(&mut self) -> Result<(), EspError> {
+        for chunk in 0..self.chunk_count {
+            self.nvs.remove(&chunk_key(chunk))?;
+        }
+        self.head = 0;
+        self.wrapped = false;
+        self.head_records.clear();
+        self.unflushed = 0;
+        self.write_meta()
+    }
+
+    pub fn len(&self) -> usize {
+        let full_chunks = if self.wrapped {
+            self.chunk_count as usize - 1
+        } else {
+            self.head as usize
+        };
+        full_chunks * RECORDS_PER_CHUNK + self.head_records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Chunks from the oldest to the head
+    fn chunk_order(&self) -> Vec<u16> {
+        let oldest = if self.wrapped {
+            (self.head + 1) % self.chunk_count
+        } else {
+            0
+        };
+        let mut chunks = Vec::new();
+        let mut chunk = oldest;
+        while chunk != self.head {
+            chunks.push(chunk);
+            chunk = (chunk + 1) % self.chunk_count;
+        }
+        chunks.push(self.head);
+        chunks
+    }
+
+    fn read_chunk(&self, chunk: u16) -> Result<Vec<Record>, EspError> {
+        if chunk == self.head && !self.head_records.is_empty() {
+            return Ok(self.head_records.clone());
+        }
+        let mut buf = [0u8; RECORDS_PER_CHUNK * RECORD_LEN];
+        let bytes = self
+            .nvs
+            .get_blob(&chunk_key(chunk), &mut buf)?
+            .unwrap_or(&[]);
+        Ok(bytes
+            .chunks_exact(RECORD_LEN)
+            .filter_map(|record| record.try_into().ok())
+            .map(Record::decode)
+            .collect())
+    }
+
+    fn write_meta(&mut self) -> Result<(), EspError> {
+        let count = self.chunk_count.to_le_bytes();
+        let head = self.head.to_le_bytes();
+        let meta = [
+            META_VERSION,
+            count[0],
+            count[1],
+            head[0],
+            head[1],
+            u8::from(self.wrapped),
+        ];
+        self.nvs.set_blob(META_KEY, &meta)
+    }
+}
+
+/// Handle to the log shared by its sink, the console and the HTTP API
+#[derive(Clone)]
+pub struct DataLogHandle {
+    log: Arc<Mutex<DataLog>>,
+    interval: Duration,
+}
+
+impl DataLogHandle {
+    fn lock(&self) -> MutexGuard<'_, DataLog> {
+        self.log
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Iterate the records from the oldest on. The log is read one chunk at a
+    /// time, so it keeps being written while the records are consumed.
+    pub fn iter_records(&self) -> Records {
+        Records {
+            chunks: self.lock().chunk_order().into_iter(),
+            log: self.clone(),
+            records: Vec::new().into_iter(),
+        }
+    }
+
+    /// Log a record out of the interval, e.g. a point of a soak test
+    pub fn append(&self, record: Record) -> Result<(), EspError> {
+        self.lock().append(record)
+    }
+
+    /// Write the records still held in RAM, e.g. before powering down
+    pub fn flush(&self) -> Result<(), EspError> {
+        self.lock().flush()
+    }
+
+    /// The bins of the last `count` hours or days, oldest first
+    pub fn history(&self, granularity: Granularity, count: u32) -> Result<Vec<Bin>, EspError> {
+        let now = match Timestamp::now() {
+            Timestamp::WallClock { since_epoch, .. } => since_epoch.as_secs().try_into().ok(),
+            Timestamp::Uptime(_) => None,
+        };
+        self.lock().history.bins(granularity, count, now)
+    }
+
+    pub fn clear_log(&self) -> Result<(), EspError> {
+        self.lock().clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Time between the records logged by the sink
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// Logs the samples the sink is offered, one per interval
+impl OutputSink for DataLogHandle {
+    fn name(&self) -> &'static str {
+        DATALOG_SINK
+    }
+
+    fn offer(&mut self, sample: &Sample) -> SinkResult {
+        let record = Record::now(sample.grams_filtered, sample.stable, sample.quality);
+        self.append(record)
+            .map_err(|err| SinkError::Failed(format!("{:?}", err)))
+    }
+
+    fn flush(&mut self) -> SinkResult {
+        DataLogHandle::flush(self).map_err(|err| SinkError::Failed(format!("{:?}", err)))
+    }
+}
+
+/// Records of the log, oldest first
+pub struct Records {
+    log: DataLogHandle,
+    chunks: std::vec::IntoIter<u16>,
+    records: std::vec::IntoIter<Record>,
+}
+
+impl Iterator for Records {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Some(record);
+            }
+            let chunk = self.chunks.next()?;
+            match self.log.lock().read_chunk(chunk) {
+                Ok(records) => self.records = records.into_iter(),
+                Err(err) => warn!("Failed to read data log chunk {}: {:?}", chunk, err),
+            }
+        }
+    }
+}
+
+/// Open the log for its sink to write the weight at the configured
+/// interval. Returns `None` while logging is disabled.
+pub fn open_datalog(settings: &Settings) -> anyhow::Result<Option<DataLogHandle>> {
+    let Some(interval) = settings.datalog_interval() else {
+        return Ok(None);
+    };
+    let partition = EspCustomNvsPartition::take(DATALOG_PARTITION)?;
+    let log = DataLog::open(partition, settings.datalog_retention())?;
+    info!(
+        "Logging the weight every {}s, {} records stored",
+        interval.as_secs(),
+        log.len()
+    );
+
+    let handle = DataLogHandle {
+        log: Arc::new(Mutex::new(log)),
+        interval,
+    };
+    let shutdown_handle = handle.clone();
+    shutdown::register("datalog", move || {
+        if let Err(err) = shutdown_handle.flush() {
+            warn!("Failed to flush the weight log: {:?}", err);
+        }
+    });
+    Ok(Some(handle))
+}
@@ -0,0 +1,286 @@
+//! SD card backend of the weight log, capturing every sample.
+//!
+//! Samples are formatted into a RAM buffer that is appended to the card
+//! every `FLUSH_INTERVAL` or once it holds `FLUSH_BYTES`. The card is
+//! mounted for as long as writes succeed; after a failure, e.g. when the
+//! card was pulled, logging is suspended and the mount is retried every
+//! `REMOUNT_PERIOD`, buffering up to `BUFFER_MAX_BYTES` in the meantime.
+
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::Write as _,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use esp_idf_hal::{
+    gpio::AnyIOPin,
+    peripheral::Peripheral,
+    sd::{spi::SdSpiHostDriver, SdCardConfiguration, SdCardDriver},
+    spi::{config::DriverConfig, SpiDriver, SPI3},
+};
+use esp_idf_svc::{fs::fatfs::Fatfs, io::vfs::MountedFatfs};
+use log::{info, warn};
+
+use crate::{
+    device,
+    filter::Sample,
+    settings::Settings,
+    sink::{OutputSink, SinkError, SinkResult},
+    stream::{CsvSample, CSV_HEADER, CSV_HEADER_WALL_CLOCK},
+    time::Timestamp,
+};
+
+const MOUNT_POINT: &str = "/sdcard";
+/// File for the samples taken before the clock is synchronized
+const UNSYNCED_FILE: &str = "UNSYNCED.CSV";
+const MAX_OPEN_FILES: usize = 2;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const FLUSH_BYTES: usize = 4 * 1024;
+/// Samples buffered while the card is missing, older ones are dropped
+const BUFFER_MAX_BYTES: usize = 32 * 1024;
+const REMOUNT_PERIOD: Duration = Duration::from_secs(5);
+
+/// Samples queued between the main loop and the writing task
+const SAMPLE_QUEUE_LEN: usize = 32;
+const SDCARD_TASK_STACK_SIZE: usize = 6 * 1024;
+
+type MountedCard =
+    MountedFatfs<Fatfs<SdCardDriver<SdSpiHostDriver<'static, Arc<SpiDriver<'static>>>>>>;
+
+/// Name of the card among the sinks
+pub const SDCARD_SINK: &str = "sdcard";
+
+/// Handle feeding samples to the SD card task, one of the sinks
+#[derive(Clone)]
+pub struct SdCardLog {
+    samples: SyncSender<(Timestamp, Sample)>,
+    suspended: Arc<AtomicBool>,
+    dropped: Arc<AtomicU32>,
+}
+
+impl SdCardLog {
+    /// Whether the card is missing or failing, so samples are not written
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Relaxed)
+    }
+
+    /// Samples dropped because the task or the card could not keep up
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Queues the samples for the card, never blocking the caller. A missing
+/// card is no failure, the task buffers the samples until it is back.
+impl OutputSink for SdCardLog {
+    fn name(&self) -> &'static str {
+        SDCARD_SINK
+    }
+
+    fn offer(&mut self, sample: &Sample) -> SinkResult {
+        match self.samples.try_send((Timestamp::now(), *sample)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Err(SinkError::Full)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                Err(SinkError::Failed("card task stopped".to_string()))
+            }
+        }
+    }
+}
+
+/// Start logging to the SD card if it is enabled in the settings
+pub fn start_sdcard_task(spi: SPI3, settings: &Settings) -> anyhow::Result<Option<SdCardLog>> {
+    if !settings.sd_card_enabled() {
+        return Ok(None);
+    }
+    let pins = settings.sd_card_pins();
+    // The pins come from the settings, so they can only be picked at runtime
+    let (sck, mosi, miso, cs) = unsafe {
+        (
+            AnyIOPin::new(pins.sck.into()),
+            AnyIOPin::new(pins.mosi.into()),
+            AnyIOPin::new(pins.miso.into()),
+            AnyIOPin::new(pins.cs.into()),
+        )
+    };
+    let spi_driver = SpiDriver::new(spi, sck, mosi, Some(miso), &DriverConfig::default())?;
+    let spi_driver = Arc::new(spi_driver);
+
+    let (samples_tx, samples_rx) = sync_channel(SAMPLE_QUEUE_LEN);
+    let suspended = Arc::new(AtomicBool::new(false));
+    let dropped = Arc::new(AtomicU32::new(0));
+    let writer = CardWriter {
+        spi_driver,
+        cs,
+        card: None,
+        last_mount_attempt: None,
+        suspended: suspended.clone(),
+    };
+    let task_dropped = dropped.clone();
+    std::thread::Builder::new()
+        .name("sdcard".to_string())
+        .stack_size(SDCARD_TASK_STACK_SIZE)
+        .spawn(move || sdcard_task(writer, samples_rx, task_dropped))?;
+
+    Ok(Some(SdCardLog {
+        samples: samples_tx,
+        suspended,
+        dropped,
+    }))
+}
+
+/// Owns the SPI bus and the card mounted on it
+struct CardWriter {
+    /// Shared with the host driver of the mounted card
+    spi_driver: Arc<SpiDriver<'static>>,
+    cs: AnyIOPin,
+    card: Option<MountedCard>,
+    last_mount_attempt: Option<Instant>,
+    suspended: Arc<AtomicBool>,
+}
+
+impl CardWriter {
+    fn mount(&mut self) -> anyhow::Result<MountedCard> {
+        let host = SdSpiHostDriver::new(
+            self.spi_driver.clone(),
+            // Only ever used by one mount at a time
+            Some(unsafe { self.cs.clone_unchecked() }),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            None,
+        )?;
+        let card = SdCardDriver::new_spi(host, &SdCardConfiguration::new())?;
+        Ok(MountedFatfs::mount(
+            Fatfs::new_sdcard(0, card)?,
+            MOUNT_POINT,
+            MAX_OPEN_FILES,
+        )?)
+    }
+
+    /// Append the lines to the file, creating it with the header. Returns
+    /// false while the card is unavailable.
+    fn write(&mut self, file: &str, header: &str, lines: &str) -> bool {
+        if self.card.is_none() {
+            let due = self
+                .last_mount_attempt
+                .map_or(true, |attempt| attempt.elapsed() >= REMOUNT_PERIOD);
+            if !due {
+                return false;
+            }
+            self.last_mount_attempt = Some(Instant::now());
+            match self.mount() {
+                Ok(card) => {
+                    info!("SD card mounted at {}", MOUNT_POINT);
+                    self.card = Some(card);
+                }
+                Err(err) => {
+                    if !self.suspended.swap(true, Ordering::Relaxed) {
+                        warn!("SD card logging suspended: {:?}", err);
+                    }
+                    return false;
+                }
+            }
+        }
+
+        match append(&Path::new(MOUNT_POINT).join(file), header, lines) {
+            Ok(()) => {
+                if self.suspended.swap(false, Ordering::Relaxed) {
+                    info!("SD card logging resumed");
+                }
+                true
+            }
+            Err(err) => {
+                warn!("SD card logging suspended, write failed: {:?}", err);
+                self.suspended.store(true, Ordering::Relaxed);
+                // Unmount, the card may have been pulled
+                self.card = None;
+                self.last_mount_attempt = Some(Instant::now());
+                false
+            }
+        }
+    }
+}
+
+fn append(path: &Path, header: &str, lines: &str) -> std::io::Result<()> {
+    let new_file = !path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if new_file {
+        writeln!(file, "{}", header)?;
+    }
+    file.write_all(lines.as_bytes())?;
+    file.sync_all()
+}
+
+/// Name of the file a sample goes to: one per UTC day, 8.3 names as FAT is
+/// built without long file names
+fn file_name(timestamp: Timestamp) -> String {
+    match timestamp.date() {
+        Some((year, month, day)) => format!("{:04}{:02}{:02}.CSV", year, month, day),
+        None => UNSYNCED_FILE.to_string(),
+    }
+}
+
+fn sdcard_task(
+    mut writer: CardWriter,
+    samples: Receiver<(Timestamp, Sample)>,
+    dropped: Arc<AtomicU32>,
+) {
+    let mut buffer = String::with_capacity(FLUSH_BYTES);
+    // File and header of the buffered lines
+    let mut file = UNSYNCED_FILE.to_string();
+    let mut header = CSV_HEADER;
+    let mut last_flush = Instant::now();
+
+    loop {
+        let mut flush = match samples.recv_timeout(FLUSH_INTERVAL) {
+            Ok((timestamp, sample)) => {
+                // Lines of the previous file have to go out before switching
+                let sample_file = file_name(timestamp);
+                if sample_file != file && !buffer.is_empty() {
+                    if !writer.write(&file, header, &buffer) {
+                        dropped.fetch_add(buffer.lines().count() as u32, Ordering::Relaxed);
+                    }
+                    buffer.clear();
+                }
+                file = sample_file;
+                header = if timestamp.is_wall_clock() {
+                    CSV_HEADER_WALL_CLOCK
+                } else {
+                    CSV_HEADER
+                };
+                let _ = writeln!(buffer, "{}", CsvSample(timestamp, &sample, device::stamp()));
+                buffer.len() >= FLUSH_BYTES
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        flush |= !buffer.is_empty() && last_flush.elapsed() >= FLUSH_INTERVAL;
+        if !flush {
+            continue;
+        }
+
+        last_flush = Instant::now();
+        if writer.write(&file, header, &buffer) {
+            buffer.clear();
+        } else if buffer.len() > BUFFER_MAX_BYTES {
+            // Drop the older half, line by line
+            let cut = buffer[buffer.len() / 2..]
+                .find('\n')
+                .map_or(buffer.len(), |newline| buffer.len() / 2 + newline + 1);
+            dropped.fetch_add(buffer[..cut].lines().count() as u32, Ordering::Relaxed);
+            buffer.drain(..cut);
+        }
+    }
+}
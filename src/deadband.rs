@@ -0,0 +1,232 @@
+//! Deadband of the weight on the display, so its last digit does not flicker
+//! with the noise of the load cell. The weight shown only moves once the
+//! filtered weight leaves the band around it.
+//!
+//! The band follows the noise: `k` times the standard deviation of the
+//! filtered weight, clamped to sane bounds. The deviation is averaged slowly
+//! over the stable weights, and a noise test replaces it with what it
+//! measured. A band set by hand takes over from the derived one.
+
+/// Multiple of the noise the band spans by default
+pub const DEFAULT_DEADBAND_K: f32 = 3.0;
+pub const MAX_DEADBAND_K: f32 = 10.0;
+/// Widest band in grams, derived or set by hand
+pub const MAX_DEADBAND_GRAMS: f32 = 5.0;
+/// Narrowest derived band in grams, a quiet cell still gets some
+const MIN_DERIVED_GRAMS: f32 = 0.05;
+/// Share of a new stable weight in the level the deviations are taken from
+const LEVEL_SMOOTHING: f32 = 0.05;
+/// Share of a new squared deviation in the noise, about the last few
+/// hundred stable weights count
+const NOISE_SMOOTHING: f32 = 0.005;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeadbandSetting {
+    /// Derived from the noise, `k` times its standard deviation
+    Auto { k: f32 },
+    /// Set by hand in grams, 0 turns it off
+    Manual(f32),
+}
+
+impl Default for DeadbandSetting {
+    fn default() -> Self {
+        DeadbandSetting::Auto {
+            k: DEFAULT_DEADBAND_K,
+        }
+    }
+}
+
+/// Weight shown on the display, held within the band
+#[derive(Clone, Debug, Default)]
+pub struct Deadband {
+    setting: DeadbandSetting,
+    /// Slow average of the stable weight, none while the weight moves
+    level: Option<f32>,
+    /// Slow average of the squared deviation from the level
+    variance: Option<f32>,
+    shown: Option<f32>,
+}
+
+impl Deadband {
+    pub fn new(setting: DeadbandSetting) -> Self {
+        Self {
+            setting,
+            ..Self::default()
+        }
+    }
+
+    /// Apply another setting, the noise measured so far is kept
+    pub fn configure(&mut self, setting: DeadbandSetting) {
+        self.setting = setting;
+    }
+
+    pub fn setting(&self) -> DeadbandSetting {
+        self.setting
+    }
+
+    /// Standard deviation of the filtered weight in grams, none until a
+    /// stable weight was followed for a while or a noise test ran
+    pub fn noise(&self) -> Option<f32> {
+        self.variance.map(f32::sqrt)
+    }
+
+    /// Band the noise calls for in grams, whether or not it is in use
+    pub fn derived(&self) -> Option<f32> {
+        let k = match self.setting {
+            DeadbandSetting::Auto { k } => k,
+            DeadbandSetting::Manual(_) => DEFAULT_DEADBAND_K,
+        };
+        self.noise()
+            .map(|noise| (k * noise).clamp(MIN_DERIVED_GRAMS, MAX_DEADBAND_GRAMS))
+    }
+
+    /// Band in use in grams
+    pub fn effective(&self) -> f32 {
+        match self.setting {
+            DeadbandSetting::Auto { .. } => self.derived().unwrap_or(0.0),
+            DeadbandSetting::Manual(grams) => grams,
+        }
+    }
+
+    /// Take the noise a noise test measured, the RMS of the raw readings in
+    /// grams. The filter averages `window` of them, which takes their noise
+    /// down by its square root.
+    pub fn on_noise_test(&mut self, rms_grams: f32, window: usize) {
+        let noise = rms_grams / (window.max(1) as f32).sqrt();
+        self.variance = Some(noise * noise);
+    }
+
+    /// Follow a filtered weight, returning the weight to show: the one shown
+    /// last while the new one stays within the band around it
+    pub fn apply(&mut self, grams: f32, stable: bool) -> f32 {
+        if stable {
+            self.track(grams);
+        } else {
+            self.level = None;
+        }
+        let band = self.effective();
+        match self.shown {
+            Some(shown) if (grams - shown).abs() <= band => shown,
+            _ => {
+                self.shown = Some(grams);
+                grams
+            }
+        }
+    }
+
+    /// Show the next weight as it is, after a tare or any jump of the zero
+    pub fn reset(&mut self) {
+        self.shown = None;
+        self.level = None;
+    }
+
+    fn track(&mut self, grams: f32) {
+        let Some(level) = &mut self.level else {
+            self.level = Some(grams);
+            return;
+        };
+        let deviation = grams - *level;
+        *level += LEVEL_SMOOTHING * deviation;
+        let squared = deviation * deviation;
+        self.variance = Some(match self.variance {
+            Some(variance) => variance + NOISE_SMOOTHING * (squared - variance),
+            None => squared,
+        });
+    }
+
+    /// Line for the diagnostics page
+    pub fn describe(&self) -> String {
+        let derived = self
+            .derived()
+            .map_or("-".to_string(), |grams| format!("{:.2}g", grams));
+        format!("Band {:.2}g auto {}", self.effective(), derived)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uniform noise of the given standard deviation, from a fixed seed
+    struct Noise {
+        state: u32,
+        std_dev: f32,
+    }
+
+    impl Noise {
+        fn new(std_dev: f32) -> Self {
+            Self {
+                state: 0x2545_f491,
+                std_dev,
+            }
+        }
+
+        fn next(&mut self) -> f32 {
+            self.state = self
+                .state
+                .wrapping_mul(1_664_525)
+                .wrapping_add(1_013_904_223);
+            let uniform = (self.state >> 8) as f32 / (1 << 24) as f32;
+            (uniform * 2.0 - 1.0) * 3f32.sqrt() * self.std_dev
+        }
+    }
+
+    /// Follow a stable weight with the noise for a while
+    fn follow(deadband: &mut Deadband, grams: f32, std_dev: f32) {
+        let mut noise = Noise::new(std_dev);
+        for _ in 0..5000 {
+            deadband.apply(grams + noise.next(), true);
+        }
+    }
+
+    #[test]
+    fn follows_the_noise() {
+        for std_dev in [0.02, 0.1, 0.5] {
+            let mut deadband = Deadband::default();
+            assert_eq!(deadband.derived(), None);
+            assert_eq!(deadband.effective(), 0.0);
+            follow(&mut deadband, 250.0, std_dev);
+            let noise = deadband.noise().unwrap();
+            assert!((noise - std_dev).abs() < 0.2 * std_dev, "{noise} {std_dev}");
+            let derived = deadband.derived().unwrap();
+            assert!(
+                (derived - DEFAULT_DEADBAND_K * std_dev).abs() < 0.2 * DEFAULT_DEADBAND_K * std_dev
+            );
+            assert_eq!(deadband.effective(), derived);
+        }
+    }
+
+    #[test]
+    fn stays_within_the_clamps() {
+        let mut quiet = Deadband::default();
+        follow(&mut quiet, 250.0, 0.001);
+        assert_eq!(quiet.derived(), Some(MIN_DERIVED_GRAMS));
+
+        let mut noisy = Deadband::new(DeadbandSetting::Auto { k: MAX_DEADBAND_K });
+        follow(&mut noisy, 250.0, 2.0);
+        assert_eq!(noisy.derived(), Some(MAX_DEADBAND_GRAMS));
+    }
+
+    #[test]
+    fn holds_the_weight_within_the_band() {
+        let mut deadband = Deadband::new(DeadbandSetting::Manual(0.5));
+        assert_eq!(deadband.apply(100.0, true), 100.0);
+        assert_eq!(deadband.apply(100.4, true), 100.0);
+        assert_eq!(deadband.apply(99.6, true), 100.0);
+        assert_eq!(deadband.apply(100.6, true), 100.6);
+        deadband.reset();
+        assert_eq!(deadband.apply(100.7, true), 100.7);
+    }
+
+    #[test]
+    fn a_noise_test_replaces_the_noise() {
+        let mut deadband = Deadband::default();
+        follow(&mut deadband, 250.0, 0.5);
+        deadband.on_noise_test(0.4, 16);
+        assert!((deadband.noise().unwrap() - 0.1).abs() < 1e-6);
+        // A band set by hand takes over, the derived one is still reported
+        deadband.configure(DeadbandSetting::Manual(1.0));
+        assert_eq!(deadband.effective(), 1.0);
+        assert!((deadband.derived().unwrap() - 0.3).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,170 @@
+//! Demo mode: the load cell is swapped for a signal generator at runtime, so
+//! the filters, the stability, the targets, the alarms and the pages can be
+//! shown on the hardware without anything on the platform, or without a
+//! load cell at all. The generator hands out counts like a sensor, from the
+//! zero and the scale factor the scale had when the demo started, so the
+//! readings go through the whole weighing as they would from the HX711.
+
+use std::time::{Duration, Instant};
+
+use crate::sensor::{LoadSensor, SensorError};
+
+/// Period of the generated readings, that of the sensors
+pub const DEMO_READING_PERIOD: Duration = Duration::from_millis(100);
+/// Heaviest weight a pattern goes to, either way
+pub const MAX_DEMO_GRAMS: f32 = 100_000.0;
+/// Longest ramp and latest step of a script
+pub const MAX_DEMO_SECS: f32 = 3600.0;
+/// Steps a script has at most
+pub const MAX_SCRIPT_STEPS: usize = 16;
+
+/// Weight of a scripted sequence from a time on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScriptStep {
+    /// Time since the start of the demo
+    pub at: Duration,
+    pub grams: f32,
+}
+
+/// Weight the generator puts on the platform over time
+#[derive(Clone, Debug, PartialEq)]
+pub enum DemoPattern {
+    /// From a weight to another over a time, staying there after
+    Ramp {
+        from_grams: f32,
+        to_grams: f32,
+        duration: Duration,
+    },
+    /// Straight to a weight, staying there
+    Step { grams: f32 },
+    /// Readings spread evenly within the amplitude of the setpoint
+    Noise {
+        setpoint_grams: f32,
+        amplitude_grams: f32,
+    },
+    /// Weights in turn from their times on, nothing before the first one
+    /// and the last one staying. The steps are in the order of their times.
+    Script(Vec<ScriptStep>),
+}
+
+impl Default for DemoPattern {
+    /// A coffee cup filling up over ten seconds
+    fn default() -> Self {
+        DemoPattern::Ramp {
+            from_grams: 0.0,
+            to_grams: 250.0,
+            duration: Duration::from_secs(10),
+        }
+    }
+}
+
+impl DemoPattern {
+    /// Weight at `elapsed` since the start, `noise` between -1 and 1
+    pub fn grams_at(&self, elapsed: Duration, noise: f32) -> f32 {
+        match self {
+            DemoPattern::Ramp {
+                from_grams,
+                to_grams,
+                duration,
+            } => {
+                let fraction = if duration.is_zero() {
+                    1.0
+                } else {
+                    (elapsed.as_secs_f32() / duration.as_secs_f32()).min(1.0)
+                };
+                from_grams + (to_grams - from_grams) * fraction
+            }
+            DemoPattern::Step { grams } => *grams,
+            DemoPattern::Noise {
+                setpoint_grams,
+                amplitude_grams,
+            } => setpoint_grams + amplitude_grams * noise,
+            DemoPattern::Script(steps) => steps
+                .iter()
+                .take_while(|step| step.at <= elapsed)
+                .last()
+                .map_or(0.0, |step| step.grams),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            DemoPattern::Ramp {
+                from_grams,
+                to_grams,
+                duration,
+            } => format!(
+                "ramp {}g to {}g over {}s",
+                from_grams,
+                to_grams,
+                duration.as_secs_f32()
+            ),
+            DemoPattern::Step { grams } => format!("step to {}g", grams),
+            DemoPattern::Noise {
+                setpoint_grams,
+                amplitude_grams,
+            } => format!("noise {}g around {}g", amplitude_grams, setpoint_grams),
+            DemoPattern::Script(steps) => format!(
+                "script {}",
+                steps
+                    .iter()
+                    .map(|step| format!("{}:{}", step.at.as_secs_f32(), step.grams))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        }
+    }
+}
+
+/// Sensor handing out the counts of the pattern, one reading per period
+pub struct DemoSensor {
+    pattern: DemoPattern,
+    /// Counts of the empty platform
+    zero_counts: i32,
+    counts_per_gram: f32,
+    started: Instant,
+    last_reading: Option<Instant>,
+    /// State of the xorshift generator of the noise, never zero
+    rng: u32,
+}
+
+impl DemoSensor {
+    pub fn new(pattern: DemoPattern, zero_counts: i32, counts_per_gram: f32) -> Self {
+        Self {
+            pattern,
+            zero_counts,
+            counts_per_gram,
+            started: Instant::now(),
+            last_reading: None,
+            rng: 0x2545_f491,
+        }
+    }
+
+    /// Next noise between -1 and 1
+    fn noise(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+impl LoadSensor for DemoSensor {
+    fn is_ready(&mut self) -> bool {
+        self.last_reading
+            .map_or(true, |at| at.elapsed() >= DEMO_READING_PERIOD)
+    }
+
+    fn read(&mut self) -> Result<i32, SensorError> {
+        if !self.is_ready() {
+            return Err(SensorError::NotReady);
+        }
+        let now = Instant::now();
+        self.last_reading = Some(now);
+        let noise = self.noise();
+        let grams = self
+            .pattern
+            .grams_at(now.duration_since(self.started), noise);
+        Ok(self.zero_counts + (grams * self.counts_per_gram).round() as i32)
+    }
+}
@@ -0,0 +1,161 @@
+//! Identity of the scale in everything it sends out. The device ID comes
+//! from the factory MAC address, the boot counter is kept in NVS, and every
+//! payload takes the next number of a sequence starting over at each boot,
+//! so the messages of several scales can be told apart and put in order.
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    OnceLock,
+};
+
+#[cfg(feature = "esp")]
+use log::warn;
+
+#[cfg(feature = "esp")]
+use crate::storage::StorageService;
+
+pub const DEVICE_NAMESPACE: &str = "device";
+/// Key of the boot counter, in the scale namespace too before the device
+/// kept the counter
+#[cfg(feature = "esp")]
+const BOOTS_KEY: &str = "boots";
+/// Hex digits of the device ID the default hostname ends with
+const HOSTNAME_SUFFIX_LEN: usize = 6;
+/// Prefix of the default hostname, the one used before it got a suffix
+const HOSTNAME_PREFIX: &str = "esp32-scale";
+
+static IDENTITY: OnceLock<DeviceIdentity> = OnceLock::new();
+/// Payloads stamped since boot
+static SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// Which scale this is, and which boot of it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    device_id: String,
+    boot: u32,
+}
+
+impl DeviceIdentity {
+    pub fn new(mac: [u8; 6], boot: u32) -> Self {
+        Self {
+            device_id: device_id(&mac),
+            boot,
+        }
+    }
+
+    /// Read the factory MAC address and count this boot
+    #[cfg(feature = "esp")]
+    pub fn load(storage: &StorageService) -> Self {
+        let mut mac = [0u8; 6];
+        // Reading the factory MAC from eFuse cannot fail
+        unsafe { esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+        Self::new(mac, count_boot(storage))
+    }
+
+    /// `scale_` followed by the MAC address in hex
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Boots counted since the storage was first used, this one included
+    pub fn boot(&self) -> u32 {
+        self.boot
+    }
+
+    /// Hostname used until another one is set, ending with the last digits
+    /// of the device ID
+    pub fn default_hostname(&self) -> String {
+        let suffix = &self.device_id[self.device_id.len() - HOSTNAME_SUFFIX_LEN..];
+        format!("{}-{}", HOSTNAME_PREFIX, suffix)
+    }
+
+    /// Identity along with the next sequence number, for a payload
+    pub fn stamp(&'static self) -> Stamp {
+        Stamp {
+            device_id: &self.device_id,
+            boot: self.boot,
+            seq: SEQ.fetch_add(1, Ordering::Relaxed).wrapping_add(1),
+        }
+    }
+}
+
+/// Set the identity for this boot, before anything is sent out. The first
+/// one set stays.
+pub fn init(identity: DeviceIdentity) -> &'static DeviceIdentity {
+    IDENTITY.get_or_init(|| identity)
+}
+
+/// Identity of this boot. Unset, as on the host, the MAC address and the
+/// boot read zero.
+pub fn identity() -> &'static DeviceIdentity {
+    IDENTITY.get_or_init(|| DeviceIdentity::new([0; 6], 0))
+}
+
+/// Device ID of the scale with the factory MAC address `mac`
+pub fn device_id(mac: &[u8; 6]) -> String {
+    let mac: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("scale_{}", mac)
+}
+
+/// Identity for the next payload
+pub fn stamp() -> Stamp {
+    identity().stamp()
+}
+
+/// Hostname the scale is reachable at, the one set or else the default
+pub fn hostname(configured: Option<&str>) -> String {
+    configured.map_or_else(|| identity().default_hostname(), str::to_string)
+}
+
+/// Sequence number of the last payload stamped, 0 before the first one
+pub fn last_seq() -> u32 {
+    SEQ.load(Ordering::Relaxed)
+}
+
+/// What every payload sent out carries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    pub device_id: &'static str,
+    pub boot: u32,
+    /// Starts at 1 with every boot
+    pub seq: u32,
+}
+
+#[cfg(any(feature = "mqtt", feature = "http"))]
+impl Stamp {
+    /// Add the fields to a JSON object, other values are left alone
+    pub fn insert_into(&self, value: &mut serde_json::Value) {
+        if let Some(object) = value.as_object_mut() {
+            object.insert("device_id".to_string(), self.device_id.into());
+            object.insert("boot".to_string(), self.boot.into());
+            object.insert("seq".to_string(), self.seq.into());
+        }
+    }
+}
+
+/// Count this boot. Until the device kept the counter the scale did, so
+/// its count carries over once.
+#[cfg(feature = "esp")]
+fn count_boot(storage_service: &StorageService) -> u32 {
+    let storage = match storage_service.open(DEVICE_NAMESPACE) {
+        Ok(storage) => storage,
+        Err(err) => {
+            warn!("Failed to open the device storage: {:?}", err);
+            return 0;
+        }
+    };
+    let scale = storage_service.open(crate::scale::STORAGE_NAMESPACE).ok();
+    let counted = storage
+        .get_u32(BOOTS_KEY)
+        .or_else(|| scale.as_ref().and_then(|scale| scale.get_u32(BOOTS_KEY)));
+    let boot = counted.unwrap_or(0).wrapping_add(1);
+    match storage.set_u32(BOOTS_KEY, boot) {
+        Ok(()) => {
+            if let Some(scale) = &scale {
+                let _ = scale.remove(BOOTS_KEY);
+            }
+        }
+        Err(err) => warn!("Failed to count the boot: {:?}", err),
+    }
+    boot
+}
@@ -0,0 +1,160 @@
+//! Memory and task diagnostics, to keep an eye on the headroom left as more
+//! services are added. Collecting walks the task list once and scans the
+//! stacks for their high-water marks, which takes well under a millisecond,
+//! so it can run every few seconds next to the sampling. On the host the
+//! heap figures read zero and no task is listed.
+
+use std::time::Duration;
+#[cfg(feature = "esp")]
+use std::{ffi::CStr, ptr};
+
+#[cfg(feature = "esp")]
+use esp_idf_svc::systime::EspSystemTime;
+#[cfg(feature = "esp")]
+use esp_idf_sys::{
+    esp_get_free_heap_size, esp_get_minimum_free_heap_size, heap_caps_get_largest_free_block,
+    uxTaskGetNumberOfTasks, uxTaskGetSystemState, TaskStatus_t, MALLOC_CAP_8BIT,
+};
+
+use crate::{
+    counters::{self, Counter},
+    device,
+    frame_rate::{achieved_fps, skipped_frames},
+    governor::{self, Subsystem, Suppression},
+    quiesce::quiesced_samples,
+};
+
+/// Room for tasks started between counting and listing them
+#[cfg(feature = "esp")]
+const EXTRA_TASK_SLOTS: usize = 4;
+/// Longest task name shown on the display
+const DISPLAY_TASK_NAME_LEN: usize = 11;
+
+/// Stack usage of one task
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskStack {
+    pub name: String,
+    /// Least free stack in bytes since the task started
+    pub free_min: u32,
+}
+
+/// Memory and task figures at one point in time
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiagSnapshot {
+    pub uptime: Duration,
+    /// Free heap in bytes
+    pub free_heap: u32,
+    /// Least free heap in bytes since boot
+    pub min_free_heap: u32,
+    /// Largest allocation in bytes that can still succeed
+    pub largest_free_block: u32,
+    /// Readings dropped or weighted down for a display flush since boot
+    pub quiesced_samples: u32,
+    /// Frames drawn per second lately
+    pub fps: f32,
+    /// Weight changes drawn with a later frame since boot
+    pub skipped_frames: u32,
+    /// Lifetime counters
+    pub counters: [(Counter, u32); Counter::ALL.len()],
+    /// Optional subsystems off for lack of heap, and why
+    pub suppressed: Vec<(Subsystem, Suppression)>,
+    /// The tasks, the one closest to overflowing its stack first
+    pub tasks: Vec<TaskStack>,
+}
+
+impl DiagSnapshot {
+    #[cfg(feature = "esp")]
+    pub fn collect() -> Self {
+        Self {
+            uptime: EspSystemTime.now(),
+            free_heap: unsafe { esp_get_free_heap_size() },
+            min_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+            largest_free_block: unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) } as u32,
+            quiesced_samples: quiesced_samples(),
+            fps: achieved_fps(),
+            skipped_frames: skipped_frames(),
+            counters: counters::values(),
+            suppressed: governor::suppressed(),
+            tasks: task_stacks(),
+        }
+    }
+
+    #[cfg(not(feature = "esp"))]
+    pub fn collect() -> Self {
+        Self {
+            uptime: crate::time::uptime(),
+            free_heap: 0,
+            min_free_heap: 0,
+            largest_free_block: 0,
+            quiesced_samples: quiesced_samples(),
+            fps: achieved_fps(),
+            skipped_frames: skipped_frames(),
+            counters: counters::values(),
+            suppressed: governor::suppressed(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Lines of the display page, the identity `whoami` prints first
+    pub fn display_lines(&self) -> Vec<String> {
+        let uptime = self.uptime.as_secs();
+        let identity = device::identity();
+        let mut lines = vec![
+            identity.device_id().to_string(),
+            format!("Boot {} seq {}", identity.boot(), device::last_seq()),
+            format!(
+                "Heap {}k min {}k",
+                self.free_heap / 1024,
+                self.min_free_heap / 1024
+            ),
+            format!(
+                "Blk {}k up {}:{:02}",
+                self.largest_free_block / 1024,
+                uptime / 3600,
+                uptime / 60 % 60
+            ),
+            format!("Quiesced {}", self.quiesced_samples),
+            format!("Fps {:.1} skip {}", self.fps, self.skipped_frames),
+        ];
+        lines.extend(
+            self.counters
+                .iter()
+                .map(|(counter, value)| format!("{} {}", counter.name(), value)),
+        );
+        lines.extend(
+            self.suppressed
+                .iter()
+                .map(|(subsystem, suppression)| format!("{} {}", subsystem.name(), suppression)),
+        );
+        lines.extend(self.tasks.iter().map(|task| {
+            let name: String = task.name.chars().take(DISPLAY_TASK_NAME_LEN).collect();
+            format!("{} {}", name, task.free_min)
+        }));
+        lines
+    }
+}
+
+/// Stack high-water marks of all the tasks, the lowest first
+#[cfg(feature = "esp")]
+fn task_stacks() -> Vec<TaskStack> {
+    let capacity = unsafe { uxTaskGetNumberOfTasks() } as usize + EXTRA_TASK_SLOTS;
+    let mut statuses: Vec<TaskStatus_t> = Vec::with_capacity(capacity);
+    // Needs CONFIG_FREERTOS_USE_TRACE_FACILITY, see sdkconfig.defaults
+    let count =
+        unsafe { uxTaskGetSystemState(statuses.as_mut_ptr(), capacity as u32, ptr::null_mut()) }
+            as usize;
+    // The first `count` entries were filled in
+    unsafe { statuses.set_len(count.min(capacity)) };
+
+    let mut tasks: Vec<TaskStack> = statuses
+        .iter()
+        .map(|status| TaskStack {
+            name: unsafe { CStr::from_ptr(status.pcTaskName) }
+                .to_string_lossy()
+                .into_owned(),
+            free_min: u32::from(status.usStackHighWaterMark),
+        })
+        .collect();
+    tasks.sort_by_key(|task| task.free_min);
+    tasks
+}
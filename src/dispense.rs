@@ -0,0 +1,293 @@
+//! Dispense by weight: a relay or SSR on an output pin opens a valve or runs
+//! a feeder until the target weight landed on the scale.
+//!
+//! The output is cut a little before the target, by the compensation, to
+//! account for what is still falling. The settled weight of every completed
+//! run tells how far off the compensation was, so it converges over
+//! successive runs. A timeout, a weight limit, a tare and any button press
+//! force the output off.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use esp_idf_hal::gpio::{AnyOutputPin, Output, PinDriver};
+use log::error;
+use thiserror::Error;
+
+use crate::{events::WeightEvent, settings::Settings};
+
+const DISPENSE_TASK_STACK_SIZE: usize = 3 * 1024;
+/// Period the limits are checked at when no weight arrives
+const POLL_PERIOD: Duration = Duration::from_millis(50);
+/// Longest wait for the weight to settle once the output is off
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Share of the overshoot moved into the compensation after a run, so a
+/// single odd run does not throw it off
+const TUNING_GAIN: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DispenseOutcome {
+    /// The target was reached
+    Completed,
+    /// Stopped by the button, the console or a tare
+    Aborted,
+    /// The output stayed on for the longest time allowed
+    TimedOut,
+    /// The weight on the scale reached the limit
+    OverWeight,
+    /// The output could not be driven
+    Failed,
+}
+
+/// How a dispense went, once the weight settled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DispenseResult {
+    pub outcome: DispenseOutcome,
+    pub target_grams: f32,
+    /// Settled weight added since the start
+    pub dispensed_grams: f32,
+    /// Time the output was on
+    pub duration: Duration,
+}
+
+impl DispenseResult {
+    /// Weight dispensed past the target, negative when short of it
+    pub fn overshoot_grams(&self) -> f32 {
+        self.dispensed_grams - self.target_grams
+    }
+}
+
+/// Compensation to use after a completed run that used `compensation_grams`
+pub fn tuned_compensation(compensation_grams: f32, result: &DispenseResult) -> f32 {
+    (compensation_grams + result.overshoot_grams() * TUNING_GAIN).clamp(0.0, result.target_grams)
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum DispenseError {
+    #[error("Already dispensing")]
+    Busy,
+    #[error("Target out of range: {0}g")]
+    InvalidTarget(f32),
+    #[error("Dispenser task stopped")]
+    Stopped,
+}
+
+struct DispenseRequest {
+    target_grams: f32,
+    compensation_grams: f32,
+}
+
+#[derive(Default)]
+struct Shared {
+    running: AtomicBool,
+    abort: AtomicBool,
+    result: Mutex<Option<DispenseResult>>,
+}
+
+/// Limits forcing the output off, from the settings
+#[derive(Clone, Copy)]
+struct Limits {
+    timeout: Duration,
+    max_grams: f32,
+}
+
+/// Handle to the task driving the dispenser output
+#[derive(Clone)]
+pub struct Dispenser {
+    requests: Sender<DispenseRequest>,
+    shared: Arc<Shared>,
+    max_grams: f32,
+}
+
+impl Dispenser {
+    /// Energize the output until `target_grams` were added to the weight on
+    /// the scale, cutting it `overshoot_compensation_grams` early
+    pub fn start_dispense(
+        &self,
+        target_grams: f32,
+        overshoot_compensation_grams: f32,
+    ) -> Result<(), DispenseError> {
+        if !(target_grams > 0.0 && target_grams < self.max_grams) {
+            return Err(DispenseError::InvalidTarget(target_grams));
+        }
+        if self.shared.running.swap(true, Ordering::AcqRel) {
+            return Err(DispenseError::Busy);
+        }
+        self.shared.abort.store(false, Ordering::Release);
+        let request = DispenseRequest {
+            target_grams,
+            compensation_grams: overshoot_compensation_grams.clamp(0.0, target_grams),
+        };
+        self.requests.send(request).map_err(|_| {
+            self.shared.running.store(false, Ordering::Release);
+            DispenseError::Stopped
+        })
+    }
+
+    /// Force the output off, if dispensing
+    pub fn abort(&self) {
+        self.shared.abort.store(true, Ordering::Release);
+    }
+
+    /// Whether a dispense is running, including the wait for the weight to
+    /// settle
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::Acquire)
+    }
+
+    /// Result of the last dispense, reported once
+    pub fn take_result(&self) -> Option<DispenseResult> {
+        self.shared
+            .result
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take()
+    }
+}
+
+/// Start the dispenser task on the configured output pin, following the
+/// weight through the events
+pub fn start_dispenser(
+    settings: &Settings,
+    events: Receiver<WeightEvent>,
+) -> anyhow::Result<Dispenser> {
+    // The pin comes from the settings, so it can only be picked at runtime
+    let pin = unsafe { AnyOutputPin::new(settings.dispense_pin().into()) };
+    let mut output = PinDriver::output(pin)?;
+    output.set_low()?;
+
+    let limits = Limits {
+        timeout: settings.dispense_timeout(),
+        max_grams: settings.dispense_max_grams(),
+    };
+    let (requests_tx, requests_rx) = channel();
+    let shared = Arc::new(Shared::default());
+    let task_shared = shared.clone();
+    std::thread::Builder::new()
+        .name("dispense".to_string())
+        .stack_size(DISPENSE_TASK_STACK_SIZE)
+        .spawn(move || dispense_task(output, limits, requests_rx, events, task_shared))?;
+
+    Ok(Dispenser {
+        requests: requests_tx,
+        shared,
+        max_grams: limits.max_grams,
+    })
+}
+
+fn dispense_task(
+    mut output: PinDriver<'static, AnyOutputPin, Output>,
+    limits: Limits,
+    requests: Receiver<DispenseRequest>,
+    events: Receiver<WeightEvent>,
+    shared: Arc<Shared>,
+) {
+    // Latest filtered weight, the start of the next dispense
+    let mut grams = 0.0;
+    loop {
+        let request = match requests.recv_timeout(POLL_PERIOD) {
+            Ok(request) => request,
+            Err(RecvTimeoutError::Timeout) => {
+                follow_weight(&events, &mut grams);
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        follow_weight(&events, &mut grams);
+
+        let result = dispense(&mut output, limits, &request, &events, &mut grams, &shared);
+        // Never leave the output on, whatever happened
+        if let Err(err) = output.set_low() {
+            error!("Failed to turn the dispenser output off: {:?}", err);
+        }
+        *shared
+            .result
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(result);
+        shared.running.store(false, Ordering::Release);
+    }
+}
+
+/// Catch up with the weight events received while not dispensing
+fn follow_weight(events: &Receiver<WeightEvent>, grams: &mut f32) {
+    while let Ok(event) = events.try_recv() {
+        if let WeightEvent::Changed { grams: latest, .. } = event {
+            *grams = latest;
+        }
+    }
+}
+
+fn dispense(
+    output: &mut PinDriver<'static, AnyOutputPin, Output>,
+    limits: Limits,
+    request: &DispenseRequest,
+    events: &Receiver<WeightEvent>,
+    grams: &mut f32,
+    shared: &Shared,
+) -> DispenseResult {
+    let start_grams = *grams;
+    let cutoff_grams = request.target_grams - request.compensation_grams;
+    let started = Instant::now();
+
+    let outcome = match output.set_high() {
+        Ok(()) => loop {
+            if shared.abort.load(Ordering::Acquire) {
+                break DispenseOutcome::Aborted;
+            }
+            if started.elapsed() >= limits.timeout {
+                break DispenseOutcome::TimedOut;
+            }
+            match events.recv_timeout(POLL_PERIOD) {
+                Ok(WeightEvent::Changed { grams: latest, .. }) => {
+                    *grams = latest;
+                    if latest >= limits.max_grams {
+                        break DispenseOutcome::OverWeight;
+                    }
+                    if latest - start_grams >= cutoff_grams {
+                        break DispenseOutcome::Completed;
+                    }
+                }
+                // The start weight means nothing after a tare
+                Ok(WeightEvent::Tared) | Err(RecvTimeoutError::Disconnected) => {
+                    break DispenseOutcome::Aborted;
+                }
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            }
+        },
+        Err(err) => {
+            error!("Failed to turn the dispenser output on: {:?}", err);
+            DispenseOutcome::Failed
+        }
+    };
+    if let Err(err) = output.set_low() {
+        error!("Failed to turn the dispenser output off: {:?}", err);
+    }
+    let duration = started.elapsed();
+
+    // Let what is still falling land before weighing the result
+    let settle_started = Instant::now();
+    while settle_started.elapsed() < SETTLE_TIMEOUT {
+        match events.recv_timeout(POLL_PERIOD) {
+            Ok(WeightEvent::Stable { grams: latest }) => {
+                *grams = latest;
+                break;
+            }
+            Ok(WeightEvent::Changed { grams: latest, .. }) => *grams = latest,
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    DispenseResult {
+        outcome,
+        target_grams: request.target_grams,
+        dispensed_grams: *grams - start_grams,
+        duration,
+    }
+}
@@ -0,0 +1,54 @@
+//! Detection of the SSD1306 panel on the I2C bus. Boards come with the
+//! panel at either of its two addresses, or without one for a headless
+//! scale, which then draws into a `NullDisplay`. The simulator has its
+//! panel in a window, set with `set_detected`.
+
+use std::sync::OnceLock;
+
+#[cfg(feature = "esp")]
+use esp_idf_hal::{delay::TickType, i2c::I2cDriver};
+#[cfg(feature = "esp")]
+use log::{info, warn};
+
+/// Addresses the SSD1306 can be strapped to, the usual one first
+pub const SSD1306_ADDRESSES: [u8; 2] = [0x3C, 0x3D];
+
+/// Time given to a device to acknowledge the probe
+#[cfg(feature = "esp")]
+const PROBE_TIMEOUT_MS: u64 = 10;
+
+/// Address the panel answered at, `None` when headless
+static DETECTED: OnceLock<Option<u8>> = OnceLock::new();
+
+/// Find the panel on the bus by writing a lone command control byte to each
+/// address, which the SSD1306 acknowledges without acting on. The result is
+/// kept for `detected_address`.
+#[cfg(feature = "esp")]
+pub fn probe(i2c: &mut I2cDriver<'_>) -> Option<u8> {
+    let timeout = TickType::new_millis(PROBE_TIMEOUT_MS).ticks();
+    let address = SSD1306_ADDRESSES
+        .into_iter()
+        .find(|&address| i2c.write(address, &[0x00], timeout).is_ok());
+    match address {
+        Some(address) => info!("Display found at 0x{:02X}", address),
+        None => warn!("No display found at 0x3C or 0x3D, running headless"),
+    }
+    let _ = DETECTED.set(address);
+    address
+}
+
+/// Take the panel as found at `address` without probing the bus, only the
+/// first call or probe has an effect
+pub fn set_detected(address: Option<u8>) {
+    let _ = DETECTED.set(address);
+}
+
+/// Address of the panel found at startup
+pub fn detected_address() -> Option<u8> {
+    DETECTED.get().copied().flatten()
+}
+
+/// Whether the scale runs without a panel
+pub fn is_headless() -> bool {
+    detected_address().is_none()
+}
@@ -0,0 +1,128 @@
+//! Length and CRC32 envelope around the blobs kept in NVS. A blob cut short
+//! by a brown-out in the middle of its write, or with bits flipped in the
+//! flash, fails the check and reads as missing instead of as garbage the
+//! scale would carry on with.
+//!
+//! The envelope is the length of the payload and its CRC32, both little
+//! endian, followed by the payload.
+
+use thiserror::Error;
+
+/// Bytes the envelope adds in front of the payload
+pub const ENVELOPE_LEN: usize = 8;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeError {
+    #[error("Blob of {0} bytes is shorter than its envelope")]
+    Truncated(usize),
+    #[error("Payload is {actual} bytes instead of {expected}")]
+    Length { expected: usize, actual: usize },
+    #[error("CRC {actual:08X} instead of {expected:08X}")]
+    Crc { expected: u32, actual: u32 },
+}
+
+/// CRC-32 of the bytes, the common one of zlib and Ethernet
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// The payload in its envelope, to be stored
+pub fn seal(payload: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(ENVELOPE_LEN + payload.len());
+    blob.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    blob.extend_from_slice(&crc32(payload).to_le_bytes());
+    blob.extend_from_slice(payload);
+    blob
+}
+
+/// The payload of a stored blob, once its length and CRC check out
+pub fn open(blob: &[u8]) -> Result<&[u8], EnvelopeError> {
+    if blob.len() < ENVELOPE_LEN {
+        return Err(EnvelopeError::Truncated(blob.len()));
+    }
+    let (header, payload) = blob.split_at(ENVELOPE_LEN);
+    let expected = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if payload.len() != expected {
+        return Err(EnvelopeError::Length {
+            expected,
+            actual: payload.len(),
+        });
+    }
+    let expected = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let actual = crc32(payload);
+    if actual != expected {
+        return Err(EnvelopeError::Crc { expected, actual });
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn round_trip() {
+        let payload = b"settings blob";
+        let blob = seal(payload);
+        assert_eq!(blob.len(), ENVELOPE_LEN + payload.len());
+        assert_eq!(open(&blob), Ok(&payload[..]));
+    }
+
+    #[test]
+    fn empty_payload() {
+        let blob = seal(&[]);
+        assert_eq!(blob.len(), ENVELOPE_LEN);
+        assert_eq!(open(&blob), Ok(&[][..]));
+        assert_eq!(open(&blob[..4]), Err(EnvelopeError::Truncated(4)));
+    }
+
+    #[test]
+    fn flipped_bit_fails() {
+        let payload = b"settings blob";
+        let mut blob = seal(payload);
+        blob[ENVELOPE_LEN + 3] ^= 0x10;
+        assert_eq!(
+            open(&blob),
+            Err(EnvelopeError::Crc {
+                expected: crc32(payload),
+                actual: crc32(&blob[ENVELOPE_LEN..]),
+            })
+        );
+    }
+
+    #[test]
+    fn wrong_length_fails() {
+        let mut blob = seal(b"settings blob");
+        blob[0] += 1;
+        assert_eq!(
+            open(&blob),
+            Err(EnvelopeError::Length {
+                expected: 14,
+                actual: 13
+            })
+        );
+        // Cut short by a brown-out
+        let blob = seal(b"settings blob");
+        assert_eq!(
+            open(&blob[..blob.len() - 2]),
+            Err(EnvelopeError::Length {
+                expected: 13,
+                actual: 11
+            })
+        );
+    }
+}
@@ -0,0 +1,66 @@
+#[cfg(feature = "display")]
+use std::fmt::Debug;
+
+#[cfg(feature = "esp")]
+use esp_idf_sys::EspError;
+use thiserror::Error;
+
+#[cfg(feature = "esp")]
+use crate::nau7802::Nau7802Error;
+use crate::{
+    scale::ScaleError,
+    storage::{StorageError, StoreError},
+};
+#[cfg(feature = "display")]
+use crate::{selftest::Check, text_drawer::TextError};
+
+/// Error stopping the firmware. Failures of optional services are only
+/// logged, these are the ones the scale cannot weigh without.
+#[derive(Error, Debug)]
+pub enum FirmwareError {
+    #[cfg(feature = "esp")]
+    #[error("Failed {context}: {source}")]
+    Esp {
+        context: &'static str,
+        source: EspError,
+    },
+    /// The display error type is generic over the panel, so only its
+    /// description is kept
+    #[cfg(feature = "display")]
+    #[error("Display error: {0}")]
+    Display(String),
+    #[error(transparent)]
+    Scale(#[from] ScaleError),
+    #[cfg(feature = "esp")]
+    #[error("Failed to start the NAU7802: {0}")]
+    Nau7802(#[from] Nau7802Error),
+    #[error("Failed to access the settings storage: {0}")]
+    Nvs(StoreError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    /// A boot check the scale cannot weigh without failed, restarting does
+    /// not help
+    #[cfg(feature = "display")]
+    #[error("Self-test failed on the {}: {detail}", .check.name())]
+    SelfTest { check: Check, detail: &'static str },
+}
+
+#[cfg(feature = "display")]
+impl<E: Debug> From<TextError<E>> for FirmwareError {
+    fn from(err: TextError<E>) -> Self {
+        FirmwareError::Display(err.to_string())
+    }
+}
+
+/// Attach what was being done to an esp-idf error
+#[cfg(feature = "esp")]
+pub trait EspContext<T> {
+    fn context(self, context: &'static str) -> Result<T, FirmwareError>;
+}
+
+#[cfg(feature = "esp")]
+impl<T> EspContext<T> for Result<T, EspError> {
+    fn context(self, context: &'static str) -> Result<T, FirmwareError> {
+        self.map_err(|source| FirmwareError::Esp { context, source })
+    }
+}
@@ -0,0 +1,910 @@
+//! Scales on the corners of a platform sending their weight over ESP-NOW to
+//! one aggregator, which shows the sum of the corners. A corner sends its
+//! weight every `SEND_PERIOD`, so a corner that goes silent for the stale
+//! time of the aggregator makes the total invalid.
+//!
+//! Pairing needs no MAC typed in: the aggregator opens a pairing window,
+//! then each corner opened for pairing broadcasts `Packet::Pair` until the
+//! aggregator answers with `Packet::PairAck`. The aggregator adds the
+//! corner to its peers and the corner keeps the aggregator's MAC, both
+//! through a console command so they are saved like any other setting.
+//!
+//! Every packet carries the device ID, the boot and the sequence number of
+//! its sender like any other payload of the scale. The aggregator takes the
+//! weight of a corner by its device ID, the one of the MAC it paired with,
+//! and drops a packet whose sequence number it has seen already.
+//!
+//! The packets and the aggregation build anywhere, the radio task needs
+//! the `espnow` feature. ESP-NOW runs on the radio of the Wi-Fi task, so
+//! the scales have to be on the same channel, e.g. on the same network.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "espnow")]
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{channel, RecvTimeoutError, Sender},
+    Arc, Mutex, MutexGuard,
+};
+
+#[cfg(feature = "espnow")]
+use esp_idf_svc::espnow::{EspNow, PeerInfo, ReceiveInfo, BROADCAST};
+#[cfg(feature = "espnow")]
+use log::{debug, info, warn};
+
+#[cfg(feature = "espnow")]
+use crate::{
+    command_channel::{Command, CommandSender, EspNowSetting},
+    settings::Settings,
+    snapshot::SharedSnapshot,
+    watchdog::WatchdogGuard,
+};
+use crate::{
+    device::{self, Stamp},
+    quality::Quality,
+};
+
+/// Corners an aggregator sums at most
+pub const MAX_PEERS: usize = 8;
+/// Longest label of a corner, so a line of the breakdown page fits
+pub const MAX_LABEL_LEN: usize = 6;
+pub const DEFAULT_STALE_MS: u32 = 2000;
+pub const MIN_STALE_MS: u32 = 500;
+pub const MAX_STALE_MS: u32 = 60_000;
+/// Time a pairing stays open, on the aggregator and on a corner alike
+pub const PAIRING_WINDOW: Duration = Duration::from_secs(60);
+
+/// First byte of every packet, telling them apart from other ESP-NOW
+/// traffic on the channel
+const MAGIC: u8 = 0x5C;
+const PACKET_VERSION: u8 = 2;
+const KIND_WEIGHT: u8 = 0;
+const KIND_PAIR: u8 = 1;
+const KIND_PAIR_ACK: u8 = 2;
+
+#[cfg(feature = "espnow")]
+const ESPNOW_TASK_STACK_SIZE: usize = 4 * 1024;
+/// Period a corner sends its weight at, whether it changed or not
+#[cfg(feature = "espnow")]
+const SEND_PERIOD: Duration = Duration::from_millis(250);
+/// Period a corner broadcasts its pairing request at
+#[cfg(feature = "espnow")]
+const PAIR_PERIOD: Duration = Duration::from_secs(1);
+/// Period the task wakes up at without packets
+#[cfg(feature = "espnow")]
+const TASK_TICK: Duration = Duration::from_millis(50);
+/// Period ESP-NOW is retried at until the Wi-Fi task started the radio
+#[cfg(feature = "espnow")]
+const INIT_RETRY_PERIOD: Duration = Duration::from_secs(1);
+
+/// MAC address of a station
+pub type Mac = [u8; 6];
+
+/// `aa:bb:cc:dd:ee:ff`
+pub fn format_mac(mac: &Mac) -> String {
+    mac.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Six hex bytes separated by colons or dashes
+pub fn parse_mac(text: &str) -> Option<Mac> {
+    let mut mac = [0u8; 6];
+    let mut bytes = text.split([':', '-']);
+    for byte in &mut mac {
+        let part = bytes.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    bytes.next().is_none().then_some(mac)
+}
+
+/// What the scale does on ESP-NOW, taking effect after a restart
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EspNowRole {
+    #[default]
+    Off,
+    /// Sends its weight to the aggregator
+    Corner,
+    /// Sums the weights of its peers
+    Aggregator,
+}
+
+impl EspNowRole {
+    pub const ALL: [EspNowRole; 3] = [EspNowRole::Off, EspNowRole::Corner, EspNowRole::Aggregator];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EspNowRole::Off => "off",
+            EspNowRole::Corner => "corner",
+            EspNowRole::Aggregator => "aggregator",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|role| role.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            EspNowRole::Off => 0,
+            EspNowRole::Corner => 1,
+            EspNowRole::Aggregator => 2,
+        }
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+}
+
+/// A corner of the aggregator
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub mac: Mac,
+    pub label: String,
+}
+
+impl Peer {
+    /// Labelled after its position until given a label
+    pub fn new(mac: Mac, index: usize) -> Self {
+        Self {
+            mac,
+            label: format!("C{}", index + 1),
+        }
+    }
+
+    /// Device ID the corner stamps its packets with, the one of its MAC
+    pub fn device_id(&self) -> String {
+        device::device_id(&self.mac)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.label, format_mac(&self.mac))
+    }
+}
+
+/// What the aggregator makes of a pairing request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// Paired already, at the index
+    Known(usize),
+    /// To be added at the index
+    New(usize),
+    /// No room for another corner
+    Full,
+}
+
+/// Admit the corner at `mac` to the peers
+pub fn admit(peers: &[Peer], mac: Mac) -> Admission {
+    match peers.iter().position(|peer| peer.mac == mac) {
+        Some(index) => Admission::Known(index),
+        None if peers.len() >= MAX_PEERS => Admission::Full,
+        None => Admission::New(peers.len()),
+    }
+}
+
+/// Sender of a packet and its place in the sequence of the sender
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub device_id: String,
+    pub boot: u32,
+    pub seq: u32,
+}
+
+impl From<Stamp> for Origin {
+    fn from(stamp: Stamp) -> Self {
+        Self {
+            device_id: stamp.device_id.to_string(),
+            boot: stamp.boot,
+            seq: stamp.seq,
+        }
+    }
+}
+
+/// Payload of an ESP-NOW frame between the scales, after the origin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packet {
+    /// Weight of a corner along with its quality
+    Weight { grams: f32, quality: Quality },
+    /// A corner asking the aggregator to pair, broadcast
+    Pair,
+    /// The aggregator took the corner in
+    PairAck,
+}
+
+/// A packet along with its origin, as it goes over the air
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub origin: Origin,
+    pub packet: Packet,
+}
+
+impl Message {
+    /// The packet stamped with the identity and the next sequence number
+    /// of this scale
+    pub fn stamped(packet: Packet) -> Self {
+        Self {
+            origin: device::stamp().into(),
+            packet,
+        }
+    }
+
+    /// Magic, version and kind, then the device ID prefixed with its
+    /// length, the boot and the sequence number, then the payload of the
+    /// kind, little endian
+    pub fn encode(&self) -> Vec<u8> {
+        let kind = match self.packet {
+            Packet::Weight { .. } => KIND_WEIGHT,
+            Packet::Pair => KIND_PAIR,
+            Packet::PairAck => KIND_PAIR_ACK,
+        };
+        let device_id = self.origin.device_id.as_bytes();
+        let device_id = &device_id[..device_id.len().min(usize::from(u8::MAX))];
+        let mut bytes = vec![MAGIC, PACKET_VERSION, kind, device_id.len() as u8];
+        bytes.extend_from_slice(device_id);
+        bytes.extend_from_slice(&self.origin.boot.to_le_bytes());
+        bytes.extend_from_slice(&self.origin.seq.to_le_bytes());
+        if let Packet::Weight { grams, quality } = self.packet {
+            bytes.extend_from_slice(&grams.to_le_bytes());
+            bytes.push(quality.index());
+        }
+        bytes
+    }
+
+    /// None for the packets of another application or version, and for
+    /// truncated ones
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let [MAGIC, PACKET_VERSION, kind, len, rest @ ..] = bytes else {
+            return None;
+        };
+        let len = usize::from(*len);
+        let (device_id, rest) = (rest.get(..len)?, &rest[len..]);
+        let (boot, rest) = rest.split_first_chunk::<4>()?;
+        let (seq, rest) = rest.split_first_chunk::<4>()?;
+        let origin = Origin {
+            device_id: std::str::from_utf8(device_id).ok()?.to_string(),
+            boot: u32::from_le_bytes(*boot),
+            seq: u32::from_le_bytes(*seq),
+        };
+        let packet = match (*kind, rest) {
+            (KIND_WEIGHT, [g0, g1, g2, g3, quality]) => Packet::Weight {
+                grams: f32::from_le_bytes([*g0, *g1, *g2, *g3]),
+                quality: Quality::from_index(*quality)?,
+            },
+            (KIND_PAIR, []) => Packet::Pair,
+            (KIND_PAIR_ACK, []) => Packet::PairAck,
+            _ => return None,
+        };
+        Some(Self { origin, packet })
+    }
+}
+
+/// What the aggregator made of a weight
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Received {
+    Taken,
+    /// From a device that is none of the corners
+    Unpaired,
+    /// Sequence number seen already in this boot of the corner, a repeat
+    /// or one overtaken by a later packet
+    Repeated,
+}
+
+/// Weight a corner last sent, as the pages show it
+#[derive(Clone, Debug, PartialEq)]
+pub struct CornerReading {
+    pub label: String,
+    /// None before the first packet
+    pub grams: Option<f32>,
+    /// Whole seconds since the last packet, so it changes once a second
+    pub age_s: Option<u64>,
+    /// Whether the weight came within the stale time, from a sensor that
+    /// still converts and is not overloaded
+    pub fresh: bool,
+}
+
+/// Sum of the corners
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Aggregate {
+    /// Sum of the last weights sent, of the corners that sent one
+    pub grams: f32,
+    /// Whether every corner is fresh, the total is no weight otherwise
+    pub valid: bool,
+    /// Whether every corner is settled
+    pub stable: bool,
+    pub corners: Vec<CornerReading>,
+}
+
+impl Aggregate {
+    /// The corners that are not fresh, by their label
+    pub fn stale_labels(&self) -> impl Iterator<Item = &str> {
+        self.corners
+            .iter()
+            .filter(|corner| !corner.fresh)
+            .map(|corner| corner.label.as_str())
+    }
+}
+
+struct Corner {
+    peer: Peer,
+    device_id: String,
+    /// Weight and quality last sent, with when
+    last: Option<(f32, Quality, Instant)>,
+    /// Boot and sequence number of the last weight taken
+    seq: Option<(u32, u32)>,
+}
+
+/// Last weights of the corners, summed when they are all fresh
+pub struct Aggregator {
+    corners: Vec<Corner>,
+    stale_after: Duration,
+}
+
+impl Aggregator {
+    pub fn new(peers: &[Peer], stale_after: Duration) -> Self {
+        let mut aggregator = Self {
+            corners: Vec::new(),
+            stale_after,
+        };
+        aggregator.configure(peers, stale_after);
+        aggregator
+    }
+
+    /// Follow a change of the peers, keeping the weights of those still
+    /// listed
+    pub fn configure(&mut self, peers: &[Peer], stale_after: Duration) {
+        let mut previous = std::mem::take(&mut self.corners);
+        self.corners = peers
+            .iter()
+            .map(|peer| {
+                let device_id = peer.device_id();
+                let (last, seq) = previous
+                    .iter_mut()
+                    .find(|corner| corner.device_id == device_id)
+                    .map_or((None, None), |corner| {
+                        (corner.last.take(), corner.seq.take())
+                    });
+                Corner {
+                    peer: peer.clone(),
+                    device_id,
+                    last,
+                    seq,
+                }
+            })
+            .collect();
+        self.stale_after = stale_after;
+    }
+
+    /// Take the weight of the corner with the device ID of `origin`, unless
+    /// its sequence number is not past the last one of the same boot. A
+    /// new boot starts the sequence over.
+    pub fn on_weight(
+        &mut self,
+        origin: &Origin,
+        grams: f32,
+        quality: Quality,
+        now: Instant,
+    ) -> Received {
+        let Some(corner) = self
+            .corners
+            .iter_mut()
+            .find(|corner| corner.device_id == origin.device_id)
+        else {
+            return Received::Unpaired;
+        };
+        if matches!(corner.seq, Some((boot, seq)) if boot == origin.boot && origin.seq <= seq) {
+            return Received::Repeated;
+        }
+        corner.seq = Some((origin.boot, origin.seq));
+        corner.last = Some((grams, quality, now));
+        Received::Taken
+    }
+
+    pub fn aggregate(&self, now: Instant) -> Aggregate {
+        let corners: Vec<CornerReading> = self
+            .corners
+            .iter()
+            .map(|corner| {
+                let age = corner
+                    .last
+                    .map(|(_, _, at)| now.saturating_duration_since(at));
+                CornerReading {
+                    label: corner.peer.label.clone(),
+                    grams: corner.last.map(|(grams, _, _)| grams),
+                    age_s: age.map(|age| age.as_secs()),
+                    fresh: match (corner.last, age) {
+                        (Some((_, quality, _)), Some(age)) => {
+                            age < self.stale_after
+                                && !matches!(quality, Quality::Stale | Quality::Overload)
+                        }
+                        _ => false,
+                    },
+                }
+            })
+            .collect();
+        Aggregate {
+            grams: corners.iter().filter_map(|corner| corner.grams).sum(),
+            valid: !corners.is_empty() && corners.iter().all(|corner| corner.fresh),
+            stable: self
+                .corners
+                .iter()
+                .all(|corner| matches!(corner.last, Some((_, Quality::Good, _)))),
+            corners,
+        }
+    }
+}
+
+#[cfg(feature = "espnow")]
+enum EspNowRequest {
+    Pair,
+    Configure {
+        peers: Vec<Peer>,
+        stale_after: Duration,
+        aggregator: Option<Mac>,
+    },
+}
+
+/// Handle to the ESP-NOW task
+#[cfg(feature = "espnow")]
+#[derive(Clone)]
+pub struct EspNowHandle {
+    role: EspNowRole,
+    aggregator: Arc<Mutex<Aggregator>>,
+    pairing: Arc<AtomicBool>,
+    requests: Sender<EspNowRequest>,
+}
+
+#[cfg(feature = "espnow")]
+impl EspNowHandle {
+    pub fn role(&self) -> EspNowRole {
+        self.role
+    }
+
+    /// Open the pairing window: the aggregator takes the corners asking in,
+    /// a corner asks the aggregator
+    pub fn pair(&self) {
+        let _ = self.requests.send(EspNowRequest::Pair);
+    }
+
+    pub fn is_pairing(&self) -> bool {
+        self.pairing.load(Ordering::Relaxed)
+    }
+
+    /// Follow the peers, the stale time and the aggregator of the settings
+    pub fn configure(&self, settings: &Settings) {
+        let _ = self.requests.send(EspNowRequest::Configure {
+            peers: settings.espnow_peers().to_vec(),
+            stale_after: settings.espnow_stale(),
+            aggregator: settings.espnow_aggregator(),
+        });
+    }
+
+    /// Sum of the corners, on the aggregator
+    pub fn aggregate(&self) -> Option<Aggregate> {
+        (self.role == EspNowRole::Aggregator)
+            .then(|| lock(&self.aggregator).aggregate(Instant::now()))
+    }
+}
+
+#[cfg(feature = "espnow")]
+fn lock(aggregator: &Mutex<Aggregator>) -> MutexGuard<'_, Aggregator> {
+    aggregator
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Register `mac` with ESP-NOW, which only sends to known peers
+#[cfg(feature = "espnow")]
+fn ensure_peer(espnow: &EspNow<'_>, mac: Mac) {
+    if espnow.peer_exists(mac).unwrap_or(false) {
+        return;
+    }
+    let peer = PeerInfo {
+        peer_addr: mac,
+        // The channel the radio is on
+        channel: 0,
+        encrypt: false,
+        ..Default::default()
+    };
+    if let Err(err) = espnow.add_peer(peer) {
+        warn!("Failed to add ESP-NOW peer {}: {:?}", format_mac(&mac), err);
+    }
+}
+
+/// Start the task sending the weight to the aggregator, or summing the
+/// corners, as the role of the settings says. Pairings are sent as
+/// commands to the main loop, which saves them and configures the task.
+#[cfg(feature = "espnow")]
+pub fn start_espnow_task(
+    settings: &Settings,
+    snapshot: SharedSnapshot,
+    commands: CommandSender,
+) -> anyhow::Result<EspNowHandle> {
+    let role = settings.espnow_role();
+    let (requests, request_receiver) = channel();
+    let handle = EspNowHandle {
+        role,
+        aggregator: Arc::new(Mutex::new(Aggregator::new(
+            settings.espnow_peers(),
+            settings.espnow_stale(),
+        ))),
+        pairing: Arc::new(AtomicBool::new(false)),
+        requests,
+    };
+    let mut peers = settings.espnow_peers().to_vec();
+    let mut target = settings.espnow_aggregator();
+    let aggregator = handle.aggregator.clone();
+    let pairing = handle.pairing.clone();
+
+    std::thread::Builder::new()
+        .name("espnow".to_string())
+        .stack_size(ESPNOW_TASK_STACK_SIZE)
+        .spawn(move || {
+            let watchdog = WatchdogGuard::subscribe("espnow");
+            // Up once the Wi-Fi task started the radio
+            let espnow = loop {
+                watchdog.feed();
+                match EspNow::take() {
+                    Ok(espnow) => break espnow,
+                    Err(err) => debug!("ESP-NOW not up yet: {:?}", err),
+                }
+                std::thread::sleep(INIT_RETRY_PERIOD);
+            };
+            let (packet_sender, packets) = channel();
+            let registered = espnow.register_recv_cb(move |info: &ReceiveInfo, data: &[u8]| {
+                if let Some(message) = Message::decode(data) {
+                    let _ = packet_sender.send((*info.src_addr, message));
+                }
+            });
+            if let Err(err) = registered {
+                warn!("Failed to receive over ESP-NOW: {:?}", err);
+                return;
+            }
+            ensure_peer(&espnow, BROADCAST);
+            if let Some(mac) = target {
+                ensure_peer(&espnow, mac);
+            }
+            info!("ESP-NOW up as {}", role.name());
+
+            let mut pairing_until: Option<Instant> = None;
+            let mut last_sent: Option<Instant> = None;
+            let mut last_pair: Option<Instant> = None;
+            loop {
+                watchdog.feed();
+                let received = packets.recv_timeout(TASK_TICK);
+                let now = Instant::now();
+                if pairing_until.is_some_and(|until| now >= until) {
+                    pairing_until = None;
+                    info!("ESP-NOW pairing closed");
+                }
+
+                while let Ok(request) = request_receiver.try_recv() {
+                    match request {
+                        EspNowRequest::Pair => {
+                            pairing_until = Some(now + PAIRING_WINDOW);
+                            last_pair = None;
+                            info!("ESP-NOW pairing open for {:?}", PAIRING_WINDOW);
+                        }
+                        EspNowRequest::Configure {
+                            peers: configured,
+                            stale_after,
+                            aggregator: configured_target,
+                        } => {
+                            lock(&aggregator).configure(&configured, stale_after);
+                            peers = configured;
+                            target = configured_target;
+                            if let Some(mac) = target {
+                                ensure_peer(&espnow, mac);
+                            }
+                        }
+                    }
+                }
+
+                match received {
+                    Ok((mac, Message { origin, packet })) => match (role, packet) {
+                        (EspNowRole::Aggregator, Packet::Weight { grams, quality }) => {
+                            match lock(&aggregator).on_weight(&origin, grams, quality, now) {
+                                Received::Taken => {}
+                                Received::Unpaired => {
+                                    debug!("Weight from unpaired {}", origin.device_id)
+                                }
+                                Received::Repeated => {
+                                    debug!("Repeated seq {} of {}", origin.seq, origin.device_id)
+                                }
+                            }
+                        }
+                        (EspNowRole::Aggregator, Packet::Pair) if pairing_until.is_some() => {
+                            let index = match admit(&peers, mac) {
+                                Admission::Known(index) => Some(index),
+                                Admission::New(index) => {
+                                    peers.push(Peer::new(mac, index));
+                                    let setting = EspNowSetting::AddPeer(mac, None);
+                                    if commands.send(Command::SetEspNow(setting)).is_err() {
+                                        warn!("Pairing dropped, the command channel is closed");
+                                    }
+                                    Some(index)
+                                }
+                                Admission::Full => {
+                                    warn!(
+                                        "No room for {}, at most {} corners",
+                                        format_mac(&mac),
+                                        MAX_PEERS
+                                    );
+                                    None
+                                }
+                            };
+                            // Answered again when known, its first answer may have been lost
+                            if let Some(peer) = index.and_then(|index| peers.get(index)) {
+                                ensure_peer(&espnow, mac);
+                                if let Err(err) =
+                                    espnow.send(mac, &Message::stamped(Packet::PairAck).encode())
+                                {
+                                    warn!("Failed to answer {}: {:?}", format_mac(&mac), err);
+                                }
+                                info!("Paired {}", peer);
+                            }
+                        }
+                        (EspNowRole::Corner, Packet::PairAck) if pairing_until.is_some() => {
+                            pairing_until = None;
+                            target = Some(mac);
+                            ensure_peer(&espnow, mac);
+                            let setting = EspNowSetting::Aggregator(Some(mac));
+                            if commands.send(Command::SetEspNow(setting)).is_err() {
+                                warn!("Pairing dropped, the command channel is closed");
+                            }
+                            info!("Paired with the aggregator {}", format_mac(&mac));
+                        }
+                        _ => {}
+                    },
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                pairing.store(pairing_until.is_some(), Ordering::Relaxed);
+
+                if role != EspNowRole::Corner {
+                    continue;
+                }
+                if pairing_until.is_some()
+                    && !last_pair.is_some_and(|at| now.duration_since(at) < PAIR_PERIOD)
+                {
+                    last_pair = Some(now);
+                    if let Err(err) =
+                        espnow.send(BROADCAST, &Message::stamped(Packet::Pair).encode())
+                    {
+                        warn!("Failed to ask for pairing: {:?}", err);
+                    }
+                }
+                let Some(mac) = target else {
+                    continue;
+                };
+                if last_sent.is_some_and(|at| now.duration_since(at) < SEND_PERIOD) {
+                    continue;
+                }
+                last_sent = Some(now);
+                // Nothing to send before the first reading
+                let Some(reading) = snapshot.get().reading() else {
+                    continue;
+                };
+                let packet = Packet::Weight {
+                    grams: reading.grams,
+                    quality: reading.quality,
+                };
+                if let Err(err) = espnow.send(mac, &Message::stamped(packet).encode()) {
+                    debug!("Failed to send the weight: {:?}", err);
+                }
+            }
+        })?;
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FL: Mac = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+    const FR: Mac = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x02];
+    const STALE: Duration = Duration::from_secs(2);
+
+    fn peers() -> Vec<Peer> {
+        vec![Peer::new(FL, 0), Peer::new(FR, 1)]
+    }
+
+    /// Origin of the packet `seq` of the first boot of the corner at `mac`
+    fn from(mac: Mac, seq: u32) -> Origin {
+        Origin {
+            device_id: device::device_id(&mac),
+            boot: 1,
+            seq,
+        }
+    }
+
+    #[test]
+    fn mac_round_trip() {
+        assert_eq!(format_mac(&FL), "24:0a:c4:00:00:01");
+        assert_eq!(parse_mac("24:0a:c4:00:00:01"), Some(FL));
+        assert_eq!(parse_mac("24-0A-C4-00-00-02"), Some(FR));
+        assert_eq!(parse_mac("24:0a:c4:00:00"), None);
+        assert_eq!(parse_mac("24:0a:c4:00:00:01:02"), None);
+        assert_eq!(parse_mac("24:0a:c4:00:00:1"), None);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let weight = Packet::Weight {
+            grams: -12.5,
+            quality: Quality::Settling,
+        };
+        let origin = Origin {
+            device_id: "scale_240ac4000001".to_string(),
+            boot: 0x0102_0304,
+            seq: u32::MAX,
+        };
+        for packet in [weight, Packet::Pair, Packet::PairAck] {
+            let message = Message {
+                origin: origin.clone(),
+                packet,
+            };
+            assert_eq!(Message::decode(&message.encode()), Some(message));
+        }
+
+        let bytes = Message {
+            origin,
+            packet: weight,
+        }
+        .encode();
+        // Magic, version, kind and the length of the device ID, which the
+        // boot and the sequence number follow little endian
+        assert_eq!(bytes[..4], [MAGIC, PACKET_VERSION, KIND_WEIGHT, 18]);
+        assert_eq!(bytes[4..22], *b"scale_240ac4000001");
+        assert_eq!(bytes[22..26], [0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(bytes[26..30], [0xff; 4]);
+        // Every cut is refused, down to the quality of the weight
+        for len in 0..bytes.len() {
+            assert_eq!(Message::decode(&bytes[..len]), None, "cut at {}", len);
+        }
+        // Someone else's traffic, or the first version without an origin
+        let mut other = bytes.clone();
+        other[0] = 0x00;
+        assert_eq!(Message::decode(&other), None);
+        assert_eq!(Message::decode(&[MAGIC, 1, KIND_PAIR]), None);
+    }
+
+    #[test]
+    fn stamped_messages_carry_the_identity() {
+        let first = Message::stamped(Packet::Pair).origin;
+        let second = Message::stamped(Packet::Pair).origin;
+        assert_eq!(first.device_id, device::identity().device_id());
+        assert!(second.seq > first.seq);
+    }
+
+    #[test]
+    fn sums_fresh_corners() {
+        let start = Instant::now();
+        let mut aggregator = Aggregator::new(&peers(), STALE);
+        assert!(!aggregator.aggregate(start).valid);
+
+        let taken = aggregator.on_weight(&from(FL, 1), 10.0, Quality::Good, start);
+        assert_eq!(taken, Received::Taken);
+        let aggregate = aggregator.aggregate(start);
+        // The right corner never sent
+        assert!(!aggregate.valid);
+        assert_eq!(aggregate.stale_labels().collect::<Vec<_>>(), ["C2"]);
+
+        aggregator.on_weight(&from(FR, 1), 15.5, Quality::Good, start);
+        let aggregate = aggregator.aggregate(start + Duration::from_secs(1));
+        assert!(aggregate.valid);
+        assert!(aggregate.stable);
+        assert_eq!(aggregate.grams, 25.5);
+        assert_eq!(aggregate.corners[1].age_s, Some(1));
+
+        // Unknown devices are left out
+        let stranger = aggregator.on_weight(&from([0; 6], 1), 100.0, Quality::Good, start);
+        assert_eq!(stranger, Received::Unpaired);
+        assert_eq!(aggregator.aggregate(start).grams, 25.5);
+    }
+
+    #[test]
+    fn corners_are_keyed_on_the_device_id() {
+        let start = Instant::now();
+        let mut aggregator = Aggregator::new(&peers(), STALE);
+        let origin = Origin {
+            device_id: "scale_240ac4000002".to_string(),
+            boot: 1,
+            seq: 1,
+        };
+        aggregator.on_weight(&origin, 15.5, Quality::Good, start);
+        let aggregate = aggregator.aggregate(start);
+        assert_eq!(aggregate.corners[0].grams, None);
+        assert_eq!(aggregate.corners[1].grams, Some(15.5));
+    }
+
+    #[test]
+    fn repeated_and_overtaken_packets_are_dropped() {
+        let start = Instant::now();
+        let later = start + Duration::from_secs(1);
+        let mut aggregator = Aggregator::new(&peers(), STALE);
+        aggregator.on_weight(&from(FL, 5), 10.0, Quality::Good, start);
+
+        // The same packet again, then one sent before it
+        let repeated = aggregator.on_weight(&from(FL, 5), 12.0, Quality::Good, later);
+        assert_eq!(repeated, Received::Repeated);
+        let overtaken = aggregator.on_weight(&from(FL, 4), 9.0, Quality::Good, later);
+        assert_eq!(overtaken, Received::Repeated);
+        let aggregate = aggregator.aggregate(later);
+        assert_eq!(aggregate.corners[0].grams, Some(10.0));
+        assert_eq!(aggregate.corners[0].age_s, Some(1));
+
+        // Sequences of corners are their own
+        let other = aggregator.on_weight(&from(FR, 1), 5.0, Quality::Good, later);
+        assert_eq!(other, Received::Taken);
+
+        // A restart of the corner starts its sequence over
+        let restarted = Origin {
+            boot: 2,
+            ..from(FL, 1)
+        };
+        let taken = aggregator.on_weight(&restarted, 11.0, Quality::Good, later);
+        assert_eq!(taken, Received::Taken);
+        assert_eq!(aggregator.aggregate(later).grams, 16.0);
+    }
+
+    #[test]
+    fn stale_corner_invalidates_the_total() {
+        let start = Instant::now();
+        let mut aggregator = Aggregator::new(&peers(), STALE);
+        aggregator.on_weight(&from(FL, 1), 10.0, Quality::Good, start);
+        aggregator.on_weight(&from(FR, 1), 10.0, Quality::Settling, start + STALE);
+
+        let aggregate = aggregator.aggregate(start + STALE);
+        assert!(!aggregate.valid);
+        assert!(!aggregate.stable);
+        assert_eq!(aggregate.grams, 20.0);
+        assert_eq!(aggregate.stale_labels().collect::<Vec<_>>(), ["C1"]);
+
+        // Fresh but its sensor stopped converting
+        aggregator.on_weight(&from(FL, 2), 10.0, Quality::Stale, start + STALE);
+        assert!(!aggregator.aggregate(start + STALE).valid);
+    }
+
+    #[test]
+    fn configure_keeps_the_weights_of_known_peers() {
+        let start = Instant::now();
+        let mut aggregator = Aggregator::new(&peers(), STALE);
+        aggregator.on_weight(&from(FR, 7), 15.0, Quality::Good, start);
+
+        let relabelled = vec![Peer {
+            mac: FR,
+            label: "Back".to_string(),
+        }];
+        aggregator.configure(&relabelled, STALE);
+        let aggregate = aggregator.aggregate(start);
+        assert!(aggregate.valid);
+        assert_eq!(aggregate.corners[0].label, "Back");
+        assert_eq!(aggregate.corners[0].grams, Some(15.0));
+        // Along with the sequence it got to
+        let repeated = aggregator.on_weight(&from(FR, 7), 1.0, Quality::Good, start);
+        assert_eq!(repeated, Received::Repeated);
+    }
+
+    #[test]
+    fn admits_up_to_the_peer_limit() {
+        let mut peers = peers();
+        assert_eq!(admit(&peers, FR), Admission::Known(1));
+        assert_eq!(admit(&peers, [1; 6]), Admission::New(2));
+        while peers.len() < MAX_PEERS {
+            peers.push(Peer::new([peers.len() as u8; 6], peers.len()));
+        }
+        assert_eq!(admit(&peers, [0xff; 6]), Admission::Full);
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    button::TimedButtonEvent,
+    filter::Sample,
+    power::IdleStage,
+    sink::{OutputSink, SinkError, SinkResult},
+    unit::Unit,
+};
+
+/// Number of events buffered per subscriber before new ones are dropped
+const SUBSCRIBER_QUEUE_LEN: usize = 16;
+
+/// Something that happened to the weight, as seen by other tasks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WeightEvent {
+    /// The filtered weight changed
+    Changed {
+        grams: f32,
+        stable: bool,
+    },
+    /// The weight settled after changing
+    Stable {
+        grams: f32,
+    },
+    Tared,
+    Calibrated {
+        scale_factor: f32,
+    },
+    /// The display unit changed
+    UnitChanged(Unit),
+}
+
+/// Which `Changed` events a subscriber is sent: those that moved by the
+/// delta since the last one sent, once the interval passed. A change of the
+/// stability always goes out, as does the first stable weight after a tare,
+/// and the other events are never held back.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChangeThreshold {
+    pub min_delta_grams: f32,
+    pub min_interval: Duration,
+}
+
+/// The `Changed` events last sent to a subscriber, measured against its
+/// threshold
+#[derive(Debug, Default)]
+struct ChangeGate {
+    threshold: ChangeThreshold,
+    /// Weight and stability of the last `Changed` sent, with when
+    last_sent: Option<(f32, bool, Instant)>,
+    /// Whether the next stable weight goes out whatever the threshold
+    after_tare: bool,
+}
+
+impl ChangeGate {
+    /// Whether the event is to be sent, noting a tare or a calibration
+    fn wants(&mut self, event: &WeightEvent, now: Instant) -> bool {
+        if matches!(event, WeightEvent::Tared | WeightEvent::Calibrated { .. }) {
+            self.after_tare = true;
+        }
+        let WeightEvent::Changed { grams, stable } = *event else {
+            return true;
+        };
+        let Some((last_grams, last_stable, at)) = self.last_sent else {
+            return true;
+        };
+        (stable && self.after_tare)
+            || stable != last_stable
+            || ((grams - last_grams).abs() >= self.threshold.min_delta_grams
+                && now.duration_since(at) >= self.threshold.min_interval)
+    }
+
+    /// The event was sent, the next change is measured against it
+    fn sent(&mut self, event: &WeightEvent, now: Instant) {
+        if let WeightEvent::Changed { grams, stable } = *event {
+            self.last_sent = Some((grams, stable, now));
+            self.after_tare &= !stable;
+        }
+    }
+}
+
+/// Gate of a subscriber, shared with its sink
+type SharedGate = Arc<Mutex<ChangeGate>>;
+
+fn lock(gate: &SharedGate) -> MutexGuard<'_, ChangeGate> {
+    gate.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct Subscriber {
+    sender: SyncSender<WeightEvent>,
+    gate: SharedGate,
+    /// Whether the `Changed` events are sent, they come through a
+    /// `WeightSink` otherwise
+    changes: bool,
+}
+
+impl Subscriber {
+    /// Returns false once the receiver is gone
+    fn send(&mut self, event: WeightEvent, now: Instant) -> bool {
+        let mut gate = lock(&self.gate);
+        if !gate.wants(&event, now) {
+            return true;
+        }
+        if !self.changes && matches!(event, WeightEvent::Changed { .. }) {
+            return true;
+        }
+        match self.sender.try_send(event) {
+            Ok(()) => {
+                gate.sent(&event, now);
+                true
+            }
+            // Missed, the next change is measured against the last one sent
+            Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// Sink passing the samples on to a subscribed task as `Changed` events, at
+/// the rate it is throttled to in the `SinkManager` and within the threshold
+/// of the subscriber. The samples held back by the threshold count as
+/// delivered.
+#[derive(Clone)]
+pub struct WeightSink {
+    name: &'static str,
+    sender: SyncSender<WeightEvent>,
+    gate: SharedGate,
+}
+
+impl OutputSink for WeightSink {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn offer(&mut self, sample: &Sample) -> SinkResult {
+        let event = WeightEvent::Changed {
+            grams: sample.grams_filtered,
+            stable: sample.stable,
+        };
+        let now = Instant::now();
+        let mut gate = lock(&self.gate);
+        if !gate.wants(&event, now) {
+            return Ok(());
+        }
+        match self.sender.try_send(event) {
+            Ok(()) => {
+                gate.sent(&event, now);
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => Err(SinkError::Full),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(SinkError::Failed("The task stopped".to_string()))
+            }
+        }
+    }
+}
+
+/// Fans weight events out to the subscribed tasks. A subscriber that falls
+/// behind misses events instead of stalling the sampling loop, and dropped
+/// subscribers are forgotten.
+#[derive(Default)]
+pub struct WeightEvents {
+    subscribers: Vec<Subscriber>,
+}
+
+impl WeightEvents {
+    /// Receive every event
+    pub fn subscribe(&mut self) -> Receiver<WeightEvent> {
+        self.subscribe_with_threshold(ChangeThreshold::default())
+    }
+
+    /// Receive the changes of the weight that pass the threshold, and every
+    /// other event
+    pub fn subscribe_with_threshold(
+        &mut self,
+        threshold: ChangeThreshold,
+    ) -> Receiver<WeightEvent> {
+        let (tx, rx) = sync_channel(SUBSCRIBER_QUEUE_LEN);
+        self.subscribers.push(Subscriber {
+            sender: tx,
+            gate: Self::gate(threshold),
+            changes: true,
+        });
+        rx
+    }
+
+    /// Receive every event but the changes of the weight, which the sink
+    /// named `name` passes on from the samples once registered, those that
+    /// pass the threshold
+    pub fn subscribe_through_sink(
+        &mut self,
+        name: &'static str,
+        threshold: ChangeThreshold,
+    ) -> (Receiver<WeightEvent>, WeightSink) {
+        let (tx, rx) = sync_channel(SUBSCRIBER_QUEUE_LEN);
+        let gate = Self::gate(threshold);
+        self.subscribers.push(Subscriber {
+            sender: tx.clone(),
+            gate: gate.clone(),
+            changes: false,
+        });
+        (
+            rx,
+            WeightSink {
+                name,
+                sender: tx,
+                gate,
+            },
+        )
+    }
+
+    pub fn publish(&mut self, event: WeightEvent) {
+        let now = Instant::now();
+        self.subscribers
+            .retain_mut(|subscriber| subscriber.send(event, now));
+    }
+
+    fn gate(threshold: ChangeThreshold) -> SharedGate {
+        Arc::new(Mutex::new(ChangeGate {
+            threshold,
+            ..ChangeGate::default()
+        }))
+    }
+}
+
+/// Something the main loop reacts to. The producers all send through one
+/// channel, so the main loop sleeps until there is something to do.
+#[derive(Clone, Copy, Debug)]
+pub enum AppEvent {
+    /// Raw sensor counts read by the sampling task, disturbed when converted
+    /// during a display flush
+    Reading {
+        raw: i32,
+        at: Instant,
+        disturbed: bool,
+    },
+    /// The button changed, the gesture is recognized by the main loop
+    Button(TimedButtonEvent),
+    /// The idle scale goes to another stage, or back to active
+    Idle(IdleStage),
+    /// Nothing else came in for a tick, the timeouts and the slowly changing
+    /// state are checked then too
+    Tick,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quality::Quality;
+
+    /// Never long enough to pass during a test
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn changed(grams: f32, stable: bool) -> WeightEvent {
+        WeightEvent::Changed { grams, stable }
+    }
+
+    fn sample(grams: f32, stable: bool) -> Sample {
+        Sample {
+            raw: 0,
+            grams_raw: grams,
+            grams_filtered: grams,
+            stable,
+            quality: Quality::of(stable, false, false),
+        }
+    }
+
+    fn received(events: &Receiver<WeightEvent>) -> Vec<WeightEvent> {
+        events.try_iter().collect()
+    }
+
+    #[test]
+    fn changes_below_the_delta_are_held_back() {
+        let mut weight_events = WeightEvents::default();
+        let all = weight_events.subscribe();
+        let thresholded = weight_events.subscribe_with_threshold(ChangeThreshold {
+            min_delta_grams: 5.0,
+            min_interval: Duration::ZERO,
+        });
+        for event in [
+            changed(100.0, false),
+            changed(102.0, false),
+            changed(106.0, false),
+            // The stability changed, within the delta
+            changed(106.5, true),
+            WeightEvent::Stable { grams: 106.5 },
+            changed(107.0, true),
+        ] {
+            weight_events.publish(event);
+        }
+        assert_eq!(received(&all).len(), 6);
+        assert_eq!(
+            received(&thresholded),
+            [
+                changed(100.0, false),
+                changed(106.0, false),
+                changed(106.5, true),
+                WeightEvent::Stable { grams: 106.5 },
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_wait_for_the_interval() {
+        let mut weight_events = WeightEvents::default();
+        let events = weight_events.subscribe_with_threshold(ChangeThreshold {
+            min_delta_grams: 1.0,
+            min_interval: HOUR,
+        });
+        for event in [
+            changed(100.0, false),
+            changed(200.0, false),
+            changed(300.0, true),
+            changed(400.0, true),
+        ] {
+            weight_events.publish(event);
+        }
+        assert_eq!(
+            received(&events),
+            [changed(100.0, false), changed(300.0, true)]
+        );
+    }
+
+    #[test]
+    fn first_stable_weight_after_a_tare_goes_out() {
+        let mut weight_events = WeightEvents::default();
+        let events = weight_events.subscribe_with_threshold(ChangeThreshold {
+            min_delta_grams: 5.0,
+            min_interval: HOUR,
+        });
+        for event in [
+            changed(250.0, true),
+            WeightEvent::Tared,
+            changed(0.0, true),
+            changed(0.5, true),
+            WeightEvent::Calibrated { scale_factor: 2.0 },
+            changed(0.8, true),
+        ] {
+            weight_events.publish(event);
+        }
+        assert_eq!(
+            received(&events),
+            [
+                changed(250.0, true),
+                WeightEvent::Tared,
+                changed(0.0, true),
+                WeightEvent::Calibrated { scale_factor: 2.0 },
+                changed(0.8, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_sink_keeps_the_threshold() {
+        let mut weight_events = WeightEvents::default();
+        let (events, mut sink) = weight_events.subscribe_through_sink(
+            "test",
+            ChangeThreshold {
+                min_delta_grams: 5.0,
+                min_interval: HOUR,
+            },
+        );
+        // The changes only come through the sink
+        weight_events.publish(changed(1.0, false));
+        assert_eq!(received(&events), []);
+
+        for grams in [250.0, 252.0, 300.0] {
+            assert_eq!(sink.offer(&sample(grams, true)), Ok(()));
+        }
+        weight_events.publish(WeightEvent::Tared);
+        for grams in [0.0, 0.5] {
+            assert_eq!(sink.offer(&sample(grams, true)), Ok(()));
+        }
+        assert_eq!(
+            received(&events),
+            [changed(250.0, true), WeightEvent::Tared, changed(0.0, true)]
+        );
+
+        drop(events);
+        assert!(matches!(
+            sink.offer(&sample(0.0, false)),
+            Err(SinkError::Failed(_))
+        ));
+    }
+}
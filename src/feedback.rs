@@ -0,0 +1,160 @@
+use std::sync::{mpsc::Receiver, Arc, Mutex};
+
+use crate::{events::WeightEvent, power::IdleStage, settings::Settings};
+
+const FEEDBACK_TASK_STACK_SIZE: usize = 3 * 1024;
+/// Fraction of the target the weight has to drop below before reaching the
+/// target is signalled again
+const TARGET_REARM_FRACTION: f32 = 0.9;
+
+/// Something worth signalling to the user through the buzzer or the LED
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feedback {
+    /// The weight started changing
+    Settling,
+    /// The weight settled
+    Settled,
+    Taring,
+    Tared,
+    Calibrating,
+    Calibrated,
+    CalibrationFailed,
+    /// The weight rose to the target weight
+    TargetReached,
+    /// The weight went past the capacity of the scale
+    Overload,
+    BatteryLow,
+    /// Asked to point out which device this is
+    Identify,
+    /// An alarm tripped, or is still latched after the re-notify interval
+    AlarmTripped,
+    /// No alarm is latched anymore
+    AlarmCleared,
+    /// The firmware stopped on an error and is about to restart
+    Fault,
+    /// The idle scale went to another stage
+    Idle(IdleStage),
+}
+
+type Listener = Box<dyn Fn(Feedback) + Send>;
+
+/// Central dispatcher the feedback devices subscribe to. Listeners run on
+/// the notifying thread, so they must only hand the feedback off.
+#[derive(Clone, Default)]
+pub struct FeedbackDispatcher {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+    /// Weight signalled once reached
+    target_grams: Arc<Mutex<Option<f32>>>,
+}
+
+impl FeedbackDispatcher {
+    /// Change the weight signalled once reached, `None` to stop watching it
+    pub fn set_target(&self, grams: Option<f32>) {
+        *self
+            .target_grams
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = grams;
+    }
+
+    pub fn target(&self) -> Option<f32> {
+        *self
+            .target_grams
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn subscribe(&self, listener: impl Fn(Feedback) + Send + 'static) {
+        self.listeners
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(Box::new(listener));
+    }
+
+    pub fn notify(&self, feedback: Feedback) {
+        let listeners = self
+            .listeners
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for listener in listeners.iter() {
+            listener(feedback);
+        }
+    }
+}
+
+/// Tracks the weight against the target and the capacity
+pub struct WeightWatch {
+    capacity: Option<f32>,
+    target_armed: bool,
+    overloaded: bool,
+    stable: bool,
+}
+
+impl WeightWatch {
+    pub fn new(capacity: Option<f32>) -> Self {
+        Self {
+            capacity,
+            target_armed: true,
+            overloaded: false,
+            stable: false,
+        }
+    }
+
+    /// What the event is worth signalling, with the weight signalled once
+    /// reached
+    pub fn on_event(&mut self, event: WeightEvent, target: Option<f32>) -> Option<Feedback> {
+        match event {
+            WeightEvent::Tared => {
+                self.target_armed = true;
+                Some(Feedback::Tared)
+            }
+            WeightEvent::Calibrated { .. } => Some(Feedback::Calibrated),
+            WeightEvent::Changed { grams, stable } => {
+                let overloaded = self.capacity.is_some_and(|capacity| grams > capacity);
+                let was_overloaded = std::mem::replace(&mut self.overloaded, overloaded);
+                let was_stable = std::mem::replace(&mut self.stable, stable);
+                if overloaded {
+                    return (!was_overloaded).then_some(Feedback::Overload);
+                }
+
+                if let Some(target) = target {
+                    if grams < target * TARGET_REARM_FRACTION {
+                        self.target_armed = true;
+                    } else if grams >= target && self.target_armed {
+                        self.target_armed = false;
+                        return Some(Feedback::TargetReached);
+                    }
+                }
+                if was_overloaded {
+                    return Some(match stable {
+                        true => Feedback::Settled,
+                        false => Feedback::Settling,
+                    });
+                }
+                (was_stable && !stable).then_some(Feedback::Settling)
+            }
+            WeightEvent::Stable { .. } if !self.overloaded => Some(Feedback::Settled),
+            WeightEvent::Stable { .. } | WeightEvent::UnitChanged(_) => None,
+        }
+    }
+}
+
+/// Start turning the weight events into feedback
+pub fn start_feedback_task(
+    dispatcher: FeedbackDispatcher,
+    events: Receiver<WeightEvent>,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    dispatcher.set_target(settings.target_grams());
+    let mut watch = WeightWatch::new(settings.capacity_grams());
+    std::thread::Builder::new()
+        .name("feedback".to_string())
+        .stack_size(FEEDBACK_TASK_STACK_SIZE)
+        .spawn(move || {
+            while let Ok(event) = events.recv() {
+                if let Some(feedback) = watch.on_event(event, dispatcher.target()) {
+                    dispatcher.notify(feedback);
+                }
+            }
+        })?;
+    Ok(())
+}
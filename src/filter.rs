@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+
+use crate::{quality::Quality, sensor::SampleRate};
+
+/// Number of samples averaged by the default filter
+pub const DEFAULT_FILTER_WINDOW: usize = 8;
+/// Spread in grams the filter window must stay within to be considered stable
+pub const DEFAULT_STABLE_BAND_GRAMS: f32 = 2.0;
+
+/// A single conversion run through the filter pipeline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub raw: i32,
+    pub grams_raw: f32,
+    pub grams_filtered: f32,
+    pub stable: bool,
+    pub quality: Quality,
+}
+
+/// Moving average over the last samples, with a stability detector
+pub struct WeightFilter {
+    /// Samples along with their weight in the average
+    window: VecDeque<(f32, f32)>,
+    capacity: usize,
+    stable_band: f32,
+}
+
+impl Default for WeightFilter {
+    fn default() -> Self {
+        Self::new(DEFAULT_FILTER_WINDOW, DEFAULT_STABLE_BAND_GRAMS)
+    }
+}
+
+impl WeightFilter {
+    pub fn new(capacity: usize, stable_band: f32) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            window: VecDeque::with_capacity(capacity),
+            capacity,
+            stable_band,
+        }
+    }
+
+    /// Samples averaged
+    pub fn window(&self) -> usize {
+        self.capacity
+    }
+
+    /// Spread in grams the window stays within once stable
+    pub fn stable_band(&self) -> f32 {
+        self.stable_band
+    }
+
+    /// Empty filter averaging over as long at the rate `to` as this one does
+    /// at `from`, within the same band
+    pub fn rescaled(&self, from: SampleRate, to: SampleRate) -> Self {
+        Self::new(to.samples_in(from.time_of(self.capacity)), self.stable_band)
+    }
+
+    /// Add a sample, returning the filtered value
+    pub fn push(&mut self, grams: f32) -> f32 {
+        self.push_weighted(grams, 1.0)
+    }
+
+    /// Add a sample counting for `weight` in the average, e.g. less than 1
+    /// for one that is likely disturbed
+    pub fn push_weighted(&mut self, grams: f32, weight: f32) -> f32 {
+        if self.window.len() == self.capacity {
+            self.window.pop_front();
+        }
+        self.window.push_back((grams, weight.max(f32::EPSILON)));
+        self.value()
+    }
+
+    /// Weighted average of the samples in the window
+    pub fn value(&self) -> f32 {
+        let (sum, weights) = self
+            .window
+            .iter()
+            .fold((0.0, 0.0), |(sum, weights), &(grams, weight)| {
+                (sum + grams * weight, weights + weight)
+            });
+        if weights <= 0.0 {
+            return 0.0;
+        }
+        sum / weights
+    }
+
+    /// Whether the window is full and its samples stay within the stable band
+    pub fn is_stable(&self) -> bool {
+        if self.window.len() < self.capacity {
+            return false;
+        }
+        let (min, max) = self
+            .window
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), &(grams, _)| {
+                (min.min(grams), max.max(grams))
+            });
+        max - min <= self.stable_band
+    }
+
+    /// Drop all samples, e.g. after the zero point changed
+    pub fn reset(&mut self) {
+        self.window.clear();
+    }
+
+    /// Move the samples in the window by `grams`, for a zero that moved by
+    /// as much under the same load: the filtered value steps at once instead
+    /// of sagging over the window, and the stability is kept
+    pub fn rebase(&mut self, grams: f32) {
+        for (sample, _) in &mut self.window {
+            *sample += grams;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::brew::FlowMeter;
+
+    #[test]
+    fn averages_the_window() {
+        let mut filter = WeightFilter::new(4, 2.0);
+        assert_eq!(filter.value(), 0.0);
+        assert_eq!(filter.push(10.0), 10.0);
+        assert_eq!(filter.push(20.0), 15.0);
+        for grams in [30.0, 40.0] {
+            filter.push(grams);
+        }
+        assert_eq!(filter.value(), 25.0);
+        // The oldest sample leaves the window
+        assert_eq!(filter.push(50.0), 35.0);
+    }
+
+    #[test]
+    fn weighted_samples() {
+        let mut filter = WeightFilter::new(4, 2.0);
+        filter.push(100.0);
+        assert_eq!(filter.push_weighted(200.0, 0.25), 120.0);
+        // Never left without any weight at all
+        let mut filter = WeightFilter::new(4, 2.0);
+        assert_eq!(filter.push_weighted(50.0, 0.0), 50.0);
+    }
+
+    #[test]
+    fn stable_once_full_and_within_the_band() {
+        let mut filter = WeightFilter::new(4, 2.0);
+        for grams in [100.0, 101.0, 99.5] {
+            filter.push(grams);
+            assert!(!filter.is_stable());
+        }
+        filter.push(101.5);
+        assert!(filter.is_stable());
+        filter.push(102.0);
+        assert!(!filter.is_stable());
+
+        filter.reset();
+        assert!(!filter.is_stable());
+        assert_eq!(filter.value(), 0.0);
+    }
+
+    #[test]
+    fn rebase_keeps_the_stability() {
+        let mut filter = WeightFilter::new(4, 2.0);
+        for grams in [100.0, 100.5, 99.5, 100.0] {
+            filter.push(grams);
+        }
+        filter.rebase(-100.0);
+        assert!(filter.is_stable());
+        assert_eq!(filter.value(), 0.0);
+    }
+
+    /// Weight poured from 2s to 7s at 20 g/s, with a ripple within the band
+    fn poured(secs: f32) -> f32 {
+        let poured = 20.0 * (secs - 2.0).clamp(0.0, 5.0);
+        poured + 0.4 * (secs * 7.0).sin()
+    }
+
+    /// Replay the pour at the rate, with the default filter rescaled to it.
+    /// Returns the times the stability changed at and the flow rate halfway.
+    fn replay(rate: SampleRate) -> (Vec<f32>, f32) {
+        let mut filter = WeightFilter::default().rescaled(SampleRate::Sps10, rate);
+        let mut flow = FlowMeter::default();
+        let start = Instant::now();
+        let mut changes = Vec::new();
+        let mut stable = false;
+        let mut halfway = 0.0;
+        for i in 0..10 * rate.hz() {
+            let at = rate.time_of(i as usize);
+            let grams = filter.push(poured(at.as_secs_f32()));
+            flow.add(grams, start + at);
+            if filter.is_stable() != stable {
+                stable = !stable;
+                changes.push(at.as_secs_f32());
+            }
+            if at == Duration::from_millis(4500) {
+                halfway = flow.grams_per_sec();
+            }
+        }
+        (changes, halfway)
+    }
+
+    #[test]
+    fn same_trace_at_both_rates() {
+        let (slow_changes, slow_flow) = replay(SampleRate::Sps10);
+        let (fast_changes, fast_flow) = replay(SampleRate::Sps80);
+        // Stable before the pour, not during it, and again once it stopped
+        assert_eq!(slow_changes.len(), 3);
+        assert_eq!(fast_changes.len(), 3);
+        for (slow, fast) in slow_changes.iter().zip(&fast_changes) {
+            assert!((slow - fast).abs() <= 0.1, "{slow} {fast}");
+        }
+        assert!((slow_flow - 20.0).abs() < 0.5, "{slow_flow}");
+        assert!((fast_flow - 20.0).abs() < 0.5, "{fast_flow}");
+        assert!((slow_flow - fast_flow).abs() < 0.5);
+    }
+}
@@ -0,0 +1,217 @@
+//! Weights as shown on the display and sent in the payloads, formatted the
+//! same way everywhere.
+
+use std::fmt::Write;
+
+use crate::{unit::Unit, volume::Volume};
+
+/// A formatted weight, kept inline. 32 bytes hold any weight the scale
+/// shows with its sign, separators and decimals; a longer one is cut.
+pub type WeightText = heapless::String<32>;
+
+/// Gram weight from which on kilograms are shown
+const KILO_UP_GRAMS: f32 = 1100.0;
+/// Gram weight below which grams are shown again
+const KILO_DOWN_GRAMS: f32 = 950.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatOpts {
+    /// Decimals per unit, in the order of `Unit::ALL`
+    pub decimals: [usize; 4],
+    /// Step in grams the weight is rounded to, anything closer to 0 than
+    /// half of it shows as exactly 0
+    pub resolution_grams: f32,
+    /// Separator between the thousands of the integer part, if any
+    pub thousands_separator: Option<char>,
+    /// Whether a weight in grams is shown in kilograms, see `KiloSwitch`
+    pub kilo: bool,
+}
+
+impl Default for FormatOpts {
+    /// The decimals of `Unit::decimals`, with the weight unrounded and kept
+    /// in its unit
+    fn default() -> Self {
+        Self {
+            decimals: Unit::ALL.map(Unit::decimals),
+            resolution_grams: 0.0,
+            thousands_separator: None,
+            kilo: false,
+        }
+    }
+}
+
+impl FormatOpts {
+    /// Decimals for the display: none for grams unless the resolution is
+    /// finer than a gram, two for the other units
+    pub fn for_resolution(resolution_grams: f32) -> Self {
+        let gram_decimals = if resolution_grams < 1.0 { 1 } else { 0 };
+        Self {
+            decimals: [gram_decimals, 2, 2, 2],
+            resolution_grams,
+            ..Self::default()
+        }
+    }
+
+    fn decimals(&self, unit: Unit) -> usize {
+        self.decimals[unit.index() as usize]
+    }
+}
+
+/// Switches weights in grams over to kilograms and back, with some
+/// hysteresis so a weight around 1kg does not flicker between the two
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KiloSwitch {
+    kilo: bool,
+}
+
+impl KiloSwitch {
+    /// Follow the weight, returning whether it is shown in kilograms
+    pub fn update(&mut self, grams: f32) -> bool {
+        let grams = grams.abs();
+        if grams >= KILO_UP_GRAMS {
+            self.kilo = true;
+        } else if grams < KILO_DOWN_GRAMS {
+            self.kilo = false;
+        }
+        self.kilo
+    }
+
+    pub fn is_kilo(&self) -> bool {
+        self.kilo
+    }
+}
+
+/// Weight in milligrams, as taken by `format_weight`
+pub fn milligrams(grams: f32) -> i64 {
+    (f64::from(grams) * 1000.0).round() as i64
+}
+
+/// Unit a weight in `unit` is shown in, kilograms for grams when `kilo` is set
+pub fn shown_unit(unit: Unit, opts: &FormatOpts) -> Unit {
+    match unit {
+        Unit::Grams if opts.kilo => Unit::Kilograms,
+        unit => unit,
+    }
+}
+
+/// The number of a weight in `shown_unit(unit, opts)`, without the symbol.
+/// A weight that rounds to 0 never shows a sign.
+pub fn format_weight(milligrams: i64, unit: Unit, opts: &FormatOpts) -> WeightText {
+    let unit = shown_unit(unit, opts);
+    let value = unit.from_grams(rounded_grams(milligrams, opts));
+    format_number(value, opts.decimals(unit), opts)
+}
+
+/// The number of the milliliters a weight takes up, without the symbol.
+/// Shown with the decimals of grams and never switched to liters.
+pub fn format_volume(milligrams: i64, volume: &Volume, opts: &FormatOpts) -> WeightText {
+    let ml = volume.ml(rounded_grams(milligrams, opts));
+    format_number(ml, opts.decimals(Unit::Grams), opts)
+}
+
+/// Weight in grams, anything closer to 0 than half the resolution taken
+/// as 0
+fn rounded_grams(milligrams: i64, opts: &FormatOpts) -> f32 {
+    let grams = milligrams as f64 / 1000.0;
+    if grams.abs() < f64::from(opts.resolution_grams) / 2.0 {
+        0.0
+    } else {
+        grams as f32
+    }
+}
+
+fn format_number(value: f32, decimals: usize, opts: &FormatOpts) -> WeightText {
+    let mut formatted = WeightText::new();
+    let _ = write!(formatted, "{:.*}", decimals, value);
+
+    let (sign, digits) = match formatted.strip_prefix('-') {
+        Some(digits) if digits.bytes().any(|byte| (b'1'..=b'9').contains(&byte)) => ("-", digits),
+        Some(digits) => ("", digits),
+        None => ("", formatted.as_str()),
+    };
+    let mut grouped = WeightText::new();
+    let _ = grouped.push_str(sign);
+    let Some(separator) = opts.thousands_separator else {
+        let _ = grouped.push_str(digits);
+        return grouped;
+    };
+    let (integer, fraction) = match digits.find('.') {
+        Some(point) => digits.split_at(point),
+        None => (digits, ""),
+    };
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            let _ = grouped.push(separator);
+        }
+        let _ = grouped.push(digit);
+    }
+    let _ = grouped.push_str(fraction);
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kilo_switch_hysteresis() {
+        let mut kilo = KiloSwitch::default();
+        assert!(!kilo.update(1099.9));
+        assert!(kilo.update(1100.0));
+        // Stays in kilograms down to the lower threshold
+        assert!(kilo.update(950.0));
+        assert!(!kilo.update(949.9));
+        // And in grams up to the upper one
+        assert!(!kilo.update(1050.0));
+        assert!(kilo.update(-1100.0));
+        assert!(kilo.update(-950.0));
+        assert!(!kilo.update(-949.9));
+    }
+
+    #[test]
+    fn snaps_to_zero_within_half_the_resolution() {
+        let opts = FormatOpts::for_resolution(2.0);
+        assert_eq!(format_weight(-999, Unit::Grams, &opts), "0");
+        assert_eq!(format_weight(999, Unit::Grams, &opts), "0");
+        assert_eq!(format_weight(-1000, Unit::Grams, &opts), "-1");
+        assert_eq!(format_weight(1000, Unit::Grams, &opts), "1");
+        // Rounding to no digit but zeros never shows a sign either
+        let opts = FormatOpts::default();
+        assert_eq!(format_weight(-40, Unit::Grams, &opts), "0.0");
+    }
+
+    #[test]
+    fn thousands_separator() {
+        let opts = FormatOpts {
+            thousands_separator: Some(','),
+            ..FormatOpts::for_resolution(1.0)
+        };
+        assert_eq!(format_weight(999_000, Unit::Grams, &opts), "999");
+        assert_eq!(format_weight(1_000_000, Unit::Grams, &opts), "1,000");
+        assert_eq!(format_weight(-12_345_000, Unit::Grams, &opts), "-12,345");
+        assert_eq!(
+            format_weight(1_234_567_000, Unit::Grams, &opts),
+            "1,234,567"
+        );
+        let opts = FormatOpts {
+            thousands_separator: Some('\''),
+            ..FormatOpts::default()
+        };
+        assert_eq!(format_weight(1_234_500, Unit::Grams, &opts), "1'234.5");
+    }
+
+    #[test]
+    fn decimals_per_unit() {
+        let opts = FormatOpts::default();
+        assert_eq!(format_weight(1_234_567, Unit::Grams, &opts), "1234.6");
+        assert_eq!(format_weight(1_234_567, Unit::Kilograms, &opts), "1.235");
+        assert_eq!(format_weight(28_350, Unit::Ounces, &opts), "1.00");
+        assert_eq!(format_weight(453_592, Unit::Pounds, &opts), "1.00");
+        let opts = FormatOpts {
+            kilo: true,
+            ..FormatOpts::for_resolution(1.0)
+        };
+        assert_eq!(shown_unit(Unit::Grams, &opts), Unit::Kilograms);
+        assert_eq!(format_weight(1_234_000, Unit::Grams, &opts), "1.23");
+    }
+}
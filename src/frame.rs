@@ -0,0 +1,246 @@
+//! A screen drawn as a list of operations, each a region along with what
+//! goes into it. A frame is diffed against the one drawn before it and only
+//! the operations that changed are drawn, so changing the weight redraws the
+//! weight alone. The diff is handed back, for what a change of the state
+//! touched to be checked on the host against the simulator display.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use embedded_graphics::{mono_font::ascii::FONT_10X20, prelude::Point, primitives::Rectangle};
+use ssd1306::{prelude::WriteOnlyDataCommand, size::DisplaySize};
+
+use crate::{
+    status::{draw_status_icons, StatusIcon},
+    text_drawer::{DisplayError, TextDrawer, TextError},
+};
+
+/// What an operation draws into its region, cleared first
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DrawContent {
+    /// Text at the top left corner, in the font of the drawer
+    Text(String),
+    /// Text in the large font, `offset` from the top left corner
+    Large { text: String, offset: Point },
+    /// The status icons, the clock to the left and the rest right aligned
+    Icons(Vec<StatusIcon>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DrawOp {
+    pub region: Rectangle,
+    pub content: DrawContent,
+}
+
+impl DrawOp {
+    pub fn text(region: Rectangle, text: impl Into<String>) -> Self {
+        Self {
+            region,
+            content: DrawContent::Text(text.into()),
+        }
+    }
+
+    /// Hash of the region and the content, told apart from the last frame's
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn draw<DI, SIZE>(
+        &self,
+        text_drawer: &mut TextDrawer<DI, SIZE>,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+    where
+        DI: WriteOnlyDataCommand,
+        SIZE: DisplaySize,
+    {
+        text_drawer.clear_region(self.region)?;
+        match &self.content {
+            // Nothing to fit, the region is blank already
+            DrawContent::Text(text) if text.is_empty() => Ok(()),
+            DrawContent::Text(text) => text_drawer.draw_text(text, self.region.top_left),
+            DrawContent::Large { text, offset } => {
+                let char_style = text_drawer.style_with_font(&FONT_10X20);
+                text_drawer.draw_text_with_char_style(
+                    text,
+                    self.region.top_left + *offset,
+                    char_style,
+                )
+            }
+            DrawContent::Icons(icons) => draw_status_icons(text_drawer, icons),
+        }
+    }
+}
+
+/// What a frame changed on the screen
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameDiff {
+    /// Whether the whole screen was cleared first, every operation drawn
+    pub cleared: bool,
+    /// Regions of the operations drawn, in their order
+    pub redrawn: Vec<Rectangle>,
+    /// Regions of the last frame no operation draws into anymore, blanked
+    pub blanked: Vec<Rectangle>,
+}
+
+impl FrameDiff {
+    /// Whether the screen is left as it was
+    pub fn is_empty(&self) -> bool {
+        !self.cleared && self.redrawn.is_empty() && self.blanked.is_empty()
+    }
+
+    /// Whether the region was drawn or blanked
+    pub fn touched(&self, region: Rectangle) -> bool {
+        self.cleared || self.redrawn.contains(&region) || self.blanked.contains(&region)
+    }
+}
+
+/// The operations last drawn, by their region and hash
+#[derive(Debug, Default)]
+pub struct Frame {
+    /// None while what the screen shows is not known, e.g. before the first
+    /// frame or after something else drew on it
+    drawn: Option<Vec<(Rectangle, u64)>>,
+}
+
+impl Frame {
+    /// Forget what was drawn, the next frame clears the screen and draws
+    /// every operation
+    pub fn invalidate(&mut self) {
+        self.drawn = None;
+    }
+
+    /// What drawing the operations would change, without drawing them
+    pub fn diff(&self, ops: &[DrawOp]) -> FrameDiff {
+        let Some(drawn) = &self.drawn else {
+            return FrameDiff {
+                cleared: true,
+                redrawn: ops.iter().map(|op| op.region).collect(),
+                blanked: Vec::new(),
+            };
+        };
+        let redrawn = ops
+            .iter()
+            .filter(|op| !drawn.contains(&(op.region, op.content_hash())))
+            .map(|op| op.region)
+            .collect();
+        let blanked = drawn
+            .iter()
+            .map(|&(region, _)| region)
+            .filter(|region| ops.iter().all(|op| op.region != *region))
+            .collect();
+        FrameDiff {
+            cleared: false,
+            redrawn,
+            blanked,
+        }
+    }
+
+    /// Draw the operations that changed since the last frame, without
+    /// flushing, and return what changed. A failed draw leaves the frame
+    /// unknown, redrawn as a whole the next time.
+    pub fn draw<DI, SIZE>(
+        &mut self,
+        text_drawer: &mut TextDrawer<DI, SIZE>,
+        ops: &[DrawOp],
+    ) -> Result<FrameDiff, TextError<DisplayError<DI, SIZE>>>
+    where
+        DI: WriteOnlyDataCommand,
+        SIZE: DisplaySize,
+    {
+        let diff = self.diff(ops);
+        self.drawn = None;
+        if diff.cleared {
+            text_drawer.clear()?;
+        }
+        for &region in &diff.blanked {
+            text_drawer.clear_region(region)?;
+        }
+        for op in ops.iter().filter(|op| diff.redrawn.contains(&op.region)) {
+            op.draw(text_drawer)?;
+        }
+        self.drawn = Some(
+            ops.iter()
+                .map(|op| (op.region, op.content_hash()))
+                .collect(),
+        );
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::{mono_font::iso_8859_1::FONT_7X13_BOLD, prelude::Size};
+    use ssd1306::{prelude::DisplayRotation, size::DisplaySize128x64, Ssd1306};
+
+    use super::*;
+    use crate::text_drawer::NullDisplay;
+
+    fn region(y: i32) -> Rectangle {
+        Rectangle::new(Point::new(0, y), Size::new(128, 16))
+    }
+
+    fn screen(weight: &str, unit: &str) -> Vec<DrawOp> {
+        vec![
+            DrawOp::text(region(0), weight),
+            DrawOp::text(region(16), unit),
+        ]
+    }
+
+    #[test]
+    fn redraws_what_changed() {
+        let display = Ssd1306::new(NullDisplay, DisplaySize128x64, DisplayRotation::Rotate0)
+            .into_buffered_graphics_mode();
+        let mut text_drawer = TextDrawer::new(display, &FONT_7X13_BOLD);
+        let mut frame = Frame::default();
+
+        // Nothing is known of the screen at first
+        let diff = frame.draw(&mut text_drawer, &screen("0 g", "g")).unwrap();
+        assert!(diff.cleared);
+        assert_eq!(diff.redrawn, [region(0), region(16)]);
+
+        let diff = frame.draw(&mut text_drawer, &screen("12 g", "g")).unwrap();
+        assert!(!diff.cleared);
+        assert_eq!(diff.redrawn, [region(0)]);
+        assert!(diff.blanked.is_empty());
+        assert!(!diff.touched(region(16)));
+
+        let diff = frame.draw(&mut text_drawer, &screen("12 g", "g")).unwrap();
+        assert!(diff.is_empty());
+
+        // The unit line goes away
+        let diff = frame
+            .draw(&mut text_drawer, &[DrawOp::text(region(0), "12 g")])
+            .unwrap();
+        assert!(diff.redrawn.is_empty());
+        assert_eq!(diff.blanked, [region(16)]);
+
+        // Something else drew on the screen
+        frame.invalidate();
+        let diff = frame
+            .draw(&mut text_drawer, &[DrawOp::text(region(0), "12 g")])
+            .unwrap();
+        assert!(diff.cleared);
+        assert_eq!(diff.redrawn, [region(0)]);
+        assert!(diff.touched(region(16)));
+    }
+
+    #[test]
+    fn a_moved_op_is_redrawn() {
+        let frame = Frame {
+            drawn: Some(
+                screen("0 g", "g")
+                    .iter()
+                    .map(|op| (op.region, op.content_hash()))
+                    .collect(),
+            ),
+        };
+        let moved = [
+            DrawOp::text(region(32), "0 g"),
+            DrawOp::text(region(16), "g"),
+        ];
+        let diff = frame.diff(&moved);
+        assert_eq!(diff.redrawn, [region(32)]);
+        assert_eq!(diff.blanked, [region(0)]);
+    }
+}
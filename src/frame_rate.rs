@@ -0,0 +1,100 @@
+//! Caps the rate the weight is redrawn at. The readings come in at up to
+//! 80 per second, far faster than a reader follows the digits and than the
+//! I2C bus flushes them, so the changes of the weight are coalesced into
+//! frames at most `max_fps` apart. Discrete changes, a gesture or a page
+//! turned, are drawn right away and take the pending weight along.
+
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_MAX_FPS: u8 = 5;
+pub const MAX_FPS: u8 = 30;
+/// Window the achieved rate is counted over
+const FPS_WINDOW: Duration = Duration::from_secs(2);
+
+/// Frames drawn per second over the last window, in tenths
+static FPS_TENTHS: AtomicU32 = AtomicU32::new(0);
+/// Weight changes coalesced into a later frame
+static SKIPPED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+pub struct FrameGovernor {
+    period: Duration,
+    /// Whether the weight changed since the last frame
+    pending: bool,
+    last_frame: Option<Instant>,
+    window_start: Instant,
+    window_frames: u32,
+}
+
+impl FrameGovernor {
+    pub fn new(max_fps: u8, now: Instant) -> Self {
+        Self {
+            period: frame_period(max_fps),
+            pending: false,
+            last_frame: None,
+            window_start: now,
+            window_frames: 0,
+        }
+    }
+
+    pub fn configure(&mut self, max_fps: u8) {
+        self.period = frame_period(max_fps);
+    }
+
+    /// The weight changed, to be drawn with the next frame
+    pub fn on_update(&mut self) {
+        if self.pending {
+            SKIPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        self.pending = true;
+    }
+
+    /// Whether a change of the weight waits and its frame is due
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.pending
+            && self
+                .last_frame
+                .map_or(true, |at| now.saturating_duration_since(at) >= self.period)
+    }
+
+    /// Time until the waiting change is due, for the loop to come back by
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        if !self.pending {
+            return None;
+        }
+        let next = self.last_frame.map_or(now, |at| at + self.period);
+        Some(next.saturating_duration_since(now))
+    }
+
+    /// A frame was drawn, with whatever was waiting
+    pub fn on_frame(&mut self, now: Instant) {
+        self.pending = false;
+        self.last_frame = Some(now);
+        self.window_frames += 1;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= FPS_WINDOW {
+            let tenths = self.window_frames as f32 * 10.0 / elapsed.as_secs_f32();
+            FPS_TENTHS.store(tenths.round() as u32, Ordering::Relaxed);
+            self.window_start = now;
+            self.window_frames = 0;
+        }
+    }
+}
+
+fn frame_period(max_fps: u8) -> Duration {
+    Duration::from_secs(1) / u32::from(max_fps.clamp(1, MAX_FPS))
+}
+
+/// Frames drawn per second lately. Only counted as frames come, so it
+/// stays at the last rate while nothing is drawn.
+pub fn achieved_fps() -> f32 {
+    FPS_TENTHS.load(Ordering::Relaxed) as f32 / 10.0
+}
+
+/// Weight changes since boot that were drawn with a later frame
+pub fn skipped_frames() -> u32 {
+    SKIPPED.load(Ordering::Relaxed)
+}
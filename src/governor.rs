@@ -0,0 +1,334 @@
+//! Heap governor. With Wi-Fi, MQTT and BLE all built in the heap gets tight,
+//! and an allocation failing inside a driver takes the scale down with it.
+//! The optional subsystems register here with the heap they need and a way
+//! to stop them: one that would leave less than the reserve free is not
+//! started, and once the free heap falls below the critical level the
+//! running ones are stopped one at a time in the order of `Subsystem`. The
+//! weighing and the display never register, so they are never given up.
+//!
+//! A subsystem stays off until the next restart, bringing it back would only
+//! run the heap low again.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "esp")]
+use std::time::Duration;
+
+#[cfg(feature = "esp")]
+use log::{info, warn};
+
+/// Free heap in kB kept for the scale when the subsystems start
+pub const DEFAULT_HEAP_RESERVE_KB: u32 = 48;
+/// Free heap in kB below which the subsystems are stopped
+pub const DEFAULT_HEAP_CRITICAL_KB: u32 = 24;
+pub const MAX_HEAP_THRESHOLD_KB: u32 = 256;
+
+#[cfg(feature = "esp")]
+const CHECK_PERIOD: Duration = Duration::from_secs(5);
+#[cfg(feature = "esp")]
+const GOVERNOR_TASK_STACK_SIZE: usize = 3 * 1024;
+
+/// The optional subsystems, in the order they are given up
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Subsystem {
+    Ble,
+    /// Live weight pushed to the clients of the web page
+    WebSocket,
+    /// Weights queued in the MQTT outbox while the broker lags, sent right
+    /// away without it
+    MqttBuffering,
+}
+
+impl Subsystem {
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Ble => "ble",
+            Subsystem::WebSocket => "websocket",
+            Subsystem::MqttBuffering => "mqtt_buffer",
+        }
+    }
+}
+
+/// Why a subsystem is off, or short of the heap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suppression {
+    /// Not started, it needed more than the heap left over the reserve
+    Refused { free_heap: u32 },
+    /// Stopped once the free heap fell below the critical level
+    Stopped { free_heap: u32 },
+    /// Running, but it took more than it asked for and left less than the
+    /// reserve free once up
+    Short { free_heap: u32 },
+}
+
+impl Suppression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Suppression::Refused { .. } => "refused",
+            Suppression::Stopped { .. } => "stopped",
+            Suppression::Short { .. } => "short",
+        }
+    }
+
+    /// Free heap in bytes when it was refused, stopped or found short
+    pub fn free_heap(self) -> u32 {
+        match self {
+            Suppression::Refused { free_heap }
+            | Suppression::Stopped { free_heap }
+            | Suppression::Short { free_heap } => free_heap,
+        }
+    }
+
+    /// Whether the subsystem is off, a short one still runs
+    pub fn is_off(self) -> bool {
+        !matches!(self, Suppression::Short { .. })
+    }
+}
+
+impl fmt::Display for Suppression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}k", self.name(), self.free_heap() / 1024)
+    }
+}
+
+type Stop = Arc<dyn Fn() + Send + Sync>;
+
+struct Entry {
+    subsystem: Subsystem,
+    stop: Stop,
+    suppressed: Option<Suppression>,
+}
+
+/// The subsystems registered, along with the thresholds in bytes
+pub struct Governor {
+    reserve: u32,
+    critical: u32,
+    /// In the order they are given up
+    entries: Vec<Entry>,
+}
+
+impl Governor {
+    pub const fn new(reserve: u32, critical: u32) -> Self {
+        Self {
+            reserve,
+            critical,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn configure(&mut self, reserve: u32, critical: u32) {
+        self.reserve = reserve;
+        self.critical = critical;
+    }
+
+    /// Register a subsystem needing `need` bytes, with `free_heap` left.
+    /// Returns whether it may start, `stop` is called once it has to stop.
+    pub fn register(
+        &mut self,
+        subsystem: Subsystem,
+        need: u32,
+        free_heap: u32,
+        stop: impl Fn() + Send + Sync + 'static,
+    ) -> bool {
+        let admitted = free_heap >= need.saturating_add(self.reserve);
+        let entry = Entry {
+            subsystem,
+            stop: Arc::new(stop),
+            suppressed: (!admitted).then_some(Suppression::Refused { free_heap }),
+        };
+        let index = self
+            .entries
+            .partition_point(|entry| entry.subsystem <= subsystem);
+        self.entries.insert(index, entry);
+        admitted
+    }
+
+    /// Record the free heap left once `subsystem` is up. Below the reserve
+    /// it took more than it asked for: it goes on running, but counts as
+    /// short and the scale as degraded. Returns whether the reserve is left.
+    pub fn started(&mut self, subsystem: Subsystem, free_heap: u32) -> bool {
+        if free_heap >= self.reserve {
+            return true;
+        }
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.subsystem == subsystem && entry.suppressed.is_none())
+        {
+            entry.suppressed = Some(Suppression::Short { free_heap });
+        }
+        false
+    }
+
+    /// Pick the subsystem to stop with `free_heap` left, if any, along with
+    /// the call stopping it. One at a time, so the heap it gave back is
+    /// seen before the next one goes.
+    pub fn check(&mut self, free_heap: u32) -> Option<(Subsystem, Stop)> {
+        if free_heap >= self.critical {
+            return None;
+        }
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| !entry.suppressed.is_some_and(Suppression::is_off))?;
+        entry.suppressed = Some(Suppression::Stopped { free_heap });
+        Some((entry.subsystem, entry.stop.clone()))
+    }
+
+    /// The subsystems off or short, in the order they are given up
+    pub fn suppressed(&self) -> Vec<(Subsystem, Suppression)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| Some((entry.subsystem, entry.suppressed?)))
+            .collect()
+    }
+
+    /// Whether a subsystem is off or short for lack of heap
+    pub fn is_degraded(&self) -> bool {
+        self.entries.iter().any(|entry| entry.suppressed.is_some())
+    }
+}
+
+static GOVERNOR: Mutex<Governor> = Mutex::new(Governor::new(
+    DEFAULT_HEAP_RESERVE_KB * 1024,
+    DEFAULT_HEAP_CRITICAL_KB * 1024,
+));
+
+fn governor() -> std::sync::MutexGuard<'static, Governor> {
+    GOVERNOR
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(feature = "esp")]
+fn free_heap() -> u32 {
+    unsafe { esp_idf_sys::esp_get_free_heap_size() }
+}
+
+/// Set the thresholds in kB and start checking the free heap
+#[cfg(feature = "esp")]
+pub fn start(reserve_kb: u32, critical_kb: u32) -> std::io::Result<()> {
+    configure(reserve_kb, critical_kb);
+    std::thread::Builder::new()
+        .name("governor".to_string())
+        .stack_size(GOVERNOR_TASK_STACK_SIZE)
+        .spawn(|| loop {
+            std::thread::sleep(CHECK_PERIOD);
+            let free_heap = free_heap();
+            // Stopped outside the lock, the call may take a while
+            let stopping = governor().check(free_heap);
+            if let Some((subsystem, stop)) = stopping {
+                warn!(
+                    "Only {} bytes of heap left, stopping {}",
+                    free_heap,
+                    subsystem.name()
+                );
+                stop();
+            }
+        })?;
+    Ok(())
+}
+
+/// Change the thresholds, in kB
+pub fn configure(reserve_kb: u32, critical_kb: u32) {
+    governor().configure(reserve_kb * 1024, critical_kb * 1024);
+}
+
+/// Register a subsystem about to start, needing about `need` bytes of heap.
+/// Returns whether it may start, `stop` is called once it has to stop.
+#[cfg(feature = "esp")]
+pub fn admit(subsystem: Subsystem, need: u32, stop: impl Fn() + Send + Sync + 'static) -> bool {
+    let free_heap = free_heap();
+    let admitted = governor().register(subsystem, need, free_heap, stop);
+    match admitted {
+        true => info!(
+            "Starting {}, {} bytes of heap free",
+            subsystem.name(),
+            free_heap
+        ),
+        false => warn!(
+            "Not starting {}, it needs {} bytes and only {} are free",
+            subsystem.name(),
+            need,
+            free_heap
+        ),
+    }
+    admitted
+}
+
+/// Check the reserve is still free once `subsystem` is up, warning and
+/// marking it short otherwise. Returns the free heap left.
+#[cfg(feature = "esp")]
+pub fn started(subsystem: Subsystem) -> u32 {
+    let free_heap = free_heap();
+    match governor().started(subsystem, free_heap) {
+        true => info!(
+            "{} started, {} bytes of heap left",
+            subsystem.name(),
+            free_heap
+        ),
+        false => warn!(
+            "{} started, but only {} bytes of heap are left, less than the reserve",
+            subsystem.name(),
+            free_heap
+        ),
+    }
+    free_heap
+}
+
+/// The subsystems off or short for lack of heap, and why
+pub fn suppressed() -> Vec<(Subsystem, Suppression)> {
+    governor().suppressed()
+}
+
+pub fn is_degraded() -> bool {
+    governor().is_degraded()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KB: u32 = 1024;
+
+    #[test]
+    fn admits_within_the_reserve() {
+        let mut governor = Governor::new(48 * KB, 24 * KB);
+        assert!(governor.register(Subsystem::Ble, 56 * KB, 104 * KB, || {}));
+        assert!(!governor.register(Subsystem::WebSocket, 16 * KB, 63 * KB, || {}));
+        assert_eq!(
+            governor.suppressed(),
+            [(
+                Subsystem::WebSocket,
+                Suppression::Refused { free_heap: 63 * KB }
+            )]
+        );
+    }
+
+    #[test]
+    fn short_once_up_below_the_reserve() {
+        let mut governor = Governor::new(48 * KB, 24 * KB);
+        assert!(governor.register(Subsystem::Ble, 56 * KB, 110 * KB, || {}));
+        assert!(governor.started(Subsystem::Ble, 50 * KB));
+        assert!(!governor.is_degraded());
+
+        // It took 70k instead of the 56k it asked for
+        assert!(!governor.started(Subsystem::Ble, 40 * KB));
+        assert!(governor.is_degraded());
+        assert_eq!(
+            governor.suppressed(),
+            [(Subsystem::Ble, Suppression::Short { free_heap: 40 * KB })]
+        );
+        // Still running, so still the first to stop
+        let (stopped, _) = governor.check(20 * KB).unwrap();
+        assert_eq!(stopped, Subsystem::Ble);
+        assert_eq!(
+            governor.suppressed(),
+            [(Subsystem::Ble, Suppression::Stopped { free_heap: 20 * KB })]
+        );
+        assert!(governor.check(20 * KB).is_none());
+    }
+}
@@ -0,0 +1,636 @@
+//! Hourly and daily aggregates of the weight log (min, max, mean and count),
+//! small enough to pull over HTTP where the raw log is not.
+//!
+//! The bins are computed as the records are appended and only cover records
+//! with a wall clock time. Records taken before the clock is synchronized
+//! wait in RAM, and are moved onto the wall clock once the first
+//! synchronized record tells when the scale booted. A clock stepping back
+//! does not reopen the bins already closed, its records go to the open bins.
+//!
+//! Closed bins are stored in chunks, one UTC day of hourly bins or 32 days
+//! of daily bins per blob, in rings of `HOUR_CHUNKS` and `DAY_CHUNKS`. The
+//! open bins are written along with the log records, so a restart mid-hour
+//! loses no more of them than of the log. Closing an hour rewrites its day's
+//! chunk, some 300 bytes on average, about 7KiB a day.
+
+use std::{collections::VecDeque, time::Duration};
+
+#[cfg(feature = "esp")]
+use esp_idf_svc::nvs::{EspCustomNvsPartition, EspNvs, NvsCustom};
+#[cfg(feature = "esp")]
+use esp_idf_sys::EspError;
+use log::warn;
+
+use crate::time::Timestamp;
+
+/// Header of the CSV the `history` command prints
+pub const HISTORY_CSV_HEADER: &str = "start,min_grams,max_grams,mean_grams,count";
+
+#[cfg(feature = "esp")]
+const HISTORY_NAMESPACE: &str = "history";
+const OPEN_KEY: &str = "open";
+const OPEN_VERSION: u8 = 1;
+const OPEN_LEN: usize = 2 + 2 * BIN_LEN;
+const OPEN_HOUR: u8 = 1 << 0;
+const OPEN_DAY: u8 = 1 << 1;
+const BIN_LEN: usize = 24;
+
+/// Days of hourly bins kept
+const HOUR_CHUNKS: u32 = 7;
+/// Chunks of 32 daily bins kept
+const DAY_CHUNKS: u32 = 4;
+const DAYS_PER_CHUNK: u32 = 32;
+/// Records kept while the clock is not synchronized, 2.5 days of them at
+/// the default interval
+const MAX_PENDING: usize = 360;
+
+/// Time span of a bin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Granularity {
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    pub const ALL: [Granularity; 2] = [Granularity::Hour, Granularity::Day];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|granularity| granularity.name() == name)
+    }
+
+    pub fn seconds(self) -> u32 {
+        match self {
+            Granularity::Hour => 3600,
+            Granularity::Day => 24 * 3600,
+        }
+    }
+
+    /// Bins returned when not told how many
+    pub fn default_count(self) -> u32 {
+        match self {
+            Granularity::Hour => 24,
+            Granularity::Day => 30,
+        }
+    }
+
+    /// Most bins that can be asked for, the open one included
+    pub fn kept(self) -> u32 {
+        match self {
+            Granularity::Hour => (HOUR_CHUNKS - 1) * 24 + 1,
+            Granularity::Day => (DAY_CHUNKS - 1) * DAYS_PER_CHUNK + 1,
+        }
+    }
+}
+
+impl Granularity {
+    fn start_of(self, seconds: u32) -> u32 {
+        seconds - seconds % self.seconds()
+    }
+
+    fn bins_per_chunk(self) -> u32 {
+        match self {
+            Granularity::Hour => 24,
+            Granularity::Day => DAYS_PER_CHUNK,
+        }
+    }
+
+    /// Chunks in the ring
+    fn chunks(self) -> u32 {
+        match self {
+            Granularity::Hour => HOUR_CHUNKS,
+            Granularity::Day => DAY_CHUNKS,
+        }
+    }
+
+    /// Chunk number of the bin starting then, counted from the epoch
+    fn chunk(self, start: u32) -> u32 {
+        start / (self.seconds() * self.bins_per_chunk())
+    }
+
+    fn chunk_key(self, chunk: u32) -> String {
+        let prefix = match self {
+            Granularity::Hour => 'h',
+            Granularity::Day => 'd',
+        };
+        format!("{}{}", prefix, chunk % self.chunks())
+    }
+
+    fn slot(self) -> usize {
+        match self {
+            Granularity::Hour => 0,
+            Granularity::Day => 1,
+        }
+    }
+}
+
+/// Aggregate of the records of one hour or day
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bin {
+    /// Seconds since the epoch at the start of the hour or day, UTC
+    pub start: u32,
+    pub min_milligrams: i32,
+    pub max_milligrams: i32,
+    sum_milligrams: i64,
+    pub count: u32,
+}
+
+impl Bin {
+    pub fn min_grams(&self) -> f32 {
+        self.min_milligrams as f32 / 1000.0
+    }
+
+    pub fn max_grams(&self) -> f32 {
+        self.max_milligrams as f32 / 1000.0
+    }
+
+    pub fn mean_grams(&self) -> f32 {
+        (self.sum_milligrams as f64 / f64::from(self.count.max(1)) / 1000.0) as f32
+    }
+
+    /// Start of the bin as a UTC timestamp
+    pub fn timestamp(&self) -> Timestamp {
+        Timestamp::WallClock {
+            since_epoch: Duration::from_secs(self.start.into()),
+            offset_minutes: 0,
+        }
+    }
+
+    /// CSV line matching `HISTORY_CSV_HEADER`, without the line break
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{:.3},{:.3},{:.3},{}",
+            self.timestamp(),
+            self.min_grams(),
+            self.max_grams(),
+            self.mean_grams(),
+            self.count
+        )
+    }
+}
+
+impl Bin {
+    fn new(start: u32, milligrams: i32) -> Self {
+        Self {
+            start,
+            min_milligrams: milligrams,
+            max_milligrams: milligrams,
+            sum_milligrams: milligrams.into(),
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, milligrams: i32) {
+        self.min_milligrams = self.min_milligrams.min(milligrams);
+        self.max_milligrams = self.max_milligrams.max(milligrams);
+        self.sum_milligrams += i64::from(milligrams);
+        self.count += 1;
+    }
+
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.start.to_le_bytes());
+        bytes.extend_from_slice(&self.min_milligrams.to_le_bytes());
+        bytes.extend_from_slice(&self.max_milligrams.to_le_bytes());
+        bytes.extend_from_slice(&self.sum_milligrams.to_le_bytes());
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; BIN_LEN] = bytes.try_into().ok()?;
+        let word = |at: usize| [bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]];
+        let mut sum = [0u8; 8];
+        sum.copy_from_slice(&bytes[12..20]);
+        Some(Self {
+            start: u32::from_le_bytes(word(0)),
+            min_milligrams: i32::from_le_bytes(word(4)),
+            max_milligrams: i32::from_le_bytes(word(8)),
+            sum_milligrams: i64::from_le_bytes(sum),
+            count: u32::from_le_bytes(word(20)),
+        })
+    }
+}
+
+/// Blobs the bins are stored in, a namespace of the log partition on the
+/// scale
+pub trait BinStore {
+    type Error;
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn set_blob(&mut self, key: &str, bytes: &[u8]) -> Result<(), Self::Error>;
+    fn remove(&mut self, key: &str) -> Result<(), Self::Error>;
+}
+
+#[cfg(feature = "esp")]
+impl BinStore for EspNvs<NvsCustom> {
+    type Error = EspError;
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, EspError> {
+        let Some(len) = self.blob_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len];
+        Ok(EspNvs::get_blob(self, key, &mut buf)?.map(<[u8]>::to_vec))
+    }
+
+    fn set_blob(&mut self, key: &str, bytes: &[u8]) -> Result<(), EspError> {
+        EspNvs::set_blob(self, key, bytes)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), EspError> {
+        EspNvs::remove(self, key).map(|_| ())
+    }
+}
+
+/// The aggregates along with the records waiting for the clock
+pub struct History<S> {
+    store: S,
+    /// Bins still taking records, by granularity
+    open: [Option<Bin>; 2],
+    /// Seconds since boot and milligrams of the records taken before the
+    /// clock was synchronized
+    pending: VecDeque<(u32, i32)>,
+    /// Whether the open bins changed since they were written
+    dirty: bool,
+}
+
+#[cfg(feature = "esp")]
+impl History<EspNvs<NvsCustom>> {
+    pub fn open(partition: EspCustomNvsPartition) -> Result<Self, EspError> {
+        Self::new(EspNvs::new(partition, HISTORY_NAMESPACE, true)?)
+    }
+}
+
+impl<S: BinStore> History<S> {
+    /// The bins in the store, with the open ones left at the last flush
+    pub fn new(store: S) -> Result<Self, S::Error> {
+        let mut history = Self {
+            store,
+            open: [None, None],
+            pending: VecDeque::new(),
+            dirty: false,
+        };
+        match history.store.get_blob(OPEN_KEY)?.as_deref() {
+            Some(&[OPEN_VERSION, flags, ref bins @ ..]) if bins.len() == 2 * BIN_LEN => {
+                let (hour, day) = bins.split_at(BIN_LEN);
+                history.open = [
+                    Bin::decode(hour).filter(|_| flags & OPEN_HOUR != 0),
+                    Bin::decode(day).filter(|_| flags & OPEN_DAY != 0),
+                ];
+            }
+            Some(_) => warn!("History layout changed, starting over"),
+            None => {}
+        }
+        Ok(history)
+    }
+
+    /// Aggregate a record appended to the log, taken at `time`, `uptime`
+    /// being the time since boot then
+    pub fn add(
+        &mut self,
+        time: Timestamp,
+        milligrams: i32,
+        uptime: Duration,
+    ) -> Result<(), S::Error> {
+        let seconds = match time {
+            Timestamp::WallClock { since_epoch, .. } => since_epoch.as_secs() as u32,
+            Timestamp::Uptime(since_boot) => {
+                if self.pending.len() >= MAX_PENDING {
+                    self.pending.pop_front();
+                }
+                self.pending
+                    .push_back((since_boot.as_secs() as u32, milligrams));
+                return Ok(());
+            }
+        };
+        if !self.pending.is_empty() {
+            let boot = seconds.saturating_sub(uptime.as_secs() as u32);
+            while let Some((since_boot, milligrams)) = self.pending.pop_front() {
+                self.add_at(boot.saturating_add(since_boot), milligrams)?;
+            }
+        }
+        self.add_at(seconds, milligrams)
+    }
+
+    fn add_at(&mut self, seconds: u32, milligrams: i32) -> Result<(), S::Error> {
+        for granularity in Granularity::ALL {
+            let start = granularity.start_of(seconds);
+            match &mut self.open[granularity.slot()] {
+                // A clock stepping back lands in the open bin too
+                Some(bin) if start <= bin.start => bin.add(milligrams),
+                open => {
+                    if let Some(closed) = open.replace(Bin::new(start, milligrams)) {
+                        self.close(granularity, closed)?;
+                    }
+                }
+            }
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Store a bin that takes no more records in its chunk
+    fn close(&mut self, granularity: Granularity, bin: Bin) -> Result<(), S::Error> {
+        let chunk = granularity.chunk(bin.start);
+        // Bins of the chunk a lap of the ring ago make room, and a bin
+        // stored again after a restart replaces itself
+        let mut bins: Vec<Bin> = self
+            .read_chunk(granularity, chunk)?
+            .into_iter()
+            .filter(|stored| stored.start != bin.start)
+            .collect();
+        bins.push(bin);
+        let mut bytes = Vec::with_capacity(bins.len() * BIN_LEN);
+        for bin in &bins {
+            bin.encode(&mut bytes);
+        }
+        self.store.set_blob(&granularity.chunk_key(chunk), &bytes)
+    }
+
+    /// Bins of the chunk, without those left from a lap of the ring ago
+    fn read_chunk(&self, granularity: Granularity, chunk: u32) -> Result<Vec<Bin>, S::Error> {
+        let bytes = self
+            .store
+            .get_blob(&granularity.chunk_key(chunk))?
+            .unwrap_or_default();
+        Ok(bytes
+            .chunks_exact(BIN_LEN)
+            .filter_map(Bin::decode)
+            .filter(|bin| granularity.chunk(bin.start) == chunk)
+            .collect())
+    }
+
+    /// Write the open bins, when they changed
+    pub fn flush(&mut self) -> Result<(), S::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut bytes = Vec::with_capacity(OPEN_LEN);
+        let mut flags = 0;
+        if self.open[Granularity::Hour.slot()].is_some() {
+            flags |= OPEN_HOUR;
+        }
+        if self.open[Granularity::Day.slot()].is_some() {
+            flags |= OPEN_DAY;
+        }
+        bytes.extend_from_slice(&[OPEN_VERSION, flags]);
+        for bin in self.open {
+            bin.unwrap_or(Bin::new(0, 0)).encode(&mut bytes);
+        }
+        self.store.set_blob(OPEN_KEY, &bytes)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// The bins of the last `count` hours or days up to `now`, seconds
+    /// since the epoch, or up to the open bin while the clock is not
+    /// synchronized. Oldest first, the hours or days without records are
+    /// left out.
+    pub fn bins(
+        &self,
+        granularity: Granularity,
+        count: u32,
+        now: Option<u32>,
+    ) -> Result<Vec<Bin>, S::Error> {
+        let open = self.open[granularity.slot()];
+        let now = now.map(|now| granularity.start_of(now));
+        let Some(last) = open.map(|bin| bin.start).max(now) else {
+            return Ok(Vec::new());
+        };
+        let span = count.clamp(1, granularity.kept()) - 1;
+        let first = last.saturating_sub(span * granularity.seconds());
+        let mut bins = Vec::new();
+        for chunk in granularity.chunk(first)..=granularity.chunk(last) {
+            let mut stored = self.read_chunk(granularity, chunk)?;
+            // The open bin is stored too when it was closed right before a
+            // restart
+            stored.retain(|bin| {
+                bin.start >= first
+                    && bin.start <= last
+                    && !matches!(open, Some(open) if open.start == bin.start)
+            });
+            bins.extend(stored);
+        }
+        bins.extend(open.filter(|open| open.start >= first));
+        bins.sort_by_key(|bin| bin.start);
+        Ok(bins)
+    }
+
+    /// Drop all bins, and the records waiting for the clock
+    pub fn clear(&mut self) -> Result<(), S::Error> {
+        for granularity in Granularity::ALL {
+            for chunk in 0..granularity.chunks() {
+                self.store.remove(&granularity.chunk_key(chunk))?;
+            }
+        }
+        self.store.remove(OPEN_KEY)?;
+        self.open = [None, None];
+        self.pending.clear();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, convert::Infallible};
+
+    use super::*;
+
+    /// Start of a UTC day, 2023-11-14
+    const DAY: u32 = 19_675 * 24 * 3600;
+    const HOUR: u32 = 3600;
+
+    type Blobs = BTreeMap<String, Vec<u8>>;
+
+    /// The blobs outlive the history, as the partition does a restart
+    impl BinStore for &mut Blobs {
+        type Error = Infallible;
+
+        fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, Infallible> {
+            Ok(self.get(key).cloned())
+        }
+
+        fn set_blob(&mut self, key: &str, bytes: &[u8]) -> Result<(), Infallible> {
+            self.insert(key.to_string(), bytes.to_vec());
+            Ok(())
+        }
+
+        fn remove(&mut self, key: &str) -> Result<(), Infallible> {
+            BTreeMap::remove(*self, key);
+            Ok(())
+        }
+    }
+
+    fn wall_clock(seconds: u32) -> Timestamp {
+        Timestamp::WallClock {
+            since_epoch: Duration::from_secs(seconds.into()),
+            offset_minutes: 0,
+        }
+    }
+
+    /// Add a record of `grams` taken at `seconds` since the epoch, the
+    /// uptime does not matter once the clock is synchronized
+    fn add(history: &mut History<&mut Blobs>, seconds: u32, grams: i32) {
+        history
+            .add(wall_clock(seconds), grams * 1000, Duration::ZERO)
+            .unwrap();
+    }
+
+    /// Starts and counts of the bins up to `now`
+    fn bins(history: &History<&mut Blobs>, granularity: Granularity, now: u32) -> Vec<(u32, u32)> {
+        history
+            .bins(granularity, granularity.kept(), Some(now))
+            .unwrap()
+            .iter()
+            .map(|bin| (bin.start, bin.count))
+            .collect()
+    }
+
+    #[test]
+    fn aggregates_the_records() {
+        let mut blobs = Blobs::new();
+        let mut history = History::new(&mut blobs).unwrap();
+        add(&mut history, DAY + 10 * HOUR + 60, 100);
+        add(&mut history, DAY + 10 * HOUR + 120, 300);
+        add(&mut history, DAY + 12 * HOUR, 50);
+        let hours = history.bins(Granularity::Hour, 24, None).unwrap();
+        assert_eq!(hours.len(), 2);
+        assert_eq!(hours[0].start, DAY + 10 * HOUR);
+        assert_eq!(hours[0].min_grams(), 100.0);
+        assert_eq!(hours[0].max_grams(), 300.0);
+        assert_eq!(hours[0].mean_grams(), 200.0);
+        assert_eq!(hours[0].count, 2);
+        assert_eq!(hours[1].start, DAY + 12 * HOUR);
+        assert_eq!(
+            bins(&history, Granularity::Day, DAY + 12 * HOUR),
+            [(DAY, 3)]
+        );
+    }
+
+    #[test]
+    fn restart_keeps_the_open_bins() {
+        let mut blobs = Blobs::new();
+        let mut history = History::new(&mut blobs).unwrap();
+        add(&mut history, DAY + 10 * HOUR + 300, 100);
+        add(&mut history, DAY + 10 * HOUR + 1800, 100);
+        history.flush().unwrap();
+        drop(history);
+
+        let mut history = History::new(&mut blobs).unwrap();
+        assert_eq!(
+            bins(&history, Granularity::Hour, DAY + 10 * HOUR + 1800),
+            [(DAY + 10 * HOUR, 2)]
+        );
+        add(&mut history, DAY + 10 * HOUR + 2700, 100);
+        add(&mut history, DAY + 11 * HOUR + 600, 100);
+        assert_eq!(
+            bins(&history, Granularity::Hour, DAY + 11 * HOUR + 600),
+            [(DAY + 10 * HOUR, 3), (DAY + 11 * HOUR, 1)]
+        );
+        assert_eq!(
+            bins(&history, Granularity::Day, DAY + 11 * HOUR),
+            [(DAY, 4)]
+        );
+    }
+
+    #[test]
+    fn bin_closed_right_before_a_restart_is_not_duplicated() {
+        let mut blobs = Blobs::new();
+        let mut history = History::new(&mut blobs).unwrap();
+        add(&mut history, DAY + 10 * HOUR + 300, 100);
+        history.flush().unwrap();
+        // Closes the hour, the power goes before the open bins are written
+        add(&mut history, DAY + 11 * HOUR + 300, 100);
+        drop(history);
+
+        // The hour closed is open again, and stored
+        let mut history = History::new(&mut blobs).unwrap();
+        assert_eq!(
+            bins(&history, Granularity::Hour, DAY + 11 * HOUR + 300),
+            [(DAY + 10 * HOUR, 1)]
+        );
+        add(&mut history, DAY + 11 * HOUR + 600, 100);
+        add(&mut history, DAY + 12 * HOUR, 100);
+        assert_eq!(
+            bins(&history, Granularity::Hour, DAY + 12 * HOUR),
+            [
+                (DAY + 10 * HOUR, 1),
+                (DAY + 11 * HOUR, 1),
+                (DAY + 12 * HOUR, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn records_before_the_clock_are_moved_onto_it() {
+        let mut blobs = Blobs::new();
+        let mut history = History::new(&mut blobs).unwrap();
+        let since_boot = |seconds| Timestamp::Uptime(Duration::from_secs(seconds));
+        history.add(since_boot(5), 1000, Duration::ZERO).unwrap();
+        history.add(since_boot(65), 2000, Duration::ZERO).unwrap();
+        assert_eq!(history.bins(Granularity::Hour, 24, None).unwrap(), []);
+        // Synchronized 125s after booting at 9:58:25
+        history
+            .add(
+                wall_clock(DAY + 10 * HOUR + 30),
+                3000,
+                Duration::from_secs(125),
+            )
+            .unwrap();
+        assert_eq!(
+            bins(&history, Granularity::Hour, DAY + 10 * HOUR),
+            [(DAY + 9 * HOUR, 2), (DAY + 10 * HOUR, 1)]
+        );
+    }
+
+    #[test]
+    fn clock_stepping_back_stays_in_the_open_bins() {
+        let mut blobs = Blobs::new();
+        let mut history = History::new(&mut blobs).unwrap();
+        add(&mut history, DAY + 10 * HOUR + 600, 100);
+        add(&mut history, DAY + 11 * HOUR + 300, 100);
+        // Back into the hour closed
+        add(&mut history, DAY + 10 * HOUR + 3000, 100);
+        assert_eq!(
+            bins(&history, Granularity::Hour, DAY + 11 * HOUR),
+            [(DAY + 10 * HOUR, 1), (DAY + 11 * HOUR, 2)]
+        );
+
+        // Back over midnight
+        add(&mut history, DAY + 24 * HOUR + 600, 100);
+        add(&mut history, DAY + 23 * HOUR + 3000, 100);
+        assert_eq!(
+            bins(&history, Granularity::Hour, DAY + 24 * HOUR),
+            [
+                (DAY + 10 * HOUR, 1),
+                (DAY + 11 * HOUR, 2),
+                (DAY + 24 * HOUR, 2)
+            ]
+        );
+        assert_eq!(
+            bins(&history, Granularity::Day, DAY + 24 * HOUR),
+            [(DAY, 3), (DAY + 24 * HOUR, 2)]
+        );
+    }
+
+    #[test]
+    fn clear_drops_everything() {
+        let mut blobs = Blobs::new();
+        let mut history = History::new(&mut blobs).unwrap();
+        add(&mut history, DAY + 10 * HOUR, 100);
+        add(&mut history, DAY + 11 * HOUR, 100);
+        history.flush().unwrap();
+        history.clear().unwrap();
+        assert_eq!(bins(&history, Granularity::Hour, DAY + 11 * HOUR), []);
+        drop(history);
+        assert!(blobs.is_empty());
+    }
+}
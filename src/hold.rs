@@ -0,0 +1,282 @@
+//! Holding the weight of a load that never settles, e.g. a squirming cat or
+//! a sheep in a crate. The weight is estimated from the readings of the last
+//! seconds with the extremes trimmed off, and stays frozen on the display
+//! until released. The auto-hold does the same on its own once the readings
+//! stay inside a band for a while, which a restless load still manages.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Readings the estimate is made from, counting back from the last one
+pub const HOLD_WINDOW: Duration = Duration::from_secs(3);
+/// Readings kept at most, the window takes 240 of them at 80 SPS
+const MAX_SAMPLES: usize = 256;
+/// Readings needed for an estimate
+const MIN_SAMPLES: usize = 5;
+/// Share of the readings trimmed off each end before averaging
+const TRIM_FRACTION: f32 = 0.25;
+/// Loads lighter than this many bands never get auto-held, and the
+/// auto-hold is armed again once the weight fell below it
+pub const AUTO_HOLD_MIN_BANDS: f32 = 10.0;
+/// Longest time the readings may take to stay in the band
+pub const MAX_AUTO_HOLD_S: u32 = 60;
+
+/// What the weight page shows
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HoldState {
+    /// The weight as it is read
+    #[default]
+    Live,
+    /// A weight frozen until released, `auto` when the auto-hold caught it
+    Held { grams: f32, auto: bool },
+}
+
+/// When the weight holds on its own
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutoHold {
+    /// Spread of the readings that counts as settled enough
+    pub band_grams: f32,
+    /// Time the readings must stay within the band
+    pub duration: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Hold {
+    /// Recent readings along with when they were taken, the oldest first
+    samples: VecDeque<(Instant, f32)>,
+    state: HoldState,
+    auto: Option<AutoHold>,
+    /// Whether a settling load gets held, only once the scale emptied since
+    /// the last hold
+    armed: bool,
+    /// Since when the readings stay within the band, with their extremes
+    settling: Option<(Instant, f32, f32)>,
+}
+
+impl Hold {
+    pub fn new(auto: Option<AutoHold>) -> Self {
+        Self {
+            auto,
+            armed: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn configure(&mut self, auto: Option<AutoHold>) {
+        self.auto = auto;
+        self.settling = None;
+    }
+
+    /// Record a reading. Returns true when the auto-hold caught the weight.
+    pub fn on_sample(&mut self, grams: f32, at: Instant) -> bool {
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, grams));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(taken, _)| at.duration_since(*taken) > HOLD_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+
+        let Some(auto) = self.auto else {
+            return false;
+        };
+        if grams.abs() < auto.band_grams * AUTO_HOLD_MIN_BANDS {
+            self.armed = true;
+            self.settling = None;
+            return false;
+        }
+        let (since, low, high) = match self.settling {
+            Some((since, low, high)) if high.max(grams) - low.min(grams) <= auto.band_grams => {
+                (since, low.min(grams), high.max(grams))
+            }
+            // Out of the band, settling starts over from this reading
+            _ => (at, grams, grams),
+        };
+        self.settling = Some((since, low, high));
+        if !self.armed || at.duration_since(since) < auto.duration {
+            return false;
+        }
+        let Some(grams) = self.estimate() else {
+            return false;
+        };
+        self.state = HoldState::Held { grams, auto: true };
+        self.armed = false;
+        self.settling = None;
+        true
+    }
+
+    /// Mean of the readings of the last `HOLD_WINDOW` with a quarter
+    /// trimmed off each end, none without enough readings
+    pub fn estimate(&self) -> Option<f32> {
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut grams: Vec<f32> = self.samples.iter().map(|(_, grams)| *grams).collect();
+        grams.sort_by(f32::total_cmp);
+        let trim = (grams.len() as f32 * TRIM_FRACTION) as usize;
+        let kept = &grams[trim..grams.len() - trim];
+        Some(kept.iter().sum::<f32>() / kept.len() as f32)
+    }
+
+    /// Freeze the estimate of the weight, returning it
+    pub fn hold(&mut self) -> Option<f32> {
+        let grams = self.estimate()?;
+        self.state = HoldState::Held { grams, auto: false };
+        // The load still on the scale is not held again on its own
+        self.armed = false;
+        Some(grams)
+    }
+
+    /// Back to the live weight, returning whether it was held
+    pub fn release(&mut self) -> bool {
+        let held = self.state != HoldState::Live;
+        self.state = HoldState::Live;
+        held
+    }
+
+    /// Release the weight and forget the readings, which a tare moves
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.state = HoldState::Live;
+        self.settling = None;
+    }
+
+    pub fn state(&self) -> HoldState {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Readings every 100ms, 10 SPS
+    const TICK: Duration = Duration::from_millis(100);
+    const AUTO: AutoHold = AutoHold {
+        band_grams: 2.0,
+        duration: Duration::from_secs(1),
+    };
+
+    /// Feed the readings, returning the tick the auto-hold caught one at
+    fn feed(hold: &mut Hold, start: &mut Instant, readings: &[f32]) -> Option<usize> {
+        let mut caught = None;
+        for (tick, &grams) in readings.iter().enumerate() {
+            *start += TICK;
+            if hold.on_sample(grams, *start) && caught.is_none() {
+                caught = Some(tick);
+            }
+        }
+        caught
+    }
+
+    /// A load swinging by a gram around `grams`, within the band
+    fn restless(grams: f32, ticks: usize) -> Vec<f32> {
+        (0..ticks)
+            .map(|tick| grams + if tick % 2 == 0 { 0.5 } else { -0.5 })
+            .collect()
+    }
+
+    #[test]
+    fn holds_once_in_the_band_for_the_duration() {
+        let mut hold = Hold::new(Some(AUTO));
+        let mut now = Instant::now();
+        // In the band from the first reading, a second later at the 11th
+        assert_eq!(feed(&mut hold, &mut now, &restless(5000.0, 30)), Some(10));
+        let HoldState::Held { grams, auto: true } = hold.state() else {
+            panic!("not auto-held: {:?}", hold.state());
+        };
+        assert!((grams - 5000.0).abs() < 0.5, "held {grams}g");
+        // Frozen while the weight moves
+        feed(&mut hold, &mut now, &[5100.0, 4900.0]);
+        assert_eq!(hold.state(), HoldState::Held { grams, auto: true });
+    }
+
+    #[test]
+    fn light_load_is_not_held() {
+        let mut hold = Hold::new(Some(AUTO));
+        let mut now = Instant::now();
+        // Under ten bands
+        assert_eq!(feed(&mut hold, &mut now, &restless(19.0, 100)), None);
+        assert_eq!(hold.state(), HoldState::Live);
+        assert_eq!(feed(&mut hold, &mut now, &restless(21.0, 20)), Some(10));
+    }
+
+    #[test]
+    fn leaving_the_band_starts_over() {
+        let mut hold = Hold::new(Some(AUTO));
+        let mut now = Instant::now();
+        // Drifting by half a gram a reading, out of the band every fifth
+        let drifting: Vec<f32> = (0..100).map(|tick| 3000.0 + tick as f32 * 0.5).collect();
+        assert_eq!(feed(&mut hold, &mut now, &drifting), None);
+        // A kick right before the duration is up, settling starts at it
+        let mut readings = restless(3000.0, 9);
+        readings.push(3010.0);
+        assert_eq!(feed(&mut hold, &mut now, &readings), None);
+        assert_eq!(feed(&mut hold, &mut now, &restless(3010.0, 20)), Some(9));
+    }
+
+    #[test]
+    fn rearms_once_the_scale_emptied() {
+        let mut hold = Hold::new(Some(AUTO));
+        let mut now = Instant::now();
+        assert!(feed(&mut hold, &mut now, &restless(5000.0, 20)).is_some());
+        assert!(hold.release());
+        // The same load is not held again
+        assert_eq!(feed(&mut hold, &mut now, &restless(5000.0, 50)), None);
+        assert_eq!(hold.state(), HoldState::Live);
+        // Nor after falling to just over ten bands
+        assert_eq!(feed(&mut hold, &mut now, &restless(21.0, 50)), None);
+        feed(&mut hold, &mut now, &[0.0]);
+        assert_eq!(feed(&mut hold, &mut now, &restless(4000.0, 20)), Some(10));
+    }
+
+    #[test]
+    fn manual_hold_trims_the_extremes() {
+        let mut hold = Hold::new(None);
+        let mut now = Instant::now();
+        feed(&mut hold, &mut now, &[1000.0; 4]);
+        assert_eq!(hold.hold(), None);
+        // A kick each way among eight readings is trimmed off
+        feed(&mut hold, &mut now, &[1000.0, 1500.0, 400.0, 1000.0]);
+        assert_eq!(hold.hold(), Some(1000.0));
+        assert_eq!(
+            hold.state(),
+            HoldState::Held {
+                grams: 1000.0,
+                auto: false
+            }
+        );
+        // The readings of the last three seconds only
+        feed(&mut hold, &mut now, &[2000.0; 31]);
+        assert_eq!(hold.estimate(), Some(2000.0));
+        hold.clear();
+        assert_eq!(hold.state(), HoldState::Live);
+        assert_eq!(hold.estimate(), None);
+    }
+
+    #[test]
+    fn manual_hold_disarms_the_auto_hold() {
+        let mut hold = Hold::new(Some(AUTO));
+        let mut now = Instant::now();
+        feed(&mut hold, &mut now, &restless(5000.0, 5));
+        assert!(hold.hold().is_some());
+        assert!(hold.release());
+        assert_eq!(feed(&mut hold, &mut now, &restless(5000.0, 50)), None);
+        assert!(!hold.release());
+    }
+
+    #[test]
+    fn no_auto_hold_when_off() {
+        let mut hold = Hold::new(None);
+        let mut now = Instant::now();
+        assert_eq!(feed(&mut hold, &mut now, &restless(5000.0, 100)), None);
+        hold.configure(Some(AUTO));
+        assert_eq!(feed(&mut hold, &mut now, &restless(5000.0, 20)), Some(10));
+    }
+}
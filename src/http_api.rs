@@ -0,0 +1,625 @@
+mod websocket;
+
+use std::{sync::mpsc::Receiver, time::Duration};
+
+use esp_idf_svc::{
+    http::{
+        server::{Configuration, EspHttpConnection, EspHttpServer, Request},
+        Headers, Method,
+    },
+    io::{Read, Write},
+    systime::EspSystemTime,
+};
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use self::websocket::{WsClients, CLIENTS_HEAP_NEED};
+use crate::{
+    cal_transfer::CalibrationRecord,
+    certified::{self, RESTRICTIONS},
+    command_channel::{Command, CommandError, CommandOutcome, CommandSender, RemoteCalibration},
+    counters,
+    datalog::{DataLogHandle, DATALOG_CSV_HEADER},
+    device, display,
+    events::WeightEvent,
+    format::{format_weight, milligrams, shown_unit, FormatOpts},
+    governor::{self, Subsystem},
+    history::Granularity,
+    lock::{parse_pin, LockError, MAX_PIN},
+    logger,
+    ota::{OtaError, OtaHandle},
+    procedure::CalibrationStatus,
+    reset::ResetLog,
+    shutdown::{self, ShutdownReason},
+    snapshot::SharedSnapshot,
+    time::Timestamp,
+    time_average::{SharedTimeWeighted, DEFAULT_AVERAGE_WINDOW, MAX_AVERAGE_WINDOW},
+    wifi::{WifiHandle, WifiState},
+};
+
+/// Port the API is served on
+pub const HTTP_PORT: u16 = 80;
+
+const HTTP_TASK_STACK_SIZE: usize = 4 * 1024;
+/// Period the Wi-Fi state is checked at to start or stop the server
+const WIFI_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+/// Time given to the response of a firmware update before the restart
+const UPDATE_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Largest JSON body accepted by the endpoints taking one
+const MAX_JSON_BODY_LEN: usize = 256;
+
+/// Live weight page served at `/`
+const DASHBOARD_PAGE: &str = include_str!("http_api/dashboard.html");
+
+/// Handles of the firmware the endpoints read from and act through
+pub struct HttpHandles {
+    pub snapshot: SharedSnapshot,
+    /// Actions run on the main loop like console commands
+    pub commands: CommandSender,
+    pub datalog: Option<DataLogHandle>,
+    pub resets: Option<ResetLog>,
+    pub ota: OtaHandle,
+    /// Integral of the weight the time-weighted average is taken from
+    pub time_weighted: SharedTimeWeighted,
+}
+
+/// Start the task serving the HTTP API and the live weight page. The server
+/// runs while Wi-Fi is connected and is restarted after a reconnect.
+pub fn start_http_task(
+    wifi: WifiHandle,
+    events: Receiver<WeightEvent>,
+    handles: HttpHandles,
+) -> anyhow::Result<()> {
+    let ws_clients = WsClients::default();
+    let stopped = ws_clients.clone();
+    if !governor::admit(Subsystem::WebSocket, CLIENTS_HEAP_NEED, move || {
+        stopped.disable()
+    }) {
+        ws_clients.disable();
+    }
+    websocket::start_broadcast_task(ws_clients.clone(), events)?;
+    std::thread::Builder::new()
+        .name("http".to_string())
+        .stack_size(HTTP_TASK_STACK_SIZE)
+        .spawn(move || http_task(wifi, ws_clients, handles))?;
+    Ok(())
+}
+
+fn http_task(wifi: WifiHandle, ws_clients: WsClients, handles: HttpHandles) {
+    let mut server = None;
+    loop {
+        let connected = wifi.state() == WifiState::Connected;
+        if connected && server.is_none() {
+            match start_server(&handles, &ws_clients) {
+                Ok(started) => {
+                    info!("HTTP API started");
+                    server = Some(started);
+                }
+                Err(err) => warn!("Failed to start HTTP API: {:?}", err),
+            }
+        } else if !connected && server.take().is_some() {
+            ws_clients.clear();
+            info!("HTTP API stopped");
+        }
+        std::thread::sleep(WIFI_POLL_PERIOD);
+    }
+}
+
+fn respond_json(
+    request: Request<&mut EspHttpConnection>,
+    status: u16,
+    mut body: Value,
+) -> anyhow::Result<()> {
+    device::stamp().insert_into(&mut body);
+    request
+        .into_response(status, None, &[("Content-Type", "application/json")])?
+        .write_all(body.to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Value of a parameter of the query string, as it is in the URI
+fn query_param<'u>(uri: &'u str, name: &str) -> Option<&'u str> {
+    let (_, query) = uri.split_once('?')?;
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+/// The JSON body of the request, `None` when it is missing, too large or
+/// not JSON
+fn read_json_body(request: &mut Request<&mut EspHttpConnection>) -> Option<Value> {
+    let len = request.content_len()? as usize;
+    if len > MAX_JSON_BODY_LEN {
+        return None;
+    }
+    let mut body = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        match request.read(&mut body[read..]) {
+            Ok(0) | Err(_) => return None,
+            Ok(count) => read += count,
+        }
+    }
+    serde_json::from_slice(&body).ok()
+}
+
+/// PIN of a JSON body, as a string of 4 digits or as a number
+fn body_pin(body: &Value) -> Option<u16> {
+    match &body["pin"] {
+        Value::String(pin) => parse_pin(pin),
+        Value::Number(pin) => pin
+            .as_u64()
+            .filter(|&pin| pin <= u64::from(MAX_PIN))
+            .map(|pin| pin as u16),
+        _ => None,
+    }
+}
+
+/// Status of a request the lock refused
+fn lock_status(err: LockError) -> u16 {
+    match err {
+        LockError::RateLimited(_) => 429,
+        LockError::Locked | LockError::WrongPin | LockError::WrongPattern => 403,
+    }
+}
+
+/// Respond with what became of a command sent to the main loop
+fn respond_outcome(
+    request: Request<&mut EspHttpConnection>,
+    outcome: Result<CommandOutcome, CommandError>,
+) -> anyhow::Result<()> {
+    match outcome {
+        Ok(CommandOutcome::Done) => respond_json(request, 200, json!({ "status": "ok" })),
+        Ok(CommandOutcome::Started) => respond_json(request, 202, json!({ "status": "started" })),
+        // Still runs once the main loop gets to it
+        Ok(CommandOutcome::Queued) | Err(CommandError::Timeout) => {
+            respond_json(request, 202, json!({ "status": "queued" }))
+        }
+        Ok(CommandOutcome::Locked(err)) => respond_json(
+            request,
+            lock_status(err),
+            json!({ "error": err.to_string() }),
+        ),
+        Ok(CommandOutcome::Refused(reason)) => {
+            respond_json(request, 409, json!({ "error": reason }))
+        }
+        Err(CommandError::Disconnected) => {
+            respond_json(request, 503, json!({ "error": "scale unavailable" }))
+        }
+    }
+}
+
+fn calibration_json(status: CalibrationStatus) -> Value {
+    let mut json = json!({ "status": status.name() });
+    match status {
+        CalibrationStatus::WaitingEmpty => json["waiting_for"] = json!("empty_scale"),
+        CalibrationStatus::WaitingWeight { grams } => {
+            json["waiting_for"] = json!("weight");
+            json["weight_grams"] = json!(grams);
+        }
+        CalibrationStatus::Done { scale_factor } => json["factor"] = json!(scale_factor),
+        CalibrationStatus::Failed(err) => json["error"] = json!(err.to_string()),
+        CalibrationStatus::Idle | CalibrationStatus::Taring | CalibrationStatus::Weighing => {}
+    }
+    json
+}
+
+fn start_server(
+    handles: &HttpHandles,
+    ws_clients: &WsClients,
+) -> anyhow::Result<EspHttpServer<'static>> {
+    let HttpHandles {
+        snapshot,
+        commands,
+        datalog,
+        resets,
+        ota,
+        time_weighted,
+    } = handles;
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: HTTP_PORT,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, |request| -> anyhow::Result<()> {
+        request
+            .into_response(200, None, &[("Content-Type", "text/html")])?
+            .write_all(DASHBOARD_PAGE.as_bytes())?;
+        Ok(())
+    })?;
+
+    let ws_clients = ws_clients.clone();
+    server.ws_handler("/ws", move |ws| {
+        websocket::handle_connection(&ws_clients, ws)
+    })?;
+
+    let weight_snapshot = snapshot.clone();
+    server.fn_handler("/weight", Method::Get, move |request| {
+        let snapshot = weight_snapshot.get();
+        let timestamp = Timestamp::now();
+        let opts = FormatOpts::default();
+        let formatted = format!(
+            "{}{}",
+            format_weight(milligrams(snapshot.grams), snapshot.unit, &opts),
+            shown_unit(snapshot.unit, &opts).symbol()
+        );
+        respond_json(
+            request,
+            200,
+            json!({
+                "grams": snapshot.grams,
+                "stable": snapshot.stable,
+                // null before the first reading
+                "quality": snapshot.reading().map(|reading| reading.quality.name()),
+                "demo": snapshot.demo,
+                "idle": snapshot.idle.name(),
+                "unit": snapshot.unit.symbol(),
+                "formatted": formatted,
+                // null while the display shows the weight
+                "substance": snapshot.volume.map(|volume| volume.substance.name()),
+                "ml": snapshot.volume.map(|volume| volume.ml(snapshot.grams)),
+                // null before the first reading
+                "reading_age_ms": snapshot.reading_age().map(|age| age.as_millis() as u64),
+                "uptime_s": EspSystemTime.now().as_secs(),
+                // null until the clock is synchronized
+                "time": timestamp.is_wall_clock().then(|| timestamp.to_string()),
+                "battery": snapshot.battery_voltage.map(|voltage| json!({
+                    "voltage": voltage,
+                    "percent": snapshot.battery_percent,
+                })),
+            }),
+        )
+    })?;
+
+    let calibration_snapshot = snapshot.clone();
+    server.fn_handler("/calibration", Method::Get, move |request| {
+        let snapshot = calibration_snapshot.get();
+        let linearity = snapshot.linearity.map(|report| {
+            let points: Vec<Value> = report
+                .points()
+                .iter()
+                .map(|point| {
+                    json!({
+                        "grams": point.grams,
+                        "counts": point.counts,
+                        "deviation_grams": report.deviation_grams(point),
+                    })
+                })
+                .collect();
+            json!({
+                "points": points,
+                "max_deviation_grams": report.max_deviation_grams(),
+                "max_deviation_percent": report.max_deviation_percent(),
+                "full_scale_grams": report.full_scale_grams,
+                "checked_epoch_s": (report.checked.epoch_s > 0).then_some(report.checked.epoch_s),
+                "checked_boot": report.checked.boot,
+            })
+        });
+        respond_json(
+            request,
+            200,
+            json!({
+                "calibrated": snapshot.scale_factor.is_some(),
+                "factor": snapshot.scale_factor,
+                "offset": snapshot.offset,
+                "calibration_weight_grams": snapshot.calibration_weight,
+                "linearity": linearity,
+            }),
+        )
+    })?;
+
+    // Checked here so a mistyped blob gets its error back, whether it fits
+    // the sensor is checked on the main loop
+    let commands = commands.clone();
+    server.fn_handler("/calibration", Method::Put, move |mut request| {
+        let body = read_json_body(&mut request).unwrap_or_default();
+        let Some(blob) = body["blob"].as_str() else {
+            return respond_json(
+                request,
+                400,
+                json!({ "error": "expected { \"blob\": <hex of cal export> }" }),
+            );
+        };
+        let record = match CalibrationRecord::decode(blob) {
+            Ok(record) => record,
+            Err(err) => return respond_json(request, 400, json!({ "error": err.to_string() })),
+        };
+        let import = Command::ImportCalibration(record);
+        let import = match body_pin(&body) {
+            Some(pin) => Command::WithPin(pin, Box::new(import)),
+            None => import,
+        };
+        respond_outcome(request, commands.request(import))
+    })?;
+
+    let resets = resets.clone();
+    server.fn_handler("/status", Method::Get, move |request| {
+        let resets = resets.as_ref().map(|resets| {
+            let counts: serde_json::Map<String, Value> = resets
+                .counts()
+                .into_iter()
+                .map(|(reason, count)| (reason.name().to_string(), json!(count)))
+                .collect();
+            json!({
+                "reason": resets.reason().name(),
+                "counts": counts,
+                "last_panic": resets.last_panic(),
+            })
+        });
+        let counters: serde_json::Map<String, Value> = counters::values()
+            .into_iter()
+            .map(|(counter, value)| (counter.name().to_string(), json!(value)))
+            .collect();
+        let suppressed: serde_json::Map<String, Value> = governor::suppressed()
+            .into_iter()
+            .map(|(subsystem, suppression)| {
+                let reason = json!({
+                    "reason": suppression.name(),
+                    "free_heap": suppression.free_heap(),
+                });
+                (subsystem.name().to_string(), reason)
+            })
+            .collect();
+        let audit = certified::audit();
+        respond_json(
+            request,
+            200,
+            json!({
+                "uptime_s": EspSystemTime.now().as_secs(),
+                "resets": resets,
+                "counters": counters,
+                "suppressed": suppressed,
+                "display": {
+                    "address": display::detected_address()
+                        .map(|address| format!("0x{:02X}", address)),
+                    "headless": display::is_headless(),
+                },
+                "certified": {
+                    "active": audit.active,
+                    "audit_count": audit.count,
+                    "corrupt": audit.corrupt,
+                    "restrictions": if audit.active { RESTRICTIONS.to_vec() } else { Vec::new() },
+                },
+            }),
+        )
+    })?;
+
+    // Streamed into flash, the image is far too large to buffer
+    let ota = ota.clone();
+    let update_commands = commands.clone();
+    server.fn_handler("/update", Method::Post, move |mut request| {
+        if !ota.is_enabled() {
+            return respond_json(request, 403, json!({ "error": "updates are disabled" }));
+        }
+        let authorized = request
+            .header("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .is_some_and(|token| ota.is_authorized(token));
+        if !authorized {
+            return respond_json(request, 401, json!({ "error": "invalid token" }));
+        }
+        let Some(len) = request.content_len() else {
+            return respond_json(request, 411, json!({ "error": "missing Content-Length" }));
+        };
+
+        info!("Firmware update of {} bytes started", len);
+        match ota.update(&mut request, len as usize) {
+            Ok(()) => {
+                respond_json(request, 200, json!({ "status": "restarting" }))?;
+                std::thread::sleep(UPDATE_RESTART_DELAY);
+                // The main loop saves its state first, unless it is gone
+                let restart = Command::Restart(ShutdownReason::Update);
+                if update_commands.send(restart).is_err() {
+                    shutdown::restart(ShutdownReason::Update);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                warn!("Firmware update failed: {}", err);
+                let status = match err {
+                    OtaError::Busy => 409,
+                    OtaError::Receive | OtaError::Truncated { .. } => 400,
+                    OtaError::Esp(_) => 500,
+                };
+                respond_json(request, status, json!({ "error": err.to_string() }))
+            }
+        }
+    })?;
+
+    // Written line by line, the log is too large to buffer
+    let datalog = datalog.clone();
+    server.fn_handler("/log.csv", Method::Get, move |request| {
+        let Some(datalog) = &datalog else {
+            return respond_json(request, 404, json!({ "error": "logging is disabled" }));
+        };
+        // The records span several boots, the identity goes in the headers
+        let stamp = device::stamp();
+        let (boot, seq) = (stamp.boot.to_string(), stamp.seq.to_string());
+        let headers = [
+            ("Content-Type", "text/csv"),
+            ("X-Device-Id", stamp.device_id),
+            ("X-Boot", boot.as_str()),
+            ("X-Seq", seq.as_str()),
+        ];
+        let mut response = request.into_response(200, None, &headers)?;
+        response.write_all(format!("{}\n", DATALOG_CSV_HEADER).as_bytes())?;
+        for record in datalog.iter_records() {
+            response.write_all(format!("{}\n", record.to_csv()).as_bytes())?;
+        }
+        Ok(())
+    })?;
+
+    // The hours or days asked for in the query, e.g.
+    // `/history?granularity=hour&hours=48`
+    let datalog = datalog.clone();
+    server.fn_handler("/history", Method::Get, move |request| {
+        let Some(datalog) = &datalog else {
+            return respond_json(request, 404, json!({ "error": "logging is disabled" }));
+        };
+        let uri = request.uri().to_string();
+        let granularity = match query_param(&uri, "granularity") {
+            Some(name) => match Granularity::from_name(name) {
+                Some(granularity) => granularity,
+                None => {
+                    return respond_json(
+                        request,
+                        400,
+                        json!({ "error": "granularity is hour or day" }),
+                    )
+                }
+            },
+            None => Granularity::Hour,
+        };
+        let count_param = match granularity {
+            Granularity::Hour => "hours",
+            Granularity::Day => "days",
+        };
+        let count = match query_param(&uri, count_param) {
+            Some(arg) => match arg
+                .parse::<u32>()
+                .ok()
+                .filter(|count| (1..=granularity.kept()).contains(count))
+            {
+                Some(count) => count,
+                None => {
+                    let error = format!("{} is 1 to {}", count_param, granularity.kept());
+                    return respond_json(request, 400, json!({ "error": error }));
+                }
+            },
+            None => granularity.default_count(),
+        };
+        match datalog.history(granularity, count) {
+            Ok(bins) => {
+                let bins: Vec<Value> = bins
+                    .iter()
+                    .map(|bin| {
+                        json!({
+                            "start": bin.timestamp().to_string(),
+                            "min_grams": bin.min_grams(),
+                            "max_grams": bin.max_grams(),
+                            "mean_grams": bin.mean_grams(),
+                            "count": bin.count,
+                        })
+                    })
+                    .collect();
+                respond_json(
+                    request,
+                    200,
+                    json!({ "granularity": granularity.name(), "bins": bins }),
+                )
+            }
+            Err(err) => respond_json(request, 500, json!({ "error": format!("{:?}", err) })),
+        }
+    })?;
+
+    let time_weighted = time_weighted.clone();
+    server.fn_handler("/average", Method::Get, move |request| {
+        let uri = request.uri().to_string();
+        let max_minutes = MAX_AVERAGE_WINDOW.as_secs() / 60;
+        let window = match query_param(&uri, "minutes") {
+            Some(arg) => match arg
+                .parse::<u64>()
+                .ok()
+                .filter(|minutes| (1..=max_minutes).contains(minutes))
+            {
+                Some(minutes) => Duration::from_secs(minutes * 60),
+                None => {
+                    let error = format!("minutes is 1 to {}", max_minutes);
+                    return respond_json(request, 400, json!({ "error": error }));
+                }
+            },
+            None => DEFAULT_AVERAGE_WINDOW,
+        };
+        let average = time_weighted.average(window);
+        respond_json(
+            request,
+            200,
+            json!({
+                "grams": average.grams,
+                "window_s": window.as_secs(),
+                "covered_s": average.covered.as_secs_f32(),
+                "coverage_percent": average.coverage_percent(),
+            }),
+        )
+    })?;
+
+    server.fn_handler("/logs", Method::Get, |request| -> anyhow::Result<()> {
+        let mut response = request.into_response(200, None, &[("Content-Type", "text/plain")])?;
+        for line in logger::recent_lines() {
+            response.write_all(format!("{}\n", line).as_bytes())?;
+        }
+        Ok(())
+    })?;
+
+    // The tare runs on the main loop like a console command, so the request
+    // only learns whether it started
+    let commands = commands.clone();
+    server.fn_handler("/tare", Method::Post, move |request| {
+        respond_outcome(request, commands.request(Command::Tare))
+    })?;
+
+    let commands = commands.clone();
+    server.fn_handler("/identify", Method::Post, move |request| {
+        respond_outcome(request, commands.request(Command::Identify))
+    })?;
+
+    // The calibration runs on the main loop, advanced by the steps in place
+    // of the presses. Its prompts still show on the display.
+    // A locked scale takes the PIN along with the weight
+    let commands = commands.clone();
+    let start_snapshot = snapshot.clone();
+    server.fn_handler("/calibrate/start", Method::Post, move |mut request| {
+        let body = read_json_body(&mut request).unwrap_or_default();
+        let weight_grams = body["weight_grams"]
+            .as_f64()
+            .map(|grams| grams as f32)
+            .filter(|grams| grams.is_finite() && *grams > 0.0);
+        let Some(weight_grams) = weight_grams else {
+            return respond_json(
+                request,
+                400,
+                json!({ "error": "expected { \"weight_grams\": <positive number> }" }),
+            );
+        };
+        let status = start_snapshot.get().calibration;
+        if status.is_running() {
+            return respond_json(request, 409, calibration_json(status));
+        }
+        let start = Command::RemoteCalibration(RemoteCalibration::Start(weight_grams));
+        let start = match body_pin(&body) {
+            Some(pin) => Command::WithPin(pin, Box::new(start)),
+            None => start,
+        };
+        respond_outcome(request, commands.request(start))
+    })?;
+
+    let commands = commands.clone();
+    let step_snapshot = snapshot.clone();
+    server.fn_handler("/calibrate/step", Method::Post, move |request| {
+        let status = step_snapshot.get().calibration;
+        if !status.awaits_step() {
+            return respond_json(request, 409, calibration_json(status));
+        }
+        let step = Command::RemoteCalibration(RemoteCalibration::Step);
+        respond_outcome(request, commands.request(step))
+    })?;
+
+    let status_snapshot = snapshot.clone();
+    server.fn_handler("/calibrate/status", Method::Get, move |request| {
+        respond_json(
+            request,
+            200,
+            calibration_json(status_snapshot.get().calibration),
+        )
+    })?;
+
+    let commands = commands.clone();
+    server.fn_handler("/alarm/ack", Method::Post, move |request| {
+        respond_outcome(request, commands.request(Command::AcknowledgeAlarms))
+    })?;
+
+    Ok(server)
+}
@@ -0,0 +1,176 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::{
+    http::server::ws::{EspHttpWsConnection, EspHttpWsDetachedSender},
+    ws::FrameType,
+};
+use esp_idf_sys::EspError;
+use log::{info, warn};
+
+use crate::{device, events::WeightEvent};
+
+/// Minimum time between two frames, about 10 Hz
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_CLIENTS: usize = 4;
+/// Frames queued per client, a client further behind is dropped
+const CLIENT_QUEUE_LEN: usize = 8;
+const BROADCAST_TASK_STACK_SIZE: usize = 3 * 1024;
+const CLIENT_TASK_STACK_SIZE: usize = 3 * 1024;
+/// Incoming frames are not used, but have to be read
+const MAX_INCOMING_FRAME_LEN: usize = 64;
+/// Heap the clients take at most, their tasks and queues
+pub const CLIENTS_HEAP_NEED: u32 = (MAX_CLIENTS * (CLIENT_TASK_STACK_SIZE + 1024)) as u32;
+
+struct Client {
+    session: i32,
+    frames: SyncSender<Arc<str>>,
+}
+
+/// Connected WebSocket clients, each with its own send queue and task so a
+/// slow client never holds up the others
+#[derive(Clone, Default)]
+pub struct WsClients {
+    clients: Arc<Mutex<Vec<Client>>>,
+    /// Set once the heap governor stopped the clients
+    disabled: Arc<AtomicBool>,
+}
+
+impl WsClients {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Client>> {
+        self.clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn add(&self, session: i32, sender: EspHttpWsDetachedSender) -> anyhow::Result<()> {
+        if self.disabled.load(Ordering::Relaxed) {
+            return Err(anyhow::anyhow!(
+                "WebSocket clients are off for lack of heap"
+            ));
+        }
+        let mut clients = self.lock();
+        if clients.len() >= MAX_CLIENTS {
+            return Err(anyhow::anyhow!("Too many WebSocket clients"));
+        }
+        let (frames_tx, frames_rx) = sync_channel(CLIENT_QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("ws_client".to_string())
+            .stack_size(CLIENT_TASK_STACK_SIZE)
+            .spawn(move || client_task(sender, frames_rx))?;
+        clients.push(Client {
+            session,
+            frames: frames_tx,
+        });
+        info!("WebSocket client {} connected", session);
+        Ok(())
+    }
+
+    fn remove(&self, session: i32) {
+        self.lock().retain(|client| client.session != session);
+    }
+
+    /// Drop all clients, e.g. when the server stops
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Drop all clients and refuse new ones until the next restart
+    pub fn disable(&self) {
+        self.disabled.store(true, Ordering::Relaxed);
+        self.clear();
+    }
+
+    fn broadcast(&self, frame: Arc<str>) {
+        self.lock()
+            .retain(|client| match client.frames.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Dropping slow WebSocket client {}", client.session);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+fn client_task(mut sender: EspHttpWsDetachedSender, frames: Receiver<Arc<str>>) {
+    while let Ok(frame) = frames.recv() {
+        if sender
+            .send(FrameType::Text(false), frame.as_bytes())
+            .is_err()
+        {
+            return;
+        }
+    }
+    // Dropped from the client list, tell the browser
+    let _ = sender.send(FrameType::Close, &[]);
+}
+
+/// Handle a WebSocket event for `/ws`
+pub fn handle_connection(
+    clients: &WsClients,
+    ws: &mut EspHttpWsConnection,
+) -> Result<(), EspError> {
+    if ws.is_new() {
+        let sender = ws.create_detached_sender()?;
+        if let Err(err) = clients.add(ws.session(), sender) {
+            warn!("Rejected WebSocket client: {:?}", err);
+        }
+    } else if ws.is_closed() {
+        clients.remove(ws.session());
+        info!("WebSocket client {} disconnected", ws.session());
+    } else {
+        let mut buf = [0u8; MAX_INCOMING_FRAME_LEN];
+        let (_, len) = ws.recv(&mut [])?;
+        if len <= buf.len() {
+            ws.recv(&mut buf)?;
+        }
+    }
+    Ok(())
+}
+
+/// Start the task sending the filtered weight to all the WebSocket clients
+pub fn start_broadcast_task(
+    clients: WsClients,
+    events: Receiver<WeightEvent>,
+) -> anyhow::Result<()> {
+    std::thread::Builder::new()
+        .name("ws_broadcast".to_string())
+        .stack_size(BROADCAST_TASK_STACK_SIZE)
+        .spawn(move || broadcast_task(clients, events))?;
+    Ok(())
+}
+
+fn broadcast_task(clients: WsClients, events: Receiver<WeightEvent>) {
+    let mut last_frame: Option<Instant> = None;
+    // Latest reading not sent yet, so the final value always goes out
+    let mut pending = None;
+    loop {
+        match events.recv_timeout(FRAME_INTERVAL) {
+            Ok(WeightEvent::Changed { grams, stable }) => pending = Some((grams, stable)),
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let due = last_frame.map_or(true, |last_frame| last_frame.elapsed() >= FRAME_INTERVAL);
+        if let Some((grams, stable)) = pending.filter(|_| due) {
+            pending = None;
+            last_frame = Some(Instant::now());
+            let stamp = device::stamp();
+            clients.broadcast(
+                format!(
+                    r#"{{"grams":{:.1},"stable":{},"device_id":"{}","boot":{},"seq":{}}}"#,
+                    grams, stable, stamp.device_id, stamp.boot, stamp.seq
+                )
+                .into(),
+            );
+        }
+    }
+}
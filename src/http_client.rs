@@ -0,0 +1,38 @@
+//! Outgoing HTTP requests, through the esp-idf client. `https://` URLs are
+//! verified against the certificate bundle of esp-idf, which covers the
+//! common public authorities, so no certificate has to be set up per
+//! endpoint.
+
+use std::time::Duration;
+
+use esp_idf_svc::http::{
+    client::{Configuration, EspHttpConnection},
+    Method,
+};
+use esp_idf_sys::EspError;
+
+/// Longest wait for the connection, and then for the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POST a JSON body. Returns the status of the response, its body is
+/// ignored.
+pub fn post_json(url: &str, body: &str) -> Result<u16, EspError> {
+    let mut connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(REQUEST_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let content_length = body.len().to_string();
+    let headers = [
+        ("Content-Type", "application/json"),
+        ("Content-Length", content_length.as_str()),
+    ];
+    connection.initiate_request(Method::Post, url, &headers)?;
+    let mut rest = body.as_bytes();
+    while !rest.is_empty() {
+        let written = connection.write(rest)?;
+        rest = &rest[written..];
+    }
+    connection.initiate_response()?;
+    Ok(connection.status())
+}
@@ -0,0 +1,617 @@
+//! Messages shown on the display, in the language of the settings. Every
+//! message is a field of `Strings` and each language one const table of
+//! them, so a language takes a table and a `Language` variant. The console
+//! and the log stay in English.
+//!
+//! A message with parameters has a `{}` for each, filled in by `trf` in
+//! their order on a buffer of its own instead of the heap.
+
+use std::{
+    fmt::{self, Display, Write},
+    ops::Deref,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Longest message with its parameters, longer ones are cut
+const MESSAGE_LEN: usize = 96;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    English,
+    German,
+}
+
+impl Language {
+    pub const ALL: [Language; 2] = [Language::English, Language::German];
+    /// Names of `ALL` in their own language, for the menu
+    pub const NAMES: [&'static str; 2] = ["English", "Deutsch"];
+
+    pub fn index(self) -> u8 {
+        match self {
+            Language::English => 0,
+            Language::German => 1,
+        }
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+
+    /// ISO 639-1 code, as set from the console
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::German => "de",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|language| language.code().eq_ignore_ascii_case(code))
+    }
+
+    fn strings(self) -> &'static Strings {
+        match self {
+            Language::English => &ENGLISH,
+            Language::German => &GERMAN,
+        }
+    }
+}
+
+/// A message shown on the display
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StringId {
+    Taring,
+    Calibrating,
+    Weighing,
+    PressToCancel,
+    PressHoldToCancel,
+    PressToContinue,
+    /// Followed by the press to give
+    EmptyScale,
+    /// The weight, then the press to give
+    PlaceWeight,
+    /// The weight, its number, the number of weights, then the press to give
+    PlaceLinearityWeight,
+    /// Progress of the noise test in percent
+    MeasuringNoise,
+    /// The noise test stopped, the platform was touched
+    ScaleMoved,
+    NoiseTest,
+    /// The error
+    Error,
+    ErrorRestarting,
+    /// The check, then what it found
+    CheckFailed,
+    SensorLost,
+    LowBattery,
+    Restarting,
+    FactoryResetting,
+    ResetCancelled,
+    /// The seconds left
+    ReleaseToReset,
+    EnterPin,
+    /// The clicks entered so far
+    EnterPattern,
+    Unlocked,
+    CheckCancelled,
+    CalibrationCancelled,
+    /// A tare asked for again right after one
+    AlreadyTared,
+    /// The scale keeps reading below zero
+    NegativeHint,
+    PlaceTheLoad,
+    CreepMeasured,
+    /// The weight moved since the restart
+    MovedOff,
+    /// The percentage written
+    Updating,
+    MenuTitle,
+    On,
+    Off,
+    /// The bound a number was moved to
+    Min,
+    Max,
+    SoftTare,
+    Tare,
+    Untare,
+    NetGross,
+    Clear,
+    Hold,
+    BrewTimer,
+    NewSession,
+    Unlock,
+    Units,
+    Resolution,
+    CalWeight,
+    Brightness,
+    Language,
+    Idle,
+    DimSecs,
+    OffMins,
+    SleepMins,
+    Calibrate,
+    Linearity,
+    Start,
+    Weight1,
+    Weight2,
+    Weight3,
+    Weight4,
+    CalReminder,
+    Snooze7d,
+    Dismiss,
+    AfterDays,
+    Recipe,
+    DoseGrams,
+    Ratio,
+    Reset,
+    FactoryReset,
+    WifiSetup,
+    PairScales,
+    Setup,
+    /// The step, then the number of steps
+    SetupStep,
+    SetupHint,
+    SetupNumberHint,
+    Rotation,
+    CalibrateNow,
+    SetupDone,
+    NotCalibrated,
+    Volume,
+    ShowMl,
+    Substance,
+    Density,
+    SoakRunning,
+    CertifiedOn,
+    /// The seconds left to hold the weight down
+    GestureHold,
+    GestureRelease,
+    /// The presses counted, then the seconds left for the next one
+    GesturePresses,
+}
+
+/// Every message of a language
+pub struct Strings {
+    pub taring: &'static str,
+    pub calibrating: &'static str,
+    pub weighing: &'static str,
+    pub press_to_cancel: &'static str,
+    pub press_hold_to_cancel: &'static str,
+    pub press_to_continue: &'static str,
+    pub empty_scale: &'static str,
+    pub place_weight: &'static str,
+    pub place_linearity_weight: &'static str,
+    pub measuring_noise: &'static str,
+    pub scale_moved: &'static str,
+    pub noise_test: &'static str,
+    pub error: &'static str,
+    pub error_restarting: &'static str,
+    pub check_failed: &'static str,
+    pub sensor_lost: &'static str,
+    pub low_battery: &'static str,
+    pub restarting: &'static str,
+    pub factory_resetting: &'static str,
+    pub reset_cancelled: &'static str,
+    pub release_to_reset: &'static str,
+    pub enter_pin: &'static str,
+    pub enter_pattern: &'static str,
+    pub unlocked: &'static str,
+    pub check_cancelled: &'static str,
+    pub calibration_cancelled: &'static str,
+    pub already_tared: &'static str,
+    pub negative_hint: &'static str,
+    pub place_the_load: &'static str,
+    pub creep_measured: &'static str,
+    pub moved_off: &'static str,
+    pub updating: &'static str,
+    pub menu_title: &'static str,
+    pub on: &'static str,
+    pub off: &'static str,
+    pub min: &'static str,
+    pub max: &'static str,
+    pub soft_tare: &'static str,
+    pub tare: &'static str,
+    pub untare: &'static str,
+    pub net_gross: &'static str,
+    pub clear: &'static str,
+    pub hold: &'static str,
+    pub brew_timer: &'static str,
+    pub new_session: &'static str,
+    pub unlock: &'static str,
+    pub units: &'static str,
+    pub resolution: &'static str,
+    pub cal_weight: &'static str,
+    pub brightness: &'static str,
+    pub language: &'static str,
+    pub idle: &'static str,
+    pub dim_secs: &'static str,
+    pub off_mins: &'static str,
+    pub sleep_mins: &'static str,
+    pub calibrate: &'static str,
+    pub linearity: &'static str,
+    pub start: &'static str,
+    pub weights: [&'static str; 4],
+    pub cal_reminder: &'static str,
+    pub snooze_7d: &'static str,
+    pub dismiss: &'static str,
+    pub after_days: &'static str,
+    pub recipe: &'static str,
+    pub dose_grams: &'static str,
+    pub ratio: &'static str,
+    pub reset: &'static str,
+    pub factory_reset: &'static str,
+    pub wifi_setup: &'static str,
+    pub pair_scales: &'static str,
+    pub setup: &'static str,
+    pub setup_step: &'static str,
+    pub setup_hint: &'static str,
+    pub setup_number_hint: &'static str,
+    pub rotation: &'static str,
+    pub calibrate_now: &'static str,
+    pub setup_done: &'static str,
+    pub not_calibrated: &'static str,
+    pub volume: &'static str,
+    pub show_ml: &'static str,
+    pub substance: &'static str,
+    pub density: &'static str,
+    pub soak_running: &'static str,
+    pub certified_on: &'static str,
+    pub gesture_hold: &'static str,
+    pub gesture_release: &'static str,
+    pub gesture_presses: &'static str,
+}
+
+impl Strings {
+    pub fn get(&self, id: StringId) -> &'static str {
+        match id {
+            StringId::Taring => self.taring,
+            StringId::Calibrating => self.calibrating,
+            StringId::Weighing => self.weighing,
+            StringId::PressToCancel => self.press_to_cancel,
+            StringId::PressHoldToCancel => self.press_hold_to_cancel,
+            StringId::PressToContinue => self.press_to_continue,
+            StringId::EmptyScale => self.empty_scale,
+            StringId::PlaceWeight => self.place_weight,
+            StringId::PlaceLinearityWeight => self.place_linearity_weight,
+            StringId::MeasuringNoise => self.measuring_noise,
+            StringId::ScaleMoved => self.scale_moved,
+            StringId::NoiseTest => self.noise_test,
+            StringId::Error => self.error,
+            StringId::ErrorRestarting => self.error_restarting,
+            StringId::CheckFailed => self.check_failed,
+            StringId::SensorLost => self.sensor_lost,
+            StringId::LowBattery => self.low_battery,
+            StringId::Restarting => self.restarting,
+            StringId::FactoryResetting => self.factory_resetting,
+            StringId::ResetCancelled => self.reset_cancelled,
+            StringId::ReleaseToReset => self.release_to_reset,
+            StringId::EnterPin => self.enter_pin,
+            StringId::EnterPattern => self.enter_pattern,
+            StringId::Unlocked => self.unlocked,
+            StringId::CheckCancelled => self.check_cancelled,
+            StringId::CalibrationCancelled => self.calibration_cancelled,
+            StringId::AlreadyTared => self.already_tared,
+            StringId::NegativeHint => self.negative_hint,
+            StringId::PlaceTheLoad => self.place_the_load,
+            StringId::CreepMeasured => self.creep_measured,
+            StringId::MovedOff => self.moved_off,
+            StringId::Updating => self.updating,
+            StringId::MenuTitle => self.menu_title,
+            StringId::On => self.on,
+            StringId::Off => self.off,
+            StringId::Min => self.min,
+            StringId::Max => self.max,
+            StringId::SoftTare => self.soft_tare,
+            StringId::Tare => self.tare,
+            StringId::Untare => self.untare,
+            StringId::NetGross => self.net_gross,
+            StringId::Clear => self.clear,
+            StringId::Hold => self.hold,
+            StringId::BrewTimer => self.brew_timer,
+            StringId::NewSession => self.new_session,
+            StringId::Unlock => self.unlock,
+            StringId::Units => self.units,
+            StringId::Resolution => self.resolution,
+            StringId::CalWeight => self.cal_weight,
+            StringId::Brightness => self.brightness,
+            StringId::Language => self.language,
+            StringId::Idle => self.idle,
+            StringId::DimSecs => self.dim_secs,
+            StringId::OffMins => self.off_mins,
+            StringId::SleepMins => self.sleep_mins,
+            StringId::Calibrate => self.calibrate,
+            StringId::Linearity => self.linearity,
+            StringId::Start => self.start,
+            StringId::Weight1 => self.weights[0],
+            StringId::Weight2 => self.weights[1],
+            StringId::Weight3 => self.weights[2],
+            StringId::Weight4 => self.weights[3],
+            StringId::CalReminder => self.cal_reminder,
+            StringId::Snooze7d => self.snooze_7d,
+            StringId::Dismiss => self.dismiss,
+            StringId::AfterDays => self.after_days,
+            StringId::Recipe => self.recipe,
+            StringId::DoseGrams => self.dose_grams,
+            StringId::Ratio => self.ratio,
+            StringId::Reset => self.reset,
+            StringId::FactoryReset => self.factory_reset,
+            StringId::WifiSetup => self.wifi_setup,
+            StringId::PairScales => self.pair_scales,
+            StringId::Setup => self.setup,
+            StringId::SetupStep => self.setup_step,
+            StringId::SetupHint => self.setup_hint,
+            StringId::SetupNumberHint => self.setup_number_hint,
+            StringId::Rotation => self.rotation,
+            StringId::CalibrateNow => self.calibrate_now,
+            StringId::SetupDone => self.setup_done,
+            StringId::NotCalibrated => self.not_calibrated,
+            StringId::Volume => self.volume,
+            StringId::ShowMl => self.show_ml,
+            StringId::Substance => self.substance,
+            StringId::Density => self.density,
+            StringId::SoakRunning => self.soak_running,
+            StringId::CertifiedOn => self.certified_on,
+            StringId::GestureHold => self.gesture_hold,
+            StringId::GestureRelease => self.gesture_release,
+            StringId::GesturePresses => self.gesture_presses,
+        }
+    }
+}
+
+pub const ENGLISH: Strings = Strings {
+    taring: "Taring...",
+    calibrating: "Calibrating...",
+    weighing: "Weighing...",
+    press_to_cancel: "Press to cancel",
+    press_hold_to_cancel: "Press, hold=cancel",
+    press_to_continue: "Press to continue",
+    empty_scale: "Empty the scale!\n{}",
+    place_weight: "Place {}g weight\n{}",
+    place_linearity_weight: "Place {}g {}/{}\n{}",
+    measuring_noise: "Keep still {}%",
+    scale_moved: "Scale moved",
+    noise_test: "Noise test",
+    error: "Error: {}",
+    error_restarting: "Error, restarting",
+    check_failed: "{} failed\n{}",
+    sensor_lost: "Sensor lost",
+    low_battery: "LOW BATTERY",
+    restarting: "Restarting...",
+    factory_resetting: "Factory reset...",
+    reset_cancelled: "Reset cancelled",
+    release_to_reset: "Release to reset\nHold to cancel {}",
+    enter_pin: "Enter PIN",
+    enter_pattern: "Enter pattern\n{}",
+    unlocked: "Unlocked",
+    check_cancelled: "Check cancelled",
+    calibration_cancelled: "Calibration cancelled",
+    already_tared: "Already tared",
+    negative_hint: "Press to re-tare",
+    place_the_load: "Place the load",
+    creep_measured: "Creep measured",
+    moved_off: "Moved {}g off",
+    updating: "Updating\n{}%",
+    menu_title: "Menu",
+    on: "On",
+    off: "Off",
+    min: "Min {}",
+    max: "Max {}",
+    soft_tare: "Soft tare",
+    tare: "Tare",
+    untare: "Untare",
+    net_gross: "Net/Gross",
+    clear: "Clear",
+    hold: "Hold",
+    brew_timer: "Brew timer",
+    new_session: "New session",
+    unlock: "Unlock",
+    units: "Units",
+    resolution: "Resolution",
+    cal_weight: "Cal weight",
+    brightness: "Brightness",
+    language: "Language",
+    idle: "Idle",
+    dim_secs: "Dim s",
+    off_mins: "Off min",
+    sleep_mins: "Sleep min",
+    calibrate: "Calibrate",
+    linearity: "Linearity",
+    start: "Start",
+    weights: ["Weight 1", "Weight 2", "Weight 3", "Weight 4"],
+    cal_reminder: "Cal reminder",
+    snooze_7d: "Snooze 7d",
+    dismiss: "Dismiss",
+    after_days: "After days",
+    recipe: "Recipe",
+    dose_grams: "Dose g",
+    ratio: "Ratio 1:",
+    reset: "Reset",
+    factory_reset: "Factory reset",
+    wifi_setup: "Wi-Fi setup",
+    pair_scales: "Pair scales",
+    setup: "Setup",
+    setup_step: "Setup {}/{}",
+    setup_hint: "Hold=OK 2x=skip",
+    setup_number_hint: "2x=OK",
+    rotation: "Rotation",
+    calibrate_now: "Calibrate now",
+    setup_done: "Setup done",
+    not_calibrated: "Not calibrated",
+    volume: "Volume",
+    show_ml: "Show ml",
+    substance: "Substance",
+    density: "Density",
+    soak_running: "Soak test running",
+    certified_on: "Certified mode on",
+    gesture_hold: "Hold {}s",
+    gesture_release: "Release",
+    gesture_presses: "Presses {}, next {}s",
+};
+
+pub const GERMAN: Strings = Strings {
+    taring: "Tarieren...",
+    calibrating: "Kalibrieren...",
+    weighing: "Wiegen...",
+    press_to_cancel: "Drücken=Abbruch",
+    press_hold_to_cancel: "Drücken, halten=Abbr.",
+    press_to_continue: "Drücken=weiter",
+    empty_scale: "Waage leeren!\n{}",
+    place_weight: "{}g auflegen\n{}",
+    place_linearity_weight: "{}g auflegen {}/{}\n{}",
+    measuring_noise: "Ruhig halten {}%",
+    scale_moved: "Waage bewegt",
+    noise_test: "Rauschtest",
+    error: "Fehler: {}",
+    error_restarting: "Fehler, Neustart",
+    check_failed: "{} fehlgeschl.\n{}",
+    sensor_lost: "Sensor fehlt",
+    low_battery: "AKKU LEER",
+    restarting: "Neustart...",
+    factory_resetting: "Zurücksetzen...",
+    reset_cancelled: "Abgebrochen",
+    release_to_reset: "Loslassen=Reset\nHalten=Abbruch {}",
+    enter_pin: "PIN eingeben",
+    enter_pattern: "Muster eingeben\n{}",
+    unlocked: "Entsperrt",
+    check_cancelled: "Prüfung abgebr.",
+    calibration_cancelled: "Kalibr. abgebr.",
+    already_tared: "Schon tariert",
+    negative_hint: "Taste: tarieren",
+    place_the_load: "Last auflegen",
+    creep_measured: "Kriechen gemessen",
+    moved_off: "{}g verschoben",
+    updating: "Update\n{}%",
+    menu_title: "Menü",
+    on: "Ein",
+    off: "Aus",
+    min: "Min {}",
+    max: "Max {}",
+    soft_tare: "Soft-Tara",
+    tare: "Tara",
+    untare: "Tara zurück",
+    net_gross: "Netto/Brutto",
+    clear: "Löschen",
+    hold: "Halten",
+    brew_timer: "Brühtimer",
+    new_session: "Neue Sitzung",
+    unlock: "Entsperren",
+    units: "Einheit",
+    resolution: "Auflösung",
+    cal_weight: "Kal.-Gewicht",
+    brightness: "Helligkeit",
+    language: "Sprache",
+    idle: "Ruhezustand",
+    dim_secs: "Dimmen s",
+    off_mins: "Aus min",
+    sleep_mins: "Schlaf min",
+    calibrate: "Kalibrieren",
+    linearity: "Linearität",
+    start: "Start",
+    weights: ["Gewicht 1", "Gewicht 2", "Gewicht 3", "Gewicht 4"],
+    cal_reminder: "Kal.-Erinnerung",
+    snooze_7d: "7 Tage später",
+    dismiss: "Verwerfen",
+    after_days: "Nach Tagen",
+    recipe: "Rezept",
+    dose_grams: "Dosis g",
+    ratio: "Verhältnis 1:",
+    reset: "Zurücksetzen",
+    factory_reset: "Werksreset",
+    wifi_setup: "WLAN einrichten",
+    pair_scales: "Waagen koppeln",
+    setup: "Einrichtung",
+    setup_step: "Einrichtung {}/{}",
+    setup_hint: "Halten=OK 2x=weiter",
+    setup_number_hint: "2x=OK",
+    rotation: "Drehung",
+    calibrate_now: "Jetzt kalibrieren",
+    setup_done: "Eingerichtet",
+    not_calibrated: "Nicht kalibriert",
+    volume: "Volumen",
+    show_ml: "ml anzeigen",
+    substance: "Stoff",
+    density: "Dichte",
+    soak_running: "Dauertest läuft",
+    certified_on: "Eichmodus aktiv",
+    gesture_hold: "Halten {}s",
+    gesture_release: "Loslassen",
+    gesture_presses: "Drücke {}, nächster {}s",
+};
+
+static LANGUAGE: AtomicU8 = AtomicU8::new(0);
+
+/// Show the messages in `language` from now on
+pub fn set_language(language: Language) {
+    LANGUAGE.store(language.index(), Ordering::Relaxed);
+}
+
+pub fn language() -> Language {
+    Language::from_index(LANGUAGE.load(Ordering::Relaxed)).unwrap_or_default()
+}
+
+/// The message in the current language
+pub fn tr(id: StringId) -> &'static str {
+    language().strings().get(id)
+}
+
+/// The message with its `{}` filled in with `args` in their order
+pub fn trf(id: StringId, args: &[&dyn Display]) -> Message {
+    let mut message = Message::default();
+    let mut args = args.iter();
+    let mut parts = tr(id).split("{}");
+    if let Some(first) = parts.next() {
+        let _ = message.write_str(first);
+    }
+    for part in parts {
+        if let Some(arg) = args.next() {
+            let _ = write!(message, "{}", arg);
+        }
+        let _ = message.write_str(part);
+    }
+    message
+}
+
+/// A message with its parameters, on a buffer of its own
+pub struct Message {
+    buf: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Self {
+            buf: [0; MESSAGE_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl Write for Message {
+    /// Cut at the last whole character that fits
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(MESSAGE_LEN - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl Deref for Message {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        // Only whole characters are ever written
+        std::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self)
+    }
+}
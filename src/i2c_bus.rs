@@ -0,0 +1,47 @@
+//! The I2C bus shared by the display, the NAU7802 and the MPU6050. Each
+//! side takes the bus for one transaction at a time, so a display flush only
+//! delays a reading instead of corrupting it.
+
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
+use embedded_hal::i2c::{ErrorType, I2c, Operation};
+use esp_idf_hal::i2c::{I2cDriver, I2cError};
+
+#[derive(Clone)]
+pub struct SharedI2c(Arc<Mutex<I2cDriver<'static>>>);
+
+impl SharedI2c {
+    pub fn new(driver: I2cDriver<'static>) -> Self {
+        Self(Arc::new(Mutex::new(driver)))
+    }
+
+    /// Take the bus for a series of transfers
+    pub fn lock(&self) -> MutexGuard<'_, I2cDriver<'static>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Take the bus if nobody holds it
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, I2cDriver<'static>>> {
+        match self.0.try_lock() {
+            Ok(driver) => Some(driver),
+            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+impl ErrorType for SharedI2c {
+    type Error = I2cError;
+}
+
+impl I2c for SharedI2c {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        I2c::transaction(&mut *self.lock(), address, operations)
+    }
+}
@@ -0,0 +1,249 @@
+//! MPU6050 accelerometer on the I2C bus shared with the display, for a
+//! scale on a table that footsteps shake. The vertical acceleration is
+//! followed at 50 Hz, and while it strays from its slow moving baseline the
+//! readings of the load cell count as bumped and are dropped. The IMU is
+//! looked for at startup, without one nothing changes.
+//!
+//! The die temperature of the IMU is read once a second too, as the
+//! temperature near the load cell for a soak test.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "esp")]
+use esp_idf_hal::delay::{FreeRtos, TickType};
+#[cfg(feature = "esp")]
+use esp_idf_sys::EspError;
+#[cfg(feature = "esp")]
+use log::{debug, info};
+
+#[cfg(feature = "esp")]
+use crate::{
+    i2c_bus::SharedI2c,
+    quiesce::{disturb, Disturbance},
+    watchdog::WatchdogGuard,
+};
+
+/// Addresses of the MPU6050, depending on its AD0 pin
+pub const MPU6050_ADDRESSES: [u8; 2] = [0x68, 0x69];
+/// Longest time the readings of the load cell stay dropped after the last
+/// deviation, while the load settles again
+pub const BUMP_HOLD: Duration = Duration::from_millis(300);
+/// Share of a sample the baseline moves by, following a change of the tilt
+/// over a second or so
+const BASELINE_ALPHA: f32 = 0.02;
+
+#[cfg(feature = "esp")]
+const I2C_TIMEOUT_MS: u64 = 10;
+#[cfg(feature = "esp")]
+const IMU_TASK_STACK_SIZE: usize = 3 * 1024;
+#[cfg(feature = "esp")]
+const SAMPLE_PERIOD_MS: u32 = 20;
+/// Samples between two readings of the temperature, a second
+#[cfg(feature = "esp")]
+const TEMPERATURE_EVERY: u32 = 50;
+
+#[cfg(feature = "esp")]
+const REG_CONFIG: u8 = 0x1A;
+#[cfg(feature = "esp")]
+const REG_ACCEL_CONFIG: u8 = 0x1C;
+/// First of the two bytes of the Z acceleration, most significant first
+#[cfg(feature = "esp")]
+const REG_ACCEL_ZOUT_H: u8 = 0x3F;
+/// First of the two bytes of the temperature, most significant first
+#[cfg(feature = "esp")]
+const REG_TEMP_OUT_H: u8 = 0x41;
+#[cfg(feature = "esp")]
+const REG_PWR_MGMT_1: u8 = 0x6B;
+#[cfg(feature = "esp")]
+const REG_WHO_AM_I: u8 = 0x75;
+/// Identities of the MPU6050 and of the MPU6500 that stands in for it on
+/// some boards
+#[cfg(feature = "esp")]
+const WHO_AM_I_VALUES: [u8; 2] = [0x68, 0x70];
+/// Out of sleep, clocked from the X gyro
+#[cfg(feature = "esp")]
+const PWR_MGMT_1_CLKSEL_PLL_X: u8 = 0x01;
+/// 44 Hz bandwidth of the low pass filter, below the sample rate
+#[cfg(feature = "esp")]
+const CONFIG_DLPF_44HZ: u8 = 0x03;
+/// +-2g full scale
+#[cfg(feature = "esp")]
+const ACCEL_CONFIG_2G: u8 = 0x00;
+#[cfg(feature = "esp")]
+const LSB_PER_G: f32 = 16384.0;
+#[cfg(feature = "esp")]
+const LSB_PER_CELSIUS: f32 = 340.0;
+#[cfg(feature = "esp")]
+const TEMPERATURE_OFFSET_CELSIUS: f32 = 36.53;
+
+/// Bumps felt since boot
+static BUMPS: AtomicU32 = AtomicU32::new(0);
+/// Whether an IMU was found and is followed
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Bits of the last temperature in °C, NaN until one was read
+static TEMPERATURE: AtomicU32 = AtomicU32::new(0x7FC0_0000);
+
+/// Bumps felt since boot
+pub fn bumps() -> u32 {
+    BUMPS.load(Ordering::Relaxed)
+}
+
+/// Whether bumps are being rejected, only with an IMU found at startup
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Last temperature read from the IMU in °C, none without one
+pub fn temperature() -> Option<f32> {
+    let celsius = f32::from_bits(TEMPERATURE.load(Ordering::Relaxed));
+    (!celsius.is_nan()).then_some(celsius)
+}
+
+/// Tells the bumps from the vertical acceleration
+pub struct BumpDetector {
+    /// Deviation from the baseline in g that counts as a bump
+    threshold_g: f32,
+    baseline: Option<f32>,
+    /// When the acceleration last strayed past the threshold
+    last_bump: Option<Instant>,
+}
+
+impl BumpDetector {
+    pub fn new(threshold_g: f32) -> Self {
+        Self {
+            threshold_g,
+            baseline: None,
+            last_bump: None,
+        }
+    }
+
+    /// Take a vertical acceleration in g. Returns whether the scale counts
+    /// as bumped, until `BUMP_HOLD` after the last deviation.
+    pub fn on_sample(&mut self, accel_g: f32, at: Instant) -> bool {
+        let baseline = *self.baseline.get_or_insert(accel_g);
+        if (accel_g - baseline).abs() > self.threshold_g {
+            self.last_bump = Some(at);
+        } else {
+            // A bump does not drag the baseline along
+            self.baseline = Some(baseline + (accel_g - baseline) * BASELINE_ALPHA);
+        }
+        self.last_bump
+            .is_some_and(|last_bump| at.duration_since(last_bump) < BUMP_HOLD)
+    }
+}
+
+#[cfg(feature = "esp")]
+pub struct Mpu6050 {
+    bus: SharedI2c,
+    address: u8,
+}
+
+#[cfg(feature = "esp")]
+impl Mpu6050 {
+    /// Look for the IMU at both its addresses
+    pub fn probe(bus: &SharedI2c) -> Option<Self> {
+        let imu = MPU6050_ADDRESSES.into_iter().find_map(|address| {
+            let mut imu = Self {
+                bus: bus.clone(),
+                address,
+            };
+            let who_am_i = imu.read_register(REG_WHO_AM_I).ok()?;
+            WHO_AM_I_VALUES.contains(&who_am_i).then_some(imu)
+        });
+        match &imu {
+            Some(imu) => info!("IMU found at 0x{:02X}", imu.address),
+            None => debug!("No IMU found, bumps are not rejected"),
+        }
+        imu
+    }
+
+    /// Wake the IMU up and set up the accelerometer
+    pub fn start(&mut self) -> Result<(), EspError> {
+        self.write_register(REG_PWR_MGMT_1, PWR_MGMT_1_CLKSEL_PLL_X)?;
+        self.write_register(REG_CONFIG, CONFIG_DLPF_44HZ)?;
+        self.write_register(REG_ACCEL_CONFIG, ACCEL_CONFIG_2G)
+    }
+
+    /// Acceleration along the Z axis in g, vertical with the board mounted
+    /// flat
+    pub fn read_vertical_g(&mut self) -> Result<f32, EspError> {
+        let mut bytes = [0; 2];
+        self.read_registers(REG_ACCEL_ZOUT_H, &mut bytes)?;
+        Ok(f32::from(i16::from_be_bytes(bytes)) / LSB_PER_G)
+    }
+
+    /// Temperature of the die in °C
+    pub fn read_temperature(&mut self) -> Result<f32, EspError> {
+        let mut bytes = [0; 2];
+        self.read_registers(REG_TEMP_OUT_H, &mut bytes)?;
+        Ok(f32::from(i16::from_be_bytes(bytes)) / LSB_PER_CELSIUS + TEMPERATURE_OFFSET_CELSIUS)
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, EspError> {
+        let mut byte = [0];
+        self.read_registers(register, &mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_registers(&mut self, first: u8, bytes: &mut [u8]) -> Result<(), EspError> {
+        let timeout = TickType::new_millis(I2C_TIMEOUT_MS).ticks();
+        self.bus
+            .lock()
+            .write_read(self.address, &[first], bytes, timeout)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), EspError> {
+        let timeout = TickType::new_millis(I2C_TIMEOUT_MS).ticks();
+        self.bus
+            .lock()
+            .write(self.address, &[register, value], timeout)
+    }
+}
+
+/// Start the task following the acceleration, flagging the readings of the
+/// load cell as bumped while it strays past `threshold_g`
+#[cfg(feature = "esp")]
+pub fn start_imu_task(mut imu: Mpu6050, threshold_g: f32) -> Result<(), std::io::Error> {
+    std::thread::Builder::new()
+        .name("imu".to_string())
+        .stack_size(IMU_TASK_STACK_SIZE)
+        .spawn(move || {
+            let watchdog = WatchdogGuard::subscribe("imu");
+            let mut detector = BumpDetector::new(threshold_g);
+            let mut guard = None;
+            let mut samples = 0u32;
+            loop {
+                watchdog.feed();
+                if samples % TEMPERATURE_EVERY == 0 {
+                    match imu.read_temperature() {
+                        Ok(celsius) => TEMPERATURE.store(celsius.to_bits(), Ordering::Relaxed),
+                        Err(err) => debug!("Failed to read the temperature: {:?}", err),
+                    }
+                }
+                samples = samples.wrapping_add(1);
+                match imu.read_vertical_g() {
+                    Ok(accel_g) => {
+                        let bumped = detector.on_sample(accel_g, Instant::now());
+                        if bumped && guard.is_none() {
+                            BUMPS.fetch_add(1, Ordering::Relaxed);
+                            debug!("Bump felt at {:.3}g", accel_g);
+                            guard = Some(disturb(Disturbance::Bump));
+                        } else if !bumped {
+                            guard = None;
+                        }
+                    }
+                    // A missed sample leaves the readings alone
+                    Err(err) => {
+                        debug!("Failed to read the IMU: {:?}", err);
+                        guard = None;
+                    }
+                }
+                FreeRtos::delay_ms(SAMPLE_PERIOD_MS);
+            }
+        })?;
+    RUNNING.store(true, Ordering::Relaxed);
+    Ok(())
+}
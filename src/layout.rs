@@ -0,0 +1,151 @@
+use embedded_graphics::{
+    prelude::{Point, Size},
+    primitives::Rectangle,
+};
+
+/// Displays at least this tall get the multi-region layout
+const TALL_DISPLAY_MIN_HEIGHT: u32 = 64;
+
+/// Height of the status strip, fits a single line of the default font
+const STATUS_STRIP_HEIGHT: u32 = 14;
+
+const TALL_WEIGHT_HEIGHT: u32 = 24;
+const TALL_UNIT_WIDTH: u32 = 32;
+const TALL_FLOW_RATE_HEIGHT: u32 = 16;
+
+/// Named screen regions the UI draws into, so no other code needs to know
+/// about pixel coordinates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UiLayout {
+    /// Full screen area used for multi-line prompts
+    pub prompt: Rectangle,
+    /// Main weight readout
+    pub weight: Rectangle,
+    /// Unit label, rendered inline with the weight when `None`
+    pub unit: Option<Rectangle>,
+    /// Flow rate readout, not shown when `None`
+    pub flow_rate: Option<Rectangle>,
+    /// Status strip at the bottom of the screen
+    pub status: Rectangle,
+}
+
+impl UiLayout {
+    /// Pick the built-in profile matching the display size
+    pub fn for_display_size(size: Size) -> Self {
+        if size.height >= TALL_DISPLAY_MIN_HEIGHT {
+            Self::tall(size)
+        } else {
+            Self::short(size)
+        }
+    }
+
+    /// Single big line with a status strip below it
+    fn short(size: Size) -> Self {
+        let weight_height = size.height.saturating_sub(STATUS_STRIP_HEIGHT);
+        Self {
+            prompt: Rectangle::new(Point::zero(), size),
+            weight: Rectangle::new(Point::zero(), Size::new(size.width, weight_height)),
+            unit: None,
+            flow_rate: None,
+            status: Rectangle::new(
+                Point::new(0, weight_height as i32),
+                Size::new(size.width, STATUS_STRIP_HEIGHT),
+            ),
+        }
+    }
+
+    /// Big weight with a separate unit label, flow rate and a status bar
+    fn tall(size: Size) -> Self {
+        let weight_width = size.width.saturating_sub(TALL_UNIT_WIDTH);
+        Self {
+            prompt: Rectangle::new(Point::zero(), size),
+            weight: Rectangle::new(Point::zero(), Size::new(weight_width, TALL_WEIGHT_HEIGHT)),
+            unit: Some(Rectangle::new(
+                Point::new(weight_width as i32, 0),
+                Size::new(TALL_UNIT_WIDTH, TALL_WEIGHT_HEIGHT),
+            )),
+            flow_rate: Some(Rectangle::new(
+                Point::new(0, TALL_WEIGHT_HEIGHT as i32),
+                Size::new(size.width, TALL_FLOW_RATE_HEIGHT),
+            )),
+            status: Rectangle::new(
+                Point::new(0, size.height.saturating_sub(STATUS_STRIP_HEIGHT) as i32),
+                Size::new(size.width, STATUS_STRIP_HEIGHT),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(size: Size) -> Rectangle {
+        Rectangle::new(Point::zero(), size)
+    }
+
+    fn inside(outer: Rectangle, inner: Rectangle) -> bool {
+        outer.intersection(&inner) == inner
+    }
+
+    fn overlap(a: Rectangle, b: Rectangle) -> bool {
+        a.intersection(&b).size != Size::zero()
+    }
+
+    #[test]
+    fn short_display() {
+        let size = Size::new(128, 32);
+        let layout = UiLayout::for_display_size(size);
+        assert_eq!(layout.prompt, screen(size));
+        assert_eq!(
+            layout.weight,
+            Rectangle::new(Point::zero(), Size::new(128, 18))
+        );
+        assert_eq!(
+            layout.status,
+            Rectangle::new(Point::new(0, 18), Size::new(128, 14))
+        );
+        assert_eq!(layout.unit, None);
+        assert_eq!(layout.flow_rate, None);
+    }
+
+    #[test]
+    fn tall_display() {
+        let size = Size::new(128, 64);
+        let layout = UiLayout::for_display_size(size);
+        assert_eq!(
+            layout.weight,
+            Rectangle::new(Point::zero(), Size::new(96, 24))
+        );
+        assert_eq!(
+            layout.unit,
+            Some(Rectangle::new(Point::new(96, 0), Size::new(32, 24)))
+        );
+        assert_eq!(
+            layout.flow_rate,
+            Some(Rectangle::new(Point::new(0, 24), Size::new(128, 16)))
+        );
+        assert_eq!(
+            layout.status,
+            Rectangle::new(Point::new(0, 50), Size::new(128, 14))
+        );
+    }
+
+    #[test]
+    fn regions_fit_and_stay_apart() {
+        for size in [Size::new(128, 32), Size::new(128, 64), Size::new(72, 40)] {
+            let layout = UiLayout::for_display_size(size);
+            let regions: Vec<_> = [Some(layout.weight), layout.unit, layout.flow_rate]
+                .into_iter()
+                .flatten()
+                .chain([layout.status])
+                .collect();
+            for (i, &region) in regions.iter().enumerate() {
+                assert!(inside(screen(size), region), "{:?} in {:?}", region, size);
+                for &other in &regions[i + 1..] {
+                    assert!(!overlap(region, other), "{:?} {:?}", region, other);
+                }
+            }
+        }
+    }
+}
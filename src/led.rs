@@ -0,0 +1,285 @@
+use std::{
+    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use esp_idf_hal::{
+    gpio::{AnyOutputPin, Output, PinDriver},
+    rmt::{config::TransmitConfig, FixedLengthSignal, PinState, Pulse, TxRmtDriver, CHANNEL0},
+};
+use log::warn;
+
+use crate::{
+    feedback::{Feedback, FeedbackDispatcher},
+    power::IdleStage,
+    settings::{LedBackend, Settings},
+};
+
+const LED_TASK_STACK_SIZE: usize = 3 * 1024;
+/// Feedback waiting to be shown, later ones are dropped
+const FEEDBACK_QUEUE_LEN: usize = 8;
+/// Time an error is shown before the LED goes back to the weight
+const ERROR_DURATION: Duration = Duration::from_secs(5);
+const IDENTIFY_DURATION: Duration = Duration::from_secs(10);
+
+/// Color of a WS2812, also telling whether a plain LED is lit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+const fn rgb(r: u8, g: u8, b: u8) -> Rgb {
+    Rgb { r, g, b }
+}
+
+// Kept dim, a WS2812 at full brightness is glaring next to the display
+pub const OFF: Rgb = rgb(0, 0, 0);
+pub const GREEN: Rgb = rgb(0, 48, 0);
+pub const YELLOW: Rgb = rgb(40, 32, 0);
+pub const ORANGE: Rgb = rgb(48, 12, 0);
+pub const RED: Rgb = rgb(48, 0, 0);
+pub const BLUE: Rgb = rgb(0, 0, 48);
+pub const WHITE: Rgb = rgb(32, 32, 32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Blink {
+    Solid,
+    Slow,
+    Fast,
+}
+
+impl Blink {
+    /// Time the LED stays on, then off, `None` when it does not blink
+    fn half_period(self) -> Option<Duration> {
+        match self {
+            Blink::Solid => None,
+            Blink::Slow => Some(Duration::from_millis(500)),
+            Blink::Fast => Some(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// What the status LED shows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedState {
+    Stable,
+    Settling,
+    Overload,
+    Taring,
+    Calibrating,
+    Error,
+    Identify,
+    Alarm,
+    /// Dark along with the display
+    Off,
+}
+
+impl LedState {
+    /// Color on a WS2812 and blink pattern, the only part a plain LED shows
+    pub fn look(self) -> (Rgb, Blink) {
+        match self {
+            LedState::Stable => (GREEN, Blink::Solid),
+            LedState::Settling => (YELLOW, Blink::Solid),
+            LedState::Overload => (RED, Blink::Fast),
+            LedState::Taring => (WHITE, Blink::Slow),
+            LedState::Calibrating => (BLUE, Blink::Slow),
+            LedState::Error => (ORANGE, Blink::Fast),
+            LedState::Identify => (WHITE, Blink::Fast),
+            LedState::Alarm => (RED, Blink::Slow),
+            LedState::Off => (OFF, Blink::Solid),
+        }
+    }
+}
+
+/// Folds the feedback into the state to show. A latched alarm takes over
+/// from the weight until it is acknowledged, taring and calibrating take over
+/// from both until they end, errors and identification take over from
+/// everything for a while. The weight is not shown while the display is off.
+struct LedStatus {
+    weight: LedState,
+    /// Whether the display is off
+    dark: bool,
+    alarm: bool,
+    activity: Option<LedState>,
+    temporary: Option<(LedState, Instant)>,
+}
+
+impl LedStatus {
+    fn on_feedback(&mut self, feedback: Feedback, now: Instant) {
+        match feedback {
+            Feedback::Settling => self.weight = LedState::Settling,
+            Feedback::Settled => self.weight = LedState::Stable,
+            Feedback::Overload => self.weight = LedState::Overload,
+            // The calibration tares too, keep showing the calibration then
+            Feedback::Taring => {
+                self.activity.get_or_insert(LedState::Taring);
+            }
+            Feedback::Tared => {
+                if self.activity == Some(LedState::Taring) {
+                    self.activity = None;
+                }
+            }
+            Feedback::Calibrating => self.activity = Some(LedState::Calibrating),
+            Feedback::Calibrated => self.activity = None,
+            Feedback::CalibrationFailed => {
+                self.activity = None;
+                self.temporary = Some((LedState::Error, now + ERROR_DURATION));
+            }
+            Feedback::BatteryLow => {
+                self.temporary = Some((LedState::Error, now + ERROR_DURATION));
+            }
+            Feedback::Identify => {
+                self.temporary = Some((LedState::Identify, now + IDENTIFY_DURATION));
+            }
+            // Nothing comes after it but the restart
+            Feedback::Fault => {
+                self.activity = Some(LedState::Error);
+                self.temporary = None;
+            }
+            Feedback::AlarmTripped => self.alarm = true,
+            Feedback::AlarmCleared => self.alarm = false,
+            Feedback::TargetReached => {}
+            Feedback::Idle(stage) => self.dark = stage >= IdleStage::DisplayOff,
+        }
+    }
+
+    fn current(&mut self, now: Instant) -> LedState {
+        match self.temporary {
+            Some((state, until)) if now < until => return state,
+            Some(_) => self.temporary = None,
+            None => {}
+        }
+        match self.activity {
+            Some(state) => state,
+            None if self.alarm => LedState::Alarm,
+            None if self.dark => LedState::Off,
+            None => self.weight,
+        }
+    }
+}
+
+/// Single WS2812 on an RMT channel
+struct Ws2812 {
+    tx: TxRmtDriver<'static>,
+    /// Pulses encoding the bits
+    zero: (Pulse, Pulse),
+    one: (Pulse, Pulse),
+}
+
+impl Ws2812 {
+    fn new(channel: CHANNEL0, pin: AnyOutputPin) -> anyhow::Result<Self> {
+        let tx = TxRmtDriver::new(channel, pin, &TransmitConfig::new().clock_divider(1))?;
+        let ticks_hz = tx.counter_clock()?;
+        let pulse =
+            |state, nanos| Pulse::new_with_duration(ticks_hz, state, &Duration::from_nanos(nanos));
+        Ok(Self {
+            zero: (pulse(PinState::High, 350)?, pulse(PinState::Low, 800)?),
+            one: (pulse(PinState::High, 700)?, pulse(PinState::Low, 600)?),
+            tx,
+        })
+    }
+
+    fn show(&mut self, color: Rgb) -> anyhow::Result<()> {
+        // Sent green first, most significant bit first
+        let bits = u32::from(color.g) << 16 | u32::from(color.r) << 8 | u32::from(color.b);
+        let mut signal = FixedLengthSignal::<24>::new();
+        for index in 0..24 {
+            let bit = bits & (1 << (23 - index)) != 0;
+            signal.set(index, if bit { &self.one } else { &self.zero })?;
+        }
+        self.tx.start_blocking(&signal)?;
+        Ok(())
+    }
+}
+
+enum LedDriver {
+    Gpio(PinDriver<'static, AnyOutputPin, Output>),
+    Ws2812(Ws2812),
+}
+
+impl LedDriver {
+    fn show(&mut self, color: Rgb) -> anyhow::Result<()> {
+        match self {
+            LedDriver::Gpio(pin) if color == OFF => pin.set_low()?,
+            LedDriver::Gpio(pin) => pin.set_high()?,
+            LedDriver::Ws2812(ws2812) => ws2812.show(color)?,
+        }
+        Ok(())
+    }
+}
+
+/// Start the status LED configured in the settings and have it follow the
+/// feedback. Does nothing when the LED is turned off.
+pub fn start_led_task(
+    channel: CHANNEL0,
+    settings: &Settings,
+    feedback: &FeedbackDispatcher,
+) -> anyhow::Result<()> {
+    // The pin comes from the settings, so it can only be picked at runtime
+    let pin = || unsafe { AnyOutputPin::new(settings.led_pin().into()) };
+    let driver = match settings.led_backend() {
+        LedBackend::Off => return Ok(()),
+        LedBackend::Gpio => LedDriver::Gpio(PinDriver::output(pin())?),
+        LedBackend::Ws2812 => LedDriver::Ws2812(Ws2812::new(channel, pin())?),
+    };
+
+    let (feedback_tx, feedback_rx) = sync_channel(FEEDBACK_QUEUE_LEN);
+    std::thread::Builder::new()
+        .name("led".to_string())
+        .stack_size(LED_TASK_STACK_SIZE)
+        .spawn(move || led_task(driver, feedback_rx))?;
+
+    feedback.subscribe(move |feedback| {
+        let _ = feedback_tx.try_send(feedback);
+    });
+    Ok(())
+}
+
+fn led_task(mut driver: LedDriver, feedback: Receiver<Feedback>) {
+    let mut status = LedStatus {
+        weight: LedState::Settling,
+        dark: false,
+        alarm: false,
+        activity: None,
+        temporary: None,
+    };
+    let mut lit = true;
+    let mut next_toggle = Instant::now();
+    let mut shown = None;
+
+    loop {
+        let now = Instant::now();
+        let (color, blink) = status.current(now).look();
+        let half_period = blink.half_period();
+        match half_period {
+            Some(half_period) if now >= next_toggle => {
+                lit = !lit;
+                next_toggle = now + half_period;
+            }
+            Some(_) => {}
+            None => lit = true,
+        }
+
+        let color = if lit { color } else { OFF };
+        if shown != Some(color) {
+            if let Err(err) = driver.show(color) {
+                warn!("Failed to drive the status LED: {:?}", err);
+            }
+            shown = Some(color);
+        }
+
+        // Solid states only change with the feedback, blinking ones wake up
+        // for the next toggle
+        let received = match half_period {
+            Some(_) => feedback.recv_timeout(next_toggle.saturating_duration_since(now)),
+            None => feedback.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(feedback) => status.on_feedback(feedback, Instant::now()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Building blocks of the weighing scale firmware.
+//!
+//! The platform independent parts (button gestures, layout, menu, text
+//! drawing) build anywhere, while the parts talking to esp-idf are only
+//! available with the `esp` feature. Everything drawing on the panel needs
+//! the `display` feature, without it the scale, button, storage and network
+//! parts still build for a headless scale.
+
+pub mod alarms;
+#[cfg(feature = "display")]
+pub mod app;
+#[cfg(feature = "battery")]
+pub mod battery;
+pub mod ble;
+pub mod brew;
+pub mod button;
+#[cfg(feature = "buzzer")]
+pub mod buzzer;
+pub mod cal_transfer;
+pub mod calibration;
+pub mod certified;
+pub mod command_channel;
+pub mod console;
+pub mod counters;
+pub mod creep;
+#[cfg(feature = "esp")]
+pub mod datalog;
+pub mod deadband;
+pub mod demo;
+pub mod device;
+pub mod diagnostics;
+#[cfg(feature = "dispense")]
+pub mod dispense;
+pub mod display;
+pub mod envelope;
+pub mod error;
+pub mod espnow;
+pub mod events;
+pub mod feedback;
+pub mod filter;
+pub mod format;
+#[cfg(feature = "display")]
+pub mod frame;
+pub mod frame_rate;
+pub mod governor;
+pub mod history;
+pub mod hold;
+#[cfg(feature = "http")]
+pub mod http_api;
+#[cfg(feature = "webhook")]
+pub mod http_client;
+pub mod i18n;
+#[cfg(feature = "esp")]
+pub mod i2c_bus;
+pub mod imu;
+#[cfg(feature = "display")]
+pub mod layout;
+#[cfg(feature = "led")]
+pub mod led;
+pub mod line_editor;
+pub mod linearity;
+pub mod lock;
+pub mod logger;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+#[cfg(feature = "display")]
+pub mod menu;
+pub mod modbus;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "esp")]
+pub mod nau7802;
+pub mod negative;
+pub mod noise;
+#[cfg(feature = "esp")]
+pub mod ota;
+#[cfg(feature = "display")]
+pub mod pages;
+pub mod panic_screen;
+pub mod pipeline;
+pub mod power;
+pub mod procedure;
+pub mod quality;
+pub mod quiesce;
+pub mod recipe;
+#[cfg(feature = "esp")]
+pub mod reset;
+pub mod scale;
+#[cfg(feature = "display")]
+pub mod selftest;
+pub mod sensor;
+pub mod session;
+pub mod settings;
+#[cfg(feature = "display")]
+pub mod setup;
+pub mod shutdown;
+pub mod sink;
+pub mod snapshot;
+pub mod soak;
+#[cfg(feature = "display")]
+pub mod status;
+pub mod storage;
+pub mod stream;
+pub mod tare;
+#[cfg(feature = "display")]
+pub mod text_drawer;
+pub mod time;
+pub mod time_average;
+pub mod trace;
+pub mod unit;
+pub mod volume;
+pub mod watchdog;
+pub mod webhook;
+pub mod weight_gesture;
+#[cfg(feature = "wifi")]
+pub mod wifi;
+pub mod write_cache;
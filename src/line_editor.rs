@@ -0,0 +1,471 @@
+//! Line editing for the serial console: backspace and delete, the cursor
+//! moved with the arrows, home and end, the last commands recalled with up
+//! and down, and the command name completed with tab. The input comes a
+//! byte at a time, the keys past the printable ones as ANSI escape
+//! sequences, and everything typed is echoed back, the terminals of both
+//! the USB-serial-JTAG and the UART console leaving that to the device.
+//!
+//! The line and the history are fixed buffers, a longer line is cut.
+
+use std::io::{self, Write};
+
+/// Longest line taken
+pub const LINE_LEN: usize = 128;
+/// Lines kept in the history
+pub const HISTORY_LEN: usize = 8;
+
+const ESC: u8 = 0x1b;
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const DEL: u8 = 0x7f;
+/// Erase from the cursor to the end of the line
+const ERASE_LINE_END: &[u8] = b"\x1b[K";
+
+/// A key, decoded from one byte or a whole escape sequence
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Tab,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    /// Ctrl-C, drops the line
+    Cancel,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum EscapeState {
+    #[default]
+    Ground,
+    /// After the escape
+    Escape,
+    /// In a control sequence, `ESC [` or `ESC O`, with its number so far
+    Sequence(u8),
+}
+
+/// Decodes the keys out of the bytes typed
+#[derive(Debug, Default)]
+pub struct KeyDecoder {
+    state: EscapeState,
+    /// Whether the last byte was a carriage return, so the line feed of a
+    /// CRLF does not end a second, empty line
+    after_cr: bool,
+}
+
+impl KeyDecoder {
+    /// The key the byte completes, if any. Unknown sequences are dropped
+    /// whole, a lone escape on its own.
+    pub fn feed(&mut self, byte: u8) -> Option<Key> {
+        let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+        match self.state {
+            EscapeState::Ground => self.ground(byte, after_cr),
+            EscapeState::Escape => match byte {
+                b'[' | b'O' => {
+                    self.state = EscapeState::Sequence(0);
+                    None
+                }
+                // The escape key itself, the byte after it is a key of its own
+                _ => {
+                    self.state = EscapeState::Ground;
+                    self.ground(byte, after_cr)
+                }
+            },
+            EscapeState::Sequence(param) => {
+                if byte.is_ascii_digit() {
+                    let param = param.saturating_mul(10).saturating_add(byte - b'0');
+                    self.state = EscapeState::Sequence(param);
+                    return None;
+                }
+                // Parameters past the first, e.g. modifiers, do not change
+                // the key
+                if byte == b';' {
+                    return None;
+                }
+                self.state = EscapeState::Ground;
+                match (byte, param) {
+                    (b'A', _) => Some(Key::Up),
+                    (b'B', _) => Some(Key::Down),
+                    (b'C', _) => Some(Key::Right),
+                    (b'D', _) => Some(Key::Left),
+                    (b'H', _) | (b'~', 1 | 7) => Some(Key::Home),
+                    (b'F', _) | (b'~', 4 | 8) => Some(Key::End),
+                    (b'~', 3) => Some(Key::Delete),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    fn ground(&mut self, byte: u8, after_cr: bool) -> Option<Key> {
+        match byte {
+            ESC => {
+                self.state = EscapeState::Escape;
+                None
+            }
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => Some(Key::Enter),
+            BACKSPACE | DEL => Some(Key::Backspace),
+            b'\t' => Some(Key::Tab),
+            CTRL_C => Some(Key::Cancel),
+            0x20..=0x7e => Some(Key::Char(byte)),
+            _ => None,
+        }
+    }
+}
+
+/// A line of at most `LINE_LEN` bytes
+#[derive(Clone, Copy)]
+struct Line {
+    bytes: [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        bytes: [0; LINE_LEN],
+        len: 0,
+    };
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Edits the console line, echoing to the output it is fed with
+pub struct LineEditor {
+    keys: KeyDecoder,
+    line: Line,
+    cursor: usize,
+    /// Whether the line was entered, to be cleared with the next byte
+    entered: bool,
+    /// The lines entered, wrapping around
+    history: [Line; HISTORY_LEN],
+    history_len: usize,
+    /// Slot the next line goes into
+    history_next: usize,
+    /// How many lines back up went, 0 for the line being typed
+    browsing: usize,
+    completions: &'static [&'static str],
+}
+
+impl LineEditor {
+    /// Tab completes the first word to one of `completions`
+    pub fn new(completions: &'static [&'static str]) -> Self {
+        Self {
+            keys: KeyDecoder::default(),
+            line: Line::EMPTY,
+            cursor: 0,
+            entered: false,
+            history: [Line::EMPTY; HISTORY_LEN],
+            history_len: 0,
+            history_next: 0,
+            browsing: 0,
+            completions,
+        }
+    }
+
+    /// Take a byte typed. Returns the line once entered, valid until the
+    /// next byte.
+    pub fn feed(&mut self, byte: u8, out: &mut impl Write) -> io::Result<Option<&str>> {
+        if std::mem::take(&mut self.entered) {
+            self.line = Line::EMPTY;
+        }
+        let Some(key) = self.keys.feed(byte) else {
+            return Ok(None);
+        };
+        match key {
+            Key::Char(byte) => self.insert(&[byte], out)?,
+            Key::Enter => {
+                out.write_all(b"\r\n")?;
+                self.remember();
+                self.cursor = 0;
+                self.browsing = 0;
+                self.entered = true;
+                // Only ASCII is taken
+                return Ok(std::str::from_utf8(self.line.as_bytes()).ok());
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                out.write_all(&[BACKSPACE])?;
+                self.remove_at_cursor(out)?;
+            }
+            Key::Delete if self.cursor < self.line.len => self.remove_at_cursor(out)?,
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                out.write_all(&[BACKSPACE])?;
+            }
+            Key::Right if self.cursor < self.line.len => {
+                out.write_all(&[self.line.bytes[self.cursor]])?;
+                self.cursor += 1;
+            }
+            Key::Home => {
+                self.cursor = 0;
+                out.write_all(b"\r")?;
+            }
+            Key::End => {
+                out.write_all(&self.line.bytes[self.cursor..self.line.len])?;
+                self.cursor = self.line.len;
+            }
+            Key::Up if self.browsing < self.history_len => {
+                self.browsing += 1;
+                self.recall(out)?;
+            }
+            Key::Down if self.browsing > 0 => {
+                self.browsing -= 1;
+                self.recall(out)?;
+            }
+            Key::Tab => self.complete(out)?,
+            Key::Cancel => {
+                out.write_all(b"^C\r\n")?;
+                self.line = Line::EMPTY;
+                self.cursor = 0;
+                self.browsing = 0;
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Insert at the cursor, as much as fits
+    fn insert(&mut self, bytes: &[u8], out: &mut impl Write) -> io::Result<()> {
+        let count = bytes.len().min(LINE_LEN - self.line.len);
+        if count == 0 {
+            return Ok(());
+        }
+        let Line { bytes: line, len } = &mut self.line;
+        line.copy_within(self.cursor..*len, self.cursor + count);
+        line[self.cursor..self.cursor + count].copy_from_slice(&bytes[..count]);
+        *len += count;
+        self.cursor += count;
+        out.write_all(&line[self.cursor - count..*len])?;
+        move_back(out, *len - self.cursor)
+    }
+
+    /// Remove the byte under the cursor and draw the rest of the line again
+    fn remove_at_cursor(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let Line { bytes: line, len } = &mut self.line;
+        line.copy_within(self.cursor + 1..*len, self.cursor);
+        *len -= 1;
+        out.write_all(&line[self.cursor..*len])?;
+        out.write_all(ERASE_LINE_END)?;
+        move_back(out, *len - self.cursor)
+    }
+
+    /// Replace the line with the one `browsing` lines back, or an empty one
+    fn recall(&mut self, out: &mut impl Write) -> io::Result<()> {
+        self.line = match self.browsing {
+            0 => Line::EMPTY,
+            back => self.history[(self.history_next + HISTORY_LEN - back) % HISTORY_LEN],
+        };
+        self.cursor = self.line.len;
+        out.write_all(b"\r")?;
+        out.write_all(ERASE_LINE_END)?;
+        out.write_all(self.line.as_bytes())
+    }
+
+    /// Keep the line in the history, unless blank or the same as the last
+    fn remember(&mut self) {
+        if self.line.as_bytes().iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        let last = (self.history_next + HISTORY_LEN - 1) % HISTORY_LEN;
+        if self.history_len > 0 && self.history[last].as_bytes() == self.line.as_bytes() {
+            return;
+        }
+        self.history[self.history_next] = self.line;
+        self.history_next = (self.history_next + 1) % HISTORY_LEN;
+        self.history_len = (self.history_len + 1).min(HISTORY_LEN);
+    }
+
+    /// Complete the command name at the end of the line as far as the names
+    /// agree, listing them when they part right there
+    fn complete(&mut self, out: &mut impl Write) -> io::Result<()> {
+        if self.cursor < self.line.len {
+            return Ok(());
+        }
+        let line = self.line;
+        let prefix = line.as_bytes();
+        if prefix.contains(&b' ') {
+            return Ok(());
+        }
+        let completions = self.completions;
+        let mut matches = completions
+            .iter()
+            .filter(|name| starts_with_ignore_case(name.as_bytes(), prefix));
+        let Some(first) = matches.next() else {
+            return out.write_all(b"\x07");
+        };
+        // The longest start all the matches share
+        let mut shared = first.len();
+        let mut unique = true;
+        for name in matches.clone() {
+            unique = false;
+            shared = first
+                .bytes()
+                .zip(name.bytes())
+                .take(shared)
+                .take_while(|(a, b)| a == b)
+                .count();
+        }
+        if shared > prefix.len() {
+            let rest = &first.as_bytes()[prefix.len()..shared];
+            self.insert(rest, out)?;
+        }
+        if unique {
+            return self.insert(b" ", out);
+        }
+        if shared == prefix.len() {
+            out.write_all(b"\r\n")?;
+            for name in std::iter::once(first).chain(matches) {
+                write!(out, "{}  ", name)?;
+            }
+            out.write_all(b"\r\n")?;
+            out.write_all(self.line.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Move the cursor of the terminal left by `count`
+fn move_back(out: &mut impl Write, count: usize) -> io::Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+    write!(out, "\x1b[{}D", count)
+}
+
+fn starts_with_ignore_case(name: &[u8], prefix: &[u8]) -> bool {
+    name.len() >= prefix.len() && name[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMANDS: &[&str] = &["tare", "zero", "stats", "stream"];
+
+    fn keys(decoder: &mut KeyDecoder, bytes: &[u8]) -> Vec<Key> {
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.feed(byte))
+            .collect()
+    }
+
+    /// Feed the bytes, returning the last line entered
+    fn type_in(editor: &mut LineEditor, bytes: &[u8]) -> Option<String> {
+        let mut entered = None;
+        for &byte in bytes {
+            if let Some(line) = editor.feed(byte, &mut Vec::new()).unwrap() {
+                entered = Some(line.to_string());
+            }
+        }
+        entered
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let mut decoder = KeyDecoder::default();
+        assert_eq!(
+            keys(&mut decoder, b"\x1b[A\x1b[B\x1b[C\x1b[D\x1b[1;5C"),
+            [Key::Up, Key::Down, Key::Right, Key::Left, Key::Right]
+        );
+        assert_eq!(
+            keys(
+                &mut decoder,
+                b"\x1b[H\x1bOH\x1b[1~\x1b[7~\x1b[F\x1bOF\x1b[4~\x1b[8~"
+            ),
+            [
+                Key::Home,
+                Key::Home,
+                Key::Home,
+                Key::Home,
+                Key::End,
+                Key::End,
+                Key::End,
+                Key::End
+            ]
+        );
+        assert_eq!(keys(&mut decoder, b"\x1b[3~"), [Key::Delete]);
+        // Unknown ones are dropped whole
+        assert_eq!(keys(&mut decoder, b"\x1b[15~a"), [Key::Char(b'a')]);
+    }
+
+    #[test]
+    fn lone_escape() {
+        let mut decoder = KeyDecoder::default();
+        assert_eq!(keys(&mut decoder, b"\x1bt"), [Key::Char(b't')]);
+        assert_eq!(keys(&mut decoder, b"\x1b\r"), [Key::Enter]);
+        assert_eq!(keys(&mut decoder, b"\x1b\x1b[A"), [Key::Up]);
+    }
+
+    #[test]
+    fn split_across_reads() {
+        let mut decoder = KeyDecoder::default();
+        assert_eq!(keys(&mut decoder, b"\x1b"), []);
+        assert_eq!(keys(&mut decoder, b"[3"), []);
+        assert_eq!(
+            keys(&mut decoder, b"~x\r"),
+            [Key::Delete, Key::Char(b'x'), Key::Enter]
+        );
+        // The line feed of a CRLF coming in a read of its own
+        assert_eq!(keys(&mut decoder, b"\n"), []);
+        assert_eq!(keys(&mut decoder, b"\n"), [Key::Enter]);
+    }
+
+    #[test]
+    fn edits_at_the_cursor() {
+        let mut editor = LineEditor::new(COMMANDS);
+        // Delete under the cursor, then insert at the start and the end
+        let line = type_in(&mut editor, b"tarre\x1b[D\x1b[D\x1b[3~\x1b[Hx\x1b[Fy\r");
+        assert_eq!(line.as_deref(), Some("xtarey"));
+        let line = type_in(&mut editor, b"zeroo\x08\x1b[D\x1b[D\x7f\r");
+        assert_eq!(line.as_deref(), Some("zro"));
+        let line = type_in(&mut editor, b"tare\x03stats\r");
+        assert_eq!(line.as_deref(), Some("stats"));
+    }
+
+    #[test]
+    fn history() {
+        let mut editor = LineEditor::new(COMMANDS);
+        for line in ["tare", "zero", "zero", " "] {
+            type_in(&mut editor, format!("{}\r", line).as_bytes());
+        }
+        // The repeat and the blank line were not kept
+        assert_eq!(type_in(&mut editor, b"\x1b[A\r").as_deref(), Some("zero"));
+        assert_eq!(
+            type_in(&mut editor, b"\x1b[A\x1b[A\x1b[A\r").as_deref(),
+            Some("tare")
+        );
+        // tare, zero and tare are kept by now
+        assert_eq!(
+            type_in(&mut editor, b"\x1b[A\x1b[A\x1b[B\r").as_deref(),
+            Some("tare")
+        );
+        assert_eq!(
+            type_in(&mut editor, b"\x1b[A\x1b[B\x1b[Bstats\r").as_deref(),
+            Some("stats")
+        );
+        // A recalled line can be edited before it is entered
+        assert_eq!(
+            type_in(&mut editor, b"\x1b[A\x1b[Hx\r").as_deref(),
+            Some("xstats")
+        );
+    }
+
+    #[test]
+    fn history_wraps_around() {
+        let mut editor = LineEditor::new(COMMANDS);
+        for line in 0..HISTORY_LEN + 2 {
+            type_in(&mut editor, format!("{}\r", line).as_bytes());
+        }
+        let up = b"\x1b[A".repeat(HISTORY_LEN + 5);
+        let oldest = type_in(&mut editor, &[up.as_slice(), b"\r"].concat());
+        assert_eq!(oldest, Some(2.to_string()));
+    }
+}
@@ -0,0 +1,180 @@
+//! How far the load cell strays from a straight line. The check weighs a
+//! few known weights after a tare, fits a line through the averaged counts
+//! of the empty scale and of each weight, and reports the largest distance
+//! of a weight from the line, in grams and as a share of the full scale.
+
+use crate::calibration::Moment;
+
+/// Known weights a check takes at most
+pub const MAX_LINEARITY_WEIGHTS: usize = 4;
+/// Known weights a check takes at least, a line fits any two points
+pub const MIN_LINEARITY_WEIGHTS: usize = 2;
+/// Points of a check, the empty scale first
+const MAX_POINTS: usize = MAX_LINEARITY_WEIGHTS + 1;
+
+/// Header of the table `LinearityReport::table` prints
+pub const LINEARITY_TABLE_HEADER: &str = "grams,counts,fitted_grams,deviation_grams";
+
+const ENCODED_LEN: usize = 1 + MAX_POINTS * 8 + 4 + 12;
+
+/// A known weight along with the averaged counts it read, net of the tare
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinearityPoint {
+    pub grams: f32,
+    pub counts: f32,
+}
+
+/// Outcome of a linearity check
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinearityReport {
+    points: [LinearityPoint; MAX_POINTS],
+    len: usize,
+    /// Weight the deviation is a share of, the capacity when set and the
+    /// heaviest weight otherwise
+    pub full_scale_grams: f32,
+    /// The fitted line, in grams per count and grams
+    slope: f32,
+    intercept: f32,
+    pub checked: Moment,
+}
+
+impl LinearityReport {
+    /// Fit the line through the points. `None` when fewer than two points
+    /// read apart.
+    pub fn fit(points: &[LinearityPoint], full_scale_grams: f32, checked: Moment) -> Option<Self> {
+        let points = &points[..points.len().min(MAX_POINTS)];
+        let n = points.len() as f64;
+        let mean_counts = points
+            .iter()
+            .map(|point| f64::from(point.counts))
+            .sum::<f64>()
+            / n;
+        let mean_grams = points
+            .iter()
+            .map(|point| f64::from(point.grams))
+            .sum::<f64>()
+            / n;
+        let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), point| {
+            let counts = f64::from(point.counts) - mean_counts;
+            let grams = f64::from(point.grams) - mean_grams;
+            (cov + counts * grams, var + counts * counts)
+        });
+        if points.len() < 2 || variance == 0.0 {
+            return None;
+        }
+        let slope = covariance / variance;
+        let mut report = Self {
+            points: [LinearityPoint::default(); MAX_POINTS],
+            len: points.len(),
+            full_scale_grams,
+            slope: slope as f32,
+            intercept: (mean_grams - slope * mean_counts) as f32,
+            checked,
+        };
+        report.points[..points.len()].copy_from_slice(points);
+        Some(report)
+    }
+
+    pub fn points(&self) -> &[LinearityPoint] {
+        &self.points[..self.len]
+    }
+
+    /// Weight the line gives for the counts of the point
+    pub fn fitted_grams(&self, point: &LinearityPoint) -> f32 {
+        self.slope * point.counts + self.intercept
+    }
+
+    /// Distance of the point from the line, positive when the line reads
+    /// heavier than the weight
+    pub fn deviation_grams(&self, point: &LinearityPoint) -> f32 {
+        self.fitted_grams(point) - point.grams
+    }
+
+    /// Largest distance of a point from the line
+    pub fn max_deviation_grams(&self) -> f32 {
+        self.points()
+            .iter()
+            .map(|point| self.deviation_grams(point).abs())
+            .fold(0.0, f32::max)
+    }
+
+    /// Largest distance as a percentage of the full scale
+    pub fn max_deviation_percent(&self) -> f32 {
+        if self.full_scale_grams > 0.0 {
+            self.max_deviation_grams() / self.full_scale_grams * 100.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Short enough for the status strip
+    pub fn describe(&self) -> String {
+        format!(
+            "Lin {:.1}g {:.2}%",
+            self.max_deviation_grams(),
+            self.max_deviation_percent()
+        )
+    }
+
+    /// Lines of the table matching `LINEARITY_TABLE_HEADER`, one per point
+    pub fn table(&self) -> Vec<String> {
+        self.points()
+            .iter()
+            .map(|point| {
+                format!(
+                    "{:.1},{:.0},{:.2},{:.2}",
+                    point.grams,
+                    point.counts,
+                    self.fitted_grams(point),
+                    self.deviation_grams(point)
+                )
+            })
+            .collect()
+    }
+}
+
+impl crate::storage::Stored for LinearityReport {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        bytes.push(self.len as u8);
+        for point in &self.points {
+            bytes.extend_from_slice(&point.grams.to_le_bytes());
+            bytes.extend_from_slice(&point.counts.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.full_scale_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.checked.epoch_s.to_le_bytes());
+        bytes.extend_from_slice(&self.checked.boot.to_le_bytes());
+        bytes
+    }
+
+    /// The line is fitted again from the points
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; ENCODED_LEN] = bytes.try_into().ok()?;
+        let f32_at = |at: usize| bytes[at..at + 4].try_into().ok().map(f32::from_le_bytes);
+        let len = usize::from(bytes[0]);
+        if len > MAX_POINTS {
+            return None;
+        }
+        let mut points = Vec::with_capacity(len);
+        for index in 0..len {
+            let at = 1 + index * 8;
+            points.push(LinearityPoint {
+                grams: f32_at(at)?,
+                counts: f32_at(at + 4)?,
+            });
+        }
+        let at = 1 + MAX_POINTS * 8;
+        let full_scale_grams = f32_at(at)?;
+        let checked = Moment {
+            epoch_s: u64::from_le_bytes(bytes[at + 4..at + 12].try_into().ok()?),
+            boot: u32::from_le_bytes(bytes[at + 12..at + 16].try_into().ok()?),
+        };
+        if !points
+            .iter()
+            .all(|point| point.grams.is_finite() && point.counts.is_finite())
+        {
+            return None;
+        }
+        Self::fit(&points, full_scale_grams, checked)
+    }
+}
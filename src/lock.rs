@@ -0,0 +1,293 @@
+//! Lock keeping a shared scale calibrated. While locked, the calibration,
+//! the resets and the settings are out of reach until unlocked with the PIN,
+//! or with a click pattern on the button, the tare and the weighing stay
+//! free. Unlocking lasts until the scale goes unused for the relock time,
+//! and failed attempts make the next ones wait longer and longer.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+/// Highest PIN, of 4 digits
+pub const MAX_PIN: u16 = 9999;
+pub const PIN_DIGITS: u8 = 4;
+/// Clicks a pattern has at most, one bit each
+pub const MAX_PATTERN_LEN: usize = 8;
+/// Clicks a pattern has at least, a single one would be too easy to hit
+pub const MIN_PATTERN_LEN: usize = 2;
+pub const DEFAULT_RELOCK_S: u32 = 300;
+/// Failed attempts before the next ones have to wait
+const FREE_ATTEMPTS: u32 = 3;
+/// Wait after the free attempts, doubled with every further failure
+const FIRST_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// A click of an unlock pattern
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Click {
+    Short,
+    Long,
+}
+
+/// Clicks unlocking the scale from the button, e.g. short-short-long
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClickPattern {
+    len: u8,
+    /// A set bit is a long click, the first click in the lowest bit
+    longs: u8,
+}
+
+impl ClickPattern {
+    pub fn new(clicks: &[Click]) -> Option<Self> {
+        if !(MIN_PATTERN_LEN..=MAX_PATTERN_LEN).contains(&clicks.len()) {
+            return None;
+        }
+        let longs = clicks
+            .iter()
+            .enumerate()
+            .filter(|(_, &click)| click == Click::Long)
+            .fold(0u8, |longs, (index, _)| longs | 1 << index);
+        Some(Self {
+            len: clicks.len() as u8,
+            longs,
+        })
+    }
+
+    /// Parse `short-short-long`, or `ssl` for short
+    pub fn from_name(name: &str) -> Option<Self> {
+        let clicks: Option<Vec<Click>> = if name.contains('-') {
+            name.split('-')
+                .map(|click| match click.to_ascii_lowercase().as_str() {
+                    "short" | "s" => Some(Click::Short),
+                    "long" | "l" => Some(Click::Long),
+                    _ => None,
+                })
+                .collect()
+        } else {
+            name.chars()
+                .map(|click| match click.to_ascii_lowercase() {
+                    's' => Some(Click::Short),
+                    'l' => Some(Click::Long),
+                    _ => None,
+                })
+                .collect()
+        };
+        Self::new(&clicks?)
+    }
+
+    pub fn clicks(&self) -> impl Iterator<Item = Click> + '_ {
+        (0..self.len).map(|index| {
+            if self.longs & 1 << index != 0 {
+                Click::Long
+            } else {
+                Click::Short
+            }
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.clicks()
+            .map(|click| match click {
+                Click::Short => "short",
+                Click::Long => "long",
+            })
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Length and long clicks, as kept in the settings
+    pub fn to_bytes(self) -> [u8; 2] {
+        [self.len, self.longs]
+    }
+
+    pub fn from_bytes([len, longs]: [u8; 2]) -> Option<Self> {
+        let len_ok = (MIN_PATTERN_LEN..=MAX_PATTERN_LEN).contains(&usize::from(len));
+        let mask = if usize::from(len) >= 8 {
+            u8::MAX
+        } else {
+            (1 << len) - 1
+        };
+        (len_ok && longs & !mask == 0).then_some(Self { len, longs })
+    }
+}
+
+/// How the lock is set up, kept in the settings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockConfig {
+    pub enabled: bool,
+    /// PIN the console and HTTP give, and that the button can enter
+    pub pin: Option<u16>,
+    /// Unlocks from the button in place of entering the PIN
+    pub pattern: Option<ClickPattern>,
+    /// Time unused after which an unlocked scale locks again
+    pub relock: Duration,
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pin: None,
+            pattern: None,
+            relock: Duration::from_secs(DEFAULT_RELOCK_S.into()),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockError {
+    #[error("Locked, the PIN is needed")]
+    Locked,
+    #[error("Wrong PIN")]
+    WrongPin,
+    #[error("Wrong pattern")]
+    WrongPattern,
+    #[error("Retry in {0}s")]
+    RateLimited(u64),
+}
+
+/// Whether the scale is locked, and the failed attempts at unlocking it
+#[derive(Debug, Default)]
+pub struct Lock {
+    config: LockConfig,
+    /// Last use of the unlocked scale, the relock time counts from it
+    unlocked: Option<Instant>,
+    failures: u32,
+    blocked_until: Option<Instant>,
+}
+
+impl Lock {
+    /// Locked from the start when enabled
+    pub fn new(config: LockConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn config(&self) -> &LockConfig {
+        &self.config
+    }
+
+    /// Apply a new setup. Enabling the lock leaves the scale unlocked for
+    /// the relock time, as it was unlocked to change it.
+    pub fn configure(&mut self, config: LockConfig, now: Instant) {
+        if config.enabled && !self.config.enabled {
+            self.unlocked = Some(now);
+        }
+        self.config = config;
+    }
+
+    /// Whether the calibration and the settings are out of reach. Without
+    /// a PIN the lock cannot be opened, so it stays off.
+    pub fn is_locked(&self, now: Instant) -> bool {
+        self.config.enabled
+            && self.config.pin.is_some()
+            && !matches!(self.unlocked, Some(at) if now.duration_since(at) < self.config.relock)
+    }
+
+    /// Lock again right away
+    pub fn relock(&mut self) {
+        self.unlocked = None;
+    }
+
+    /// Postpone the relock of the unlocked scale, used from now
+    pub fn touch(&mut self, now: Instant) {
+        if self.unlocked.is_some() && !self.is_locked(now) {
+            self.unlocked = Some(now);
+        }
+    }
+
+    /// Let a guarded action through, with the PIN given along with it if
+    /// any. An action of the unlocked scale postpones the relock, the PIN
+    /// lets a single one through without unlocking.
+    pub fn authorize(&mut self, pin: Option<u16>, now: Instant) -> Result<(), LockError> {
+        if !self.is_locked(now) {
+            self.touch(now);
+            return Ok(());
+        }
+        let pin = pin.ok_or(LockError::Locked)?;
+        self.attempt(Some(pin) == self.config.pin, LockError::WrongPin, now)
+    }
+
+    /// Unlock with the PIN until the relock time
+    pub fn unlock_with_pin(&mut self, pin: u16, now: Instant) -> Result<(), LockError> {
+        self.attempt(Some(pin) == self.config.pin, LockError::WrongPin, now)?;
+        self.unlocked = Some(now);
+        Ok(())
+    }
+
+    /// Unlock with the clicks entered on the button until the relock time
+    pub fn unlock_with_clicks(&mut self, clicks: &[Click], now: Instant) -> Result<(), LockError> {
+        let matches = self
+            .config
+            .pattern
+            .is_some_and(|pattern| pattern.clicks().eq(clicks.iter().copied()));
+        self.attempt(matches, LockError::WrongPattern, now)?;
+        self.unlocked = Some(now);
+        Ok(())
+    }
+
+    /// Count the attempt, refused outright while the failed ones make it
+    /// wait
+    fn attempt(&mut self, matches: bool, wrong: LockError, now: Instant) -> Result<(), LockError> {
+        if let Some(until) = self.blocked_until.filter(|&until| until > now) {
+            let wait = until.duration_since(now);
+            return Err(LockError::RateLimited(wait.as_secs() + 1));
+        }
+        if matches {
+            self.failures = 0;
+            self.blocked_until = None;
+            return Ok(());
+        }
+        self.failures = self.failures.saturating_add(1);
+        if let Some(doublings) = self.failures.checked_sub(FREE_ATTEMPTS) {
+            let backoff = FIRST_BACKOFF
+                .saturating_mul(1u32 << doublings.min(16))
+                .min(MAX_BACKOFF);
+            self.blocked_until = Some(now + backoff);
+        }
+        Err(wrong)
+    }
+}
+
+/// Lock shared between the main loop and the HTTP server
+#[derive(Clone, Default)]
+pub struct LockHandle(Arc<Mutex<Lock>>);
+
+impl LockHandle {
+    pub fn new(config: LockConfig) -> Self {
+        Self(Arc::new(Mutex::new(Lock::new(config))))
+    }
+
+    /// Run `f` with the lock held
+    pub fn with<T>(&self, f: impl FnOnce(&mut Lock) -> T) -> T {
+        f(&mut self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.with(|lock| lock.is_locked(Instant::now()))
+    }
+
+    pub fn authorize(&self, pin: Option<u16>) -> Result<(), LockError> {
+        self.with(|lock| lock.authorize(pin, Instant::now()))
+    }
+
+    pub fn touch(&self) {
+        self.with(|lock| lock.touch(Instant::now()))
+    }
+}
+
+/// Parse a PIN of exactly 4 digits
+pub fn parse_pin(text: &str) -> Option<u16> {
+    if text.len() != usize::from(PIN_DIGITS) || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
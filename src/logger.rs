@@ -0,0 +1,97 @@
+//! Logger printing through esp-idf while keeping the latest lines in memory,
+//! so they can still be read over the console or HTTP after something went
+//! wrong. On the host the lines go to stderr.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+#[cfg(feature = "esp")]
+use esp_idf_svc::log::EspLogger;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::time::Timestamp;
+
+/// Lines kept in memory, the oldest are dropped first
+pub const LOG_RING_LINES: usize = 64;
+
+struct RingLogger {
+    #[cfg(feature = "esp")]
+    esp: EspLogger,
+    lines: Mutex<VecDeque<String>>,
+}
+
+static LOGGER: RingLogger = RingLogger {
+    #[cfg(feature = "esp")]
+    esp: EspLogger::new(),
+    lines: Mutex::new(VecDeque::new()),
+};
+
+impl Log for RingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        #[cfg(feature = "esp")]
+        if self.esp.enabled(record.metadata()) {
+            self.esp.log(record);
+        }
+
+        let line = format!(
+            "{} {} {}: {}",
+            Timestamp::now(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        #[cfg(not(feature = "esp"))]
+        eprintln!("{}", line);
+        let mut lines = self
+            .lines
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if lines.len() == LOG_RING_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn flush(&self) {
+        #[cfg(feature = "esp")]
+        self.esp.flush();
+    }
+}
+
+/// Install the logger, only the first call has an effect
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        set_level(level);
+    }
+}
+
+/// Change the level of the messages printed and kept
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+    // esp-idf filters on its own level too
+    #[cfg(feature = "esp")]
+    if let Err(err) = LOGGER.esp.set_target_level("*", level) {
+        log::warn!("Failed to set the esp-idf log level: {:?}", err);
+    }
+}
+
+pub fn level() -> LevelFilter {
+    log::max_level()
+}
+
+/// Latest lines, the oldest first
+pub fn recent_lines() -> Vec<String> {
+    LOGGER
+        .lines
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .iter()
+        .cloned()
+        .collect()
+}
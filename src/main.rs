@@ -1,80 +1,519 @@
-mod button;
-mod scale;
-mod text_drawer;
-
-use embedded_graphics::{mono_font::ascii::FONT_7X13_BOLD, prelude::*};
+use embedded_graphics::mono_font::iso_8859_1::FONT_7X13_BOLD;
+#[cfg(feature = "battery")]
+use esp32::battery::start_battery_task;
+#[cfg(feature = "ble")]
+use esp32::ble::start_ble;
+#[cfg(feature = "buzzer")]
+use esp32::buzzer::start_buzzer_task;
+#[cfg(feature = "sdcard")]
+use esp32::datalog::sdcard::start_sdcard_task;
+#[cfg(feature = "dispense")]
+use esp32::dispense::start_dispenser;
+#[cfg(feature = "espnow")]
+use esp32::espnow::{start_espnow_task, EspNowRole};
+#[cfg(feature = "http")]
+use esp32::http_api::{start_http_task, HttpHandles};
+#[cfg(feature = "led")]
+use esp32::led::start_led_task;
+#[cfg(feature = "mdns")]
+use esp32::mdns::start_mdns_task;
+#[cfg(feature = "modbus")]
+use esp32::modbus::start_modbus_task;
+#[cfg(feature = "mqtt")]
+use esp32::mqtt::{start_mqtt_task, MqttConfig, CHANGE_MIN_INTERVAL, MQTT_SINK};
+#[cfg(feature = "webhook")]
+use esp32::webhook::{start_webhook_task, WebhookConfig, WEBHOOK_SINK};
+use esp32::{
+    alarms::AlarmStore,
+    app::{self, Services},
+    certified,
+    command_channel::{command_channel, CommandRequest, CommandSender},
+    console, counters,
+    datalog::open_datalog,
+    device::{self, DeviceIdentity},
+    display,
+    error::{EspContext, FirmwareError},
+    events::AppEvent,
+    feedback::start_feedback_task,
+    governor, i18n,
+    i2c_bus::SharedI2c,
+    imu::{start_imu_task, Mpu6050},
+    lock::LockHandle,
+    logger,
+    nau7802::Nau7802,
+    ota::OtaHandle,
+    panic_screen::{self, PanicScreen},
+    reset::ResetLog,
+    scale::{start_scale_button, Scale},
+    sensor::{Hx711, LoadSensor, SensorKind},
+    session::SessionStore,
+    settings::{MirrorConfig, Settings, SettingsStore},
+    shutdown::{self, ShutdownReason},
+    storage::{self, StorageService},
+    text_drawer::{MirrorInterface, NullDisplay, TextDrawer},
+    watchdog::{self, WATCHDOG_TIMEOUT},
+};
 use esp_idf_hal::{
-    delay::FreeRtos,
+    delay::{Delay, FreeRtos},
     gpio::*,
-    i2c::{I2cConfig, I2cDriver},
+    i2c::{I2cConfig, I2cDriver, I2C1},
     peripherals::Peripherals,
     prelude::*,
 };
-use scale::*;
-use text_drawer::*;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{error, info, warn, LevelFilter};
+use std::sync::mpsc::{sync_channel, Receiver};
+#[cfg(feature = "wifi")]
+use {
+    esp32::{time::start_time_task, wifi::start_wifi_task},
+    esp_idf_svc::eventloop::EspSystemEventLoop,
+};
 
 use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
 
-fn main() -> anyhow::Result<()> {
+const TALL_DISPLAY_HEIGHT: u8 = 64;
+/// Time the error stays up before restarting
+const FATAL_ERROR_RESTART_MS: u32 = 10_000;
+
+fn main() {
     esp_idf_hal::sys::link_patches();
+    // Anchor the uptime timestamps to boot
+    esp32::time::uptime();
+    // Raised or lowered once the settings are loaded
+    logger::init(LevelFilter::Info);
 
-    let peripherals = Peripherals::take()?;
+    if let Err(err) = start() {
+        error!("Fatal error: {}", err);
+        // Restarting does not fix the wiring, keep the failed check on screen
+        if matches!(err, FirmwareError::SelfTest { .. }) {
+            loop {
+                FreeRtos::delay_ms(FATAL_ERROR_RESTART_MS);
+            }
+        }
+        FreeRtos::delay_ms(FATAL_ERROR_RESTART_MS);
+        shutdown::restart(ShutdownReason::FatalError);
+    }
+}
 
-    // Create the display
-    let mut display = {
+/// Bring up the hardware and the services, then run the application until it
+/// fails
+fn start() -> Result<(), FirmwareError> {
+    let peripherals = Peripherals::take().context("to take the peripherals")?;
+    let nvs_default_partition =
+        EspDefaultNvsPartition::take().context("to take the NVS partition")?;
+    let storage_service = StorageService::start(nvs_default_partition.clone())?;
+    // Bring the stored layouts up to date before anything reads them
+    storage::migrate(&storage_service);
+    // Every payload sent out carries the identity, so it is settled first
+    let identity = device::init(DeviceIdentity::load(&storage_service));
+    info!("Device {} boot {}", identity.device_id(), identity.boot());
+    if let Err(err) = counters::start(&storage_service) {
+        warn!("Failed to start the counters: {:?}", err);
+    }
+    let settings_store = SettingsStore::new(&storage_service).map_err(FirmwareError::Nvs)?;
+    let settings = settings_store.settings().clone();
+    logger::set_level(settings.log_level());
+    // Before the scale, whose calibration changes it counts
+    if let Err(err) = certified::start(&storage_service, settings.certified()) {
+        warn!("Failed to load the certified audit counter: {:?}", err);
+    }
+    // Before the optional subsystems, which it admits
+    if let Err(err) = governor::start(settings.heap_reserve_kb(), settings.heap_critical_kb()) {
+        warn!("Failed to start the heap governor: {:?}", err);
+    }
+    i18n::set_language(settings.language());
+    if let Err(err) = watchdog::configure(WATCHDOG_TIMEOUT) {
+        warn!("Failed to configure the watchdog: {:?}", err);
+    }
+
+    let pins = match settings.board_pins().validate() {
+        Ok(()) => settings.board_pins(),
+        Err(err) => {
+            warn!("Invalid pin settings, using the defaults: {}", err);
+            Settings::default().board_pins()
+        }
+    };
+
+    let (app_event_sender, app_events) = sync_channel(app::APP_EVENT_QUEUE_LEN);
+
+    // The display and the NAU7802 share the I2C bus, so it comes up first.
+    // Probe for the panel at the address it answers at, or none to run
+    // headless.
+    let (i2c_bus, display_address) = {
         let i2c = peripherals.i2c0;
-        let sda = peripherals.pins.gpio21;
-        let scl = peripherals.pins.gpio22;
+        let sda = unsafe { AnyIOPin::new(pins.sda.into()) };
+        let scl = unsafe { AnyIOPin::new(pins.scl.into()) };
         let config = I2cConfig::new().baudrate(400.kHz().into());
-        let i2c_driver = I2cDriver::new(i2c, sda, scl, &config)?;
-        let i2c_interface = I2CDisplayInterface::new(i2c_driver);
-        Ssd1306::new(i2c_interface, DisplaySize128x32, DisplayRotation::Rotate0)
-            .into_buffered_graphics_mode()
+        let i2c_driver =
+            I2cDriver::new(i2c, sda, scl, &config).context("to start the display I2C bus")?;
+        let i2c_bus = SharedI2c::new(i2c_driver);
+        let display_address = display::probe(&mut i2c_bus.lock());
+        (i2c_bus, display_address)
+    };
+    let i2c_interface = display_address
+        .map(|address| I2CDisplayInterface::new_custom_address(i2c_bus.clone(), address));
+    let mirror = match settings.mirror() {
+        Some(mirror) => mirror_interface(mirror, &i2c_bus, display_address, peripherals.i2c1),
+        None => None,
     };
 
-    // Initialize the display
-    display.init().unwrap();
+    // Create the scale. The pins and the sensor come from the settings, so
+    // they can only be picked at runtime.
+    let mut scale = {
+        let (sensor, rate_pin) = match settings.sensor() {
+            SensorKind::Hx711 => {
+                let hx711_dt = unsafe { AnyInputPin::new(pins.hx711_dt.into()) };
+                let hx711_sck = unsafe { AnyOutputPin::new(pins.hx711_sck.into()) };
+                let hx711_dt = PinDriver::input(hx711_dt).context("to set up the HX711")?;
+                let hx711_sck = PinDriver::output(hx711_sck).context("to set up the HX711")?;
+                let rate_pin = settings
+                    .hx711_rate_pin()
+                    .map(|pin| PinDriver::output(unsafe { AnyOutputPin::new(pin.into()) }))
+                    .transpose()
+                    .context("to set up the HX711 rate pin")?;
+                let sensor: Box<dyn LoadSensor> =
+                    Box::new(Hx711::new(hx711_sck, hx711_dt, Delay::default()));
+                (sensor, rate_pin)
+            }
+            SensorKind::Nau7802 => {
+                let sensor: Box<dyn LoadSensor> =
+                    Box::new(Nau7802::start(i2c_bus.clone(), settings.nau7802_gain())?);
+                (sensor, None)
+            }
+        };
+        let button = unsafe { AnyIOPin::new(pins.button.into()) };
+        let button = PinDriver::input(button).context("to set up the button")?;
+        let button_event_handle = start_scale_button(button, &settings, app_event_sender.clone())?;
+        let builder = Scale::builder(
+            sensor,
+            settings.sensor(),
+            button_event_handle,
+            &storage_service,
+        )
+        .settings(&settings);
+        match rate_pin {
+            Some(pin) => builder.rate_pin(pin),
+            None => builder,
+        }
+        .build()?
+    };
 
-    // Create the text drawer
-    let mut text_drawer = TextDrawer::new(display, &FONT_7X13_BOLD);
+    // Bumps of the table are only rejected with an IMU on the bus
+    if let Some(threshold_g) = settings.bump_threshold() {
+        match Mpu6050::probe(&i2c_bus).map(|mut imu| imu.start().map(|()| imu)) {
+            Some(Ok(imu)) => {
+                if let Err(err) = start_imu_task(imu, threshold_g) {
+                    warn!("Failed to start the IMU task: {:?}", err);
+                }
+            }
+            Some(Err(err)) => warn!("Failed to set up the IMU: {:?}", err),
+            None => {}
+        }
+    }
 
-    // Create the scale
-    let mut scale = {
-        let hx711_dt = PinDriver::input(peripherals.pins.gpio16)?;
-        let hx711_sck = PinDriver::output(peripherals.pins.gpio4)?;
-        let button = PinDriver::input(peripherals.pins.gpio17)?;
-        Scale::new(hx711_sck, hx711_dt, button)?
+    let (command_sender, commands) = command_channel();
+    console::start_console_task(command_sender.clone());
+
+    let mut services = Services {
+        storage: Some(storage_service.clone()),
+        lock: LockHandle::new(settings.lock()),
+        ..Services::default()
     };
+    if let Err(err) = start_feedback_task(services.feedback.clone(), scale.subscribe(), &settings) {
+        warn!("Failed to start the feedback task: {:?}", err);
+    }
+    #[cfg(feature = "buzzer")]
+    if let Err(err) = start_buzzer_task(
+        peripherals.ledc.timer0,
+        peripherals.ledc.channel0,
+        &settings,
+        &services.feedback,
+    ) {
+        warn!("Failed to start the buzzer: {:?}", err);
+    }
+    #[cfg(feature = "led")]
+    if let Err(err) = start_led_task(peripherals.rmt.channel0, &settings, &services.feedback) {
+        warn!("Failed to start the status LED: {:?}", err);
+    }
+    match ResetLog::start(nvs_default_partition.clone()) {
+        Ok(resets) => services.resets = Some(resets),
+        Err(err) => warn!("Failed to count the resets: {:?}", err),
+    }
+    // After the reset log, which saves the panic message first
+    if let (Some(address), Some(hold)) = (display_address, settings.panic_hold()) {
+        panic_screen::install(PanicScreen {
+            bus: i2c_bus.clone(),
+            address,
+            tall: settings.display_height() == TALL_DISPLAY_HEIGHT,
+            rotation: settings.display_rotation(),
+            hold,
+        });
+    }
+    match AlarmStore::open(&storage_service) {
+        Ok(alarm_store) => services.alarm_store = Some(alarm_store),
+        Err(err) => warn!("Failed to open the alarm states: {:?}", err),
+    }
+    match SessionStore::open(&storage_service) {
+        Ok(session_store) => services.session_store = Some(session_store),
+        Err(err) => warn!("Failed to open the session stats: {:?}", err),
+    }
+    services.ota = OtaHandle::new(settings.update_token());
+    match open_datalog(&settings) {
+        Ok(datalog) => services.datalog = datalog,
+        Err(err) => warn!("Failed to open the weight log: {:?}", err),
+    }
+    #[cfg(feature = "battery")]
+    match start_battery_task(peripherals.adc1, peripherals.pins.gpio34, &settings) {
+        Ok(battery) => services.battery = Some(battery),
+        Err(err) => warn!("Failed to start battery monitoring: {:?}", err),
+    }
+    #[cfg(feature = "dispense")]
+    match start_dispenser(&settings, scale.subscribe()) {
+        Ok(dispenser) => services.dispenser = Some(dispenser),
+        Err(err) => warn!("Failed to start the dispenser: {:?}", err),
+    }
+    #[cfg(feature = "modbus")]
+    if let Err(err) = start_modbus_task(
+        peripherals.uart1,
+        &settings,
+        services.snapshot.clone(),
+        services.feedback.clone(),
+        command_sender.clone(),
+    ) {
+        warn!("Failed to start the Modbus slave: {:?}", err);
+    }
+    #[cfg(feature = "sdcard")]
+    match start_sdcard_task(peripherals.spi3, &settings) {
+        Ok(sdcard) => services.sdcard = sdcard,
+        Err(err) => warn!("Failed to start SD card logging: {:?}", err),
+    }
 
-    scale.tare(&mut text_drawer)?;
-    if scale.needs_calibration() {
-        scale.calibrate(&mut text_drawer)?;
+    // Network failures are logged but never keep the scale from weighing
+    #[cfg(feature = "wifi")]
+    match start_wifi_task(
+        peripherals.modem,
+        EspSystemEventLoop::take().context("to take the system event loop")?,
+        nvs_default_partition.clone(),
+        &settings,
+        command_sender.clone(),
+    ) {
+        Ok(wifi) => services.wifi = Some(wifi),
+        Err(err) => warn!("Failed to start Wi-Fi: {:?}", err),
+    }
+    #[cfg(feature = "wifi")]
+    if let Some(wifi) = &services.wifi {
+        if let Err(err) = start_time_task(wifi.clone()) {
+            warn!("Failed to start time synchronization: {:?}", err);
+        }
+    }
+    // On the radio the Wi-Fi task started
+    #[cfg(feature = "espnow")]
+    if services.wifi.is_some() && settings.espnow_role() != EspNowRole::Off {
+        match start_espnow_task(&settings, services.snapshot.clone(), command_sender.clone()) {
+            Ok(espnow) => services.espnow = Some(espnow),
+            Err(err) => warn!("Failed to start ESP-NOW: {:?}", err),
+        }
+    }
+    #[cfg(feature = "http")]
+    if let Some(wifi) = &services.wifi {
+        let handles = HttpHandles {
+            snapshot: services.snapshot.clone(),
+            commands: command_sender.clone(),
+            datalog: services.datalog.clone(),
+            resets: services.resets.clone(),
+            ota: services.ota.clone(),
+            time_weighted: scale.time_weighted(),
+        };
+        let started = start_http_task(wifi.clone(), scale.subscribe(), handles);
+        if let Err(err) = started {
+            warn!("Failed to start HTTP API: {:?}", err);
+        }
+    }
+    #[cfg(feature = "mdns")]
+    if let Some(wifi) = &services.wifi {
+        let events = scale.subscribe();
+        match start_mdns_task(
+            wifi.clone(),
+            &device::hostname(settings.hostname()),
+            settings.unit(),
+            events,
+        ) {
+            Ok(mdns) => services.mdns = Some(mdns),
+            Err(err) => warn!("Failed to start mDNS: {:?}", err),
+        }
+    }
+    #[cfg(feature = "mqtt")]
+    if let Some(config) = MqttConfig::from_settings(&settings) {
+        #[cfg(feature = "battery")]
+        let config = match services.battery {
+            Some(_) => config.with_battery_voltage(),
+            None => config,
+        };
+        let (events, sink) =
+            scale.subscribe_through_sink(MQTT_SINK, config.min_delta_grams(), CHANGE_MIN_INTERVAL);
+        match start_mqtt_task(config, events, services.snapshot.clone()) {
+            Ok(mqtt) => {
+                services.mqtt = Some(mqtt);
+                services.mqtt_sink = Some(sink);
+            }
+            Err(err) => warn!("Failed to start MQTT publishing: {:?}", err),
+        }
     }
 
-    loop {
-        let scale_action = scale.poll_action();
+    #[cfg(feature = "webhook")]
+    if let (Some(wifi), Some(config)) = (&services.wifi, WebhookConfig::from_settings(&settings)) {
+        let (events, sink) =
+            scale.subscribe_through_sink(WEBHOOK_SINK, 0.0, std::time::Duration::ZERO);
+        match start_webhook_task(config, wifi.clone(), events, &storage_service) {
+            Ok(()) => services.webhook_sink = Some(sink),
+            Err(err) => warn!("Failed to start the webhook: {:?}", err),
+        }
+    }
 
-        if let Some(action) = scale_action {
-            match action {
-                ScaleAction::Tare => {
-                    scale.tare(&mut text_drawer)?;
-                }
-                ScaleAction::Calibrate => {
-                    scale.calibrate(&mut text_drawer)?;
+    #[cfg(feature = "ble")]
+    if let Err(err) = start_ble(
+        services.snapshot.clone(),
+        scale.subscribe(),
+        command_sender.clone(),
+    ) {
+        warn!("Failed to start BLE: {:?}", err);
+    }
+
+    scale.start_sampling(app_event_sender)?;
+
+    // The panel size is a type parameter of the driver, so pick the matching
+    // one based on the configured display height. Without a panel the size
+    // makes no difference.
+    let Some(i2c_interface) = i2c_interface else {
+        let text_drawer = create_text_drawer(NullDisplay, DisplaySize128x64, mirror, &settings);
+        return run_app(
+            text_drawer,
+            scale,
+            settings_store,
+            app_events,
+            commands,
+            command_sender,
+            services,
+        );
+    };
+    if settings.display_height() == TALL_DISPLAY_HEIGHT {
+        let text_drawer = create_text_drawer(i2c_interface, DisplaySize128x64, mirror, &settings);
+        run_app(
+            text_drawer,
+            scale,
+            settings_store,
+            app_events,
+            commands,
+            command_sender,
+            services,
+        )
+    } else {
+        let text_drawer = create_text_drawer(i2c_interface, DisplaySize128x32, mirror, &settings);
+        run_app(
+            text_drawer,
+            scale,
+            settings_store,
+            app_events,
+            commands,
+            command_sender,
+            services,
+        )
+    }
+}
+
+/// Run the application, showing the error it stopped on
+fn run_app<DI, SIZE>(
+    mut text_drawer: TextDrawer<DI, SIZE>,
+    scale: Scale,
+    settings_store: SettingsStore,
+    app_events: Receiver<AppEvent>,
+    commands: Receiver<CommandRequest>,
+    command_sender: CommandSender,
+    services: Services,
+) -> Result<(), FirmwareError>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let feedback = services.feedback.clone();
+    let result = app::run(
+        &mut text_drawer,
+        scale,
+        settings_store,
+        app_events,
+        commands,
+        command_sender,
+        services,
+    );
+    if let Err(err) = &result {
+        app::show_fatal_error(&mut text_drawer, &feedback, err);
+    }
+    result
+}
+
+/// Interface of the panel mirroring the display, on the bus of the display
+/// or on the second I2C controller. None when that bus fails to come up.
+fn mirror_interface(
+    mirror: MirrorConfig,
+    display_bus: &SharedI2c,
+    display_address: Option<u8>,
+    i2c: I2C1,
+) -> Option<MirrorInterface> {
+    let bus = match mirror.pins {
+        None if display_address == Some(mirror.address) => {
+            warn!(
+                "The mirror is set to 0x{:02X}, the address of the display",
+                mirror.address
+            );
+            return None;
+        }
+        None => display_bus.clone(),
+        Some((sda, scl)) => {
+            let sda = unsafe { AnyIOPin::new(sda.into()) };
+            let scl = unsafe { AnyIOPin::new(scl.into()) };
+            let config = I2cConfig::new().baudrate(400.kHz().into());
+            match I2cDriver::new(i2c, sda, scl, &config) {
+                Ok(driver) => SharedI2c::new(driver),
+                Err(err) => {
+                    warn!("Failed to start the mirror I2C bus: {:?}", err);
+                    return None;
                 }
             }
         }
+    };
+    info!("Mirroring the display to 0x{:02X}", mirror.address);
+    Some(MirrorInterface::new(
+        I2CDisplayInterface::new_custom_address(bus, mirror.address),
+    ))
+}
 
-        if let Some(grams) = scale.poll_grams() {
-            println!("Weight: {}g", grams);
-            let fmt_string = if grams.abs() > 1000.0 {
-                format!("Weight: {:.2}kg", grams / 1000.0)
-            } else {
-                format!("Weight: {}g", grams.round() as i32)
-            };
-            text_drawer.draw_text_clear_flush(&fmt_string, Point::zero())?;
-        }
+fn create_text_drawer<'a, DI, SIZE>(
+    interface: DI,
+    size: SIZE,
+    mirror: Option<MirrorInterface>,
+    settings: &Settings,
+) -> TextDrawer<'a, DI, SIZE>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize + Copy,
+{
+    let display =
+        Ssd1306::new(interface, size, settings.display_rotation()).into_buffered_graphics_mode();
+    let mut text_drawer = TextDrawer::new(display, &FONT_7X13_BOLD);
 
-        FreeRtos::delay_ms(500u32);
+    // Initialize the display, a missing panel is retried from the main loop
+    if let Err(err) = text_drawer.reinit() {
+        warn!("Failed to initialize display: {:?}", err);
+    }
+    if let Err(err) = text_drawer.set_brightness(settings.brightness()) {
+        warn!("Failed to set display brightness: {:?}", err);
     }
+    // A missing or failing mirror is retried from the main loop too
+    if let Some(mirror) = mirror {
+        let display =
+            Ssd1306::new(mirror, size, settings.mirror_rotation()).into_buffered_graphics_mode();
+        text_drawer.set_mirror(display);
+    }
+
+    text_drawer
 }
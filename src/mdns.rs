@@ -0,0 +1,119 @@
+use std::{
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_sys::EspError;
+use log::{info, warn};
+
+use crate::{
+    events::WeightEvent,
+    http_api::HTTP_PORT,
+    unit::Unit,
+    wifi::{WifiHandle, WifiState},
+};
+
+const MDNS_TASK_STACK_SIZE: usize = 4 * 1024;
+/// Period the Wi-Fi state is checked at while no events arrive
+const WIFI_POLL_PERIOD: Duration = Duration::from_secs(1);
+const INSTANCE_NAME: &str = "ESP32 Scale";
+
+enum MdnsRequest {
+    SetHostname(String),
+}
+
+/// Handle to the task advertising the scale over mDNS
+pub struct MdnsHandle {
+    requests: Sender<MdnsRequest>,
+}
+
+impl MdnsHandle {
+    /// Advertise the scale under another name, effective right away
+    pub fn set_hostname(&self, hostname: &str) {
+        let _ = self
+            .requests
+            .send(MdnsRequest::SetHostname(hostname.to_string()));
+    }
+}
+
+/// Start advertising the scale as `<hostname>.local`, along with its HTTP
+/// API. The records are registered whenever Wi-Fi is connected.
+pub fn start_mdns_task(
+    wifi: WifiHandle,
+    hostname: &str,
+    unit: Unit,
+    events: Receiver<WeightEvent>,
+) -> anyhow::Result<MdnsHandle> {
+    let (requests_tx, requests_rx) = channel();
+    let hostname = hostname.to_string();
+    std::thread::Builder::new()
+        .name("mdns".to_string())
+        .stack_size(MDNS_TASK_STACK_SIZE)
+        .spawn(move || mdns_task(wifi, hostname, unit, events, requests_rx))?;
+    Ok(MdnsHandle {
+        requests: requests_tx,
+    })
+}
+
+fn mdns_task(
+    wifi: WifiHandle,
+    mut hostname: String,
+    mut unit: Unit,
+    events: Receiver<WeightEvent>,
+    requests: Receiver<MdnsRequest>,
+) {
+    let mut mdns: Option<EspMdns> = None;
+    loop {
+        // Any change of the advertised records needs them registered again
+        let mut changed = false;
+        match events.recv_timeout(WIFI_POLL_PERIOD) {
+            Ok(WeightEvent::UnitChanged(new_unit)) => {
+                unit = new_unit;
+                changed = true;
+            }
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            // Keep following the Wi-Fi state without unit updates
+            Err(RecvTimeoutError::Disconnected) => std::thread::sleep(WIFI_POLL_PERIOD),
+        }
+        while let Ok(request) = requests.try_recv() {
+            match request {
+                MdnsRequest::SetHostname(new_hostname) => {
+                    hostname = new_hostname;
+                    changed = true;
+                }
+            }
+        }
+
+        let connected = wifi.state() == WifiState::Connected;
+        if (changed || !connected) && mdns.take().is_some() {
+            info!("mDNS records removed");
+        }
+        if connected && mdns.is_none() {
+            match register(&hostname, unit) {
+                Ok(registered) => {
+                    info!("Advertising as {}.local", hostname);
+                    mdns = Some(registered);
+                }
+                Err(err) => warn!("Failed to register mDNS records: {:?}", err),
+            }
+        }
+    }
+}
+
+fn register(hostname: &str, unit: Unit) -> Result<EspMdns, EspError> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(INSTANCE_NAME)?;
+    mdns.add_service(
+        None,
+        "_http",
+        "_tcp",
+        HTTP_PORT,
+        &[
+            ("version", env!("CARGO_PKG_VERSION")),
+            ("unit", unit.symbol()),
+        ],
+    )?;
+    Ok(mdns)
+}
@@ -0,0 +1,443 @@
+use embedded_graphics::prelude::Point;
+use ssd1306::{prelude::WriteOnlyDataCommand, size::DisplaySize};
+
+use crate::{
+    button::ButtonAction,
+    i18n::{tr, trf, StringId},
+    text_drawer::{DisplayError, TextDrawer, TextError},
+};
+
+/// A menu entry. Values are read from and handed back to the owning
+/// subsystem through the context `C` the menu is driven with.
+pub enum MenuItem<C> {
+    Submenu {
+        label: &'static str,
+        items: Vec<MenuItem<C>>,
+    },
+    Toggle {
+        label: &'static str,
+        get: fn(&C) -> bool,
+        set: fn(&mut C, bool),
+    },
+    Numeric {
+        label: &'static str,
+        min: i32,
+        max: i32,
+        step: i32,
+        get: fn(&C) -> i32,
+        set: fn(&mut C, i32),
+    },
+    /// Number entered digit by digit, see `NumberEntry`
+    Number {
+        label: &'static str,
+        digits: u8,
+        decimals: u8,
+        min: f32,
+        max: f32,
+        get: fn(&C) -> f32,
+        set: fn(&mut C, f32),
+    },
+    Choice {
+        label: &'static str,
+        options: &'static [&'static str],
+        get: fn(&C) -> usize,
+        set: fn(&mut C, usize),
+    },
+    Action {
+        label: &'static str,
+        run: fn(&mut C),
+    },
+}
+
+/// Bound a confirmed number was moved to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Clamp {
+    Min,
+    Max,
+}
+
+/// Number entered with a single button, for values too far apart for
+/// stepping through them. A press increments the selected digit (wrapping
+/// around), a long press moves on to the next digit and a double press
+/// confirms the whole number. A number out of range is clamped on
+/// confirmation and shown for another double press to accept it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumberEntry {
+    /// Digits, the most significant first
+    digits: Vec<u8>,
+    /// Digits after the decimal point
+    decimals: u8,
+    min: f32,
+    max: f32,
+    selected: usize,
+    clamped: Option<Clamp>,
+}
+
+impl NumberEntry {
+    /// At least one digit is entered, and at least one before the decimal
+    /// point
+    pub fn new(digits: u8, decimals: u8, min: f32, max: f32, value: f32) -> Self {
+        let digits = digits.max(1);
+        let mut entry = Self {
+            digits: vec![0; usize::from(digits)],
+            decimals: decimals.min(digits - 1),
+            min,
+            max,
+            selected: 0,
+            clamped: None,
+        };
+        entry.set_value(value);
+        entry
+    }
+
+    fn scale(&self) -> f32 {
+        10f32.powi(i32::from(self.decimals))
+    }
+
+    pub fn value(&self) -> f32 {
+        let units = self
+            .digits
+            .iter()
+            .fold(0u32, |units, &digit| units * 10 + u32::from(digit));
+        units as f32 / self.scale()
+    }
+
+    /// Show the value, its digits beyond the entry dropped
+    fn set_value(&mut self, value: f32) {
+        let mut units = (value * self.scale()).round().max(0.0) as u32;
+        for digit in self.digits.iter_mut().rev() {
+            *digit = (units % 10) as u8;
+            units /= 10;
+        }
+    }
+
+    /// Bound the value was moved to on the last confirmation, cleared by
+    /// any change
+    pub fn clamped(&self) -> Option<Clamp> {
+        self.clamped
+    }
+
+    /// Handle a button gesture, returning the number once confirmed
+    pub fn handle(&mut self, action: ButtonAction) -> Option<f32> {
+        match action {
+            ButtonAction::Press => {
+                let digit = &mut self.digits[self.selected];
+                *digit = (*digit + 1) % 10;
+                self.clamped = None;
+            }
+            ButtonAction::LongPress => {
+                self.selected = (self.selected + 1) % self.digits.len();
+            }
+            ButtonAction::DoublePress => {
+                let value = self.value();
+                if self.clamped.is_some() {
+                    return Some(value);
+                }
+                if value < self.min {
+                    self.set_value(self.min);
+                    self.clamped = Some(Clamp::Min);
+                } else if value > self.max {
+                    self.set_value(self.max);
+                    self.clamped = Some(Clamp::Max);
+                } else {
+                    return Some(value);
+                }
+            }
+        }
+        None
+    }
+
+    /// Characters of the number along with whether they are highlighted
+    fn chars(&self) -> impl Iterator<Item = (char, bool)> + '_ {
+        let point = self.digits.len() - usize::from(self.decimals);
+        self.digits
+            .iter()
+            .enumerate()
+            .flat_map(move |(index, &digit)| {
+                let separator = (index == point).then_some(('.', false));
+                let digit = (char::from(b'0' + digit), index == self.selected);
+                separator.into_iter().chain(Some(digit))
+            })
+    }
+
+    /// Draw the number with the selected digit in inverse video
+    pub fn render<DI, SIZE>(
+        &self,
+        position: Point,
+        text_drawer: &mut TextDrawer<DI, SIZE>,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+    where
+        DI: WriteOnlyDataCommand,
+        SIZE: DisplaySize,
+    {
+        let normal = text_drawer.char_style();
+        let inverse = text_drawer.inverse_char_style();
+        let char_width = normal.font.character_size.width as i32;
+        for (index, (c, highlighted)) in self.chars().enumerate() {
+            let style = if highlighted { inverse } else { normal };
+            let mut buf = [0; 4];
+            text_drawer.draw_text_with_char_style(
+                c.encode_utf8(&mut buf),
+                position + Point::new(index as i32 * char_width, 0),
+                style,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Width of the number in characters
+    pub(crate) fn len(&self) -> usize {
+        self.digits.len() + usize::from(self.decimals > 0)
+    }
+}
+
+/// Value of the selected item while it is being edited
+enum Edit {
+    Value(i32),
+    Number(NumberEntry),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuState {
+    Open,
+    Closed,
+}
+
+/// Menu navigable with a single button: a press moves to the next item, a
+/// long press enters the selected item and a double press goes back.
+/// While a value is edited, a press increments it (wrapping around), a long
+/// press confirms it and a double press discards the change. Numbers are
+/// entered through a `NumberEntry` instead.
+pub struct Menu<C> {
+    items: Vec<MenuItem<C>>,
+    /// Indices of the entered submenus, starting from the root
+    path: Vec<usize>,
+    selected: usize,
+    editing: Option<Edit>,
+}
+
+impl<C> MenuItem<C> {
+    pub fn label(&self) -> &'static str {
+        match self {
+            MenuItem::Submenu { label, .. }
+            | MenuItem::Toggle { label, .. }
+            | MenuItem::Numeric { label, .. }
+            | MenuItem::Number { label, .. }
+            | MenuItem::Choice { label, .. }
+            | MenuItem::Action { label, .. } => label,
+        }
+    }
+
+    pub(crate) fn format_value(&self, value: i32) -> String {
+        match self {
+            MenuItem::Submenu { .. } => ">".to_string(),
+            MenuItem::Toggle { .. } => String::from(tr(if value != 0 {
+                StringId::On
+            } else {
+                StringId::Off
+            })),
+            MenuItem::Numeric { .. } => value.to_string(),
+            MenuItem::Choice { options, .. } => options
+                .get(value as usize)
+                .copied()
+                .unwrap_or("?")
+                .to_string(),
+            MenuItem::Number { .. } | MenuItem::Action { .. } => String::new(),
+        }
+    }
+
+    pub(crate) fn current_value(&self, ctx: &C) -> i32 {
+        match self {
+            MenuItem::Toggle { get, .. } => get(ctx).into(),
+            MenuItem::Numeric { get, .. } => get(ctx),
+            MenuItem::Choice { get, .. } => get(ctx) as i32,
+            MenuItem::Submenu { .. } | MenuItem::Number { .. } | MenuItem::Action { .. } => 0,
+        }
+    }
+
+    fn format_current(&self, ctx: &C) -> String {
+        match self {
+            MenuItem::Number { decimals, get, .. } => {
+                format!("{:.*}", usize::from(*decimals), get(ctx))
+            }
+            _ => self.format_value(self.current_value(ctx)),
+        }
+    }
+}
+
+impl<C> Menu<C> {
+    pub fn new(items: Vec<MenuItem<C>>) -> Self {
+        Self {
+            items,
+            path: Vec::new(),
+            selected: 0,
+            editing: None,
+        }
+    }
+
+    /// Items of the currently entered submenu and its title
+    fn current(&self) -> (&'static str, &[MenuItem<C>]) {
+        self.path.iter().fold(
+            (tr(StringId::MenuTitle), &self.items[..]),
+            |(title, items), &index| match &items[index] {
+                MenuItem::Submenu { label, items } => (*label, &items[..]),
+                _ => (title, items),
+            },
+        )
+    }
+
+    fn selected_item(&self) -> Option<&MenuItem<C>> {
+        self.current().1.get(self.selected)
+    }
+
+    /// Handle a button gesture, returning whether the menu is still open
+    pub fn handle(&mut self, action: ButtonAction, ctx: &mut C) -> MenuState {
+        match self.editing.take() {
+            Some(Edit::Value(value)) => {
+                self.handle_edit(action, value, ctx);
+                return MenuState::Open;
+            }
+            Some(Edit::Number(entry)) => {
+                self.handle_entry(action, entry, ctx);
+                return MenuState::Open;
+            }
+            None => {}
+        }
+
+        match action {
+            ButtonAction::Press => {
+                let len = self.current().1.len();
+                if len > 0 {
+                    self.selected = (self.selected + 1) % len;
+                }
+            }
+            ButtonAction::LongPress => self.enter(ctx),
+            ButtonAction::DoublePress => match self.path.pop() {
+                Some(parent_index) => self.selected = parent_index,
+                None => {
+                    self.selected = 0;
+                    return MenuState::Closed;
+                }
+            },
+        }
+        MenuState::Open
+    }
+
+    fn enter(&mut self, ctx: &mut C) {
+        let Some(item) = self.selected_item() else {
+            return;
+        };
+        match item {
+            MenuItem::Submenu { .. } => {
+                self.path.push(self.selected);
+                self.selected = 0;
+            }
+            MenuItem::Toggle { get, set, .. } => {
+                let value = get(ctx);
+                set(ctx, !value);
+            }
+            MenuItem::Numeric { .. } | MenuItem::Choice { .. } => {
+                self.editing = Some(Edit::Value(item.current_value(ctx)));
+            }
+            MenuItem::Number {
+                digits,
+                decimals,
+                min,
+                max,
+                get,
+                ..
+            } => {
+                let entry = NumberEntry::new(*digits, *decimals, *min, *max, get(ctx));
+                self.editing = Some(Edit::Number(entry));
+            }
+            MenuItem::Action { run, .. } => run(ctx),
+        }
+    }
+
+    fn handle_edit(&mut self, action: ButtonAction, value: i32, ctx: &mut C) {
+        let Some(item) = self.selected_item() else {
+            return;
+        };
+        match action {
+            ButtonAction::Press => {
+                self.editing = Some(Edit::Value(match item {
+                    MenuItem::Numeric { min, max, step, .. } => {
+                        let next = value + step;
+                        if next > *max {
+                            *min
+                        } else {
+                            next
+                        }
+                    }
+                    MenuItem::Choice { options, .. } => (value + 1) % options.len() as i32,
+                    _ => value,
+                }));
+            }
+            ButtonAction::LongPress => match item {
+                MenuItem::Numeric { set, .. } => set(ctx, value),
+                MenuItem::Choice { set, .. } => set(ctx, value as usize),
+                _ => {}
+            },
+            ButtonAction::DoublePress => {}
+        }
+    }
+
+    fn handle_entry(&mut self, action: ButtonAction, mut entry: NumberEntry, ctx: &mut C) {
+        let Some(&MenuItem::Number { set, .. }) = self.selected_item() else {
+            return;
+        };
+        match entry.handle(action) {
+            Some(value) => set(ctx, value),
+            None => self.editing = Some(Edit::Number(entry)),
+        }
+    }
+
+    pub fn render<DI, SIZE>(
+        &self,
+        ctx: &C,
+        text_drawer: &mut TextDrawer<DI, SIZE>,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+    where
+        DI: WriteOnlyDataCommand,
+        SIZE: DisplaySize,
+    {
+        let (title, items) = self.current();
+        let item_line = match self.selected_item() {
+            Some(item) => match &self.editing {
+                Some(Edit::Value(value)) => {
+                    format!("{}: [{}]", item.label(), item.format_value(*value))
+                }
+                Some(Edit::Number(_)) => format!("{}:", item.label()),
+                None => format!("{} {}", item.label(), item.format_current(ctx)),
+            },
+            None => String::new(),
+        };
+        let header = match (&self.editing, self.selected_item()) {
+            (Some(Edit::Number(entry)), Some(MenuItem::Number { min, max, .. })) => {
+                match entry.clamped() {
+                    Some(Clamp::Min) => trf(StringId::Min, &[min]).to_string(),
+                    Some(Clamp::Max) => trf(StringId::Max, &[max]).to_string(),
+                    None => format!("{} {}/{}", title, self.selected + 1, items.len()),
+                }
+            }
+            _ => format!("{} {}/{}", title, self.selected + 1, items.len()),
+        };
+        let text = format!("{}\n{}", header, item_line);
+
+        let prompt = text_drawer.layout().prompt;
+        text_drawer.draw_text_clear(&text, prompt.top_left)?;
+        if let Some(Edit::Number(entry)) = &self.editing {
+            // After the label, or on a line of its own when it does not fit
+            let char_width = text_drawer.char_style().font.character_size.width;
+            let line_height = text_drawer.line_height() as i32;
+            let label_width = (item_line.chars().count() as u32 + 1) * char_width;
+            let position = if label_width + entry.len() as u32 * char_width <= prompt.size.width {
+                Point::new(label_width as i32, line_height)
+            } else {
+                Point::new(0, 2 * line_height)
+            };
+            entry.render(prompt.top_left + position, text_drawer)?;
+        }
+        text_drawer.flush()
+    }
+}
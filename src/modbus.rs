@@ -0,0 +1,608 @@
+//! Minimal Modbus RTU slave, for a PLC polling the weight over RS-485.
+//! Reads go to a register image refreshed from the snapshot before each
+//! request, and writes are turned into console commands run by the main
+//! loop, like the tare of the HTTP API.
+//!
+//! Input registers (function 4), read-only:
+//!
+//! | Address | Content                                              |
+//! |---------|------------------------------------------------------|
+//! | 0-1     | Weight in milligrams, signed 32 bit, high word first |
+//! | 2       | Status flags, `STATUS_*`                             |
+//! | 3       | Display unit: 0 g, 1 kg, 2 oz, 3 lb                  |
+//! | 4-5     | Unfiltered weight in milligrams, like the weight     |
+//!
+//! Holding registers (functions 3, 6 and 16):
+//!
+//! | Address | Content                                              |
+//! |---------|------------------------------------------------------|
+//! | 0       | Command, write `COMMAND_TARE` to tare, reads 0       |
+//! | 1-2     | Target weight in milligrams, unsigned, 0 disables it |
+
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "modbus")]
+use esp_idf_hal::{
+    delay::{TickType, NON_BLOCK},
+    gpio::{AnyIOPin, AnyInputPin, AnyOutputPin},
+    peripheral::Peripheral,
+    uart::{config::Config, Uart, UartDriver},
+    units::Hertz,
+};
+#[cfg(feature = "modbus")]
+use esp_idf_sys::{
+    esp, uart_mode_t_UART_MODE_RS485_HALF_DUPLEX, uart_set_mode, uart_set_rx_timeout,
+};
+#[cfg(feature = "modbus")]
+use log::{info, warn};
+
+#[cfg(feature = "modbus")]
+use crate::{
+    command_channel::{Command, CommandSender},
+    feedback::FeedbackDispatcher,
+    settings::Settings,
+    snapshot::SharedSnapshot,
+    watchdog::WatchdogGuard,
+};
+use crate::{procedure::CalibrationStatus, snapshot::Snapshot};
+
+/// Baud rates the slave can run at
+pub const MODBUS_BAUD_RATES: [u32; 7] = [2400, 4800, 9600, 19200, 38400, 57600, 115200];
+/// Highest address of a slave, the higher ones are reserved
+pub const MAX_MODBUS_ADDRESS: u8 = 247;
+/// Requests to every slave, carried out but never answered
+const BROADCAST_ADDRESS: u8 = 0;
+/// Longest RTU frame, longer ones are dropped
+const MAX_FRAME_LEN: usize = 256;
+/// Bits of a character: start, 8 data, parity and stop
+const CHARACTER_BITS: u64 = 11;
+/// Baud rate above which the frame gap is fixed
+const FIXED_GAP_BAUD: u32 = 19200;
+const FIXED_GAP: Duration = Duration::from_micros(1750);
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+/// Set in the function code of an exception response
+const EXCEPTION_FLAG: u8 = 0x80;
+/// Registers a request may read at most
+const MAX_READ_REGISTERS: u16 = 125;
+/// Registers a request may write at most
+const MAX_WRITE_REGISTERS: u16 = 123;
+
+pub const INPUT_WEIGHT_MG: u16 = 0;
+pub const INPUT_STATUS: u16 = 2;
+pub const INPUT_UNIT: u16 = 3;
+pub const INPUT_RAW_WEIGHT_MG: u16 = 4;
+const INPUT_REGISTERS: usize = 6;
+
+pub const HOLDING_COMMAND: u16 = 0;
+pub const HOLDING_TARGET_MG: u16 = 1;
+const HOLDING_REGISTERS: usize = 3;
+
+/// The weight settled
+pub const STATUS_STABLE: u16 = 1 << 0;
+/// A scale factor is stored
+pub const STATUS_CALIBRATED: u16 = 1 << 1;
+/// A calibration is running
+pub const STATUS_CALIBRATING: u16 = 1 << 2;
+
+/// Value of the command register that tares the scale
+pub const COMMAND_TARE: u16 = 1;
+
+#[cfg(feature = "modbus")]
+const MODBUS_TASK_STACK_SIZE: usize = 4 * 1024;
+/// Longest wait for a byte, bounding the time to notice the end of a frame
+#[cfg(feature = "modbus")]
+const READ_TIMEOUT_MS: u64 = 10;
+/// Silence in characters after which the UART hands the received bytes over
+#[cfg(feature = "modbus")]
+const RX_TIMEOUT_CHARACTERS: u8 = 4;
+
+/// Why a request was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+}
+
+/// What a write asks of the scale
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Tare,
+    /// Target weight in grams, none disables it
+    SetTarget(Option<f32>),
+}
+
+/// CRC-16 of a frame, sent low byte first
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// Silence that ends a frame: 3.5 characters, fixed above 19200 baud as
+/// the specification recommends
+pub fn frame_gap(baud: u32) -> Duration {
+    if baud > FIXED_GAP_BAUD {
+        FIXED_GAP
+    } else {
+        Duration::from_micros(35 * CHARACTER_BITS * 100_000 / u64::from(baud.max(1)))
+    }
+}
+
+/// Splits the bytes received into frames at the gaps between them
+pub struct FrameReceiver {
+    buffer: Vec<u8>,
+    /// When the last byte came in, none between frames
+    last_byte: Option<Instant>,
+    gap: Duration,
+    /// Whether the frame grew past `MAX_FRAME_LEN`
+    overflow: bool,
+}
+
+impl FrameReceiver {
+    pub fn new(baud: u32) -> Self {
+        Self {
+            buffer: Vec::with_capacity(MAX_FRAME_LEN),
+            last_byte: None,
+            gap: frame_gap(baud),
+            overflow: false,
+        }
+    }
+
+    /// Take the bytes received at `at`. Returns the frame a gap before them
+    /// ended.
+    pub fn push(&mut self, bytes: &[u8], at: Instant) -> Option<Vec<u8>> {
+        let frame = self.poll(at);
+        if self.buffer.len() + bytes.len() > MAX_FRAME_LEN {
+            self.overflow = true;
+        } else {
+            self.buffer.extend_from_slice(bytes);
+        }
+        self.last_byte = Some(at);
+        frame
+    }
+
+    /// The frame received so far, once the line stayed silent for the gap
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let last_byte = self.last_byte?;
+        if now.duration_since(last_byte) < self.gap {
+            return None;
+        }
+        self.last_byte = None;
+        let frame = std::mem::take(&mut self.buffer);
+        let overflow = std::mem::take(&mut self.overflow);
+        (!overflow && !frame.is_empty()).then_some(frame)
+    }
+}
+
+/// Registers of the scale as the master sees them
+#[derive(Debug, Default)]
+pub struct Registers {
+    input: [u16; INPUT_REGISTERS],
+    holding: [u16; HOLDING_REGISTERS],
+    /// Asked for by the writes since last taken
+    actions: Vec<Action>,
+}
+
+impl Registers {
+    /// Refresh the registers from the state of the scale
+    pub fn update(&mut self, snapshot: &Snapshot, target_grams: Option<f32>) {
+        let mut status = 0;
+        if snapshot.stable {
+            status |= STATUS_STABLE;
+        }
+        if snapshot.scale_factor.is_some() {
+            status |= STATUS_CALIBRATED;
+        }
+        if !matches!(
+            snapshot.calibration,
+            CalibrationStatus::Idle | CalibrationStatus::Done { .. } | CalibrationStatus::Failed(_)
+        ) {
+            status |= STATUS_CALIBRATING;
+        }
+        let [weight_high, weight_low] = split(milligrams(snapshot.grams) as u32);
+        let [raw_high, raw_low] = split(milligrams(snapshot.grams_raw) as u32);
+        self.input = [
+            weight_high,
+            weight_low,
+            status,
+            snapshot.unit.index().into(),
+            raw_high,
+            raw_low,
+        ];
+        let target = target_grams.map_or(0, |grams| milligrams(grams).max(0) as u32);
+        let [target_high, target_low] = split(target);
+        self.holding = [0, target_high, target_low];
+    }
+
+    /// Write `values` from `start`, all or none of them
+    fn write(&mut self, start: u16, values: &[u16]) -> Result<(), Exception> {
+        let start = usize::from(start);
+        let end = start + values.len();
+        if end > HOLDING_REGISTERS {
+            return Err(Exception::IllegalDataAddress);
+        }
+        let command = usize::from(HOLDING_COMMAND);
+        if (start..end).contains(&command) && values[command - start] > COMMAND_TARE {
+            return Err(Exception::IllegalDataValue);
+        }
+        self.holding[start..end].copy_from_slice(values);
+
+        if self.holding[command] == COMMAND_TARE {
+            self.actions.push(Action::Tare);
+        }
+        self.holding[command] = 0;
+        let target = usize::from(HOLDING_TARGET_MG);
+        if start < target + 2 && end > target {
+            let mg = u32::from(self.holding[target]) << 16 | u32::from(self.holding[target + 1]);
+            self.actions
+                .push(Action::SetTarget((mg > 0).then(|| mg as f32 / 1000.0)));
+        }
+        Ok(())
+    }
+
+    /// The actions asked for since last taken
+    pub fn take_actions(&mut self) -> Vec<Action> {
+        std::mem::take(&mut self.actions)
+    }
+}
+
+fn milligrams(grams: f32) -> i32 {
+    (grams * 1000.0).round() as i32
+}
+
+/// High and low word
+fn split(value: u32) -> [u16; 2] {
+    [(value >> 16) as u16, value as u16]
+}
+
+/// Carry out a request frame to `address`, returning the response frame.
+/// Frames with a bad CRC, to another slave or broadcast get none.
+pub fn handle_request(frame: &[u8], address: u8, registers: &mut Registers) -> Option<Vec<u8>> {
+    // Address, function code and CRC at least
+    if frame.len() < 4 {
+        return None;
+    }
+    let (adu, crc) = frame.split_at(frame.len() - 2);
+    if crc16(adu) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return None;
+    }
+    let unit = adu[0];
+    if unit != address && unit != BROADCAST_ADDRESS {
+        return None;
+    }
+    let function = adu[1];
+    let data = &adu[2..];
+    let result = match function {
+        READ_HOLDING_REGISTERS => read(data, &registers.holding),
+        READ_INPUT_REGISTERS => read(data, &registers.input),
+        WRITE_SINGLE_REGISTER => write_single(data, registers),
+        WRITE_MULTIPLE_REGISTERS => write_multiple(data, registers),
+        _ => Err(Exception::IllegalFunction),
+    };
+    if unit == BROADCAST_ADDRESS {
+        return None;
+    }
+
+    let mut response = vec![address];
+    match result {
+        Ok(body) => {
+            response.push(function);
+            response.extend_from_slice(&body);
+        }
+        Err(exception) => {
+            response.push(function | EXCEPTION_FLAG);
+            response.push(exception as u8);
+        }
+    }
+    let crc = crc16(&response);
+    response.extend_from_slice(&crc.to_le_bytes());
+    Some(response)
+}
+
+/// Start address and count of a request
+fn range(data: &[u8]) -> Option<(u16, u16)> {
+    match data {
+        [start_high, start_low, count_high, count_low, ..] => Some((
+            u16::from_be_bytes([*start_high, *start_low]),
+            u16::from_be_bytes([*count_high, *count_low]),
+        )),
+        _ => None,
+    }
+}
+
+fn read(data: &[u8], registers: &[u16]) -> Result<Vec<u8>, Exception> {
+    let (start, count) = range(data)
+        .filter(|&(_, count)| data.len() == 4 && (1..=MAX_READ_REGISTERS).contains(&count))
+        .ok_or(Exception::IllegalDataValue)?;
+    let start = usize::from(start);
+    let values = registers
+        .get(start..start + usize::from(count))
+        .ok_or(Exception::IllegalDataAddress)?;
+    let mut body = vec![(count * 2) as u8];
+    for value in values {
+        body.extend_from_slice(&value.to_be_bytes());
+    }
+    Ok(body)
+}
+
+/// The response echoes the request
+fn write_single(data: &[u8], registers: &mut Registers) -> Result<Vec<u8>, Exception> {
+    let (address, value) = range(data)
+        .filter(|_| data.len() == 4)
+        .ok_or(Exception::IllegalDataValue)?;
+    registers.write(address, &[value])?;
+    Ok(data.to_vec())
+}
+
+/// The response carries the start address and the count
+fn write_multiple(data: &[u8], registers: &mut Registers) -> Result<Vec<u8>, Exception> {
+    let (start, count) = range(data)
+        .filter(|&(_, count)| (1..=MAX_WRITE_REGISTERS).contains(&count))
+        .ok_or(Exception::IllegalDataValue)?;
+    let bytes = &data[4..];
+    if bytes.first().map(|&len| usize::from(len)) != Some(usize::from(count) * 2)
+        || bytes.len() != 1 + usize::from(count) * 2
+    {
+        return Err(Exception::IllegalDataValue);
+    }
+    let values: Vec<u16> = bytes[1..]
+        .chunks_exact(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+        .collect();
+    registers.write(start, &values)?;
+    Ok(data[..4].to_vec())
+}
+
+/// Start the slave on the UART and the pins of the settings. The target
+/// weight is read from the feedback dispatcher, the writes are sent as
+/// commands to the main loop.
+#[cfg(feature = "modbus")]
+pub fn start_modbus_task<UART: Uart>(
+    uart: impl Peripheral<P = UART> + 'static,
+    settings: &Settings,
+    snapshot: SharedSnapshot,
+    feedback: FeedbackDispatcher,
+    commands: CommandSender,
+) -> anyhow::Result<()> {
+    let pins = settings.modbus_pins();
+    let address = settings.modbus_address();
+    let baud = settings.modbus_baud();
+    let tx = unsafe { AnyOutputPin::new(pins.tx.into()) };
+    let rx = unsafe { AnyInputPin::new(pins.rx.into()) };
+    // The transceiver is driven through RTS while sending
+    let de = pins.de.map(|pin| unsafe { AnyOutputPin::new(pin.into()) });
+    let config = Config::new().baudrate(Hertz(baud)).parity_even();
+    let uart = UartDriver::new(uart, tx, rx, Option::<AnyIOPin>::None, de, &config)?;
+    if pins.de.is_some() {
+        esp!(unsafe { uart_set_mode(uart.port(), uart_mode_t_UART_MODE_RS485_HALF_DUPLEX) })?;
+    }
+    esp!(unsafe { uart_set_rx_timeout(uart.port(), RX_TIMEOUT_CHARACTERS) })?;
+
+    std::thread::Builder::new()
+        .name("modbus".to_string())
+        .stack_size(MODBUS_TASK_STACK_SIZE)
+        .spawn(move || {
+            let watchdog = WatchdogGuard::subscribe("modbus");
+            let timeout = TickType::new_millis(READ_TIMEOUT_MS).ticks();
+            let mut receiver = FrameReceiver::new(baud);
+            let mut registers = Registers::default();
+            let mut buf = [0u8; MAX_FRAME_LEN];
+            loop {
+                watchdog.feed();
+                // Wait for the first byte, then take whatever else arrived
+                let received = match uart.read(&mut buf[..1], timeout) {
+                    Ok(0) | Err(_) => 0,
+                    Ok(_) => 1 + uart.read(&mut buf[1..], NON_BLOCK).unwrap_or(0),
+                };
+                let now = Instant::now();
+                let frame = match received {
+                    0 => receiver.poll(now),
+                    len => receiver.push(&buf[..len], now),
+                };
+                let Some(frame) = frame else {
+                    continue;
+                };
+
+                registers.update(&snapshot.get(), feedback.target());
+                if let Some(response) = handle_request(&frame, address, &mut registers) {
+                    if let Err(err) = uart.write(&response) {
+                        warn!("Failed to send a Modbus response: {:?}", err);
+                    }
+                }
+                for action in registers.take_actions() {
+                    let command = match action {
+                        Action::Tare => Command::Tare,
+                        Action::SetTarget(grams) => Command::SetTarget(grams),
+                    };
+                    if commands.send(command).is_err() {
+                        warn!("Modbus write dropped, the command channel is closed");
+                    }
+                }
+            }
+        })?;
+    info!("Modbus RTU slave {} at {} baud", address, baud);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: u8 = 1;
+
+    /// The ADU with its CRC
+    fn frame(adu: &[u8]) -> Vec<u8> {
+        let mut frame = adu.to_vec();
+        frame.extend_from_slice(&crc16(adu).to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn gap_is_three_and_a_half_characters() {
+        // 38.5 bits at 9600 and 19200 baud
+        assert_eq!(frame_gap(9600), Duration::from_micros(4010));
+        assert_eq!(frame_gap(19200), Duration::from_micros(2005));
+        // Fixed above 19200 baud
+        assert_eq!(frame_gap(38400), Duration::from_micros(1750));
+        assert_eq!(frame_gap(115_200), Duration::from_micros(1750));
+    }
+
+    #[test]
+    fn splits_frames_on_the_gap() {
+        let read = frame(&[ADDRESS, READ_INPUT_REGISTERS, 0x00, 0x00, 0x00, 0x02]);
+        let write = frame(&[ADDRESS, WRITE_SINGLE_REGISTER, 0x00, 0x00, 0x00, 0x01]);
+        let mut receiver = FrameReceiver::new(9600);
+        let start = Instant::now();
+        let at = |micros| start + Duration::from_micros(micros);
+
+        // The read in chunks a character apart
+        assert_eq!(receiver.push(&read[..3], at(0)), None);
+        assert_eq!(receiver.push(&read[3..], at(1150)), None);
+        // Silent for less than the gap
+        assert_eq!(receiver.poll(at(1150 + 4009)), None);
+        // The write right after the gap ends the read
+        assert_eq!(receiver.push(&write, at(1150 + 4010)), Some(read));
+        assert_eq!(receiver.poll(at(1150 + 4010 + 4010)), Some(write));
+        // Nothing more until bytes come in
+        assert_eq!(receiver.poll(at(20_000)), None);
+    }
+
+    #[test]
+    fn back_to_back_bytes_stay_one_frame() {
+        let read = frame(&[ADDRESS, READ_HOLDING_REGISTERS, 0x00, 0x00, 0x00, 0x03]);
+        let mut receiver = FrameReceiver::new(115_200);
+        let start = Instant::now();
+        for (i, byte) in read.iter().enumerate() {
+            // A character at 115200 baud, well within the gap
+            let at = start + Duration::from_micros(100 * i as u64);
+            assert_eq!(receiver.push(&[*byte], at), None);
+        }
+        let last = start + Duration::from_micros(100 * (read.len() as u64 - 1));
+        assert_eq!(receiver.poll(last + Duration::from_micros(1749)), None);
+        assert_eq!(
+            receiver.poll(last + Duration::from_micros(1750)),
+            Some(read)
+        );
+    }
+
+    #[test]
+    fn drops_an_overflowed_frame() {
+        let mut receiver = FrameReceiver::new(9600);
+        let start = Instant::now();
+        let gap = frame_gap(9600);
+        assert_eq!(receiver.push(&[0; MAX_FRAME_LEN], start), None);
+        // One byte too many spoils the whole frame
+        assert_eq!(receiver.push(&[0], start), None);
+        assert_eq!(receiver.poll(start + gap), None);
+
+        // The next frame comes through
+        let read = frame(&[ADDRESS, READ_INPUT_REGISTERS, 0x00, 0x00, 0x00, 0x01]);
+        let next = start + gap * 2;
+        assert_eq!(receiver.push(&read, next), None);
+        assert_eq!(receiver.poll(next + gap), Some(read));
+    }
+
+    #[test]
+    fn keeps_a_frame_of_the_largest_size() {
+        let mut receiver = FrameReceiver::new(9600);
+        let start = Instant::now();
+        assert_eq!(receiver.push(&[0; MAX_FRAME_LEN - 1], start), None);
+        assert_eq!(receiver.push(&[1], start), None);
+        let frame = receiver.poll(start + frame_gap(9600)).unwrap();
+        assert_eq!(frame.len(), MAX_FRAME_LEN);
+        assert_eq!(frame.last(), Some(&1));
+    }
+
+    #[test]
+    fn crc16_of_a_read() {
+        assert_eq!(
+            frame(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]),
+            [0x01, 0x03, 0x00, 0x00, 0x00, 0x01, 0x84, 0x0A]
+        );
+    }
+
+    #[test]
+    fn reads_and_writes() {
+        let mut registers = Registers::default();
+        let request = frame(&[ADDRESS, READ_HOLDING_REGISTERS, 0x00, 0x00, 0x00, 0x03]);
+        assert_eq!(
+            handle_request(&request, ADDRESS, &mut registers),
+            Some(frame(&[ADDRESS, 0x03, 0x06, 0, 0, 0, 0, 0, 0]))
+        );
+
+        // Tare along with a target of 70000mg
+        let request = frame(&[
+            ADDRESS,
+            WRITE_MULTIPLE_REGISTERS,
+            0x00,
+            0x00,
+            0x00,
+            0x03,
+            0x06,
+            0x00,
+            0x01,
+            0x00,
+            0x01,
+            0x11,
+            0x70,
+        ]);
+        assert_eq!(
+            handle_request(&request, ADDRESS, &mut registers),
+            Some(frame(&[ADDRESS, 0x10, 0x00, 0x00, 0x00, 0x03]))
+        );
+        assert_eq!(
+            registers.take_actions(),
+            [Action::Tare, Action::SetTarget(Some(70.0))]
+        );
+    }
+
+    #[test]
+    fn illegal_function() {
+        let mut registers = Registers::default();
+        let request = frame(&[ADDRESS, 0x07]);
+        assert_eq!(
+            handle_request(&request, ADDRESS, &mut registers),
+            Some(frame(&[ADDRESS, 0x87, Exception::IllegalFunction as u8]))
+        );
+    }
+
+    #[test]
+    fn illegal_address() {
+        let mut registers = Registers::default();
+        // Past the last input register
+        let request = frame(&[ADDRESS, READ_INPUT_REGISTERS, 0x00, 0x04, 0x00, 0x03]);
+        assert_eq!(
+            handle_request(&request, ADDRESS, &mut registers),
+            Some(frame(&[ADDRESS, 0x84, Exception::IllegalDataAddress as u8]))
+        );
+        let request = frame(&[ADDRESS, WRITE_SINGLE_REGISTER, 0x00, 0x03, 0x00, 0x01]);
+        assert_eq!(
+            handle_request(&request, ADDRESS, &mut registers),
+            Some(frame(&[ADDRESS, 0x86, Exception::IllegalDataAddress as u8]))
+        );
+        assert!(registers.take_actions().is_empty());
+    }
+
+    #[test]
+    fn drops_wrong_crc() {
+        let mut registers = Registers::default();
+        let mut request = frame(&[ADDRESS, WRITE_SINGLE_REGISTER, 0x00, 0x00, 0x00, 0x01]);
+        request[7] ^= 0x01;
+        assert_eq!(handle_request(&request, ADDRESS, &mut registers), None);
+        assert!(registers.take_actions().is_empty());
+        // Nor is another slave answered
+        let request = frame(&[ADDRESS + 1, READ_INPUT_REGISTERS, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(handle_request(&request, ADDRESS, &mut registers), None);
+    }
+}
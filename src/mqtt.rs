@@ -0,0 +1,603 @@
+pub mod homeassistant;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+use esp_idf_sys::EspError;
+use log::{info, warn};
+use serde_json::json;
+
+use crate::{
+    alarms::AlarmEvent,
+    device,
+    diagnostics::DiagSnapshot,
+    events::WeightEvent,
+    format::{format_weight, milligrams, FormatOpts},
+    governor::{self, Subsystem},
+    quality::{Quality, Reading},
+    settings::Settings,
+    shutdown,
+    time::Timestamp,
+    unit::Unit,
+    volume::Volume,
+};
+
+const AVAILABILITY_ONLINE: &str = "online";
+const AVAILABILITY_OFFLINE: &str = "offline";
+
+const MQTT_TASK_STACK_SIZE: usize = 6 * 1024;
+const CONNECTION_TASK_STACK_SIZE: usize = 4 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Period the publishing task checks the connection at while no events arrive
+const EVENT_POLL_PERIOD: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+const BATTERY_PUBLISH_PERIOD: Duration = Duration::from_secs(60);
+/// Longest wait for the scale to be published offline before a restart
+const OFFLINE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Name of the sink passing the weight changes on to the task
+pub const MQTT_SINK: &str = "mqtt";
+/// Shortest time between two weight changes sent to the task, which only
+/// publishes the stable weight
+pub const CHANGE_MIN_INTERVAL: Duration = Duration::from_secs(5);
+/// Heap the outbox grows to while the broker lags
+const BUFFERING_HEAP_NEED: u32 = 8 * 1024;
+
+/// Broker and publishing settings, copied out of the settings blob
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    broker_url: String,
+    username: String,
+    password: String,
+    topic_prefix: String,
+    interval: Option<Duration>,
+    min_delta_grams: f32,
+    unit: Unit,
+    battery_voltage: bool,
+    diag_interval: Option<Duration>,
+}
+
+impl MqttConfig {
+    /// Returns `None` when no broker is configured
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if settings.mqtt_broker_url().is_empty() {
+            return None;
+        }
+        Some(Self {
+            broker_url: settings.mqtt_broker_url().to_string(),
+            username: settings.mqtt_username().to_string(),
+            password: settings.mqtt_password().to_string(),
+            topic_prefix: settings.mqtt_topic_prefix().to_string(),
+            interval: settings.mqtt_interval(),
+            min_delta_grams: settings.mqtt_min_delta_grams(),
+            unit: settings.unit(),
+            battery_voltage: false,
+            diag_interval: settings.mqtt_diag_interval(),
+        })
+    }
+
+    /// Smallest change of the weight worth publishing
+    pub fn min_delta_grams(&self) -> f32 {
+        self.min_delta_grams
+    }
+
+    /// Announce a battery voltage sensor to Home Assistant
+    pub fn with_battery_voltage(mut self) -> Self {
+        self.battery_voltage = true;
+        self
+    }
+
+    pub fn availability_topic(&self) -> String {
+        format!("{}/availability", self.topic_prefix)
+    }
+
+    /// Stable weight in the active unit, as JSON with a `weight` field plus
+    /// either the UTC `time` or, before the clock is synchronized, `uptime_ms`
+    pub fn weight_topic(&self) -> String {
+        format!("{}/weight", self.topic_prefix)
+    }
+
+    /// `ON` while the weight is stable, `OFF` otherwise
+    pub fn stable_topic(&self) -> String {
+        format!("{}/stable", self.topic_prefix)
+    }
+
+    /// Pack voltage in volts, with `with_battery_voltage`
+    pub fn battery_topic(&self) -> String {
+        format!("{}/battery", self.topic_prefix)
+    }
+
+    /// Heap and stack figures as JSON, when a diagnostics interval is set
+    pub fn diag_topic(&self) -> String {
+        format!("{}/diag", self.topic_prefix)
+    }
+
+    /// Alarm events as JSON, with the alarm number, the event and the
+    /// threshold
+    pub fn alarm_topic(&self) -> String {
+        format!("{}/alarm", self.topic_prefix)
+    }
+}
+
+/// Requests handled by the publishing task
+enum MqttControl {
+    Decommission,
+    Alarm(AlarmEvent),
+    /// Publish the scale offline and stop, answering once it went out
+    Shutdown(Sender<()>),
+}
+
+/// Handle to the running publishing task
+#[derive(Clone)]
+pub struct MqttHandle {
+    control: Sender<MqttControl>,
+}
+
+impl MqttHandle {
+    /// Remove the scale from Home Assistant. Discovery stays off until the
+    /// next restart.
+    pub fn decommission(&self) {
+        let _ = self.control.send(MqttControl::Decommission);
+    }
+
+    /// Publish an alarm event, held until the broker is reachable
+    pub fn publish_alarm(&self, event: AlarmEvent) {
+        let _ = self.control.send(MqttControl::Alarm(event));
+    }
+
+    /// Publish the scale offline and stop publishing, before a restart.
+    /// Without a connection the last will tells it once the broker notices.
+    pub fn go_offline(&self) {
+        let (done_tx, done_rx) = channel();
+        if self.control.send(MqttControl::Shutdown(done_tx)).is_ok() {
+            let _ = done_rx.recv_timeout(OFFLINE_TIMEOUT);
+        }
+    }
+}
+
+/// State of the publishing task that outlives a broker connection
+struct TaskState {
+    policy: PublishPolicy,
+    unit: Unit,
+    /// Stability last published, `None` while it has to be (re)published
+    stable_published: Option<bool>,
+    discovery: bool,
+    decommission_pending: bool,
+    battery_published: Option<Instant>,
+    diag_published: Option<Instant>,
+    /// Alarm events waiting for a connection
+    alarms_pending: Vec<AlarmEvent>,
+    /// Whether the scale was published offline for a restart, which ends
+    /// the task
+    shut_down: bool,
+}
+
+/// Decides when the stable weight is worth publishing: right after
+/// connecting, when it moved by the minimum delta, or when the republish
+/// interval elapsed
+struct PublishPolicy {
+    interval: Option<Duration>,
+    min_delta_grams: f32,
+    stable_grams: Option<f32>,
+    stable: bool,
+    last_published: Option<(f32, Instant)>,
+}
+
+impl PublishPolicy {
+    fn new(config: &MqttConfig) -> Self {
+        Self {
+            interval: config.interval,
+            min_delta_grams: config.min_delta_grams,
+            stable_grams: None,
+            stable: false,
+            last_published: None,
+        }
+    }
+
+    fn on_event(&mut self, event: WeightEvent) {
+        match event {
+            WeightEvent::Changed { grams, stable } => {
+                self.stable = stable;
+                if stable {
+                    self.stable_grams = Some(grams);
+                }
+            }
+            WeightEvent::Stable { grams } => {
+                self.stable = true;
+                self.stable_grams = Some(grams);
+            }
+            WeightEvent::Tared | WeightEvent::Calibrated { .. } => {
+                self.stable = false;
+                self.stable_grams = None;
+            }
+            // The weight is republished in the new unit
+            WeightEvent::UnitChanged(_) => self.last_published = None,
+        }
+    }
+
+    fn due(&self, now: Instant) -> Option<f32> {
+        let grams = self.stable_grams?;
+        let Some((last_grams, at)) = self.last_published else {
+            return Some(grams);
+        };
+        let moved = (grams - last_grams).abs() >= self.min_delta_grams;
+        let expired = self
+            .interval
+            .is_some_and(|interval| now.duration_since(at) >= interval);
+        (moved || expired).then_some(grams)
+    }
+
+    fn published(&mut self, grams: f32, now: Instant) {
+        self.last_published = Some((grams, now));
+    }
+}
+
+/// Weight along with the age and the quality of the reading it comes from,
+/// the age grows once the sensor stops converting. The volume shown on the
+/// display goes along with the substance.
+fn weight_payload(
+    grams: f32,
+    unit: Unit,
+    reading: Option<Reading>,
+    volume: Option<Volume>,
+) -> String {
+    let weight = format_weight(milligrams(grams), unit, &FormatOpts::default());
+    let age_ms = reading.map_or(0, |reading| reading.age.as_millis());
+    let quality = reading.map_or(Quality::Stale, |reading| reading.quality);
+    let timestamp = Timestamp::now();
+    let stamp = device::stamp();
+    let time = if timestamp.is_wall_clock() {
+        format!(r#""time":"{}""#, timestamp)
+    } else {
+        format!(r#""uptime_ms":{}"#, timestamp)
+    };
+    let volume = volume.map_or(String::new(), |volume| {
+        format!(
+            r#","substance":"{}","ml":{:.1}"#,
+            volume.substance.name(),
+            volume.ml(grams)
+        )
+    });
+    format!(
+        r#"{{"weight":{},{},"age_ms":{},"quality":"{}"{},"device_id":"{}","boot":{},"seq":{}}}"#,
+        weight,
+        time,
+        age_ms,
+        quality.name(),
+        volume,
+        stamp.device_id,
+        stamp.boot,
+        stamp.seq
+    )
+}
+
+fn format_diag(diag: &DiagSnapshot) -> String {
+    let stacks: serde_json::Map<String, serde_json::Value> = diag
+        .tasks
+        .iter()
+        .map(|task| (task.name.clone(), json!(task.free_min)))
+        .collect();
+    let mut payload = json!({
+        "uptime_s": diag.uptime.as_secs(),
+        "free_heap": diag.free_heap,
+        "min_free_heap": diag.min_free_heap,
+        "largest_free_block": diag.largest_free_block,
+        "quiesced_samples": diag.quiesced_samples,
+        "fps": diag.fps,
+        "skipped_frames": diag.skipped_frames,
+        "stack_free": stacks,
+    });
+    if !diag.suppressed.is_empty() {
+        let suppressed: serde_json::Map<String, serde_json::Value> = diag
+            .suppressed
+            .iter()
+            .map(|(subsystem, suppression)| {
+                let reason = json!({
+                    "reason": suppression.name(),
+                    "free_heap": suppression.free_heap(),
+                });
+                (subsystem.name().to_string(), reason)
+            })
+            .collect();
+        payload["suppressed"] = json!(suppressed);
+    }
+    device::stamp().insert_into(&mut payload);
+    payload.to_string()
+}
+
+fn alarm_payload(event: &AlarmEvent) -> String {
+    let config = event.config();
+    let mut payload = json!({
+        "alarm": event.slot() + 1,
+        "event": event.name(),
+        "condition": config.kind.name(),
+        "threshold_grams": config.grams,
+    });
+    if let AlarmEvent::Tripped { grams, .. } = event {
+        payload["weight_grams"] = json!(grams);
+    }
+    let timestamp = Timestamp::now();
+    if timestamp.is_wall_clock() {
+        payload["time"] = json!(timestamp.to_string());
+    }
+    device::stamp().insert_into(&mut payload);
+    payload.to_string()
+}
+
+fn format_stable(stable: bool) -> &'static str {
+    if stable {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+/// Start publishing the weight events to the broker. The task owns the
+/// connection, so a slow or missing broker never holds up weighing.
+pub fn start_mqtt_task(
+    config: MqttConfig,
+    events: Receiver<WeightEvent>,
+    snapshot: SharedSnapshot,
+) -> anyhow::Result<MqttHandle> {
+    let (control_tx, control_rx) = channel();
+    let buffering = Arc::new(AtomicBool::new(true));
+    let stopped = buffering.clone();
+    let admitted = governor::admit(Subsystem::MqttBuffering, BUFFERING_HEAP_NEED, move || {
+        stopped.store(false, Ordering::Relaxed)
+    });
+    buffering.store(admitted, Ordering::Relaxed);
+    std::thread::Builder::new()
+        .name("mqtt".to_string())
+        .stack_size(MQTT_TASK_STACK_SIZE)
+        .spawn(move || mqtt_task(config, events, snapshot, control_rx, buffering))?;
+    let handle = MqttHandle {
+        control: control_tx,
+    };
+    let shutdown_handle = handle.clone();
+    shutdown::register("mqtt", move || shutdown_handle.go_offline());
+    Ok(handle)
+}
+
+fn mqtt_task(
+    config: MqttConfig,
+    events: Receiver<WeightEvent>,
+    snapshot: SharedSnapshot,
+    control: Receiver<MqttControl>,
+    buffering: Arc<AtomicBool>,
+) {
+    let mut state = TaskState {
+        policy: PublishPolicy::new(&config),
+        unit: config.unit,
+        stable_published: None,
+        discovery: true,
+        decommission_pending: false,
+        battery_published: None,
+        diag_published: None,
+        alarms_pending: Vec::new(),
+        shut_down: false,
+    };
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    loop {
+        match run_session(
+            &config, &events, &snapshot, &control, &buffering, &mut state,
+        ) {
+            // The broker was reached, so start over with a short backoff
+            Ok(()) => backoff = RECONNECT_BACKOFF_MIN,
+            Err(err) => warn!("MQTT session failed: {:?}", err),
+        }
+        if state.shut_down {
+            info!("MQTT publishing stopped for the shutdown");
+            return;
+        }
+        info!("Reconnecting to MQTT broker in {}s", backoff.as_secs());
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+/// Connect to the broker and publish until the connection is lost
+fn run_session(
+    config: &MqttConfig,
+    events: &Receiver<WeightEvent>,
+    snapshot: &SharedSnapshot,
+    control: &Receiver<MqttControl>,
+    buffering: &AtomicBool,
+    state: &mut TaskState,
+) -> anyhow::Result<()> {
+    let availability_topic = config.availability_topic();
+    let weight_topic = config.weight_topic();
+    let stable_topic = config.stable_topic();
+
+    let (mut client, mut connection) = EspMqttClient::new(
+        &config.broker_url,
+        &MqttClientConfiguration {
+            username: (!config.username.is_empty()).then_some(config.username.as_str()),
+            password: (!config.password.is_empty()).then_some(config.password.as_str()),
+            lwt: Some(LwtConfiguration {
+                topic: &availability_topic,
+                payload: AVAILABILITY_OFFLINE.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            ..Default::default()
+        },
+    )?;
+
+    // The connection has to be polled for the client to make progress, it
+    // stops once the client is dropped
+    let (connected_tx, connected_rx) = channel();
+    std::thread::Builder::new()
+        .name("mqtt_conn".to_string())
+        .stack_size(CONNECTION_TASK_STACK_SIZE)
+        .spawn(move || {
+            while let Ok(event) = connection.next() {
+                let connected = match event.payload() {
+                    EventPayload::Connected(_) => true,
+                    EventPayload::Disconnected => false,
+                    EventPayload::Error(err) => {
+                        warn!("MQTT error: {:?}", err);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if connected_tx.send(connected).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+    if connected_rx.recv_timeout(CONNECT_TIMEOUT) != Ok(true) {
+        return Err(anyhow!("Could not connect to {}", config.broker_url));
+    }
+    info!("Connected to MQTT broker {}", config.broker_url);
+
+    client.enqueue(
+        &availability_topic,
+        QoS::AtLeastOnce,
+        true,
+        AVAILABILITY_ONLINE.as_bytes(),
+    )?;
+    if state.discovery {
+        homeassistant::publish_discovery(&mut client, config, state.unit)?;
+    }
+    state.policy.last_published = None;
+    state.stable_published = None;
+    state.battery_published = None;
+    state.diag_published = None;
+
+    loop {
+        match connected_rx.try_recv() {
+            Ok(false) | Err(TryRecvError::Disconnected) => {
+                warn!("Lost connection to MQTT broker");
+                return Ok(());
+            }
+            Ok(true) | Err(TryRecvError::Empty) => {}
+        }
+
+        while let Ok(request) = control.try_recv() {
+            match request {
+                MqttControl::Decommission => {
+                    state.discovery = false;
+                    state.decommission_pending = true;
+                }
+                MqttControl::Alarm(event) => state.alarms_pending.push(event),
+                MqttControl::Shutdown(done) => {
+                    // Offline right away rather than once the broker
+                    // notices, the client disconnects once dropped
+                    client.publish(
+                        &availability_topic,
+                        QoS::AtLeastOnce,
+                        true,
+                        AVAILABILITY_OFFLINE.as_bytes(),
+                    )?;
+                    state.shut_down = true;
+                    let _ = done.send(());
+                    return Ok(());
+                }
+            }
+        }
+        for event in &state.alarms_pending {
+            client.enqueue(
+                &config.alarm_topic(),
+                QoS::AtLeastOnce,
+                false,
+                alarm_payload(event).as_bytes(),
+            )?;
+        }
+        state.alarms_pending.clear();
+        if state.decommission_pending {
+            homeassistant::remove_discovery(&mut client, config)?;
+            state.decommission_pending = false;
+            info!("Removed the Home Assistant discovery config");
+        }
+
+        match events.recv_timeout(EVENT_POLL_PERIOD) {
+            Ok(event) => {
+                if let WeightEvent::UnitChanged(unit) = event {
+                    state.unit = unit;
+                    if state.discovery {
+                        homeassistant::publish_discovery(&mut client, config, unit)?;
+                    }
+                }
+                state.policy.on_event(event);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("Weight events are no longer published"))
+            }
+        }
+
+        let now = Instant::now();
+        if let Some(grams) = state.policy.due(now) {
+            let current = snapshot.get();
+            let payload = weight_payload(grams, state.unit, current.reading(), current.volume);
+            publish_reading(&mut client, buffering, &weight_topic, payload.as_bytes())?;
+            state.policy.published(grams, now);
+        }
+
+        let stable = state.policy.stable;
+        if state.stable_published != Some(stable) {
+            publish_reading(
+                &mut client,
+                buffering,
+                &stable_topic,
+                format_stable(stable).as_bytes(),
+            )?;
+            state.stable_published = Some(stable);
+        }
+
+        let battery_due = state
+            .battery_published
+            .map_or(true, |at| now.duration_since(at) >= BATTERY_PUBLISH_PERIOD);
+        if config.battery_voltage && battery_due {
+            if let Some(voltage) = snapshot.get().battery_voltage {
+                publish_reading(
+                    &mut client,
+                    buffering,
+                    &config.battery_topic(),
+                    format!("{:.2}", voltage).as_bytes(),
+                )?;
+                state.battery_published = Some(now);
+            }
+        }
+
+        if let Some(interval) = config.diag_interval {
+            let diag_due = state
+                .diag_published
+                .map_or(true, |at| now.duration_since(at) >= interval);
+            if diag_due {
+                publish_reading(
+                    &mut client,
+                    buffering,
+                    &config.diag_topic(),
+                    format_diag(&DiagSnapshot::collect()).as_bytes(),
+                )?;
+                state.diag_published = Some(now);
+            }
+        }
+    }
+}
+
+/// Queue a reading in the outbox, or send it right away once the governor
+/// stopped the buffering. Either way it may be lost, the next one follows.
+fn publish_reading(
+    client: &mut EspMqttClient<'_>,
+    buffering: &AtomicBool,
+    topic: &str,
+    payload: &[u8],
+) -> Result<u32, EspError> {
+    match buffering.load(Ordering::Relaxed) {
+        true => client.enqueue(topic, QoS::AtMostOnce, false, payload),
+        false => client.publish(topic, QoS::AtMostOnce, false, payload),
+    }
+}
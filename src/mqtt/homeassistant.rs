@@ -0,0 +1,105 @@
+use esp_idf_svc::mqtt::client::{EspMqttClient, QoS};
+use esp_idf_sys::EspError;
+use serde_json::{json, Value};
+
+use super::MqttConfig;
+use crate::{device, unit::Unit};
+
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const DEVICE_NAME: &str = "ESP32 Scale";
+
+/// Identifier of this scale, derived from the factory MAC address
+pub fn device_id() -> String {
+    device::identity().device_id().to_string()
+}
+
+fn config_topic(component: &str, device_id: &str, object_id: &str) -> String {
+    format!(
+        "{}/{}/{}/{}/config",
+        DISCOVERY_PREFIX, component, device_id, object_id
+    )
+}
+
+/// Config topics and payloads of the entities the scale provides
+fn entities(config: &MqttConfig, unit: Unit) -> Vec<(String, Value)> {
+    let device_id = device_id();
+    let device = json!({
+        "identifiers": [device_id],
+        "name": DEVICE_NAME,
+        "model": "HX711 load cell scale",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let availability_topic = config.availability_topic();
+
+    let mut entities = vec![
+        (
+            config_topic("sensor", &device_id, "weight"),
+            json!({
+                "name": "Weight",
+                "unique_id": format!("{}_weight", device_id),
+                "device_class": "weight",
+                "state_class": "measurement",
+                "unit_of_measurement": unit.symbol(),
+                "suggested_display_precision": unit.decimals(),
+                "state_topic": config.weight_topic(),
+                "value_template": "{{ value_json.weight }}",
+                "availability_topic": availability_topic,
+                "device": device,
+            }),
+        ),
+        (
+            config_topic("binary_sensor", &device_id, "stable"),
+            json!({
+                "name": "Stable",
+                "unique_id": format!("{}_stable", device_id),
+                "state_topic": config.stable_topic(),
+                "availability_topic": availability_topic,
+                "device": device,
+            }),
+        ),
+    ];
+    if config.battery_voltage {
+        entities.push((
+            config_topic("sensor", &device_id, "battery"),
+            json!({
+                "name": "Battery",
+                "unique_id": format!("{}_battery", device_id),
+                "device_class": "voltage",
+                "state_class": "measurement",
+                "unit_of_measurement": "V",
+                "state_topic": config.battery_topic(),
+                "availability_topic": availability_topic,
+                "device": device,
+            }),
+        ));
+    }
+    entities
+}
+
+/// Publish the retained discovery configs, with the weight in `unit`
+pub fn publish_discovery(
+    client: &mut EspMqttClient,
+    config: &MqttConfig,
+    unit: Unit,
+) -> Result<(), EspError> {
+    for (topic, payload) in entities(config, unit) {
+        client.enqueue(
+            &topic,
+            QoS::AtLeastOnce,
+            true,
+            payload.to_string().as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Clear the retained discovery configs, which removes the device from Home
+/// Assistant
+pub fn remove_discovery(client: &mut EspMqttClient, config: &MqttConfig) -> Result<(), EspError> {
+    // Also clear the optional entities in case they were announced before
+    let config = config.clone().with_battery_voltage();
+    for (topic, _) in entities(&config, Unit::default()) {
+        client.enqueue(&topic, QoS::AtLeastOnce, true, &[])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,284 @@
+//! NAU7802 load cell ADC on the I2C bus shared with the display, for the
+//! boards that have it instead of the HX711. It is set up for the same 10
+//! samples per second, so the filter behaves as it does with the HX711, and
+//! its analog front end is calibrated at every start.
+
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::delay::{FreeRtos, TickType};
+use esp_idf_sys::EspError;
+use log::{debug, info};
+use thiserror::Error;
+
+use crate::{
+    i2c_bus::SharedI2c,
+    sensor::{LoadSensor, SensorError, NAU7802_GAINS},
+};
+
+/// Fixed address of the NAU7802
+pub const NAU7802_ADDRESS: u8 = 0x2A;
+
+const I2C_TIMEOUT_MS: u64 = 10;
+/// Time the analog and digital parts get to report powered up
+const POWER_UP_TIMEOUT: Duration = Duration::from_millis(100);
+/// Time an AFE calibration gets, a few conversions at 10 samples per second
+const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_PERIOD_MS: u32 = 5;
+
+const REG_PU_CTRL: u8 = 0x00;
+const REG_CTRL1: u8 = 0x01;
+const REG_CTRL2: u8 = 0x02;
+/// First of the three bytes of the offset calibration, most significant
+/// first
+const REG_OCAL1_B2: u8 = 0x03;
+/// First of the four bytes of the gain calibration, most significant first
+const REG_GCAL1_B3: u8 = 0x06;
+/// First of the three bytes of the conversion, most significant first
+const REG_ADCO_B2: u8 = 0x12;
+const REG_ADC: u8 = 0x15;
+const REG_POWER: u8 = 0x1C;
+const REG_DEVICE_REV: u8 = 0x1F;
+
+const PU_CTRL_RR: u8 = 1 << 0;
+const PU_CTRL_PUD: u8 = 1 << 1;
+const PU_CTRL_PUA: u8 = 1 << 2;
+const PU_CTRL_PUR: u8 = 1 << 3;
+const PU_CTRL_CS: u8 = 1 << 4;
+const PU_CTRL_CR: u8 = 1 << 5;
+const PU_CTRL_AVDDS: u8 = 1 << 7;
+
+const CTRL1_GAINS_MASK: u8 = 0b0000_0111;
+const CTRL1_VLDO_MASK: u8 = 0b0011_1000;
+const CTRL1_VLDO_SHIFT: u8 = 3;
+/// 3.0V, leaving the LDO the headroom it needs below the 3.3V supply
+const VLDO_3V0: u8 = 0b101;
+
+const CTRL2_CALMOD_MASK: u8 = 0b0000_0011;
+const CTRL2_CALS: u8 = 1 << 2;
+const CTRL2_CAL_ERR: u8 = 1 << 3;
+const CTRL2_CRS_MASK: u8 = 0b0111_0000;
+const CTRL2_CRS_SHIFT: u8 = 4;
+const CRS_10SPS: u8 = 0b000;
+
+/// Turns the chopper clock off, as the datasheet asks for
+const ADC_CHPS_OFF: u8 = 0b0011_0000;
+/// Decoupling capacitor on the unused second channel, lowering the noise
+const POWER_PGA_CAP_EN: u8 = 1 << 7;
+/// Revision ID in the low nibble of the revision register
+const DEVICE_REV_ID_MASK: u8 = 0x0F;
+const DEVICE_REV_ID: u8 = 0x0F;
+
+#[derive(Error, Debug)]
+pub enum Nau7802Error {
+    #[error("I2C error: {0}")]
+    Bus(#[from] EspError),
+    #[error("Unexpected revision 0x{0:02X}, not a NAU7802")]
+    Revision(u8),
+    #[error("Did not power up")]
+    PowerUp,
+    #[error("Invalid gain {0}")]
+    Gain(u8),
+    #[error("AFE calibration failed")]
+    Calibration,
+}
+
+/// Calibrations the analog front end can run, the external ones with the
+/// inputs as they are wired
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AfeCalibration {
+    /// Offset of the ADC with its inputs shorted internally
+    InternalOffset,
+    /// Offset with the inputs at zero, e.g. the empty load cell
+    SystemOffset,
+    /// Gain with the inputs at full scale
+    SystemGain,
+}
+
+impl AfeCalibration {
+    fn calmod(self) -> u8 {
+        match self {
+            AfeCalibration::InternalOffset => 0b00,
+            AfeCalibration::SystemOffset => 0b10,
+            AfeCalibration::SystemGain => 0b11,
+        }
+    }
+}
+
+pub struct Nau7802 {
+    bus: SharedI2c,
+    gain: u8,
+}
+
+impl Nau7802 {
+    /// Reset and power up the chip, set the LDO, the gain and the rate, and
+    /// calibrate its offset before starting the conversions
+    pub fn start(bus: SharedI2c, gain: u8) -> Result<Self, Nau7802Error> {
+        let mut nau7802 = Self { bus, gain };
+        nau7802.init()?;
+        Ok(nau7802)
+    }
+
+    fn init(&mut self) -> Result<(), Nau7802Error> {
+        let gain = self.gain;
+        let gains = gain_bits(gain).ok_or(Nau7802Error::Gain(gain))?;
+
+        let revision = self.read_register(REG_DEVICE_REV)?;
+        if revision & DEVICE_REV_ID_MASK != DEVICE_REV_ID {
+            return Err(Nau7802Error::Revision(revision));
+        }
+
+        self.write_register(REG_PU_CTRL, PU_CTRL_RR)?;
+        self.write_register(REG_PU_CTRL, PU_CTRL_PUD)?;
+        self.update_register(REG_PU_CTRL, PU_CTRL_PUA, PU_CTRL_PUA)?;
+        let start = Instant::now();
+        while self.read_register(REG_PU_CTRL)? & PU_CTRL_PUR == 0 {
+            if start.elapsed() >= POWER_UP_TIMEOUT {
+                return Err(Nau7802Error::PowerUp);
+            }
+            FreeRtos::delay_ms(POLL_PERIOD_MS);
+        }
+
+        // Load cell excitation from the internal LDO
+        self.update_register(
+            REG_CTRL1,
+            CTRL1_VLDO_MASK | CTRL1_GAINS_MASK,
+            (VLDO_3V0 << CTRL1_VLDO_SHIFT) | gains,
+        )?;
+        self.update_register(REG_PU_CTRL, PU_CTRL_AVDDS, PU_CTRL_AVDDS)?;
+        self.update_register(REG_CTRL2, CTRL2_CRS_MASK, CRS_10SPS << CTRL2_CRS_SHIFT)?;
+        self.update_register(REG_ADC, ADC_CHPS_OFF, ADC_CHPS_OFF)?;
+        self.update_register(REG_POWER, POWER_PGA_CAP_EN, POWER_PGA_CAP_EN)?;
+
+        self.calibrate(AfeCalibration::InternalOffset)?;
+        self.update_register(REG_PU_CTRL, PU_CTRL_CS, PU_CTRL_CS)?;
+        info!(
+            "NAU7802 started with a gain of {}, revision 0x{:02X}",
+            gain, revision
+        );
+        debug!(
+            "NAU7802 offset calibration {}, gain calibration 0x{:08X}",
+            self.offset_calibration()?,
+            self.gain_calibration()?
+        );
+        Ok(())
+    }
+
+    /// Run a calibration of the analog front end, which updates the offset
+    /// or gain calibration register
+    pub fn calibrate(&mut self, calibration: AfeCalibration) -> Result<(), Nau7802Error> {
+        self.update_register(REG_CTRL2, CTRL2_CALMOD_MASK, calibration.calmod())?;
+        self.update_register(REG_CTRL2, CTRL2_CALS, CTRL2_CALS)?;
+        let start = Instant::now();
+        loop {
+            let ctrl2 = self.read_register(REG_CTRL2)?;
+            if ctrl2 & CTRL2_CALS == 0 {
+                return match ctrl2 & CTRL2_CAL_ERR {
+                    0 => Ok(()),
+                    _ => Err(Nau7802Error::Calibration),
+                };
+            }
+            if start.elapsed() >= CALIBRATION_TIMEOUT {
+                return Err(Nau7802Error::Calibration);
+            }
+            FreeRtos::delay_ms(POLL_PERIOD_MS);
+        }
+    }
+
+    /// Offset subtracted by the ADC, in counts
+    pub fn offset_calibration(&mut self) -> Result<i32, Nau7802Error> {
+        let mut bytes = [0; 3];
+        self.read_registers(REG_OCAL1_B2, &mut bytes)?;
+        Ok(sign_extend_24(bytes))
+    }
+
+    pub fn set_offset_calibration(&mut self, counts: i32) -> Result<(), Nau7802Error> {
+        let [_, b2, b1, b0] = counts.to_be_bytes();
+        self.write_registers(REG_OCAL1_B2, &[b2, b1, b0])
+    }
+
+    /// Gain applied by the ADC, 1.0 being 0x00800000
+    pub fn gain_calibration(&mut self) -> Result<u32, Nau7802Error> {
+        let mut bytes = [0; 4];
+        self.read_registers(REG_GCAL1_B3, &mut bytes)?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn set_gain_calibration(&mut self, gain: u32) -> Result<(), Nau7802Error> {
+        self.write_registers(REG_GCAL1_B3, &gain.to_be_bytes())
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Nau7802Error> {
+        let mut byte = [0];
+        self.read_registers(register, &mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Read consecutive registers, the address increments on its own
+    fn read_registers(&mut self, first: u8, bytes: &mut [u8]) -> Result<(), Nau7802Error> {
+        let timeout = TickType::new_millis(I2C_TIMEOUT_MS).ticks();
+        self.bus
+            .lock()
+            .write_read(NAU7802_ADDRESS, &[first], bytes, timeout)?;
+        Ok(())
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), Nau7802Error> {
+        self.write_registers(register, &[value])
+    }
+
+    fn write_registers(&mut self, first: u8, bytes: &[u8]) -> Result<(), Nau7802Error> {
+        let timeout = TickType::new_millis(I2C_TIMEOUT_MS).ticks();
+        let mut buf = Vec::with_capacity(bytes.len() + 1);
+        buf.push(first);
+        buf.extend_from_slice(bytes);
+        self.bus.lock().write(NAU7802_ADDRESS, &buf, timeout)?;
+        Ok(())
+    }
+
+    /// Set the bits of `mask` to those of `value`
+    fn update_register(&mut self, register: u8, mask: u8, value: u8) -> Result<(), Nau7802Error> {
+        let current = self.read_register(register)?;
+        self.write_register(register, (current & !mask) | (value & mask))
+    }
+}
+
+impl LoadSensor for Nau7802 {
+    fn is_ready(&mut self) -> bool {
+        self.read_register(REG_PU_CTRL)
+            .is_ok_and(|pu_ctrl| pu_ctrl & PU_CTRL_CR != 0)
+    }
+
+    fn read(&mut self) -> Result<i32, SensorError> {
+        if !self.is_ready() {
+            return Err(SensorError::NotReady);
+        }
+        let mut bytes = [0; 3];
+        match self.read_registers(REG_ADCO_B2, &mut bytes) {
+            Ok(()) => Ok(sign_extend_24(bytes)),
+            Err(Nau7802Error::Bus(err)) => Err(SensorError::Bus(err.code())),
+            Err(_) => Err(SensorError::Bus(0)),
+        }
+    }
+
+    /// Run the whole start sequence again, the chip may have lost power
+    fn reset(&mut self) -> Result<(), SensorError> {
+        match self.init() {
+            Ok(()) => Ok(()),
+            Err(Nau7802Error::Bus(err)) => Err(SensorError::Bus(err.code())),
+            Err(_) => Err(SensorError::Bus(0)),
+        }
+    }
+}
+
+/// Bits of the PGA gain
+fn gain_bits(gain: u8) -> Option<u8> {
+    NAU7802_GAINS
+        .iter()
+        .position(|&supported| supported == gain)
+        .map(|bits| bits as u8)
+}
+
+/// A 24 bit two's complement value, most significant byte first
+fn sign_extend_24([b2, b1, b0]: [u8; 3]) -> i32 {
+    i32::from_be_bytes([b2, b1, b0, 0]) >> 8
+}
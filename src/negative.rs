@@ -0,0 +1,103 @@
+//! Hint to tare again when the scale keeps reading below zero, e.g. after a
+//! container that was on it at the boot tare is taken off. The hint clears
+//! as soon as the weight comes back, and the scale can tare itself instead
+//! once the weight is still. Weighing below zero on purpose, e.g. what is
+//! taken out of a container standing on the scale, turns it off.
+
+use std::time::{Duration, Instant};
+
+/// Gross weight below minus this counts as reading below zero
+pub const DEFAULT_NEGATIVE_GRAMS: f32 = 10.0;
+/// Time the weight stays below before the hint shows
+pub const DEFAULT_NEGATIVE_DELAY_S: u32 = 5;
+pub const MAX_NEGATIVE_DELAY_S: u32 = 600;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NegativeConfig {
+    /// Gross weight below minus this counts, 0 turns the hint off
+    pub grams: f32,
+    pub delay: Duration,
+    /// Tare once the weight is stable instead of hinting
+    pub auto_tare: bool,
+}
+
+impl Default for NegativeConfig {
+    fn default() -> Self {
+        Self {
+            grams: DEFAULT_NEGATIVE_GRAMS,
+            delay: Duration::from_secs(DEFAULT_NEGATIVE_DELAY_S.into()),
+            auto_tare: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NegativeEvent {
+    ShowHint,
+    ClearHint,
+    /// Tare the scale, the weight is stable below zero
+    Tare,
+}
+
+/// Follows how long the weight has been below zero
+#[derive(Debug, Default)]
+pub struct NegativeWatch {
+    config: NegativeConfig,
+    /// Since when the stable weight is below the threshold
+    below_since: Option<Instant>,
+    hinting: bool,
+}
+
+impl NegativeWatch {
+    pub fn new(config: NegativeConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn configure(&mut self, config: NegativeConfig) {
+        self.config = config;
+    }
+
+    pub fn is_hinting(&self) -> bool {
+        self.hinting
+    }
+
+    /// Follow the filtered gross weight of every reading. The hint clears
+    /// on the first one back above the threshold, stable or not.
+    pub fn on_weight(&mut self, gross: f32, stable: bool, now: Instant) -> Option<NegativeEvent> {
+        let enabled = self.config.grams > 0.0;
+        if !enabled || gross >= -self.config.grams {
+            self.below_since = None;
+            return self.clear();
+        }
+        if !stable {
+            return None;
+        }
+        let since = *self.below_since.get_or_insert(now);
+        if now.saturating_duration_since(since) < self.config.delay {
+            return None;
+        }
+        if self.config.auto_tare {
+            self.below_since = None;
+            self.hinting = false;
+            return Some(NegativeEvent::Tare);
+        }
+        if self.hinting {
+            return None;
+        }
+        self.hinting = true;
+        Some(NegativeEvent::ShowHint)
+    }
+
+    /// Drop the hint after a tare, whatever the weight was
+    pub fn reset(&mut self) -> Option<NegativeEvent> {
+        self.below_since = None;
+        self.clear()
+    }
+
+    fn clear(&mut self) -> Option<NegativeEvent> {
+        std::mem::take(&mut self.hinting).then_some(NegativeEvent::ClearHint)
+    }
+}
@@ -0,0 +1,220 @@
+//! Noise floor of the load cell, to tell whether a change to the wiring
+//! helped. The test takes the raw readings of the empty, undisturbed scale
+//! and reports their peak-to-peak and RMS spread, in counts and in grams
+//! once calibrated, along with a verdict against the thresholds set.
+//!
+//! A reading far off the first ones means the platform was touched, which
+//! aborts the test instead of reporting the touch as noise.
+
+use crate::calibration::Moment;
+
+/// Readings a test takes
+pub const NOISE_NUM_SAMPLES: usize = 200;
+/// RMS noise in counts past which the wiring deserves a look
+pub const DEFAULT_NOISE_WARN_COUNTS: f32 = 100.0;
+/// RMS noise in counts past which the scale cannot weigh to its resolution
+pub const DEFAULT_NOISE_FAIL_COUNTS: f32 = 400.0;
+pub const MAX_NOISE_COUNTS: f32 = 100_000.0;
+/// Readings averaged into the level the others are compared against
+const REFERENCE_READINGS: usize = 16;
+/// A reading this many times the fail threshold off the level was a touch
+const DISTURBANCE_FACTOR: f32 = 10.0;
+
+const ENCODED_LEN: usize = 2 + 4 + 4 + 4 + 1 + 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseThresholds {
+    /// RMS noise in counts from which the verdict is a warning
+    pub warn_counts: f32,
+    /// RMS noise in counts from which the test fails
+    pub fail_counts: f32,
+}
+
+impl Default for NoiseThresholds {
+    fn default() -> Self {
+        Self {
+            warn_counts: DEFAULT_NOISE_WARN_COUNTS,
+            fail_counts: DEFAULT_NOISE_FAIL_COUNTS,
+        }
+    }
+}
+
+impl NoiseThresholds {
+    pub fn verdict(&self, rms_counts: f32) -> NoiseVerdict {
+        if rms_counts >= self.fail_counts {
+            NoiseVerdict::Fail
+        } else if rms_counts >= self.warn_counts {
+            NoiseVerdict::Warn
+        } else {
+            NoiseVerdict::Pass
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoiseVerdict {
+    #[default]
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl NoiseVerdict {
+    const ALL: [NoiseVerdict; 3] = [NoiseVerdict::Pass, NoiseVerdict::Warn, NoiseVerdict::Fail];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoiseVerdict::Pass => "pass",
+            NoiseVerdict::Warn => "warn",
+            NoiseVerdict::Fail => "fail",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            NoiseVerdict::Pass => "PASS",
+            NoiseVerdict::Warn => "WARN",
+            NoiseVerdict::Fail => "FAIL",
+        }
+    }
+}
+
+/// A reading strayed from the level of the first ones by more than noise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Disturbed;
+
+/// Readings of a test in progress
+#[derive(Clone, Debug)]
+pub struct NoiseTest {
+    thresholds: NoiseThresholds,
+    scale_factor: Option<f32>,
+    readings: Vec<i32>,
+    /// Mean of the first readings
+    level: Option<f32>,
+}
+
+impl NoiseTest {
+    pub fn new(thresholds: NoiseThresholds, scale_factor: Option<f32>) -> Self {
+        Self {
+            thresholds,
+            scale_factor,
+            readings: Vec::with_capacity(NOISE_NUM_SAMPLES),
+            level: None,
+        }
+    }
+
+    /// Add a raw reading, refused when it is a touch rather than noise
+    pub fn add(&mut self, raw: i32) -> Result<(), Disturbed> {
+        if let Some(level) = self.level {
+            let limit = self.thresholds.fail_counts * DISTURBANCE_FACTOR;
+            if (raw as f32 - level).abs() > limit {
+                return Err(Disturbed);
+            }
+        }
+        self.readings.push(raw);
+        if self.readings.len() == REFERENCE_READINGS {
+            self.level = Some(mean(&self.readings) as f32);
+        }
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.readings.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.readings.len() >= NOISE_NUM_SAMPLES
+    }
+
+    /// Spread of the readings so far
+    pub fn report(&self, measured: Moment) -> NoiseReport {
+        let mean = mean(&self.readings);
+        let variance = self
+            .readings
+            .iter()
+            .map(|&raw| (f64::from(raw) - mean).powi(2))
+            .sum::<f64>()
+            / self.readings.len().max(1) as f64;
+        let min = self.readings.iter().min().copied().unwrap_or_default();
+        let max = self.readings.iter().max().copied().unwrap_or_default();
+        let rms_counts = variance.sqrt() as f32;
+        NoiseReport {
+            samples: self.readings.len() as u16,
+            peak_to_peak_counts: max.abs_diff(min),
+            rms_counts,
+            scale_factor: self.scale_factor,
+            verdict: self.thresholds.verdict(rms_counts),
+            measured,
+        }
+    }
+}
+
+fn mean(readings: &[i32]) -> f64 {
+    readings.iter().map(|&raw| f64::from(raw)).sum::<f64>() / readings.len().max(1) as f64
+}
+
+/// Outcome of a noise test
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseReport {
+    pub samples: u16,
+    pub peak_to_peak_counts: u32,
+    pub rms_counts: f32,
+    /// Grams per count at the time, none when not calibrated
+    pub scale_factor: Option<f32>,
+    pub verdict: NoiseVerdict,
+    pub measured: Moment,
+}
+
+impl NoiseReport {
+    pub fn peak_to_peak_grams(&self) -> Option<f32> {
+        self.scale_factor
+            .map(|factor| self.peak_to_peak_counts as f32 * factor.abs())
+    }
+
+    pub fn rms_grams(&self) -> Option<f32> {
+        self.scale_factor
+            .map(|factor| self.rms_counts * factor.abs())
+    }
+
+    /// Short enough for the status strip
+    pub fn describe(&self) -> String {
+        match self.rms_grams() {
+            Some(grams) => format!("Noise {:.3}g {}", grams, self.verdict.label()),
+            None => format!("Noise {:.0} {}", self.rms_counts, self.verdict.label()),
+        }
+    }
+}
+
+impl crate::storage::Stored for NoiseReport {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        bytes.extend_from_slice(&self.samples.to_le_bytes());
+        bytes.extend_from_slice(&self.peak_to_peak_counts.to_le_bytes());
+        bytes.extend_from_slice(&self.rms_counts.to_le_bytes());
+        bytes.extend_from_slice(&self.scale_factor.unwrap_or(0.0).to_le_bytes());
+        bytes.push(self.verdict as u8);
+        bytes.extend_from_slice(&self.measured.epoch_s.to_le_bytes());
+        bytes.extend_from_slice(&self.measured.boot.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; ENCODED_LEN] = bytes.try_into().ok()?;
+        let rms_counts = f32::from_le_bytes(bytes[6..10].try_into().ok()?);
+        let scale_factor = f32::from_le_bytes(bytes[10..14].try_into().ok()?);
+        if !rms_counts.is_finite() || !scale_factor.is_finite() {
+            return None;
+        }
+        Some(Self {
+            samples: u16::from_le_bytes(bytes[0..2].try_into().ok()?),
+            peak_to_peak_counts: u32::from_le_bytes(bytes[2..6].try_into().ok()?),
+            rms_counts,
+            scale_factor: (scale_factor != 0.0).then_some(scale_factor),
+            verdict: *NoiseVerdict::ALL.get(usize::from(bytes[14]))?,
+            measured: Moment {
+                epoch_s: u64::from_le_bytes(bytes[15..23].try_into().ok()?),
+                boot: u32::from_le_bytes(bytes[23..27].try_into().ok()?),
+            },
+        })
+    }
+}
@@ -0,0 +1,161 @@
+//! Firmware updates over HTTP, with rollback: a new image boots unverified
+//! and is only confirmed once the scale took its first reading. Should it
+//! panic, fail to start or get reset by the watchdog before that, the
+//! bootloader goes back to the previous image on the next boot.
+//!
+//! This needs two app partitions and the OTA data partition instead of the
+//! single factory partition of `partitions.csv`, e.g. for a 4MB flash:
+//!
+//! ```text
+//! # Name,   Type, SubType, Offset,   Size
+//! nvs,      data, nvs,     0x9000,   0x6000
+//! otadata,  data, ota,     0xf000,   0x2000
+//! phy_init, data, phy,     0x11000,  0x1000
+//! ota_0,    app,  ota_0,   0x20000,  0x1c0000
+//! ota_1,    app,  ota_1,   0x1e0000, 0x1c0000
+//! datalog,  data, nvs,     0x3a0000, 0x20000
+//! ```
+//!
+//! The settings survive switching tables as long as the `nvs` partition
+//! keeps its offset, the weight log starts over. The new table has to be
+//! flashed over serial once, along with a bootloader built with
+//! `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE` (see `sdkconfig.defaults`).
+//! Without an OTA partition the updates are refused.
+
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::{
+    io::{Read, Write},
+    ota::{EspOta, SlotState},
+};
+use esp_idf_sys::EspError;
+use log::{info, warn};
+use thiserror::Error;
+
+/// Size of the chunks the image is received in
+const CHUNK_LEN: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum OtaError {
+    #[error("an update is already running")]
+    Busy,
+    #[error("failed to receive the image")]
+    Receive,
+    #[error("image truncated after {received} of {expected} bytes")]
+    Truncated { received: usize, expected: usize },
+    #[error("flash error: {0}")]
+    Esp(#[from] EspError),
+}
+
+/// Authorizes the updates and follows their progress, shared between the
+/// HTTP server and the main loop drawing the progress bar
+#[derive(Clone, Default)]
+pub struct OtaHandle {
+    /// Updates are refused while empty
+    token: Arc<str>,
+    /// Percentage written of the running update
+    progress: Arc<Mutex<Option<u8>>>,
+}
+
+impl OtaHandle {
+    pub fn new(token: &str) -> Self {
+        Self {
+            token: token.into(),
+            progress: Arc::default(),
+        }
+    }
+
+    /// Whether updates can be authorized at all
+    pub fn is_enabled(&self) -> bool {
+        !self.token.is_empty()
+    }
+
+    pub fn is_authorized(&self, token: &str) -> bool {
+        self.is_enabled() && token == &*self.token
+    }
+
+    /// Fraction of the running update written to flash, if any
+    pub fn progress(&self) -> Option<f32> {
+        self.lock().map(|percent| f32::from(percent) / 100.0)
+    }
+
+    /// Write the `len` bytes long image into the passive app partition and
+    /// boot it next. The image is verified once complete, a failed update
+    /// leaves the boot partition alone. After a successful one the progress
+    /// stays full and further updates are refused until the restart.
+    pub fn update<R: Read>(&self, image: &mut R, len: usize) -> Result<(), OtaError> {
+        {
+            let mut progress = self.lock();
+            if progress.is_some() {
+                return Err(OtaError::Busy);
+            }
+            *progress = Some(0);
+        }
+        let result = write_image(image, len, |percent| *self.lock() = Some(percent));
+        if result.is_err() {
+            *self.lock() = None;
+        }
+        result
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<u8>> {
+        self.progress
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn write_image<R: Read>(
+    image: &mut R,
+    len: usize,
+    mut on_progress: impl FnMut(u8),
+) -> Result<(), OtaError> {
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut buf = vec![0; CHUNK_LEN];
+    let mut received = 0;
+    while received < len {
+        let chunk = (len - received).min(CHUNK_LEN);
+        let read = match image.read(&mut buf[..chunk]) {
+            Ok(0) => {
+                update.abort()?;
+                return Err(OtaError::Truncated {
+                    received,
+                    expected: len,
+                });
+            }
+            Ok(read) => read,
+            Err(_) => {
+                update.abort()?;
+                return Err(OtaError::Receive);
+            }
+        };
+        if let Err(err) = update.write_all(&buf[..read]) {
+            update.abort()?;
+            return Err(err.into());
+        }
+        received += read;
+        on_progress((received * 100 / len) as u8);
+    }
+    // Checks the image and switches the boot partition over to it
+    update.complete()?;
+    info!("Firmware update of {} bytes written", len);
+    Ok(())
+}
+
+/// Keep the running image for good, if it was booted for the first time
+/// after an update. Called once the scale proved to work.
+pub fn confirm_running_image() {
+    let confirmed = EspOta::new().and_then(|mut ota| {
+        if ota.get_running_slot()?.state != SlotState::Unverified {
+            return Ok(false);
+        }
+        ota.mark_running_slot_valid()?;
+        Ok(true)
+    });
+    match confirmed {
+        Ok(true) => info!("Updated firmware confirmed"),
+        Ok(false) => {}
+        Err(err) => warn!("Failed to confirm the running firmware: {:?}", err),
+    }
+}
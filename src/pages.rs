@@ -0,0 +1,903 @@
+//! Screens of the main loop, cycled through with a double press. A page is
+//! the operations drawing its layout regions, and only those that changed
+//! since the last frame are drawn, so an update within the page sends just
+//! those regions to the panel; the screen is cleared as a whole only when
+//! switching pages. The clock is no part of the cycle, it takes over from
+//! the weight page while the scale is idle.
+//!
+//! An aggregator summing corner scales over ESP-NOW gets the total of the
+//! corners and their breakdown in the cycle, after the weight page.
+//!
+//! The pages are drawn from a [`PageInput`] the main loop gathers, so what a
+//! change of the state touches on the screen can be checked on the host.
+
+use std::time::{Duration, Instant};
+
+use embedded_graphics::{
+    mono_font::ascii::FONT_10X20,
+    prelude::{Point, Size},
+    primitives::Rectangle,
+};
+
+use crate::{
+    brew::{format_elapsed, BrewState, BrewTimer},
+    certified::{Audit, RESTRICTIONS},
+    espnow::Aggregate,
+    format::{format_volume, format_weight, milligrams, shown_unit, FormatOpts, KiloSwitch},
+    frame::{DrawContent, DrawOp},
+    hold::HoldState,
+    i18n::{tr, trf, StringId},
+    layout::UiLayout,
+    quality::Quality,
+    session::SessionStats,
+    status::StatusIcon,
+    tare::DisplayMode,
+    unit::Unit,
+    volume::Volume,
+    weight_gesture::GestureHint,
+};
+
+/// Time without a button gesture after which the weight page comes back
+pub const PAGE_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time the title of a page shows in the status strip after switching to it
+pub const PAGE_TITLE_TIME: Duration = Duration::from_millis(1500);
+/// Time a toast shows in the status strip
+pub const TOAST_TIME: Duration = Duration::from_secs(5);
+/// Time the lines of a text page that does not fit show before the next ones
+pub const PAGE_TURN_PERIOD: Duration = Duration::from_secs(3);
+/// Weight within which the scale counts as empty for the idle clock
+const IDLE_EMPTY_GRAMS: f32 = 1.0;
+/// Offsets the clock digits move through, one step a minute, so the same
+/// pixels are not lit for hours on end
+const BURN_IN_OFFSETS: [(i32, i32); 5] = [(0, 0), (2, 1), (-2, 2), (1, -2), (-1, -1)];
+
+/// What the pages show, gathered by the main loop for every frame
+#[derive(Debug, Default)]
+pub struct PageInput<'a> {
+    /// Live weight, none before the first reading
+    pub grams: Option<f32>,
+    pub hold: HoldState,
+    pub quality: Quality,
+    pub unit: Unit,
+    pub resolution: f32,
+    /// Whether a weight in grams is shown in kilograms
+    pub kilo: bool,
+    /// Substance the weight shows as the volume of, if any
+    pub volume: Option<Volume>,
+    /// Weight reported while a soft tare is stacked
+    pub tare_mode: Option<DisplayMode>,
+    /// Brew timer, while the brew mode is on
+    pub brew: Option<&'a BrewTimer>,
+    /// Flow rate of the weight, whether or not the brew timer runs
+    pub grams_per_sec: f32,
+
+    /// Slot and description of the first latched alarm
+    pub alarm: Option<(usize, String)>,
+    /// Hint for the press on the platform in progress
+    pub gesture_hint: Option<&'a str>,
+    pub toast: Option<&'a str>,
+    /// Whether the scale keeps reading below zero
+    pub negative_hint: bool,
+    /// Whether the status strip shows the title of the page
+    pub title_shown: bool,
+    pub icons: &'a [StatusIcon],
+
+    /// Turns of the lines of a text page that does not fit
+    pub page_turn: u64,
+    pub uptime: Duration,
+    /// Voltage and charge of the battery, if monitored
+    pub battery: Option<(f32, u8)>,
+    /// Records in the weight log, unless disabled
+    pub log_records: Option<usize>,
+    /// Weights the sinks missed on a full queue
+    pub dropped: u32,
+    /// Failed draws of the display
+    pub draw_errors: u32,
+    /// Bumps of the IMU and the samples dropped for them, while it runs
+    pub bumps: Option<(u32, u32)>,
+    /// Weighings of the current session
+    pub session: SessionStats,
+    /// State of the Wi-Fi connection, unless Wi-Fi is not running
+    pub wifi: Option<&'a str>,
+    pub hostname: &'a str,
+    /// Whether the MQTT task is running
+    pub mqtt: bool,
+
+    /// Lines of the diagnostics figures, while they are collected
+    pub diagnostics: Vec<String>,
+    /// Noise the deadband holds the weight within
+    pub deadband: String,
+    /// Errors of the sinks, a line each
+    pub sink_errors: Vec<String>,
+    /// Weighings going on without a press
+    pub button_note: Option<String>,
+    /// Outcome of the last noise test
+    pub noise: Option<String>,
+    pub audit: Audit,
+    /// Sum of the corners, while its pages are shown on the aggregator
+    pub aggregate: Option<&'a Aggregate>,
+}
+
+/// What the pages draw into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Screen {
+    pub layout: UiLayout,
+    /// Height of a line of the font of the drawer
+    pub line_height: u32,
+}
+
+/// A screen of the main loop
+trait Page {
+    /// Shown in the status strip for a moment after switching to the page
+    fn title(&self) -> &'static str;
+
+    /// Operations drawing the page above the status strip, one for every
+    /// region drawn into
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp>;
+}
+
+/// The registered pages, in the order they are cycled through
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PageId {
+    #[default]
+    Weight,
+    /// Sum of the corners, on the aggregator only
+    Total,
+    /// Weight of each corner, on the aggregator only
+    Corners,
+    Flow,
+    Stats,
+    /// Weighings of the current session
+    Session,
+    Network,
+    Diagnostics,
+    /// Shown in place of the weight page while the scale is idle
+    Clock,
+}
+
+impl PageId {
+    const ALL: [PageId; 8] = [
+        PageId::Weight,
+        PageId::Total,
+        PageId::Corners,
+        PageId::Flow,
+        PageId::Stats,
+        PageId::Session,
+        PageId::Network,
+        PageId::Diagnostics,
+    ];
+
+    /// Page after this one, the pages of the corners left out unless
+    /// `aggregating`
+    pub fn next(self, aggregating: bool) -> Self {
+        let index = Self::ALL.iter().position(|&page| page == self).unwrap_or(0);
+        Self::ALL
+            .into_iter()
+            .cycle()
+            .skip(index + 1)
+            .find(|page| aggregating || !page.is_aggregate())
+            .unwrap_or_default()
+    }
+
+    /// Whether the page shows the corners summed by the aggregator
+    pub fn is_aggregate(self) -> bool {
+        matches!(self, PageId::Total | PageId::Corners)
+    }
+
+    /// Whether the page is made of lines of text, shown in turns when they
+    /// do not all fit
+    pub fn is_text(self) -> bool {
+        matches!(
+            self,
+            PageId::Corners
+                | PageId::Stats
+                | PageId::Session
+                | PageId::Network
+                | PageId::Diagnostics
+        )
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            PageId::Weight => WeightPage.title(),
+            PageId::Total => TotalPage.title(),
+            PageId::Corners => CornersPage.title(),
+            PageId::Flow => FlowPage.title(),
+            PageId::Stats => StatsPage.title(),
+            PageId::Session => SessionPage.title(),
+            PageId::Network => NetworkPage.title(),
+            PageId::Diagnostics => DiagnosticsPage.title(),
+            PageId::Clock => ClockPage.title(),
+        }
+    }
+
+    /// Operations drawing the page along with the status strip
+    pub fn ops(self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let mut ops = match self {
+            PageId::Weight => WeightPage.ops(input, screen),
+            PageId::Total => TotalPage.ops(input, screen),
+            PageId::Corners => CornersPage.ops(input, screen),
+            PageId::Flow => FlowPage.ops(input, screen),
+            PageId::Stats => StatsPage.ops(input, screen),
+            PageId::Session => SessionPage.ops(input, screen),
+            PageId::Network => NetworkPage.ops(input, screen),
+            PageId::Diagnostics => DiagnosticsPage.ops(input, screen),
+            // The clock takes the whole screen, status strip included
+            PageId::Clock => return ClockPage.ops(input, screen),
+        };
+
+        let status = screen.layout.status;
+        // A latched alarm keeps its banner until acknowledged
+        ops.push(if let Some((slot, description)) = &input.alarm {
+            DrawOp::text(status, format!("ALARM {}: {}", slot + 1, description))
+        } else if let Some(hint) = input.gesture_hint {
+            DrawOp::text(status, hint)
+        } else if let Some(toast) = input.toast {
+            DrawOp::text(status, toast)
+        } else if input.negative_hint {
+            DrawOp::text(status, tr(StringId::NegativeHint))
+        } else if input.title_shown {
+            DrawOp::text(status, self.title())
+        } else {
+            DrawOp {
+                region: status,
+                content: DrawContent::Icons(input.icons.to_vec()),
+            }
+        });
+        ops
+    }
+}
+
+/// Hint for the press on the platform in progress, in whole seconds so it
+/// changes once a second at most
+pub fn gesture_hint_text(hint: GestureHint) -> String {
+    let secs = |left: Duration| left.as_millis().div_ceil(1000);
+    match hint {
+        GestureHint::Hold { left } => trf(StringId::GestureHold, &[&secs(left)]).to_string(),
+        GestureHint::Release => tr(StringId::GestureRelease).to_string(),
+        GestureHint::Counted { presses, left } => {
+            trf(StringId::GesturePresses, &[&presses, &secs(left)]).to_string()
+        }
+    }
+}
+
+struct WeightPage;
+
+impl Page for WeightPage {
+    fn title(&self) -> &'static str {
+        "Weight"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let Some(live) = input.grams else {
+            return Vec::new();
+        };
+        let grams = match input.hold {
+            HoldState::Held { grams, .. } => grams,
+            HoldState::Live => live,
+        };
+        let layout = screen.layout;
+        let opts = FormatOpts {
+            kilo: input.kilo,
+            ..FormatOpts::for_resolution(input.resolution)
+        };
+        let mut value = match &input.volume {
+            Some(volume) => format_volume(milligrams(grams), volume, &opts),
+            None => format_weight(milligrams(grams), input.unit, &opts),
+        }
+        .to_string();
+        // The live weight of a sensor that stopped converting is questioned,
+        // an overloaded or disturbed one flagged
+        if input.hold == HoldState::Live {
+            match input.quality {
+                Quality::Stale => value.push('?'),
+                Quality::Overload => value.push('!'),
+                Quality::Disturbed => value.push('~'),
+                Quality::Good | Quality::Settling => {}
+            }
+        }
+        let unit = match input.volume {
+            Some(_) => "ml",
+            None => shown_unit(input.unit, &opts).symbol(),
+        };
+        // A held weight is tagged, net or gross only tell apart with a soft
+        // tare stacked
+        let label = match input.hold {
+            HoldState::Held { .. } => Some("HOLD"),
+            HoldState::Live => input.tare_mode.map(DisplayMode::label),
+        };
+
+        match (layout.unit, layout.flow_rate) {
+            (Some(unit_region), flow_rate) => {
+                let mut ops = vec![
+                    DrawOp::text(layout.weight, value),
+                    DrawOp::text(unit_region, unit),
+                ];
+                if let Some(label_region) = flow_rate {
+                    ops.push(DrawOp::text(label_region, label.unwrap_or("")));
+                }
+                ops
+            }
+            (None, _) => vec![DrawOp::text(
+                layout.weight,
+                format!("{}: {}{}", label.unwrap_or("Weight"), value, unit),
+            )],
+        }
+    }
+}
+
+/// Sum of the corners in the layout of the weight page, questioned when a
+/// corner is stale
+struct TotalPage;
+
+impl Page for TotalPage {
+    fn title(&self) -> &'static str {
+        "Total"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let Some(aggregate) = input.aggregate else {
+            return Vec::new();
+        };
+        let layout = screen.layout;
+        let opts = FormatOpts {
+            kilo: KiloSwitch::default().update(aggregate.grams),
+            ..FormatOpts::for_resolution(input.resolution)
+        };
+        let mut value = format_weight(milligrams(aggregate.grams), input.unit, &opts).to_string();
+        if !aggregate.valid {
+            value.push('?');
+        }
+        let unit = shown_unit(input.unit, &opts).symbol();
+        let label = if aggregate.valid { "SUM" } else { "STALE" };
+
+        match (layout.unit, layout.flow_rate) {
+            (Some(unit_region), flow_rate) => {
+                let mut ops = vec![
+                    DrawOp::text(layout.weight, value),
+                    DrawOp::text(unit_region, unit),
+                ];
+                if let Some(label_region) = flow_rate {
+                    ops.push(DrawOp::text(label_region, label));
+                }
+                ops
+            }
+            (None, _) => vec![DrawOp::text(
+                layout.weight,
+                format!("{}: {}{}", label, value, unit),
+            )],
+        }
+    }
+}
+
+/// Weight each corner sent last, with the seconds since for a stale one
+struct CornersPage;
+
+impl Page for CornersPage {
+    fn title(&self) -> &'static str {
+        "Corners"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let corners = input
+            .aggregate
+            .map(|aggregate| aggregate.corners.as_slice());
+        let lines = match corners {
+            Some([]) | None => vec!["No corners".to_string()],
+            Some(corners) => corners
+                .iter()
+                .map(|corner| {
+                    let Some(grams) = corner.grams else {
+                        return format!("{} --", corner.label);
+                    };
+                    let opts = FormatOpts {
+                        kilo: KiloSwitch::default().update(grams),
+                        ..FormatOpts::for_resolution(input.resolution)
+                    };
+                    let weight = format!(
+                        "{} {}{}",
+                        corner.label,
+                        format_weight(milligrams(grams), input.unit, &opts),
+                        shown_unit(input.unit, &opts).symbol()
+                    );
+                    match corner.age_s {
+                        Some(age_s) if !corner.fresh => format!("{} {}s?", weight, age_s),
+                        _ => weight,
+                    }
+                })
+                .collect(),
+        };
+        line_ops(screen, input, &lines)
+    }
+}
+
+/// The brew timer while one is armed, the live flow rate otherwise
+struct FlowPage;
+
+impl Page for FlowPage {
+    fn title(&self) -> &'static str {
+        "Flow"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let layout = screen.layout;
+        // The detail is cut down to the timer on short displays
+        let (weight, detail, short_detail) = match input.brew {
+            Some(brew) => {
+                let timer = format_elapsed(brew.elapsed(Instant::now()));
+                let detail = match brew.state() {
+                    BrewState::Armed => "ready".to_string(),
+                    BrewState::Running { .. } => format!("{:.1}g/s", brew.grams_per_sec()),
+                    BrewState::Finished { .. } => "done".to_string(),
+                };
+                (
+                    format!("{:.1}", brew.grams()),
+                    format!("{} {}", timer, detail),
+                    timer,
+                )
+            }
+            None => {
+                let flow = format!("{:.1}g/s", input.grams_per_sec);
+                (
+                    format!("{:.1}", input.grams.unwrap_or_default()),
+                    flow.clone(),
+                    flow,
+                )
+            }
+        };
+
+        match (layout.unit, layout.flow_rate) {
+            (Some(unit_region), Some(flow_rate_region)) => vec![
+                DrawOp::text(layout.weight, weight),
+                DrawOp::text(unit_region, "g"),
+                DrawOp::text(flow_rate_region, detail),
+            ],
+            _ => vec![DrawOp::text(
+                layout.weight,
+                format!("{} {}g", short_detail, weight),
+            )],
+        }
+    }
+}
+
+struct StatsPage;
+
+impl Page for StatsPage {
+    fn title(&self) -> &'static str {
+        "Stats"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let uptime = input.uptime.as_secs();
+        let mut lines = vec![format!(
+            "Up {}:{:02}:{:02}",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60
+        )];
+        if let Some((volts, percent)) = input.battery {
+            lines.push(format!("Bat {:.2}V {}%", volts, percent));
+        }
+        if let Some(records) = input.log_records {
+            lines.push(format!("Log {} rec", records));
+        }
+        lines.push(format!("Drop {} err {}", input.dropped, input.draw_errors));
+        if let Some((bumps, dropped)) = input.bumps {
+            lines.push(format!("Bump {} drop {}", bumps, dropped));
+        }
+        line_ops(screen, input, &lines)
+    }
+}
+
+/// Count, total, average and largest weighing of the session
+struct SessionPage;
+
+impl Page for SessionPage {
+    fn title(&self) -> &'static str {
+        "Session"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let stats = input.session;
+        let weight = |grams: f32| {
+            let opts = FormatOpts {
+                // Fixed figures, so no hysteresis to follow
+                kilo: KiloSwitch::default().update(grams),
+                ..FormatOpts::for_resolution(input.resolution)
+            };
+            format!(
+                "{}{}",
+                format_weight(milligrams(grams), input.unit, &opts),
+                shown_unit(input.unit, &opts).symbol()
+            )
+        };
+        let mut lines = vec![
+            format!("Items {}", stats.count),
+            format!("Total {}", weight(stats.total_grams)),
+        ];
+        if let Some(average) = stats.average_grams() {
+            lines.push(format!("Avg {}", weight(average)));
+            lines.push(format!("Max {}", weight(stats.max_grams)));
+        }
+        line_ops(screen, input, &lines)
+    }
+}
+
+struct NetworkPage;
+
+impl Page for NetworkPage {
+    fn title(&self) -> &'static str {
+        "Network"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let lines = match input.wifi {
+            Some(wifi) => vec![
+                format!("Wi-Fi {}", wifi),
+                format!("{}.local", input.hostname),
+                format!("MQTT {}", if input.mqtt { "on" } else { "off" }),
+            ],
+            None => vec!["No network".to_string()],
+        };
+        line_ops(screen, input, &lines)
+    }
+}
+
+struct DiagnosticsPage;
+
+impl Page for DiagnosticsPage {
+    fn title(&self) -> &'static str {
+        "Diagnostics"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let mut lines = input.diagnostics.clone();
+        lines.insert(0, input.deadband.clone());
+        for error in &input.sink_errors {
+            lines.insert(0, error.clone());
+        }
+        if let Some(note) = &input.button_note {
+            lines.insert(0, note.clone());
+        }
+        if input.icons.contains(&StatusIcon::ButtonStuck) {
+            lines.insert(0, "Button stuck, ignored".to_string());
+        }
+        if let Some(noise) = &input.noise {
+            lines.insert(0, noise.clone());
+        }
+        let audit = input.audit;
+        if audit.active {
+            lines.insert(0, format!("Locked {}", RESTRICTIONS.join(" ")));
+        }
+        if audit.active || audit.count > 0 || audit.corrupt {
+            lines.insert(0, audit.describe());
+        }
+        line_ops(screen, input, &lines)
+    }
+}
+
+/// The local time in a large font
+struct ClockPage;
+
+impl Page for ClockPage {
+    fn title(&self) -> &'static str {
+        "Clock"
+    }
+
+    fn ops(&self, input: &PageInput, screen: &Screen) -> Vec<DrawOp> {
+        let area = screen.layout.prompt;
+        let Some((hours, minutes)) = clock_time(input.icons) else {
+            return vec![DrawOp::text(area, "")];
+        };
+        let text = format!("{:02}:{:02}", hours, minutes);
+        let width = FONT_10X20.character_size.width * text.len() as u32;
+        let height = FONT_10X20.character_size.height;
+        let (dx, dy) = BURN_IN_OFFSETS[usize::from(minutes) % BURN_IN_OFFSETS.len()];
+        let offset = Point::new(
+            area.size.width.saturating_sub(width) as i32 / 2 + dx,
+            area.size.height.saturating_sub(height) as i32 / 2 + dy,
+        );
+        vec![DrawOp {
+            region: area,
+            content: DrawContent::Large { text, offset },
+        }]
+    }
+}
+
+/// Local hours and minutes, once the clock is synchronized
+pub fn clock_time(icons: &[StatusIcon]) -> Option<(u8, u8)> {
+    icons.iter().find_map(|icon| match *icon {
+        StatusIcon::Clock { hours, minutes } => Some((hours, minutes)),
+        _ => None,
+    })
+}
+
+/// Puts the clock up once the scale has been empty and still for a while
+pub struct IdlePolicy {
+    enabled: bool,
+    timeout: Duration,
+    /// Since when the scale is empty and still
+    idle_since: Option<Instant>,
+}
+
+impl IdlePolicy {
+    pub fn new(enabled: bool, timeout: Duration) -> Self {
+        Self {
+            enabled,
+            timeout,
+            idle_since: None,
+        }
+    }
+
+    pub fn configure(&mut self, enabled: bool, timeout: Duration) {
+        self.enabled = enabled;
+        self.timeout = timeout;
+    }
+
+    /// Follow the weight, any change of it ends the idle time
+    pub fn on_weight(&mut self, grams: f32, stable: bool, changed: bool, now: Instant) {
+        if changed || !stable || grams.abs() > IDLE_EMPTY_GRAMS {
+            self.idle_since = None;
+        } else if self.idle_since.is_none() {
+            self.idle_since = Some(now);
+        }
+    }
+
+    /// A button gesture starts the idle time over
+    pub fn wake(&mut self) {
+        self.idle_since = None;
+    }
+
+    /// Time the scale is empty and still for, whether or not the clock
+    /// is enabled
+    pub fn idle_for(&self) -> Option<Duration> {
+        self.idle_since.map(|since| since.elapsed())
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.enabled
+            && self
+                .idle_since
+                .is_some_and(|since| since.elapsed() >= self.timeout)
+    }
+}
+
+/// Area above the status strip
+fn text_area(layout: &UiLayout) -> Rectangle {
+    let height = layout.status.top_left.y - layout.prompt.top_left.y;
+    Rectangle::new(
+        layout.prompt.top_left,
+        Size::new(layout.prompt.size.width, height.max(0) as u32),
+    )
+}
+
+/// Operation drawing as many of the lines as fit above the status strip,
+/// the next ones taking over on every page turn
+fn line_ops(screen: &Screen, input: &PageInput, lines: &[String]) -> Vec<DrawOp> {
+    let area = text_area(&screen.layout);
+    let per_turn = (area.size.height / screen.line_height.max(1)).max(1) as usize;
+    let turns = lines.len().div_ceil(per_turn).max(1);
+    let shown = lines
+        .chunks(per_turn)
+        .nth(input.page_turn as usize % turns)
+        .map(|chunk| chunk.join("\n"))
+        .unwrap_or_default();
+    vec![DrawOp::text(area, shown)]
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::mono_font::iso_8859_1::FONT_7X13_BOLD;
+    use ssd1306::{prelude::DisplayRotation, size::DisplaySize128x64, Ssd1306};
+
+    use super::*;
+    use crate::{
+        espnow::CornerReading,
+        frame::{Frame, FrameDiff},
+        text_drawer::{NullDisplay, TextDrawer},
+    };
+
+    const ICONS: [StatusIcon; 1] = [StatusIcon::Clock {
+        hours: 12,
+        minutes: 30,
+    }];
+
+    fn screen() -> Screen {
+        Screen {
+            layout: UiLayout::for_display_size(Size::new(128, 64)),
+            line_height: FONT_7X13_BOLD.character_size.height,
+        }
+    }
+
+    fn weighing(grams: f32) -> PageInput<'static> {
+        PageInput {
+            grams: Some(grams),
+            resolution: 0.1,
+            icons: &ICONS,
+            ..PageInput::default()
+        }
+    }
+
+    /// Draws the frames in turn against a display that goes nowhere
+    struct Panel {
+        text_drawer: TextDrawer<'static, NullDisplay, DisplaySize128x64>,
+        frame: Frame,
+    }
+
+    impl Panel {
+        fn new() -> Self {
+            let display = Ssd1306::new(NullDisplay, DisplaySize128x64, DisplayRotation::Rotate0)
+                .into_buffered_graphics_mode();
+            Self {
+                text_drawer: TextDrawer::new(display, &FONT_7X13_BOLD),
+                frame: Frame::default(),
+            }
+        }
+
+        fn draw(&mut self, page: PageId, input: &PageInput) -> FrameDiff {
+            let ops = page.ops(input, &screen());
+            self.frame.draw(&mut self.text_drawer, &ops).unwrap()
+        }
+    }
+
+    #[test]
+    fn weight_change_touches_the_weight_alone() {
+        let layout = screen().layout;
+        let mut panel = Panel::new();
+        assert!(panel.draw(PageId::Weight, &weighing(12.0)).cleared);
+
+        let diff = panel.draw(PageId::Weight, &weighing(12.5));
+        assert_eq!(diff.redrawn, [layout.weight]);
+        assert!(diff.blanked.is_empty());
+        assert!(!diff.touched(layout.status));
+
+        assert!(panel.draw(PageId::Weight, &weighing(12.5)).is_empty());
+    }
+
+    #[test]
+    fn icon_change_touches_the_status_strip_alone() {
+        let layout = screen().layout;
+        let mut panel = Panel::new();
+        panel.draw(PageId::Weight, &weighing(12.0));
+
+        let icons = [ICONS[0], StatusIcon::HeapLow];
+        let diff = panel.draw(
+            PageId::Weight,
+            &PageInput {
+                icons: &icons,
+                ..weighing(12.0)
+            },
+        );
+        assert_eq!(diff.redrawn, [layout.status]);
+        assert!(!diff.touched(layout.weight));
+    }
+
+    #[test]
+    fn hold_tags_the_label_alone() {
+        let layout = screen().layout;
+        let mut panel = Panel::new();
+        panel.draw(PageId::Weight, &weighing(12.0));
+
+        // The held weight is the one shown already
+        let held = PageInput {
+            hold: HoldState::Held {
+                grams: 12.0,
+                auto: false,
+            },
+            ..weighing(12.0)
+        };
+        let diff = panel.draw(PageId::Weight, &held);
+        assert_eq!(
+            diff.redrawn,
+            layout.flow_rate.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn toast_covers_the_icons() {
+        let layout = screen().layout;
+        let mut panel = Panel::new();
+        panel.draw(PageId::Weight, &weighing(12.0));
+
+        let toast = PageInput {
+            toast: Some("Tared"),
+            ..weighing(12.0)
+        };
+        assert_eq!(panel.draw(PageId::Weight, &toast).redrawn, [layout.status]);
+        // The icons come back once it is gone
+        assert_eq!(
+            panel.draw(PageId::Weight, &weighing(12.0)).redrawn,
+            [layout.status]
+        );
+    }
+
+    #[test]
+    fn page_turn_redraws_the_lines_alone() {
+        let screen = screen();
+        let mut panel = Panel::new();
+        let lines: Vec<String> = (0..8).map(|line| format!("Line {}", line)).collect();
+        let diagnostics = |page_turn| PageInput {
+            diagnostics: lines.clone(),
+            page_turn,
+            ..weighing(12.0)
+        };
+        panel.draw(PageId::Diagnostics, &diagnostics(0));
+
+        let diff = panel.draw(PageId::Diagnostics, &diagnostics(1));
+        assert_eq!(diff.redrawn, [text_area(&screen.layout)]);
+        assert!(!diff.touched(screen.layout.status));
+    }
+
+    fn corners(second: CornerReading) -> Aggregate {
+        let first = CornerReading {
+            label: "C1".to_string(),
+            grams: Some(10.0),
+            age_s: Some(0),
+            fresh: true,
+        };
+        Aggregate {
+            grams: first.grams.unwrap_or_default() + second.grams.unwrap_or_default(),
+            valid: second.fresh,
+            stable: second.fresh,
+            corners: vec![first, second],
+        }
+    }
+
+    #[test]
+    fn corner_pages_only_cycle_on_the_aggregator() {
+        assert_eq!(PageId::Weight.next(false), PageId::Flow);
+        assert_eq!(PageId::Weight.next(true), PageId::Total);
+        assert_eq!(PageId::Corners.next(true), PageId::Flow);
+        assert_eq!(PageId::Diagnostics.next(true), PageId::Weight);
+        // Left for the weight page once the role changed
+        assert_eq!(PageId::Total.next(false), PageId::Flow);
+    }
+
+    #[test]
+    fn stale_corner_flags_the_total() {
+        let layout = screen().layout;
+        let mut panel = Panel::new();
+        let fresh = corners(CornerReading {
+            label: "C2".to_string(),
+            grams: Some(5.0),
+            age_s: Some(0),
+            fresh: true,
+        });
+        let input = |aggregate| PageInput {
+            aggregate: Some(aggregate),
+            ..weighing(0.0)
+        };
+        let ops = PageId::Total.ops(&input(&fresh), &screen());
+        assert_eq!(ops[0], DrawOp::text(layout.weight, "15.0"));
+        panel.draw(PageId::Total, &input(&fresh));
+
+        let stale = corners(CornerReading {
+            fresh: false,
+            age_s: Some(3),
+            ..fresh.corners[1].clone()
+        });
+        let ops = PageId::Total.ops(&input(&stale), &screen());
+        assert_eq!(ops[0], DrawOp::text(layout.weight, "15.0?"));
+        let diff = panel.draw(PageId::Total, &input(&stale));
+        assert!(diff.touched(layout.weight));
+        assert!(!diff.touched(layout.status));
+
+        let ops = PageId::Corners.ops(&input(&stale), &screen());
+        assert_eq!(
+            ops[0],
+            DrawOp::text(text_area(&layout), "C1 10.0g\nC2 5.0g 3s?")
+        );
+    }
+
+    #[test]
+    fn switching_to_the_clock_blanks_the_rest() {
+        let layout = screen().layout;
+        let mut panel = Panel::new();
+        panel.draw(PageId::Weight, &weighing(0.0));
+
+        let diff = panel.draw(PageId::Clock, &weighing(0.0));
+        assert_eq!(diff.redrawn, [layout.prompt]);
+        assert!(diff.blanked.contains(&layout.weight));
+        assert!(diff.blanked.contains(&layout.status));
+    }
+}
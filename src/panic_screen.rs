@@ -0,0 +1,199 @@
+//! The panic on the display before the scale restarts, instead of the last
+//! frame frozen on it, so a failure in the field can be read off the screen.
+//! The hook runs whatever state the firmware is in: it never waits long on a
+//! lock the panicking task may hold, and it lays the message out in a fixed
+//! buffer printed through the text mode of the panel, neither of which needs
+//! the heap.
+
+use std::{
+    any::Any,
+    fmt::{self, Write},
+    panic::Location,
+};
+
+#[cfg(all(feature = "esp", feature = "display"))]
+use std::{
+    sync::{Mutex, TryLockError},
+    time::Duration,
+};
+
+#[cfg(all(feature = "esp", feature = "display"))]
+use esp_idf_hal::delay::FreeRtos;
+#[cfg(all(feature = "esp", feature = "display"))]
+use ssd1306::{mode::TerminalDisplaySize, prelude::*, I2CDisplayInterface, Ssd1306};
+
+#[cfg(all(feature = "esp", feature = "display"))]
+use crate::{
+    i2c_bus::SharedI2c,
+    watchdog::{self, WATCHDOG_TIMEOUT},
+};
+
+/// Characters of the 8x8 font of the text mode across the 128 pixel panel
+pub const PANIC_COLUMNS: usize = 16;
+/// Rows of the message on the short panel, the tall one has twice as many
+pub const PANIC_ROWS: usize = 4;
+const MAX_PANIC_ROWS: usize = 2 * PANIC_ROWS;
+/// Longest time the panic may stay on the screen
+pub const MAX_PANIC_HOLD_S: u32 = 600;
+
+/// Attempts at taking the bus from a task in the middle of a transfer
+#[cfg(all(feature = "esp", feature = "display"))]
+const BUS_ATTEMPTS: u32 = 10;
+#[cfg(all(feature = "esp", feature = "display"))]
+const BUS_RETRY_MS: u32 = 10;
+
+/// Text laid out in rows of the panel, in a buffer of its own. Writing past
+/// the last row fails, which stops the formatting early.
+pub struct PanicText {
+    cells: [u8; PANIC_COLUMNS * MAX_PANIC_ROWS],
+    rows: usize,
+    /// Next cell written to
+    cursor: usize,
+}
+
+impl PanicText {
+    pub fn new(rows: usize) -> Self {
+        Self {
+            cells: [b' '; PANIC_COLUMNS * MAX_PANIC_ROWS],
+            rows: rows.min(MAX_PANIC_ROWS),
+            cursor: 0,
+        }
+    }
+
+    /// The rows one after the other, padded with spaces
+    pub fn as_str(&self) -> &str {
+        // Only ASCII is ever written
+        std::str::from_utf8(&self.cells[..self.rows * PANIC_COLUMNS]).unwrap_or_default()
+    }
+}
+
+impl Write for PanicText {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let end = self.rows * PANIC_COLUMNS;
+        for c in text.chars() {
+            if self.cursor >= end {
+                return Err(fmt::Error);
+            }
+            match c {
+                '\n' => self.cursor = (self.cursor / PANIC_COLUMNS + 1) * PANIC_COLUMNS,
+                // The font of the panel only has ASCII
+                c if c == ' ' || c.is_ascii_graphic() => {
+                    self.cells[self.cursor] = c as u8;
+                    self.cursor += 1;
+                }
+                _ => {
+                    self.cells[self.cursor] = b'?';
+                    self.cursor += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the message of the panic, then where it happened
+pub fn write_panic(
+    text: &mut impl Write,
+    location: Option<&Location<'_>>,
+    payload: &(dyn Any + Send),
+) -> fmt::Result {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("panic");
+    writeln!(text, "PANIC {}", message)?;
+    if let Some(location) = location {
+        write!(text, "{}:{}", location.file(), location.line())?;
+    }
+    Ok(())
+}
+
+/// The panel to show a panic on
+#[cfg(all(feature = "esp", feature = "display"))]
+pub struct PanicScreen {
+    pub bus: SharedI2c,
+    pub address: u8,
+    /// Whether it is the 64 pixel tall panel
+    pub tall: bool,
+    pub rotation: DisplayRotation,
+    /// Time the panic stays on the screen before the restart
+    pub hold: Duration,
+}
+
+/// Set up when the display was found, reached from the panic hook
+#[cfg(all(feature = "esp", feature = "display"))]
+static PANIC_SCREEN: Mutex<Option<PanicScreen>> = Mutex::new(None);
+
+/// Show any later panic on the panel, after the hooks installed so far ran.
+/// Install it once, after the reset log, so the message is saved before
+/// the screen is tried.
+#[cfg(all(feature = "esp", feature = "display"))]
+pub fn install(screen: PanicScreen) {
+    *PANIC_SCREEN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(screen);
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        previous(panic_info);
+        show(panic_info.location(), panic_info.payload());
+        // Returning aborts, which restarts the scale with the panic as the
+        // reset reason
+    }));
+}
+
+#[cfg(all(feature = "esp", feature = "display"))]
+fn show(location: Option<&Location<'_>>, payload: &(dyn Any + Send)) {
+    let screen = match PANIC_SCREEN.try_lock() {
+        Ok(screen) => screen,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    let Some(screen) = screen.as_ref() else {
+        return;
+    };
+    // A bus that stays taken is held by the panicking task itself
+    let Some(mut bus) = (0..BUS_ATTEMPTS).find_map(|_| {
+        screen.bus.try_lock().or_else(|| {
+            FreeRtos::delay_ms(BUS_RETRY_MS);
+            None
+        })
+    }) else {
+        return;
+    };
+    // The tasks waiting on the bus stop feeding the watchdog, which must not
+    // restart the scale before the hold is over
+    let _ = watchdog::configure(screen.hold + WATCHDOG_TIMEOUT);
+
+    let rows = if screen.tall {
+        MAX_PANIC_ROWS
+    } else {
+        PANIC_ROWS
+    };
+    let mut text = PanicText::new(rows);
+    // Running out of rows only cuts the message short
+    let _ = write_panic(&mut text, location, payload);
+
+    let interface = I2CDisplayInterface::new_custom_address(&mut *bus, screen.address);
+    let shown = if screen.tall {
+        print(interface, DisplaySize128x64, screen.rotation, text.as_str())
+    } else {
+        print(interface, DisplaySize128x32, screen.rotation, text.as_str())
+    };
+    // The bus stays taken until the restart, nothing draws over the panic
+    if shown {
+        FreeRtos::delay_ms(screen.hold.as_millis() as u32);
+    }
+}
+
+#[cfg(all(feature = "esp", feature = "display"))]
+fn print<DI, SIZE>(interface: DI, size: SIZE, rotation: DisplayRotation, text: &str) -> bool
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: TerminalDisplaySize,
+{
+    let mut display = Ssd1306::new(interface, size, rotation).into_terminal_mode();
+    display.init().is_ok()
+        && display.clear().is_ok()
+        && text.chars().try_for_each(|c| display.print_char(c)).is_ok()
+}
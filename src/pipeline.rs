@@ -0,0 +1,157 @@
+//! The weighing of the readings once in grams: the filter along with its
+//! stability, the creep taken off and the zero followed. The scale drives it
+//! with the live readings, a trace replay with recorded ones, so both weigh
+//! the same way.
+
+use std::time::Instant;
+
+use crate::{calibration::ZeroTracker, creep::CreepCompensator, filter::WeightFilter};
+
+/// A reading through the pipeline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Weighed {
+    /// Filtered weight above the zero, with its creep
+    pub filtered: f32,
+    pub stable: bool,
+    /// Creep predicted, taken off the filtered weight
+    pub creep: f32,
+    /// Weight above the zero
+    pub gross: f32,
+    /// Grams the zero tracking asks to move the zero by. The driver moves
+    /// it, then `rebase` takes the window along.
+    pub zero: Option<f32>,
+}
+
+pub struct Pipeline {
+    pub filter: WeightFilter,
+    pub creep: CreepCompensator,
+    zero_tracker: ZeroTracker,
+    /// Whether the zero follows a slow drift near zero
+    zero_tracking: bool,
+    /// Whether the creep predicted is taken off
+    creep_compensation: bool,
+}
+
+impl Pipeline {
+    pub fn new(filter: WeightFilter, creep: CreepCompensator) -> Self {
+        Self {
+            filter,
+            creep,
+            zero_tracker: ZeroTracker::default(),
+            zero_tracking: true,
+            creep_compensation: true,
+        }
+    }
+
+    /// Follow the zero or leave it where the last tare put it
+    pub fn set_zero_tracking(&mut self, enabled: bool) {
+        if !enabled {
+            self.zero_tracker = ZeroTracker::default();
+        }
+        self.zero_tracking = enabled;
+    }
+
+    /// Take the creep off the weight or leave the weight as it is read
+    pub fn set_creep_compensation(&mut self, enabled: bool) {
+        if !enabled {
+            self.creep.reset();
+        }
+        self.creep_compensation = enabled;
+    }
+
+    /// Weigh a reading of `grams` above the zero, counting for `weight` in
+    /// the filter. The creep is only taken off with `compensate_creep`, a
+    /// demo signal does not creep, and while the compensation is on.
+    pub fn push(
+        &mut self,
+        grams: f32,
+        weight: f32,
+        compensate_creep: bool,
+        resolution: f32,
+        now: Instant,
+    ) -> Weighed {
+        let filtered = self.filter.push_weighted(grams, weight);
+        let stable = self.filter.is_stable();
+        let creep = match compensate_creep && self.creep_compensation {
+            true => self.creep.on_sample(filtered, now),
+            false => 0.0,
+        };
+        let gross = filtered - creep;
+        let zero = match self.zero_tracking {
+            true => self.zero_tracker.on_sample(gross, stable, resolution, now),
+            false => None,
+        };
+        Weighed {
+            filtered,
+            stable,
+            creep,
+            gross,
+            zero,
+        }
+    }
+
+    /// The zero moved by `moved` grams under the same load: the weight steps
+    /// by as much at once, along with the window
+    pub fn rebase(&mut self, weighed: &mut Weighed, moved: f32) {
+        self.filter.rebase(-moved);
+        weighed.filtered = self.filter.value();
+        weighed.gross = weighed.filtered - weighed.creep;
+    }
+
+    /// The zero was tared `moved` grams away, under whatever load: the
+    /// readings in the window are taken against the new zero, so the weight
+    /// steps at once instead of sagging over the window, and the stability
+    /// is kept. The weight on the scale counts as settled.
+    pub fn tare(&mut self, moved: f32) {
+        self.filter.rebase(-moved);
+        self.creep.reset();
+    }
+
+    /// Weigh the readings in grams above the zero the trace started at, in
+    /// the time they came. The zero moves by what the tracking asks for.
+    pub fn run<'p>(
+        &'p mut self,
+        readings: impl IntoIterator<Item = (Instant, f32)> + 'p,
+        resolution: f32,
+    ) -> impl Iterator<Item = Weighed> + 'p {
+        let mut zero = 0.0;
+        readings.into_iter().map(move |(at, grams)| {
+            let mut weighed = self.push(grams - zero, 1.0, true, resolution, at);
+            if let Some(moved) = weighed.zero {
+                zero += moved;
+                self.rebase(&mut weighed, moved);
+            }
+            weighed
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn tare_mid_stream_steps_once() {
+        let mut pipeline = Pipeline::new(WeightFilter::new(8, 2.0), CreepCompensator::default());
+        pipeline.set_zero_tracking(false);
+        let start = Instant::now();
+        let mut zero = 0.0;
+        let mut outputs = Vec::new();
+        for i in 0..32 {
+            if i == 16 {
+                // Tared under the load, as the scale applies a tare
+                pipeline.tare(250.0 - zero);
+                zero = 250.0;
+            }
+            let now = start + Duration::from_millis(100 * i);
+            let weighed = pipeline.push(250.0 - zero, 1.0, true, 1.0, now);
+            outputs.push((weighed.gross, weighed.stable));
+        }
+        let (before, after) = outputs.split_at(16);
+        assert!(before[8..].iter().all(|&output| output == (250.0, true)));
+        // The first reading after the tare is at zero already, and stable
+        assert!(after.iter().all(|&output| output == (0.0, true)));
+    }
+}
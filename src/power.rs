@@ -0,0 +1,569 @@
+//! Light sleep of a battery powered scale while it stands empty. The HX711
+//! keeps converting at its 10 samples per second, and its data ready pin
+//! going low at the end of a conversion wakes the chip: the readings of the
+//! next few conversions are checked, and only a weight moved past the wake
+//! threshold brings the display back. Otherwise the chip goes back to sleep
+//! until the next conversion. A press of the button wakes it too.
+//!
+//! Apart from it, the idle stages dim the display, turn it off and put the
+//! scale into deep sleep as the time since the last activity grows past
+//! their timeouts. Any activity brings the scale back to full brightness,
+//! the button wakes it from the deep sleep. Only a button on an RTC GPIO
+//! can, on another one the display stays off instead of sleeping.
+//!
+//! A schedule of daily windows keeps the scale awake only during them, once
+//! the wall clock is known. Outside of them it goes into deep sleep with a
+//! timer waking it at the start of the next window, and the button wakes it
+//! for a grace period.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "esp")]
+use esp_idf_sys::{
+    esp, esp_deep_sleep_start, esp_light_sleep_start, esp_sleep_enable_ext0_wakeup,
+    esp_sleep_enable_gpio_wakeup, esp_sleep_enable_timer_wakeup, gpio_int_type_t_GPIO_INTR_DISABLE,
+    gpio_int_type_t_GPIO_INTR_LOW_LEVEL, gpio_set_intr_type, gpio_wakeup_disable,
+    gpio_wakeup_enable, rtc_gpio_pulldown_dis, rtc_gpio_pullup_en, EspError,
+};
+#[cfg(not(feature = "esp"))]
+use log::info;
+#[cfg(feature = "esp")]
+use log::warn;
+
+/// Readings checked after each wake
+pub const WAKE_READINGS: usize = 3;
+/// Readings dropped first, the first conversion read after a wake is
+/// clocked out while the clocks settle and may be off
+const SETTLING_READINGS: usize = 1;
+/// Longest light sleep, bounding the time a dead sensor keeps the chip
+/// asleep for
+#[cfg(feature = "esp")]
+pub const MAX_LIGHT_SLEEP: Duration = Duration::from_millis(500);
+/// Range of the timeouts of the idle stages
+pub const MIN_IDLE_TIMEOUT_S: u32 = 10;
+pub const MAX_IDLE_TIMEOUT_S: u32 = 24 * 60 * 60;
+/// Windows a schedule holds
+pub const MAX_SCHEDULE_WINDOWS: usize = 4;
+/// Time the button keeps the scale awake outside of the windows
+pub const SCHEDULE_GRACE: Duration = Duration::from_secs(10 * 60);
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+const SECS_PER_WEEK: i64 = 7 * SECS_PER_DAY;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Tells from the readings taken after a wake whether the weight moved past
+/// the threshold since the scale dozed off
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WakeCheck {
+    /// Weight the scale dozed off at
+    baseline_grams: f32,
+    threshold_grams: f32,
+    /// Readings taken since the wake
+    readings: usize,
+    sum_grams: f32,
+}
+
+impl WakeCheck {
+    pub fn new(baseline_grams: f32, threshold_grams: f32) -> Self {
+        Self {
+            baseline_grams,
+            threshold_grams,
+            readings: 0,
+            sum_grams: 0.0,
+        }
+    }
+
+    /// Start over for the readings after another wake
+    pub fn restart(&mut self) {
+        self.readings = 0;
+        self.sum_grams = 0.0;
+    }
+
+    /// Take a reading in grams. Returns whether to wake up once enough
+    /// readings came in, their mean tells.
+    pub fn on_reading(&mut self, grams: f32) -> Option<bool> {
+        self.readings += 1;
+        if self.readings <= SETTLING_READINGS {
+            return None;
+        }
+        self.sum_grams += grams;
+        let taken = self.readings - SETTLING_READINGS;
+        if taken < WAKE_READINGS {
+            return None;
+        }
+        let mean = self.sum_grams / taken as f32;
+        Some((mean - self.baseline_grams).abs() >= self.threshold_grams)
+    }
+}
+
+/// How far the idle scale went, each stage deeper than the one before
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IdleStage {
+    #[default]
+    Active,
+    /// The display is at its lowest brightness
+    Dimmed,
+    DisplayOff,
+    /// Everything is off but the wakeup from the button
+    DeepSleep,
+}
+
+impl IdleStage {
+    pub fn name(self) -> &'static str {
+        match self {
+            IdleStage::Active => "active",
+            IdleStage::Dimmed => "dimmed",
+            IdleStage::DisplayOff => "display off",
+            IdleStage::DeepSleep => "deep sleep",
+        }
+    }
+}
+
+/// Time since the last activity each stage starts after, none for a stage
+/// that is skipped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IdleTimeouts {
+    pub dim: Option<Duration>,
+    pub display_off: Option<Duration>,
+    pub deep_sleep: Option<Duration>,
+}
+
+impl IdleTimeouts {
+    /// Deepest stage whose timeout passed after being idle for `idle`
+    fn stage_after(&self, idle: Duration) -> IdleStage {
+        [
+            (self.deep_sleep, IdleStage::DeepSleep),
+            (self.display_off, IdleStage::DisplayOff),
+            (self.dim, IdleStage::Dimmed),
+        ]
+        .into_iter()
+        .find(|(timeout, _)| timeout.is_some_and(|timeout| idle >= timeout))
+        .map_or(IdleStage::Active, |(_, stage)| stage)
+    }
+}
+
+/// Follows the activity of the scale, the button, a change of the weight
+/// or a command from the network, and tells when to go to another stage
+pub struct IdleStages {
+    timeouts: IdleTimeouts,
+    last_activity: Instant,
+    stage: IdleStage,
+}
+
+impl IdleStages {
+    pub fn new(timeouts: IdleTimeouts, now: Instant) -> Self {
+        Self {
+            timeouts,
+            last_activity: now,
+            stage: IdleStage::Active,
+        }
+    }
+
+    /// Takes effect on the next poll, which leaves a stage that was
+    /// disabled
+    pub fn configure(&mut self, timeouts: IdleTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn stage(&self) -> IdleStage {
+        self.stage
+    }
+
+    /// Start the idle time over, the next poll brings the scale back
+    pub fn on_activity(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// Returns the stage to go to, when it is another one than the current
+    pub fn poll(&mut self, now: Instant) -> Option<IdleStage> {
+        let stage = self
+            .timeouts
+            .stage_after(now.saturating_duration_since(self.last_activity));
+        (stage != self.stage).then(|| {
+            self.stage = stage;
+            stage
+        })
+    }
+}
+
+/// Days of the week a window opens on, bit 0 for Monday
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Weekdays(u8);
+
+impl Weekdays {
+    pub const ALL: Weekdays = Weekdays(0x7f);
+
+    /// Days from their names, `daily`, or ranges and lists of their short
+    /// names such as `mon-fri` or `mon,wed,sat-sun`
+    pub fn from_names(names: &str) -> Option<Self> {
+        if names.eq_ignore_ascii_case("daily") {
+            return Some(Self::ALL);
+        }
+        let day = |name: &str| {
+            DAY_NAMES
+                .iter()
+                .position(|day| day.eq_ignore_ascii_case(name))
+        };
+        let mut bits = 0;
+        for part in names.split(',') {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let (first, last) = (day(first)?, day(last)?);
+            if first > last {
+                return None;
+            }
+            bits |= (first..=last).fold(0, |bits, day| bits | 1 << day);
+        }
+        Some(Self(bits))
+    }
+
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        (bits != 0 && bits & !Self::ALL.0 == 0).then_some(Self(bits))
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether the day, 0 for Monday, is one of them
+    pub fn contains(self, day: usize) -> bool {
+        self.0 & 1 << day != 0
+    }
+}
+
+impl fmt::Display for Weekdays {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::ALL {
+            return write!(f, "daily");
+        }
+        let days: Vec<&str> = (0..DAY_NAMES.len())
+            .filter(|&day| self.contains(day))
+            .map(|day| DAY_NAMES[day])
+            .collect();
+        write!(f, "{}", days.join(","))
+    }
+}
+
+/// Time of the day the scale is awake, opening on each of its days. A
+/// window ending before it starts runs past midnight into the next day, one
+/// ending when it starts lasts the whole day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ActiveWindow {
+    pub days: Weekdays,
+    /// Minutes since midnight, local time
+    pub start_minute: u16,
+    pub end_minute: u16,
+}
+
+impl ActiveWindow {
+    pub fn new(days: Weekdays, start_minute: u16, end_minute: u16) -> Option<Self> {
+        (start_minute < MINUTES_PER_DAY && end_minute < MINUTES_PER_DAY).then_some(Self {
+            days,
+            start_minute,
+            end_minute,
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; 5] {
+        let [start_lo, start_hi] = self.start_minute.to_le_bytes();
+        let [end_lo, end_hi] = self.end_minute.to_le_bytes();
+        [self.days.bits(), start_lo, start_hi, end_lo, end_hi]
+    }
+
+    pub fn from_bytes(bytes: [u8; 5]) -> Option<Self> {
+        Self::new(
+            Weekdays::from_bits(bytes[0])?,
+            u16::from_le_bytes([bytes[1], bytes[2]]),
+            u16::from_le_bytes([bytes[3], bytes[4]]),
+        )
+    }
+
+    fn length_secs(&self) -> i64 {
+        let minutes = (i64::from(self.end_minute) - i64::from(self.start_minute))
+            .rem_euclid(i64::from(MINUTES_PER_DAY));
+        match minutes {
+            0 => SECS_PER_DAY,
+            minutes => minutes * 60,
+        }
+    }
+
+    /// Seconds into the week it opens at on each of its days
+    fn openings(&self) -> impl Iterator<Item = i64> + '_ {
+        (0..DAY_NAMES.len())
+            .filter(|&day| self.days.contains(day))
+            .map(|day| day as i64 * SECS_PER_DAY + i64::from(self.start_minute) * 60)
+    }
+}
+
+impl fmt::Display for ActiveWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:02}:{:02}-{:02}:{:02}",
+            self.days,
+            self.start_minute / 60,
+            self.start_minute % 60,
+            self.end_minute / 60,
+            self.end_minute % 60
+        )
+    }
+}
+
+/// Windows the scale is awake in, always awake without any
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<ActiveWindow>,
+}
+
+impl Schedule {
+    pub fn new(windows: Vec<ActiveWindow>) -> Self {
+        Self { windows }
+    }
+
+    pub fn windows(&self) -> &[ActiveWindow] {
+        &self.windows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Whether the local time, in seconds since the epoch, falls in a
+    /// window
+    pub fn is_active(&self, local_secs: i64) -> bool {
+        let now = secs_of_week(local_secs);
+        self.is_empty()
+            || self.windows.iter().any(|window| {
+                window
+                    .openings()
+                    .any(|opening| (now - opening).rem_euclid(SECS_PER_WEEK) < window.length_secs())
+            })
+    }
+
+    /// Time from the local time to the start of the next window, none
+    /// without any
+    pub fn until_next_start(&self, local_secs: i64) -> Option<Duration> {
+        let now = secs_of_week(local_secs);
+        self.windows
+            .iter()
+            .flat_map(ActiveWindow::openings)
+            .map(|opening| match (opening - now).rem_euclid(SECS_PER_WEEK) {
+                0 => SECS_PER_WEEK,
+                wait => wait,
+            })
+            .min()
+            .map(|wait| Duration::from_secs(wait as u64))
+    }
+}
+
+/// Seconds since Monday midnight, 1970-01-01 was a Thursday
+fn secs_of_week(local_secs: i64) -> i64 {
+    (local_secs + 3 * SECS_PER_DAY).rem_euclid(SECS_PER_WEEK)
+}
+
+/// Tells when the schedule sends the scale to sleep, holding off for a
+/// grace period after the boot and each press of the button
+pub struct ScheduleGate {
+    schedule: Schedule,
+    grace_until: Instant,
+}
+
+impl ScheduleGate {
+    /// The scale just started, woken by the button or the timer or powered
+    /// on, the grace period starts
+    pub fn new(schedule: Schedule, now: Instant) -> Self {
+        Self {
+            schedule,
+            grace_until: now + SCHEDULE_GRACE,
+        }
+    }
+
+    pub fn configure(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+    }
+
+    pub fn on_button(&mut self, now: Instant) {
+        self.grace_until = now + SCHEDULE_GRACE;
+    }
+
+    /// Time to sleep for until the next window, when the scale is outside
+    /// of the windows and past the grace period. Nothing happens before the
+    /// local time is known.
+    pub fn sleep_for(&self, local_secs: Option<i64>, now: Instant) -> Option<Duration> {
+        let local_secs = local_secs?;
+        if now < self.grace_until || self.schedule.is_active(local_secs) {
+            return None;
+        }
+        self.schedule.until_next_start(local_secs)
+    }
+}
+
+/// Sleep lightly until the data ready pin of the HX711 or the button goes
+/// low, or `MAX_LIGHT_SLEEP` passed. The tasks stop along with the CPU, and
+/// the Wi-Fi connection may drop.
+#[cfg(feature = "esp")]
+pub fn light_sleep(hx711_dt: u8, button: u8) -> Result<(), EspError> {
+    let pins = [i32::from(hx711_dt), i32::from(button)];
+    unsafe {
+        for pin in pins {
+            esp!(gpio_wakeup_enable(pin, gpio_int_type_t_GPIO_INTR_LOW_LEVEL))?;
+        }
+        esp!(esp_sleep_enable_gpio_wakeup())?;
+        esp!(esp_sleep_enable_timer_wakeup(
+            MAX_LIGHT_SLEEP.as_micros() as u64
+        ))?;
+        let slept = esp!(esp_light_sleep_start());
+        // Both pins are polled, the wake source left them with a level
+        // interrupt type
+        for pin in pins {
+            esp!(gpio_wakeup_disable(pin))?;
+            esp!(gpio_set_intr_type(pin, gpio_int_type_t_GPIO_INTR_DISABLE))?;
+        }
+        slept
+    }
+}
+
+/// Whether a button on the GPIO wakes the chip from deep sleep. Only the
+/// RTC GPIOs can, through ext0.
+pub fn can_wake_from_deep_sleep(gpio: u8) -> bool {
+    matches!(gpio, 0 | 2 | 4 | 12..=15 | 25..=27 | 32..=39)
+}
+
+/// Have a press of the button wake the chip from deep sleep, with the RTC
+/// pull-up holding the line high while the digital pads are off
+#[cfg(feature = "esp")]
+fn enable_button_wake(button: u8) -> Result<(), EspError> {
+    let pin = i32::from(button);
+    unsafe {
+        esp!(rtc_gpio_pullup_en(pin))?;
+        esp!(rtc_gpio_pulldown_dis(pin))?;
+        esp!(esp_sleep_enable_ext0_wakeup(pin, 0))
+    }
+}
+
+/// Go into deep sleep until the button is pressed, which restarts the scale.
+/// A button that cannot wake the chip is logged and the scale sleeps
+/// anyway, until a reset or a power cycle.
+#[cfg(feature = "esp")]
+pub fn deep_sleep(button: u8) -> ! {
+    if !can_wake_from_deep_sleep(button) {
+        warn!(
+            "The button on GPIO{} cannot wake the scale, only a reset will",
+            button
+        );
+    } else if let Err(err) = enable_button_wake(button) {
+        warn!("Failed to have the button wake the scale: {:?}", err);
+    }
+    unsafe { esp_deep_sleep_start() }
+}
+
+/// Go into deep sleep until the button is pressed or the time passed,
+/// either restarts the scale. The timer wakes the scale even when the
+/// button cannot.
+#[cfg(feature = "esp")]
+pub fn deep_sleep_for(button: u8, wake_after: Duration) -> ! {
+    if let Err(err) = unsafe { esp!(esp_sleep_enable_timer_wakeup(wake_after.as_micros() as u64)) }
+    {
+        warn!("Failed to set the wake up timer: {:?}", err);
+    }
+    deep_sleep(button)
+}
+
+/// The simulated sensor always has a conversion ready, which wakes the
+/// chip right away
+#[cfg(not(feature = "esp"))]
+pub fn light_sleep(_hx711_dt: u8, _button: u8) -> Result<(), std::convert::Infallible> {
+    Ok(())
+}
+
+/// Nothing wakes the host, the simulator exits instead
+#[cfg(not(feature = "esp"))]
+pub fn deep_sleep(_button: u8) -> ! {
+    info!("Deep sleep, exiting");
+    std::process::exit(0)
+}
+
+#[cfg(not(feature = "esp"))]
+pub fn deep_sleep_for(button: u8, wake_after: Duration) -> ! {
+    info!("Deep sleep for {}s, exiting", wake_after.as_secs());
+    deep_sleep(button)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1970-01-05, the first Monday after the epoch
+    const MONDAY: i64 = 4 * SECS_PER_DAY;
+
+    /// Local time on the day of the week, 0 for Monday, at the hour and
+    /// minute
+    fn at(day: i64, hours: i64, minutes: i64) -> i64 {
+        MONDAY + day * SECS_PER_DAY + hours * 3600 + minutes * 60
+    }
+
+    fn schedule(days: &str, start: (u16, u16), end: (u16, u16)) -> Schedule {
+        let days = Weekdays::from_names(days).unwrap();
+        let window = ActiveWindow::new(days, start.0 * 60 + start.1, end.0 * 60 + end.1).unwrap();
+        Schedule::new(vec![window])
+    }
+
+    #[test]
+    fn window_crossing_midnight() {
+        let schedule = schedule("mon", (22, 0), (6, 0));
+        assert!(!schedule.is_active(at(0, 21, 59)));
+        assert!(schedule.is_active(at(0, 23, 0)));
+        assert!(schedule.is_active(at(1, 5, 59)));
+        assert!(!schedule.is_active(at(1, 6, 0)));
+        assert_eq!(
+            schedule.until_next_start(at(1, 6, 0)),
+            Some(Duration::from_secs(6 * SECS_PER_DAY as u64 + 16 * 3600))
+        );
+    }
+
+    #[test]
+    fn sunday_to_monday_rollover() {
+        let sunday = schedule("sun", (23, 0), (1, 0));
+        assert!(sunday.is_active(at(6, 23, 30)));
+        assert!(sunday.is_active(at(7, 0, 30)));
+        assert!(!sunday.is_active(at(7, 1, 0)));
+
+        let monday = schedule("mon", (8, 0), (9, 0));
+        assert_eq!(
+            monday.until_next_start(at(6, 23, 0)),
+            Some(Duration::from_secs(9 * 3600))
+        );
+    }
+
+    #[test]
+    fn empty_weekday_mask() {
+        assert_eq!(Weekdays::from_bits(0), None);
+        assert_eq!(Weekdays::from_names(""), None);
+        let window = ActiveWindow::new(Weekdays(0), 8 * 60, 18 * 60).unwrap();
+        let schedule = Schedule::new(vec![window]);
+        assert!(!schedule.is_active(at(2, 12, 0)));
+        assert_eq!(schedule.until_next_start(at(2, 12, 0)), None);
+
+        let always = Schedule::default();
+        assert!(always.is_active(at(2, 12, 0)));
+        assert_eq!(always.until_next_start(at(2, 12, 0)), None);
+    }
+
+    #[test]
+    fn now_at_start_and_end() {
+        let schedule = schedule("daily", (8, 0), (18, 0));
+        assert!(schedule.is_active(at(3, 8, 0)));
+        assert!(!schedule.is_active(at(3, 18, 0)));
+        // Opening right now, the next start is the one of tomorrow
+        assert_eq!(
+            schedule.until_next_start(at(3, 8, 0)),
+            Some(Duration::from_secs(SECS_PER_DAY as u64))
+        );
+        assert_eq!(
+            schedule.until_next_start(at(3, 18, 0)),
+            Some(Duration::from_secs(14 * 3600))
+        );
+    }
+}
@@ -0,0 +1,803 @@
+//! Tare, calibration, the linearity check and the noise test as step by step
+//! procedures,
+//! advanced by the main loop on every event instead of blocking it. A
+//! procedure takes at most one reading per step and asks for its prompts to
+//! be shown rather than drawing them, the result is applied to the scale once
+//! it is done.
+
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use thiserror::Error;
+
+use crate::{
+    button::{ButtonEvent, TimedButtonEvent},
+    calibration::Moment,
+    device,
+    events::AppEvent,
+    i18n::{tr, trf, StringId},
+    linearity::{LinearityPoint, LinearityReport},
+    noise::{NoiseReport, NoiseTest, NoiseThresholds, NOISE_NUM_SAMPLES},
+};
+
+/// Readings averaged into the zero, unless the scale was built with another
+/// count
+pub const TARE_NUM_SAMPLES: usize = 16;
+const CALIBRATION_NUM_SAMPLES: usize = 16;
+/// The differences a linearity check looks for are a few counts, so it
+/// averages longer
+const LINEARITY_NUM_SAMPLES: usize = 32;
+/// Readings of a noise test between two updates of its progress
+const NOISE_PROGRESS_STEP: usize = 20;
+/// Time without a reading after which the sensor is given up on
+const READING_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time after a tare within which another one is refused, unless set
+/// otherwise
+pub const DEFAULT_TARE_COOLDOWN: Duration = Duration::from_secs(2);
+pub const MAX_TARE_COOLDOWN: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcedureError {
+    #[error("No reading from the sensor")]
+    NoReading,
+    #[error("Average reading is 0")]
+    ZeroReading,
+    #[error("Cancelled with the button")]
+    Cancelled,
+    #[error("The weights read the same")]
+    NoSpread,
+    #[error("The weight moved, the scale has to stay still")]
+    Disturbed,
+}
+
+/// What the display should show while a procedure runs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UiRequest {
+    /// Show the text instead of anything else
+    Prompt(String),
+    /// Same, with a spinner after the text while the readings come in
+    Busy(String),
+}
+
+/// Shows the prompts of a procedure somewhere, so one can run without a
+/// display
+pub trait Prompter {
+    fn prompt(&mut self, request: &UiRequest);
+}
+
+/// Drops the prompts, for a scale driven by a remote side reporting the
+/// calibration status itself
+pub struct NoopPrompter;
+
+impl Prompter for NoopPrompter {
+    fn prompt(&mut self, _request: &UiRequest) {}
+}
+
+/// Logs the prompts, which the serial console shows, so the procedures can
+/// be followed there alone
+pub struct LogPrompter;
+
+impl Prompter for LogPrompter {
+    fn prompt(&mut self, request: &UiRequest) {
+        match request {
+            UiRequest::Prompt(text) => info!("{}", text),
+            UiRequest::Busy(text) => info!("{}...", text),
+        }
+    }
+}
+
+/// Outcome of a completed procedure, to hand to `Scale::finish`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProcedureResult {
+    Tared { offset: i32 },
+    Calibrated { offset: i32, scale_factor: f32 },
+    Linearity(LinearityReport),
+    Noise(NoiseReport),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProcedureState {
+    /// Still running, along with a new prompt to show if any
+    Running(Option<UiRequest>),
+    Done(ProcedureResult),
+    Failed(ProcedureError),
+}
+
+/// What becomes of a procedure asked for, given the one running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Start,
+    /// A calibration or check asked for during a tare, to start once the
+    /// tare is done
+    Queue,
+    /// A tare during another or within the cool-down of the last one, which
+    /// would zero on a scale still settling
+    AlreadyTared,
+    /// A calibration or check is running
+    Busy,
+}
+
+/// Keeps a nervous double press from taring twice, the second time while
+/// the scale still settles from the first, and holds what was asked for
+/// during a tare until it is done
+#[derive(Debug)]
+pub struct TareGate<Q> {
+    cooldown: Duration,
+    last_tare: Option<Instant>,
+    /// Calibration or check asked for during a tare
+    queued: Option<Q>,
+}
+
+impl<Q> TareGate<Q> {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown: cooldown.min(MAX_TARE_COOLDOWN),
+            last_tare: None,
+            queued: None,
+        }
+    }
+
+    pub fn set_cooldown(&mut self, cooldown: Duration) {
+        self.cooldown = cooldown.min(MAX_TARE_COOLDOWN);
+    }
+
+    /// Whether a tare, or another procedure when `tare` is false, may start
+    /// with `running` still going
+    pub fn admit(&self, tare: bool, running: Option<&Procedure>, now: Instant) -> Admission {
+        match running {
+            Some(running) if running.is_tare() && tare => Admission::AlreadyTared,
+            Some(running) if running.is_tare() => Admission::Queue,
+            Some(_) => Admission::Busy,
+            None if tare && self.cooling_down(now) => Admission::AlreadyTared,
+            None => Admission::Start,
+        }
+    }
+
+    /// A tare completed at `now`, the cool-down starts from there
+    pub fn tared(&mut self, now: Instant) {
+        self.last_tare = Some(now);
+    }
+
+    /// Hold what was admitted as `Admission::Queue` until the tare is over.
+    /// Handed back when something waits already.
+    pub fn queue(&mut self, queued: Q) -> Result<(), Q> {
+        if self.queued.is_some() {
+            return Err(queued);
+        }
+        self.queued = Some(queued);
+        Ok(())
+    }
+
+    /// What waited for the tare, once nothing runs anymore
+    pub fn take_queued(&mut self, running: Option<&Procedure>) -> Option<Q> {
+        match running {
+            Some(_) => None,
+            None => self.queued.take(),
+        }
+    }
+
+    fn cooling_down(&self, now: Instant) -> bool {
+        self.last_tare
+            .is_some_and(|last| now.saturating_duration_since(last) < self.cooldown)
+    }
+}
+
+/// Where a calibration is at, as reported to the remote side driving it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CalibrationStatus {
+    #[default]
+    Idle,
+    /// Waiting for a step once the scale is empty
+    WaitingEmpty,
+    Taring,
+    /// Waiting for a step once the weight is on the scale
+    WaitingWeight {
+        grams: f32,
+    },
+    Weighing,
+    Done {
+        scale_factor: f32,
+    },
+    Failed(ProcedureError),
+}
+
+impl CalibrationStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CalibrationStatus::Idle => "idle",
+            CalibrationStatus::WaitingEmpty => "waiting_empty",
+            CalibrationStatus::Taring => "taring",
+            CalibrationStatus::WaitingWeight { .. } => "waiting_weight",
+            CalibrationStatus::Weighing => "weighing",
+            CalibrationStatus::Done { .. } => "done",
+            CalibrationStatus::Failed(ProcedureError::Cancelled) => "cancelled",
+            CalibrationStatus::Failed(_) => "failed",
+        }
+    }
+
+    /// Whether a calibration is under way
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self,
+            CalibrationStatus::WaitingEmpty
+                | CalibrationStatus::Taring
+                | CalibrationStatus::WaitingWeight { .. }
+                | CalibrationStatus::Weighing
+        )
+    }
+
+    /// Whether the calibration waits for a step to go on
+    pub fn awaits_step(&self) -> bool {
+        matches!(
+            self,
+            CalibrationStatus::WaitingEmpty | CalibrationStatus::WaitingWeight { .. }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Tare,
+    /// Tare the empty scale, then weigh the known weight
+    Calibrate,
+    /// Weigh the known weight already on the tared scale
+    CalibrateWithWeight,
+    /// Tare the empty scale, then weigh the known weights one after the
+    /// other
+    Linearity,
+    /// Collect the readings of the empty scale for their spread
+    Noise,
+}
+
+/// What the readings being averaged are for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Averaged {
+    Zero,
+    Weight,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    /// Waiting for a press before averaging
+    WaitPress(Averaged),
+    Averaging {
+        target: Averaged,
+        sum: i64,
+        count: usize,
+        last_reading: Instant,
+    },
+}
+
+/// A tare or a calibration in progress
+#[derive(Debug)]
+pub struct Procedure {
+    kind: Kind,
+    step: Step,
+    offset: i32,
+    weight_grams: f32,
+    /// Driven from the console or over HTTP: steps stand in for the presses
+    /// and a press cancels it
+    remote: bool,
+    prompt: Option<UiRequest>,
+    /// Known weights of a linearity check, `weight_grams` being the one
+    /// weighed now
+    weights: Vec<f32>,
+    points: Vec<LinearityPoint>,
+    full_scale_grams: f32,
+    /// Whether the button went down since the last step, a linearity check
+    /// goes on at the release so a hold can cancel it
+    pressed: bool,
+    /// Readings averaged into the zero
+    tare_samples: usize,
+    /// Readings of a noise test
+    noise: Option<NoiseTest>,
+}
+
+impl Procedure {
+    pub fn tare() -> Self {
+        info!("Taring scale...");
+        Self {
+            kind: Kind::Tare,
+            step: Step::averaging(Averaged::Zero),
+            offset: 0,
+            weight_grams: 0.0,
+            remote: false,
+            prompt: Some(UiRequest::Busy(tr(StringId::Taring).to_string())),
+            weights: Vec::new(),
+            points: Vec::new(),
+            full_scale_grams: 0.0,
+            pressed: false,
+            tare_samples: TARE_NUM_SAMPLES,
+            noise: None,
+        }
+    }
+
+    pub fn calibrate(weight_grams: f32) -> Self {
+        info!("Starting calibration...");
+        info!("Please remove any weight from the scale and press the button.");
+        Self::calibrate_with_prompts(weight_grams, false)
+    }
+
+    /// Calibrate through steps sent from afar, showing what it waits on
+    /// for anyone at the scale, who can cancel it with a press
+    pub fn calibrate_remote(weight_grams: f32) -> Self {
+        info!(
+            "Starting remote calibration with {} grams, waiting for the scale to be emptied",
+            weight_grams
+        );
+        Self::calibrate_with_prompts(weight_grams, true)
+    }
+
+    fn calibrate_with_prompts(weight_grams: f32, remote: bool) -> Self {
+        let mut procedure = Self {
+            kind: Kind::Calibrate,
+            step: Step::WaitPress(Averaged::Zero),
+            offset: 0,
+            weight_grams,
+            remote,
+            prompt: None,
+            weights: Vec::new(),
+            points: Vec::new(),
+            full_scale_grams: 0.0,
+            pressed: false,
+            tare_samples: TARE_NUM_SAMPLES,
+            noise: None,
+        };
+        procedure.prompt = Some(procedure.wait_prompt(Averaged::Zero));
+        procedure
+    }
+
+    pub fn calibrate_with_weight(weight_grams: f32, offset: i32) -> Self {
+        debug!("Calibrating for {} samples...", CALIBRATION_NUM_SAMPLES);
+        Self {
+            kind: Kind::CalibrateWithWeight,
+            step: Step::averaging(Averaged::Weight),
+            offset,
+            weight_grams,
+            remote: false,
+            prompt: Some(UiRequest::Busy(tr(StringId::Calibrating).to_string())),
+            weights: Vec::new(),
+            points: Vec::new(),
+            full_scale_grams: 0.0,
+            pressed: false,
+            tare_samples: TARE_NUM_SAMPLES,
+            noise: None,
+        }
+    }
+
+    /// Check how far the readings of the known weights stray from a
+    /// straight line, in grams and as a share of `full_scale_grams`. A
+    /// press goes on and a hold cancels, at any step.
+    pub fn linearity(weights: &[f32], full_scale_grams: f32) -> Self {
+        info!("Starting linearity check with {:?} grams", weights);
+        info!("Please remove any weight from the scale and press the button.");
+        let mut procedure = Self {
+            kind: Kind::Linearity,
+            step: Step::WaitPress(Averaged::Zero),
+            offset: 0,
+            weight_grams: weights.first().copied().unwrap_or_default(),
+            remote: false,
+            prompt: None,
+            weights: weights.to_vec(),
+            points: Vec::with_capacity(weights.len() + 1),
+            full_scale_grams,
+            pressed: false,
+            tare_samples: TARE_NUM_SAMPLES,
+            noise: None,
+        };
+        procedure.prompt = Some(procedure.wait_prompt(Averaged::Zero));
+        procedure
+    }
+
+    /// Measure the noise of the empty scale, in grams too when calibrated.
+    /// A press cancels it.
+    pub fn noise(thresholds: NoiseThresholds, scale_factor: Option<f32>) -> Self {
+        info!(
+            "Measuring the noise over {} readings, keep the scale empty and still",
+            NOISE_NUM_SAMPLES
+        );
+        Self {
+            kind: Kind::Noise,
+            step: Step::averaging(Averaged::Zero),
+            offset: 0,
+            weight_grams: 0.0,
+            remote: false,
+            prompt: Some(Self::noise_prompt(0)),
+            weights: Vec::new(),
+            points: Vec::new(),
+            full_scale_grams: 0.0,
+            pressed: false,
+            tare_samples: TARE_NUM_SAMPLES,
+            noise: Some(NoiseTest::new(thresholds, scale_factor)),
+        }
+    }
+
+    /// Average `samples` readings into the zero instead of
+    /// `TARE_NUM_SAMPLES`
+    pub fn with_tare_samples(mut self, samples: usize) -> Self {
+        self.tare_samples = samples.max(1);
+        self
+    }
+
+    /// Whether the procedure ends in a new scale factor
+    pub fn is_tare(&self) -> bool {
+        self.kind == Kind::Tare
+    }
+
+    pub fn is_calibration(&self) -> bool {
+        matches!(self.kind, Kind::Calibrate | Kind::CalibrateWithWeight)
+    }
+
+    pub fn is_linearity(&self) -> bool {
+        self.kind == Kind::Linearity
+    }
+
+    pub fn is_noise(&self) -> bool {
+        self.kind == Kind::Noise
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.remote
+    }
+
+    pub fn status(&self) -> CalibrationStatus {
+        match self.step {
+            Step::WaitPress(Averaged::Zero) => CalibrationStatus::WaitingEmpty,
+            Step::WaitPress(Averaged::Weight) => CalibrationStatus::WaitingWeight {
+                grams: self.weight_grams,
+            },
+            Step::Averaging {
+                target: Averaged::Zero,
+                ..
+            } => CalibrationStatus::Taring,
+            Step::Averaging {
+                target: Averaged::Weight,
+                ..
+            } => CalibrationStatus::Weighing,
+        }
+    }
+
+    /// End the wait as a press would. Returns whether it was waiting.
+    pub fn step(&mut self) -> bool {
+        let Step::WaitPress(target) = self.step else {
+            return false;
+        };
+        self.step = Step::averaging(target);
+        self.pressed = false;
+        self.prompt = Some(match target {
+            Averaged::Zero => UiRequest::Busy(tr(StringId::Taring).to_string()),
+            Averaged::Weight if self.kind == Kind::Linearity => {
+                UiRequest::Busy(tr(StringId::Weighing).to_string())
+            }
+            Averaged::Weight => UiRequest::Busy(tr(StringId::Calibrating).to_string()),
+        });
+        true
+    }
+
+    /// Move on with the event, if any: a reading is added to the average,
+    /// a press ends a wait, or cancels a remote calibration. A linearity
+    /// check goes on once the button is released and a hold cancels it.
+    pub fn advance(&mut self, event: Option<AppEvent>) -> ProcedureState {
+        let pressed = matches!(
+            event,
+            Some(AppEvent::Button(TimedButtonEvent {
+                event: ButtonEvent::Down,
+                ..
+            }))
+        );
+        if self.remote && pressed {
+            info!("Remote calibration cancelled with the button");
+            return ProcedureState::Failed(ProcedureError::Cancelled);
+        }
+        if self.kind == Kind::Noise {
+            return self.advance_noise(event, pressed);
+        }
+        if let (Kind::Linearity, Some(AppEvent::Button(TimedButtonEvent { event, .. }))) =
+            (self.kind, event)
+        {
+            match (event, self.step) {
+                (ButtonEvent::Held, _) | (ButtonEvent::Down, Step::Averaging { .. }) => {
+                    info!("Linearity check cancelled with the button");
+                    return ProcedureState::Failed(ProcedureError::Cancelled);
+                }
+                (ButtonEvent::Down, Step::WaitPress(_)) => self.pressed = true,
+                (ButtonEvent::Up, Step::WaitPress(_)) if self.pressed => {
+                    self.step();
+                }
+                _ => {}
+            }
+            return ProcedureState::Running(self.prompt.take());
+        }
+        match (&mut self.step, event) {
+            (Step::WaitPress(_), _) if pressed => {
+                self.step();
+            }
+            (
+                Step::Averaging {
+                    target,
+                    sum,
+                    count,
+                    last_reading,
+                },
+                Some(AppEvent::Reading { raw, at, .. }),
+            ) => {
+                *sum += i64::from(raw);
+                *count += 1;
+                *last_reading = at;
+                let samples = match target {
+                    _ if self.kind == Kind::Linearity => LINEARITY_NUM_SAMPLES,
+                    Averaged::Zero => self.tare_samples,
+                    Averaged::Weight => CALIBRATION_NUM_SAMPLES,
+                };
+                if *count >= samples {
+                    let average = (*sum as f64 / *count as f64) as f32;
+                    let target = *target;
+                    return self.averaged(target, average);
+                }
+            }
+            (Step::Averaging { last_reading, .. }, _)
+                if last_reading.elapsed() >= READING_TIMEOUT =>
+            {
+                warn!("No reading from the sensor for {:?}", READING_TIMEOUT);
+                return ProcedureState::Failed(ProcedureError::NoReading);
+            }
+            _ => {}
+        }
+        ProcedureState::Running(self.prompt.take())
+    }
+
+    /// Collect the reading for the noise test, which a press or a moving
+    /// weight ends early
+    fn advance_noise(&mut self, event: Option<AppEvent>, pressed: bool) -> ProcedureState {
+        let (Some(test), Step::Averaging { last_reading, .. }) =
+            (self.noise.as_mut(), &mut self.step)
+        else {
+            return ProcedureState::Failed(ProcedureError::NoReading);
+        };
+        if pressed {
+            info!("Noise test cancelled with the button");
+            return ProcedureState::Failed(ProcedureError::Cancelled);
+        }
+        match event {
+            Some(AppEvent::Reading { raw, at, .. }) => {
+                *last_reading = at;
+                if test.add(raw).is_err() {
+                    warn!("Noise test aborted, the weight moved");
+                    return ProcedureState::Failed(ProcedureError::Disturbed);
+                }
+                if test.is_complete() {
+                    let report = test.report(Moment::now(device::identity().boot()));
+                    info!(
+                        "Noise test complete. {:.1} counts RMS, {} peak to peak, {}",
+                        report.rms_counts,
+                        report.peak_to_peak_counts,
+                        report.verdict.name()
+                    );
+                    return ProcedureState::Done(ProcedureResult::Noise(report));
+                }
+                if test.count() % NOISE_PROGRESS_STEP == 0 {
+                    self.prompt = Some(Self::noise_prompt(test.count()));
+                }
+            }
+            _ if last_reading.elapsed() >= READING_TIMEOUT => {
+                warn!("No reading from the sensor for {:?}", READING_TIMEOUT);
+                return ProcedureState::Failed(ProcedureError::NoReading);
+            }
+            _ => {}
+        }
+        ProcedureState::Running(self.prompt.take())
+    }
+
+    fn noise_prompt(count: usize) -> UiRequest {
+        UiRequest::Busy(
+            trf(
+                StringId::MeasuringNoise,
+                &[&(count * 100 / NOISE_NUM_SAMPLES)],
+            )
+            .to_string(),
+        )
+    }
+
+    /// Move on once the readings for `target` are averaged
+    fn averaged(&mut self, target: Averaged, average: f32) -> ProcedureState {
+        match target {
+            Averaged::Zero => {
+                self.offset = average.round() as i32;
+                info!("Tare complete.");
+                if self.kind == Kind::Tare {
+                    return ProcedureState::Done(ProcedureResult::Tared {
+                        offset: self.offset,
+                    });
+                }
+                if self.kind == Kind::Linearity {
+                    self.points.push(LinearityPoint {
+                        grams: 0.0,
+                        counts: 0.0,
+                    });
+                }
+                info!(
+                    "Please place a known weight of {} grams on the scale.",
+                    self.weight_grams
+                );
+                if !self.remote {
+                    info!("Press the button when ready.");
+                }
+                self.step = Step::WaitPress(Averaged::Weight);
+                self.prompt = Some(self.wait_prompt(Averaged::Weight));
+                ProcedureState::Running(self.prompt.take())
+            }
+            Averaged::Weight if self.kind == Kind::Linearity => {
+                self.points.push(LinearityPoint {
+                    grams: self.weight_grams,
+                    counts: average - self.offset as f32,
+                });
+                info!(
+                    "{} grams read {} counts",
+                    self.weight_grams,
+                    average - self.offset as f32
+                );
+                // The empty scale is the first point
+                if let Some(&grams) = self.weights.get(self.points.len() - 1) {
+                    self.weight_grams = grams;
+                    info!(
+                        "Please place a known weight of {} grams on the scale.",
+                        grams
+                    );
+                    self.step = Step::WaitPress(Averaged::Weight);
+                    self.prompt = Some(self.wait_prompt(Averaged::Weight));
+                    return ProcedureState::Running(self.prompt.take());
+                }
+                let checked = Moment::now(device::identity().boot());
+                match LinearityReport::fit(&self.points, self.full_scale_grams, checked) {
+                    Some(report) => {
+                        info!(
+                            "Linearity check complete. Max deviation {:.2} grams, {:.3}% of {} grams",
+                            report.max_deviation_grams(),
+                            report.max_deviation_percent(),
+                            report.full_scale_grams
+                        );
+                        ProcedureState::Done(ProcedureResult::Linearity(report))
+                    }
+                    None => {
+                        warn!("Linearity check failed, the weights read the same");
+                        ProcedureState::Failed(ProcedureError::NoSpread)
+                    }
+                }
+            }
+            Averaged::Weight => {
+                let reading = average - self.offset as f32;
+                if reading == 0.0 {
+                    warn!("Calibration failed. Average reading is 0.");
+                    return ProcedureState::Failed(ProcedureError::ZeroReading);
+                }
+                let scale_factor = self.weight_grams / reading;
+                info!("Calibration complete. Scale factor = {}", scale_factor);
+                ProcedureState::Done(ProcedureResult::Calibrated {
+                    offset: self.offset,
+                    scale_factor,
+                })
+            }
+        }
+    }
+
+    /// Prompt of the wait before averaging for `target`
+    fn wait_prompt(&self, target: Averaged) -> UiRequest {
+        let action = tr(if self.remote {
+            StringId::PressToCancel
+        } else if self.kind == Kind::Linearity {
+            StringId::PressHoldToCancel
+        } else {
+            StringId::PressToContinue
+        });
+        let prompt = match (self.kind, target) {
+            (Kind::Linearity, Averaged::Weight) => trf(
+                StringId::PlaceLinearityWeight,
+                &[
+                    &self.weight_grams,
+                    &self.points.len(),
+                    &self.weights.len(),
+                    &action,
+                ],
+            ),
+            (_, Averaged::Zero) => trf(StringId::EmptyScale, &[&action]),
+            (_, Averaged::Weight) => trf(StringId::PlaceWeight, &[&self.weight_grams, &action]),
+        };
+        UiRequest::Prompt(prompt.to_string())
+    }
+}
+
+impl Step {
+    fn averaging(target: Averaged) -> Self {
+        Step::Averaging {
+            target,
+            sum: 0,
+            count: 0,
+            last_reading: Instant::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(raw: i32) -> Option<AppEvent> {
+        Some(AppEvent::Reading {
+            raw,
+            at: Instant::now(),
+            disturbed: false,
+        })
+    }
+
+    #[test]
+    fn a_tare_is_refused_during_a_tare_and_its_cooldown() {
+        let start = Instant::now();
+        let mut gate = TareGate::<()>::new(DEFAULT_TARE_COOLDOWN);
+        assert_eq!(gate.admit(true, None, start), Admission::Start);
+
+        let tare = Procedure::tare();
+        assert_eq!(
+            gate.admit(true, Some(&tare), start),
+            Admission::AlreadyTared
+        );
+
+        gate.tared(start);
+        let later = |millis| start + Duration::from_millis(millis);
+        assert_eq!(gate.admit(true, None, later(1000)), Admission::AlreadyTared);
+        assert_eq!(gate.admit(true, None, later(1999)), Admission::AlreadyTared);
+        assert_eq!(gate.admit(true, None, later(2000)), Admission::Start);
+
+        // The cool-down is capped
+        gate.set_cooldown(Duration::from_secs(60));
+        assert_eq!(
+            gate.admit(true, None, start + MAX_TARE_COOLDOWN),
+            Admission::Start
+        );
+    }
+
+    #[test]
+    fn a_calibration_waits_for_the_tare() {
+        let mut gate = TareGate::new(DEFAULT_TARE_COOLDOWN);
+        let mut tare = Procedure::tare().with_tare_samples(4);
+        assert_eq!(
+            gate.admit(false, Some(&tare), Instant::now()),
+            Admission::Queue
+        );
+        assert_eq!(gate.queue("calibrate"), Ok(()));
+        // One waits at most
+        assert_eq!(gate.queue("linearity"), Err("linearity"));
+
+        for raw in [100, 102, 98] {
+            assert!(matches!(
+                tare.advance(reading(raw)),
+                ProcedureState::Running(_)
+            ));
+            assert_eq!(gate.take_queued(Some(&tare)), None);
+        }
+        assert_eq!(
+            tare.advance(reading(100)),
+            ProcedureState::Done(ProcedureResult::Tared { offset: 100 })
+        );
+        gate.tared(Instant::now());
+
+        // Done, the calibration starts right away, another tare is refused
+        assert_eq!(gate.take_queued(None), Some("calibrate"));
+        assert_eq!(gate.take_queued(None), None);
+        assert_eq!(gate.admit(false, None, Instant::now()), Admission::Start);
+        assert_eq!(
+            gate.admit(true, None, Instant::now()),
+            Admission::AlreadyTared
+        );
+
+        // Nothing else runs along with a calibration
+        let calibration = Procedure::calibrate(100.0);
+        assert_eq!(
+            gate.admit(false, Some(&calibration), Instant::now()),
+            Admission::Busy
+        );
+        assert_eq!(
+            gate.admit(true, Some(&calibration), Instant::now()),
+            Admission::Busy
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! How far a weight can be trusted. The filter knows whether it settled,
+//! the sampling task whether the reading was converted during a display
+//! flush, the main loop whether the sensor went silent and the capacity
+//! whether the load is past it. A `Reading` carries the verdict along with
+//! the weight to the display, the payloads, the weight log and the alarms.
+
+use std::time::Duration;
+
+/// Largest count of the 24 bit converters, the HX711 and the NAU7802 clamp
+/// their readings to it past their input range
+pub const ADC_MAX_COUNTS: i32 = (1 << 23) - 1;
+const ADC_MIN_COUNTS: i32 = -(1 << 23);
+
+/// Quality of a reading, from the best to the worst. A reading takes the
+/// worst that applies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Quality {
+    /// Settled, nothing wrong with it
+    Good,
+    /// The filter has not settled yet
+    #[default]
+    Settling,
+    /// Converted while the display flushed, its supply may have sagged
+    Disturbed,
+    /// Past the capacity of the scale, or the converter reads at its rail
+    Overload,
+    /// The sensor stopped converting, the weight is the last one read
+    Stale,
+}
+
+impl Quality {
+    const ALL: [Quality; 5] = [
+        Quality::Good,
+        Quality::Settling,
+        Quality::Disturbed,
+        Quality::Overload,
+        Quality::Stale,
+    ];
+
+    /// Quality of a fresh reading from what the pipeline knows about it
+    pub fn of(stable: bool, disturbed: bool, overloaded: bool) -> Self {
+        if overloaded {
+            Quality::Overload
+        } else if disturbed {
+            Quality::Disturbed
+        } else if stable {
+            Quality::Good
+        } else {
+            Quality::Settling
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Quality::Good => "good",
+            Quality::Settling => "settling",
+            Quality::Disturbed => "disturbed",
+            Quality::Overload => "overload",
+            Quality::Stale => "stale",
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+
+    pub fn is_good(self) -> bool {
+        self == Quality::Good
+    }
+}
+
+/// Whether the raw counts sit at a rail of the converter
+pub fn is_saturated(raw: i32) -> bool {
+    raw >= ADC_MAX_COUNTS || raw <= ADC_MIN_COUNTS
+}
+
+/// Weight along with how far it can be trusted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    /// Filtered weight after the tares
+    pub grams: f32,
+    pub quality: Quality,
+    /// Time since the sensor converted it
+    pub age: Duration,
+}
+
+impl Reading {
+    /// The reading as it stands after `age`, stale once the sensor has been
+    /// silent for `stale_after`
+    pub fn aged(grams: f32, quality: Quality, age: Duration, stale_after: Duration) -> Self {
+        Self {
+            grams,
+            quality: if age >= stale_after {
+                Quality::Stale
+            } else {
+                quality
+            },
+            age,
+        }
+    }
+}
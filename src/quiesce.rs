@@ -0,0 +1,151 @@
+//! Keeps the readings clear of the display. A flush draws enough current
+//! from the 3.3V rail shared with the load cell ADC to move a reading by a
+//! few counts, so the display holds a guard while flushing and the sampling
+//! task tells the readings converted meanwhile apart, to drop them or to
+//! give them less weight in the filter. The IMU holds a guard of its own
+//! while the table shakes, and the readings converted then are dropped.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Weight in the filter of a reading converted during a flush
+pub const DISTURBED_WEIGHT: f32 = 0.25;
+/// Disturbed readings dropped in a row at most. The display may flush
+/// between every two readings, which must not starve the filter.
+pub const MAX_CONSECUTIVE_DISCARDS: u32 = 2;
+/// Bumped readings dropped in a row at most, a second of them, so a table
+/// that keeps shaking still gets weighed
+pub const MAX_CONSECUTIVE_BUMP_DISCARDS: u32 = 10;
+
+/// Guards alive, by disturbance
+static ACTIVE: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+/// Guards dropped so far, telling a disturbance came and went between two
+/// readings
+static FINISHED: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+/// Readings dropped or weighted down for a flush
+static QUIESCED: AtomicU32 = AtomicU32::new(0);
+/// Readings dropped for a bump
+static BUMPED: AtomicU32 = AtomicU32::new(0);
+
+/// What disturbs the readings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disturbance {
+    /// A display flush drawing on the supply
+    Flush,
+    /// A bump of the table felt by the IMU
+    Bump,
+}
+
+impl Disturbance {
+    fn slot(self) -> usize {
+        match self {
+            Disturbance::Flush => 0,
+            Disturbance::Bump => 1,
+        }
+    }
+}
+
+/// What becomes of a reading converted during a flush
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuiesceMode {
+    /// Taken like any other, for boards with a supply that holds up
+    #[default]
+    Off,
+    Discard,
+    /// Kept with `DISTURBED_WEIGHT` in the filter
+    Weight,
+}
+
+impl QuiesceMode {
+    pub const ALL: [QuiesceMode; 3] = [QuiesceMode::Off, QuiesceMode::Discard, QuiesceMode::Weight];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            QuiesceMode::Off => "off",
+            QuiesceMode::Discard => "discard",
+            QuiesceMode::Weight => "weight",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            QuiesceMode::Off => 0,
+            QuiesceMode::Discard => 1,
+            QuiesceMode::Weight => 2,
+        }
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+}
+
+/// The readings count as disturbed while a guard is alive
+pub struct QuiesceGuard {
+    disturbance: Disturbance,
+}
+
+/// Flag the supply as disturbed, e.g. around a display flush
+pub fn quiesce() -> QuiesceGuard {
+    disturb(Disturbance::Flush)
+}
+
+/// Flag the readings as disturbed by `disturbance`
+pub fn disturb(disturbance: Disturbance) -> QuiesceGuard {
+    ACTIVE[disturbance.slot()].fetch_add(1, Ordering::AcqRel);
+    QuiesceGuard { disturbance }
+}
+
+impl Drop for QuiesceGuard {
+    fn drop(&mut self) {
+        let slot = self.disturbance.slot();
+        FINISHED[slot].fetch_add(1, Ordering::AcqRel);
+        ACTIVE[slot].fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Point to tell later whether the readings were disturbed since
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuiesceMark([u32; 2]);
+
+impl QuiesceMark {
+    pub fn now() -> Self {
+        Self([
+            FINISHED[0].load(Ordering::Acquire),
+            FINISHED[1].load(Ordering::Acquire),
+        ])
+    }
+
+    /// Whether a guard of `disturbance` was alive at any point since the
+    /// mark
+    pub fn disturbed(self, disturbance: Disturbance) -> bool {
+        let slot = disturbance.slot();
+        ACTIVE[slot].load(Ordering::Acquire) > 0
+            || FINISHED[slot].load(Ordering::Acquire) != self.0[slot]
+    }
+}
+
+/// Count a reading dropped or weighted down
+pub fn count_quiesced() {
+    QUIESCED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Readings dropped or weighted down since boot
+pub fn quiesced_samples() -> u32 {
+    QUIESCED.load(Ordering::Relaxed)
+}
+
+/// Count a reading dropped for a bump
+pub fn count_bumped() {
+    BUMPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Readings dropped for a bump since boot
+pub fn bumped_samples() -> u32 {
+    BUMPED.load(Ordering::Relaxed)
+}
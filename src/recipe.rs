@@ -0,0 +1,150 @@
+//! Pour-over assistant: pick the coffee dose, weigh the coffee, then pour
+//! water up to the dose times the brew ratio.
+//!
+//! Driven by the button like the menu: while picking the dose a press adds
+//! to it (wrapping around) and a long press confirms it. In the other steps
+//! a press or a long press moves on, and a double press cancels at any
+//! point.
+
+use crate::button::ButtonAction;
+
+/// Dose change per press
+pub const DOSE_STEP_GRAMS: f32 = 0.5;
+pub const MIN_DOSE_GRAMS: f32 = 5.0;
+pub const MAX_DOSE_GRAMS: f32 = 60.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecipeStep {
+    EnterDose {
+        dose_grams: f32,
+    },
+    /// Waiting for the coffee on the tared scale
+    WeighCoffee {
+        dose_grams: f32,
+    },
+    /// Coffee weighed, waiting for the go to pour
+    Confirm {
+        coffee_grams: f32,
+    },
+    /// Pouring water up to the target, on the tared scale
+    Brewing {
+        coffee_grams: f32,
+        target_grams: f32,
+    },
+    Done {
+        coffee_grams: f32,
+        water_grams: f32,
+    },
+}
+
+/// What the main loop has to do after a button press
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecipeUpdate {
+    Continue,
+    /// Tare the scale, the next step weighs from zero
+    Tare,
+    /// Back to normal weighing
+    Exit,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipe {
+    /// Grams of water per gram of coffee
+    ratio: f32,
+    step: RecipeStep,
+    /// Latest filtered weight
+    grams: f32,
+}
+
+impl Recipe {
+    pub fn new(dose_grams: f32, ratio: f32) -> Self {
+        Self {
+            ratio,
+            step: RecipeStep::EnterDose {
+                dose_grams: dose_grams.clamp(MIN_DOSE_GRAMS, MAX_DOSE_GRAMS),
+            },
+            grams: 0.0,
+        }
+    }
+
+    pub fn step(&self) -> RecipeStep {
+        self.step
+    }
+
+    pub fn ratio(&self) -> f32 {
+        self.ratio
+    }
+
+    /// Latest filtered weight
+    pub fn grams(&self) -> f32 {
+        self.grams
+    }
+
+    /// Weight to signal once reached, while pouring
+    pub fn target_grams(&self) -> Option<f32> {
+        match self.step {
+            RecipeStep::Brewing { target_grams, .. } => Some(target_grams),
+            _ => None,
+        }
+    }
+
+    /// Share of the water poured, while pouring
+    pub fn progress(&self) -> Option<f32> {
+        self.target_grams()
+            .map(|target_grams| (self.grams / target_grams).clamp(0.0, 1.0))
+    }
+
+    /// Follow the filtered weight
+    pub fn on_weight(&mut self, grams: f32) {
+        self.grams = grams;
+    }
+
+    pub fn on_button(&mut self, action: ButtonAction) -> RecipeUpdate {
+        if action == ButtonAction::DoublePress {
+            return RecipeUpdate::Exit;
+        }
+        let (step, update) = match (self.step, action) {
+            (RecipeStep::EnterDose { dose_grams }, ButtonAction::Press) => {
+                let dose_grams = match dose_grams + DOSE_STEP_GRAMS {
+                    dose if dose > MAX_DOSE_GRAMS => MIN_DOSE_GRAMS,
+                    dose => dose,
+                };
+                (RecipeStep::EnterDose { dose_grams }, RecipeUpdate::Continue)
+            }
+            (RecipeStep::EnterDose { dose_grams }, _) => {
+                (RecipeStep::WeighCoffee { dose_grams }, RecipeUpdate::Tare)
+            }
+            // Nothing to brew with yet
+            (step @ RecipeStep::WeighCoffee { .. }, _) if self.grams <= 0.0 => {
+                (step, RecipeUpdate::Continue)
+            }
+            (RecipeStep::WeighCoffee { .. }, _) => (
+                RecipeStep::Confirm {
+                    coffee_grams: self.grams,
+                },
+                RecipeUpdate::Continue,
+            ),
+            (RecipeStep::Confirm { coffee_grams }, _) => (
+                RecipeStep::Brewing {
+                    coffee_grams,
+                    target_grams: coffee_grams * self.ratio,
+                },
+                RecipeUpdate::Tare,
+            ),
+            // A press ends the pour, keeping what was poured
+            (RecipeStep::Brewing { coffee_grams, .. }, _) => (
+                RecipeStep::Done {
+                    coffee_grams,
+                    water_grams: self.grams,
+                },
+                RecipeUpdate::Continue,
+            ),
+            (RecipeStep::Done { .. }, _) => return RecipeUpdate::Exit,
+        };
+        if update == RecipeUpdate::Tare {
+            self.grams = 0.0;
+        }
+        self.step = step;
+        update
+    }
+}
@@ -0,0 +1,209 @@
+//! Why the scale restarted: the resets are counted by reason in NVS, and the
+//! message of the last panic is kept there too, so field failures can be
+//! told apart after the fact.
+
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::{
+    esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_DEEPSLEEP,
+    esp_reset_reason_t_ESP_RST_EXT, esp_reset_reason_t_ESP_RST_INT_WDT,
+    esp_reset_reason_t_ESP_RST_PANIC, esp_reset_reason_t_ESP_RST_POWERON,
+    esp_reset_reason_t_ESP_RST_SW, esp_reset_reason_t_ESP_RST_TASK_WDT,
+    esp_reset_reason_t_ESP_RST_WDT, EspError,
+};
+use log::{info, warn};
+
+pub const RESET_NAMESPACE: &str = "reset";
+const LAST_PANIC_KEY: &str = "last_panic";
+/// Longer panic messages are truncated, NVS strings are meant to be short
+const PANIC_MESSAGE_MAX_LEN: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    PowerOn,
+    /// Reset pin
+    External,
+    /// Restart requested by the firmware
+    Software,
+    Panic,
+    InterruptWatchdog,
+    TaskWatchdog,
+    /// Any other watchdog
+    Watchdog,
+    DeepSleep,
+    Brownout,
+    Unknown,
+}
+
+impl ResetReason {
+    pub const ALL: [ResetReason; 10] = [
+        ResetReason::PowerOn,
+        ResetReason::External,
+        ResetReason::Software,
+        ResetReason::Panic,
+        ResetReason::InterruptWatchdog,
+        ResetReason::TaskWatchdog,
+        ResetReason::Watchdog,
+        ResetReason::DeepSleep,
+        ResetReason::Brownout,
+        ResetReason::Unknown,
+    ];
+
+    /// Reason of the reset the firmware just came out of
+    pub fn get() -> Self {
+        match unsafe { esp_reset_reason() } {
+            esp_reset_reason_t_ESP_RST_POWERON => ResetReason::PowerOn,
+            esp_reset_reason_t_ESP_RST_EXT => ResetReason::External,
+            esp_reset_reason_t_ESP_RST_SW => ResetReason::Software,
+            esp_reset_reason_t_ESP_RST_PANIC => ResetReason::Panic,
+            esp_reset_reason_t_ESP_RST_INT_WDT => ResetReason::InterruptWatchdog,
+            esp_reset_reason_t_ESP_RST_TASK_WDT => ResetReason::TaskWatchdog,
+            esp_reset_reason_t_ESP_RST_WDT => ResetReason::Watchdog,
+            esp_reset_reason_t_ESP_RST_DEEPSLEEP => ResetReason::DeepSleep,
+            esp_reset_reason_t_ESP_RST_BROWNOUT => ResetReason::Brownout,
+            _ => ResetReason::Unknown,
+        }
+    }
+
+    /// Short name, also the NVS key of the counter
+    pub fn name(self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "poweron",
+            ResetReason::External => "external",
+            ResetReason::Software => "software",
+            ResetReason::Panic => "panic",
+            ResetReason::InterruptWatchdog => "int_wdt",
+            ResetReason::TaskWatchdog => "task_wdt",
+            ResetReason::Watchdog => "wdt",
+            ResetReason::DeepSleep => "deepsleep",
+            ResetReason::Brownout => "brownout",
+            ResetReason::Unknown => "unknown",
+        }
+    }
+
+    /// Shown on the display after an abnormal reset
+    pub fn description(self) -> &'static str {
+        match self {
+            ResetReason::Panic => "panic",
+            ResetReason::InterruptWatchdog | ResetReason::TaskWatchdog | ResetReason::Watchdog => {
+                "watchdog reset"
+            }
+            ResetReason::Brownout => "brownout",
+            _ => "reset",
+        }
+    }
+
+    /// Whether the firmware or the power supply failed
+    pub fn is_abnormal(self) -> bool {
+        matches!(
+            self,
+            ResetReason::Panic
+                | ResetReason::InterruptWatchdog
+                | ResetReason::TaskWatchdog
+                | ResetReason::Watchdog
+                | ResetReason::Brownout
+        )
+    }
+}
+
+/// Handle to the reset counters and the last panic message
+#[derive(Clone)]
+pub struct ResetLog {
+    nvs: Arc<Mutex<EspNvs<NvsDefault>>>,
+    reason: ResetReason,
+}
+
+impl ResetLog {
+    /// Count the reset the firmware just came out of, and keep the message
+    /// of any later panic
+    pub fn start(nvs_default_partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(nvs_default_partition, RESET_NAMESPACE, true)?;
+        let reason = ResetReason::get();
+        let count = nvs.get_u32(reason.name())?.unwrap_or(0).saturating_add(1);
+        nvs.set_u32(reason.name(), count)?;
+        if reason.is_abnormal() {
+            warn!("Recovered from {} ({} so far)", reason.description(), count);
+        } else {
+            info!("Reset reason: {}", reason.name());
+        }
+
+        let log = Self {
+            nvs: Arc::new(Mutex::new(nvs)),
+            reason,
+        };
+        log.install_panic_hook();
+        Ok(log)
+    }
+
+    fn install_panic_hook(&self) {
+        let nvs = self.nvs.clone();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |panic_info| {
+            // Never wait on the lock, the panic may come from under it
+            if let Ok(nvs) = nvs.try_lock() {
+                let message = panic_info.to_string();
+                let _ = nvs.set_str(LAST_PANIC_KEY, truncate(&message, PANIC_MESSAGE_MAX_LEN));
+            }
+            default_hook(panic_info);
+        }));
+    }
+
+    /// Reason of the last reset
+    pub fn reason(&self) -> ResetReason {
+        self.reason
+    }
+
+    /// Number of resets for the reason, this boot included
+    pub fn count(&self, reason: ResetReason) -> u32 {
+        self.lock()
+            .get_u32(reason.name())
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+    }
+
+    /// Reasons the scale was reset for, along with how often
+    pub fn counts(&self) -> Vec<(ResetReason, u32)> {
+        ResetReason::ALL
+            .into_iter()
+            .map(|reason| (reason, self.count(reason)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// Message of the last panic, until cleared
+    pub fn last_panic(&self) -> Option<String> {
+        let mut buf = [0; PANIC_MESSAGE_MAX_LEN + 1];
+        self.lock()
+            .get_str(LAST_PANIC_KEY, &mut buf)
+            .ok()
+            .flatten()
+            .map(str::to_string)
+    }
+
+    /// Forget the counters and the last panic
+    pub fn clear(&self) -> Result<(), EspError> {
+        let nvs = self.lock();
+        for reason in ResetReason::ALL {
+            nvs.remove(reason.name())?;
+        }
+        nvs.remove(LAST_PANIC_KEY)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, EspNvs<NvsDefault>> {
+        self.nvs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Cut the text to at most `max_len` bytes, on a character boundary
+fn truncate(text: &str, max_len: usize) -> &str {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
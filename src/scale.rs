@@ -1,222 +1,1398 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::{Receiver, SyncSender, TrySendError},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
 
+pub use crate::unit::Unit;
 use crate::{
     button::*,
-    text_drawer::{DisplayError, TextDrawer, TextError},
+    cal_transfer::{CalTransferError, CalibrationRecord},
+    calibration::{CalibrationReminder, Moment, ReminderReason, ReminderState},
+    certified,
+    counters::{self, Counter},
+    creep::{CreepCompensator, CreepError, CreepModel, CreepReport, CreepTrace},
+    demo::{DemoPattern, DemoSensor},
+    device,
+    events::{AppEvent, ChangeThreshold, WeightEvent, WeightEvents, WeightSink},
+    filter::{Sample, WeightFilter},
+    hold::{Hold, HoldState},
+    linearity::LinearityReport,
+    noise::{NoiseReport, NoiseThresholds},
+    pipeline::Pipeline,
+    procedure::{Procedure, ProcedureResult, TARE_NUM_SAMPLES},
+    quality::{self, Quality, Reading},
+    quiesce::{
+        count_bumped, count_quiesced, Disturbance, QuiesceMark, QuiesceMode, DISTURBED_WEIGHT,
+        MAX_CONSECUTIVE_BUMP_DISCARDS, MAX_CONSECUTIVE_DISCARDS,
+    },
+    sensor::{LoadSensor, RatePin, SampleRate, SensorError, SensorKind, RATE_SETTLE_CONVERSIONS},
+    settings::Settings,
+    storage::{Storage, StorageService, StoreError},
+    tare::{DisplayMode, SoftTare},
+    time_average::{SharedTimeWeighted, TimeAverage},
+    trace::{TracePoint, TraceRecorder},
+    watchdog::WatchdogGuard,
+    weight_gesture::{GestureHint, WeightGestureDetector},
 };
 
-use embedded_graphics::prelude::Point;
-use esp_idf_hal::{
-    delay::{Delay, FreeRtos},
-    gpio::*,
-};
-use esp_idf_svc::nvs::*;
+#[cfg(feature = "esp")]
+use esp_idf_hal::gpio::{Input, InputPin, OutputPin, PinDriver};
+#[cfg(feature = "esp")]
 use esp_idf_sys::EspError;
 
-use loadcell::{hx711::HX711, LoadCell};
-use ssd1306::{prelude::WriteOnlyDataCommand, size::DisplaySize};
+use log::{debug, info, warn};
+use thiserror::Error;
 
-const STORAGE_NAMESPACE: &str = "scale_storage";
+pub const STORAGE_NAMESPACE: &str = "scale_storage";
+/// Scale factor of the HX711, under the key it had before there was a choice
 const SCALE_FACTOR_KEY: &str = "scale_factor";
+const NAU7802_SCALE_FACTOR_KEY: &str = "nau_factor";
+const OFFSET_KEY: &str = "offset";
+const NAU7802_OFFSET_KEY: &str = "nau_offset";
+const REMINDER_KEY: &str = "cal_reminder";
+/// Linearity report of the HX711, the other sensor's under its own key
+const LINEARITY_KEY: &str = "linearity";
+const NAU7802_LINEARITY_KEY: &str = "nau_linearity";
+/// Creep model of the HX711, the other sensor's under its own key
+const CREEP_KEY: &str = "creep";
+const NAU7802_CREEP_KEY: &str = "nau_creep";
+/// Last noise test of the HX711, the other sensor's under its own key
+const NOISE_KEY: &str = "noise";
+const NAU7802_NOISE_KEY: &str = "nau_noise";
+/// Conversion rate of the HX711 picked last, in SPS
+const RATE_KEY: &str = "rate";
+/// Drift absorbed since the reminder state was last saved that gets it saved
+const DRIFT_SAVE_STEP_GRAMS: f32 = 1.0;
 
-const SCALE_TARE_NUM_SAMPLES: usize = 16;
-const SCALE_CALIBRATION_NUM_SAMPLES: usize = 16;
-const SCALE_CALIBRATION_WEIGHT_GRAMS: f32 = 2000.0;
-const SCALE_CALIBRATION_DELAY_MS: Duration = Duration::from_millis(5);
-const SCALE_SCALIBRATION_SLEEP_MS: Duration = Duration::from_millis(10);
+const SAMPLING_TASK_STACK_SIZE: usize = 3 * 1024;
+/// Period the sensor is checked for a new reading at, below its output
+/// period of 12.5ms at 80 SPS
+const SAMPLING_POLL_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Error, Debug)]
+pub enum ScaleError {
+    #[cfg(feature = "esp")]
+    #[error("Failed to start the button task: {0}")]
+    Button(EspError),
+    #[error("Failed to open the calibration storage: {0}")]
+    Storage(StoreError),
+    #[error("Failed to start the sampling task: {0}")]
+    Sampling(std::io::Error),
+    #[error("No GPIO drives the RATE pin of the HX711")]
+    NoRatePin,
+    #[error("Failed to set the sample rate: {0}")]
+    SampleRate(SensorError),
+}
 
 pub enum ScaleAction {
     Tare,
-    Calibrate,
+    OpenMenu,
+    NextPage,
 }
 
-pub struct Scale<'a, T: OutputPin, S: InputPin> {
-    hx711: HX711<PinDriver<'a, T, Output>, PinDriver<'a, S, Input>, Delay>,
+impl From<ButtonAction> for ScaleAction {
+    fn from(action: ButtonAction) -> Self {
+        match action {
+            ButtonAction::Press => ScaleAction::Tare,
+            ButtonAction::LongPress => ScaleAction::OpenMenu,
+            ButtonAction::DoublePress => ScaleAction::NextPage,
+        }
+    }
+}
+
+/// The load cell put aside while the demo signal stands in for it
+struct Demo {
+    sensor: Box<dyn LoadSensor>,
+    /// Offset of the load cell, back once the demo is over
+    offset: i32,
+    pattern: DemoPattern,
+}
+
+pub struct Scale {
+    /// Shared with the sampling task, taken over while averaging readings
+    sensor: Arc<Mutex<Box<dyn LoadSensor>>>,
+    /// Set while the demo signal is read in place of the load cell
+    demo: Option<Demo>,
+    /// When the sensor last converted, shared with the sampling task
+    last_conversion: Arc<Mutex<Instant>>,
+    /// GPIO driving the RATE pin of the HX711, when it is wired to one
+    rate_pin: Option<Box<dyn RatePin>>,
+    sample_rate: SampleRate,
+    /// Conversions the sampling task still drops while the HX711 settles at
+    /// a new rate
+    settle_discards: Arc<AtomicU32>,
     button_event_handle: ButtonEventHandle,
+    /// Key of the scale factor of the sensor, the counts of one do not
+    /// compare to the other's
+    scale_factor_key: &'static str,
     scale_factor: Option<f32>,
-    nvs_partition: EspNvs<NvsDefault>,
-    last_button_event: Option<ButtonEvent>,
+    /// Key of the offset saved with every tare, restored at boot instead of
+    /// taring when the startup mode asks for it
+    offset_key: &'static str,
+    offset: i32,
+    /// Key of the linearity report of the sensor
+    linearity_key: &'static str,
+    linearity: Option<LinearityReport>,
+    /// Key of the creep model of the sensor
+    creep_key: &'static str,
+    /// Key of the last noise test of the sensor
+    noise_key: &'static str,
+    noise: Option<NoiseReport>,
+    /// Trace of a creep measurement in progress
+    creep_trace: Option<CreepTrace>,
+    /// Outcome of the last creep measurement, until it is taken
+    creep_result: Option<Result<CreepReport, CreepError>>,
+    /// Trace of the readings being recorded
+    trace: Option<TraceRecorder>,
+    /// Trace recorded last, until it is taken
+    trace_result: Option<Vec<TracePoint>>,
+    /// Tares taken off the weight reported, on top of the offset
+    soft_tare: SoftTare,
+    /// Last filtered weight above the offset, the soft tares capture it
+    gross: Option<f32>,
+    /// Last filtered weight reported along with its quality
+    last_sample: Option<(f32, Quality)>,
+    /// Gross weight past which a reading is an overload
+    capacity: Option<f32>,
+    /// Time without a reading after which the last one is stale
+    stale_after: Duration,
+    /// Integral of the weight reported, for its time-weighted average
+    time_weighted: SharedTimeWeighted,
+    /// Recent weights reported, a held one is estimated from
+    hold: Hold,
+    storage: Storage,
+    gesture_detector: GestureDetector,
+    /// Presses on the platform standing in for the button, fed by the
+    /// sampling task
+    weight_gestures: Arc<Mutex<WeightGestureDetector>>,
+    unit: Unit,
+    resolution: f32,
+    calibration_weight: f32,
+    /// Filter, creep compensation and zero tracking of the readings
+    pipeline: Pipeline,
+    /// Readings the tares average into the zero
+    tare_samples: usize,
+    /// What becomes of the readings converted during a display flush
+    quiesce: QuiesceMode,
+    /// Whether the weighing is locked down for certified use: no zero
+    /// tracking, no creep compensation and no hold
+    certified: bool,
+    events: WeightEvents,
+    /// Last filtered weight published, along with its stability
+    last_published: Option<(f32, bool)>,
+    /// Boot of the device identity, dating the calibration while the clock
+    /// is not synchronized
+    boot: u32,
+    reminder: CalibrationReminder,
+    /// Drift in the reminder state last saved
+    drift_saved: f32,
+}
+
+/// Start the task of the scale's button. Its events stay queued on the
+/// handle, the main loop is only woken up through `app_events`.
+#[cfg(feature = "esp")]
+pub fn start_scale_button<R: InputPin + OutputPin>(
+    button: PinDriver<'static, R, Input>,
+    settings: &Settings,
+    app_events: SyncSender<AppEvent>,
+) -> Result<ButtonEventHandle, ScaleError> {
+    start_button_task(
+        button,
+        true,
+        settings.long_press(),
+        settings.button_stuck(),
+        wake_on_button(app_events),
+    )
+    .map_err(ScaleError::Button)
+}
+
+/// Start the task of a button read through `pin`, low while pressed like
+/// the scale's, e.g. the keyboard of the simulator
+pub fn watch_scale_button(
+    pin: impl ButtonPin,
+    settings: &Settings,
+    app_events: SyncSender<AppEvent>,
+) -> ButtonEventHandle {
+    watch_button(
+        pin,
+        true,
+        settings.long_press(),
+        settings.button_stuck(),
+        wake_on_button(app_events),
+    )
+}
+
+fn wake_on_button(app_events: SyncSender<AppEvent>) -> impl Fn(TimedButtonEvent) + Send {
+    move |event| {
+        let _ = app_events.try_send(AppEvent::Button(event));
+    }
 }
 
-impl<'a, T: OutputPin, S: InputPin> Scale<'a, T, S> {
+/// Builds a `Scale` from parts the application already owns, e.g. the
+/// storage service it shares the NVS partition through. What is not set
+/// comes from the settings, the default ones without `settings`.
+pub struct ScaleBuilder<'a> {
+    sensor: Box<dyn LoadSensor>,
+    sensor_kind: SensorKind,
+    button_event_handle: ButtonEventHandle,
+    storage: &'a StorageService,
+    settings: Option<&'a Settings>,
+    filter: WeightFilter,
+    tare_samples: usize,
+    calibration_weight: Option<f32>,
+    rate_pin: Option<Box<dyn RatePin>>,
+}
+
+impl<'a> ScaleBuilder<'a> {
+    pub fn settings(mut self, settings: &'a Settings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Filter of the readings, `WeightFilter::default()` otherwise. Its
+    /// window counts the readings of 10 SPS, and averages over as long at
+    /// 80 SPS.
+    pub fn filter(mut self, filter: WeightFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Readings the tares average into the zero, `TARE_NUM_SAMPLES`
+    /// otherwise
+    pub fn tare_samples(mut self, samples: usize) -> Self {
+        self.tare_samples = samples.max(1);
+        self
+    }
+
+    /// Known weight of the calibration in place of the one of the settings,
+    /// until the settings are applied again
+    pub fn calibration_weight(mut self, grams: f32) -> Self {
+        self.calibration_weight = Some(grams);
+        self
+    }
+
+    /// GPIO driving the RATE pin of the HX711, for the sample rate to be
+    /// picked with `Scale::set_sample_rate`. Without it the pin is taken as
+    /// tied to ground, converting at 10 SPS.
+    pub fn rate_pin(mut self, pin: impl RatePin + 'static) -> Self {
+        self.rate_pin = Some(Box::new(pin));
+        self
+    }
+
+    /// Open the scale namespace and load the calibration. An unreadable
+    /// scale factor asks for a calibration.
+    pub fn build(self) -> Result<Scale, ScaleError> {
+        let default_settings;
+        let settings = match self.settings {
+            Some(settings) => settings,
+            None => {
+                default_settings = Settings::default();
+                &default_settings
+            }
+        };
+        let storage = self
+            .storage
+            .open(STORAGE_NAMESPACE)
+            .map_err(ScaleError::Storage)?;
+        let (scale_factor_key, offset_key, linearity_key, creep_key, noise_key) =
+            match self.sensor_kind {
+                SensorKind::Hx711 => (
+                    SCALE_FACTOR_KEY,
+                    OFFSET_KEY,
+                    LINEARITY_KEY,
+                    CREEP_KEY,
+                    NOISE_KEY,
+                ),
+                SensorKind::Nau7802 => (
+                    NAU7802_SCALE_FACTOR_KEY,
+                    NAU7802_OFFSET_KEY,
+                    NAU7802_LINEARITY_KEY,
+                    NAU7802_CREEP_KEY,
+                    NAU7802_NOISE_KEY,
+                ),
+            };
+        let scale_factor = storage.get_f32(scale_factor_key);
+        let mut rate_pin = self.rate_pin;
+        let sample_rate = match rate_pin.as_mut() {
+            Some(pin) => {
+                let rate = storage
+                    .get_u32(RATE_KEY)
+                    .and_then(SampleRate::from_hz)
+                    .unwrap_or_default();
+                pin.drive(rate).map_err(ScaleError::SampleRate)?;
+                rate
+            }
+            None => SampleRate::default(),
+        };
+        let filter = self.filter.rescaled(SampleRate::default(), sample_rate);
+        let linearity = storage.get_checked(linearity_key);
+        let creep = storage.get_checked(creep_key);
+        let noise = storage.get_checked(noise_key);
+        let mut pipeline = Pipeline::new(filter, CreepCompensator::new(creep));
+        certified::lock_pipeline(&mut pipeline, settings.certified());
+        let mut weight_gestures = WeightGestureDetector::new();
+        weight_gestures.configure(settings.weight_gestures(), settings.capacity_grams());
+        weight_gestures.set_scale_factor(scale_factor);
+
+        let boot = device::identity().boot();
+        // A calibration made before it was dated counts from now on
+        let reminder_state = storage
+            .get_checked(REMINDER_KEY)
+            .unwrap_or_else(|| ReminderState::new(Moment::now(boot)));
+
+        Ok(Scale {
+            sensor: Arc::new(Mutex::new(self.sensor)),
+            demo: None,
+            // The sensor gets the same time to convert at startup
+            last_conversion: Arc::new(Mutex::new(Instant::now())),
+            // The pin was left floating until now
+            settle_discards: Arc::new(AtomicU32::new(match rate_pin {
+                Some(_) => RATE_SETTLE_CONVERSIONS,
+                None => 0,
+            })),
+            rate_pin,
+            sample_rate,
+            button_event_handle: self.button_event_handle,
+            scale_factor_key,
+            scale_factor,
+            offset_key,
+            offset: 0,
+            linearity_key,
+            linearity,
+            creep_key,
+            noise_key,
+            noise,
+            creep_trace: None,
+            creep_result: None,
+            trace: None,
+            trace_result: None,
+            soft_tare: SoftTare::default(),
+            gross: None,
+            last_sample: None,
+            capacity: settings.capacity_grams(),
+            stale_after: settings.stale_reading(),
+            time_weighted: SharedTimeWeighted::new(settings.stale_reading()),
+            hold: Hold::new(certified::auto_hold(
+                settings.auto_hold(),
+                settings.certified(),
+            )),
+            storage,
+            gesture_detector: GestureDetector::default(),
+            weight_gestures: Arc::new(Mutex::new(weight_gestures)),
+            unit: settings.unit(),
+            resolution: settings.resolution(),
+            calibration_weight: self
+                .calibration_weight
+                .unwrap_or_else(|| settings.calibration_weight()),
+            pipeline,
+            tare_samples: self.tare_samples,
+            quiesce: settings.quiesce(),
+            certified: settings.certified(),
+            events: WeightEvents::default(),
+            last_published: None,
+            boot,
+            reminder: CalibrationReminder::new(
+                reminder_state,
+                settings.cal_reminder_days(),
+                settings.cal_drift_grams(),
+            ),
+            drift_saved: reminder_state.drift_grams,
+        })
+    }
+}
+
+impl Scale {
+    /// Build a scale with the defaults, starting the button task on `button`
+    #[cfg(feature = "esp")]
     pub fn new<R: InputPin + OutputPin>(
-        hx711_sck: PinDriver<'static, T, Output>,
-        hx711_dt: PinDriver<'static, S, Input>,
+        sensor: Box<dyn LoadSensor>,
+        sensor_kind: SensorKind,
         button: PinDriver<'static, R, Input>,
-    ) -> Result<Self, EspError> {
-        let mut hx711 = HX711::new(hx711_sck, hx711_dt, Delay::default());
-        let button_event_handle = start_button_task(button, true).unwrap();
-        hx711.set_scale(1.0);
-
-        // Create the NVS partition
-        let nvs_default_partition: EspNvsPartition<NvsDefault> = EspDefaultNvsPartition::take()?;
-        let nvs = EspNvs::new(nvs_default_partition, STORAGE_NAMESPACE, true)?;
-
-        // Try to load the scale factor from the NVS partition
-        let scale_factor = nvs
-            .get_u32(SCALE_FACTOR_KEY)
-            .unwrap_or(None)
-            .map(f32::from_bits)
-            .inspect(|&scale_factor| {
-                hx711.set_scale(scale_factor);
-            });
-
-        Ok(Self {
-            hx711,
+        storage: &StorageService,
+        settings: &Settings,
+        app_events: SyncSender<AppEvent>,
+    ) -> Result<Self, ScaleError> {
+        let button_event_handle = start_scale_button(button, settings, app_events)?;
+        Self::builder(sensor, sensor_kind, button_event_handle, storage)
+            .settings(settings)
+            .build()
+    }
+
+    /// Start building a scale reading `sensor`, its button events coming
+    /// from `button_event_handle`
+    pub fn builder(
+        sensor: Box<dyn LoadSensor>,
+        sensor_kind: SensorKind,
+        button_event_handle: ButtonEventHandle,
+        storage: &StorageService,
+    ) -> ScaleBuilder<'_> {
+        ScaleBuilder {
+            sensor,
+            sensor_kind,
             button_event_handle,
-            scale_factor,
-            nvs_partition: nvs,
-            last_button_event: None,
-        })
+            storage,
+            settings: None,
+            filter: WeightFilter::default(),
+            tare_samples: TARE_NUM_SAMPLES,
+            calibration_weight: None,
+            rate_pin: None,
+        }
     }
 
     pub fn needs_calibration(&self) -> bool {
         self.scale_factor.is_none()
     }
 
-    pub fn tare<DI, SIZE>(
-        &mut self,
-        text_drawer: &mut TextDrawer<DI, SIZE>,
-    ) -> Result<(), TextError<DisplayError<DI, SIZE>>>
-    where
-        DI: WriteOnlyDataCommand,
-        SIZE: DisplaySize,
-    {
-        println!("Taring scale...");
-        text_drawer.draw_text_clear("Taring...", Point::zero())?;
-        text_drawer.flush()?;
-
-        self.hx711.tare(SCALE_TARE_NUM_SAMPLES);
-        println!("Tare complete.");
-        text_drawer.draw_text_clear("Tare complete.", Point::zero())?;
-        text_drawer.flush()?;
+    /// Forget the stored calibration, the scale needs to be calibrated again.
+    /// The linearity report and the creep model go with it.
+    pub fn reset_calibration(&mut self) -> Result<(), StoreError> {
+        certified::calibration_changed();
+        self.scale_factor = None;
+        self.weight_gestures().set_scale_factor(None);
+        self.linearity = None;
+        self.pipeline.creep.configure(None);
+        self.storage.remove(self.linearity_key)?;
+        self.storage.remove(self.creep_key)?;
+        self.storage.remove(self.scale_factor_key).map(|_| ())
+    }
 
-        Ok(())
+    /// Report of the last linearity check
+    pub fn linearity(&self) -> Option<&LinearityReport> {
+        self.linearity.as_ref()
     }
 
-    fn get_avg_reading(&mut self, num_samples: usize) -> Result<f32, &'static str> {
-        if num_samples == 0 {
-            return Err("num_samples must be greater than 0");
-        }
-        let mut sum: i64 = 0;
-        let mut count: usize = 0;
-        loop {
-            if count >= num_samples {
-                break;
-            }
+    /// Report of the last noise test
+    pub fn noise(&self) -> Option<&NoiseReport> {
+        self.noise.as_ref()
+    }
 
-            if let Ok(reading) = self.hx711.read() {
-                sum += i64::from(reading);
-                count += 1;
-                FreeRtos::delay_ms(SCALE_CALIBRATION_DELAY_MS.as_millis().try_into().unwrap());
-            } else {
-                FreeRtos::delay_ms(SCALE_SCALIBRATION_SLEEP_MS.as_millis().try_into().unwrap());
-            }
+    /// Creep model the weight is compensated with, none when it is not
+    pub fn creep_model(&self) -> Option<CreepModel> {
+        self.pipeline.creep.model()
+    }
+
+    /// Compensate the creep with the model and store it with the
+    /// calibration, none to stop compensating
+    pub fn set_creep_model(&mut self, model: Option<CreepModel>) -> Result<(), StoreError> {
+        self.pipeline.creep.configure(model);
+        certified::calibration_changed();
+        match model {
+            Some(model) => self.storage.put_checked(self.creep_key, &model),
+            None => self.storage.remove(self.creep_key).map(|_| ()),
         }
-        Ok((sum as f64 / count as f64) as f32)
     }
 
-    pub fn calibrate<DI, SIZE>(
-        &mut self,
-        text_drawer: &mut TextDrawer<DI, SIZE>,
-    ) -> Result<(), TextError<DisplayError<DI, SIZE>>>
-    where
-        DI: WriteOnlyDataCommand,
-        SIZE: DisplaySize,
-    {
-        // Clear any pending button events
-        self.button_event_handle.clear_events();
+    /// Record the creep after the next step load, the outcome is taken with
+    /// `take_creep_result`. A tare cancels it.
+    pub fn start_creep_measure(&mut self) {
+        self.creep_trace = Some(CreepTrace::new(Instant::now()));
+        self.creep_result = None;
+    }
+
+    /// Whether a creep measurement waits for the step or records
+    pub fn is_measuring_creep(&self) -> bool {
+        self.creep_trace.is_some()
+    }
+
+    /// Outcome of the creep measurement once it is over
+    pub fn take_creep_result(&mut self) -> Option<Result<CreepReport, CreepError>> {
+        self.creep_result.take()
+    }
 
-        println!("Starting calibration...");
-        println!("Please remove any weight from the scale and press the button.");
+    /// Record the readings for `duration`, the trace is taken with
+    /// `take_trace`
+    pub fn start_trace(&mut self, duration: Duration) {
+        self.trace = Some(TraceRecorder::new(duration, Instant::now()));
+        self.trace_result = None;
+    }
 
-        text_drawer.draw_text_clear_flush("Empty the scale!\nPress to continue", Point::zero())?;
+    pub fn is_recording_trace(&self) -> bool {
+        self.trace.is_some()
+    }
 
-        self.button_event_handle.wait_for_event(ButtonEvent::Down);
+    /// Trace recorded, once it is over
+    pub fn take_trace(&mut self) -> Option<Vec<TracePoint>> {
+        self.trace_result.take()
+    }
 
-        self.tare(text_drawer)?;
+    /// Samples the filter averages
+    pub fn filter_window(&self) -> usize {
+        self.pipeline.filter.window()
+    }
 
-        println!(
-            "Please place a known weight of {} grams on the scale.",
-            SCALE_CALIBRATION_WEIGHT_GRAMS
+    /// Pipeline weighing as this scale does, for a trace to be replayed
+    /// through, with another filter window or stable band when given
+    pub fn replay_pipeline(&self, window: Option<usize>, stable_band: Option<f32>) -> Pipeline {
+        let filter = &self.pipeline.filter;
+        let filter = WeightFilter::new(
+            window.unwrap_or(filter.window()),
+            stable_band.unwrap_or(filter.stable_band()),
         );
-        println!("Press the button when ready.");
+        let mut pipeline = Pipeline::new(filter, CreepCompensator::new(self.creep_model()));
+        certified::lock_pipeline(&mut pipeline, self.certified);
+        pipeline
+    }
 
-        text_drawer.draw_text_clear_flush(
-            &format!(
-                "Place {}g weight\nPress to continue",
-                SCALE_CALIBRATION_WEIGHT_GRAMS
-            ),
-            Point::zero(),
-        )?;
+    /// Conversion rate of the HX711
+    pub fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
 
-        // Wait for the button to be pressed
-        self.button_event_handle.wait_for_event(ButtonEvent::Down);
+    /// Switch the HX711 to `rate` and save it. The filter averages over as
+    /// long as before, so the stability takes as long to show at either
+    /// rate, and the conversions made while the ADC settles are dropped.
+    pub fn set_sample_rate(&mut self, rate: SampleRate) -> Result<(), ScaleError> {
+        let pin = self.rate_pin.as_mut().ok_or(ScaleError::NoRatePin)?;
+        if rate != self.sample_rate {
+            pin.drive(rate).map_err(ScaleError::SampleRate)?;
+            self.settle_discards
+                .store(RATE_SETTLE_CONVERSIONS, Ordering::Relaxed);
+            self.pipeline.filter = self.pipeline.filter.rescaled(self.sample_rate, rate);
+            self.sample_rate = rate;
+            info!("Sampling at {} SPS", rate.hz());
+        }
+        self.storage
+            .set_u32(RATE_KEY, rate.hz())
+            .map_err(ScaleError::Storage)
+    }
 
-        println!(
-            "Calibrating for {} samples...",
-            SCALE_CALIBRATION_NUM_SAMPLES
-        );
+    /// Apply the weighing related settings
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.set_unit(settings.unit());
+        // Before the resolution, which leaving the mode unlocks
+        self.set_certified(settings.certified());
+        self.set_resolution(settings.resolution());
+        self.calibration_weight = settings.calibration_weight();
+        self.capacity = settings.capacity_grams();
+        self.set_stale_reading(settings.stale_reading());
+        self.hold.configure(certified::auto_hold(
+            settings.auto_hold(),
+            settings.certified(),
+        ));
+        self.reminder
+            .configure(settings.cal_reminder_days(), settings.cal_drift_grams());
+        self.weight_gestures()
+            .configure(settings.weight_gestures(), settings.capacity_grams());
+    }
 
-        text_drawer.draw_text_clear_flush("Calibrating...", Point::zero())?;
+    /// Lock the weighing down for certified use or back, counted by the
+    /// audit counter when it changes
+    fn set_certified(&mut self, certified: bool) {
+        if certified && !self.certified {
+            self.hold.release();
+        }
+        self.certified = certified;
+        certified::lock_pipeline(&mut self.pipeline, certified);
+        certified::set_active(certified);
+    }
 
-        let avg_result = self.get_avg_reading(SCALE_CALIBRATION_NUM_SAMPLES).unwrap();
-        if avg_result == 0.0 {
-            println!("Calibration failed. Average reading is 0.");
-            text_drawer.draw_text_clear_flush("Calibration failed", Point::zero())?;
-            return Ok(());
+    /// Whether the weighing is locked down for certified use
+    pub fn is_certified(&self) -> bool {
+        self.certified
+    }
+
+    pub fn unit(&self) -> Unit {
+        self.unit
+    }
+
+    pub fn set_unit(&mut self, unit: Unit) {
+        if unit != self.unit {
+            self.unit = unit;
+            self.events.publish(WeightEvent::UnitChanged(unit));
         }
+    }
 
-        let scale_factor = SCALE_CALIBRATION_WEIGHT_GRAMS / avg_result;
+    /// Step in grams the reported weight is rounded to
+    pub fn resolution(&self) -> f32 {
+        self.resolution
+    }
+
+    /// Round to another resolution, unless it is locked by the certified
+    /// mode
+    pub fn set_resolution(&mut self, resolution: f32) {
+        self.resolution = certified::resolution(self.resolution, resolution, self.certified);
+    }
 
-        self.hx711.set_scale(scale_factor);
-        self.scale_factor = Some(scale_factor);
+    /// Known weight in grams used by the calibration process
+    pub fn calibration_weight(&self) -> f32 {
+        self.calibration_weight
+    }
 
-        text_drawer.draw_text_clear_flush("Calibration done", Point::zero())?;
+    pub fn set_calibration_weight(&mut self, grams: f32) {
+        self.calibration_weight = grams;
+    }
+
+    /// Start taring, advanced by the main loop with the readings
+    pub fn begin_tare(&self) -> Procedure {
+        Procedure::tare().with_tare_samples(self.tare_samples)
+    }
+
+    /// Start calibrating through the button prompts, advanced by the main
+    /// loop with the readings and the button events
+    pub fn begin_calibration(&mut self) -> Procedure {
+        // Clear any pending button events
+        self.clear_button_events();
+        Procedure::calibrate(self.calibration_weight).with_tare_samples(self.tare_samples)
+    }
+
+    /// Start calibrating through steps sent from the console or over HTTP,
+    /// which a press cancels
+    pub fn begin_remote_calibration(&mut self, weight_grams: f32) -> Procedure {
+        self.clear_button_events();
+        Procedure::calibrate_remote(weight_grams).with_tare_samples(self.tare_samples)
+    }
+
+    /// Start checking the linearity through the button prompts, with the
+    /// known weights in the order they are placed
+    pub fn begin_linearity(&mut self, weights: &[f32], full_scale_grams: f32) -> Procedure {
+        self.clear_button_events();
+        Procedure::linearity(weights, full_scale_grams).with_tare_samples(self.tare_samples)
+    }
+
+    /// Start measuring the noise of the empty scale
+    pub fn begin_noise_test(&mut self, thresholds: NoiseThresholds) -> Procedure {
+        self.clear_button_events();
+        Procedure::noise(thresholds, self.scale_factor)
+    }
+
+    /// Start calibrating with a known weight that is already on the tared
+    /// scale, without any prompts
+    pub fn begin_calibration_with_weight(&self, weight_grams: f32) -> Procedure {
+        Procedure::calibrate_with_weight(weight_grams, self.offset)
+    }
+
+    /// Apply the result of a completed tare, calibration, linearity check or
+    /// noise test.
+    /// Only the tare of the demo signal is applied, and not saved.
+    pub fn finish(&mut self, result: ProcedureResult) {
+        if self.demo.is_some() && !matches!(result, ProcedureResult::Tared { .. }) {
+            warn!("Calibrated on the demo signal, ignored");
+            self.clear_button_events();
+            return;
+        }
+        match result {
+            ProcedureResult::Tared { offset } => {
+                // The readings in the window move along with the zero, the
+                // weight steps to it at once
+                let moved = (offset - self.offset) as f32 * self.scale_factor.unwrap_or(1.0);
+                self.pipeline.tare(moved);
+                if self.creep_trace.take().is_some() {
+                    self.creep_result = Some(Err(CreepError::Cancelled));
+                }
+                self.gross = None;
+                // The soft tares were taken off the old zero
+                self.offset = offset;
+                self.save_offset();
+                self.soft_tare.clear();
+                self.hold.clear();
+                self.events.publish(WeightEvent::Tared);
+                counters::increment(Counter::Tares);
+            }
+            ProcedureResult::Calibrated {
+                offset,
+                scale_factor,
+            } => {
+                if offset != self.offset {
+                    self.offset = offset;
+                    self.save_offset();
+                    self.events.publish(WeightEvent::Tared);
+                }
+                self.soft_tare.clear();
+                self.hold.clear();
+                self.scale_factor = Some(scale_factor);
+                self.weight_gestures().set_scale_factor(self.scale_factor);
+                self.restart_filter();
+                self.events
+                    .publish(WeightEvent::Calibrated { scale_factor });
+                self.save_scale_factor(scale_factor);
+                self.reminder.calibrated(Moment::now(self.boot));
+                self.save_reminder();
+                counters::increment(Counter::Calibrations);
+                certified::calibration_changed();
+            }
+            // The check tares on its own, the weighing goes on from the
+            // offset it had
+            ProcedureResult::Linearity(report) => {
+                if let Err(err) = self.storage.put_checked(self.linearity_key, &report) {
+                    warn!("Failed to save the linearity report: {:?}", err);
+                }
+                self.linearity = Some(report);
+            }
+            ProcedureResult::Noise(report) => {
+                if let Err(err) = self.storage.put_checked(self.noise_key, &report) {
+                    warn!("Failed to save the noise test: {:?}", err);
+                }
+                self.noise = Some(report);
+            }
+        }
+        // Presses meant for the prompts start no gesture
+        self.clear_button_events();
+    }
 
-        println!("Calibration complete. Scale factor = {}", scale_factor);
-        println!("Saving calibration to NVS partition...");
+    /// Written through, a brown-out right after calibrating keeps it
+    fn save_scale_factor(&mut self, scale_factor: f32) {
+        debug!("Saving calibration to NVS partition...");
         if let Some(err) = self
-            .nvs_partition
-            .set_u32(SCALE_FACTOR_KEY, scale_factor.to_bits())
+            .storage
+            .set_f32(self.scale_factor_key, scale_factor)
+            .and_then(|()| self.storage.flush())
             .err()
         {
-            println!("Failed to save calibration to NVS partition: {:?}", err);
+            warn!("Failed to save calibration to NVS partition: {:?}", err);
         } else {
-            println!("Calibration saved to NVS partition.");
+            debug!("Calibration saved to NVS partition.");
         }
+    }
 
-        // Clear any pending button events
-        self.button_event_handle.clear_events();
+    fn save_offset(&self) {
+        // The zero of the demo signal would be restored over the load cell's
+        if self.demo.is_some() {
+            return;
+        }
+        if let Err(err) = self.storage.set_i32(self.offset_key, self.offset) {
+            warn!("Failed to save the offset: {:?}", err);
+        }
+    }
+
+    /// Weigh from the offset saved last instead of taring, returning
+    /// whether there was one
+    pub fn restore_offset(&mut self) -> bool {
+        let Some(offset) = self.storage.get_i32(self.offset_key) else {
+            return false;
+        };
+        info!("Restored the offset {}", offset);
+        self.offset = offset;
+        self.restart_filter();
+        true
+    }
+
+    fn save_reminder(&mut self) {
+        let state = *self.reminder.state();
+        match self.storage.put_checked(REMINDER_KEY, &state) {
+            Ok(()) => self.drift_saved = state.drift_grams,
+            Err(err) => warn!("Failed to save the calibration reminder: {:?}", err),
+        }
+    }
+
+    /// Why recalibrating is suggested, if it is
+    pub fn calibration_reminder(&self) -> Option<ReminderReason> {
+        self.reminder.due(&Moment::now(self.boot))
+    }
+
+    /// The reminder to show as a toast, once a day while it is due
+    pub fn take_calibration_toast(&mut self) -> Option<ReminderReason> {
+        let now = Moment::now(self.boot);
+        if self.reminder.date_calibration(&now) {
+            self.save_reminder();
+        }
+        let reason = self.reminder.take_toast(&now)?;
+        self.save_reminder();
+        Some(reason)
+    }
+
+    /// Quiet the reminder for `SNOOZE_DAYS`
+    pub fn snooze_calibration_reminder(&mut self) {
+        self.reminder.snooze(Moment::now(self.boot));
+        self.save_reminder();
+    }
+
+    /// Quiet the reminder until the next calibration
+    pub fn dismiss_calibration_reminder(&mut self) {
+        self.reminder.dismiss();
+        self.save_reminder();
+    }
+
+    /// Age in days of the calibration and the drift absorbed since
+    pub fn calibration_age(&self) -> (u64, f32) {
+        let state = self.reminder.state();
+        (
+            Moment::now(self.boot).days_since(&state.calibrated),
+            state.drift_grams,
+        )
+    }
+
+    pub fn scale_factor(&self) -> Option<f32> {
+        self.scale_factor
+    }
+
+    /// The calibration to move to another board, along with the sensor and
+    /// the gain it was made at. `None` when not calibrated.
+    pub fn export_calibration(&self, sensor: SensorKind, gain: u8) -> Option<CalibrationRecord> {
+        Some(CalibrationRecord {
+            sensor,
+            gain,
+            scale_factor: self.scale_factor?,
+            offset: self.offset,
+            calibrated_epoch_s: self.reminder.state().calibrated.epoch_s,
+            weight_grams: self.calibration_weight,
+        })
+    }
+
+    /// Take over the calibration of another board, once it is checked to be
+    /// for the sensor and the gain the scale runs at. It is saved right away,
+    /// and the reminder counts its age from when it was made. The linearity
+    /// report and the creep model of the old calibration go.
+    pub fn import_calibration(
+        &mut self,
+        record: &CalibrationRecord,
+        sensor: SensorKind,
+        gain: u8,
+    ) -> Result<(), CalTransferError> {
+        record.check_sensor(sensor, gain)?;
+        self.offset = record.offset;
+        self.save_offset();
+        self.soft_tare.clear();
+        self.hold.clear();
+        self.scale_factor = Some(record.scale_factor);
+        self.weight_gestures().set_scale_factor(self.scale_factor);
+        self.linearity = None;
+        self.pipeline.creep.configure(None);
+        if let Err(err) = self
+            .storage
+            .remove(self.linearity_key)
+            .and_then(|_| self.storage.remove(self.creep_key))
+        {
+            warn!("Failed to remove the old linearity and creep: {:?}", err);
+        }
+        self.restart_filter();
+        self.events.publish(WeightEvent::Tared);
+        self.events.publish(WeightEvent::Calibrated {
+            scale_factor: record.scale_factor,
+        });
+        self.save_scale_factor(record.scale_factor);
+        let calibrated = match record.calibrated_epoch_s {
+            0 => Moment::now(self.boot),
+            epoch_s => Moment {
+                epoch_s,
+                boot: self.boot,
+            },
+        };
+        self.reminder.calibrated(calibrated);
+        self.save_reminder();
+        certified::calibration_changed();
         Ok(())
     }
 
+    /// Raw reading captured by the last tare
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    /// Read the raw sensor counts, without tare or scaling applied
+    pub fn read_raw(&mut self) -> Option<i32> {
+        self.sensor
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .read()
+            .ok()
+    }
+
+    /// Whether the sensor has a reading ready within `timeout`, which it only
+    /// signals when powered and wired up
+    pub fn sensor_ready_within(&self, timeout: Duration) -> bool {
+        let mut sensor = self
+            .sensor
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if sensor.is_ready() {
+                return true;
+            }
+            std::thread::sleep(SAMPLING_POLL_PERIOD);
+        }
+        false
+    }
+
+    /// When the sensor last converted, whether the reading was kept or not
+    pub fn last_reading(&self) -> Instant {
+        *self
+            .last_conversion
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Time since the sensor last converted, a sensor that died leaves the
+    /// weight at its last value
+    pub fn last_reading_age(&self) -> Duration {
+        self.last_reading().elapsed()
+    }
+
+    /// Run `f` while no reading is being clocked out, e.g. a light sleep
+    /// that would otherwise stop the clock of the HX711 halfway through a
+    /// reading, powering it down
+    pub fn between_readings<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _sensor = self
+            .sensor
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f()
+    }
+
+    /// Reset the sensor after it stopped converting
+    pub fn reset_sensor(&mut self) -> Result<(), SensorError> {
+        self.sensor
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .reset()
+    }
+
+    /// Read the demo signal in place of the load cell, from the zero of the
+    /// current tare, or switch to another pattern. Nothing the demo does is
+    /// saved: its tares are forgotten once it is over and the calibrations
+    /// made on it are ignored.
+    pub fn start_demo(&mut self, pattern: DemoPattern) {
+        let counts_per_gram = 1.0 / self.scale_factor.unwrap_or(1.0);
+        let signal = DemoSensor::new(pattern.clone(), self.offset, counts_per_gram);
+        let sensor = std::mem::replace(
+            &mut *self
+                .sensor
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            Box::new(signal),
+        );
+        match &mut self.demo {
+            // The signal replaced goes, the load cell stays aside
+            Some(demo) => demo.pattern = pattern,
+            None => {
+                info!("Demo mode on, the load cell is not read");
+                self.demo = Some(Demo {
+                    sensor,
+                    offset: self.offset,
+                    pattern,
+                });
+            }
+        }
+        self.restart_filter();
+        self.hold.clear();
+    }
+
+    /// Read the load cell again, with the tare it had. Returns false when
+    /// the demo was not running.
+    pub fn stop_demo(&mut self) -> bool {
+        let Some(demo) = self.demo.take() else {
+            return false;
+        };
+        *self
+            .sensor
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = demo.sensor;
+        info!("Demo mode off, back to the load cell");
+        self.offset = demo.offset;
+        // The soft tares were taken off the demo weight
+        self.soft_tare.clear();
+        self.restart_filter();
+        self.hold.clear();
+        true
+    }
+
+    /// Pattern of the demo signal read in place of the load cell, if any
+    pub fn demo_pattern(&self) -> Option<&DemoPattern> {
+        self.demo.as_ref().map(|demo| &demo.pattern)
+    }
+
+    pub fn is_demo(&self) -> bool {
+        self.demo.is_some()
+    }
+
+    /// Discard any pending button events and gestures
+    pub fn clear_button_events(&mut self) {
+        self.button_event_handle.clear_events();
+        self.gesture_detector.reset();
+        self.weight_gestures().reset();
+    }
+
+    pub fn is_button_pressed(&self) -> bool {
+        self.button_event_handle.is_pressed()
+    }
+
+    /// Whether the button is stuck, its presses ignored until it recovers
+    pub fn is_button_stuck(&self) -> bool {
+        self.button_event_handle.is_stuck()
+    }
+
+    /// Poll the button for a completed gesture, or the presses on the
+    /// platform standing in for it
+    pub fn poll_button_action(&mut self) -> Option<ButtonAction> {
+        if let Some(action) = self.weight_gestures().poll(Instant::now()) {
+            return Some(action);
+        }
+        while let Some(TimedButtonEvent { event, at }) = self.button_event_handle.get_timed_event()
+        {
+            if let Some(action) = self.gesture_detector.on_event(event, at) {
+                return Some(action);
+            }
+        }
+        self.gesture_detector.poll(Instant::now())
+    }
+
     pub fn poll_action(&mut self) -> Option<ScaleAction> {
-        self.button_event_handle
-            .get_event()
-            .and_then(|button_event| match button_event {
-                ButtonEvent::Down => {
-                    self.last_button_event = Some(button_event);
-                    None
-                }
-                ButtonEvent::Held => {
-                    self.last_button_event
-                        .replace(button_event)
-                        .and_then(|last_event| {
-                            (last_event == ButtonEvent::Down).then_some(ScaleAction::Calibrate)
-                        })
-                }
-                ButtonEvent::Up => {
-                    self.last_button_event
-                        .replace(button_event)
-                        .and_then(|last_event| {
-                            (last_event == ButtonEvent::Down).then_some(ScaleAction::Tare)
-                        })
+        self.poll_button_action().map(ScaleAction::from)
+    }
+
+    /// What the press on the platform in progress asks for, none without
+    /// one
+    pub fn gesture_hint(&self) -> Option<GestureHint> {
+        self.weight_gestures().hint(Instant::now())
+    }
+
+    fn weight_gestures(&self) -> MutexGuard<'_, WeightGestureDetector> {
+        self.weight_gestures
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Run a reading of the sampling task through the filter
+    pub fn process_reading(&mut self, raw: i32, disturbed: bool) -> Sample {
+        let scale_factor = self.scale_factor.unwrap_or(1.0);
+        let grams_raw = (raw - self.offset) as f32 * scale_factor;
+        let weight = if disturbed && self.quiesce == QuiesceMode::Weight {
+            count_quiesced();
+            DISTURBED_WEIGHT
+        } else {
+            1.0
+        };
+        let now = Instant::now();
+        if let Some(trace) = self.trace.as_mut() {
+            if trace.on_reading(raw, grams_raw, now) {
+                self.trace_result = self.trace.take().map(TraceRecorder::into_points);
+            }
+        }
+        // The demo signal does not creep
+        let compensate_creep = self.demo.is_none();
+        let mut weighed =
+            self.pipeline
+                .push(grams_raw, weight, compensate_creep, self.resolution, now);
+        let stable = weighed.stable;
+        // The trace is of the weight as read, the creep and all
+        if let Some(result) = self
+            .creep_trace
+            .as_mut()
+            .and_then(|trace| trace.on_sample(weighed.filtered, stable, now))
+        {
+            self.creep_trace = None;
+            self.creep_result = Some(result);
+        }
+        // The zero tracking follows the zero of the offset, whatever is
+        // reported
+        if let Some(grams) = weighed.zero {
+            let moved = self.track_zero(grams);
+            // From the zero just moved, along with the window
+            self.pipeline.rebase(&mut weighed, moved);
+        }
+        self.gross = Some(weighed.gross);
+        let grams_filtered = self.soft_tare.apply(weighed.gross);
+        let overloaded = quality::is_saturated(raw)
+            || self
+                .capacity
+                .is_some_and(|capacity| weighed.gross > capacity);
+        let quality = Quality::of(stable, disturbed, overloaded);
+        self.last_sample = Some((grams_filtered, quality));
+        self.time_weighted.lock().on_reading(grams_filtered, now);
+        self.publish_weight(grams_filtered, stable);
+        // The readings themselves, the filter lags behind a restless load
+        let grams_raw = self.soft_tare.apply(grams_raw - weighed.creep);
+        if self.hold.on_sample(grams_raw, now) {
+            debug!("Auto-hold caught {:?}", self.hold.state());
+        }
+
+        Sample {
+            raw,
+            grams_raw,
+            grams_filtered,
+            stable,
+            quality,
+        }
+    }
+
+    /// Last filtered weight along with its quality and age, stale once the
+    /// sensor has been silent for the stale time. None before the first
+    /// reading.
+    pub fn poll_reading(&self) -> Option<Reading> {
+        self.last_sample.map(|(grams, quality)| {
+            Reading::aged(grams, quality, self.last_reading_age(), self.stale_after)
+        })
+    }
+
+    /// Last filtered weight, whatever its quality
+    pub fn poll_grams(&self) -> Option<f32> {
+        self.poll_reading().map(|reading| reading.grams)
+    }
+
+    /// Time without a reading after which the last one is stale
+    pub fn stale_reading(&self) -> Duration {
+        self.stale_after
+    }
+
+    pub fn set_stale_reading(&mut self, stale_after: Duration) {
+        self.stale_after = stale_after;
+        self.time_weighted.lock().set_max_gap(stale_after);
+    }
+
+    /// Weight of a reading above the offset, without going through the
+    /// filter
+    pub fn gross_grams(&self, raw: i32) -> f32 {
+        (raw - self.offset) as f32 * self.scale_factor.unwrap_or(1.0)
+    }
+
+    /// Weight above the offset, before the soft tares, settled or not
+    pub fn gross(&self) -> Option<f32> {
+        self.gross
+    }
+
+    /// Weight above the offset, before the soft tares, once it settled
+    pub fn stable_gross(&self) -> Option<f32> {
+        self.gross.filter(|_| self.pipeline.filter.is_stable())
+    }
+
+    /// Freeze the weight estimated from the readings of the last seconds,
+    /// robust to a load that never settles. Returns it, none without enough
+    /// readings yet or in certified mode.
+    pub fn hold(&mut self) -> Option<f32> {
+        certified::manual_hold(&mut self.hold, self.certified)
+    }
+
+    /// Back to the live weight, returning whether it was held
+    pub fn release_hold(&mut self) -> bool {
+        self.hold.release()
+    }
+
+    pub fn hold_state(&self) -> HoldState {
+        self.hold.state()
+    }
+
+    /// Tare the weight now on the scale in software, on top of the tares
+    /// already stacked, and report the net weight. Returns false before the
+    /// first reading or with the stack full.
+    pub fn soft_tare(&mut self) -> bool {
+        match self.gross {
+            Some(gross) => self.soft_tare.push(gross),
+            None => false,
+        }
+    }
+
+    /// Remove the last soft tare, returning whether there was one
+    pub fn untare(&mut self) -> bool {
+        self.soft_tare.pop().is_some()
+    }
+
+    /// Remove all the soft tares, back to the gross weight
+    pub fn clear_soft_tare(&mut self) {
+        self.soft_tare.clear();
+    }
+
+    /// Switch between reporting the net and the gross weight
+    pub fn toggle_net_gross(&mut self) -> DisplayMode {
+        self.soft_tare.toggle()
+    }
+
+    pub fn display_mode(&self) -> DisplayMode {
+        self.soft_tare.mode()
+    }
+
+    /// Number of soft tares stacked
+    pub fn soft_tare_depth(&self) -> usize {
+        self.soft_tare.depth()
+    }
+
+    /// Grams taken off by the soft tares
+    pub fn soft_tare_grams(&self) -> f32 {
+        self.soft_tare.total()
+    }
+
+    /// Start the filter over after the zero or the scale factor changed.
+    /// The readings in its window were taken against the old ones, maybe
+    /// under another load, so they go: the weight is that of the readings
+    /// from the next one on, not stable until the window refilled. The
+    /// gross weight goes along, a soft tare before the next reading would
+    /// take off the weight from before.
+    fn restart_filter(&mut self) {
+        self.pipeline.filter.reset();
+        self.gross = None;
+        // The weight on the scale now counts as settled
+        self.pipeline.creep.reset();
+        if self.creep_trace.take().is_some() {
+            self.creep_result = Some(Err(CreepError::Cancelled));
+        }
+    }
+
+    /// Move the zero by `grams` as near as the counts go, counting it as
+    /// drift. Returns the grams it moved by.
+    fn track_zero(&mut self, grams: f32) -> f32 {
+        let Some(scale_factor) = self.scale_factor.filter(|_| self.demo.is_none()) else {
+            return 0.0;
+        };
+        let counts = (grams / scale_factor).round() as i32;
+        if counts == 0 {
+            return 0.0;
+        }
+        self.offset += counts;
+        let moved = counts as f32 * scale_factor;
+        self.reminder.absorb(moved);
+        if (self.reminder.state().drift_grams - self.drift_saved).abs() >= DRIFT_SAVE_STEP_GRAMS {
+            self.save_reminder();
+            // The zero it followed is restored along with it
+            self.save_offset();
+        }
+        moved
+    }
+
+    /// Time-weighted average of the weight reported over the window ending
+    /// now, along with the share of it the readings cover
+    pub fn time_weighted_average(&self, window: Duration) -> TimeAverage {
+        self.time_weighted.average(window)
+    }
+
+    /// Integral the average is taken from, for the tasks reading it
+    pub fn time_weighted(&self) -> SharedTimeWeighted {
+        self.time_weighted.clone()
+    }
+
+    /// Receive the weight events of this scale, e.g. from a publishing task
+    pub fn subscribe(&mut self) -> Receiver<WeightEvent> {
+        self.events.subscribe()
+    }
+
+    /// Receive the weight events of this scale, the changes of the weight
+    /// only once they moved by `min_delta_grams` and `min_interval` passed
+    /// since the last one received
+    pub fn subscribe_with_threshold(
+        &mut self,
+        min_delta_grams: f32,
+        min_interval: Duration,
+    ) -> Receiver<WeightEvent> {
+        self.events.subscribe_with_threshold(ChangeThreshold {
+            min_delta_grams,
+            min_interval,
+        })
+    }
+
+    /// Receive the weight events of this scale, the changes of the weight
+    /// through the returned sink at the rate it is registered with, and
+    /// within the same threshold
+    pub fn subscribe_through_sink(
+        &mut self,
+        name: &'static str,
+        min_delta_grams: f32,
+        min_interval: Duration,
+    ) -> (Receiver<WeightEvent>, WeightSink) {
+        self.events.subscribe_through_sink(
+            name,
+            ChangeThreshold {
+                min_delta_grams,
+                min_interval,
+            },
+        )
+    }
+
+    fn publish_weight(&mut self, grams: f32, stable: bool) {
+        let last_published = self.last_published.replace((grams, stable));
+        if last_published == Some((grams, stable)) {
+            return;
+        }
+        self.events.publish(WeightEvent::Changed { grams, stable });
+        if stable && !last_published.is_some_and(|(_, was_stable)| was_stable) {
+            self.events.publish(WeightEvent::Stable { grams });
+        }
+    }
+
+    pub fn round_to_resolution(&self, grams: f32) -> f32 {
+        (grams / self.resolution).round() * self.resolution
+    }
+
+    /// Start the task reading the sensor as soon as a reading is ready, handing
+    /// the raw counts to the main loop. Readings converted during a display
+    /// flush are flagged, or dropped with `QuiesceMode::Discard`, and those
+    /// converted while the IMU felt a bump are dropped.
+    pub fn start_sampling(&self, app_events: SyncSender<AppEvent>) -> Result<(), ScaleError> {
+        let sensor = self.sensor.clone();
+        let last_conversion = self.last_conversion.clone();
+        let settle_discards = self.settle_discards.clone();
+        let weight_gestures = self.weight_gestures.clone();
+        let quiesce = self.quiesce;
+        std::thread::Builder::new()
+            .name("sampling".to_string())
+            .stack_size(SAMPLING_TASK_STACK_SIZE)
+            .spawn(move || {
+                let watchdog = WatchdogGuard::subscribe("sampling");
+                // A reading is converted between the previous one and itself
+                let mut mark = QuiesceMark::now();
+                let mut discarded = 0;
+                let mut bump_discarded = 0;
+                loop {
+                    watchdog.feed();
+                    let reading = sensor
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .read();
+                    if let Ok(raw) = reading {
+                        *last_conversion
+                            .lock()
+                            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+                        let disturbed =
+                            quiesce != QuiesceMode::Off && mark.disturbed(Disturbance::Flush);
+                        let bumped = mark.disturbed(Disturbance::Bump);
+                        mark = QuiesceMark::now();
+                        let settling = settle_discards
+                            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                                left.checked_sub(1)
+                            })
+                            .is_ok();
+                        if settling {
+                            // Still settling at the new rate
+                        } else if bumped && bump_discarded < MAX_CONSECUTIVE_BUMP_DISCARDS {
+                            bump_discarded += 1;
+                            count_bumped();
+                        } else if disturbed
+                            && quiesce == QuiesceMode::Discard
+                            && discarded < MAX_CONSECUTIVE_DISCARDS
+                        {
+                            discarded += 1;
+                            count_quiesced();
+                        } else {
+                            discarded = 0;
+                            bump_discarded = 0;
+                            // Seen here, the menus and prompts do not take
+                            // the readings
+                            weight_gestures
+                                .lock()
+                                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                .on_reading(raw, Instant::now());
+                            let event = AppEvent::Reading {
+                                raw,
+                                at: Instant::now(),
+                                disturbed,
+                            };
+                            // A full queue only drops a reading, the next one
+                            // follows
+                            if let Err(TrySendError::Disconnected(_)) = app_events.try_send(event) {
+                                return;
+                            }
+                        }
+                    }
+                    std::thread::sleep(SAMPLING_POLL_PERIOD);
                 }
             })
+            .map_err(ScaleError::Sampling)?;
+        Ok(())
     }
+}
 
-    pub fn poll_grams(&mut self) -> Option<f32> {
-        self.hx711.read_scaled().ok()
-    }
+/// Migration putting the structures stored before the checksums into their
+/// envelope. The scale factors and offsets are single NVS entries, which
+/// NVS checks itself.
+pub fn seal_blobs(storage: &mut Storage) -> Result<(), StoreError> {
+    [
+        REMINDER_KEY,
+        LINEARITY_KEY,
+        NAU7802_LINEARITY_KEY,
+        CREEP_KEY,
+        NAU7802_CREEP_KEY,
+    ]
+    .into_iter()
+    .try_for_each(|key| storage.seal_blob(key))
 }
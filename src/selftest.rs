@@ -0,0 +1,184 @@
+//! Checks run at boot, so a miswired scale says what is wrong instead of
+//! hanging. Each check is reported on the display and in the log. The scale
+//! carries on without a display, a calibration or a working button, but not
+//! without its sensor.
+
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use ssd1306::{prelude::WriteOnlyDataCommand, size::DisplaySize};
+
+use crate::{
+    cal_transfer::factor_in_range,
+    display,
+    scale::Scale,
+    settings::SettingsStore,
+    text_drawer::{DisplayError, TextDrawer, TextError},
+};
+
+/// Time the sensor gets to signal its first reading, a few output periods
+const SENSOR_READY_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time the button may stay pressed at boot before it is reported stuck
+const BUTTON_STUCK_TIME: Duration = Duration::from_secs(2);
+const BUTTON_POLL: Duration = Duration::from_millis(50);
+/// Time a degraded check stays on the screen
+const DEGRADED_MESSAGE: Duration = Duration::from_millis(1500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    Storage,
+    Display,
+    Sensor,
+    Button,
+    Calibration,
+}
+
+impl Check {
+    /// Name in the log and in errors
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::Storage => "settings storage",
+            Check::Display => "display",
+            Check::Sensor => "load cell sensor",
+            Check::Button => "button",
+            Check::Calibration => "calibration",
+        }
+    }
+
+    /// Name on the display
+    pub fn label(self) -> &'static str {
+        match self {
+            Check::Storage => "Storage",
+            Check::Display => "Display",
+            Check::Sensor => "Sensor",
+            Check::Button => "Button",
+            Check::Calibration => "Calibration",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// Failed, the scale works without it
+    Degraded,
+    /// Failed, the scale cannot weigh
+    Fatal,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckResult {
+    pub check: Check,
+    pub outcome: Outcome,
+    /// What failed, or what was found when passing
+    pub detail: &'static str,
+}
+
+impl CheckResult {
+    fn new(check: Check, outcome: Outcome, detail: &'static str) -> Self {
+        Self {
+            check,
+            outcome,
+            detail,
+        }
+    }
+}
+
+/// Run the checks in order, showing each one on the display. A calibration
+/// factor out of bounds is forgotten, so the scale asks for a new one.
+/// Returns every result, stopping at the first fatal one.
+pub fn run<DI, SIZE>(
+    scale: &mut Scale,
+    settings_store: &SettingsStore,
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+) -> Result<Vec<CheckResult>, TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let checks = [
+        Check::Storage,
+        Check::Display,
+        Check::Sensor,
+        Check::Button,
+        Check::Calibration,
+    ];
+    let prompt = text_drawer.layout().prompt.top_left;
+    let mut results = Vec::with_capacity(checks.len());
+    for check in checks {
+        text_drawer.draw_text_clear_flush(&format!("Self-test\n{}...", check.label()), prompt)?;
+        let result = match check {
+            Check::Storage => check_storage(settings_store),
+            Check::Display => check_display(),
+            Check::Sensor => check_sensor(scale),
+            Check::Button => check_button(scale),
+            Check::Calibration => check_calibration(scale),
+        };
+        match result.outcome {
+            Outcome::Pass => info!("Self-test {}: {}", check.name(), result.detail),
+            Outcome::Degraded => {
+                warn!("Self-test {}: {}", check.name(), result.detail);
+                text_drawer.draw_text_clear_flush(
+                    &format!("{}\n{}", check.label(), result.detail),
+                    prompt,
+                )?;
+                std::thread::sleep(DEGRADED_MESSAGE);
+            }
+            Outcome::Fatal => error!("Self-test {} failed: {}", check.name(), result.detail),
+        }
+        results.push(result);
+        if result.outcome == Outcome::Fatal {
+            break;
+        }
+    }
+    Ok(results)
+}
+
+fn check_storage(settings_store: &SettingsStore) -> CheckResult {
+    if settings_store.is_readable() {
+        CheckResult::new(Check::Storage, Outcome::Pass, "ok")
+    } else {
+        CheckResult::new(Check::Storage, Outcome::Degraded, "using defaults")
+    }
+}
+
+/// The panel was looked for when creating the display interface
+fn check_display() -> CheckResult {
+    match display::detected_address() {
+        Some(_) => CheckResult::new(Check::Display, Outcome::Pass, "ok"),
+        None => CheckResult::new(Check::Display, Outcome::Degraded, "headless"),
+    }
+}
+
+fn check_sensor(scale: &Scale) -> CheckResult {
+    if scale.sensor_ready_within(SENSOR_READY_TIMEOUT) {
+        CheckResult::new(Check::Sensor, Outcome::Pass, "ok")
+    } else {
+        CheckResult::new(Check::Sensor, Outcome::Fatal, "no data on DT")
+    }
+}
+
+fn check_button(scale: &Scale) -> CheckResult {
+    let start = Instant::now();
+    while scale.is_button_pressed() {
+        if start.elapsed() >= BUTTON_STUCK_TIME {
+            return CheckResult::new(Check::Button, Outcome::Degraded, "stuck pressed?");
+        }
+        std::thread::sleep(BUTTON_POLL);
+    }
+    CheckResult::new(Check::Button, Outcome::Pass, "ok")
+}
+
+fn check_calibration(scale: &mut Scale) -> CheckResult {
+    let Some(scale_factor) = scale.scale_factor() else {
+        return CheckResult::new(Check::Calibration, Outcome::Degraded, "not calibrated");
+    };
+    if factor_in_range(scale_factor) {
+        return CheckResult::new(Check::Calibration, Outcome::Pass, "ok");
+    }
+    warn!("Stored scale factor {} is out of bounds", scale_factor);
+    if let Err(err) = scale.reset_calibration() {
+        warn!("Failed to reset calibration: {:?}", err);
+    }
+    CheckResult::new(Check::Calibration, Outcome::Degraded, "factor invalid")
+}
@@ -0,0 +1,166 @@
+//! The load cell ADCs the scale can read: the bit-banged HX711, or the
+//! NAU7802 on the I2C bus of the display. Either only hands out raw counts,
+//! the tare, the scale factor and the filtering are left to the scale, so it
+//! works the same on both. The counts of one do not compare to the other's,
+//! so each keeps a scale factor of its own.
+
+use std::time::Duration;
+
+use thiserror::Error;
+
+#[cfg(feature = "esp")]
+use esp_idf_hal::{
+    delay::Delay,
+    gpio::{AnyInputPin, AnyOutputPin, Input, Output, PinDriver},
+};
+#[cfg(feature = "esp")]
+use loadcell::{hx711::HX711, LoadCell};
+
+/// Gains of the NAU7802 PGA, in the order of their register bits
+pub const NAU7802_GAINS: [u8; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
+/// Gain of the NAU7802 unless set otherwise, for the millivolts of a load cell
+pub const DEFAULT_NAU7802_GAIN: u8 = 128;
+/// Bounds of the time without a conversion before the weight shows as
+/// stale, the sensors convert every 100ms
+pub const MIN_STALE_READING_MS: u32 = 200;
+pub const MAX_STALE_READING_MS: u32 = 60_000;
+/// Longest time without a conversion before the sensor is reset
+pub const MAX_SENSOR_LOST_S: u32 = 3600;
+/// Conversions the HX711 takes to settle once its rate changed, 400ms at
+/// 10 SPS and 50ms at 80 SPS
+pub const RATE_SETTLE_CONVERSIONS: u32 = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SensorKind {
+    #[default]
+    Hx711,
+    Nau7802,
+}
+
+impl SensorKind {
+    pub const ALL: [SensorKind; 2] = [SensorKind::Hx711, SensorKind::Nau7802];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SensorKind::Hx711 => "hx711",
+            SensorKind::Nau7802 => "nau7802",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            SensorKind::Hx711 => 0,
+            SensorKind::Nau7802 => 1,
+        }
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+}
+
+/// Conversion rate of the HX711, picked by the level of its RATE pin
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleRate {
+    /// RATE low, as on the boards with the pin tied to ground
+    #[default]
+    Sps10,
+    /// RATE high, faster to follow the weight but noisier
+    Sps80,
+}
+
+impl SampleRate {
+    pub const ALL: [SampleRate; 2] = [SampleRate::Sps10, SampleRate::Sps80];
+
+    /// Conversions per second, also the name on the console
+    pub fn hz(self) -> u32 {
+        match self {
+            SampleRate::Sps10 => 10,
+            SampleRate::Sps80 => 80,
+        }
+    }
+
+    pub fn from_hz(hz: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|rate| rate.hz() == hz)
+    }
+
+    /// Time between two conversions
+    pub fn period(self) -> Duration {
+        Duration::from_secs(1) / self.hz()
+    }
+
+    /// Conversions made over `time`, at least one
+    pub fn samples_in(self, time: Duration) -> usize {
+        ((time.as_secs_f32() * self.hz() as f32).round() as usize).max(1)
+    }
+
+    /// Time `samples` conversions take
+    pub fn time_of(self, samples: usize) -> Duration {
+        self.period() * samples as u32
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorError {
+    #[error("No conversion ready")]
+    NotReady,
+    /// Error code of the failed bus transfer
+    #[error("Bus error {0}")]
+    Bus(i32),
+    /// Error code of the failed GPIO write
+    #[error("GPIO error {0}")]
+    Pin(i32),
+}
+
+/// A load cell ADC, read by the sampling task
+pub trait LoadSensor: Send {
+    /// Whether a new conversion can be read
+    fn is_ready(&mut self) -> bool;
+
+    /// Latest conversion in counts
+    fn read(&mut self) -> Result<i32, SensorError>;
+
+    /// Bring the ADC back once it stopped converting. Nothing to do for the
+    /// ADCs that cannot be reset.
+    fn reset(&mut self) -> Result<(), SensorError> {
+        Ok(())
+    }
+}
+
+/// Output driving the RATE pin of the HX711
+pub trait RatePin: Send {
+    fn drive(&mut self, rate: SampleRate) -> Result<(), SensorError>;
+}
+
+/// The HX711 converts at 80 SPS with its RATE pin high
+#[cfg(feature = "esp")]
+impl RatePin for PinDriver<'static, AnyOutputPin, Output> {
+    fn drive(&mut self, rate: SampleRate) -> Result<(), SensorError> {
+        match rate {
+            SampleRate::Sps10 => self.set_low(),
+            SampleRate::Sps80 => self.set_high(),
+        }
+        .map_err(|err| SensorError::Pin(err.code()))
+    }
+}
+
+#[cfg(feature = "esp")]
+pub type Hx711 =
+    HX711<PinDriver<'static, AnyOutputPin, Output>, PinDriver<'static, AnyInputPin, Input>, Delay>;
+
+#[cfg(feature = "esp")]
+impl LoadSensor for Hx711 {
+    fn is_ready(&mut self) -> bool {
+        HX711::is_ready(self)
+    }
+
+    fn read(&mut self) -> Result<i32, SensorError> {
+        LoadCell::read(self).map_err(|_| SensorError::NotReady)
+    }
+}
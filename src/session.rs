@@ -0,0 +1,321 @@
+//! Running totals of a weighing session, e.g. a day at a market stall. An
+//! item counts once it was put on the scale, settled and was taken off again
+//! down to empty, with the largest stable weight it settled at. Taking part
+//! of it off or swapping items without emptying the scale in between counts
+//! as one weighing only.
+
+use log::warn;
+
+use crate::storage::{Storage, StorageService, StoreError, Stored};
+use crate::time::Timestamp;
+
+/// Number of ended sessions kept
+pub const SESSION_HISTORY_LEN: usize = 5;
+/// Stable weight counting as something on the scale
+const LOADED_GRAMS: f32 = 5.0;
+/// Stable weight within which the scale counts as empty again
+const EMPTY_GRAMS: f32 = 2.0;
+
+pub const SESSION_NAMESPACE: &str = "session";
+const CURRENT_KEY: &str = "current";
+const HISTORY_KEY: &str = "history";
+const LAST_WEIGHT_KEY: &str = "last_weight";
+const ENCODED_LEN: usize = 20;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SessionStats {
+    /// Weighings counted
+    pub count: u32,
+    pub total_grams: f32,
+    pub max_grams: f32,
+    /// Seconds since the epoch the session started at, 0 when the clock was
+    /// not synchronized yet
+    pub started_s: u64,
+}
+
+impl SessionStats {
+    /// Stats of a session starting now
+    fn starting_now() -> Self {
+        let started_s = match Timestamp::now() {
+            Timestamp::WallClock { since_epoch, .. } => since_epoch.as_secs(),
+            Timestamp::Uptime(_) => 0,
+        };
+        Self {
+            started_s,
+            ..Self::default()
+        }
+    }
+
+    pub fn average_grams(&self) -> Option<f32> {
+        (self.count > 0).then(|| self.total_grams / self.count as f32)
+    }
+
+    /// Time the session started at, if the clock was synchronized
+    pub fn started(&self) -> Option<Timestamp> {
+        (self.started_s > 0).then(|| Timestamp::WallClock {
+            since_epoch: std::time::Duration::from_secs(self.started_s),
+            offset_minutes: 0,
+        })
+    }
+}
+
+impl Stored for SessionStats {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        bytes.extend_from_slice(&self.total_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.max_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.started_s.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; ENCODED_LEN] = bytes.try_into().ok()?;
+        let stats = Self {
+            count: u32::from_le_bytes(bytes[0..4].try_into().ok()?),
+            total_grams: f32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            max_grams: f32::from_le_bytes(bytes[8..12].try_into().ok()?),
+            started_s: u64::from_le_bytes(bytes[12..20].try_into().ok()?),
+        };
+        (stats.total_grams.is_finite() && stats.max_grams.is_finite()).then_some(stats)
+    }
+}
+
+/// Counts the weighings of the current session and keeps the last ones
+pub struct SessionTracker {
+    current: SessionStats,
+    /// Ended sessions, the latest first
+    history: Vec<SessionStats>,
+    /// Largest stable weight since the scale was last empty
+    plateau: Option<f32>,
+    /// Whether the stats changed since they were last saved
+    unsaved: bool,
+}
+
+impl Default for SessionTracker {
+    fn default() -> Self {
+        Self::new(SessionStats::starting_now(), Vec::new())
+    }
+}
+
+impl SessionTracker {
+    pub fn new(current: SessionStats, mut history: Vec<SessionStats>) -> Self {
+        history.truncate(SESSION_HISTORY_LEN);
+        Self {
+            current,
+            history,
+            plateau: None,
+            unsaved: false,
+        }
+    }
+
+    /// Follow a stable weight. Returns whether a weighing was counted.
+    pub fn on_stable(&mut self, grams: f32) -> bool {
+        if grams >= LOADED_GRAMS {
+            self.plateau = Some(self.plateau.map_or(grams, |plateau| plateau.max(grams)));
+            return false;
+        }
+        if grams.abs() > EMPTY_GRAMS {
+            return false;
+        }
+        let Some(plateau) = self.plateau.take() else {
+            return false;
+        };
+        self.current.count += 1;
+        self.current.total_grams += plateau;
+        self.current.max_grams = self.current.max_grams.max(plateau);
+        self.unsaved = true;
+        true
+    }
+
+    /// A tare empties the scale without anything being taken off
+    pub fn on_tare(&mut self) {
+        self.plateau = None;
+    }
+
+    pub fn session_stats(&self) -> SessionStats {
+        self.current
+    }
+
+    /// Ended sessions, the latest first
+    pub fn history(&self) -> &[SessionStats] {
+        &self.history
+    }
+
+    /// Archive the current session and start over
+    pub fn new_session(&mut self) {
+        self.history.insert(0, self.current);
+        self.history.truncate(SESSION_HISTORY_LEN);
+        self.current = SessionStats::starting_now();
+        self.plateau = None;
+        self.unsaved = true;
+    }
+
+    /// Whether the stats changed since the last call
+    pub fn take_unsaved(&mut self) -> bool {
+        std::mem::replace(&mut self.unsaved, false)
+    }
+}
+
+/// The session stats in NVS, so a power blip does not lose the day
+#[derive(Clone)]
+pub struct SessionStore {
+    storage: Storage,
+}
+
+impl SessionStore {
+    pub fn open(storage: &StorageService) -> Result<Self, StoreError> {
+        Ok(Self {
+            storage: storage.open(SESSION_NAMESPACE)?,
+        })
+    }
+
+    /// The sessions saved last, a new one when there are none
+    pub fn load(&self) -> SessionTracker {
+        let storage = &self.storage;
+        let Some(current) = storage.get_checked(CURRENT_KEY) else {
+            return SessionTracker::default();
+        };
+        let history = storage
+            .get_checked_blob(HISTORY_KEY)
+            .unwrap_or_default()
+            .chunks(ENCODED_LEN)
+            .filter_map(SessionStats::decode)
+            .collect();
+        SessionTracker::new(current, history)
+    }
+
+    pub fn save(&self, sessions: &SessionTracker) {
+        let history: Vec<u8> = sessions
+            .history()
+            .iter()
+            .flat_map(SessionStats::encode)
+            .collect();
+        let storage = &self.storage;
+        let saved = storage
+            .put_checked(CURRENT_KEY, &sessions.session_stats())
+            .and_then(|()| storage.put_checked_blob(HISTORY_KEY, &history));
+        if let Err(err) = saved {
+            warn!("Failed to save the session stats: {:?}", err);
+        }
+    }
+
+    /// Stable weight above the tare saved last, checked against the weight
+    /// after a restart
+    pub fn last_weight(&self) -> Option<f32> {
+        self.storage.get_f32(LAST_WEIGHT_KEY)
+    }
+
+    pub fn save_last_weight(&self, grams: f32) {
+        if let Err(err) = self.storage.set_f32(LAST_WEIGHT_KEY, grams) {
+            warn!("Failed to save the last weight: {:?}", err);
+        }
+    }
+}
+
+/// Migration putting the sessions stored before the checksums into their
+/// envelope
+pub fn seal_blobs(storage: &mut Storage) -> Result<(), StoreError> {
+    storage.seal_blob(CURRENT_KEY)?;
+    storage.seal_blob(HISTORY_KEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> SessionTracker {
+        SessionTracker::new(SessionStats::default(), Vec::new())
+    }
+
+    /// Follow the stable weights, returning the weighings counted
+    fn follow(tracker: &mut SessionTracker, weights: &[f32]) -> usize {
+        weights
+            .iter()
+            .filter(|&&grams| tracker.on_stable(grams))
+            .count()
+    }
+
+    #[test]
+    fn counts_an_item_once_taken_off() {
+        let mut tracker = tracker();
+        assert_eq!(follow(&mut tracker, &[0.0, 250.0, 250.0, 0.0, 0.0]), 1);
+        let stats = tracker.session_stats();
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.total_grams, 250.0);
+        assert_eq!(stats.max_grams, 250.0);
+        assert!(tracker.take_unsaved());
+        assert!(!tracker.take_unsaved());
+    }
+
+    #[test]
+    fn partial_removal_counts_once_at_the_max() {
+        let mut tracker = tracker();
+        // Half of it taken off, then the rest
+        assert_eq!(follow(&mut tracker, &[400.0, 200.0, 100.0, 0.0]), 1);
+        let stats = tracker.session_stats();
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.total_grams, 400.0);
+    }
+
+    #[test]
+    fn swap_without_emptying_counts_once_at_the_max() {
+        let mut tracker = tracker();
+        // One item swapped for a heavier one, then for a lighter one
+        assert_eq!(follow(&mut tracker, &[300.0, 500.0, 150.0, 1.0]), 1);
+        let stats = tracker.session_stats();
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.total_grams, 500.0);
+        assert_eq!(stats.max_grams, 500.0);
+    }
+
+    #[test]
+    fn counts_each_plateau() {
+        let mut tracker = tracker();
+        follow(&mut tracker, &[120.0, 0.0, 80.0, 100.0, 0.0, 300.0, -0.5]);
+        let stats = tracker.session_stats();
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.total_grams, 520.0);
+        assert_eq!(stats.max_grams, 300.0);
+        assert_eq!(stats.average_grams(), Some(520.0 / 3.0));
+    }
+
+    #[test]
+    fn hysteresis_between_loaded_and_empty() {
+        let mut tracker = tracker();
+        // Under the loaded weight nothing is on the scale
+        assert_eq!(follow(&mut tracker, &[4.9, 3.0, 0.0]), 0);
+        // Between the two the scale is neither loaded nor empty
+        assert_eq!(follow(&mut tracker, &[5.0, 3.0, 4.0, 2.5]), 0);
+        assert_eq!(follow(&mut tracker, &[2.0]), 1);
+        assert_eq!(tracker.session_stats().total_grams, 5.0);
+        // Nor does hovering around the empty weight count again
+        assert_eq!(follow(&mut tracker, &[2.5, 1.0, 4.9, -2.0]), 0);
+        assert_eq!(tracker.session_stats().count, 1);
+    }
+
+    #[test]
+    fn tare_forgets_the_plateau() {
+        let mut tracker = tracker();
+        follow(&mut tracker, &[250.0]);
+        tracker.on_tare();
+        assert_eq!(follow(&mut tracker, &[0.0]), 0);
+        assert_eq!(tracker.session_stats().count, 0);
+    }
+
+    #[test]
+    fn new_session_archives_the_current_one() {
+        let mut tracker = tracker();
+        follow(&mut tracker, &[250.0, 0.0]);
+        for _ in 0..SESSION_HISTORY_LEN + 1 {
+            tracker.new_session();
+        }
+        assert_eq!(tracker.session_stats().count, 0);
+        assert_eq!(tracker.history().len(), SESSION_HISTORY_LEN);
+        assert_eq!(tracker.history()[SESSION_HISTORY_LEN - 1].count, 0);
+        // A plateau of the session ended is not counted in the next
+        follow(&mut tracker, &[250.0]);
+        tracker.new_session();
+        assert_eq!(follow(&mut tracker, &[0.0]), 0);
+    }
+}
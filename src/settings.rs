@@ -0,0 +1,2562 @@
+use std::{sync::Mutex, time::Duration};
+
+use log::{info, warn, LevelFilter};
+#[cfg(feature = "display")]
+use ssd1306::rotation::DisplayRotation;
+use thiserror::Error;
+
+use crate::alarms::{AlarmConfig, AlarmKind, MAX_ALARMS};
+use crate::button::{
+    DEFAULT_BUTTON_DISCONNECT_H, DEFAULT_BUTTON_STUCK_S, MAX_BUTTON_DISCONNECT_H,
+    MAX_BUTTON_STUCK_S,
+};
+use crate::deadband::{DeadbandSetting, MAX_DEADBAND_GRAMS, MAX_DEADBAND_K};
+use crate::espnow::{
+    EspNowRole, Mac, Peer, DEFAULT_STALE_MS, MAX_LABEL_LEN, MAX_PEERS, MAX_STALE_MS, MIN_STALE_MS,
+};
+use crate::frame_rate::{DEFAULT_MAX_FPS, MAX_FPS};
+use crate::governor::{DEFAULT_HEAP_CRITICAL_KB, DEFAULT_HEAP_RESERVE_KB, MAX_HEAP_THRESHOLD_KB};
+use crate::hold::{AutoHold, MAX_AUTO_HOLD_S};
+use crate::i18n::Language;
+use crate::linearity::MAX_LINEARITY_WEIGHTS;
+use crate::lock::{ClickPattern, LockConfig, DEFAULT_RELOCK_S, MAX_PIN};
+use crate::modbus::{MAX_MODBUS_ADDRESS, MODBUS_BAUD_RATES};
+use crate::negative::{
+    NegativeConfig, DEFAULT_NEGATIVE_DELAY_S, DEFAULT_NEGATIVE_GRAMS, MAX_NEGATIVE_DELAY_S,
+};
+use crate::noise::{NoiseThresholds, DEFAULT_NOISE_FAIL_COUNTS, DEFAULT_NOISE_WARN_COUNTS};
+use crate::panic_screen::MAX_PANIC_HOLD_S;
+use crate::power::{
+    can_wake_from_deep_sleep, ActiveWindow, IdleStage, IdleTimeouts, Schedule, MAX_IDLE_TIMEOUT_S,
+    MAX_SCHEDULE_WINDOWS, MIN_IDLE_TIMEOUT_S,
+};
+use crate::procedure::{DEFAULT_TARE_COOLDOWN, MAX_TARE_COOLDOWN};
+use crate::quiesce::QuiesceMode;
+use crate::sensor::{
+    SensorKind, DEFAULT_NAU7802_GAIN, MAX_SENSOR_LOST_S, MAX_STALE_READING_MS,
+    MIN_STALE_READING_MS, NAU7802_GAINS,
+};
+use crate::storage::{Storage, StorageService, StoreError};
+use crate::unit::Unit;
+use crate::volume::{Substance, Volume, MAX_DENSITY, MIN_DENSITY};
+use crate::weight_gesture::{
+    InputMode, WeightGestureConfig, MAX_GESTURE_PRESS, MAX_GESTURE_THRESHOLD_PERCENT,
+    MAX_GESTURE_WINDOW, MIN_GESTURE_THRESHOLD_PERCENT,
+};
+
+pub const SETTINGS_NAMESPACE: &str = "settings";
+const SETTINGS_KEY: &str = "settings";
+
+/// Version of the settings blob layout. Fields are only ever appended to the
+/// blob, so a blob written by an older version decodes into its known fields
+/// while the newer ones keep their defaults. Bump this whenever a field is
+/// added, and convert the old value in `Settings::decode` if the meaning of an
+/// existing field ever changes.
+pub const SETTINGS_VERSION: u8 = 49;
+
+/// Upper bound of the encoded settings size
+pub const SETTINGS_MAX_LEN: usize = 1024;
+/// Longer strings are truncated when encoded
+const SETTINGS_MAX_STRING_LEN: usize = 96;
+
+const DEFAULT_CALIBRATION_WEIGHT_GRAMS: f32 = 2000.0;
+const DEFAULT_RESOLUTION_GRAMS: f32 = 1.0;
+const DEFAULT_LONG_PRESS_MS: u32 = 3000;
+const DEFAULT_BRIGHTNESS: u8 = 2;
+const DEFAULT_DISPLAY_HEIGHT: u8 = 32;
+const DEFAULT_MQTT_TOPIC_PREFIX: &str = "scale";
+const DEFAULT_MQTT_INTERVAL_S: u32 = 60;
+const DEFAULT_MQTT_MIN_DELTA_GRAMS: f32 = 1.0;
+const DEFAULT_IDLE_CLOCK_TIMEOUT_S: u32 = 60;
+const DEFAULT_ALARM_RENOTIFY_S: u32 = 10 * 60;
+const DEFAULT_CAL_REMINDER_DAYS: u32 = 90;
+const DEFAULT_CAL_DRIFT_GRAMS: f32 = 5.0;
+const DEFAULT_PANIC_HOLD_S: u32 = 10;
+const DEFAULT_AUTO_HOLD_S: u32 = 2;
+/// Two equal resistors halve the pack voltage into the ADC range
+const DEFAULT_BATTERY_DIVIDER: f32 = 2.0;
+const DEFAULT_BATTERY_CUTOFF_VOLTS: f32 = 3.3;
+const DEFAULT_BUZZER_PIN: u8 = 25;
+const DEFAULT_BUZZER_VOLUME: u8 = 100;
+/// Onboard LED of most dev boards
+const DEFAULT_LED_PIN: u8 = 2;
+const DEFAULT_DISPENSE_PIN: u8 = 26;
+const DEFAULT_DISPENSE_TIMEOUT_S: u32 = 60;
+const DEFAULT_DISPENSE_MAX_GRAMS: f32 = 5000.0;
+const DEFAULT_BREW_START_GRAMS: f32 = 0.5;
+const DEFAULT_BREW_STOP_GRAMS_PER_SEC: f32 = 0.1;
+const DEFAULT_BREW_STOP_GRACE_MS: u32 = 3000;
+const DEFAULT_RECIPE_DOSE_GRAMS: f32 = 18.0;
+const DEFAULT_RECIPE_RATIO: f32 = 16.0;
+const DEFAULT_BUMP_THRESHOLD_G: f32 = 0.05;
+const DEFAULT_STALE_READING_MS: u32 = 1000;
+const DEFAULT_SENSOR_LOST_S: u32 = 10;
+const DEFAULT_STARTUP_TOLERANCE_GRAMS: f32 = 50.0;
+const DEFAULT_LOW_POWER_S: u32 = 60;
+const DEFAULT_WAKE_GRAMS: f32 = 20.0;
+const DEFAULT_LINEARITY_WEIGHTS_GRAMS: [f32; MAX_LINEARITY_WEIGHTS] = [100.0, 200.0, 500.0, 1000.0];
+/// Heaviest known weight a linearity check takes
+pub const MAX_LINEARITY_WEIGHT_GRAMS: f32 = 50_000.0;
+const DEFAULT_MODBUS_ADDRESS: u8 = 1;
+const DEFAULT_MODBUS_BAUD: u32 = 9600;
+/// Encodes a Modbus transceiver without a driver enable pin
+const NO_PIN: u8 = u8::MAX;
+const DEFAULT_DATALOG_INTERVAL_S: u32 = 10 * 60;
+/// Four weeks at the default interval
+const DEFAULT_DATALOG_RETENTION: u32 = 4 * 7 * 24 * 6;
+/// Longest DNS label
+const HOSTNAME_MAX_LEN: usize = 63;
+/// Longest webhook URL kept whole
+pub const WEBHOOK_URL_MAX_LEN: usize = SETTINGS_MAX_STRING_LEN;
+const DEFAULT_WEBHOOK_THRESHOLD_GRAMS: f32 = 50.0;
+const DEFAULT_WEBHOOK_EMPTY_S: u32 = 60;
+
+/// SPI pins of the SD card slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SdCardPins {
+    pub sck: u8,
+    pub mosi: u8,
+    pub miso: u8,
+    pub cs: u8,
+}
+
+/// Default VSPI pins
+const DEFAULT_SD_CARD_PINS: SdCardPins = SdCardPins {
+    sck: 18,
+    mosi: 23,
+    miso: 19,
+    cs: 5,
+};
+
+/// UART pins of the Modbus slave
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModbusPins {
+    pub tx: u8,
+    pub rx: u8,
+    /// Driver enable of an RS-485 transceiver, none for one switching on
+    /// its own
+    pub de: Option<u8>,
+}
+
+const DEFAULT_MODBUS_PINS: ModbusPins = ModbusPins {
+    tx: 32,
+    rx: 33,
+    de: None,
+};
+
+/// Second SSD1306 mirroring the display, e.g. facing the customer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MirrorConfig {
+    /// I2C address of the panel, 0x3C or 0x3D
+    pub address: u8,
+    /// Rotation in quarter turns, independent of the display
+    pub quarter_turns: u8,
+    /// SDA and SCL of the second I2C controller, none for the bus of the
+    /// display
+    pub pins: Option<(u8, u8)>,
+}
+
+/// The other address on the bus of the display
+const DEFAULT_MIRROR: MirrorConfig = MirrorConfig {
+    address: 0x3D,
+    quarter_turns: 0,
+    pins: None,
+};
+
+/// Pins of the load cell amplifier, the button and the display
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoardPins {
+    pub hx711_dt: u8,
+    pub hx711_sck: u8,
+    pub button: u8,
+    pub sda: u8,
+    pub scl: u8,
+}
+
+/// Pins of the original board
+const DEFAULT_BOARD_PINS: BoardPins = BoardPins {
+    hx711_dt: 16,
+    hx711_sck: 4,
+    button: 17,
+    sda: 21,
+    scl: 22,
+};
+
+/// Highest GPIO of the ESP32 that can drive its line
+pub const MAX_OUTPUT_GPIO: u8 = 33;
+
+/// One of the board pins
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoardPin {
+    Hx711Dt,
+    Hx711Sck,
+    Button,
+    Sda,
+    Scl,
+}
+
+impl BoardPin {
+    pub const ALL: [BoardPin; 5] = [
+        BoardPin::Hx711Dt,
+        BoardPin::Hx711Sck,
+        BoardPin::Button,
+        BoardPin::Sda,
+        BoardPin::Scl,
+    ];
+
+    /// Name used by the console
+    pub fn name(self) -> &'static str {
+        match self {
+            BoardPin::Hx711Dt => "hx711_dt",
+            BoardPin::Hx711Sck => "hx711_sck",
+            BoardPin::Button => "button",
+            BoardPin::Sda => "sda",
+            BoardPin::Scl => "scl",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|pin| pin.name() == name)
+    }
+
+    /// Whether the pin has to drive its line. The button needs the internal
+    /// pull-up, which the input only pins lack.
+    fn needs_output(self) -> bool {
+        self != BoardPin::Hx711Dt
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PinError {
+    #[error("GPIO{0} does not exist")]
+    NoSuchPin(u8),
+    #[error("GPIO{0} is wired to the flash")]
+    Flash(u8),
+    #[error("GPIO{0} is input only, {1} needs an output")]
+    InputOnly(u8, &'static str),
+    #[error("GPIO{0} is used for both {1} and {2}")]
+    Duplicate(u8, &'static str, &'static str),
+    #[error("GPIO{0} cannot wake the scale from deep sleep, only the RTC GPIOs can")]
+    NoWake(u8),
+}
+
+impl BoardPins {
+    pub fn get(&self, pin: BoardPin) -> u8 {
+        match pin {
+            BoardPin::Hx711Dt => self.hx711_dt,
+            BoardPin::Hx711Sck => self.hx711_sck,
+            BoardPin::Button => self.button,
+            BoardPin::Sda => self.sda,
+            BoardPin::Scl => self.scl,
+        }
+    }
+
+    pub fn set(&mut self, pin: BoardPin, gpio: u8) {
+        match pin {
+            BoardPin::Hx711Dt => self.hx711_dt = gpio,
+            BoardPin::Hx711Sck => self.hx711_sck = gpio,
+            BoardPin::Button => self.button = gpio,
+            BoardPin::Sda => self.sda = gpio,
+            BoardPin::Scl => self.scl = gpio,
+        }
+    }
+
+    /// Check the pins exist on the ESP32, can do what they are used for and
+    /// are all different
+    pub fn validate(&self) -> Result<(), PinError> {
+        for (index, pin) in BoardPin::ALL.into_iter().enumerate() {
+            let gpio = self.get(pin);
+            match gpio {
+                20 | 24 | 28..=31 | 40.. => return Err(PinError::NoSuchPin(gpio)),
+                6..=11 => return Err(PinError::Flash(gpio)),
+                34..=39 if pin.needs_output() => return Err(PinError::InputOnly(gpio, pin.name())),
+                _ => {}
+            }
+            if let Some(other) = BoardPin::ALL[..index]
+                .iter()
+                .find(|other| self.get(**other) == gpio)
+            {
+                return Err(PinError::Duplicate(gpio, other.name(), pin.name()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the button can wake the scale from deep sleep
+    pub fn validate_wake(&self) -> Result<(), PinError> {
+        match can_wake_from_deep_sleep(self.button) {
+            true => Ok(()),
+            false => Err(PinError::NoWake(self.button)),
+        }
+    }
+}
+
+/// How the status LED is driven
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LedBackend {
+    #[default]
+    Off,
+    /// Plain LED on a GPIO, showing the state through blink patterns
+    Gpio,
+    /// Single WS2812, showing the state through colors
+    Ws2812,
+}
+
+impl LedBackend {
+    pub const ALL: [LedBackend; 3] = [LedBackend::Off, LedBackend::Gpio, LedBackend::Ws2812];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LedBackend::Off => "off",
+            LedBackend::Gpio => "gpio",
+            LedBackend::Ws2812 => "ws2812",
+        }
+    }
+
+    fn index(self) -> u8 {
+        self as u8
+    }
+
+    fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+}
+
+/// What the scale does with the load on it at boot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StartupMode {
+    /// Tare whatever is on the scale
+    #[default]
+    TareOnBoot,
+    /// Weigh from the tare saved last, so a load stays weighed across a
+    /// power blip
+    RestoreSavedTare,
+    /// Restore the saved tare, and warn when the weight moved away from the
+    /// last one saved while the power was off
+    RestoreLastWeight,
+}
+
+impl StartupMode {
+    pub const ALL: [StartupMode; 3] = [
+        StartupMode::TareOnBoot,
+        StartupMode::RestoreSavedTare,
+        StartupMode::RestoreLastWeight,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StartupMode::TareOnBoot => "tare",
+            StartupMode::RestoreSavedTare => "restore",
+            StartupMode::RestoreLastWeight => "verify",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(name))
+    }
+
+    fn index(self) -> u8 {
+        self as u8
+    }
+
+    fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+}
+
+/// Application configuration, persisted as a single blob in NVS
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    calibration_weight: f32,
+    unit: Unit,
+    resolution: f32,
+    long_press_ms: u32,
+    brightness: u8,
+    /// Display rotation in quarter turns
+    display_rotation: u8,
+    display_height: u8,
+    wifi_ssid: String,
+    wifi_password: String,
+    /// MQTT publishing is disabled while the broker URL is empty
+    mqtt_broker_url: String,
+    mqtt_username: String,
+    mqtt_password: String,
+    mqtt_topic_prefix: String,
+    /// Interval in seconds the stable weight is republished at, 0 disables it
+    mqtt_interval_s: u32,
+    mqtt_min_delta_grams: f32,
+    /// Empty for the hostname derived from the device ID
+    hostname: String,
+    /// Offset of the local time zone from UTC, for display
+    utc_offset_minutes: i16,
+    /// Interval in seconds the weight is logged to flash at, 0 disables it
+    datalog_interval_s: u32,
+    /// Number of logged records kept before the oldest are overwritten
+    datalog_retention: u32,
+    /// Log every sample to the SD card, when built with the `sdcard` feature
+    sd_card_enabled: bool,
+    sd_card_pins: SdCardPins,
+    /// Ratio of the pack voltage to the voltage at the ADC pin
+    battery_divider: f32,
+    /// Pack voltage in volts below which the scale shuts down
+    battery_cutoff: f32,
+    /// Beep on events, when built with the `buzzer` feature
+    buzzer_enabled: bool,
+    buzzer_pin: u8,
+    /// Volume in percent
+    buzzer_volume: u8,
+    /// Weight in grams signalled once reached, 0 disables it
+    target_grams: f32,
+    /// Weight in grams signalled as overload, 0 disables it
+    capacity_grams: f32,
+    /// Status LED, when built with the `led` feature
+    led_backend: LedBackend,
+    led_pin: u8,
+    /// Relay output of the dispenser, when built with the `dispense` feature
+    dispense_pin: u8,
+    /// Longest time the output stays on, in seconds
+    dispense_timeout_s: u32,
+    /// Weight on the scale that forces the output off
+    dispense_max_grams: f32,
+    /// Weight still landing after the output is cut, tuned after every run
+    dispense_compensation_grams: f32,
+    /// Weight starting the brew timer
+    brew_start_grams: f32,
+    /// Flow rate below which the brew counts as stopped
+    brew_stop_grams_per_sec: f32,
+    /// Time the flow stays stopped before the brew timer stops
+    brew_stop_grace_ms: u32,
+    /// Coffee dose the recipe assistant starts from
+    recipe_dose_grams: f32,
+    /// Grams of water per gram of coffee
+    recipe_ratio: f32,
+    log_level: LevelFilter,
+    board_pins: BoardPins,
+    /// Token authorizing firmware updates over HTTP, empty disables them
+    update_token: String,
+    /// Interval in seconds the diagnostics are published at, 0 disables it
+    mqtt_diag_interval_s: u32,
+    /// Whether the clock takes over the display while the scale is idle
+    idle_clock: bool,
+    /// Time the scale stays empty and still before the clock shows
+    idle_clock_timeout_s: u32,
+    alarms: [Option<AlarmConfig>; MAX_ALARMS],
+    /// Interval in seconds a latched alarm is signalled again at, 0 disables
+    /// it
+    alarm_renotify_s: u32,
+    /// Age in days of the calibration past which recalibrating is
+    /// suggested, 0 disables it
+    cal_reminder_days: u32,
+    /// Drift absorbed by the zero tracking past which recalibrating is
+    /// suggested, 0 disables it
+    cal_drift_grams: f32,
+    /// Load cell ADC read by the scale, applied at the next start
+    sensor: SensorKind,
+    /// PGA gain of the NAU7802, one of `NAU7802_GAINS`
+    nau7802_gain: u8,
+    /// Seconds a panic stays on the display before the restart, 0 leaves
+    /// the display alone
+    panic_hold_s: u32,
+    /// What becomes of the readings converted during a display flush
+    quiesce: QuiesceMode,
+    /// Spread of the readings within which the weight holds on its own, 0
+    /// disables the auto-hold
+    auto_hold_band_grams: f32,
+    /// Seconds the readings must stay within the band
+    auto_hold_s: u32,
+    /// Address of the Modbus slave, 1 to `MAX_MODBUS_ADDRESS`
+    modbus_address: u8,
+    /// One of `MODBUS_BAUD_RATES`
+    modbus_baud: u32,
+    modbus_pins: ModbusPins,
+    /// Deviation of the vertical acceleration in g that counts as a bump,
+    /// 0 leaves the IMU alone
+    bump_threshold_g: f32,
+    /// Time without a conversion before the weight shows as stale
+    stale_reading_ms: u32,
+    /// Time without a conversion before the sensor is reset, 0 never resets
+    /// it
+    sensor_lost_s: u32,
+    startup_mode: StartupMode,
+    /// Distance from the last weight saved that the weight may be at after
+    /// a restart with `StartupMode::RestoreLastWeight`
+    startup_tolerance_grams: f32,
+    /// Whether the empty scale sleeps lightly, waking on a load
+    low_power: bool,
+    /// Time the scale stays empty and still before it dozes off
+    low_power_s: u32,
+    /// Weight change that wakes the dozing scale
+    wake_grams: f32,
+    /// Known weights of the linearity check, 0 for an unused slot
+    linearity_weights_grams: [f32; MAX_LINEARITY_WEIGHTS],
+    /// Whether the calibration and the settings need the PIN or the pattern
+    lock: bool,
+    lock_pin: Option<u16>,
+    lock_pattern: Option<ClickPattern>,
+    /// Time unused after which the unlocked scale locks again
+    lock_relock_s: u32,
+    /// Time without activity before the display dims, turns off and the
+    /// scale goes into deep sleep, 0 for a stage that is skipped
+    idle_dim_s: u32,
+    idle_off_s: u32,
+    idle_sleep_s: u32,
+    /// Language of the display
+    language: Language,
+    /// Frames per second the moving weight is redrawn at, at most
+    max_fps: u8,
+    /// Whether the display flashes inverted once the target is reached, and
+    /// on an overload
+    flash_target: bool,
+    flash_overload: bool,
+    /// URL posted to when something lands on the empty scale, empty for none
+    webhook_url: String,
+    /// Stable weight that counts as an arrival
+    webhook_threshold_grams: f32,
+    /// Time the scale has to stay empty before the next arrival
+    webhook_empty_s: u32,
+    /// GPIO wired to the RATE pin of the HX711, applied at the next start
+    hx711_rate_pin: Option<u8>,
+    /// Free heap in kB kept when the optional subsystems start
+    heap_reserve_kb: u32,
+    /// Free heap in kB below which they are stopped
+    heap_critical_kb: u32,
+    /// Time after a tare within which another one is refused
+    tare_cooldown_ms: u32,
+    /// Gross weight below minus this hints to tare again, 0 disables it
+    negative_hint_grams: f32,
+    /// Time the weight stays below before the hint shows
+    negative_hint_s: u32,
+    /// Tare instead of hinting once the weight is stable
+    negative_auto_tare: bool,
+    /// RMS noise in counts from which the noise test warns
+    noise_warn_counts: f32,
+    /// RMS noise in counts from which it fails
+    noise_fail_counts: f32,
+    /// Windows the scale is awake in, always awake when empty
+    schedule_windows: Vec<ActiveWindow>,
+    /// Whether the first-boot setup ran to its end
+    setup_done: bool,
+    /// Whether the weight shows as the volume of `substance`
+    volume_shown: bool,
+    substance: Substance,
+    /// Density in g/ml of a custom substance
+    custom_density: f32,
+    /// Band the weight on the display moves out of, derived from the noise
+    /// unless set by hand
+    deadband: DeadbandSetting,
+    /// Whether the display is mirrored to a second panel
+    mirror_enabled: bool,
+    mirror: MirrorConfig,
+    /// Whether the weighing is locked down for certified use
+    certified: bool,
+    input_mode: InputMode,
+    weight_gestures: WeightGestureConfig,
+    /// Seconds held down after which the button is stuck, 0 never
+    button_stuck_s: u16,
+    /// Hours without a press while weighing after which a disconnected
+    /// button is suspected, 0 never
+    button_disconnect_h: u16,
+    /// What the scale does on ESP-NOW, applied at the next start
+    espnow_role: EspNowRole,
+    /// Time without a packet after which a corner is stale
+    espnow_stale_ms: u32,
+    /// Corners summed by the aggregator, up to `MAX_PEERS`
+    espnow_peers: Vec<Peer>,
+    /// Aggregator a corner sends its weight to
+    espnow_aggregator: Option<Mac>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            calibration_weight: DEFAULT_CALIBRATION_WEIGHT_GRAMS,
+            unit: Unit::default(),
+            resolution: DEFAULT_RESOLUTION_GRAMS,
+            long_press_ms: DEFAULT_LONG_PRESS_MS,
+            brightness: DEFAULT_BRIGHTNESS,
+            display_rotation: 0,
+            display_height: DEFAULT_DISPLAY_HEIGHT,
+            wifi_ssid: String::new(),
+            wifi_password: String::new(),
+            mqtt_broker_url: String::new(),
+            mqtt_username: String::new(),
+            mqtt_password: String::new(),
+            mqtt_topic_prefix: DEFAULT_MQTT_TOPIC_PREFIX.to_string(),
+            mqtt_interval_s: DEFAULT_MQTT_INTERVAL_S,
+            mqtt_min_delta_grams: DEFAULT_MQTT_MIN_DELTA_GRAMS,
+            hostname: String::new(),
+            utc_offset_minutes: 0,
+            datalog_interval_s: DEFAULT_DATALOG_INTERVAL_S,
+            datalog_retention: DEFAULT_DATALOG_RETENTION,
+            sd_card_enabled: false,
+            sd_card_pins: DEFAULT_SD_CARD_PINS,
+            battery_divider: DEFAULT_BATTERY_DIVIDER,
+            battery_cutoff: DEFAULT_BATTERY_CUTOFF_VOLTS,
+            buzzer_enabled: true,
+            buzzer_pin: DEFAULT_BUZZER_PIN,
+            buzzer_volume: DEFAULT_BUZZER_VOLUME,
+            target_grams: 0.0,
+            capacity_grams: 0.0,
+            led_backend: LedBackend::default(),
+            led_pin: DEFAULT_LED_PIN,
+            dispense_pin: DEFAULT_DISPENSE_PIN,
+            dispense_timeout_s: DEFAULT_DISPENSE_TIMEOUT_S,
+            dispense_max_grams: DEFAULT_DISPENSE_MAX_GRAMS,
+            dispense_compensation_grams: 0.0,
+            brew_start_grams: DEFAULT_BREW_START_GRAMS,
+            brew_stop_grams_per_sec: DEFAULT_BREW_STOP_GRAMS_PER_SEC,
+            brew_stop_grace_ms: DEFAULT_BREW_STOP_GRACE_MS,
+            recipe_dose_grams: DEFAULT_RECIPE_DOSE_GRAMS,
+            recipe_ratio: DEFAULT_RECIPE_RATIO,
+            log_level: LevelFilter::Info,
+            board_pins: DEFAULT_BOARD_PINS,
+            update_token: String::new(),
+            mqtt_diag_interval_s: 0,
+            idle_clock: true,
+            idle_clock_timeout_s: DEFAULT_IDLE_CLOCK_TIMEOUT_S,
+            alarms: [None; MAX_ALARMS],
+            alarm_renotify_s: DEFAULT_ALARM_RENOTIFY_S,
+            cal_reminder_days: DEFAULT_CAL_REMINDER_DAYS,
+            cal_drift_grams: DEFAULT_CAL_DRIFT_GRAMS,
+            sensor: SensorKind::default(),
+            nau7802_gain: DEFAULT_NAU7802_GAIN,
+            panic_hold_s: DEFAULT_PANIC_HOLD_S,
+            quiesce: QuiesceMode::default(),
+            auto_hold_band_grams: 0.0,
+            auto_hold_s: DEFAULT_AUTO_HOLD_S,
+            modbus_address: DEFAULT_MODBUS_ADDRESS,
+            modbus_baud: DEFAULT_MODBUS_BAUD,
+            modbus_pins: DEFAULT_MODBUS_PINS,
+            bump_threshold_g: DEFAULT_BUMP_THRESHOLD_G,
+            stale_reading_ms: DEFAULT_STALE_READING_MS,
+            sensor_lost_s: DEFAULT_SENSOR_LOST_S,
+            startup_mode: StartupMode::default(),
+            startup_tolerance_grams: DEFAULT_STARTUP_TOLERANCE_GRAMS,
+            low_power: false,
+            low_power_s: DEFAULT_LOW_POWER_S,
+            wake_grams: DEFAULT_WAKE_GRAMS,
+            linearity_weights_grams: DEFAULT_LINEARITY_WEIGHTS_GRAMS,
+            lock: false,
+            lock_pin: None,
+            lock_pattern: None,
+            lock_relock_s: DEFAULT_RELOCK_S,
+            idle_dim_s: 0,
+            idle_off_s: 0,
+            idle_sleep_s: 0,
+            language: Language::default(),
+            max_fps: DEFAULT_MAX_FPS,
+            flash_target: false,
+            flash_overload: false,
+            webhook_url: String::new(),
+            webhook_threshold_grams: DEFAULT_WEBHOOK_THRESHOLD_GRAMS,
+            webhook_empty_s: DEFAULT_WEBHOOK_EMPTY_S,
+            hx711_rate_pin: None,
+            heap_reserve_kb: DEFAULT_HEAP_RESERVE_KB,
+            heap_critical_kb: DEFAULT_HEAP_CRITICAL_KB,
+            tare_cooldown_ms: DEFAULT_TARE_COOLDOWN.as_millis() as u32,
+            negative_hint_grams: DEFAULT_NEGATIVE_GRAMS,
+            negative_hint_s: DEFAULT_NEGATIVE_DELAY_S,
+            negative_auto_tare: false,
+            noise_warn_counts: DEFAULT_NOISE_WARN_COUNTS,
+            noise_fail_counts: DEFAULT_NOISE_FAIL_COUNTS,
+            schedule_windows: Vec::new(),
+            setup_done: false,
+            volume_shown: false,
+            substance: Substance::default(),
+            custom_density: 1.0,
+            deadband: DeadbandSetting::default(),
+            mirror_enabled: false,
+            mirror: DEFAULT_MIRROR,
+            certified: false,
+            input_mode: InputMode::default(),
+            weight_gestures: WeightGestureConfig::default(),
+            button_stuck_s: DEFAULT_BUTTON_STUCK_S as u16,
+            button_disconnect_h: DEFAULT_BUTTON_DISCONNECT_H as u16,
+            espnow_role: EspNowRole::default(),
+            espnow_stale_ms: DEFAULT_STALE_MS,
+            espnow_peers: Vec::new(),
+            espnow_aggregator: None,
+        }
+    }
+}
+
+/// Sequential little endian reader over an encoded blob
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.bytes.len() < N {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        head.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take::<1>().map(|[byte]| byte)
+    }
+
+    fn i16(&mut self) -> Option<i16> {
+        self.take().map(i16::from_le_bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.take().map(f32::from_le_bytes)
+    }
+
+    /// Length prefixed string
+    fn string(&mut self) -> Option<String> {
+        let len = self.u8()?.into();
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Some(String::from_utf8_lossy(head).into_owned())
+    }
+}
+
+fn push_string(bytes: &mut Vec<u8>, string: &str) {
+    let mut len = string.len().min(SETTINGS_MAX_STRING_LEN);
+    while !string.is_char_boundary(len) {
+        len -= 1;
+    }
+    bytes.push(len as u8);
+    bytes.extend_from_slice(&string.as_bytes()[..len]);
+}
+
+#[cfg(feature = "display")]
+fn rotation_from_quarter_turns(turns: u8) -> DisplayRotation {
+    match turns {
+        1 => DisplayRotation::Rotate90,
+        2 => DisplayRotation::Rotate180,
+        3 => DisplayRotation::Rotate270,
+        _ => DisplayRotation::Rotate0,
+    }
+}
+
+/// Timeout of an idle stage within its range, 0 staying 0 for a skipped one
+fn idle_timeout_secs(secs: u32) -> u32 {
+    match secs {
+        0 => 0,
+        secs => secs.clamp(MIN_IDLE_TIMEOUT_S, MAX_IDLE_TIMEOUT_S),
+    }
+}
+
+impl Settings {
+    pub fn reset_to_defaults(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Serialize the settings, prefixed by the schema version
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SETTINGS_MAX_LEN);
+        bytes.push(SETTINGS_VERSION);
+        bytes.extend_from_slice(&self.calibration_weight.to_le_bytes());
+        bytes.push(self.unit.index());
+        bytes.extend_from_slice(&self.resolution.to_le_bytes());
+        bytes.extend_from_slice(&self.long_press_ms.to_le_bytes());
+        bytes.push(self.brightness);
+        bytes.push(self.display_rotation);
+        bytes.push(self.display_height);
+        // Version 2
+        push_string(&mut bytes, &self.wifi_ssid);
+        push_string(&mut bytes, &self.wifi_password);
+        push_string(&mut bytes, &self.mqtt_broker_url);
+        push_string(&mut bytes, &self.mqtt_username);
+        push_string(&mut bytes, &self.mqtt_password);
+        push_string(&mut bytes, &self.mqtt_topic_prefix);
+        bytes.extend_from_slice(&self.mqtt_interval_s.to_le_bytes());
+        bytes.extend_from_slice(&self.mqtt_min_delta_grams.to_le_bytes());
+        // Version 3
+        push_string(&mut bytes, &self.hostname);
+        // Version 4
+        bytes.extend_from_slice(&self.utc_offset_minutes.to_le_bytes());
+        // Version 5
+        bytes.extend_from_slice(&self.datalog_interval_s.to_le_bytes());
+        bytes.extend_from_slice(&self.datalog_retention.to_le_bytes());
+        // Version 6
+        bytes.push(u8::from(self.sd_card_enabled));
+        let pins = self.sd_card_pins;
+        bytes.extend_from_slice(&[pins.sck, pins.mosi, pins.miso, pins.cs]);
+        // Version 7
+        bytes.extend_from_slice(&self.battery_divider.to_le_bytes());
+        bytes.extend_from_slice(&self.battery_cutoff.to_le_bytes());
+        // Version 8
+        bytes.push(u8::from(self.buzzer_enabled));
+        bytes.push(self.buzzer_pin);
+        bytes.push(self.buzzer_volume);
+        bytes.extend_from_slice(&self.target_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.capacity_grams.to_le_bytes());
+        // Version 9
+        bytes.push(self.led_backend.index());
+        bytes.push(self.led_pin);
+        // Version 10
+        bytes.push(self.dispense_pin);
+        bytes.extend_from_slice(&self.dispense_timeout_s.to_le_bytes());
+        bytes.extend_from_slice(&self.dispense_max_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.dispense_compensation_grams.to_le_bytes());
+        // Version 11
+        bytes.extend_from_slice(&self.brew_start_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.brew_stop_grams_per_sec.to_le_bytes());
+        bytes.extend_from_slice(&self.brew_stop_grace_ms.to_le_bytes());
+        // Version 12
+        bytes.extend_from_slice(&self.recipe_dose_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.recipe_ratio.to_le_bytes());
+        // Version 13
+        bytes.push(self.log_level as u8);
+        // Version 14
+        let pins = self.board_pins;
+        bytes.extend_from_slice(&[
+            pins.hx711_dt,
+            pins.hx711_sck,
+            pins.button,
+            pins.sda,
+            pins.scl,
+        ]);
+        // Version 15
+        push_string(&mut bytes, &self.update_token);
+        // Version 16
+        bytes.extend_from_slice(&self.mqtt_diag_interval_s.to_le_bytes());
+        // Version 17
+        bytes.push(u8::from(self.idle_clock));
+        bytes.extend_from_slice(&self.idle_clock_timeout_s.to_le_bytes());
+        // Version 18
+        for alarm in &self.alarms {
+            let (kind, grams, hysteresis) = match alarm {
+                None => (0, 0.0, 0.0),
+                Some(alarm) => (
+                    match alarm.kind {
+                        AlarmKind::Above => 1,
+                        AlarmKind::Below => 2,
+                    },
+                    alarm.grams,
+                    alarm.hysteresis_grams,
+                ),
+            };
+            bytes.push(kind);
+            bytes.extend_from_slice(&f32::to_le_bytes(grams));
+            bytes.extend_from_slice(&f32::to_le_bytes(hysteresis));
+        }
+        bytes.extend_from_slice(&self.alarm_renotify_s.to_le_bytes());
+        // Version 19
+        bytes.extend_from_slice(&self.cal_reminder_days.to_le_bytes());
+        bytes.extend_from_slice(&self.cal_drift_grams.to_le_bytes());
+        // Version 20
+        bytes.push(self.sensor.index());
+        bytes.push(self.nau7802_gain);
+        // Version 21
+        bytes.extend_from_slice(&self.panic_hold_s.to_le_bytes());
+        // Version 22
+        bytes.push(self.quiesce.index());
+        // Version 23
+        bytes.extend_from_slice(&self.auto_hold_band_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.auto_hold_s.to_le_bytes());
+        // Version 24
+        bytes.push(self.modbus_address);
+        bytes.extend_from_slice(&self.modbus_baud.to_le_bytes());
+        let pins = self.modbus_pins;
+        bytes.extend_from_slice(&[pins.tx, pins.rx, pins.de.unwrap_or(NO_PIN)]);
+        // Version 25
+        bytes.extend_from_slice(&self.bump_threshold_g.to_le_bytes());
+        // Version 26
+        bytes.extend_from_slice(&self.stale_reading_ms.to_le_bytes());
+        bytes.extend_from_slice(&self.sensor_lost_s.to_le_bytes());
+        // Version 27
+        bytes.push(self.startup_mode.index());
+        bytes.extend_from_slice(&self.startup_tolerance_grams.to_le_bytes());
+        // Version 28
+        bytes.push(self.low_power.into());
+        bytes.extend_from_slice(&self.low_power_s.to_le_bytes());
+        bytes.extend_from_slice(&self.wake_grams.to_le_bytes());
+        // Version 29
+        for grams in &self.linearity_weights_grams {
+            bytes.extend_from_slice(&grams.to_le_bytes());
+        }
+        // Version 30
+        bytes.push(self.lock.into());
+        bytes.extend_from_slice(&self.lock_pin.unwrap_or(u16::MAX).to_le_bytes());
+        bytes.extend_from_slice(&self.lock_pattern.unwrap_or_default().to_bytes());
+        bytes.extend_from_slice(&self.lock_relock_s.to_le_bytes());
+        // Version 31
+        bytes.extend_from_slice(&self.idle_dim_s.to_le_bytes());
+        bytes.extend_from_slice(&self.idle_off_s.to_le_bytes());
+        bytes.extend_from_slice(&self.idle_sleep_s.to_le_bytes());
+        // Version 32
+        bytes.push(self.language.index());
+        // Version 33
+        bytes.push(self.max_fps);
+        // Version 34
+        bytes.push(self.flash_target.into());
+        bytes.push(self.flash_overload.into());
+        // Version 35
+        push_string(&mut bytes, &self.webhook_url);
+        bytes.extend_from_slice(&self.webhook_threshold_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.webhook_empty_s.to_le_bytes());
+        // Version 36
+        bytes.push(self.hx711_rate_pin.unwrap_or(u8::MAX));
+        // Version 37
+        bytes.extend_from_slice(&self.heap_reserve_kb.to_le_bytes());
+        bytes.extend_from_slice(&self.heap_critical_kb.to_le_bytes());
+        // Version 38
+        bytes.extend_from_slice(&self.tare_cooldown_ms.to_le_bytes());
+        // Version 39
+        bytes.extend_from_slice(&self.negative_hint_grams.to_le_bytes());
+        bytes.extend_from_slice(&self.negative_hint_s.to_le_bytes());
+        bytes.push(self.negative_auto_tare as u8);
+        // Version 40
+        bytes.extend_from_slice(&self.noise_warn_counts.to_le_bytes());
+        bytes.extend_from_slice(&self.noise_fail_counts.to_le_bytes());
+        // Version 41
+        bytes.push(self.schedule_windows.len() as u8);
+        for window in &self.schedule_windows {
+            bytes.extend_from_slice(&window.to_bytes());
+        }
+        // Version 42
+        bytes.push(self.setup_done.into());
+        // Version 43
+        bytes.push(self.volume_shown.into());
+        bytes.push(self.substance.index());
+        bytes.extend_from_slice(&self.custom_density.to_le_bytes());
+        // Version 44
+        let (manual, value) = match self.deadband {
+            DeadbandSetting::Auto { k } => (false, k),
+            DeadbandSetting::Manual(grams) => (true, grams),
+        };
+        bytes.push(manual.into());
+        bytes.extend_from_slice(&value.to_le_bytes());
+        // Version 45
+        bytes.push(self.mirror_enabled.into());
+        bytes.push(self.mirror.address);
+        bytes.push(self.mirror.quarter_turns);
+        let (sda, scl) = self.mirror.pins.unwrap_or((u8::MAX, u8::MAX));
+        bytes.push(sda);
+        bytes.push(scl);
+        // Version 46
+        bytes.push(self.certified.into());
+        // Version 47
+        bytes.push(self.input_mode.index());
+        bytes.push(self.weight_gestures.threshold_percent);
+        bytes.extend_from_slice(&(self.weight_gestures.press.as_millis() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.weight_gestures.window.as_millis() as u16).to_le_bytes());
+        // Version 48
+        bytes.extend_from_slice(&self.button_stuck_s.to_le_bytes());
+        bytes.extend_from_slice(&self.button_disconnect_h.to_le_bytes());
+        // Version 49
+        bytes.push(self.espnow_role.index());
+        bytes.extend_from_slice(&self.espnow_stale_ms.to_le_bytes());
+        bytes.push(self.espnow_aggregator.is_some().into());
+        bytes.extend_from_slice(&self.espnow_aggregator.unwrap_or_default());
+        bytes.push(self.espnow_peers.len() as u8);
+        for peer in &self.espnow_peers {
+            bytes.extend_from_slice(&peer.mac);
+            push_string(&mut bytes, &peer.label);
+        }
+        bytes
+    }
+
+    /// Copy with the passwords, the update token and the lock PIN left out,
+    /// for settings read over a link anyone in range can use
+    pub fn redacted(&self) -> Self {
+        Self {
+            wifi_password: String::new(),
+            mqtt_password: String::new(),
+            update_token: String::new(),
+            lock_pin: None,
+            ..self.clone()
+        }
+    }
+
+    /// Take the secrets left out by `redacted` over from `current`: the
+    /// passwords and the token when left empty, the lock PIN always, which
+    /// only `set lock pin` changes
+    pub fn keep_secrets(&mut self, current: &Settings) {
+        if self.wifi_password.is_empty() {
+            self.wifi_password.clone_from(&current.wifi_password);
+        }
+        if self.mqtt_password.is_empty() {
+            self.mqtt_password.clone_from(&current.mqtt_password);
+        }
+        if self.update_token.is_empty() {
+            self.update_token.clone_from(&current.update_token);
+        }
+        self.lock_pin = current.lock_pin;
+    }
+
+    /// Deserialize settings written by this or an older version. Returns the
+    /// settings along with the version of the blob.
+    pub fn decode(bytes: &[u8]) -> Option<(Self, u8)> {
+        let (&version, fields) = bytes.split_first()?;
+        let mut settings = Self::default();
+        let mut reader = Reader { bytes: fields };
+        // Settings stored before the setup flag belong to a scale in use
+        settings.setup_done = true;
+
+        // Stops at the first field missing from an older blob
+        let _ = (|| -> Option<()> {
+            settings.calibration_weight = reader.f32()?;
+            settings.unit = Unit::from_index(reader.u8()?).unwrap_or_default();
+            settings.resolution = reader.f32()?;
+            settings.long_press_ms = reader.u32()?;
+            settings.brightness = reader.u8()?;
+            settings.display_rotation = reader.u8()? % 4;
+            settings.display_height = reader.u8()?;
+            settings.wifi_ssid = reader.string()?;
+            settings.wifi_password = reader.string()?;
+            settings.mqtt_broker_url = reader.string()?;
+            settings.mqtt_username = reader.string()?;
+            settings.mqtt_password = reader.string()?;
+            settings.mqtt_topic_prefix = reader.string()?;
+            settings.mqtt_interval_s = reader.u32()?;
+            settings.mqtt_min_delta_grams = reader.f32()?;
+            settings.hostname = reader.string()?;
+            settings.utc_offset_minutes = reader.i16()?;
+            settings.datalog_interval_s = reader.u32()?;
+            settings.datalog_retention = reader.u32()?;
+            settings.sd_card_enabled = reader.u8()? != 0;
+            let [sck, mosi, miso, cs] = reader.take()?;
+            settings.sd_card_pins = SdCardPins {
+                sck,
+                mosi,
+                miso,
+                cs,
+            };
+            settings.battery_divider = reader.f32()?;
+            settings.battery_cutoff = reader.f32()?;
+            settings.buzzer_enabled = reader.u8()? != 0;
+            settings.buzzer_pin = reader.u8()?;
+            settings.buzzer_volume = reader.u8()?;
+            settings.target_grams = reader.f32()?;
+            settings.capacity_grams = reader.f32()?;
+            settings.led_backend = LedBackend::from_index(reader.u8()?).unwrap_or_default();
+            settings.led_pin = reader.u8()?;
+            settings.dispense_pin = reader.u8()?;
+            settings.dispense_timeout_s = reader.u32()?;
+            settings.dispense_max_grams = reader.f32()?;
+            settings.dispense_compensation_grams = reader.f32()?;
+            settings.brew_start_grams = reader.f32()?;
+            settings.brew_stop_grams_per_sec = reader.f32()?;
+            settings.brew_stop_grace_ms = reader.u32()?;
+            settings.recipe_dose_grams = reader.f32()?;
+            settings.recipe_ratio = reader.f32()?;
+            settings.log_level = LevelFilter::iter()
+                .nth(usize::from(reader.u8()?))
+                .unwrap_or(LevelFilter::Info);
+            let [hx711_dt, hx711_sck, button, sda, scl] = reader.take()?;
+            settings.board_pins = BoardPins {
+                hx711_dt,
+                hx711_sck,
+                button,
+                sda,
+                scl,
+            };
+            settings.update_token = reader.string()?;
+            settings.mqtt_diag_interval_s = reader.u32()?;
+            settings.idle_clock = reader.u8()? != 0;
+            settings.idle_clock_timeout_s = reader.u32()?.max(1);
+            for alarm in &mut settings.alarms {
+                let kind = reader.u8()?;
+                let grams = reader.f32()?;
+                let hysteresis_grams = reader.f32()?;
+                let kind = match kind {
+                    1 => AlarmKind::Above,
+                    2 => AlarmKind::Below,
+                    _ => continue,
+                };
+                *alarm = Some(AlarmConfig {
+                    kind,
+                    grams,
+                    hysteresis_grams,
+                });
+            }
+            settings.alarm_renotify_s = reader.u32()?;
+            settings.cal_reminder_days = reader.u32()?;
+            settings.cal_drift_grams = reader.f32()?.max(0.0);
+            settings.sensor = SensorKind::from_index(reader.u8()?).unwrap_or_default();
+            let gain = reader.u8()?;
+            if NAU7802_GAINS.contains(&gain) {
+                settings.nau7802_gain = gain;
+            }
+            settings.panic_hold_s = reader.u32()?.min(MAX_PANIC_HOLD_S);
+            settings.quiesce = QuiesceMode::from_index(reader.u8()?).unwrap_or_default();
+            settings.auto_hold_band_grams = reader.f32()?.max(0.0);
+            settings.auto_hold_s = reader.u32()?.clamp(1, MAX_AUTO_HOLD_S);
+            let address = reader.u8()?;
+            if (1..=MAX_MODBUS_ADDRESS).contains(&address) {
+                settings.modbus_address = address;
+            }
+            let baud = reader.u32()?;
+            if MODBUS_BAUD_RATES.contains(&baud) {
+                settings.modbus_baud = baud;
+            }
+            let (tx, rx, de) = (reader.u8()?, reader.u8()?, reader.u8()?);
+            settings.modbus_pins = ModbusPins {
+                tx,
+                rx,
+                de: (de != NO_PIN).then_some(de),
+            };
+            settings.bump_threshold_g = reader.f32()?.max(0.0);
+            settings.stale_reading_ms = reader
+                .u32()?
+                .clamp(MIN_STALE_READING_MS, MAX_STALE_READING_MS);
+            settings.sensor_lost_s = reader.u32()?.min(MAX_SENSOR_LOST_S);
+            settings.startup_mode = StartupMode::from_index(reader.u8()?).unwrap_or_default();
+            let tolerance = reader.f32()?;
+            if tolerance > 0.0 {
+                settings.startup_tolerance_grams = tolerance;
+            }
+            settings.low_power = reader.u8()? != 0;
+            settings.low_power_s = reader.u32()?.max(1);
+            let wake_grams = reader.f32()?;
+            if wake_grams > 0.0 {
+                settings.wake_grams = wake_grams;
+            }
+            let mut weights = [0.0; MAX_LINEARITY_WEIGHTS];
+            for grams in &mut weights {
+                *grams = reader.f32()?.clamp(0.0, MAX_LINEARITY_WEIGHT_GRAMS);
+            }
+            settings.linearity_weights_grams = weights;
+            settings.lock = reader.u8()? != 0;
+            settings.lock_pin = Some(reader.u16()?).filter(|&pin| pin <= MAX_PIN);
+            settings.lock_pattern = ClickPattern::from_bytes(reader.take()?);
+            settings.lock_relock_s = reader.u32()?.max(1);
+            settings.idle_dim_s = idle_timeout_secs(reader.u32()?);
+            settings.idle_off_s = idle_timeout_secs(reader.u32()?);
+            settings.idle_sleep_s = idle_timeout_secs(reader.u32()?);
+            settings.language = Language::from_index(reader.u8()?).unwrap_or_default();
+            settings.max_fps = reader.u8()?.clamp(1, MAX_FPS);
+            settings.flash_target = reader.u8()? != 0;
+            settings.flash_overload = reader.u8()? != 0;
+            settings.webhook_url = reader.string()?;
+            let threshold = reader.f32()?;
+            if threshold > 0.0 {
+                settings.webhook_threshold_grams = threshold;
+            }
+            settings.webhook_empty_s = reader.u32()?;
+            settings.hx711_rate_pin = Some(reader.u8()?).filter(|&pin| pin <= MAX_OUTPUT_GPIO);
+            settings.heap_reserve_kb = reader.u32()?.min(MAX_HEAP_THRESHOLD_KB);
+            settings.heap_critical_kb = reader.u32()?.min(MAX_HEAP_THRESHOLD_KB);
+            settings.tare_cooldown_ms = reader.u32()?.min(MAX_TARE_COOLDOWN.as_millis() as u32);
+            settings.negative_hint_grams = reader.f32()?.max(0.0);
+            settings.negative_hint_s = reader.u32()?.min(MAX_NEGATIVE_DELAY_S);
+            settings.negative_auto_tare = reader.u8()? != 0;
+            let (warn, fail) = (reader.f32()?, reader.f32()?);
+            if warn > 0.0 && fail > 0.0 {
+                settings.noise_warn_counts = warn;
+                settings.noise_fail_counts = fail;
+            }
+            let windows = usize::from(reader.u8()?).min(MAX_SCHEDULE_WINDOWS);
+            for _ in 0..windows {
+                if let Some(window) = ActiveWindow::from_bytes(reader.take()?) {
+                    settings.schedule_windows.push(window);
+                }
+            }
+            settings.setup_done = reader.u8()? != 0;
+            settings.volume_shown = reader.u8()? != 0;
+            settings.substance = Substance::from_index(reader.u8()?).unwrap_or_default();
+            settings.custom_density = reader.f32()?.clamp(MIN_DENSITY, MAX_DENSITY);
+            let (manual, value) = (reader.u8()? != 0, reader.f32()?);
+            settings.deadband = if manual {
+                DeadbandSetting::Manual(value.clamp(0.0, MAX_DEADBAND_GRAMS))
+            } else {
+                DeadbandSetting::Auto {
+                    k: value.clamp(0.0, MAX_DEADBAND_K),
+                }
+            };
+            settings.mirror_enabled = reader.u8()? != 0;
+            let [address, quarter_turns, sda, scl] = reader.take()?;
+            settings.mirror = MirrorConfig {
+                address,
+                quarter_turns: quarter_turns % 4,
+                pins: Some((sda, scl))
+                    .filter(|&(sda, scl)| sda <= MAX_OUTPUT_GPIO && scl <= MAX_OUTPUT_GPIO),
+            };
+            settings.certified = reader.u8()? != 0;
+            settings.input_mode = InputMode::from_index(reader.u8()?).unwrap_or_default();
+            settings.set_gesture_threshold_percent(reader.u8()?);
+            settings.set_gesture_press(Duration::from_millis(reader.u16()?.into()));
+            settings.set_gesture_window(Duration::from_millis(reader.u16()?.into()));
+            settings.set_button_stuck(Some(reader.u16()?.into()).filter(|&secs| secs > 0));
+            settings.set_button_disconnect(Some(reader.u16()?.into()).filter(|&hours| hours > 0));
+            settings.espnow_role = EspNowRole::from_index(reader.u8()?).unwrap_or_default();
+            settings.set_espnow_stale(Duration::from_millis(reader.u32()?.into()));
+            let (paired, mac) = (reader.u8()? != 0, reader.take()?);
+            settings.espnow_aggregator = paired.then_some(mac);
+            // All of the peers or none, a cut list would sum part of the corners
+            let peers = (0..reader.u8()?)
+                .map(|_| Some((reader.take()?, reader.string()?)))
+                .collect::<Option<Vec<_>>>()?;
+            for (mac, label) in peers {
+                settings.add_espnow_peer(mac, Some(&label));
+            }
+            Some(())
+        })();
+
+        Some((settings, version))
+    }
+
+    /// Known weight in grams used by the calibration process
+    pub fn calibration_weight(&self) -> f32 {
+        self.calibration_weight
+    }
+
+    pub fn set_calibration_weight(&mut self, grams: f32) {
+        self.calibration_weight = grams;
+    }
+
+    pub fn unit(&self) -> Unit {
+        self.unit
+    }
+
+    pub fn set_unit(&mut self, unit: Unit) {
+        self.unit = unit;
+    }
+
+    /// Step in grams the reported weight is rounded to
+    pub fn resolution(&self) -> f32 {
+        self.resolution
+    }
+
+    pub fn set_resolution(&mut self, grams: f32) {
+        self.resolution = grams;
+    }
+
+    /// How long the button needs to be held for a long press
+    pub fn long_press(&self) -> Duration {
+        Duration::from_millis(self.long_press_ms.into())
+    }
+
+    pub fn set_long_press(&mut self, duration: Duration) {
+        self.long_press_ms = duration.as_millis().try_into().unwrap_or(u32::MAX);
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    pub fn set_brightness(&mut self, level: u8) {
+        self.brightness = level;
+    }
+
+    #[cfg(feature = "display")]
+    pub fn display_rotation(&self) -> DisplayRotation {
+        rotation_from_quarter_turns(self.display_rotation)
+    }
+
+    #[cfg(feature = "display")]
+    pub fn set_display_rotation(&mut self, rotation: DisplayRotation) {
+        self.display_rotation = match rotation {
+            DisplayRotation::Rotate0 => 0,
+            DisplayRotation::Rotate90 => 1,
+            DisplayRotation::Rotate180 => 2,
+            DisplayRotation::Rotate270 => 3,
+        };
+    }
+
+    /// Rotation of the display in quarter turns, applied from the next boot
+    pub fn display_quarter_turns(&self) -> u8 {
+        self.display_rotation
+    }
+
+    pub fn set_display_quarter_turns(&mut self, turns: u8) {
+        self.display_rotation = turns % 4;
+    }
+
+    /// Height in pixels of the attached display
+    pub fn display_height(&self) -> u8 {
+        self.display_height
+    }
+
+    pub fn set_display_height(&mut self, height: u8) {
+        self.display_height = height;
+    }
+
+    pub fn wifi_ssid(&self) -> &str {
+        &self.wifi_ssid
+    }
+
+    pub fn wifi_password(&self) -> &str {
+        &self.wifi_password
+    }
+
+    pub fn set_wifi_credentials(&mut self, ssid: &str, password: &str) {
+        self.wifi_ssid = ssid.to_string();
+        self.wifi_password = password.to_string();
+    }
+
+    /// URL of the MQTT broker, e.g. `mqtt://192.168.1.10:1883`
+    pub fn mqtt_broker_url(&self) -> &str {
+        &self.mqtt_broker_url
+    }
+
+    pub fn set_mqtt_broker_url(&mut self, url: &str) {
+        self.mqtt_broker_url = url.to_string();
+    }
+
+    pub fn mqtt_username(&self) -> &str {
+        &self.mqtt_username
+    }
+
+    pub fn mqtt_password(&self) -> &str {
+        &self.mqtt_password
+    }
+
+    pub fn set_mqtt_credentials(&mut self, username: &str, password: &str) {
+        self.mqtt_username = username.to_string();
+        self.mqtt_password = password.to_string();
+    }
+
+    /// Prefix of all the topics published to
+    pub fn mqtt_topic_prefix(&self) -> &str {
+        &self.mqtt_topic_prefix
+    }
+
+    pub fn set_mqtt_topic_prefix(&mut self, prefix: &str) {
+        self.mqtt_topic_prefix = prefix.trim_end_matches('/').to_string();
+    }
+
+    /// Interval the stable weight is republished at even if it did not change
+    pub fn mqtt_interval(&self) -> Option<Duration> {
+        (self.mqtt_interval_s > 0).then(|| Duration::from_secs(self.mqtt_interval_s.into()))
+    }
+
+    pub fn set_mqtt_interval(&mut self, interval: Option<Duration>) {
+        self.mqtt_interval_s = interval.map_or(0, |interval| {
+            interval.as_secs().try_into().unwrap_or(u32::MAX)
+        });
+    }
+
+    /// Interval the diagnostics are published at, if at all
+    pub fn mqtt_diag_interval(&self) -> Option<Duration> {
+        (self.mqtt_diag_interval_s > 0)
+            .then(|| Duration::from_secs(self.mqtt_diag_interval_s.into()))
+    }
+
+    pub fn set_mqtt_diag_interval(&mut self, interval: Option<Duration>) {
+        self.mqtt_diag_interval_s = interval.map_or(0, |interval| {
+            interval.as_secs().try_into().unwrap_or(u32::MAX)
+        });
+    }
+
+    /// Whether the clock shows while the scale is idle
+    pub fn idle_clock(&self) -> bool {
+        self.idle_clock
+    }
+
+    pub fn set_idle_clock(&mut self, enabled: bool) {
+        self.idle_clock = enabled;
+    }
+
+    /// Time the scale stays empty and still before the clock shows
+    pub fn idle_clock_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_clock_timeout_s.into())
+    }
+
+    pub fn set_idle_clock_timeout(&mut self, timeout: Duration) {
+        self.idle_clock_timeout_s = timeout.as_secs().clamp(1, u32::MAX.into()) as u32;
+    }
+
+    /// Alarm configured in each slot
+    pub fn alarms(&self) -> &[Option<AlarmConfig>; MAX_ALARMS] {
+        &self.alarms
+    }
+
+    /// Slots past `MAX_ALARMS` are ignored
+    pub fn set_alarm(&mut self, slot: usize, alarm: Option<AlarmConfig>) {
+        if let Some(configured) = self.alarms.get_mut(slot) {
+            *configured = alarm;
+        }
+    }
+
+    /// Interval a latched alarm is signalled again at
+    pub fn alarm_renotify(&self) -> Option<Duration> {
+        (self.alarm_renotify_s > 0).then(|| Duration::from_secs(self.alarm_renotify_s.into()))
+    }
+
+    pub fn set_alarm_renotify(&mut self, interval: Option<Duration>) {
+        self.alarm_renotify_s = interval.map_or(0, |interval| {
+            interval.as_secs().try_into().unwrap_or(u32::MAX)
+        });
+    }
+
+    /// Age in days of the calibration past which recalibrating is suggested,
+    /// 0 when disabled
+    pub fn cal_reminder_days(&self) -> u32 {
+        self.cal_reminder_days
+    }
+
+    pub fn set_cal_reminder_days(&mut self, days: u32) {
+        self.cal_reminder_days = days;
+    }
+
+    /// Drift in grams past which recalibrating is suggested, 0 when disabled
+    pub fn cal_drift_grams(&self) -> f32 {
+        self.cal_drift_grams
+    }
+
+    pub fn set_cal_drift_grams(&mut self, grams: f32) {
+        self.cal_drift_grams = grams;
+    }
+
+    /// Load cell ADC to read, takes effect after a restart
+    pub fn sensor(&self) -> SensorKind {
+        self.sensor
+    }
+
+    pub fn set_sensor(&mut self, sensor: SensorKind) {
+        self.sensor = sensor;
+    }
+
+    /// PGA gain of the NAU7802, takes effect after a restart
+    pub fn nau7802_gain(&self) -> u8 {
+        self.nau7802_gain
+    }
+
+    pub fn set_nau7802_gain(&mut self, gain: u8) {
+        self.nau7802_gain = gain;
+    }
+
+    /// GPIO driving the RATE pin of the HX711, takes effect after a restart
+    pub fn hx711_rate_pin(&self) -> Option<u8> {
+        self.hx711_rate_pin
+    }
+
+    pub fn set_hx711_rate_pin(&mut self, pin: Option<u8>) {
+        self.hx711_rate_pin = pin.filter(|&pin| pin <= MAX_OUTPUT_GPIO);
+    }
+
+    /// Free heap in kB the optional subsystems have to leave when they start
+    pub fn heap_reserve_kb(&self) -> u32 {
+        self.heap_reserve_kb
+    }
+
+    pub fn set_heap_reserve_kb(&mut self, kb: u32) {
+        self.heap_reserve_kb = kb.min(MAX_HEAP_THRESHOLD_KB);
+    }
+
+    /// Free heap in kB below which the optional subsystems are stopped
+    pub fn heap_critical_kb(&self) -> u32 {
+        self.heap_critical_kb
+    }
+
+    pub fn set_heap_critical_kb(&mut self, kb: u32) {
+        self.heap_critical_kb = kb.min(MAX_HEAP_THRESHOLD_KB);
+    }
+
+    /// Time after a tare within which another one is refused
+    pub fn tare_cooldown(&self) -> Duration {
+        Duration::from_millis(self.tare_cooldown_ms.into())
+    }
+
+    pub fn set_tare_cooldown(&mut self, cooldown: Duration) {
+        self.tare_cooldown_ms = cooldown.min(MAX_TARE_COOLDOWN).as_millis() as u32;
+    }
+
+    /// When the scale reading below zero hints to tare again
+    pub fn negative_hint(&self) -> NegativeConfig {
+        NegativeConfig {
+            grams: self.negative_hint_grams,
+            delay: Duration::from_secs(self.negative_hint_s.into()),
+            auto_tare: self.negative_auto_tare,
+        }
+    }
+
+    pub fn set_negative_hint_grams(&mut self, grams: Option<f32>) {
+        self.negative_hint_grams = grams.unwrap_or(0.0);
+    }
+
+    pub fn set_negative_hint_time(&mut self, secs: u32) {
+        self.negative_hint_s = secs.min(MAX_NEGATIVE_DELAY_S);
+    }
+
+    pub fn set_negative_auto_tare(&mut self, enabled: bool) {
+        self.negative_auto_tare = enabled;
+    }
+
+    /// Verdicts of the noise test
+    pub fn noise_thresholds(&self) -> NoiseThresholds {
+        NoiseThresholds {
+            warn_counts: self.noise_warn_counts,
+            fail_counts: self.noise_fail_counts,
+        }
+    }
+
+    pub fn set_noise_warn_counts(&mut self, counts: f32) {
+        self.noise_warn_counts = counts;
+    }
+
+    pub fn set_noise_fail_counts(&mut self, counts: f32) {
+        self.noise_fail_counts = counts;
+    }
+
+    /// Daily windows the scale is awake in
+    pub fn schedule(&self) -> Schedule {
+        Schedule::new(self.schedule_windows.clone())
+    }
+
+    /// Returns whether it was added, up to `MAX_SCHEDULE_WINDOWS`
+    pub fn add_schedule_window(&mut self, window: ActiveWindow) -> bool {
+        if self.schedule_windows.len() >= MAX_SCHEDULE_WINDOWS {
+            return false;
+        }
+        self.schedule_windows.push(window);
+        true
+    }
+
+    /// Keep the scale awake all the time again
+    pub fn clear_schedule(&mut self) {
+        self.schedule_windows.clear();
+    }
+
+    /// Whether the first-boot setup ran, it runs on every boot until then
+    pub fn setup_done(&self) -> bool {
+        self.setup_done
+    }
+
+    pub fn set_setup_done(&mut self, done: bool) {
+        self.setup_done = done;
+    }
+
+    /// Substance the weight shows as the volume of, none while it shows as
+    /// a weight
+    pub fn volume(&self) -> Option<Volume> {
+        self.volume_shown
+            .then(|| Volume::new(self.substance, self.custom_density))
+    }
+
+    pub fn volume_shown(&self) -> bool {
+        self.volume_shown
+    }
+
+    pub fn set_volume_shown(&mut self, shown: bool) {
+        self.volume_shown = shown;
+    }
+
+    pub fn substance(&self) -> Substance {
+        self.substance
+    }
+
+    pub fn set_substance(&mut self, substance: Substance) {
+        self.substance = substance;
+    }
+
+    /// Density in g/ml of the custom substance
+    pub fn custom_density(&self) -> f32 {
+        self.custom_density
+    }
+
+    pub fn set_custom_density(&mut self, density: f32) {
+        self.custom_density = density.clamp(MIN_DENSITY, MAX_DENSITY);
+    }
+
+    /// Band the weight on the display moves out of
+    pub fn deadband(&self) -> DeadbandSetting {
+        self.deadband
+    }
+
+    pub fn set_deadband(&mut self, setting: DeadbandSetting) {
+        self.deadband = setting;
+    }
+
+    /// Panel mirroring the display, none when it is not mirrored. Takes
+    /// effect after a restart.
+    pub fn mirror(&self) -> Option<MirrorConfig> {
+        self.mirror_enabled.then_some(self.mirror)
+    }
+
+    pub fn set_mirror_enabled(&mut self, enabled: bool) {
+        self.mirror_enabled = enabled;
+    }
+
+    pub fn set_mirror_address(&mut self, address: u8) {
+        self.mirror.address = address;
+    }
+
+    pub fn set_mirror_quarter_turns(&mut self, turns: u8) {
+        self.mirror.quarter_turns = turns % 4;
+    }
+
+    pub fn set_mirror_pins(&mut self, pins: Option<(u8, u8)>) {
+        self.mirror.pins =
+            pins.filter(|&(sda, scl)| sda <= MAX_OUTPUT_GPIO && scl <= MAX_OUTPUT_GPIO);
+    }
+
+    #[cfg(feature = "display")]
+    pub fn mirror_rotation(&self) -> DisplayRotation {
+        rotation_from_quarter_turns(self.mirror.quarter_turns)
+    }
+
+    /// Whether the resolution is locked and the zero tracking, the creep
+    /// compensation and the hold are off
+    pub fn certified(&self) -> bool {
+        self.certified
+    }
+
+    pub fn set_certified(&mut self, certified: bool) {
+        self.certified = certified;
+    }
+
+    pub fn input_mode(&self) -> InputMode {
+        self.input_mode
+    }
+
+    pub fn set_input_mode(&mut self, mode: InputMode) {
+        self.input_mode = mode;
+    }
+
+    /// Gestures made with the weight, none while operated with the button
+    pub fn weight_gestures(&self) -> Option<WeightGestureConfig> {
+        (self.input_mode == InputMode::Weight).then_some(self.weight_gestures)
+    }
+
+    pub fn set_gesture_threshold_percent(&mut self, percent: u8) {
+        self.weight_gestures.threshold_percent =
+            percent.clamp(MIN_GESTURE_THRESHOLD_PERCENT, MAX_GESTURE_THRESHOLD_PERCENT);
+    }
+
+    pub fn set_gesture_press(&mut self, press: Duration) {
+        self.weight_gestures.press = press.clamp(Duration::from_millis(100), MAX_GESTURE_PRESS);
+    }
+
+    pub fn set_gesture_window(&mut self, window: Duration) {
+        self.weight_gestures.window = window.clamp(Duration::from_secs(1), MAX_GESTURE_WINDOW);
+    }
+
+    /// Time held down after which the button is stuck, takes effect after a
+    /// restart. None never takes it as stuck.
+    pub fn button_stuck(&self) -> Option<Duration> {
+        (self.button_stuck_s > 0).then(|| Duration::from_secs(self.button_stuck_s.into()))
+    }
+
+    pub fn set_button_stuck(&mut self, secs: Option<u32>) {
+        self.button_stuck_s = secs.map_or(0, |secs| secs.clamp(1, MAX_BUTTON_STUCK_S) as u16);
+    }
+
+    /// Time without a press while weighing after which a disconnected
+    /// button is suspected, none never
+    pub fn button_disconnect(&self) -> Option<Duration> {
+        (self.button_disconnect_h > 0)
+            .then(|| Duration::from_secs(u64::from(self.button_disconnect_h) * 3600))
+    }
+
+    pub fn set_button_disconnect(&mut self, hours: Option<u32>) {
+        self.button_disconnect_h =
+            hours.map_or(0, |hours| hours.clamp(1, MAX_BUTTON_DISCONNECT_H) as u16);
+    }
+
+    /// What the scale does on ESP-NOW, takes effect after a restart
+    pub fn espnow_role(&self) -> EspNowRole {
+        self.espnow_role
+    }
+
+    pub fn set_espnow_role(&mut self, role: EspNowRole) {
+        self.espnow_role = role;
+    }
+
+    /// Time without a packet after which a corner is stale
+    pub fn espnow_stale(&self) -> Duration {
+        Duration::from_millis(self.espnow_stale_ms.into())
+    }
+
+    pub fn set_espnow_stale(&mut self, stale: Duration) {
+        self.espnow_stale_ms = (stale.as_millis() as u32).clamp(MIN_STALE_MS, MAX_STALE_MS);
+    }
+
+    /// Corners summed by the aggregator
+    pub fn espnow_peers(&self) -> &[Peer] {
+        &self.espnow_peers
+    }
+
+    /// Returns whether it was added, up to `MAX_PEERS` and once per MAC.
+    /// Without a label it is labelled after its position.
+    pub fn add_espnow_peer(&mut self, mac: Mac, label: Option<&str>) -> bool {
+        if self.espnow_peers.len() >= MAX_PEERS
+            || self.espnow_peers.iter().any(|peer| peer.mac == mac)
+        {
+            return false;
+        }
+        let index = self.espnow_peers.len();
+        self.espnow_peers.push(Peer::new(mac, index));
+        if let Some(label) = label {
+            self.set_espnow_label(index, label);
+        }
+        true
+    }
+
+    /// Returns the peer removed, if there was one at `index`
+    pub fn remove_espnow_peer(&mut self, index: usize) -> Option<Peer> {
+        (index < self.espnow_peers.len()).then(|| self.espnow_peers.remove(index))
+    }
+
+    /// Returns whether there is a peer at `index`. The label is cut to
+    /// `MAX_LABEL_LEN` characters, an empty one is left alone.
+    pub fn set_espnow_label(&mut self, index: usize, label: &str) -> bool {
+        let Some(peer) = self.espnow_peers.get_mut(index) else {
+            return false;
+        };
+        let label: String = label.trim().chars().take(MAX_LABEL_LEN).collect();
+        if !label.trim_end().is_empty() {
+            peer.label = label.trim_end().to_string();
+        }
+        true
+    }
+
+    /// Aggregator a corner sends its weight to, set by the pairing
+    pub fn espnow_aggregator(&self) -> Option<Mac> {
+        self.espnow_aggregator
+    }
+
+    pub fn set_espnow_aggregator(&mut self, mac: Option<Mac>) {
+        self.espnow_aggregator = mac;
+    }
+
+    /// Time a panic stays on the display before the restart
+    pub fn panic_hold(&self) -> Option<Duration> {
+        (self.panic_hold_s > 0).then(|| Duration::from_secs(self.panic_hold_s.into()))
+    }
+
+    /// What becomes of the readings converted during a display flush, takes
+    /// effect after a restart
+    pub fn quiesce(&self) -> QuiesceMode {
+        self.quiesce
+    }
+
+    pub fn set_quiesce(&mut self, mode: QuiesceMode) {
+        self.quiesce = mode;
+    }
+
+    /// When the weight holds on its own, none when it never does
+    pub fn auto_hold(&self) -> Option<AutoHold> {
+        (self.auto_hold_band_grams > 0.0).then(|| AutoHold {
+            band_grams: self.auto_hold_band_grams,
+            duration: Duration::from_secs(self.auto_hold_s.into()),
+        })
+    }
+
+    /// Set the band of the auto-hold, `None` disables it
+    pub fn set_auto_hold_band(&mut self, grams: Option<f32>) {
+        self.auto_hold_band_grams = grams.unwrap_or(0.0);
+    }
+
+    pub fn set_auto_hold_time(&mut self, secs: u32) {
+        self.auto_hold_s = secs.clamp(1, MAX_AUTO_HOLD_S);
+    }
+
+    /// Address of the Modbus slave, takes effect after a restart
+    pub fn modbus_address(&self) -> u8 {
+        self.modbus_address
+    }
+
+    pub fn set_modbus_address(&mut self, address: u8) {
+        self.modbus_address = address;
+    }
+
+    pub fn modbus_baud(&self) -> u32 {
+        self.modbus_baud
+    }
+
+    pub fn set_modbus_baud(&mut self, baud: u32) {
+        self.modbus_baud = baud;
+    }
+
+    pub fn modbus_pins(&self) -> ModbusPins {
+        self.modbus_pins
+    }
+
+    pub fn set_modbus_pins(&mut self, pins: ModbusPins) {
+        self.modbus_pins = pins;
+    }
+
+    /// Deviation of the vertical acceleration in g that counts as a bump,
+    /// none when bumps are not rejected. Takes effect after a restart.
+    pub fn bump_threshold(&self) -> Option<f32> {
+        (self.bump_threshold_g > 0.0).then_some(self.bump_threshold_g)
+    }
+
+    pub fn set_bump_threshold(&mut self, threshold_g: Option<f32>) {
+        self.bump_threshold_g = threshold_g.unwrap_or(0.0);
+    }
+
+    /// Time without a conversion before the weight shows as stale
+    pub fn stale_reading(&self) -> Duration {
+        Duration::from_millis(self.stale_reading_ms.into())
+    }
+
+    pub fn set_stale_reading(&mut self, millis: u32) {
+        self.stale_reading_ms = millis.clamp(MIN_STALE_READING_MS, MAX_STALE_READING_MS);
+    }
+
+    /// Time without a conversion before the sensor is reset, none when it
+    /// is left alone
+    pub fn sensor_lost(&self) -> Option<Duration> {
+        (self.sensor_lost_s > 0).then(|| Duration::from_secs(self.sensor_lost_s.into()))
+    }
+
+    pub fn set_sensor_lost(&mut self, secs: Option<u32>) {
+        self.sensor_lost_s = secs.unwrap_or(0).min(MAX_SENSOR_LOST_S);
+    }
+
+    /// What the scale does with the load on it at boot
+    pub fn startup_mode(&self) -> StartupMode {
+        self.startup_mode
+    }
+
+    pub fn set_startup_mode(&mut self, mode: StartupMode) {
+        self.startup_mode = mode;
+    }
+
+    pub fn startup_tolerance_grams(&self) -> f32 {
+        self.startup_tolerance_grams
+    }
+
+    pub fn set_startup_tolerance(&mut self, grams: f32) {
+        self.startup_tolerance_grams = grams;
+    }
+
+    /// Time the scale stays empty and still before it sleeps lightly, none
+    /// when it never does
+    pub fn low_power(&self) -> Option<Duration> {
+        self.low_power
+            .then(|| Duration::from_secs(self.low_power_s.into()))
+    }
+
+    pub fn set_low_power(&mut self, enabled: bool) {
+        self.low_power = enabled;
+    }
+
+    pub fn set_low_power_time(&mut self, secs: u32) {
+        self.low_power_s = secs.max(1);
+    }
+
+    /// Weight change that wakes the scale from its light sleep
+    pub fn wake_grams(&self) -> f32 {
+        self.wake_grams
+    }
+
+    pub fn set_wake_grams(&mut self, grams: f32) {
+        self.wake_grams = grams;
+    }
+
+    /// Known weights of the linearity check in grams, in the order they are
+    /// placed
+    pub fn linearity_weights(&self) -> Vec<f32> {
+        self.linearity_weights_grams
+            .iter()
+            .copied()
+            .filter(|&grams| grams > 0.0)
+            .collect()
+    }
+
+    /// Weight in the slot, 0 when unused
+    pub fn linearity_weight(&self, slot: usize) -> f32 {
+        self.linearity_weights_grams
+            .get(slot)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Slots past `MAX_LINEARITY_WEIGHTS` are ignored, 0 leaves the slot
+    /// unused
+    pub fn set_linearity_weight(&mut self, slot: usize, grams: f32) {
+        if let Some(configured) = self.linearity_weights_grams.get_mut(slot) {
+            *configured = grams.clamp(0.0, MAX_LINEARITY_WEIGHT_GRAMS);
+        }
+    }
+
+    pub fn lock(&self) -> LockConfig {
+        LockConfig {
+            enabled: self.lock,
+            pin: self.lock_pin,
+            pattern: self.lock_pattern,
+            relock: Duration::from_secs(self.lock_relock_s.into()),
+        }
+    }
+
+    /// The lock stays off until a PIN is set
+    pub fn set_lock(&mut self, enabled: bool) {
+        self.lock = enabled;
+    }
+
+    pub fn set_lock_pin(&mut self, pin: u16) {
+        self.lock_pin = Some(pin.min(MAX_PIN));
+    }
+
+    /// `None` leaves the PIN as the only way to unlock from the button
+    pub fn set_lock_pattern(&mut self, pattern: Option<ClickPattern>) {
+        self.lock_pattern = pattern;
+    }
+
+    pub fn set_lock_relock_secs(&mut self, secs: u32) {
+        self.lock_relock_s = secs.max(1);
+    }
+
+    /// Timeouts of the idle stages, counted from the last activity
+    pub fn idle_timeouts(&self) -> IdleTimeouts {
+        let timeout = |secs: u32| (secs > 0).then(|| Duration::from_secs(secs.into()));
+        IdleTimeouts {
+            dim: timeout(self.idle_dim_s),
+            display_off: timeout(self.idle_off_s),
+            deep_sleep: timeout(self.idle_sleep_s),
+        }
+    }
+
+    /// Time without activity before the stage, 0 to skip it. Kept within
+    /// `MIN_IDLE_TIMEOUT_S` and `MAX_IDLE_TIMEOUT_S`.
+    pub fn set_idle_timeout(&mut self, stage: IdleStage, secs: u32) {
+        let secs = idle_timeout_secs(secs);
+        match stage {
+            IdleStage::Active => {}
+            IdleStage::Dimmed => self.idle_dim_s = secs,
+            IdleStage::DisplayOff => self.idle_off_s = secs,
+            IdleStage::DeepSleep => self.idle_sleep_s = secs,
+        }
+    }
+
+    pub fn language(&self) -> Language {
+        self.language
+    }
+
+    /// Check the pins as `BoardPins::validate` does, and that the button
+    /// wakes the scale while the idle stages go into deep sleep
+    pub fn validate_pins(&self, pins: &BoardPins) -> Result<(), PinError> {
+        pins.validate()?;
+        if self.idle_sleep_s > 0 {
+            pins.validate_wake()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_language(&mut self, language: Language) {
+        self.language = language;
+    }
+
+    pub fn max_fps(&self) -> u8 {
+        self.max_fps
+    }
+
+    pub fn set_max_fps(&mut self, fps: u8) {
+        self.max_fps = fps.clamp(1, MAX_FPS);
+    }
+
+    pub fn flash_target(&self) -> bool {
+        self.flash_target
+    }
+
+    pub fn set_flash_target(&mut self, enabled: bool) {
+        self.flash_target = enabled;
+    }
+
+    pub fn flash_overload(&self) -> bool {
+        self.flash_overload
+    }
+
+    pub fn set_flash_overload(&mut self, enabled: bool) {
+        self.flash_overload = enabled;
+    }
+
+    /// URL an arrival is posted to, `None` when the webhook is off
+    pub fn webhook_url(&self) -> Option<&str> {
+        Some(self.webhook_url.as_str()).filter(|url| !url.is_empty())
+    }
+
+    pub fn set_webhook_url(&mut self, url: Option<&str>) {
+        self.webhook_url = url.unwrap_or_default().to_string();
+    }
+
+    pub fn webhook_threshold_grams(&self) -> f32 {
+        self.webhook_threshold_grams
+    }
+
+    pub fn set_webhook_threshold_grams(&mut self, grams: f32) {
+        self.webhook_threshold_grams = grams;
+    }
+
+    pub fn webhook_empty_time(&self) -> Duration {
+        Duration::from_secs(self.webhook_empty_s.into())
+    }
+
+    pub fn set_webhook_empty_secs(&mut self, secs: u32) {
+        self.webhook_empty_s = secs;
+    }
+
+    pub fn set_panic_hold(&mut self, hold: Option<Duration>) {
+        self.panic_hold_s = hold.map_or(0, |hold| {
+            hold.as_secs()
+                .min(MAX_PANIC_HOLD_S.into())
+                .try_into()
+                .unwrap_or(MAX_PANIC_HOLD_S)
+        });
+    }
+
+    /// Change in grams that gets the stable weight published right away
+    pub fn mqtt_min_delta_grams(&self) -> f32 {
+        self.mqtt_min_delta_grams
+    }
+
+    pub fn set_mqtt_min_delta_grams(&mut self, grams: f32) {
+        self.mqtt_min_delta_grams = grams;
+    }
+
+    /// Name the scale is reachable at as `<hostname>.local`, `None` for the
+    /// one derived from the device ID
+    pub fn hostname(&self) -> Option<&str> {
+        (!self.hostname.is_empty()).then_some(self.hostname.as_str())
+    }
+
+    pub fn set_hostname(&mut self, hostname: Option<&str>) {
+        self.hostname = hostname.unwrap_or_default().to_string();
+    }
+
+    pub fn utc_offset_minutes(&self) -> i16 {
+        self.utc_offset_minutes
+    }
+
+    pub fn set_utc_offset_minutes(&mut self, minutes: i16) {
+        self.utc_offset_minutes = minutes;
+    }
+
+    pub fn datalog_interval(&self) -> Option<Duration> {
+        (self.datalog_interval_s > 0).then(|| Duration::from_secs(self.datalog_interval_s.into()))
+    }
+
+    pub fn set_datalog_interval(&mut self, interval: Option<Duration>) {
+        self.datalog_interval_s = interval.map_or(0, |interval| {
+            interval.as_secs().try_into().unwrap_or(u32::MAX)
+        });
+    }
+
+    /// Number of logged records kept before the oldest are overwritten
+    pub fn datalog_retention(&self) -> u32 {
+        self.datalog_retention
+    }
+
+    pub fn set_datalog_retention(&mut self, records: u32) {
+        self.datalog_retention = records;
+    }
+
+    pub fn sd_card_enabled(&self) -> bool {
+        self.sd_card_enabled
+    }
+
+    pub fn set_sd_card_enabled(&mut self, enabled: bool) {
+        self.sd_card_enabled = enabled;
+    }
+
+    pub fn sd_card_pins(&self) -> SdCardPins {
+        self.sd_card_pins
+    }
+
+    pub fn set_sd_card_pins(&mut self, pins: SdCardPins) {
+        self.sd_card_pins = pins;
+    }
+
+    /// Ratio of the pack voltage to the voltage at the ADC pin
+    pub fn battery_divider(&self) -> f32 {
+        self.battery_divider
+    }
+
+    pub fn set_battery_divider(&mut self, ratio: f32) {
+        self.battery_divider = ratio;
+    }
+
+    /// Pack voltage below which the scale shuts down to protect the cell
+    pub fn battery_cutoff(&self) -> f32 {
+        self.battery_cutoff
+    }
+
+    pub fn set_battery_cutoff(&mut self, volts: f32) {
+        self.battery_cutoff = volts;
+    }
+
+    pub fn buzzer_enabled(&self) -> bool {
+        self.buzzer_enabled
+    }
+
+    pub fn set_buzzer_enabled(&mut self, enabled: bool) {
+        self.buzzer_enabled = enabled;
+    }
+
+    pub fn buzzer_pin(&self) -> u8 {
+        self.buzzer_pin
+    }
+
+    pub fn set_buzzer_pin(&mut self, pin: u8) {
+        self.buzzer_pin = pin;
+    }
+
+    /// Volume in percent
+    pub fn buzzer_volume(&self) -> u8 {
+        self.buzzer_volume
+    }
+
+    pub fn set_buzzer_volume(&mut self, percent: u8) {
+        self.buzzer_volume = percent.min(100);
+    }
+
+    /// Weight signalled once reached
+    pub fn target_grams(&self) -> Option<f32> {
+        (self.target_grams > 0.0).then_some(self.target_grams)
+    }
+
+    pub fn set_target_grams(&mut self, grams: Option<f32>) {
+        self.target_grams = grams.unwrap_or(0.0);
+    }
+
+    /// Weight above which the scale signals an overload
+    pub fn capacity_grams(&self) -> Option<f32> {
+        (self.capacity_grams > 0.0).then_some(self.capacity_grams)
+    }
+
+    pub fn set_capacity_grams(&mut self, grams: Option<f32>) {
+        self.capacity_grams = grams.unwrap_or(0.0);
+    }
+
+    pub fn led_backend(&self) -> LedBackend {
+        self.led_backend
+    }
+
+    pub fn set_led_backend(&mut self, backend: LedBackend) {
+        self.led_backend = backend;
+    }
+
+    pub fn led_pin(&self) -> u8 {
+        self.led_pin
+    }
+
+    pub fn set_led_pin(&mut self, pin: u8) {
+        self.led_pin = pin;
+    }
+
+    pub fn dispense_pin(&self) -> u8 {
+        self.dispense_pin
+    }
+
+    pub fn set_dispense_pin(&mut self, pin: u8) {
+        self.dispense_pin = pin;
+    }
+
+    /// Longest time the dispenser output stays on
+    pub fn dispense_timeout(&self) -> Duration {
+        Duration::from_secs(self.dispense_timeout_s.into())
+    }
+
+    pub fn set_dispense_timeout_secs(&mut self, secs: u32) {
+        self.dispense_timeout_s = secs;
+    }
+
+    /// Weight on the scale that forces the dispenser output off
+    pub fn dispense_max_grams(&self) -> f32 {
+        self.dispense_max_grams
+    }
+
+    pub fn set_dispense_max_grams(&mut self, grams: f32) {
+        self.dispense_max_grams = grams;
+    }
+
+    /// Weight still landing after the dispenser output is cut
+    pub fn dispense_compensation_grams(&self) -> f32 {
+        self.dispense_compensation_grams
+    }
+
+    pub fn set_dispense_compensation_grams(&mut self, grams: f32) {
+        self.dispense_compensation_grams = grams;
+    }
+
+    /// Weight starting the brew timer
+    pub fn brew_start_grams(&self) -> f32 {
+        self.brew_start_grams
+    }
+
+    pub fn set_brew_start_grams(&mut self, grams: f32) {
+        self.brew_start_grams = grams;
+    }
+
+    /// Flow rate below which the brew counts as stopped
+    pub fn brew_stop_grams_per_sec(&self) -> f32 {
+        self.brew_stop_grams_per_sec
+    }
+
+    pub fn set_brew_stop_grams_per_sec(&mut self, grams_per_sec: f32) {
+        self.brew_stop_grams_per_sec = grams_per_sec;
+    }
+
+    /// Time the flow stays stopped before the brew timer stops
+    pub fn brew_stop_grace(&self) -> Duration {
+        Duration::from_millis(self.brew_stop_grace_ms.into())
+    }
+
+    pub fn set_brew_stop_grace(&mut self, grace: Duration) {
+        self.brew_stop_grace_ms = grace.as_millis().try_into().unwrap_or(u32::MAX);
+    }
+
+    /// Coffee dose the recipe assistant starts from
+    pub fn recipe_dose_grams(&self) -> f32 {
+        self.recipe_dose_grams
+    }
+
+    pub fn set_recipe_dose_grams(&mut self, grams: f32) {
+        self.recipe_dose_grams = grams;
+    }
+
+    /// Grams of water per gram of coffee
+    pub fn recipe_ratio(&self) -> f32 {
+        self.recipe_ratio
+    }
+
+    pub fn set_recipe_ratio(&mut self, ratio: f32) {
+        self.recipe_ratio = ratio;
+    }
+
+    /// Level of the log messages printed and kept in memory
+    pub fn log_level(&self) -> LevelFilter {
+        self.log_level
+    }
+
+    pub fn set_log_level(&mut self, level: LevelFilter) {
+        self.log_level = level;
+    }
+
+    /// Pins of the load cell amplifier, the button and the display
+    pub fn board_pins(&self) -> BoardPins {
+        self.board_pins
+    }
+
+    pub fn set_board_pins(&mut self, pins: BoardPins) {
+        self.board_pins = pins;
+    }
+
+    /// Token authorizing firmware updates over HTTP, empty disables them
+    pub fn update_token(&self) -> &str {
+        &self.update_token
+    }
+
+    pub fn set_update_token(&mut self, token: &str) {
+        self.update_token = token.to_string();
+    }
+
+    /// Whether the name is a valid DNS label
+    pub fn is_valid_hostname(hostname: &str) -> bool {
+        !hostname.is_empty()
+            && hostname.len() <= HOSTNAME_MAX_LEN
+            && !hostname.starts_with('-')
+            && !hostname.ends_with('-')
+            && hostname
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+    }
+}
+
+impl Settings {
+    /// Load the settings from NVS, falling back to the defaults when they are
+    /// missing or unreadable. Returns the version of the stored blob.
+    pub fn load(storage: &Storage) -> (Self, u8) {
+        match storage.get_checked_blob(SETTINGS_KEY) {
+            Some(bytes) => Self::decode(&bytes).unwrap_or_else(|| {
+                warn!("Stored settings are empty, using defaults");
+                (Self::default(), SETTINGS_VERSION)
+            }),
+            None => (Self::default(), SETTINGS_VERSION),
+        }
+    }
+
+    pub fn save(&self, storage: &mut Storage) -> Result<(), StoreError> {
+        storage.put_checked_blob(SETTINGS_KEY, &self.encode())?;
+        publish(self);
+        Ok(())
+    }
+}
+
+/// Blob of the settings as last loaded or saved, secrets redacted
+static PUBLISHED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+fn publish(settings: &Settings) {
+    *PUBLISHED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = settings.redacted().encode();
+}
+
+/// The settings as last loaded or saved, encoded without their secrets, for
+/// the frontends reading them outside the main loop
+pub fn published_blob() -> Vec<u8> {
+    PUBLISHED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+/// Migration putting the settings stored before the checksums into their
+/// envelope
+pub fn seal_blobs(storage: &mut Storage) -> Result<(), StoreError> {
+    storage.seal_blob(SETTINGS_KEY)
+}
+
+/// Settings along with the NVS namespace they are persisted in
+pub struct SettingsStore {
+    storage: Storage,
+    settings: Settings,
+}
+
+impl SettingsStore {
+    pub fn new(storage: &StorageService) -> Result<Self, StoreError> {
+        let mut storage = storage.open(SETTINGS_NAMESPACE)?;
+        let (settings, version) = Settings::load(&storage);
+
+        // Rewrite blobs from older versions so the new fields get persisted
+        if version < SETTINGS_VERSION {
+            info!(
+                "Migrating settings from version {} to {}",
+                version, SETTINGS_VERSION
+            );
+            settings.save(&mut storage)?;
+        }
+        publish(&settings);
+
+        Ok(Self { storage, settings })
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    pub fn save(&mut self) -> Result<(), StoreError> {
+        self.settings.save(&mut self.storage)
+    }
+
+    /// Whether the settings namespace can be read, the defaults are used
+    /// when it cannot
+    pub fn is_readable(&self) -> bool {
+        self.storage.is_readable(SETTINGS_KEY)
+    }
+
+    /// Restore and persist the default settings
+    pub fn reset_to_defaults(&mut self) -> Result<(), StoreError> {
+        self.settings.reset_to_defaults();
+        self.save()
+    }
+
+    /// Remove the stored settings, the defaults are used from the next boot on
+    pub fn erase(&mut self) -> Result<(), StoreError> {
+        self.settings.reset_to_defaults();
+        self.storage.remove(SETTINGS_KEY).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{power::Weekdays, storage::MemoryStore};
+
+    /// Settings with fields of the first and the last versions changed
+    fn changed() -> Settings {
+        let mut settings = Settings {
+            calibration_weight: 200.0,
+            unit: Unit::Ounces,
+            display_rotation: 2,
+            wifi_ssid: "kitchen".to_string(),
+            wifi_password: "secret".to_string(),
+            hostname: "scale".to_string(),
+            utc_offset_minutes: -90,
+            datalog_interval_s: 30,
+            buzzer_enabled: true,
+            idle_sleep_s: 600,
+            language: Language::German,
+            max_fps: 12,
+            webhook_url: "https://example.com/hook".to_string(),
+            setup_done: true,
+            substance: Substance::Flour,
+            deadband: DeadbandSetting::Manual(0.5),
+            certified: true,
+            button_stuck_s: 20,
+            button_disconnect_h: 48,
+            espnow_role: EspNowRole::Aggregator,
+            espnow_stale_ms: 5000,
+            espnow_aggregator: Some([2; 6]),
+            ..Settings::default()
+        };
+        let days = Weekdays::from_names("mon,fri").unwrap();
+        assert!(settings.add_schedule_window(ActiveWindow::new(days, 22 * 60, 6 * 60).unwrap()));
+        assert!(settings.add_espnow_peer([4; 6], Some("Front")));
+        assert!(settings.add_espnow_peer([5; 6], None));
+        settings
+    }
+
+    /// Fields of `changed` in the order they are stored
+    fn stored_in_order(settings: &Settings) -> Vec<String> {
+        vec![
+            format!("{:?}", settings.calibration_weight),
+            format!("{:?}", settings.unit),
+            format!("{:?}", settings.display_rotation),
+            format!("{:?}", settings.wifi_ssid),
+            format!("{:?}", settings.wifi_password),
+            format!("{:?}", settings.hostname),
+            format!("{:?}", settings.utc_offset_minutes),
+            format!("{:?}", settings.datalog_interval_s),
+            format!("{:?}", settings.buzzer_enabled),
+            format!("{:?}", settings.idle_sleep_s),
+            format!("{:?}", settings.language),
+            format!("{:?}", settings.max_fps),
+            format!("{:?}", settings.webhook_url),
+            format!("{:?}", settings.schedule_windows),
+            format!("{:?}", settings.substance),
+            format!("{:?}", settings.deadband),
+            format!("{:?}", settings.certified),
+            format!("{:?}", settings.button_stuck_s),
+            format!("{:?}", settings.button_disconnect_h),
+            format!("{:?}", settings.espnow_role),
+            format!("{:?}", settings.espnow_stale_ms),
+            format!("{:?}", settings.espnow_aggregator),
+            format!("{:?}", settings.espnow_peers),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let settings = changed();
+        assert_ne!(settings, Settings::default());
+        assert_eq!(
+            Settings::decode(&settings.encode()),
+            Some((settings, SETTINGS_VERSION))
+        );
+        let defaults = Settings::default();
+        assert_eq!(
+            Settings::decode(&defaults.encode()),
+            Some((defaults, SETTINGS_VERSION))
+        );
+    }
+
+    #[test]
+    fn older_blobs_keep_the_defaults() {
+        let settings = changed();
+        let bytes = settings.encode();
+        let expected = stored_in_order(&settings);
+        let defaults = stored_in_order(&Settings::default());
+        assert_eq!(Settings::decode(&[]), None);
+        for len in 1..=bytes.len() {
+            let (decoded, version) = Settings::decode(&bytes[..len]).unwrap();
+            assert_eq!(version, SETTINGS_VERSION);
+            // The fields stored up to the cut, then the defaults
+            let decoded = stored_in_order(&decoded);
+            let read = decoded
+                .iter()
+                .zip(&expected)
+                .take_while(|(decoded, expected)| decoded == expected)
+                .count();
+            assert_eq!(decoded[read..], defaults[read..], "cut at {}", len);
+        }
+    }
+
+    #[test]
+    fn older_blobs_are_set_up() {
+        let settings = Settings {
+            setup_done: false,
+            ..changed()
+        };
+        let bytes = settings.encode();
+        let set_up: Vec<bool> = (1..=bytes.len())
+            .map(|len| Settings::decode(&bytes[..len]).unwrap().0.setup_done)
+            .collect();
+        // Set up until the blob reaches the flag, which is read from then on
+        let flag_read = set_up.iter().position(|&done| !done).unwrap();
+        assert!(flag_read > 0);
+        assert!(set_up[flag_read..].iter().all(|&done| !done));
+        let (before, _) = Settings::decode(&bytes[..flag_read]).unwrap();
+        assert_eq!(before.schedule_windows, settings.schedule_windows);
+        assert_eq!(before.substance, Substance::default());
+        assert!(!Settings::default().setup_done);
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let settings = Settings {
+            display_rotation: 7,
+            auto_hold_s: 0,
+            modbus_address: 0,
+            modbus_baud: 1234,
+            stale_reading_ms: 1,
+            idle_dim_s: 1,
+            idle_off_s: u32::MAX,
+            max_fps: 0,
+            custom_density: 100.0,
+            deadband: DeadbandSetting::Manual(1000.0),
+            ..Settings::default()
+        };
+        let (decoded, _) = Settings::decode(&settings.encode()).unwrap();
+        let defaults = Settings::default();
+        assert_eq!(decoded.display_rotation, 3);
+        assert_eq!(decoded.auto_hold_s, 1);
+        assert_eq!(decoded.modbus_address, defaults.modbus_address);
+        assert_eq!(decoded.modbus_baud, defaults.modbus_baud);
+        assert_eq!(decoded.stale_reading_ms, MIN_STALE_READING_MS);
+        assert_eq!(decoded.idle_dim_s, MIN_IDLE_TIMEOUT_S);
+        assert_eq!(decoded.idle_off_s, MAX_IDLE_TIMEOUT_S);
+        assert_eq!(decoded.max_fps, 1);
+        assert_eq!(decoded.custom_density, MAX_DENSITY);
+        assert_eq!(
+            decoded.deadband,
+            DeadbandSetting::Manual(MAX_DEADBAND_GRAMS)
+        );
+
+        let settings = Settings {
+            max_fps: u8::MAX,
+            custom_density: 0.0,
+            ..Settings::default()
+        };
+        let (decoded, _) = Settings::decode(&settings.encode()).unwrap();
+        assert_eq!(decoded.max_fps, MAX_FPS);
+        assert_eq!(decoded.custom_density, MIN_DENSITY);
+    }
+
+    #[test]
+    fn espnow_peers_are_limited() {
+        let mut settings = Settings::default();
+        assert!(settings.add_espnow_peer([1; 6], Some("  Front left  ")));
+        assert!(!settings.add_espnow_peer([1; 6], None));
+        assert_eq!(settings.espnow_peers()[0].label, "Front");
+        for n in 2..=MAX_PEERS as u8 {
+            assert!(settings.add_espnow_peer([n; 6], None));
+        }
+        assert!(!settings.add_espnow_peer([0xff; 6], None));
+        assert_eq!(settings.espnow_peers()[1].label, "C2");
+
+        assert!(settings.set_espnow_label(1, "Back"));
+        assert!(!settings.set_espnow_label(MAX_PEERS, "Back"));
+        assert_eq!(
+            settings.remove_espnow_peer(0).map(|peer| peer.mac),
+            Some([1; 6])
+        );
+        assert_eq!(settings.espnow_peers()[0].label, "Back");
+        assert_eq!(settings.remove_espnow_peer(MAX_PEERS), None);
+    }
+
+    #[test]
+    fn saved_settings_are_loaded_again() {
+        let storage = StorageService::start_with(MemoryStore::default()).unwrap();
+        let mut store = SettingsStore::new(&storage).unwrap();
+        assert!(store.is_readable());
+        *store.settings_mut() = changed();
+        store.save().unwrap();
+
+        let store = SettingsStore::new(&storage).unwrap();
+        assert_eq!(
+            stored_in_order(store.settings()),
+            stored_in_order(&changed())
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! First-boot setup, walking through the settings a new scale needs one
+//! screen at a time. The steps are menu items, so they read and write the
+//! settings the same way the menu does.
+
+use embedded_graphics::prelude::Point;
+use ssd1306::{prelude::WriteOnlyDataCommand, size::DisplaySize};
+
+use crate::{
+    button::ButtonAction,
+    i18n::{tr, trf, StringId},
+    menu::{MenuItem, NumberEntry},
+    text_drawer::{DisplayError, TextDrawer, TextError},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetupState {
+    Open,
+    Done,
+}
+
+/// Value of the current step while it is being picked
+enum Pick {
+    Value(i32),
+    Number(NumberEntry),
+}
+
+/// Steps run one after the other with a single button. A press moves to the
+/// next value, a long press keeps it and goes on and a double press skips
+/// the step, leaving the value as it was. Numbers are entered through a
+/// `NumberEntry`, whose double press confirms the prefilled value too.
+///
+/// The steps are built again after each one, so their labels follow a
+/// language picked in an earlier step.
+pub struct Setup<C> {
+    build: fn() -> Vec<MenuItem<C>>,
+    steps: Vec<MenuItem<C>>,
+    step: usize,
+    pick: Option<Pick>,
+}
+
+impl<C> Setup<C> {
+    pub fn new(build: fn() -> Vec<MenuItem<C>>, ctx: &C) -> Self {
+        let mut setup = Self {
+            build,
+            steps: build(),
+            step: 0,
+            pick: None,
+        };
+        setup.begin(ctx);
+        setup
+    }
+
+    /// Start picking the value of the current step from the setting
+    fn begin(&mut self, ctx: &C) {
+        self.pick = self.steps.get(self.step).map(|item| match item {
+            MenuItem::Number {
+                digits,
+                decimals,
+                min,
+                max,
+                get,
+                ..
+            } => Pick::Number(NumberEntry::new(*digits, *decimals, *min, *max, get(ctx))),
+            _ => Pick::Value(item.current_value(ctx)),
+        });
+    }
+
+    fn advance(&mut self, ctx: &C) -> SetupState {
+        self.step += 1;
+        self.steps = (self.build)();
+        self.begin(ctx);
+        if self.step < self.steps.len() {
+            SetupState::Open
+        } else {
+            SetupState::Done
+        }
+    }
+
+    /// Handle a button gesture, returning whether steps are left
+    pub fn handle(&mut self, action: ButtonAction, ctx: &mut C) -> SetupState {
+        let (Some(item), Some(pick)) = (self.steps.get(self.step), self.pick.take()) else {
+            return SetupState::Done;
+        };
+        match pick {
+            Pick::Number(mut entry) => {
+                let MenuItem::Number { set, .. } = item else {
+                    return self.advance(ctx);
+                };
+                match entry.handle(action) {
+                    Some(value) => set(ctx, value),
+                    None => {
+                        self.pick = Some(Pick::Number(entry));
+                        return SetupState::Open;
+                    }
+                }
+            }
+            Pick::Value(value) => match action {
+                ButtonAction::Press => {
+                    self.pick = Some(Pick::Value(match item {
+                        MenuItem::Toggle { .. } => i32::from(value == 0),
+                        MenuItem::Numeric { min, max, step, .. } => {
+                            let next = value + step;
+                            if next > *max {
+                                *min
+                            } else {
+                                next
+                            }
+                        }
+                        MenuItem::Choice { options, .. } => (value + 1) % options.len() as i32,
+                        _ => value,
+                    }));
+                    return SetupState::Open;
+                }
+                ButtonAction::LongPress => match item {
+                    MenuItem::Toggle { set, .. } => set(ctx, value != 0),
+                    MenuItem::Numeric { set, .. } => set(ctx, value),
+                    MenuItem::Choice { set, .. } => set(ctx, value as usize),
+                    MenuItem::Action { run, .. } => run(ctx),
+                    MenuItem::Submenu { .. } | MenuItem::Number { .. } => {}
+                },
+                ButtonAction::DoublePress => {}
+            },
+        }
+        self.advance(ctx)
+    }
+
+    pub fn render<DI, SIZE>(
+        &self,
+        text_drawer: &mut TextDrawer<DI, SIZE>,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+    where
+        DI: WriteOnlyDataCommand,
+        SIZE: DisplaySize,
+    {
+        let Some(item) = self.steps.get(self.step) else {
+            return Ok(());
+        };
+        let header = trf(StringId::SetupStep, &[&(self.step + 1), &self.steps.len()]);
+        let prompt = text_drawer.layout().prompt;
+        let char_width = text_drawer.char_style().font.character_size.width;
+        let (item_line, value_line, hint) = match &self.pick {
+            Some(Pick::Value(value)) => {
+                let value = format!("[{}]", item.format_value(*value));
+                let line = format!("{}: {}", item.label(), value);
+                // On the blank line when it does not fit after the label,
+                // e.g. "Language: [English]"
+                if line.chars().count() as u32 * char_width <= prompt.size.width {
+                    (line, String::new(), tr(StringId::SetupHint))
+                } else {
+                    (format!("{}:", item.label()), value, tr(StringId::SetupHint))
+                }
+            }
+            Some(Pick::Number(_)) => (
+                format!("{}:", item.label()),
+                String::new(),
+                tr(StringId::SetupNumberHint),
+            ),
+            None => (item.label().to_string(), String::new(), ""),
+        };
+        let text = format!("{}\n{}\n{}\n{}", header, item_line, value_line, hint);
+
+        text_drawer.draw_text_clear(&text, prompt.top_left)?;
+        if let Some(Pick::Number(entry)) = &self.pick {
+            // After the label, or on a line of its own when it does not fit
+            let line_height = text_drawer.line_height() as i32;
+            let label_width = (item_line.chars().count() as u32 + 1) * char_width;
+            let position = if label_width + entry.len() as u32 * char_width <= prompt.size.width {
+                Point::new(label_width as i32, line_height)
+            } else {
+                Point::new(0, 2 * line_height)
+            };
+            entry.render(prompt.top_left + position, text_drawer)?;
+        }
+        text_drawer.flush()
+    }
+}
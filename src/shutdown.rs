@@ -0,0 +1,117 @@
+//! Orderly restart. The subsystems holding state in memory register a flush
+//! hook, and `restart` runs them in the order they were registered before
+//! restarting, each given `HOOK_TIMEOUT` so a hung one cannot keep the scale
+//! from coming back. The esp-idf shutdown handlers, e.g. the one of the
+//! storage, still run within the restart after them. The deep sleep runs the
+//! hooks through `flush`, as it skips those handlers.
+//!
+//! A brown-out resets the chip on the spot without running anything, so the
+//! state that matters most is written through instead of waiting for a
+//! hook: the calibration is flushed as soon as it is saved.
+
+use std::{
+    sync::{mpsc::sync_channel, Arc, Mutex},
+    time::Duration,
+};
+
+use log::{info, warn};
+
+/// Longest time a hook may take before the next one runs
+const HOOK_TIMEOUT: Duration = Duration::from_secs(2);
+const HOOK_STACK_SIZE: usize = 4096;
+
+/// Why the scale goes down, for the log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// A firmware update was written
+    Update,
+    /// `reboot` on the console
+    Command,
+    FactoryReset,
+    LowBattery,
+    /// The idle scale went into deep sleep
+    Idle,
+    /// `sleep` on the console
+    Sleep,
+    /// Deep sleep until the next window of the schedule
+    Schedule,
+    /// The display rotation picked in the setup is applied at boot
+    Setup,
+    FatalError,
+}
+
+impl ShutdownReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            ShutdownReason::Update => "firmware update",
+            ShutdownReason::Command => "reboot command",
+            ShutdownReason::FactoryReset => "factory reset",
+            ShutdownReason::LowBattery => "low battery",
+            ShutdownReason::Idle => "idle timeout",
+            ShutdownReason::Sleep => "sleep command",
+            ShutdownReason::Schedule => "schedule",
+            ShutdownReason::Setup => "setup",
+            ShutdownReason::FatalError => "fatal error",
+        }
+    }
+}
+
+type Hook = (&'static str, Arc<dyn Fn() + Send + Sync>);
+
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+
+/// Have `flush` run before every restart and deep sleep
+pub fn register(name: &'static str, flush: impl Fn() + Send + Sync + 'static) {
+    HOOKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push((name, Arc::new(flush)));
+}
+
+/// Run every hook in the order they were registered. A hook that takes
+/// longer than `HOOK_TIMEOUT` is left running and the next one starts.
+pub fn flush(reason: ShutdownReason) {
+    info!("Shutting down for the {}", reason.name());
+    // Cloned so a hook registering another one cannot deadlock
+    let hooks = HOOKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    for (name, hook) in hooks {
+        let (done_tx, done_rx) = sync_channel(1);
+        let running = hook.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("shutdown_{}", name))
+            .stack_size(HOOK_STACK_SIZE)
+            .spawn(move || {
+                running();
+                let _ = done_tx.send(());
+            });
+        match spawned {
+            Ok(_) => {
+                if done_rx.recv_timeout(HOOK_TIMEOUT).is_err() {
+                    warn!("Shutdown hook {} timed out", name);
+                }
+            }
+            // Without a thread there is no bound, better than skipping it
+            Err(err) => {
+                warn!("Failed to start shutdown hook {}: {}", name, err);
+                hook();
+            }
+        }
+    }
+}
+
+/// Flush everything registered, then restart
+#[cfg(feature = "esp")]
+pub fn restart(reason: ShutdownReason) -> ! {
+    flush(reason);
+    esp_idf_hal::reset::restart()
+}
+
+/// Flush everything registered, then exit, the simulator does not come back
+#[cfg(not(feature = "esp"))]
+pub fn restart(reason: ShutdownReason) -> ! {
+    flush(reason);
+    std::process::exit(0)
+}
@@ -0,0 +1,432 @@
+//! Destinations the samples go out to, e.g. the CSV stream on the console,
+//! the SD card, the weight log and the MQTT and webhook tasks. Each
+//! implements `OutputSink` and is registered with the `SinkManager` of the
+//! main loop, which offers it the samples at the rate of its throttle and
+//! counts what became of them.
+//!
+//! A sink that fails is left out for a backoff doubling from 5s up to
+//! 5 min, then offered the samples again; the other sinks and the main
+//! loop carry on. One whose queue is full only misses the sample.
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::filter::Sample;
+
+const BACKOFF_MIN: Duration = Duration::from_secs(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SinkError {
+    /// The sample did not fit the queue of the task writing it out, the
+    /// sink itself is fine
+    #[error("Queue full")]
+    Full,
+    #[error("{0}")]
+    Failed(String),
+}
+
+pub type SinkResult = Result<(), SinkError>;
+
+/// Destination of the samples
+pub trait OutputSink {
+    /// Name in the diagnostics, the sink is throttled by it
+    fn name(&self) -> &'static str;
+
+    /// Take a sample the throttle let through. Should not block.
+    fn offer(&mut self, sample: &Sample) -> SinkResult;
+
+    /// Write out what is held back, e.g. before a restart
+    fn flush(&mut self) -> SinkResult {
+        Ok(())
+    }
+
+    /// Offered the samples again after a pause, turned back on or at the
+    /// end of a backoff
+    fn resume(&mut self) {}
+}
+
+/// Rate a sink is offered the samples at
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Throttle {
+    Off,
+    #[default]
+    EverySample,
+    /// One sample per interval at most
+    Interval(Duration),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SinkPolicy {
+    pub throttle: Throttle,
+    /// Whether the sink leaves out the samples of the demo mode, unless
+    /// logging them was asked for
+    pub loggable_only: bool,
+}
+
+/// What became of the samples offered to a sink
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SinkStats {
+    pub delivered: u32,
+    /// Missed on a full queue
+    pub dropped: u32,
+    pub errors: u32,
+    /// Left out until its backoff ends
+    pub backing_off: bool,
+}
+
+impl SinkStats {
+    /// One line for the console and the diagnostics page
+    pub fn describe(&self, name: &str) -> String {
+        format!(
+            "{} {} sent {} drop {} err{}",
+            name,
+            self.delivered,
+            self.dropped,
+            self.errors,
+            if self.backing_off { " OFF" } else { "" }
+        )
+    }
+}
+
+struct Registered {
+    sink: Box<dyn OutputSink>,
+    policy: SinkPolicy,
+    last_offered: Option<Instant>,
+    stats: SinkStats,
+    /// Failures in a row, the backoff doubles with each
+    failures: u32,
+    backoff_until: Option<Instant>,
+}
+
+impl Registered {
+    fn is_due(&self, loggable: bool, now: Instant) -> bool {
+        if self.policy.loggable_only && !loggable {
+            return false;
+        }
+        match self.policy.throttle {
+            Throttle::Off => false,
+            Throttle::EverySample => true,
+            Throttle::Interval(interval) => self
+                .last_offered
+                .map_or(true, |at| now.saturating_duration_since(at) >= interval),
+        }
+    }
+
+    fn offer(&mut self, sample: &Sample, loggable: bool, now: Instant) {
+        if let Some(until) = self.backoff_until {
+            if now < until {
+                return;
+            }
+            self.backoff_until = None;
+            self.stats.backing_off = false;
+            info!(
+                "Offering the samples to the {} sink again",
+                self.sink.name()
+            );
+            self.sink.resume();
+        }
+        if !self.is_due(loggable, now) {
+            return;
+        }
+        self.last_offered = Some(now);
+        match self.sink.offer(sample) {
+            Ok(()) => {
+                self.stats.delivered = self.stats.delivered.wrapping_add(1);
+                self.failures = 0;
+            }
+            Err(SinkError::Full) => self.stats.dropped = self.stats.dropped.wrapping_add(1),
+            Err(err) => self.fail(err, now),
+        }
+    }
+
+    /// Leave the sink out for a backoff growing with the failures in a row
+    fn fail(&mut self, err: SinkError, now: Instant) {
+        self.stats.errors = self.stats.errors.wrapping_add(1);
+        self.failures = self.failures.saturating_add(1);
+        let backoff = BACKOFF_MIN
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(BACKOFF_MAX);
+        warn!(
+            "The {} sink failed, left out for {}s: {}",
+            self.sink.name(),
+            backoff.as_secs(),
+            err
+        );
+        self.backoff_until = Some(now + backoff);
+        self.stats.backing_off = true;
+    }
+}
+
+/// The sinks of the main loop, offered every new sample
+#[derive(Default)]
+pub struct SinkManager {
+    sinks: Vec<Registered>,
+}
+
+impl SinkManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, sink: impl OutputSink + 'static, policy: SinkPolicy) {
+        let mut sink: Box<dyn OutputSink> = Box::new(sink);
+        if policy.throttle != Throttle::Off {
+            sink.resume();
+        }
+        self.sinks.push(Registered {
+            sink,
+            policy,
+            last_offered: None,
+            stats: SinkStats::default(),
+            failures: 0,
+            backoff_until: None,
+        });
+    }
+
+    /// Throttle of the sink, none for one not registered
+    pub fn throttle(&self, name: &str) -> Option<Throttle> {
+        self.find(name).map(|registered| registered.policy.throttle)
+    }
+
+    /// Change the rate of the sink, resumed when it was off. Returns false
+    /// for one not registered.
+    pub fn set_throttle(&mut self, name: &str, throttle: Throttle) -> bool {
+        let Some(registered) = self
+            .sinks
+            .iter_mut()
+            .find(|registered| registered.sink.name() == name)
+        else {
+            return false;
+        };
+        if registered.policy.throttle == Throttle::Off && throttle != Throttle::Off {
+            registered.sink.resume();
+        }
+        registered.policy.throttle = throttle;
+        registered.last_offered = None;
+        true
+    }
+
+    /// Offer the sample to the sinks it is due to. `loggable` is false for
+    /// a sample of the demo mode not to be logged.
+    pub fn offer(&mut self, sample: &Sample, loggable: bool, now: Instant) {
+        for registered in &mut self.sinks {
+            registered.offer(sample, loggable, now);
+        }
+    }
+
+    /// Flush every sink, a failure logged and counted
+    pub fn flush(&mut self) {
+        for registered in &mut self.sinks {
+            if let Err(err) = registered.sink.flush() {
+                warn!(
+                    "Failed to flush the {} sink: {}",
+                    registered.sink.name(),
+                    err
+                );
+                registered.stats.errors = registered.stats.errors.wrapping_add(1);
+            }
+        }
+    }
+
+    /// The sinks along with their stats, in the order registered
+    pub fn stats(&self) -> impl Iterator<Item = (&'static str, SinkStats)> + '_ {
+        self.sinks
+            .iter()
+            .map(|registered| (registered.sink.name(), registered.stats))
+    }
+
+    fn find(&self, name: &str) -> Option<&Registered> {
+        self.sinks
+            .iter()
+            .find(|registered| registered.sink.name() == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::quality::Quality;
+
+    const SAMPLE: Sample = Sample {
+        raw: 0,
+        grams_raw: 100.0,
+        grams_filtered: 100.0,
+        stable: true,
+        quality: Quality::Good,
+    };
+
+    /// Sink answering with the results queued up, `Ok` once they run out
+    #[derive(Clone, Default)]
+    struct Scripted {
+        name: &'static str,
+        results: Rc<RefCell<Vec<SinkResult>>>,
+        offered: Rc<RefCell<u32>>,
+        resumed: Rc<RefCell<u32>>,
+    }
+
+    impl Scripted {
+        fn named(name: &'static str) -> Self {
+            Self {
+                name,
+                ..Self::default()
+            }
+        }
+
+        fn answer(&self, results: &[SinkResult]) {
+            let mut queued = self.results.borrow_mut();
+            queued.extend(results.iter().rev().cloned());
+        }
+
+        fn offered(&self) -> u32 {
+            *self.offered.borrow()
+        }
+    }
+
+    impl OutputSink for Scripted {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn offer(&mut self, _sample: &Sample) -> SinkResult {
+            *self.offered.borrow_mut() += 1;
+            self.results.borrow_mut().pop().unwrap_or(Ok(()))
+        }
+
+        fn resume(&mut self) {
+            *self.resumed.borrow_mut() += 1;
+        }
+    }
+
+    fn failed() -> SinkResult {
+        Err(SinkError::Failed("unreachable".to_string()))
+    }
+
+    fn stats_of(sinks: &SinkManager, name: &str) -> SinkStats {
+        sinks
+            .stats()
+            .find(|(sink, _)| *sink == name)
+            .map(|(_, stats)| stats)
+            .unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_and_resets() {
+        let start = Instant::now();
+        let sink = Scripted::named("flaky");
+        sink.answer(&[failed(), failed(), Ok(()), failed()]);
+        let mut sinks = SinkManager::new();
+        sinks.register(sink.clone(), SinkPolicy::default());
+        assert_eq!(*sink.resumed.borrow(), 1);
+
+        // Left out for 5s after the first failure
+        sinks.offer(&SAMPLE, true, start);
+        assert!(stats_of(&sinks, "flaky").backing_off);
+        sinks.offer(&SAMPLE, true, start + Duration::from_millis(4_900));
+        assert_eq!(sink.offered(), 1);
+
+        // Then for 10s after the second one in a row
+        let second = start + BACKOFF_MIN;
+        sinks.offer(&SAMPLE, true, second);
+        assert_eq!(sink.offered(), 2);
+        assert_eq!(*sink.resumed.borrow(), 2);
+        sinks.offer(&SAMPLE, true, second + Duration::from_millis(9_900));
+        assert_eq!(sink.offered(), 2);
+
+        // A delivery starts the backoff over
+        let third = second + BACKOFF_MIN * 2;
+        sinks.offer(&SAMPLE, true, third);
+        sinks.offer(&SAMPLE, true, third);
+        assert_eq!(sink.offered(), 4);
+        sinks.offer(&SAMPLE, true, third + BACKOFF_MIN);
+        assert_eq!(sink.offered(), 5);
+
+        let stats = stats_of(&sinks, "flaky");
+        assert_eq!((stats.delivered, stats.errors), (2, 3));
+        assert!(!stats.backing_off);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let start = Instant::now();
+        let sink = Scripted::named("down");
+        sink.answer(&vec![failed(); 20]);
+        let mut sinks = SinkManager::new();
+        sinks.register(sink.clone(), SinkPolicy::default());
+        let mut now = start;
+        for _ in 0..20 {
+            sinks.offer(&SAMPLE, true, now);
+            now += BACKOFF_MAX;
+        }
+        assert_eq!(sink.offered(), 20);
+    }
+
+    #[test]
+    fn failure_is_isolated() {
+        let start = Instant::now();
+        let broken = Scripted::named("broken");
+        broken.answer(&[failed()]);
+        let full = Scripted::named("full");
+        full.answer(&[Err(SinkError::Full), Err(SinkError::Full)]);
+        let fine = Scripted::named("fine");
+        let mut sinks = SinkManager::new();
+        for sink in [&broken, &full, &fine] {
+            sinks.register(sink.clone(), SinkPolicy::default());
+        }
+
+        for tick in 0..3 {
+            sinks.offer(&SAMPLE, true, start + Duration::from_millis(100 * tick));
+        }
+        assert_eq!(broken.offered(), 1);
+        assert_eq!(full.offered(), 3);
+        assert_eq!(fine.offered(), 3);
+
+        let stats = stats_of(&sinks, "broken");
+        assert_eq!((stats.delivered, stats.errors), (0, 1));
+        assert!(stats.backing_off);
+        // A full queue only drops the sample
+        let stats = stats_of(&sinks, "full");
+        assert_eq!((stats.delivered, stats.dropped, stats.errors), (1, 2, 0));
+        assert!(!stats.backing_off);
+        let stats = stats_of(&sinks, "fine");
+        assert_eq!((stats.delivered, stats.dropped, stats.errors), (3, 0, 0));
+    }
+
+    #[test]
+    fn throttle_and_demo_samples() {
+        let start = Instant::now();
+        let sampled = Scripted::named("sampled");
+        let log = Scripted::named("log");
+        let mut sinks = SinkManager::new();
+        sinks.register(
+            sampled.clone(),
+            SinkPolicy {
+                throttle: Throttle::Interval(Duration::from_secs(1)),
+                loggable_only: false,
+            },
+        );
+        sinks.register(
+            log.clone(),
+            SinkPolicy {
+                throttle: Throttle::EverySample,
+                loggable_only: true,
+            },
+        );
+        for tick in 0..25 {
+            let loggable = tick % 5 != 0;
+            sinks.offer(&SAMPLE, loggable, start + Duration::from_millis(100 * tick));
+        }
+        assert_eq!(sampled.offered(), 3);
+        assert_eq!(log.offered(), 20);
+
+        assert!(sinks.set_throttle("log", Throttle::Off));
+        assert!(!sinks.set_throttle("missing", Throttle::Off));
+        sinks.offer(&SAMPLE, true, start + Duration::from_secs(10));
+        assert_eq!(log.offered(), 20);
+        assert_eq!(sinks.throttle("log"), Some(Throttle::Off));
+    }
+}
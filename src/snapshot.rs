@@ -0,0 +1,94 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    linearity::LinearityReport,
+    power::IdleStage,
+    procedure::CalibrationStatus,
+    quality::{Quality, Reading},
+    unit::Unit,
+    volume::Volume,
+};
+
+/// Latest state of the scale, for tasks that report it without touching the
+/// load cell
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    /// Filtered weight, rounded to the resolution
+    pub grams: f32,
+    /// Latest weight before filtering
+    pub grams_raw: f32,
+    pub stable: bool,
+    /// Quality of the reading the weight comes from, when it was taken
+    pub quality: Quality,
+    /// Time without a reading after which the weight is stale
+    pub stale_after: Duration,
+    pub unit: Unit,
+    /// Substance the weight shows as the volume of, if any
+    pub volume: Option<Volume>,
+    pub scale_factor: Option<f32>,
+    pub offset: i32,
+    pub calibration_weight: f32,
+    /// Pack voltage, when the battery is monitored
+    pub battery_voltage: Option<f32>,
+    pub battery_percent: Option<u8>,
+    /// Where the last calibration is at
+    pub calibration: CalibrationStatus,
+    /// Report of the last linearity check
+    pub linearity: Option<LinearityReport>,
+    /// When the sensor last converted, none before the first reading
+    pub last_reading: Option<Instant>,
+    /// Whether the weight comes from the demo signal
+    pub demo: bool,
+    /// Whether the demo weight may go into the weight log
+    pub log_demo: bool,
+    /// How far the idle scale went
+    pub idle: IdleStage,
+}
+
+impl Snapshot {
+    /// Time since the sensor last converted, the weight above is at least
+    /// that old
+    pub fn reading_age(&self) -> Option<Duration> {
+        self.last_reading.map(|at| at.elapsed())
+    }
+
+    /// The weight along with its quality as it stands now, stale once the
+    /// sensor has been silent for long. None before the first reading.
+    pub fn reading(&self) -> Option<Reading> {
+        let age = self.reading_age()?;
+        Some(Reading::aged(
+            self.grams,
+            self.quality,
+            age,
+            self.stale_after,
+        ))
+    }
+
+    /// Whether the weight may be logged, the demo weight only when allowed
+    pub fn is_loggable(&self) -> bool {
+        !self.demo || self.log_demo
+    }
+}
+
+/// Snapshot written by the main loop and shared with the reporting tasks
+#[derive(Clone, Default)]
+pub struct SharedSnapshot(Arc<Mutex<Snapshot>>);
+
+impl SharedSnapshot {
+    pub fn get(&self) -> Snapshot {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn set(&self, snapshot: Snapshot) {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = snapshot;
+    }
+}
@@ -0,0 +1,205 @@
+//! Soak test characterizing the drift of the load cell over hours, e.g.
+//! overnight with a fixed weight on the platform. The filtered weight, the
+//! raw counts and the temperature, when there is a sensor for it, are taken
+//! every interval. At the end a line fitted through the weights tells the
+//! drift per hour and, against the temperatures, the drift per degree.
+
+use std::time::{Duration, Instant};
+
+/// Interval the points are taken at by default
+pub const DEFAULT_SOAK_INTERVAL: Duration = Duration::from_secs(60);
+pub const MIN_SOAK_INTERVAL: Duration = Duration::from_secs(1);
+pub const MAX_SOAK_INTERVAL: Duration = Duration::from_secs(3600);
+/// Longest soak that can be asked for
+pub const MAX_SOAK_DURATION: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Points kept in RAM, the oldest are thinned out past it
+pub const MAX_SOAK_POINTS: usize = 2880;
+
+/// Header of the lines `SoakPoint::to_csv` prints
+pub const SOAK_CSV_HEADER: &str = "soak,elapsed_s,grams,raw_counts,temperature_c";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SoakPoint {
+    /// Seconds since the soak started
+    pub elapsed_s: u32,
+    /// Filtered weight
+    pub grams: f32,
+    pub raw: i32,
+    pub temperature_c: Option<f32>,
+}
+
+impl SoakPoint {
+    /// CSV line matching `SOAK_CSV_HEADER`, tagged so it stands out in the
+    /// console output
+    pub fn to_csv(&self) -> String {
+        format!(
+            "soak,{},{:.3},{},{}",
+            self.elapsed_s,
+            self.grams,
+            self.raw,
+            self.temperature_c
+                .map_or(String::new(), |celsius| format!("{:.2}", celsius))
+        )
+    }
+}
+
+/// Running soak
+#[derive(Clone, Debug)]
+pub struct Soak {
+    interval: Duration,
+    /// None runs until stopped
+    duration: Option<Duration>,
+    started: Instant,
+    next_at: Instant,
+    points: Vec<SoakPoint>,
+}
+
+impl Soak {
+    pub fn new(interval: Duration, duration: Option<Duration>, now: Instant) -> Self {
+        Self {
+            interval: interval.clamp(MIN_SOAK_INTERVAL, MAX_SOAK_INTERVAL),
+            duration: duration.map(|duration| duration.min(MAX_SOAK_DURATION)),
+            started: now,
+            next_at: now,
+            points: Vec::new(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        now.duration_since(self.started)
+    }
+
+    pub fn points(&self) -> &[SoakPoint] {
+        &self.points
+    }
+
+    /// Take a reading, returning the point once one is due
+    pub fn on_sample(
+        &mut self,
+        grams: f32,
+        raw: i32,
+        temperature_c: Option<f32>,
+        now: Instant,
+    ) -> Option<SoakPoint> {
+        if now < self.next_at {
+            return None;
+        }
+        self.next_at += self.interval;
+        // After a stall, the next point is an interval away from now
+        if self.next_at < now {
+            self.next_at = now + self.interval;
+        }
+        let point = SoakPoint {
+            elapsed_s: self.elapsed(now).as_secs().try_into().unwrap_or(u32::MAX),
+            grams,
+            raw,
+            temperature_c,
+        };
+        if self.points.len() >= MAX_SOAK_POINTS {
+            // Every other point goes, the fit keeps the whole span
+            let mut index = 0;
+            self.points.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+        }
+        self.points.push(point);
+        Some(point)
+    }
+
+    /// Whether the duration asked for has passed
+    pub fn is_over(&self, now: Instant) -> bool {
+        self.duration
+            .is_some_and(|duration| self.elapsed(now) >= duration)
+    }
+
+    /// Fit the drift through the points taken so far
+    pub fn report(&self) -> SoakReport {
+        let hours: Vec<(f64, f64)> = self
+            .points
+            .iter()
+            .map(|point| (f64::from(point.elapsed_s) / 3600.0, f64::from(point.grams)))
+            .collect();
+        let temperatures: Vec<(f64, f64)> = self
+            .points
+            .iter()
+            .filter_map(|point| {
+                point
+                    .temperature_c
+                    .map(|celsius| (f64::from(celsius), f64::from(point.grams)))
+            })
+            .collect();
+        let span = |values: &mut dyn Iterator<Item = f32>| {
+            values.fold(None, |span: Option<(f32, f32)>, value| {
+                Some(span.map_or((value, value), |(low, high)| {
+                    (low.min(value), high.max(value))
+                }))
+            })
+        };
+        SoakReport {
+            points: self.points.len(),
+            hours: self
+                .points
+                .last()
+                .map_or(0.0, |point| point.elapsed_s as f32 / 3600.0),
+            grams: span(&mut self.points.iter().map(|point| point.grams)),
+            temperature_c: span(&mut self.points.iter().filter_map(|point| point.temperature_c)),
+            drift_per_hour: slope(&hours),
+            drift_per_degree: slope(&temperatures),
+        }
+    }
+}
+
+/// Outcome of a soak
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SoakReport {
+    pub points: usize,
+    /// Time from the first to the last point
+    pub hours: f32,
+    /// Lowest and highest weight
+    pub grams: Option<(f32, f32)>,
+    /// Lowest and highest temperature, none without a sensor
+    pub temperature_c: Option<(f32, f32)>,
+    /// Slope of the weight over time in g/h, none with less than two points
+    pub drift_per_hour: Option<f32>,
+    /// Slope of the weight against the temperature in g/°C, none without a
+    /// sensor or while the temperature did not change
+    pub drift_per_degree: Option<f32>,
+}
+
+impl SoakReport {
+    /// One line for the status strip
+    pub fn describe(&self) -> String {
+        match (self.drift_per_hour, self.drift_per_degree) {
+            (Some(per_hour), Some(per_degree)) => {
+                format!("{:+.2}g/h {:+.2}g/C", per_hour, per_degree)
+            }
+            (Some(per_hour), None) => format!("Drift {:+.2}g/h", per_hour),
+            (None, _) => "Soak too short".to_string(),
+        }
+    }
+}
+
+/// Least-squares slope of `y` over `x`, none for less than two points or
+/// when `x` does not change
+fn slope(points: &[(f64, f64)]) -> Option<f32> {
+    if points.len() < 2 {
+        return None;
+    }
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+    let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+        let dx = x - mean_x;
+        (cov + dx * (y - mean_y), var + dx * dx)
+    });
+    (variance > f64::EPSILON).then(|| (covariance / variance) as f32)
+}
@@ -0,0 +1,250 @@
+use embedded_graphics::{
+    prelude::{Point, Size},
+    primitives::Rectangle,
+    text::TextStyle,
+};
+use ssd1306::{prelude::WriteOnlyDataCommand, size::DisplaySize};
+
+use crate::text_drawer::{DisplayError, TextDrawer, TextError};
+
+const ICON_SIZE: u32 = 10;
+const ICON_SPACING: u32 = 4;
+const WIFI_BAR_WIDTH: u32 = 2;
+const DEMO_BADGE: &str = "DEMO";
+const SOAK_BADGE: &str = "SOAK";
+const CERTIFIED_BADGE: &str = "CERT";
+const BUTTON_STUCK_BADGE: &str = "BTN";
+
+/// Indicators shown in the status strip
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatusIcon {
+    WifiConnected,
+    WifiConnecting,
+    WifiOffline,
+    /// The Wi-Fi setup access point is open
+    AccessPoint,
+    /// Battery charge in quarters, 0 to 4
+    Battery {
+        quarters: u8,
+    },
+    /// Logging to the SD card is suspended, usually as the card is missing
+    SdCardSuspended,
+    /// Optional subsystems are off for lack of heap
+    HeapLow,
+    /// Recalibrating is suggested
+    Recalibrate,
+    /// The panel mirroring the display does not respond
+    MirrorDown,
+    /// The weight comes from the demo signal, not from the load cell
+    Demo,
+    /// A soak test is running, tares and calibrations wait for its end
+    Soak,
+    /// Certified mode is on, nothing alters the readings on its own
+    Certified,
+    /// The button is stuck, its presses ignored until it recovers
+    ButtonStuck,
+    /// Local time, shown once the clock is synchronized
+    Clock {
+        hours: u8,
+        minutes: u8,
+    },
+}
+
+/// Draw the icons right aligned in the status strip, without flushing. The
+/// clock goes to the left end instead.
+pub fn draw_status_icons<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    icons: &[StatusIcon],
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let status = text_drawer.layout().status;
+    let top = status.top_left.y + (status.size.height.saturating_sub(ICON_SIZE) / 2) as i32;
+    let mut right = status.top_left.x + status.size.width as i32;
+
+    for &icon in icons {
+        if let StatusIcon::Clock { hours, minutes } = icon {
+            let time = format!("{:02}:{:02}", hours, minutes);
+            text_drawer.draw_text(&time, status.top_left)?;
+            continue;
+        }
+        let badge = match icon {
+            StatusIcon::Demo => Some(DEMO_BADGE),
+            StatusIcon::Soak => Some(SOAK_BADGE),
+            StatusIcon::Certified => Some(CERTIFIED_BADGE),
+            StatusIcon::ButtonStuck => Some(BUTTON_STUCK_BADGE),
+            _ => None,
+        };
+        if let Some(badge) = badge {
+            // Inverted text, wider than an icon
+            let width = text_drawer.measure_text(badge, &TextStyle::default()).width;
+            right -= width as i32;
+            let inverse = text_drawer.inverse_char_style();
+            text_drawer.draw_text_with_char_style(
+                badge,
+                Point::new(right, status.top_left.y),
+                inverse,
+            )?;
+            right -= ICON_SPACING as i32;
+            continue;
+        }
+        right -= ICON_SIZE as i32;
+        let origin = Point::new(right, top);
+        match icon {
+            StatusIcon::Battery { quarters } => {
+                // Horizontal cell with a tip, filled by the charge
+                let body = Rectangle::new(origin + Point::new(0, 2), Size::new(ICON_SIZE - 2, 6));
+                text_drawer.draw_rect(body, false)?;
+                text_drawer.draw_rect(
+                    Rectangle::new(
+                        origin + Point::new(ICON_SIZE as i32 - 2, 4),
+                        Size::new(2, 2),
+                    ),
+                    true,
+                )?;
+                let fill = (ICON_SIZE - 4) * u32::from(quarters.min(4)) / 4;
+                if fill > 0 {
+                    text_drawer.draw_rect(
+                        Rectangle::new(origin + Point::new(1, 3), Size::new(fill, 4)),
+                        true,
+                    )?;
+                }
+            }
+            StatusIcon::SdCardSuspended => {
+                // Card outline, crossed out
+                let size = ICON_SIZE as i32 - 1;
+                text_drawer.draw_rect(
+                    Rectangle::new(
+                        origin + Point::new(1, 0),
+                        Size::new(ICON_SIZE - 2, ICON_SIZE),
+                    ),
+                    false,
+                )?;
+                text_drawer
+                    .draw_line(origin + Point::new(0, size), origin + Point::new(size, 0))?;
+            }
+            StatusIcon::HeapLow => {
+                // Memory chip with its pins, the lower half of it left empty
+                let body = Rectangle::new(
+                    origin + Point::new(2, 0),
+                    Size::new(ICON_SIZE - 4, ICON_SIZE),
+                );
+                text_drawer.draw_rect(body, false)?;
+                text_drawer.draw_rect(
+                    Rectangle::new(origin + Point::new(3, 1), Size::new(ICON_SIZE - 6, 3)),
+                    true,
+                )?;
+                for y in [2, 5, 8] {
+                    let y = origin.y + y;
+                    text_drawer.draw_line(Point::new(origin.x, y), Point::new(origin.x + 1, y))?;
+                    let right = origin.x + ICON_SIZE as i32 - 1;
+                    text_drawer.draw_line(Point::new(right - 1, y), Point::new(right, y))?;
+                }
+            }
+            StatusIcon::Recalibrate => {
+                // Exclamation mark in a box
+                let middle = origin.x + ICON_SIZE as i32 / 2;
+                text_drawer.draw_rect(
+                    Rectangle::new(origin, Size::new(ICON_SIZE, ICON_SIZE)),
+                    false,
+                )?;
+                text_drawer.draw_line(
+                    Point::new(middle, origin.y + 2),
+                    Point::new(middle, origin.y + ICON_SIZE as i32 - 5),
+                )?;
+                text_drawer.draw_rect(
+                    Rectangle::new(
+                        Point::new(middle, origin.y + ICON_SIZE as i32 - 3),
+                        Size::new(1, 1),
+                    ),
+                    true,
+                )?;
+            }
+            StatusIcon::MirrorDown => {
+                // Two panels back to back, the far one crossed out
+                let size = ICON_SIZE as i32 - 1;
+                text_drawer.draw_rect(
+                    Rectangle::new(origin + Point::new(0, 4), Size::new(6, 6)),
+                    false,
+                )?;
+                text_drawer.draw_rect(
+                    Rectangle::new(origin + Point::new(4, 0), Size::new(6, 6)),
+                    false,
+                )?;
+                text_drawer.draw_line(origin + Point::new(4, 5), origin + Point::new(size, 0))?;
+            }
+            StatusIcon::Clock { .. }
+            | StatusIcon::Demo
+            | StatusIcon::Soak
+            | StatusIcon::Certified
+            | StatusIcon::ButtonStuck => {}
+            StatusIcon::WifiConnected => draw_wifi_bars(text_drawer, origin, true)?,
+            StatusIcon::WifiConnecting => draw_wifi_bars(text_drawer, origin, false)?,
+            StatusIcon::WifiOffline => {
+                let size = ICON_SIZE as i32 - 1;
+                text_drawer.draw_line(origin, origin + Point::new(size, size))?;
+                text_drawer
+                    .draw_line(origin + Point::new(0, size), origin + Point::new(size, 0))?;
+            }
+            StatusIcon::AccessPoint => {
+                let center = origin + Point::new(ICON_SIZE as i32 / 2, ICON_SIZE as i32 / 2);
+                text_drawer.draw_circle(center, ICON_SIZE / 2 - 1, false)?;
+                text_drawer.draw_circle(center, 1, true)?;
+            }
+        }
+        right -= ICON_SPACING as i32;
+    }
+    Ok(())
+}
+
+/// Three bars of increasing height, outlined while not connected
+fn draw_wifi_bars<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    origin: Point,
+    filled: bool,
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    for bar in 1..=3u32 {
+        let height = ICON_SIZE * bar / 3;
+        let x = origin.x + ((bar - 1) * (WIFI_BAR_WIDTH + 2)) as i32;
+        let y = origin.y + (ICON_SIZE - height) as i32;
+        text_drawer.draw_rect(
+            Rectangle::new(Point::new(x, y), Size::new(WIFI_BAR_WIDTH, height)),
+            filled,
+        )?;
+    }
+    Ok(())
+}
+
+/// Draw a bar across the status strip filled by `fraction`, without flushing
+pub fn draw_progress_bar<DI, SIZE>(
+    text_drawer: &mut TextDrawer<DI, SIZE>,
+    fraction: f32,
+) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    let status = text_drawer.layout().status;
+    let bar = Rectangle::new(
+        status.top_left + Point::new(0, 2),
+        Size::new(status.size.width, status.size.height.saturating_sub(4)),
+    );
+    text_drawer.draw_rect(bar, false)?;
+    let fill = (bar.size.width.saturating_sub(4) as f32 * fraction.clamp(0.0, 1.0)) as u32;
+    if fill > 0 {
+        text_drawer.draw_rect(
+            Rectangle::new(
+                bar.top_left + Point::new(2, 2),
+                Size::new(fill, bar.size.height.saturating_sub(4)),
+            ),
+            true,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,701 @@
+//! NVS namespaces with a schema version. Each namespace of the default
+//! partition is brought up to date at boot by the migrations registered in
+//! `SCHEMAS`, before anything reads it, so a layout can change without old
+//! devices misreading what they stored. The typed accessors log a value that
+//! fails to read or decode and return nothing, leaving the callers to fall
+//! back to their defaults. The structures are stored in the envelope of
+//! `envelope`, so one left half written by a brown-out reads as missing too,
+//! and counts towards the corrupt blobs counter.
+//!
+//! Every namespace goes through the one `StorageService`, which owns the
+//! `Store` behind it, the NVS handles on the scale and a map in memory on the
+//! host. Writes are held in a cache for a few seconds, so a value set several
+//! times in a row costs a single flash write, and are flushed by a task of
+//! their own, before a restart, or on `flush`. The reset log keeps a handle
+//! of its own, as its panic hook has to write right away.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "esp")]
+use std::{
+    ffi::{c_char, CStr},
+    ptr,
+    sync::OnceLock,
+};
+
+#[cfg(feature = "esp")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+#[cfg(feature = "esp")]
+use esp_idf_sys::{
+    esp, esp_err_t, esp_register_shutdown_handler, nvs_close, nvs_entry_find, nvs_entry_info,
+    nvs_entry_info_t, nvs_entry_next, nvs_get_blob, nvs_get_str, nvs_handle_t, nvs_iterator_t,
+    nvs_open, nvs_open_mode_t_NVS_READONLY, nvs_release_iterator, nvs_type_t,
+    nvs_type_t_NVS_TYPE_ANY, nvs_type_t_NVS_TYPE_BLOB, nvs_type_t_NVS_TYPE_I16,
+    nvs_type_t_NVS_TYPE_I32, nvs_type_t_NVS_TYPE_I64, nvs_type_t_NVS_TYPE_I8,
+    nvs_type_t_NVS_TYPE_STR, nvs_type_t_NVS_TYPE_U16, nvs_type_t_NVS_TYPE_U32,
+    nvs_type_t_NVS_TYPE_U64, nvs_type_t_NVS_TYPE_U8, EspError, ESP_ERR_NVS_NOT_FOUND, ESP_OK,
+    NVS_DEFAULT_PART_NAME,
+};
+use log::{info, warn};
+use thiserror::Error;
+
+#[cfg(feature = "esp")]
+use crate::shutdown;
+use crate::{
+    counters::{self, Counter},
+    envelope,
+    write_cache::{Backend, Value, ValueKind, WriteCache},
+};
+
+/// Key of the schema version in every versioned namespace
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// Version of a namespace written before it was versioned
+const UNVERSIONED: u8 = 1;
+/// Time a write waits for more writes to flush along with
+const WRITE_WINDOW: Duration = Duration::from_secs(5);
+/// Period the flush task checks for writes due
+const FLUSH_CHECK_PERIOD: Duration = Duration::from_secs(1);
+const FLUSH_TASK_STACK_SIZE: usize = 4096;
+
+/// Error of the store behind the cache
+#[cfg(feature = "esp")]
+pub type StoreError = EspError;
+/// The store in memory does not fail
+#[cfg(not(feature = "esp"))]
+pub type StoreError = std::convert::Infallible;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[cfg(feature = "esp")]
+    #[error("Failed to register the storage flush: {0}")]
+    Shutdown(EspError),
+    #[error("Failed to start the storage task: {0}")]
+    Task(std::io::Error),
+}
+
+/// Flushed by the shutdown handler before a restart
+#[cfg(feature = "esp")]
+static SHUTDOWN_STORAGE: OnceLock<StorageService> = OnceLock::new();
+
+/// Brings a namespace from one schema version to the next
+pub type Migration = fn(&mut Storage) -> Result<(), StoreError>;
+
+/// Layout history of a namespace
+pub struct Schema {
+    pub namespace: &'static str,
+    /// Migration from version `n + 1` to `n + 2` at index `n`, appended to
+    /// whenever the layout of the namespace changes
+    pub migrations: &'static [Migration],
+}
+
+impl Schema {
+    pub fn version(&self) -> u8 {
+        UNVERSIONED + self.migrations.len() as u8
+    }
+}
+
+/// The versioned namespaces of the default partition. The weight log lives
+/// in a partition of its own and keeps its own metadata.
+pub const SCHEMAS: &[Schema] = &[
+    Schema {
+        namespace: crate::settings::SETTINGS_NAMESPACE,
+        migrations: &[crate::settings::seal_blobs],
+    },
+    Schema {
+        namespace: crate::scale::STORAGE_NAMESPACE,
+        migrations: &[crate::scale::seal_blobs],
+    },
+    #[cfg(feature = "esp")]
+    Schema {
+        namespace: crate::reset::RESET_NAMESPACE,
+        migrations: &[],
+    },
+    Schema {
+        namespace: crate::alarms::ALARMS_NAMESPACE,
+        migrations: &[crate::alarms::seal_blobs],
+    },
+    Schema {
+        namespace: crate::session::SESSION_NAMESPACE,
+        migrations: &[crate::session::seal_blobs],
+    },
+    Schema {
+        namespace: crate::device::DEVICE_NAMESPACE,
+        migrations: &[],
+    },
+    Schema {
+        namespace: crate::webhook::WEBHOOK_NAMESPACE,
+        migrations: &[crate::webhook::seal_blobs],
+    },
+];
+
+/// A value stored as a blob, in the checked envelope
+pub trait Stored: Sized {
+    fn encode(&self) -> Vec<u8>;
+    /// `None` when the bytes cannot be decoded
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+/// Key-value store the namespaces are kept in
+pub trait Store: Backend<Error = StoreError> + Send {
+    /// Open the namespace, creating it if needed
+    fn open(&mut self, namespace: &'static str) -> Result<(), StoreError>;
+
+    /// Every key of the store
+    fn entries(&mut self) -> Result<Vec<StorageEntry>, StoreError>;
+}
+
+impl Backend for Box<dyn Store> {
+    type Error = StoreError;
+
+    fn read(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        kind: ValueKind,
+    ) -> Result<Option<Value>, StoreError> {
+        (**self).read(namespace, key, kind)
+    }
+
+    fn write(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        value: Option<&Value>,
+    ) -> Result<(), StoreError> {
+        (**self).write(namespace, key, value)
+    }
+
+    fn contains(&mut self, namespace: &'static str, key: &str) -> Result<bool, StoreError> {
+        (**self).contains(namespace, key)
+    }
+}
+
+/// Store kept in memory for as long as the process runs, for the simulator
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    values: BTreeMap<(&'static str, String), Value>,
+}
+
+impl Backend for MemoryStore {
+    type Error = StoreError;
+
+    fn read(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        kind: ValueKind,
+    ) -> Result<Option<Value>, StoreError> {
+        Ok(self
+            .values
+            .get(&(namespace, key.to_string()))
+            .filter(|value| value.kind() == kind)
+            .cloned())
+    }
+
+    fn write(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        value: Option<&Value>,
+    ) -> Result<(), StoreError> {
+        match value {
+            Some(value) => self
+                .values
+                .insert((namespace, key.to_string()), value.clone()),
+            None => self.values.remove(&(namespace, key.to_string())),
+        };
+        Ok(())
+    }
+
+    fn contains(&mut self, namespace: &'static str, key: &str) -> Result<bool, StoreError> {
+        Ok(self.values.contains_key(&(namespace, key.to_string())))
+    }
+}
+
+impl Store for MemoryStore {
+    fn open(&mut self, _namespace: &'static str) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn entries(&mut self) -> Result<Vec<StorageEntry>, StoreError> {
+        Ok(self
+            .values
+            .iter()
+            .map(|((namespace, key), value)| StorageEntry {
+                namespace: namespace.to_string(),
+                key: key.clone(),
+                kind: match value.kind() {
+                    ValueKind::U8 => "u8",
+                    ValueKind::U32 => "u32",
+                    ValueKind::Blob => "blob",
+                },
+                size: Some(match value {
+                    Value::U8(_) => 1,
+                    Value::U32(_) => 4,
+                    Value::Blob(bytes) => bytes.len(),
+                }),
+            })
+            .collect())
+    }
+}
+
+/// The NVS handles of the default partition, one per namespace
+#[cfg(feature = "esp")]
+struct NvsBackend {
+    partition: EspDefaultNvsPartition,
+    handles: BTreeMap<&'static str, EspNvs<NvsDefault>>,
+}
+
+#[cfg(feature = "esp")]
+impl NvsBackend {
+    fn handle(&mut self, namespace: &'static str) -> Result<&mut EspNvs<NvsDefault>, StoreError> {
+        if !self.handles.contains_key(namespace) {
+            let nvs = EspNvs::new(self.partition.clone(), namespace, true)?;
+            self.handles.insert(namespace, nvs);
+        }
+        Ok(self.handles.get_mut(namespace).expect("handle just opened"))
+    }
+}
+
+#[cfg(feature = "esp")]
+impl Backend for NvsBackend {
+    type Error = EspError;
+
+    fn read(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        kind: ValueKind,
+    ) -> Result<Option<Value>, StoreError> {
+        let nvs = self.handle(namespace)?;
+        Ok(match kind {
+            ValueKind::U8 => nvs.get_u8(key)?.map(Value::U8),
+            ValueKind::U32 => nvs.get_u32(key)?.map(Value::U32),
+            ValueKind::Blob => match nvs.blob_len(key)? {
+                Some(len) => {
+                    let mut buf = vec![0; len];
+                    nvs.get_blob(key, &mut buf)?
+                        .map(|bytes| Value::Blob(bytes.to_vec()))
+                }
+                None => None,
+            },
+        })
+    }
+
+    fn write(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        value: Option<&Value>,
+    ) -> Result<(), StoreError> {
+        let nvs = self.handle(namespace)?;
+        match value {
+            Some(Value::U8(value)) => nvs.set_u8(key, *value),
+            Some(Value::U32(value)) => nvs.set_u32(key, *value),
+            Some(Value::Blob(bytes)) => nvs.set_blob(key, bytes),
+            None => nvs.remove(key).map(|_| ()),
+        }
+    }
+
+    fn contains(&mut self, namespace: &'static str, key: &str) -> Result<bool, StoreError> {
+        self.handle(namespace)?.contains(key)
+    }
+}
+
+#[cfg(feature = "esp")]
+impl Store for NvsBackend {
+    fn open(&mut self, namespace: &'static str) -> Result<(), StoreError> {
+        self.handle(namespace).map(|_| ())
+    }
+
+    fn entries(&mut self) -> Result<Vec<StorageEntry>, StoreError> {
+        let mut found = Vec::new();
+        let mut iterator: nvs_iterator_t = ptr::null_mut();
+        let mut result = unsafe {
+            nvs_entry_find(
+                NVS_DEFAULT_PART_NAME.as_ptr() as *const c_char,
+                ptr::null(),
+                nvs_type_t_NVS_TYPE_ANY,
+                &mut iterator,
+            )
+        };
+        while result == ESP_OK as esp_err_t {
+            let mut info = nvs_entry_info_t::default();
+            result = unsafe { nvs_entry_info(iterator, &mut info) };
+            if result != ESP_OK as esp_err_t {
+                break;
+            }
+            found.push(info);
+            result = unsafe { nvs_entry_next(&mut iterator) };
+        }
+        // Releasing the iterator after the last entry is a no-op
+        unsafe { nvs_release_iterator(iterator) };
+        if result != ESP_ERR_NVS_NOT_FOUND as esp_err_t {
+            esp!(result)?;
+        }
+
+        Ok(found
+            .iter()
+            .map(|info| {
+                let namespace = unsafe { CStr::from_ptr(info.namespace_name.as_ptr()) };
+                let key = unsafe { CStr::from_ptr(info.key.as_ptr()) };
+                StorageEntry {
+                    namespace: namespace.to_string_lossy().into_owned(),
+                    key: key.to_string_lossy().into_owned(),
+                    kind: kind_name(info.type_),
+                    size: value_size(namespace, key, info.type_),
+                }
+            })
+            .collect())
+    }
+}
+
+/// Handle to the default partition, shared by every namespace
+#[derive(Clone)]
+pub struct StorageService {
+    cache: Arc<Mutex<WriteCache<Box<dyn Store>>>>,
+}
+
+impl StorageService {
+    /// Start flushing the writes to NVS as they come due, and before any
+    /// restart
+    #[cfg(feature = "esp")]
+    pub fn start(nvs_default_partition: EspDefaultNvsPartition) -> Result<Self, StorageError> {
+        let service = Self::start_with(NvsBackend {
+            partition: nvs_default_partition,
+            handles: BTreeMap::new(),
+        })?;
+        if SHUTDOWN_STORAGE.set(service.clone()).is_ok() {
+            esp!(unsafe { esp_register_shutdown_handler(Some(flush_on_shutdown)) })
+                .map_err(StorageError::Shutdown)?;
+            // The deep sleep does not run the handler
+            let sleeping = service.clone();
+            shutdown::register("storage", move || {
+                if let Err(err) = sleeping.flush() {
+                    warn!("Failed to write the storage: {:?}", err);
+                }
+            });
+        }
+        Ok(service)
+    }
+
+    /// Start flushing the writes to `store` as they come due
+    pub fn start_with(store: impl Store + 'static) -> Result<Self, StorageError> {
+        let store: Box<dyn Store> = Box::new(store);
+        let service = Self {
+            cache: Arc::new(Mutex::new(WriteCache::new(store, WRITE_WINDOW))),
+        };
+
+        let flushing = service.clone();
+        std::thread::Builder::new()
+            .name("storage".to_string())
+            .stack_size(FLUSH_TASK_STACK_SIZE)
+            .spawn(move || loop {
+                std::thread::sleep(FLUSH_CHECK_PERIOD);
+                let mut cache = flushing.lock();
+                if cache.is_due(Instant::now()) {
+                    if let Err(err) = cache.flush() {
+                        warn!("Failed to write the storage: {:?}", err);
+                    }
+                }
+            })
+            .map_err(StorageError::Task)?;
+        Ok(service)
+    }
+
+    /// Handle to a namespace, opening it if needed
+    pub fn open(&self, namespace: &'static str) -> Result<Storage, StoreError> {
+        self.lock().backend_mut().open(namespace)?;
+        Ok(Storage {
+            service: self.clone(),
+            namespace,
+        })
+    }
+
+    /// Write out every pending write now, e.g. before going to sleep
+    pub fn flush(&self) -> Result<(), StoreError> {
+        let written = self.lock().flush()?;
+        if written > 0 {
+            info!("Flushed {} storage writes", written);
+        }
+        Ok(())
+    }
+
+    /// Number of writes waiting to be flushed
+    pub fn pending(&self) -> usize {
+        self.lock().pending()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WriteCache<Box<dyn Store>>> {
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Called by `esp_restart`. Never waits on the lock, the restart may come
+/// from under it.
+#[cfg(feature = "esp")]
+extern "C" fn flush_on_shutdown() {
+    let Some(service) = SHUTDOWN_STORAGE.get() else {
+        return;
+    };
+    if let Ok(mut cache) = service.cache.try_lock() {
+        let _ = cache.flush();
+    }
+}
+
+/// A namespace of the default partition
+#[derive(Clone)]
+pub struct Storage {
+    service: StorageService,
+    namespace: &'static str,
+}
+
+impl Storage {
+    pub fn namespace(&self) -> &'static str {
+        self.namespace
+    }
+
+    /// Schema version the namespace was last migrated to
+    pub fn schema_version(&self) -> Result<u8, StoreError> {
+        let stored = match self.get(SCHEMA_VERSION_KEY, ValueKind::U8)? {
+            Some(Value::U8(version)) => version,
+            _ => UNVERSIONED,
+        };
+        Ok(stored.max(UNVERSIONED))
+    }
+
+    /// Run the migrations the namespace has not seen yet. A namespace written
+    /// by a newer firmware is left alone.
+    fn migrate(&mut self, schema: &Schema) -> Result<(), StoreError> {
+        let stored = self.schema_version()?;
+        let current = schema.version();
+        if stored > current {
+            warn!(
+                "Storage {} has schema version {}, newer than {}",
+                self.namespace, stored, current
+            );
+            return Ok(());
+        }
+        for from in stored..current {
+            info!(
+                "Migrating storage {} from version {} to {}",
+                self.namespace,
+                from,
+                from + 1
+            );
+            schema.migrations[(from - UNVERSIONED) as usize](self)?;
+            self.set(SCHEMA_VERSION_KEY, Value::U8(from + 1));
+        }
+        // Version namespaces that were written before versioning or are new
+        if self.get(SCHEMA_VERSION_KEY, ValueKind::U8)?.is_none() {
+            self.set(SCHEMA_VERSION_KEY, Value::U8(current));
+        }
+        Ok(())
+    }
+
+    pub fn get_u32(&self, key: &str) -> Option<u32> {
+        match self.get(key, ValueKind::U32) {
+            Ok(Some(Value::U32(value))) => Some(value),
+            Ok(_) => None,
+            Err(err) => self.unreadable(key, err),
+        }
+    }
+
+    pub fn set_u32(&self, key: &str, value: u32) -> Result<(), StoreError> {
+        self.set(key, Value::U32(value));
+        Ok(())
+    }
+
+    /// Stored as its two's complement bits
+    pub fn get_i32(&self, key: &str) -> Option<i32> {
+        self.get_u32(key).map(|bits| bits as i32)
+    }
+
+    pub fn set_i32(&self, key: &str, value: i32) -> Result<(), StoreError> {
+        self.set_u32(key, value as u32)
+    }
+
+    /// Stored as its bits, a value that is not a finite number is dropped
+    pub fn get_f32(&self, key: &str) -> Option<f32> {
+        let value = f32::from_bits(self.get_u32(key)?);
+        if value.is_finite() {
+            Some(value)
+        } else {
+            warn!(
+                "Stored {}/{} is not a number, ignoring it",
+                self.namespace, key
+            );
+            None
+        }
+    }
+
+    pub fn set_f32(&self, key: &str, value: f32) -> Result<(), StoreError> {
+        self.set_u32(key, value.to_bits())
+    }
+
+    pub fn get_blob(&self, key: &str) -> Option<Vec<u8>> {
+        match self.get(key, ValueKind::Blob) {
+            Ok(Some(Value::Blob(bytes))) => Some(bytes),
+            Ok(_) => None,
+            Err(err) => self.unreadable(key, err),
+        }
+    }
+
+    pub fn set_blob(&self, key: &str, bytes: &[u8]) -> Result<(), StoreError> {
+        self.set(key, Value::Blob(bytes.to_vec()));
+        Ok(())
+    }
+
+    /// The payload of a blob stored with `put_checked_blob`. One failing
+    /// its length or CRC is logged, counted and read as missing.
+    pub fn get_checked_blob(&self, key: &str) -> Option<Vec<u8>> {
+        let blob = self.get_blob(key)?;
+        match envelope::open(&blob) {
+            Ok(payload) => Some(payload.to_vec()),
+            Err(err) => {
+                warn!("Stored {}/{} is corrupt: {}", self.namespace, key, err);
+                counters::increment(Counter::CorruptBlobs);
+                None
+            }
+        }
+    }
+
+    pub fn put_checked_blob(&self, key: &str, payload: &[u8]) -> Result<(), StoreError> {
+        self.set_blob(key, &envelope::seal(payload))
+    }
+
+    pub fn get_checked<T: Stored>(&self, key: &str) -> Option<T> {
+        let decoded = T::decode(&self.get_checked_blob(key)?);
+        if decoded.is_none() {
+            warn!("Failed to decode {}/{}, ignoring it", self.namespace, key);
+        }
+        decoded
+    }
+
+    pub fn put_checked<T: Stored>(&self, key: &str, value: &T) -> Result<(), StoreError> {
+        self.put_checked_blob(key, &value.encode())
+    }
+
+    /// Put a blob stored before the envelope into one, for the migrations
+    pub fn seal_blob(&self, key: &str) -> Result<(), StoreError> {
+        match self.get(key, ValueKind::Blob)? {
+            Some(Value::Blob(bytes)) => self.put_checked_blob(key, &bytes),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the key could be looked up, present or not
+    pub fn is_readable(&self, key: &str) -> bool {
+        self.get(key, ValueKind::Blob).is_ok()
+    }
+
+    /// Write out the pending writes of every namespace now, for a value
+    /// that has to survive a brown-out
+    pub fn flush(&self) -> Result<(), StoreError> {
+        self.service.flush()
+    }
+
+    /// Returns whether the key was there
+    pub fn remove(&self, key: &str) -> Result<bool, StoreError> {
+        self.service
+            .lock()
+            .remove(self.namespace, key, Instant::now())
+    }
+
+    fn get(&self, key: &str, kind: ValueKind) -> Result<Option<Value>, StoreError> {
+        self.service.lock().get(self.namespace, key, kind)
+    }
+
+    /// Written out along with the other writes of the window
+    fn set(&self, key: &str, value: Value) {
+        self.service
+            .lock()
+            .set(self.namespace, key, value, Instant::now());
+    }
+
+    fn unreadable<T>(&self, key: &str, err: StoreError) -> Option<T> {
+        warn!("Failed to read {}/{}: {:?}", self.namespace, key, err);
+        None
+    }
+}
+
+/// Bring every namespace in `SCHEMAS` up to date, logging the ones that fail
+pub fn migrate(storage: &StorageService) {
+    for schema in SCHEMAS {
+        let migrated = storage
+            .open(schema.namespace)
+            .and_then(|mut namespace| namespace.migrate(schema));
+        if let Err(err) = migrated {
+            warn!("Failed to migrate storage {}: {:?}", schema.namespace, err);
+        }
+    }
+    if let Err(err) = storage.flush() {
+        warn!("Failed to write the migrated storage: {:?}", err);
+    }
+}
+
+/// A key of the default partition, as listed by `dump`
+#[derive(Clone, Debug)]
+pub struct StorageEntry {
+    pub namespace: String,
+    pub key: String,
+    pub kind: &'static str,
+    /// Bytes taken by the value, `None` when its length could not be read
+    pub size: Option<usize>,
+}
+
+/// List every key of the default partition, after flushing the pending
+/// writes so they are listed too
+pub fn dump(storage: &StorageService) -> Result<Vec<StorageEntry>, StoreError> {
+    storage.flush()?;
+    storage.lock().backend_mut().entries()
+}
+
+#[cfg(feature = "esp")]
+fn kind_name(kind: nvs_type_t) -> &'static str {
+    match kind {
+        nvs_type_t_NVS_TYPE_U8 => "u8",
+        nvs_type_t_NVS_TYPE_I8 => "i8",
+        nvs_type_t_NVS_TYPE_U16 => "u16",
+        nvs_type_t_NVS_TYPE_I16 => "i16",
+        nvs_type_t_NVS_TYPE_U32 => "u32",
+        nvs_type_t_NVS_TYPE_I32 => "i32",
+        nvs_type_t_NVS_TYPE_U64 => "u64",
+        nvs_type_t_NVS_TYPE_I64 => "i64",
+        nvs_type_t_NVS_TYPE_STR => "str",
+        nvs_type_t_NVS_TYPE_BLOB => "blob",
+        _ => "?",
+    }
+}
+
+/// Size of a value, integers take the bytes of their type
+#[cfg(feature = "esp")]
+fn value_size(namespace: &CStr, key: &CStr, kind: nvs_type_t) -> Option<usize> {
+    if kind != nvs_type_t_NVS_TYPE_STR && kind != nvs_type_t_NVS_TYPE_BLOB {
+        return Some((kind & 0x0f) as usize);
+    }
+    let mut handle: nvs_handle_t = 0;
+    esp!(unsafe {
+        nvs_open(
+            namespace.as_ptr(),
+            nvs_open_mode_t_NVS_READONLY,
+            &mut handle,
+        )
+    })
+    .ok()?;
+    let mut len = 0;
+    let result = unsafe {
+        if kind == nvs_type_t_NVS_TYPE_STR {
+            nvs_get_str(handle, key.as_ptr(), ptr::null_mut(), &mut len)
+        } else {
+            nvs_get_blob(handle, key.as_ptr(), ptr::null_mut(), &mut len)
+        }
+    };
+    unsafe { nvs_close(handle) };
+    esp!(result).ok().map(|()| len)
+}
@@ -0,0 +1,168 @@
+use std::{
+    fmt,
+    io::{stdout, Cursor, Write},
+    sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    time::Duration,
+};
+
+use crate::{
+    device::{self, Stamp},
+    filter::Sample,
+    sink::{OutputSink, SinkError, SinkResult, Throttle},
+    time::Timestamp,
+};
+
+/// Name of the streamer among the sinks
+pub const CSV_SINK: &str = "csv";
+
+/// Header printed when streaming is turned on before the clock is synchronized
+pub const CSV_HEADER: &str =
+    "millis,raw_counts,grams_filtered,grams_raw,stable_flag,quality,device_id,boot,seq";
+/// Header printed once the clock is synchronized, timestamps are then UTC
+pub const CSV_HEADER_WALL_CLOCK: &str =
+    "time_utc,raw_counts,grams_filtered,grams_raw,stable_flag,quality,device_id,boot,seq";
+/// Printed ahead of the new header when the clock gets synchronized mid-stream
+const CSV_TIME_SYNCED_NOTE: &str = "# clock synchronized, timestamps are ISO-8601 UTC from here on";
+
+/// Rate streaming starts with at boot
+pub const STREAM_DEFAULT_RATE: StreamRate = StreamRate::Off;
+
+/// Number of lines buffered for the printing task
+const STREAM_QUEUE_LEN: usize = 32;
+const CSV_LINE_MAX_LEN: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamRate {
+    Off,
+    EverySample,
+    /// Decimate the samples down to the given rate
+    Hz(f32),
+}
+
+impl From<StreamRate> for Throttle {
+    fn from(rate: StreamRate) -> Self {
+        match rate {
+            StreamRate::Off => Throttle::Off,
+            StreamRate::EverySample => Throttle::EverySample,
+            StreamRate::Hz(hz) => Throttle::Interval(Duration::from_secs_f32(1.0 / hz)),
+        }
+    }
+}
+
+/// Sample formatted as a CSV line matching the headers, without the line
+/// break
+pub struct CsvSample<'s>(pub Timestamp, pub &'s Sample, pub Stamp);
+
+impl fmt::Display for CsvSample<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let CsvSample(timestamp, sample, stamp) = self;
+        write!(
+            f,
+            "{},{},{:.2},{:.2},{},{},{},{},{}",
+            timestamp,
+            sample.raw,
+            sample.grams_filtered,
+            sample.grams_raw,
+            u8::from(sample.stable),
+            sample.quality.name(),
+            stamp.device_id,
+            stamp.boot,
+            stamp.seq
+        )
+    }
+}
+
+/// A formatted CSV line, stored inline so queueing it does not allocate
+struct CsvLine {
+    buf: [u8; CSV_LINE_MAX_LEN],
+    len: usize,
+}
+
+/// Streams samples as CSV lines over the serial console, at the rate of
+/// its throttle among the sinks. Lines are queued to a printing task, so a
+/// slow UART never blocks sampling; lines that do not fit in the queue are
+/// dropped.
+pub struct CsvStreamer {
+    lines: SyncSender<CsvLine>,
+    /// Whether the header last printed is for wall clock timestamps
+    wall_clock: bool,
+}
+
+impl CsvStreamer {
+    /// Start the printing task
+    pub fn start() -> Self {
+        let (tx, rx) = sync_channel(STREAM_QUEUE_LEN);
+        std::thread::spawn(move || print_task(rx));
+
+        Self {
+            lines: tx,
+            wall_clock: false,
+        }
+    }
+
+    fn queue_header(&mut self, wall_clock: bool) -> SinkResult {
+        let header = if wall_clock {
+            CSV_HEADER_WALL_CLOCK
+        } else {
+            CSV_HEADER
+        };
+        self.queue(format_args!("{}", header))?;
+        self.wall_clock = wall_clock;
+        Ok(())
+    }
+
+    /// Queue a line for the printing task, which keeps it in order with the
+    /// samples
+    fn queue(&mut self, args: fmt::Arguments) -> SinkResult {
+        let mut cursor = Cursor::new([0u8; CSV_LINE_MAX_LEN]);
+        // A line that does not fit the buffer is truncated, never allocated
+        let _ = cursor.write_fmt(args);
+        let _ = cursor.write_all(b"\n");
+        let len = cursor.position() as usize;
+        let line = CsvLine {
+            buf: cursor.into_inner(),
+            len,
+        };
+
+        match self.lines.try_send(line) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(SinkError::Full),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(SinkError::Failed("printing task stopped".to_string()))
+            }
+        }
+    }
+}
+
+impl OutputSink for CsvStreamer {
+    fn name(&self) -> &'static str {
+        CSV_SINK
+    }
+
+    fn offer(&mut self, sample: &Sample) -> SinkResult {
+        // The timestamp format changes with the time source, so flag the
+        // switch with a fresh header
+        let timestamp = Timestamp::now();
+        if timestamp.is_wall_clock() != self.wall_clock {
+            self.queue(format_args!("{}", CSV_TIME_SYNCED_NOTE))?;
+            self.queue_header(timestamp.is_wall_clock())?;
+        }
+
+        self.queue(format_args!(
+            "{}",
+            CsvSample(timestamp, sample, device::stamp())
+        ))
+    }
+
+    /// Print the header each time streaming is turned on
+    fn resume(&mut self) {
+        let _ = self.queue_header(Timestamp::now().is_wall_clock());
+    }
+}
+
+fn print_task(lines: Receiver<CsvLine>) {
+    let mut stdout = stdout();
+    while let Ok(line) = lines.recv() {
+        let _ = stdout.write_all(&line.buf[..line.len]);
+    }
+}
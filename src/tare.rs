@@ -0,0 +1,96 @@
+//! Software tares stacked on top of the zero captured by the tare procedure,
+//! e.g. the bowl, then the flour, before adding the sugar. They only shift
+//! the weight reported, so the gross weight stays a toggle away and popping
+//! a tare brings the previous net weight back.
+
+/// Soft tares that can be stacked
+pub const MAX_SOFT_TARES: usize = 4;
+
+/// Which weight the scale reports
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisplayMode {
+    /// Weight above the zero of the tare procedure
+    #[default]
+    Gross,
+    /// Weight above the stacked soft tares
+    Net,
+}
+
+impl DisplayMode {
+    /// Label shown next to the weight
+    pub fn label(self) -> &'static str {
+        match self {
+            DisplayMode::Gross => "GRS",
+            DisplayMode::Net => "NET",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SoftTare {
+    /// Grams each tare took off, the first one at the bottom
+    layers: Vec<f32>,
+    mode: DisplayMode,
+}
+
+impl SoftTare {
+    /// Tare the net weight given the `gross` one, switching to it. Returns
+    /// false when the stack is full.
+    pub fn push(&mut self, gross: f32) -> bool {
+        if self.layers.len() >= MAX_SOFT_TARES {
+            return false;
+        }
+        self.layers.push(gross - self.total());
+        self.mode = DisplayMode::Net;
+        true
+    }
+
+    /// Remove the last tare, returning the grams it took off. The gross
+    /// weight comes back with the last one gone.
+    pub fn pop(&mut self) -> Option<f32> {
+        let layer = self.layers.pop()?;
+        if self.layers.is_empty() {
+            self.mode = DisplayMode::Gross;
+        }
+        Some(layer)
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+        self.mode = DisplayMode::Gross;
+    }
+
+    /// Switch between the net and the gross weight, which only differ with
+    /// a tare stacked
+    pub fn toggle(&mut self) -> DisplayMode {
+        if !self.layers.is_empty() {
+            self.mode = match self.mode {
+                DisplayMode::Gross => DisplayMode::Net,
+                DisplayMode::Net => DisplayMode::Gross,
+            };
+        }
+        self.mode
+    }
+
+    pub fn mode(&self) -> DisplayMode {
+        self.mode
+    }
+
+    /// Number of tares stacked
+    pub fn depth(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Grams taken off by all the tares
+    pub fn total(&self) -> f32 {
+        self.layers.iter().sum()
+    }
+
+    /// Weight to report for the `gross` one
+    pub fn apply(&self, gross: f32) -> f32 {
+        match self.mode {
+            DisplayMode::Gross => gross,
+            DisplayMode::Net => gross - self.total(),
+        }
+    }
+}
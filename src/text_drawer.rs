@@ -1,21 +1,81 @@
+use std::time::{Duration, Instant};
+
+use display_interface::{DataFormat, DisplayError as InterfaceError, WriteOnlyDataCommand};
 use embedded_graphics::{
     mono_font::{MonoFont, MonoTextStyle, MonoTextStyleBuilder},
     pixelcolor::BinaryColor,
     prelude::*,
-    primitives::Rectangle,
+    primitives::{Circle, Line, Primitive, PrimitiveStyle, Rectangle, Styled},
     text::{Baseline, Text, TextStyle, TextStyleBuilder},
     Drawable,
 };
+use log::{error, warn};
 use ssd1306::{
-    mode::BufferedGraphicsMode, prelude::WriteOnlyDataCommand, size::DisplaySize, Ssd1306,
+    mode::{BufferedGraphicsMode, DisplayConfig},
+    prelude::Brightness,
+    size::DisplaySize,
+    Ssd1306,
 };
 use thiserror::Error;
 
+use crate::{layout::UiLayout, quiesce::quiesce};
+
+const SPINNER_FRAMES: [&str; 4] = ["|", "/", "-", "\\"];
+const SPINNER_FRAME_PERIOD: Duration = Duration::from_millis(125);
+
+const FLUSH_ATTEMPTS: u32 = 3;
+const FLUSH_BACKOFF: Duration = Duration::from_millis(5);
+
+/// Brightness levels selectable through `set_brightness`, dimmest first
+const BRIGHTNESS_LEVELS: [Brightness; 5] = [
+    Brightness::DIMMEST,
+    Brightness::DIM,
+    Brightness::NORMAL,
+    Brightness::BRIGHT,
+    Brightness::BRIGHTEST,
+];
+pub const MAX_BRIGHTNESS_LEVEL: u8 = BRIGHTNESS_LEVELS.len() as u8 - 1;
+const DEFAULT_BRIGHTNESS_LEVEL: u8 = 2;
+
+struct Spinner {
+    position: Point,
+    frame: usize,
+    last_frame: Instant,
+}
+
+/// Whole panel inverted and back a few times, by its invert command, so the
+/// buffer is left alone
+struct InvertFlash {
+    /// Polarity changes still to come, the last one back to normal
+    toggles_left: u32,
+    period: Duration,
+    last_toggle: Instant,
+}
+
+/// Second panel showing what the display shows, e.g. to the customer across
+/// the counter. It has a buffer of its own, drawn into along with the one of
+/// the display, so it can be rotated on its own.
+struct Mirror<SIZE: DisplaySize> {
+    display: MirrorDisplay<SIZE>,
+    /// Whether it stopped responding. It is drawn into all the same, only
+    /// the flushes are skipped until `reinit_mirror` brings it back.
+    down: bool,
+}
+
 pub struct TextDrawer<'a, DI, SIZE: DisplaySize> {
     display: Ssd1306<DI, SIZE, BufferedGraphicsMode<SIZE>>,
+    mirror: Option<Mirror<SIZE>>,
     default_char_style: MonoTextStyle<'a, BinaryColor>,
     default_text_style: TextStyle,
     bounds: Rectangle,
+    layout: UiLayout,
+    spinner: Option<Spinner>,
+    flash: Option<InvertFlash>,
+    /// Whether the panel is inverted right now
+    inverted: bool,
+    error_count: u32,
+    offline: bool,
+    brightness: u8,
 }
 
 #[derive(Error, Debug)]
@@ -26,7 +86,43 @@ pub enum TextError<E: std::fmt::Debug> {
     DoesNotFit,
 }
 
+/// Interface of a missing panel, accepting and dropping everything, so a
+/// headless scale runs the same code with nothing to show it on
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullDisplay;
+
+impl WriteOnlyDataCommand for NullDisplay {
+    fn send_commands(&mut self, _cmd: DataFormat<'_>) -> Result<(), InterfaceError> {
+        Ok(())
+    }
+
+    fn send_data(&mut self, _buf: DataFormat<'_>) -> Result<(), InterfaceError> {
+        Ok(())
+    }
+}
+
+/// Interface of the mirror panel, boxed as it may sit on another bus than
+/// the display
+pub struct MirrorInterface(Box<dyn WriteOnlyDataCommand>);
+
+impl MirrorInterface {
+    pub fn new(interface: impl WriteOnlyDataCommand + 'static) -> Self {
+        Self(Box::new(interface))
+    }
+}
+
+impl WriteOnlyDataCommand for MirrorInterface {
+    fn send_commands(&mut self, cmd: DataFormat<'_>) -> Result<(), InterfaceError> {
+        self.0.send_commands(cmd)
+    }
+
+    fn send_data(&mut self, buf: DataFormat<'_>) -> Result<(), InterfaceError> {
+        self.0.send_data(buf)
+    }
+}
+
 pub type DisplayType<DI, SIZE> = Ssd1306<DI, SIZE, BufferedGraphicsMode<SIZE>>;
+pub type MirrorDisplay<SIZE> = Ssd1306<MirrorInterface, SIZE, BufferedGraphicsMode<SIZE>>;
 pub type DisplayError<DI, SIZE> = <DisplayType<DI, SIZE> as DrawTarget>::Error;
 
 impl<'a, DI, SIZE> TextDrawer<'a, DI, SIZE>
@@ -43,12 +139,21 @@ where
         let default_text_style = TextStyleBuilder::new().baseline(Baseline::Top).build();
 
         let bounds = display.bounding_box();
+        let layout = UiLayout::for_display_size(bounds.size);
 
         Self {
             display,
+            mirror: None,
             default_char_style,
             default_text_style,
             bounds,
+            layout,
+            spinner: None,
+            flash: None,
+            inverted: false,
+            error_count: 0,
+            offline: false,
+            brightness: DEFAULT_BRIGHTNESS_LEVEL,
         }
     }
 
@@ -60,16 +165,17 @@ where
 
     pub fn will_text_fit(&self, text: &str, position: Point, style: &TextStyle) -> bool {
         let text_size = self.measure_text(text, style);
-        let text_bounds = Rectangle::new(position, text_size);
+        self.will_area_fit(&Rectangle::new(position, text_size))
+    }
 
-        // Check if text starts inside display bounds
-        if !self.bounds.contains(position) {
+    pub fn will_area_fit(&self, area: &Rectangle) -> bool {
+        // Check if the area starts inside display bounds
+        if !self.bounds.contains(area.top_left) {
             return false;
         }
 
-        // Check if text ends inside display bounds
-        text_bounds
-            .bottom_right()
+        // Check if the area ends inside display bounds
+        area.bottom_right()
             .map(|p| self.bounds.contains(p))
             .unwrap_or(false)
     }
@@ -87,9 +193,7 @@ where
         text: &str,
         position: Point,
     ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
-        self.display
-            .clear(BinaryColor::Off)
-            .map_err(TextError::DrawError)?;
+        self.clear()?;
         self.draw_text_with_style(text, position, &self.default_text_style.clone())
     }
 
@@ -111,10 +215,23 @@ where
         if !self.will_text_fit(text, position, style) {
             return Err(TextError::DoesNotFit);
         }
-        Text::with_text_style(text, position, self.default_char_style, *style)
-            .draw(&mut self.display)
-            .map_err(TextError::DrawError)
-            .map(|_| ())
+        let text = Text::with_text_style(text, position, self.default_char_style, *style);
+        self.draw_mirrored(&text)
+    }
+
+    /// Draw the text in another character style than the default one, e.g.
+    /// a larger font
+    pub fn draw_text_with_char_style(
+        &mut self,
+        text: &str,
+        position: Point,
+        char_style: MonoTextStyle<'a, BinaryColor>,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        let text = Text::with_text_style(text, position, char_style, self.default_text_style);
+        if !self.will_area_fit(&text.bounding_box()) {
+            return Err(TextError::DoesNotFit);
+        }
+        self.draw_mirrored(&text)
     }
 
     pub fn draw_text_with_style_clear(
@@ -123,9 +240,7 @@ where
         position: Point,
         style: &TextStyle,
     ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
-        self.display
-            .clear(BinaryColor::Off)
-            .map_err(TextError::DrawError)?;
+        self.clear()?;
         self.draw_text_with_style(text, position, style)
     }
 
@@ -139,6 +254,193 @@ where
         self.flush()
     }
 
+    pub fn draw_line(
+        &mut self,
+        start: Point,
+        end: Point,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        self.draw_primitive(
+            Line::new(start, end),
+            PrimitiveStyle::with_stroke(BinaryColor::On, 1),
+        )
+    }
+
+    pub fn draw_rect(
+        &mut self,
+        rect: Rectangle,
+        filled: bool,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        self.draw_primitive(rect, Self::shape_style(filled))
+    }
+
+    pub fn draw_circle(
+        &mut self,
+        center: Point,
+        radius: u32,
+        filled: bool,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        self.draw_primitive(
+            Circle::with_center(center, radius * 2 + 1),
+            Self::shape_style(filled),
+        )
+    }
+
+    /// Outline the full display
+    pub fn draw_frame(&mut self) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        self.draw_rect(self.bounds, false)
+    }
+
+    fn shape_style(filled: bool) -> PrimitiveStyle<BinaryColor> {
+        if filled {
+            PrimitiveStyle::with_fill(BinaryColor::On)
+        } else {
+            PrimitiveStyle::with_stroke(BinaryColor::On, 1)
+        }
+    }
+
+    /// Draw a primitive through the display's draw target, so the buffered
+    /// mode keeps track of the modified area for the next flush
+    fn draw_primitive<P>(
+        &mut self,
+        primitive: P,
+        style: PrimitiveStyle<BinaryColor>,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+    where
+        P: Primitive + Dimensions,
+        Styled<P, PrimitiveStyle<BinaryColor>>: Drawable<Color = BinaryColor>,
+    {
+        if !self.will_area_fit(&primitive.bounding_box()) {
+            return Err(TextError::DoesNotFit);
+        }
+        self.draw_mirrored(&primitive.into_styled(style))
+    }
+
+    /// Draw into the buffer of the display and into the one of the mirror
+    fn draw_mirrored<D>(&mut self, drawable: &D) -> Result<(), TextError<DisplayError<DI, SIZE>>>
+    where
+        D: Drawable<Color = BinaryColor>,
+    {
+        drawable
+            .draw(&mut self.display)
+            .map_err(TextError::DrawError)?;
+        if let Some(mirror) = &mut self.mirror {
+            // Only the buffer is written, which does not fail
+            let _ = drawable.draw(&mut mirror.display);
+        }
+        Ok(())
+    }
+
+    /// Start a spinner animation at the given position and draw its first frame
+    pub fn start_spinner(
+        &mut self,
+        position: Point,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        self.spinner = Some(Spinner {
+            position,
+            frame: 0,
+            last_frame: Instant::now(),
+        });
+        self.draw_text(SPINNER_FRAMES[0], position)?;
+        self.flush()
+    }
+
+    /// Advance the spinner animation, redrawing only the spinner cell, and
+    /// the invert flash. Calls are rate-limited, so this can be invoked as
+    /// often as needed.
+    pub fn tick(&mut self) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        self.tick_flash();
+        let Some(spinner) = self.spinner.as_mut() else {
+            return Ok(());
+        };
+        if spinner.last_frame.elapsed() < SPINNER_FRAME_PERIOD {
+            return Ok(());
+        }
+
+        spinner.frame = (spinner.frame + 1) % SPINNER_FRAMES.len();
+        spinner.last_frame = Instant::now();
+        let (frame, position) = (SPINNER_FRAMES[spinner.frame], spinner.position);
+
+        self.draw_text(frame, position)?;
+        self.flush()
+    }
+
+    /// Stop the spinner animation and clear its cell
+    pub fn stop_spinner(&mut self) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        let Some(spinner) = self.spinner.take() else {
+            return Ok(());
+        };
+        let cell_size = self.measure_text(SPINNER_FRAMES[0], &self.default_text_style);
+        self.draw_mirrored(
+            &Rectangle::new(spinner.position, cell_size)
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off)),
+        )?;
+        self.flush()
+    }
+
+    /// Invert the whole panel `times` times, for `period` each and as long
+    /// back to normal in between, driven by `tick`. Only the invert command
+    /// of the panel changes, so rendering goes on meanwhile and the normal
+    /// polarity comes back at the end whatever was drawn. Highlighted text
+    /// is inverted in the buffer instead and simply flashes along. A flash
+    /// asked for during another one starts over.
+    pub fn flash_invert(&mut self, times: u32, period: Duration) {
+        if times == 0 {
+            return;
+        }
+        // An inverted panel already shows the first pulse
+        if !self.inverted {
+            self.set_inverted(true);
+        }
+        self.flash = Some(InvertFlash {
+            toggles_left: times * 2 - 1,
+            period,
+            last_toggle: Instant::now(),
+        });
+    }
+
+    /// Whether an invert flash is going on
+    pub fn is_flashing(&self) -> bool {
+        self.flash.is_some()
+    }
+
+    fn tick_flash(&mut self) {
+        let Some(flash) = self.flash.as_mut() else {
+            return;
+        };
+        if flash.last_toggle.elapsed() < flash.period {
+            return;
+        }
+        flash.last_toggle = Instant::now();
+        flash.toggles_left = flash.toggles_left.saturating_sub(1);
+        let inverted = flash.toggles_left > 0 && !self.inverted;
+        if flash.toggles_left == 0 {
+            self.flash = None;
+        }
+        self.set_inverted(inverted);
+    }
+
+    /// Send the invert command. A panel that does not take it is marked
+    /// offline like on a failed flush, its reinit restores the polarity.
+    fn set_inverted(&mut self, inverted: bool) {
+        if self.offline {
+            return;
+        }
+        let sent = {
+            let _quiesce = quiesce();
+            self.with_mirror(|mirror| mirror.set_invert(inverted));
+            self.display.set_invert(inverted)
+        };
+        match sent {
+            Ok(()) => self.inverted = inverted,
+            Err(err) => {
+                error!("Display invert failed, marking display offline: {:?}", err);
+                self.error_count = self.error_count.saturating_add(1);
+                self.offline = true;
+                self.flash = None;
+            }
+        }
+    }
+
     pub fn style_with_font(&self, font: &'a MonoFont<'a>) -> MonoTextStyle<'a, BinaryColor> {
         MonoTextStyleBuilder::new()
             .font(font)
@@ -147,6 +449,18 @@ where
             .build()
     }
 
+    pub fn char_style(&self) -> MonoTextStyle<'a, BinaryColor> {
+        self.default_char_style
+    }
+
+    /// Default character style with the colors swapped, to highlight text
+    pub fn inverse_char_style(&self) -> MonoTextStyle<'a, BinaryColor> {
+        MonoTextStyleBuilder::from(&self.default_char_style)
+            .text_color(BinaryColor::Off)
+            .background_color(BinaryColor::On)
+            .build()
+    }
+
     pub fn set_char_style(&mut self, style: MonoTextStyle<'a, BinaryColor>) {
         self.default_char_style = style;
     }
@@ -159,13 +473,199 @@ where
         self.bounds.size
     }
 
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    /// Set the brightness level, from 0 up to `MAX_BRIGHTNESS_LEVEL`
+    pub fn set_brightness(&mut self, level: u8) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        let level = level.min(MAX_BRIGHTNESS_LEVEL);
+        if level == self.brightness {
+            return Ok(());
+        }
+        let brightness = BRIGHTNESS_LEVELS[level as usize];
+        self.with_mirror(|mirror| mirror.set_brightness(brightness));
+        self.display
+            .set_brightness(brightness)
+            .map_err(TextError::DrawError)?;
+        self.brightness = level;
+        Ok(())
+    }
+
+    /// Turn the panel off or back on, the buffer is kept meanwhile
+    pub fn set_display_on(&mut self, on: bool) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        self.with_mirror(|mirror| mirror.set_display_on(on));
+        self.display
+            .set_display_on(on)
+            .map_err(TextError::DrawError)
+    }
+
+    pub fn layout(&self) -> &UiLayout {
+        &self.layout
+    }
+
     pub fn clear(&mut self) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        if let Some(mirror) = &mut self.mirror {
+            let _ = mirror.display.clear(BinaryColor::Off);
+        }
         self.display
             .clear(BinaryColor::Off)
             .map_err(TextError::DrawError)
     }
 
+    /// Blank an area, so only it is sent with the next flush instead of the
+    /// whole buffer
+    pub fn clear_region(
+        &mut self,
+        area: Rectangle,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        self.draw_mirrored(&area.into_styled(PrimitiveStyle::with_fill(BinaryColor::Off)))
+    }
+
+    /// Height of a line of text in the default font
+    pub fn line_height(&self) -> u32 {
+        self.default_char_style.font.character_size.height
+    }
+
+    /// Flush the buffer to the display, retrying transient bus errors.
+    /// If the retries are exhausted the display is marked offline and further
+    /// flushes are skipped until `reinit` succeeds, so a flaky panel never
+    /// stops the rest of the firmware. The mirror is flushed after it, on
+    /// its own: either panel keeps working without the other.
     pub fn flush(&mut self) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
-        self.display.flush().map_err(TextError::DrawError)
+        if !self.offline {
+            if let Err(err) = self.flush_with_retry(FLUSH_ATTEMPTS, FLUSH_BACKOFF) {
+                error!("Display flush failed, marking display offline: {:?}", err);
+                self.offline = true;
+            }
+        }
+        self.with_mirror(|mirror| {
+            let _quiesce = quiesce();
+            mirror.flush()
+        });
+        Ok(())
+    }
+
+    /// Flush the buffer to the display, making up to `attempts` tries with a
+    /// linearly increasing delay between them
+    pub fn flush_with_retry(
+        &mut self,
+        attempts: u32,
+        backoff: Duration,
+    ) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        let mut attempt = 1;
+        loop {
+            // The readings converted meanwhile may be off by a few counts
+            let flushed = {
+                let _quiesce = quiesce();
+                self.display.flush()
+            };
+            match flushed {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    self.error_count = self.error_count.saturating_add(1);
+                    if attempt >= attempts {
+                        return Err(TextError::DrawError(err));
+                    }
+                    warn!("Display flush attempt {} failed: {:?}", attempt, err);
+                    std::thread::sleep(backoff * attempt);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Re-run the display init sequence, e.g. after a brown-out reset the panel.
+    /// On success the display is back online and the next full redraw restores
+    /// its contents.
+    pub fn reinit(&mut self) -> Result<(), TextError<DisplayError<DI, SIZE>>> {
+        let brightness = BRIGHTNESS_LEVELS[self.brightness as usize];
+        match self
+            .display
+            .init()
+            .and_then(|_| self.display.set_brightness(brightness))
+        {
+            Ok(()) => {
+                // The init sequence leaves the panel in normal polarity
+                self.offline = false;
+                self.inverted = false;
+                self.flash = None;
+                Ok(())
+            }
+            Err(err) => {
+                self.error_count = self.error_count.saturating_add(1);
+                self.offline = true;
+                Err(TextError::DrawError(err))
+            }
+        }
+    }
+
+    /// Whether the display stopped responding and needs a `reinit`
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Mirror everything drawn from now on to a second panel, which has to
+    /// be turned like the display or upside down. The panel is set up
+    /// right away; one that does not respond is marked down and retried by
+    /// `reinit_mirror`.
+    pub fn set_mirror(&mut self, display: MirrorDisplay<SIZE>) {
+        if display.bounding_box().size != self.bounds.size {
+            warn!("The mirror is turned across the display, leaving it out");
+            return;
+        }
+        self.mirror = Some(Mirror {
+            display,
+            down: true,
+        });
+        if let Err(err) = self.reinit_mirror() {
+            warn!("Failed to initialize the mirror: {:?}", err);
+        }
+    }
+
+    /// Run the init sequence of the mirror, e.g. once it was plugged back
+    /// in. It is shown again from the next full redraw.
+    pub fn reinit_mirror(&mut self) -> Result<(), InterfaceError> {
+        let brightness = BRIGHTNESS_LEVELS[self.brightness as usize];
+        let inverted = self.inverted;
+        let Some(mirror) = &mut self.mirror else {
+            return Ok(());
+        };
+        let result = mirror
+            .display
+            .init()
+            .and_then(|_| mirror.display.set_brightness(brightness))
+            .and_then(|_| mirror.display.set_invert(inverted));
+        mirror.down = result.is_err();
+        if result.is_err() {
+            self.error_count = self.error_count.saturating_add(1);
+        }
+        result
+    }
+
+    /// Whether a mirror is set up but stopped responding
+    pub fn is_mirror_down(&self) -> bool {
+        self.mirror.as_ref().is_some_and(|mirror| mirror.down)
+    }
+
+    /// Send a command to the mirror unless it is down, marking it down when
+    /// it fails. The display goes on either way.
+    fn with_mirror(
+        &mut self,
+        command: impl FnOnce(&mut MirrorDisplay<SIZE>) -> Result<(), InterfaceError>,
+    ) {
+        let Some(mirror) = self.mirror.as_mut().filter(|mirror| !mirror.down) else {
+            return;
+        };
+        if let Err(err) = command(&mut mirror.display) {
+            error!("Mirror display failed, marking it down: {:?}", err);
+            mirror.down = true;
+            self.error_count = self.error_count.saturating_add(1);
+        }
+    }
+
+    /// Number of display bus errors since startup
+    pub fn error_count(&self) -> u32 {
+        self.error_count
     }
 }
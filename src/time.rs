@@ -0,0 +1,184 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "wifi")]
+use {
+    crate::wifi::{WifiHandle, WifiState},
+    esp_idf_svc::sntp::{EspSntp, SntpConf},
+    log::{info, warn},
+};
+
+#[cfg(feature = "wifi")]
+const TIME_TASK_STACK_SIZE: usize = 3 * 1024;
+#[cfg(feature = "wifi")]
+const WIFI_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+static BOOT_TIME: OnceLock<Instant> = OnceLock::new();
+static SYNCED: AtomicBool = AtomicBool::new(false);
+
+/// Time since the first call, which `main` makes right at boot
+pub fn uptime() -> Duration {
+    BOOT_TIME.get_or_init(Instant::now).elapsed()
+}
+
+/// Whether the wall clock has been set through SNTP
+pub fn is_synced() -> bool {
+    SYNCED.load(Ordering::Relaxed)
+}
+
+/// Current UTC time, only meaningful once synchronized
+pub fn now_utc() -> SystemTime {
+    SystemTime::now()
+}
+
+/// Point in time attached to readings: the wall clock once synchronized,
+/// the uptime before that. The two print in different formats, so
+/// consumers can always tell them apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timestamp {
+    /// Printed as milliseconds since boot
+    Uptime(Duration),
+    /// Printed as ISO-8601, `Z` suffixed when the offset is zero
+    WallClock {
+        since_epoch: Duration,
+        offset_minutes: i16,
+    },
+}
+
+impl Timestamp {
+    /// UTC time if synchronized, uptime otherwise
+    pub fn now() -> Self {
+        Self::now_with_offset(0)
+    }
+
+    /// Local time if synchronized, uptime otherwise
+    pub fn now_with_offset(offset_minutes: i16) -> Self {
+        if !is_synced() {
+            return Timestamp::Uptime(uptime());
+        }
+        match now_utc().duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => Timestamp::WallClock {
+                since_epoch,
+                offset_minutes,
+            },
+            Err(_) => Timestamp::Uptime(uptime()),
+        }
+    }
+
+    pub fn is_wall_clock(&self) -> bool {
+        matches!(self, Timestamp::WallClock { .. })
+    }
+
+    /// Seconds since the epoch in local time
+    pub fn local_secs(&self) -> Option<i64> {
+        let Timestamp::WallClock {
+            since_epoch,
+            offset_minutes,
+        } = *self
+        else {
+            return None;
+        };
+        Some(since_epoch.as_secs() as i64 + i64::from(offset_minutes) * 60)
+    }
+
+    /// Year, month and day of the local date
+    pub fn date(&self) -> Option<(i64, u32, u32)> {
+        let local_secs = self.local_secs()?;
+        Some(civil_from_days(local_secs.div_euclid(SECS_PER_DAY as i64)))
+    }
+
+    /// Hours and minutes of the local time, for display
+    pub fn hours_minutes(&self) -> Option<(u8, u8)> {
+        let local_secs = self.local_secs()?;
+        let secs_of_day = local_secs.rem_euclid(SECS_PER_DAY as i64) as u64;
+        Some(((secs_of_day / 3600) as u8, (secs_of_day / 60 % 60) as u8))
+    }
+}
+
+/// Civil date from days since 1970-01-01, Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Timestamp::Uptime(uptime) => write!(f, "{}", uptime.as_millis()),
+            Timestamp::WallClock {
+                since_epoch,
+                offset_minutes,
+            } => {
+                let local_secs = since_epoch.as_secs() as i64 + i64::from(offset_minutes) * 60;
+                let days = local_secs.div_euclid(SECS_PER_DAY as i64);
+                let secs_of_day = local_secs.rem_euclid(SECS_PER_DAY as i64);
+                let (year, month, day) = civil_from_days(days);
+                write!(
+                    f,
+                    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+                    year,
+                    month,
+                    day,
+                    secs_of_day / 3600,
+                    secs_of_day / 60 % 60,
+                    secs_of_day % 60,
+                    since_epoch.subsec_millis()
+                )?;
+                if offset_minutes == 0 {
+                    write!(f, "Z")
+                } else {
+                    let sign = if offset_minutes < 0 { '-' } else { '+' };
+                    let offset = offset_minutes.unsigned_abs();
+                    write!(f, "{}{:02}:{:02}", sign, offset / 60, offset % 60)
+                }
+            }
+        }
+    }
+}
+
+/// Start synchronizing the wall clock over SNTP once Wi-Fi is connected
+#[cfg(feature = "wifi")]
+pub fn start_time_task(wifi: WifiHandle) -> anyhow::Result<()> {
+    std::thread::Builder::new()
+        .name("time".to_string())
+        .stack_size(TIME_TASK_STACK_SIZE)
+        .spawn(move || {
+            while wifi.state() != WifiState::Connected {
+                std::thread::sleep(WIFI_POLL_PERIOD);
+            }
+
+            // SNTP keeps resynchronizing in the background as long as it lives
+            let _sntp = match EspSntp::new_with_callback(&SntpConf::default(), |_| {
+                if !SYNCED.swap(true, Ordering::Relaxed) {
+                    info!("Time synchronized");
+                }
+            }) {
+                Ok(sntp) => sntp,
+                Err(err) => {
+                    warn!("Failed to start SNTP: {:?}", err);
+                    return;
+                }
+            };
+            loop {
+                std::thread::park();
+            }
+        })?;
+    Ok(())
+}
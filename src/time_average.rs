@@ -0,0 +1,306 @@
+//! Time-weighted average of the weight, for billing by the load a platform
+//! carried over a window. The sample rate changes with the sensor rate and
+//! the low power mode, so the readings are integrated over time with the
+//! trapezoidal rule instead of averaged.
+//!
+//! The integral is kept in buckets of a minute, each along with the time
+//! it covers, so a window of hours takes a few kilobytes. A reading coming
+//! later than the stale time after the one before it ends a gap, e.g. the
+//! sensor recovering, which is neither integrated nor covered: the average
+//! is over the time covered, and the coverage tells how much of the window
+//! that is. The bucket the window starts in is counted pro rata.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Span of a bucket of the integral
+const BUCKET_SPAN: Duration = Duration::from_secs(60);
+/// Longest window averaged over
+pub const MAX_AVERAGE_WINDOW: Duration = Duration::from_secs(12 * 3600);
+/// Window averaged over when none is given
+pub const DEFAULT_AVERAGE_WINDOW: Duration = Duration::from_secs(3600);
+const MAX_BUCKETS: usize = (MAX_AVERAGE_WINDOW.as_secs() / BUCKET_SPAN.as_secs()) as usize + 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Bucket {
+    /// Integral of the weight over the time covered, in gram seconds
+    gram_secs: f32,
+    covered_secs: f32,
+}
+
+/// Average over a window along with how much of it the readings cover
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeAverage {
+    /// None without any time covered
+    pub grams: Option<f32>,
+    pub window: Duration,
+    pub covered: Duration,
+}
+
+impl TimeAverage {
+    /// Share of the window covered by the readings
+    pub fn coverage_percent(&self) -> f32 {
+        if self.window.is_zero() {
+            return 0.0;
+        }
+        (self.covered.as_secs_f32() / self.window.as_secs_f32() * 100.0).min(100.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct TimeWeighted {
+    /// Start of the bucket at index 0, the time of the first reading
+    origin: Option<Instant>,
+    /// Index of the front bucket
+    first_index: u64,
+    buckets: VecDeque<Bucket>,
+    /// Last reading, the next segment starts at
+    last: Option<(Instant, f32)>,
+    /// Longest time between two readings still integrated
+    max_gap: Duration,
+}
+
+impl TimeWeighted {
+    pub fn new(max_gap: Duration) -> Self {
+        Self {
+            origin: None,
+            first_index: 0,
+            buckets: VecDeque::new(),
+            last: None,
+            max_gap,
+        }
+    }
+
+    pub fn set_max_gap(&mut self, max_gap: Duration) {
+        self.max_gap = max_gap;
+    }
+
+    /// Integrate the segment from the last reading up to this one, unless
+    /// it spans a gap
+    pub fn on_reading(&mut self, grams: f32, now: Instant) {
+        let origin = *self.origin.get_or_insert(now);
+        if let Some((at, last_grams)) = self.last {
+            if now > at && now.duration_since(at) <= self.max_gap {
+                self.integrate(
+                    at.duration_since(origin),
+                    last_grams,
+                    now.duration_since(origin),
+                    grams,
+                );
+            }
+        }
+        self.last = Some((now, grams));
+    }
+
+    /// Add the trapezoid from `start` to `end` since the origin, split at
+    /// the bucket boundaries with the weight in between interpolated
+    fn integrate(&mut self, start: Duration, start_grams: f32, end: Duration, end_grams: f32) {
+        let span = (end - start).as_secs_f32();
+        let grams_at = |at: Duration| {
+            start_grams + (end_grams - start_grams) * (at - start).as_secs_f32() / span
+        };
+        let mut from = start;
+        while from < end {
+            let index = from.as_secs() / BUCKET_SPAN.as_secs();
+            let bucket_end = BUCKET_SPAN * (index + 1) as u32;
+            let to = end.min(bucket_end);
+            let secs = (to - from).as_secs_f32();
+            if let Some(bucket) = self.bucket_mut(index) {
+                bucket.gram_secs += (grams_at(from) + grams_at(to)) / 2.0 * secs;
+                bucket.covered_secs += secs;
+            }
+            from = to;
+        }
+    }
+
+    /// Bucket at the index, the ones up to it added and the oldest ones
+    /// dropped. None for one already dropped.
+    fn bucket_mut(&mut self, index: u64) -> Option<&mut Bucket> {
+        if index < self.first_index {
+            return None;
+        }
+        // Past a gap longer than the buckets kept none of them is left
+        if index >= self.first_index + (self.buckets.len() + MAX_BUCKETS) as u64 {
+            self.buckets.clear();
+            self.first_index = index;
+        }
+        while self.first_index + self.buckets.len() as u64 <= index {
+            self.buckets.push_back(Bucket::default());
+            if self.buckets.len() > MAX_BUCKETS {
+                self.buckets.pop_front();
+                self.first_index += 1;
+            }
+        }
+        self.buckets.get_mut((index - self.first_index) as usize)
+    }
+
+    /// Average over the window ending now, at most `MAX_AVERAGE_WINDOW`
+    pub fn average(&self, window: Duration, now: Instant) -> TimeAverage {
+        let window = window.min(MAX_AVERAGE_WINDOW);
+        let mut average = TimeAverage {
+            grams: None,
+            window,
+            covered: Duration::ZERO,
+        };
+        let Some(origin) = self.origin else {
+            return average;
+        };
+        let window_start = now.saturating_duration_since(origin).saturating_sub(window);
+        let (mut gram_secs, mut covered_secs) = (0.0, 0.0);
+        for (index, bucket) in (self.first_index..).zip(&self.buckets) {
+            let bucket_start = BUCKET_SPAN * index as u32;
+            let bucket_end = bucket_start + BUCKET_SPAN;
+            if bucket_end <= window_start {
+                continue;
+            }
+            let share = if bucket_start < window_start {
+                (bucket_end - window_start).as_secs_f32() / BUCKET_SPAN.as_secs_f32()
+            } else {
+                1.0
+            };
+            gram_secs += bucket.gram_secs * share;
+            covered_secs += bucket.covered_secs * share;
+        }
+        average.covered = Duration::from_secs_f32(covered_secs).min(window);
+        average.grams = (covered_secs > 0.0).then(|| gram_secs / covered_secs);
+        average
+    }
+}
+
+/// The integral shared between the scale feeding it and the tasks reading
+/// the average, the HTTP API
+#[derive(Clone, Debug)]
+pub struct SharedTimeWeighted(Arc<Mutex<TimeWeighted>>);
+
+impl SharedTimeWeighted {
+    pub fn new(max_gap: Duration) -> Self {
+        Self(Arc::new(Mutex::new(TimeWeighted::new(max_gap))))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, TimeWeighted> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Average over the window ending now
+    pub fn average(&self, window: Duration) -> TimeAverage {
+        self.lock().average(window, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_GAP: Duration = Duration::from_secs(5);
+
+    fn secs(secs: f32) -> Duration {
+        Duration::from_secs_f32(secs)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3 * b.abs().max(1.0)
+    }
+
+    /// Readings at irregular times, none further apart than `MAX_GAP`
+    fn irregular_times(until: f32) -> Vec<f32> {
+        let steps = [0.1, 0.7, 1.3, 0.25, 2.9, 0.05, 4.4];
+        let mut times = vec![0.0];
+        let mut at = 0.0;
+        for step in steps.iter().cycle() {
+            at += step;
+            if at >= until {
+                break;
+            }
+            times.push(at);
+        }
+        times.push(until);
+        times
+    }
+
+    #[test]
+    fn constant_weight() {
+        let start = Instant::now();
+        let mut average = TimeWeighted::new(MAX_GAP);
+        for at in irregular_times(600.0) {
+            average.on_reading(250.0, start + secs(at));
+        }
+        let result = average.average(secs(600.0), start + secs(600.0));
+        assert!(close(result.grams.unwrap(), 250.0));
+        assert!(close(result.covered.as_secs_f32(), 600.0));
+        assert!(close(result.coverage_percent(), 100.0));
+    }
+
+    #[test]
+    fn linear_ramp_is_exact() {
+        // 0g to 600g over 600s, averaging 300g whatever the spacing
+        let start = Instant::now();
+        let mut average = TimeWeighted::new(MAX_GAP);
+        for at in irregular_times(600.0) {
+            average.on_reading(at, start + secs(at));
+        }
+        let result = average.average(secs(600.0), start + secs(600.0));
+        assert!(close(result.grams.unwrap(), 300.0));
+        // The last 300s, from 300g to 600g
+        let result = average.average(secs(300.0), start + secs(600.0));
+        assert!(close(result.grams.unwrap(), 450.0));
+    }
+
+    #[test]
+    fn gap_is_not_covered() {
+        let start = Instant::now();
+        let mut average = TimeWeighted::new(MAX_GAP);
+        for at in irregular_times(100.0) {
+            average.on_reading(100.0, start + secs(at));
+        }
+        // Silent for 20s, then another weight
+        for at in irregular_times(60.0) {
+            average.on_reading(400.0, start + secs(120.0 + at));
+        }
+        let result = average.average(secs(180.0), start + secs(180.0));
+        assert!(close(result.covered.as_secs_f32(), 160.0));
+        let expected = (100.0 * 100.0 + 400.0 * 60.0) / 160.0;
+        assert!(close(result.grams.unwrap(), expected));
+        assert!(close(result.coverage_percent(), 160.0 / 180.0 * 100.0));
+    }
+
+    #[test]
+    fn window_start_counted_pro_rata() {
+        // 100g in the first minute, 200g in the second
+        let start = Instant::now();
+        let mut average = TimeWeighted::new(MAX_GAP);
+        for at in (0..=60).map(|at| at as f32) {
+            average.on_reading(100.0, start + secs(at));
+        }
+        for at in (60..=120).map(|at| at as f32) {
+            average.on_reading(200.0, start + secs(at + 0.001));
+        }
+        // The window starts halfway into the first minute
+        let result = average.average(secs(90.0), start + secs(120.0));
+        assert!(close(result.covered.as_secs_f32(), 90.0));
+        let expected = (100.0 * 30.0 + 200.0 * 60.0) / 90.0;
+        assert!((result.grams.unwrap() - expected).abs() < 0.1);
+    }
+
+    #[test]
+    fn long_gap_clears_the_buckets() {
+        let start = Instant::now();
+        let mut average = TimeWeighted::new(MAX_GAP);
+        for at in 0..=10 {
+            average.on_reading(100.0, start + Duration::from_secs(at));
+        }
+        // Back after longer than all the buckets span
+        let back = BUCKET_SPAN * (MAX_BUCKETS as u32 + 10);
+        for at in 0..=10 {
+            average.on_reading(300.0, start + back + Duration::from_secs(at));
+        }
+        assert!(average.buckets.len() <= 2);
+        let result = average.average(MAX_AVERAGE_WINDOW, start + back + Duration::from_secs(10));
+        assert!(close(result.grams.unwrap(), 300.0));
+        assert!(close(result.covered.as_secs_f32(), 10.0));
+    }
+}
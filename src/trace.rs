@@ -0,0 +1,147 @@
+//! Traces of the readings, to tune the filter on the same input every time:
+//! `trace record` keeps the readings of a few seconds, with their time, and
+//! `trace replay` weighs a trace again through the pipeline of the scale,
+//! as fast as it goes, with the filter of the scale or another one.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    events::WeightEvent,
+    feedback::{Feedback, WeightWatch},
+    pipeline::{Pipeline, Weighed},
+};
+
+/// Longest trace recorded
+pub const MAX_TRACE_SECS: u32 = 60;
+/// Points kept at most, 30 seconds of the fastest sensor rate
+pub const MAX_TRACE_POINTS: usize = 2400;
+/// Largest filter window a trace is replayed with
+pub const MAX_REPLAY_WINDOW: usize = 64;
+
+pub const TRACE_CSV_HEADER: &str = "ms,raw,grams";
+pub const REPLAY_CSV_HEADER: &str = "ms,grams,filtered,gross,stable,feedback";
+
+/// A reading, with the grams it weighed above the zero of the time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TracePoint {
+    /// Since the start of the trace
+    pub ms: u32,
+    pub raw: i32,
+    pub grams: f32,
+}
+
+impl TracePoint {
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{:.3}", self.ms, self.raw, self.grams)
+    }
+
+    /// Parse a line of `to_csv`, none for the header or anything else
+    pub fn from_csv(line: &str) -> Option<Self> {
+        let mut fields = line.trim().split(',');
+        let point = TracePoint {
+            ms: fields.next()?.trim().parse().ok()?,
+            raw: fields.next()?.trim().parse().ok()?,
+            grams: fields.next()?.trim().parse().ok()?,
+        };
+        (fields.next().is_none() && point.grams.is_finite()).then_some(point)
+    }
+}
+
+/// Records the readings for a while
+#[derive(Debug)]
+pub struct TraceRecorder {
+    started: Instant,
+    duration: Duration,
+    points: Vec<TracePoint>,
+}
+
+impl TraceRecorder {
+    pub fn new(duration: Duration, now: Instant) -> Self {
+        Self {
+            started: now,
+            duration,
+            points: Vec::new(),
+        }
+    }
+
+    /// Keep the reading. Returns whether the trace is over, by its time or
+    /// once full.
+    pub fn on_reading(&mut self, raw: i32, grams: f32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed >= self.duration {
+            return true;
+        }
+        self.points.push(TracePoint {
+            ms: elapsed.as_millis() as u32,
+            raw,
+            grams,
+        });
+        self.points.len() >= MAX_TRACE_POINTS
+    }
+
+    pub fn into_points(self) -> Vec<TracePoint> {
+        self.points
+    }
+}
+
+/// A point of the trace weighed again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayRow {
+    pub point: TracePoint,
+    pub weighed: Weighed,
+    /// What the buzzer and the LED would have signalled
+    pub feedback: Option<Feedback>,
+}
+
+impl ReplayRow {
+    pub fn to_csv(&self) -> String {
+        let feedback = self
+            .feedback
+            .map(|feedback| format!("{:?}", feedback))
+            .unwrap_or_default();
+        format!(
+            "{},{:.3},{:.3},{:.3},{},{}",
+            self.point.ms,
+            self.point.grams,
+            self.weighed.filtered,
+            self.weighed.gross,
+            u8::from(self.weighed.stable),
+            feedback
+        )
+    }
+}
+
+/// Weigh the trace through `pipeline`, its time going by as fast as the
+/// points are weighed. The target and the capacity are watched the way the
+/// feedback task does.
+pub fn replay<'p>(
+    points: &'p [TracePoint],
+    pipeline: &'p mut Pipeline,
+    resolution: f32,
+    target: Option<f32>,
+    capacity: Option<f32>,
+) -> impl Iterator<Item = ReplayRow> + 'p {
+    let start = Instant::now();
+    let readings = points
+        .iter()
+        .map(move |point| (start + Duration::from_millis(point.ms.into()), point.grams));
+    let mut watch = WeightWatch::new(capacity);
+    let mut was_stable = false;
+    pipeline
+        .run(readings, resolution)
+        .zip(points)
+        .map(move |(weighed, &point)| {
+            let grams = weighed.gross;
+            let stable = weighed.stable;
+            let changed = watch.on_event(WeightEvent::Changed { grams, stable }, target);
+            let settled = (stable && !was_stable)
+                .then(|| watch.on_event(WeightEvent::Stable { grams }, target))
+                .flatten();
+            was_stable = stable;
+            ReplayRow {
+                point,
+                weighed,
+                feedback: changed.or(settled),
+            }
+        })
+}
@@ -0,0 +1,52 @@
+const GRAMS_PER_OUNCE: f32 = 28.349_523;
+const GRAMS_PER_POUND: f32 = 453.592_37;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Unit {
+    #[default]
+    Grams,
+    Kilograms,
+    Ounces,
+    Pounds,
+}
+
+impl Unit {
+    pub const ALL: [Unit; 4] = [Unit::Grams, Unit::Kilograms, Unit::Ounces, Unit::Pounds];
+
+    /// Position of the unit in `Unit::ALL`, used for persisting it
+    pub fn index(self) -> u8 {
+        Unit::ALL.iter().position(|&unit| unit == self).unwrap_or(0) as u8
+    }
+
+    pub fn from_index(index: u8) -> Option<Unit> {
+        Unit::ALL.get(index as usize).copied()
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Unit::Grams => "g",
+            Unit::Kilograms => "kg",
+            Unit::Ounces => "oz",
+            Unit::Pounds => "lb",
+        }
+    }
+
+    /// Convert a weight in grams to this unit
+    pub fn from_grams(self, grams: f32) -> f32 {
+        match self {
+            Unit::Grams => grams,
+            Unit::Kilograms => grams / 1000.0,
+            Unit::Ounces => grams / GRAMS_PER_OUNCE,
+            Unit::Pounds => grams / GRAMS_PER_POUND,
+        }
+    }
+
+    /// Number of decimals a weight in this unit is reported with
+    pub fn decimals(self) -> usize {
+        match self {
+            Unit::Grams => 1,
+            Unit::Kilograms => 3,
+            Unit::Ounces | Unit::Pounds => 2,
+        }
+    }
+}
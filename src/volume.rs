@@ -0,0 +1,91 @@
+//! Volume estimated from the weight for kitchen use, through the density of
+//! what is on the scale. Only the display converts, the weight stays in
+//! grams everywhere else.
+
+/// Bounds of a custom density in g/ml
+pub const MIN_DENSITY: f32 = 0.1;
+pub const MAX_DENSITY: f32 = 5.0;
+
+/// What is on the scale
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Substance {
+    #[default]
+    Water,
+    Milk,
+    Oil,
+    Flour,
+    /// Density entered by the user
+    Custom,
+}
+
+impl Substance {
+    pub const ALL: [Substance; 5] = [
+        Substance::Water,
+        Substance::Milk,
+        Substance::Oil,
+        Substance::Flour,
+        Substance::Custom,
+    ];
+    /// Names for the menu, in the order of `ALL`
+    pub const LABELS: [&'static str; 5] = ["Water", "Milk", "Oil", "Flour", "Custom"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Substance::Water => "water",
+            Substance::Milk => "milk",
+            Substance::Oil => "oil",
+            Substance::Flour => "flour",
+            Substance::Custom => "custom",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|substance| substance.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+
+    /// Density in g/ml, none for a custom one
+    pub fn density(self) -> Option<f32> {
+        match self {
+            Substance::Water => Some(1.0),
+            Substance::Milk => Some(1.03),
+            Substance::Oil => Some(0.92),
+            Substance::Flour => Some(0.59),
+            Substance::Custom => None,
+        }
+    }
+}
+
+/// Substance the weight is shown as a volume of
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Volume {
+    pub substance: Substance,
+    /// Density in g/ml
+    pub density: f32,
+}
+
+impl Volume {
+    /// Volume of `substance`, with `custom_density` for a custom one
+    pub fn new(substance: Substance, custom_density: f32) -> Self {
+        Self {
+            substance,
+            density: substance
+                .density()
+                .unwrap_or_else(|| custom_density.clamp(MIN_DENSITY, MAX_DENSITY)),
+        }
+    }
+
+    /// Milliliters a weight in grams takes up
+    pub fn ml(&self, grams: f32) -> f32 {
+        grams / self.density
+    }
+}
@@ -0,0 +1,97 @@
+//! Task watchdog: a task holding a `WatchdogGuard` has to feed it within the
+//! timeout, otherwise the watchdog panics and the scale restarts with the
+//! backtrace of the wedged task. Nothing watches the tasks on the host.
+
+#[cfg(feature = "esp")]
+use std::ptr;
+use std::{marker::PhantomData, time::Duration};
+
+#[cfg(feature = "esp")]
+use esp_idf_sys::{
+    esp, esp_err_t, esp_task_wdt_add, esp_task_wdt_config_t, esp_task_wdt_delete,
+    esp_task_wdt_init, esp_task_wdt_reconfigure, esp_task_wdt_reset, EspError,
+    ESP_ERR_INVALID_STATE,
+};
+#[cfg(feature = "esp")]
+use log::warn;
+
+/// Longest time a watched task may go without feeding the watchdog. Taring
+/// and calibrating block the main task for about 2 seconds.
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Idle task of the first core, watched by esp-idf by default
+#[cfg(feature = "esp")]
+const IDLE_CORE_MASK: u32 = 1;
+
+/// Set the timeout and have the watchdog panic when it expires
+#[cfg(feature = "esp")]
+pub fn configure(timeout: Duration) -> Result<(), EspError> {
+    let config = esp_task_wdt_config_t {
+        timeout_ms: timeout.as_millis() as u32,
+        idle_core_mask: IDLE_CORE_MASK,
+        trigger_panic: true,
+    };
+    // esp-idf starts the watchdog at boot, unless disabled in the sdkconfig
+    match esp!(unsafe { esp_task_wdt_reconfigure(&config) }) {
+        Err(err) if err.code() == ESP_ERR_INVALID_STATE as esp_err_t => {
+            esp!(unsafe { esp_task_wdt_init(&config) })
+        }
+        result => result,
+    }
+}
+
+/// Subscription of the current task to the watchdog, removed on drop. The
+/// guard stays on the task it was created on.
+pub struct WatchdogGuard {
+    #[cfg(feature = "esp")]
+    name: &'static str,
+    #[cfg(feature = "esp")]
+    subscribed: bool,
+    _task: PhantomData<*const ()>,
+}
+
+impl WatchdogGuard {
+    /// Watch the current task. A failure is only logged, the task then runs
+    /// unwatched.
+    #[cfg(feature = "esp")]
+    pub fn subscribe(name: &'static str) -> Self {
+        let subscribed = match esp!(unsafe { esp_task_wdt_add(ptr::null_mut()) }) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("Failed to watch the {} task: {}", name, err);
+                false
+            }
+        };
+        Self {
+            name,
+            subscribed,
+            _task: PhantomData,
+        }
+    }
+
+    /// The task runs unwatched
+    #[cfg(not(feature = "esp"))]
+    pub fn subscribe(_name: &'static str) -> Self {
+        Self { _task: PhantomData }
+    }
+
+    /// Tell the watchdog the task is still alive
+    pub fn feed(&self) {
+        #[cfg(feature = "esp")]
+        if self.subscribed {
+            unsafe { esp_task_wdt_reset() };
+        }
+    }
+}
+
+#[cfg(feature = "esp")]
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        if !self.subscribed {
+            return;
+        }
+        if let Err(err) = esp!(unsafe { esp_task_wdt_delete(ptr::null_mut()) }) {
+            warn!("Failed to stop watching the {} task: {}", self.name, err);
+        }
+    }
+}
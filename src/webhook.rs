@@ -0,0 +1,340 @@
+//! Webhook fired when something lands on the empty scale, e.g. a parcel on
+//! a porch scale: a stable weight above the threshold after the scale stayed
+//! empty for a while is posted as JSON to the configured URL. The arrivals
+//! wait in a small outbox kept in NVS until they are delivered, so those
+//! made while Wi-Fi is down, or before a restart, still go out later.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "webhook")]
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+
+#[cfg(feature = "webhook")]
+use log::{info, warn};
+#[cfg(feature = "webhook")]
+use serde_json::json;
+
+use crate::storage::{Storage, StoreError};
+#[cfg(feature = "webhook")]
+use crate::{
+    device, http_client,
+    settings::Settings,
+    storage::StorageService,
+    wifi::{WifiHandle, WifiState},
+};
+use crate::{events::WeightEvent, time::Timestamp};
+
+/// Arrivals kept until delivered, the oldest dropped past them
+pub const OUTBOX_LEN: usize = 16;
+/// Stable weight within which the scale counts as empty
+const EMPTY_GRAMS: f32 = 2.0;
+
+/// Name of the sink passing the weight changes on to the task
+#[cfg(feature = "webhook")]
+pub const WEBHOOK_SINK: &str = "webhook";
+pub const WEBHOOK_NAMESPACE: &str = "webhook";
+const OUTBOX_KEY: &str = "outbox";
+#[cfg(feature = "webhook")]
+const WEBHOOK_TASK_STACK_SIZE: usize = 8 * 1024;
+/// Period the task checks the connection at while no events arrive
+#[cfg(feature = "webhook")]
+const EVENT_POLL_PERIOD: Duration = Duration::from_millis(500);
+#[cfg(feature = "webhook")]
+const RETRY_BACKOFF_MIN: Duration = Duration::from_secs(5);
+#[cfg(feature = "webhook")]
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Spots the arrivals. Only the plateau the weight first settles at above
+/// the threshold fires, the scale then has to stay empty for the whole time
+/// again before anything else does, so a parcel that settles twice, or
+/// another one put on top of it, fires once.
+#[derive(Debug)]
+pub struct ArrivalDetector {
+    threshold_grams: f32,
+    empty_time: Duration,
+    /// Since when the scale is empty and still
+    empty_since: Option<Instant>,
+    /// Whether the scale was empty for long enough since the last arrival
+    armed: bool,
+}
+
+impl ArrivalDetector {
+    /// Not armed until the scale was seen empty, so a load already on the
+    /// scale at startup does not fire
+    pub fn new(threshold_grams: f32, empty_time: Duration) -> Self {
+        Self {
+            threshold_grams,
+            empty_time,
+            empty_since: None,
+            armed: false,
+        }
+    }
+
+    /// Returns the weight of an arrival
+    pub fn on_event(&mut self, event: WeightEvent, now: Instant) -> Option<f32> {
+        let (grams, stable) = match event {
+            WeightEvent::Changed { grams, stable } => (grams, stable),
+            WeightEvent::Stable { grams } => (grams, true),
+            // Zeroed with whatever is on the scale, which is not known to be
+            // empty then
+            WeightEvent::Tared | WeightEvent::Calibrated { .. } => {
+                self.empty_since = None;
+                self.armed = false;
+                return None;
+            }
+            WeightEvent::UnitChanged(_) => return None,
+        };
+        if grams.abs() <= EMPTY_GRAMS {
+            if stable && self.empty_since.is_none() {
+                self.empty_since = Some(now);
+            }
+            return None;
+        }
+        // Nothing comes in while the scale sits empty, so its time is told
+        // once the weight leaves it
+        if let Some(since) = self.empty_since.take() {
+            self.armed |= now.saturating_duration_since(since) >= self.empty_time;
+        }
+        if stable && self.armed && grams >= self.threshold_grams {
+            self.armed = false;
+            return Some(grams);
+        }
+        None
+    }
+}
+
+/// Something that landed on the scale, waiting to be posted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Arrival {
+    pub grams: f32,
+    pub timestamp: Timestamp,
+    /// Boot it landed in, its uptime only tells the time within it
+    pub boot: u32,
+}
+
+impl Arrival {
+    const ENCODED_LEN: usize = 17;
+
+    fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let (kind, ms) = match self.timestamp {
+            Timestamp::Uptime(uptime) => (0, uptime.as_millis() as u64),
+            Timestamp::WallClock { since_epoch, .. } => (1, since_epoch.as_millis() as u64),
+        };
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[0..4].copy_from_slice(&self.grams.to_le_bytes());
+        bytes[4] = kind;
+        bytes[5..13].copy_from_slice(&ms.to_le_bytes());
+        bytes[13..17].copy_from_slice(&self.boot.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; Self::ENCODED_LEN] = bytes.try_into().ok()?;
+        let grams = f32::from_le_bytes(bytes[0..4].try_into().ok()?);
+        let time = Duration::from_millis(u64::from_le_bytes(bytes[5..13].try_into().ok()?));
+        let timestamp = match bytes[4] {
+            0 => Timestamp::Uptime(time),
+            1 => Timestamp::WallClock {
+                since_epoch: time,
+                offset_minutes: 0,
+            },
+            _ => return None,
+        };
+        let boot = u32::from_le_bytes(bytes[13..17].try_into().ok()?);
+        grams.is_finite().then_some(Self {
+            grams,
+            timestamp,
+            boot,
+        })
+    }
+}
+
+/// Arrivals waiting to be delivered, the oldest first
+#[derive(Debug, Default)]
+pub struct Outbox {
+    arrivals: VecDeque<Arrival>,
+}
+
+impl Outbox {
+    /// Queue the arrival. Returns the oldest one, dropped to make room.
+    pub fn push(&mut self, arrival: Arrival) -> Option<Arrival> {
+        let dropped = match self.arrivals.len() >= OUTBOX_LEN {
+            true => self.arrivals.pop_front(),
+            false => None,
+        };
+        self.arrivals.push_back(arrival);
+        dropped
+    }
+
+    pub fn front(&self) -> Option<&Arrival> {
+        self.arrivals.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<Arrival> {
+        self.arrivals.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.arrivals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arrivals.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.arrivals.iter().flat_map(Arrival::encode).collect()
+    }
+
+    /// Skips the arrivals that do not decode
+    pub fn decode(bytes: &[u8]) -> Self {
+        let mut arrivals: VecDeque<Arrival> = bytes
+            .chunks(Arrival::ENCODED_LEN)
+            .filter_map(Arrival::decode)
+            .collect();
+        while arrivals.len() > OUTBOX_LEN {
+            arrivals.pop_front();
+        }
+        Self { arrivals }
+    }
+}
+
+/// Webhook settings, copied out of the settings blob
+#[cfg(feature = "webhook")]
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    url: String,
+    threshold_grams: f32,
+    empty_time: Duration,
+}
+
+#[cfg(feature = "webhook")]
+impl WebhookConfig {
+    /// Returns `None` when no URL is configured
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        Some(Self {
+            url: settings.webhook_url()?.to_string(),
+            threshold_grams: settings.webhook_threshold_grams(),
+            empty_time: settings.webhook_empty_time(),
+        })
+    }
+}
+
+/// JSON posted for an arrival, with the UTC `time` or, when the clock was
+/// not synchronized yet, the `uptime_ms` within its `boot`
+#[cfg(feature = "webhook")]
+fn arrival_payload(arrival: &Arrival) -> String {
+    let mut payload = json!({
+        "event": "arrival",
+        "weight_grams": arrival.grams,
+    });
+    match arrival.timestamp {
+        Timestamp::WallClock { .. } => payload["time"] = json!(arrival.timestamp.to_string()),
+        Timestamp::Uptime(uptime) => payload["uptime_ms"] = json!(uptime.as_millis() as u64),
+    }
+    device::stamp().insert_into(&mut payload);
+    payload["boot"] = json!(arrival.boot);
+    payload.to_string()
+}
+
+/// Start watching the weight for arrivals and posting them once Wi-Fi is
+/// connected, retrying with a growing backoff
+#[cfg(feature = "webhook")]
+pub fn start_webhook_task(
+    config: WebhookConfig,
+    wifi: WifiHandle,
+    events: Receiver<WeightEvent>,
+    storage: &StorageService,
+) -> anyhow::Result<()> {
+    let storage = storage.open(WEBHOOK_NAMESPACE)?;
+    let outbox = Outbox::decode(&storage.get_checked_blob(OUTBOX_KEY).unwrap_or_default());
+    if !outbox.is_empty() {
+        info!("{} webhook arrivals waiting from before", outbox.len());
+    }
+    std::thread::Builder::new()
+        .name("webhook".to_string())
+        .stack_size(WEBHOOK_TASK_STACK_SIZE)
+        .spawn(move || webhook_task(config, wifi, events, storage, outbox))?;
+    Ok(())
+}
+
+#[cfg(feature = "webhook")]
+fn webhook_task(
+    config: WebhookConfig,
+    wifi: WifiHandle,
+    events: Receiver<WeightEvent>,
+    storage: Storage,
+    mut outbox: Outbox,
+) {
+    let mut detector = ArrivalDetector::new(config.threshold_grams, config.empty_time);
+    let mut backoff = RETRY_BACKOFF_MIN;
+    let mut retry_at: Option<Instant> = None;
+    loop {
+        match events.recv_timeout(EVENT_POLL_PERIOD) {
+            Ok(event) => {
+                if let Some(grams) = detector.on_event(event, Instant::now()) {
+                    info!("Arrival of {:.1}g", grams);
+                    let arrival = Arrival {
+                        grams,
+                        timestamp: Timestamp::now(),
+                        boot: device::identity().boot(),
+                    };
+                    if let Some(dropped) = outbox.push(arrival) {
+                        warn!("Webhook outbox full, dropped {:?}", dropped);
+                    }
+                    save_outbox(&storage, &outbox);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        if wifi.state() != WifiState::Connected || retry_at.is_some_and(|at| now < at) {
+            continue;
+        }
+        let Some(arrival) = outbox.front() else {
+            continue;
+        };
+        match http_client::post_json(&config.url, &arrival_payload(arrival)) {
+            Ok(200..=299) => {
+                backoff = RETRY_BACKOFF_MIN;
+                retry_at = None;
+            }
+            // Sent again it would only be refused again
+            Ok(status @ 400..=499) if status != 408 && status != 429 => {
+                warn!(
+                    "Webhook refused the arrival with status {}, dropped",
+                    status
+                );
+            }
+            failed => {
+                match failed {
+                    Ok(status) => warn!("Webhook failed with status {}", status),
+                    Err(err) => warn!("Webhook failed: {:?}", err),
+                }
+                retry_at = Some(now + backoff);
+                backoff = (backoff * 2).min(RETRY_BACKOFF_MAX);
+                continue;
+            }
+        }
+        outbox.pop_front();
+        save_outbox(&storage, &outbox);
+    }
+}
+
+#[cfg(feature = "webhook")]
+fn save_outbox(storage: &Storage, outbox: &Outbox) {
+    if let Err(err) = storage.put_checked_blob(OUTBOX_KEY, &outbox.encode()) {
+        warn!("Failed to save the webhook outbox: {:?}", err);
+    }
+}
+
+/// Migration putting the outbox stored before the checksums into its
+/// envelope
+pub fn seal_blobs(storage: &mut Storage) -> Result<(), StoreError> {
+    storage.seal_blob(OUTBOX_KEY)
+}
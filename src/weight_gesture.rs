@@ -0,0 +1,389 @@
+//! Gestures made with the weight instead of the button, for a scale sealed
+//! in an enclosure without one. Pressing down on the platform past a large
+//! share of the capacity for a couple of seconds and letting go is a press.
+//! The presses made within a window of each other, counted once it closed,
+//! stand for the button gestures: one for a press, tare on the weight page,
+//! two for a long press, the menu, and three for a double press, the next
+//! page. The menus and the prompts thus work as with the button.
+//!
+//! The presses are told apart on the readings in grams before the tare and
+//! the zero tracking, against a rest level following the load slowly. A
+//! load past the threshold left on the platform longer than a press is no
+//! press, the rest level moves up to it.
+
+use std::time::{Duration, Instant};
+
+use crate::button::ButtonAction;
+
+pub const DEFAULT_GESTURE_THRESHOLD_PERCENT: u8 = 80;
+pub const MIN_GESTURE_THRESHOLD_PERCENT: u8 = 20;
+pub const MAX_GESTURE_THRESHOLD_PERCENT: u8 = 100;
+/// Time the weight has to stay pressed down by default
+pub const DEFAULT_GESTURE_PRESS: Duration = Duration::from_secs(2);
+/// Time after a press within which the next one counts, by default
+pub const DEFAULT_GESTURE_WINDOW: Duration = Duration::from_secs(5);
+pub const MAX_GESTURE_PRESS: Duration = Duration::from_secs(10);
+pub const MAX_GESTURE_WINDOW: Duration = Duration::from_secs(15);
+/// A press longer than this many times the press time is a load left on the
+/// platform
+const LOAD_PRESS_FACTOR: u32 = 4;
+/// Share of the threshold the weight has to fall back under to release
+const RELEASE_FRACTION: f32 = 0.5;
+/// Weight of a reading in the rest level, which follows a load placed in a
+/// couple of seconds at 10 SPS
+const REST_SMOOTHING: f32 = 0.1;
+/// Presses in a row counted at most, a double press past it
+const MAX_PRESSES: u8 = 3;
+
+/// What the scale is operated with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputMode {
+    #[default]
+    Button,
+    /// Presses on the platform, the button works along
+    Weight,
+}
+
+impl InputMode {
+    pub const ALL: [InputMode; 2] = [InputMode::Button, InputMode::Weight];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InputMode::Button => "button",
+            InputMode::Weight => "weight",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            InputMode::Button => 0,
+            InputMode::Weight => 1,
+        }
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WeightGestureConfig {
+    /// Share of the capacity in percent a press goes past
+    pub threshold_percent: u8,
+    /// Time a press holds the weight down
+    pub press: Duration,
+    /// Time after a press within which the next one counts
+    pub window: Duration,
+}
+
+impl Default for WeightGestureConfig {
+    fn default() -> Self {
+        Self {
+            threshold_percent: DEFAULT_GESTURE_THRESHOLD_PERCENT,
+            press: DEFAULT_GESTURE_PRESS,
+            window: DEFAULT_GESTURE_WINDOW,
+        }
+    }
+}
+
+/// What the gesture in progress asks of the user, for the hints
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GestureHint {
+    /// Pressed down, not long enough yet
+    Hold { left: Duration },
+    /// Pressed long enough, counted once released
+    Release,
+    /// The presses so far, the gesture they make taken once the window
+    /// closes
+    Counted { presses: u8, left: Duration },
+}
+
+#[derive(Debug, Default)]
+pub struct WeightGestureDetector {
+    config: WeightGestureConfig,
+    /// Grams a press rises above the rest level by, none while the gestures
+    /// are off
+    threshold_grams: Option<f32>,
+    /// Grams per count of the calibration, none before one
+    scale_factor: Option<f32>,
+    /// Level of the readings at rest, in grams
+    rest: Option<f32>,
+    /// When the weight went past the threshold
+    pressed_since: Option<Instant>,
+    presses: u8,
+    last_release: Option<Instant>,
+}
+
+impl WeightGestureDetector {
+    /// Detector off until configured
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tell presses apart with the config, past the share of the capacity.
+    /// None for either turns the gestures off.
+    pub fn configure(&mut self, config: Option<WeightGestureConfig>, capacity: Option<f32>) {
+        self.config = config.unwrap_or_default();
+        self.threshold_grams = config
+            .zip(capacity)
+            .map(|(config, capacity)| capacity * f32::from(config.threshold_percent) / 100.0);
+        self.reset();
+    }
+
+    /// Calibration the readings are weighed with
+    pub fn set_scale_factor(&mut self, scale_factor: Option<f32>) {
+        self.scale_factor = scale_factor;
+        self.rest = None;
+        self.reset();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold_grams.is_some() && self.scale_factor.is_some()
+    }
+
+    /// Forget the gesture in progress, e.g. the presses made before a prompt
+    pub fn reset(&mut self) {
+        self.pressed_since = None;
+        self.presses = 0;
+        self.last_release = None;
+    }
+
+    /// Take a reading in raw counts
+    pub fn on_reading(&mut self, raw: i32, now: Instant) {
+        let (Some(threshold), Some(scale_factor)) = (self.threshold_grams, self.scale_factor)
+        else {
+            return;
+        };
+        let grams = raw as f32 * scale_factor;
+        let Some(rest) = self.rest else {
+            self.rest = Some(grams);
+            return;
+        };
+        let above = grams - rest;
+        match self.pressed_since {
+            None if above > threshold => {
+                self.pressed_since = Some(now);
+            }
+            None => {
+                self.rest = Some(rest + (grams - rest) * REST_SMOOTHING);
+            }
+            Some(since) if above > threshold * RELEASE_FRACTION => {
+                // A load put down, not a press: it is the rest from now on
+                if now.duration_since(since) > self.config.press * LOAD_PRESS_FACTOR {
+                    self.pressed_since = None;
+                    self.rest = Some(grams);
+                }
+            }
+            Some(since) => {
+                self.pressed_since = None;
+                if now.duration_since(since) >= self.config.press {
+                    self.presses = (self.presses + 1).min(MAX_PRESSES);
+                    self.last_release = Some(now);
+                }
+            }
+        }
+    }
+
+    /// Gesture made by the presses once the window after the last one
+    /// closed
+    pub fn poll(&mut self, now: Instant) -> Option<ButtonAction> {
+        let released = self.last_release?;
+        if self.pressed_since.is_some() || now.duration_since(released) < self.config.window {
+            return None;
+        }
+        let action = match self.presses {
+            1 => ButtonAction::Press,
+            2 => ButtonAction::LongPress,
+            _ => ButtonAction::DoublePress,
+        };
+        self.presses = 0;
+        self.last_release = None;
+        Some(action)
+    }
+
+    /// What the gesture in progress asks for, none without one
+    pub fn hint(&self, now: Instant) -> Option<GestureHint> {
+        if let Some(since) = self.pressed_since {
+            let held = now.duration_since(since);
+            return Some(match self.config.press.checked_sub(held) {
+                Some(left) if !left.is_zero() => GestureHint::Hold { left },
+                _ => GestureHint::Release,
+            });
+        }
+        let released = self.last_release?;
+        let left = self
+            .config
+            .window
+            .saturating_sub(now.duration_since(released));
+        Some(GestureHint::Counted {
+            presses: self.presses,
+            left,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Readings every 100ms, 10 SPS
+    const TICK: Duration = Duration::from_millis(100);
+    const CAPACITY: f32 = 5000.0;
+    /// Past the default threshold of 80% of the capacity
+    const PRESS_GRAMS: i32 = 4500;
+
+    /// A calibrated scale of one gram per count, empty at first
+    struct Platform {
+        detector: WeightGestureDetector,
+        now: Instant,
+        actions: Vec<ButtonAction>,
+    }
+
+    impl Platform {
+        fn new() -> Self {
+            let mut detector = WeightGestureDetector::new();
+            detector.configure(Some(WeightGestureConfig::default()), Some(CAPACITY));
+            detector.set_scale_factor(Some(1.0));
+            let now = Instant::now();
+            detector.on_reading(0, now);
+            Self {
+                detector,
+                now,
+                actions: Vec::new(),
+            }
+        }
+
+        /// Read `grams` for `ticks` readings, taking the gestures made
+        fn read(&mut self, grams: i32, ticks: u32) {
+            for _ in 0..ticks {
+                self.now += TICK;
+                self.detector.on_reading(grams, self.now);
+                self.actions.extend(self.detector.poll(self.now));
+            }
+        }
+
+        /// Press down for 2.5s and let go for 1s
+        fn press(&mut self) {
+            self.read(PRESS_GRAMS, 25);
+            self.read(0, 10);
+        }
+
+        fn hint(&self) -> Option<GestureHint> {
+            self.detector.hint(self.now)
+        }
+    }
+
+    #[test]
+    fn placing_and_removing_a_load_is_no_gesture() {
+        let mut platform = Platform::new();
+        platform.read(3000, 100);
+        platform.read(0, 100);
+        // Even taken off and put back quickly
+        for _ in 0..5 {
+            platform.read(3000, 5);
+            platform.read(0, 5);
+        }
+        platform.read(0, 100);
+        assert_eq!(platform.actions, []);
+        assert_eq!(platform.hint(), None);
+    }
+
+    #[test]
+    fn heavy_load_left_on_is_no_press() {
+        let mut platform = Platform::new();
+        // Past the threshold for longer than four press times
+        platform.read(PRESS_GRAMS, 100);
+        assert_eq!(platform.hint(), None);
+        platform.read(PRESS_GRAMS, 100);
+        // Taken off again
+        platform.read(0, 100);
+        assert_eq!(platform.actions, []);
+        assert_eq!(platform.hint(), None);
+    }
+
+    #[test]
+    fn slow_drift_is_no_press() {
+        let mut platform = Platform::new();
+        // Up to past the threshold over five minutes and back
+        for step in (0..3000).chain((0..3000).rev()) {
+            platform.read(step * 3 / 2, 1);
+        }
+        platform.read(0, 100);
+        assert_eq!(platform.actions, []);
+        assert_eq!(platform.hint(), None);
+    }
+
+    #[test]
+    fn short_press_is_not_counted() {
+        let mut platform = Platform::new();
+        platform.read(PRESS_GRAMS, 10);
+        platform.read(0, 100);
+        assert_eq!(platform.actions, []);
+    }
+
+    #[test]
+    fn presses_make_a_gesture_once_the_window_closed() {
+        for (presses, action) in [
+            (1, ButtonAction::Press),
+            (2, ButtonAction::LongPress),
+            (3, ButtonAction::DoublePress),
+            // Counted up to three
+            (4, ButtonAction::DoublePress),
+        ] {
+            let mut platform = Platform::new();
+            for _ in 0..presses {
+                platform.press();
+            }
+            // 4s after the last release, the window still open
+            platform.read(0, 30);
+            assert_eq!(platform.actions, [], "{presses} presses");
+            platform.read(0, 20);
+            assert_eq!(platform.actions, [action], "{presses} presses");
+            platform.read(0, 100);
+            assert_eq!(platform.actions, [action], "{presses} presses");
+        }
+    }
+
+    #[test]
+    fn hint_follows_the_press() {
+        let mut platform = Platform::new();
+        assert_eq!(platform.hint(), None);
+        // Pressed down on the first of the readings
+        platform.read(PRESS_GRAMS, 5);
+        assert_eq!(
+            platform.hint(),
+            Some(GestureHint::Hold {
+                left: Duration::from_millis(1600)
+            })
+        );
+        platform.read(PRESS_GRAMS, 20);
+        assert_eq!(platform.hint(), Some(GestureHint::Release));
+        platform.read(0, 10);
+        assert_eq!(
+            platform.hint(),
+            Some(GestureHint::Counted {
+                presses: 1,
+                left: Duration::from_millis(4100)
+            })
+        );
+        platform.read(0, 41);
+        assert_eq!(platform.actions, [ButtonAction::Press]);
+        assert_eq!(platform.hint(), None);
+    }
+
+    #[test]
+    fn reset_forgets_the_presses() {
+        let mut platform = Platform::new();
+        platform.press();
+        platform.detector.reset();
+        assert_eq!(platform.hint(), None);
+        platform.read(0, 100);
+        assert_eq!(platform.actions, []);
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use esp_idf_hal::modem::Modem;
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    http::{server::EspHttpServer, Method},
+    io::{Read, Write},
+    nvs::EspDefaultNvsPartition,
+    wifi::{
+        AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, Configuration,
+        EspWifi,
+    },
+};
+use log::{info, warn};
+
+use crate::{
+    command_channel::{Command, CommandSender},
+    settings::Settings,
+};
+
+/// Name of the open access point used for entering the credentials
+const SETUP_AP_SSID: &str = "esp32-scale-setup";
+
+const WIFI_TASK_STACK_SIZE: usize = 8 * 1024;
+/// Period the connection is checked at while connected
+const CONNECTION_CHECK_PERIOD: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(2);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(120);
+const SETUP_FORM_MAX_LEN: usize = 256;
+
+const SETUP_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width"><title>Scale setup</title></head>
+<body><h1>Scale Wi-Fi setup</h1>
+<form method="post" action="/">
+<p><label>Network <input name="ssid" maxlength="32" required></label></p>
+<p><label>Password <input name="password" type="password" maxlength="64"></label></p>
+<p><button type="submit">Connect</button></p>
+</form></body></html>"#;
+
+const SETUP_DONE_PAGE: &str = "<!DOCTYPE html><html><body><p>Saved, the scale is connecting to the network.</p></body></html>";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WifiState {
+    Connecting,
+    Connected,
+    /// Waiting before the next connection attempt
+    Disconnected,
+    /// The setup access point is open
+    Provisioning,
+}
+
+impl WifiState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            1 => WifiState::Connected,
+            2 => WifiState::Disconnected,
+            3 => WifiState::Provisioning,
+            _ => WifiState::Connecting,
+        }
+    }
+}
+
+enum WifiRequest {
+    /// Open the setup access point
+    Provision,
+    /// Connect to a network with new credentials
+    Connect { ssid: String, password: String },
+}
+
+/// What the Wi-Fi task is currently trying to do
+enum Mode {
+    Station { ssid: String, password: String },
+    Provisioning,
+}
+
+/// Handle to the task managing the Wi-Fi connection
+#[derive(Clone)]
+pub struct WifiHandle {
+    state: Arc<AtomicU8>,
+    requests: Sender<WifiRequest>,
+}
+
+impl WifiHandle {
+    pub fn state(&self) -> WifiState {
+        WifiState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    /// Open the setup access point to enter new credentials
+    pub fn start_provisioning(&self) {
+        let _ = self.requests.send(WifiRequest::Provision);
+    }
+
+    /// Connect to another network, e.g. after the credentials were changed
+    pub fn connect(&self, ssid: &str, password: &str) {
+        let _ = self.requests.send(WifiRequest::Connect {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+        });
+    }
+}
+
+/// Start the task managing the Wi-Fi connection. Without stored credentials
+/// the setup access point is opened right away. Credentials entered there are
+/// sent as a console command, so they are persisted like any other setting.
+pub fn start_wifi_task(
+    modem: Modem,
+    sysloop: EspSystemEventLoop,
+    nvs_default_partition: EspDefaultNvsPartition,
+    settings: &Settings,
+    commands: CommandSender,
+) -> anyhow::Result<WifiHandle> {
+    let wifi = BlockingWifi::wrap(
+        EspWifi::new(modem, sysloop.clone(), Some(nvs_default_partition))?,
+        sysloop,
+    )?;
+
+    let mode = if settings.wifi_ssid().is_empty() {
+        info!("No Wi-Fi network configured");
+        Mode::Provisioning
+    } else {
+        Mode::Station {
+            ssid: settings.wifi_ssid().to_string(),
+            password: settings.wifi_password().to_string(),
+        }
+    };
+
+    let state = Arc::new(AtomicU8::new(WifiState::Connecting as u8));
+    let (requests_tx, requests_rx) = channel();
+    let task_state = state.clone();
+    std::thread::Builder::new()
+        .name("wifi".to_string())
+        .stack_size(WIFI_TASK_STACK_SIZE)
+        .spawn(move || wifi_task(wifi, mode, requests_rx, task_state, commands))?;
+
+    Ok(WifiHandle {
+        state,
+        requests: requests_tx,
+    })
+}
+
+fn wifi_task(
+    mut wifi: BlockingWifi<EspWifi<'static>>,
+    mut mode: Mode,
+    requests: Receiver<WifiRequest>,
+    state: Arc<AtomicU8>,
+    commands: CommandSender,
+) {
+    let set_state = |new_state: WifiState| state.store(new_state as u8, Ordering::Relaxed);
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+
+    loop {
+        // Each step runs until a request or a lost connection changes the mode
+        let request = match &mode {
+            Mode::Station { ssid, password } => {
+                set_state(WifiState::Connecting);
+                match connect(&mut wifi, ssid, password) {
+                    Ok(()) => {
+                        info!("Connected to Wi-Fi network {}", ssid);
+                        set_state(WifiState::Connected);
+                        backoff = RECONNECT_BACKOFF_MIN;
+                        wait_for_disconnect(&wifi, &requests)
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Wi-Fi connection failed, retrying in {}s: {:?}",
+                            backoff.as_secs(),
+                            err
+                        );
+                        set_state(WifiState::Disconnected);
+                        let request = requests.recv_timeout(backoff).ok();
+                        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                        request
+                    }
+                }
+            }
+            Mode::Provisioning => {
+                set_state(WifiState::Provisioning);
+                match provision(&mut wifi, &requests, &commands) {
+                    Ok(request) => Some(request),
+                    Err(err) => {
+                        warn!("Wi-Fi setup failed: {:?}", err);
+                        set_state(WifiState::Disconnected);
+                        requests.recv_timeout(backoff).ok()
+                    }
+                }
+            }
+        };
+
+        match request {
+            Some(WifiRequest::Provision) => mode = Mode::Provisioning,
+            Some(WifiRequest::Connect { ssid, password }) => {
+                backoff = RECONNECT_BACKOFF_MIN;
+                mode = Mode::Station { ssid, password };
+            }
+            None => {}
+        }
+    }
+}
+
+fn connect(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    ssid: &str,
+    password: &str,
+) -> anyhow::Result<()> {
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: ssid
+            .try_into()
+            .map_err(|_| anyhow!("Wi-Fi SSID is too long"))?,
+        password: password
+            .try_into()
+            .map_err(|_| anyhow!("Wi-Fi password is too long"))?,
+        auth_method: if password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.connect()?;
+    wifi.wait_netif_up()?;
+    Ok(())
+}
+
+/// Block while connected. Returns the request that interrupted the
+/// connection, or `None` when it was lost.
+fn wait_for_disconnect(
+    wifi: &BlockingWifi<EspWifi<'static>>,
+    requests: &Receiver<WifiRequest>,
+) -> Option<WifiRequest> {
+    loop {
+        match requests.recv_timeout(CONNECTION_CHECK_PERIOD) {
+            Ok(request) => return Some(request),
+            Err(RecvTimeoutError::Timeout) => {
+                if !wifi.is_connected().unwrap_or(false) {
+                    warn!("Lost Wi-Fi connection");
+                    return None;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+/// Open the setup access point and serve the credentials form until a
+/// request arrives, usually the `Connect` following a submitted form
+fn provision(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    requests: &Receiver<WifiRequest>,
+    commands: &CommandSender,
+) -> anyhow::Result<WifiRequest> {
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: SETUP_AP_SSID
+            .try_into()
+            .map_err(|_| anyhow!("Setup SSID is too long"))?,
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    info!(
+        "Wi-Fi setup access point {} is open at http://{}",
+        SETUP_AP_SSID,
+        wifi.wifi().ap_netif().get_ip_info()?.ip
+    );
+
+    let mut server = EspHttpServer::new(&Default::default())?;
+    server.fn_handler("/", Method::Get, |request| -> anyhow::Result<()> {
+        request
+            .into_ok_response()?
+            .write_all(SETUP_PAGE.as_bytes())?;
+        Ok(())
+    })?;
+    let commands = commands.clone();
+    server.fn_handler(
+        "/",
+        Method::Post,
+        move |mut request| -> anyhow::Result<()> {
+            let mut body = [0u8; SETUP_FORM_MAX_LEN];
+            let mut len = 0;
+            while len < body.len() {
+                match request.read(&mut body[len..])? {
+                    0 => break,
+                    read => len += read,
+                }
+            }
+            let Some((ssid, password)) = parse_setup_form(&String::from_utf8_lossy(&body[..len]))
+            else {
+                request
+                    .into_status_response(400)?
+                    .write_all(b"Missing network name")?;
+                return Ok(());
+            };
+
+            commands.send(Command::SetWifi { ssid, password })?;
+            request
+                .into_ok_response()?
+                .write_all(SETUP_DONE_PAGE.as_bytes())?;
+            Ok(())
+        },
+    )?;
+
+    // The server is stopped when dropped on return
+    requests.recv().map_err(|_| anyhow!("Wi-Fi handle dropped"))
+}
+
+/// Extract the credentials from the url encoded setup form
+fn parse_setup_form(body: &str) -> Option<(String, String)> {
+    let mut ssid = None;
+    let mut password = String::new();
+    for pair in body.split('&') {
+        let (key, value) = pair.split_once('=')?;
+        match key {
+            "ssid" => ssid = Some(url_decode(value)?),
+            "password" => password = url_decode(value)?,
+            _ => {}
+        }
+    }
+    ssid.filter(|ssid| !ssid.is_empty())
+        .map(|ssid| (ssid, password))
+}
+
+fn url_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
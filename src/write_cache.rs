@@ -0,0 +1,337 @@
+//! Write-back cache in front of a key-value store such as NVS. Writes only
+//! land in the cache and are written out together once the first of them is
+//! a window old, or on an explicit flush, so a key set several times in a row
+//! is written once. Reads go to the store only the first time a key is seen,
+//! later ones are answered from the cache.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    U8(u8),
+    U32(u32),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Value::U8(_) => ValueKind::U8,
+            Value::U32(_) => ValueKind::U32,
+            Value::Blob(_) => ValueKind::Blob,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueKind {
+    U8,
+    U32,
+    Blob,
+}
+
+/// The store behind the cache
+pub trait Backend {
+    type Error;
+
+    fn read(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        kind: ValueKind,
+    ) -> Result<Option<Value>, Self::Error>;
+
+    /// Write the value, or remove the key when there is none
+    fn write(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        value: Option<&Value>,
+    ) -> Result<(), Self::Error>;
+
+    fn contains(&mut self, namespace: &'static str, key: &str) -> Result<bool, Self::Error>;
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// `None` when the key is missing or removed
+    value: Option<Value>,
+    /// Whether the value still has to be written
+    dirty: bool,
+}
+
+pub struct WriteCache<B> {
+    backend: B,
+    entries: BTreeMap<(&'static str, String), Entry>,
+    /// Time a write waits for others to join it
+    window: Duration,
+    /// Time of the oldest write not flushed yet
+    oldest_dirty: Option<Instant>,
+}
+
+impl<B: Backend> WriteCache<B> {
+    pub fn new(backend: B, window: Duration) -> Self {
+        Self {
+            backend,
+            entries: BTreeMap::new(),
+            window,
+            oldest_dirty: None,
+        }
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// The value of the key, if it holds one of that kind
+    pub fn get(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        kind: ValueKind,
+    ) -> Result<Option<Value>, B::Error> {
+        if let Some(entry) = self.entries.get(&(namespace, key.to_string())) {
+            // A key holding another kind is left to the backend to answer
+            match &entry.value {
+                Some(value) if value.kind() != kind => {}
+                value => return Ok(value.clone()),
+            }
+        }
+        let value = self.backend.read(namespace, key, kind)?;
+        let entry = self
+            .entries
+            .entry((namespace, key.to_string()))
+            .or_insert(Entry {
+                value: None,
+                dirty: false,
+            });
+        // Never overwrite a write that was not flushed yet
+        if !entry.dirty {
+            entry.value = value.clone();
+        }
+        Ok(value)
+    }
+
+    pub fn set(&mut self, namespace: &'static str, key: &str, value: Value, now: Instant) {
+        self.queue(namespace, key, Some(value), now);
+    }
+
+    /// Returns whether the key was there
+    pub fn remove(
+        &mut self,
+        namespace: &'static str,
+        key: &str,
+        now: Instant,
+    ) -> Result<bool, B::Error> {
+        let present = match self.entries.get(&(namespace, key.to_string())) {
+            Some(entry) => entry.value.is_some(),
+            None => self.backend.contains(namespace, key)?,
+        };
+        self.queue(namespace, key, None, now);
+        Ok(present)
+    }
+
+    fn queue(&mut self, namespace: &'static str, key: &str, value: Option<Value>, now: Instant) {
+        self.entries
+            .insert((namespace, key.to_string()), Entry { value, dirty: true });
+        self.oldest_dirty.get_or_insert(now);
+    }
+
+    /// Number of keys waiting to be written
+    pub fn pending(&self) -> usize {
+        self.entries.values().filter(|entry| entry.dirty).count()
+    }
+
+    /// Whether the oldest pending write waited for the whole window
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.oldest_dirty
+            .is_some_and(|oldest| now.duration_since(oldest) >= self.window)
+    }
+
+    /// Write out the pending keys. Returns how many were written, a key that
+    /// failed stays pending along with the ones after it.
+    pub fn flush(&mut self) -> Result<usize, B::Error> {
+        let mut written = 0;
+        for ((namespace, key), entry) in &mut self.entries {
+            if !entry.dirty {
+                continue;
+            }
+            self.backend.write(namespace, key, entry.value.as_ref())?;
+            entry.dirty = false;
+            written += 1;
+        }
+        self.oldest_dirty = None;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NS: &str = "test";
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    /// Store in memory, recording what reaches it
+    #[derive(Default)]
+    struct Memory {
+        stored: BTreeMap<String, Value>,
+        reads: usize,
+        writes: Vec<(String, Option<Value>)>,
+        failing: bool,
+    }
+
+    impl Backend for Memory {
+        type Error = ();
+
+        fn read(
+            &mut self,
+            _namespace: &'static str,
+            key: &str,
+            kind: ValueKind,
+        ) -> Result<Option<Value>, ()> {
+            self.reads += 1;
+            Ok(self
+                .stored
+                .get(key)
+                .filter(|value| value.kind() == kind)
+                .cloned())
+        }
+
+        fn write(
+            &mut self,
+            _namespace: &'static str,
+            key: &str,
+            value: Option<&Value>,
+        ) -> Result<(), ()> {
+            if self.failing {
+                return Err(());
+            }
+            self.writes.push((key.to_string(), value.cloned()));
+            match value {
+                Some(value) => self.stored.insert(key.to_string(), value.clone()),
+                None => self.stored.remove(key),
+            };
+            Ok(())
+        }
+
+        fn contains(&mut self, _namespace: &'static str, key: &str) -> Result<bool, ()> {
+            Ok(self.stored.contains_key(key))
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn writes_within_the_window_are_coalesced() {
+        let start = Instant::now();
+        let mut cache = WriteCache::new(Memory::default(), WINDOW);
+        for (at, value) in [(0, 1), (1, 2), (3, 3)] {
+            cache.set(NS, "tare", Value::U32(value), start + secs(at));
+        }
+        cache.set(NS, "unit", Value::U8(1), start + secs(4));
+        assert_eq!(cache.pending(), 2);
+        assert!(cache.backend_mut().writes.is_empty());
+
+        assert_eq!(cache.flush(), Ok(2));
+        assert_eq!(
+            cache.backend_mut().writes,
+            [
+                ("tare".to_string(), Some(Value::U32(3))),
+                ("unit".to_string(), Some(Value::U8(1))),
+            ]
+        );
+        assert_eq!(cache.pending(), 0);
+        assert_eq!(cache.flush(), Ok(0));
+    }
+
+    #[test]
+    fn due_once_the_oldest_write_is_a_window_old() {
+        let start = Instant::now();
+        let mut cache = WriteCache::new(Memory::default(), WINDOW);
+        assert!(!cache.is_due(start + secs(60)));
+
+        cache.set(NS, "tare", Value::U32(1), start);
+        // A later write does not push the deadline back
+        cache.set(NS, "tare", Value::U32(2), start + secs(4));
+        assert!(!cache.is_due(start + secs(4)));
+        assert!(cache.is_due(start + WINDOW));
+
+        cache.flush().unwrap();
+        assert!(!cache.is_due(start + secs(60)));
+        // The window starts again with the next write
+        cache.set(NS, "tare", Value::U32(3), start + secs(60));
+        assert!(!cache.is_due(start + secs(64)));
+        assert!(cache.is_due(start + secs(65)));
+    }
+
+    #[test]
+    fn a_failed_flush_keeps_the_writes() {
+        let start = Instant::now();
+        let mut cache = WriteCache::new(Memory::default(), WINDOW);
+        cache.set(NS, "tare", Value::U32(1), start);
+        cache.backend_mut().failing = true;
+        assert_eq!(cache.flush(), Err(()));
+        assert_eq!(cache.pending(), 1);
+        assert!(cache.is_due(start + WINDOW));
+
+        cache.backend_mut().failing = false;
+        assert_eq!(cache.flush(), Ok(1));
+        assert_eq!(cache.backend_mut().stored["tare"], Value::U32(1));
+    }
+
+    #[test]
+    fn reads_go_through_the_pending_writes() {
+        let start = Instant::now();
+        let mut memory = Memory::default();
+        memory.stored.insert("tare".to_string(), Value::U32(7));
+        let mut cache = WriteCache::new(memory, WINDOW);
+
+        // Read from the store once, then from the cache
+        assert_eq!(
+            cache.get(NS, "tare", ValueKind::U32),
+            Ok(Some(Value::U32(7)))
+        );
+        assert_eq!(
+            cache.get(NS, "tare", ValueKind::U32),
+            Ok(Some(Value::U32(7)))
+        );
+        assert_eq!(cache.backend_mut().reads, 1);
+
+        cache.set(NS, "tare", Value::U32(8), start);
+        assert_eq!(
+            cache.get(NS, "tare", ValueKind::U32),
+            Ok(Some(Value::U32(8)))
+        );
+        assert_eq!(cache.remove(NS, "tare", start), Ok(true));
+        assert_eq!(cache.get(NS, "tare", ValueKind::U32), Ok(None));
+        assert_eq!(cache.remove(NS, "tare", start), Ok(false));
+        assert_eq!(cache.backend_mut().reads, 1);
+        assert_eq!(cache.backend_mut().stored["tare"], Value::U32(7));
+
+        cache.flush().unwrap();
+        assert!(cache.backend_mut().stored.is_empty());
+    }
+
+    #[test]
+    fn a_first_read_does_not_undo_a_pending_write() {
+        let start = Instant::now();
+        let mut memory = Memory::default();
+        memory.stored.insert("unit".to_string(), Value::U8(0));
+        let mut cache = WriteCache::new(memory, WINDOW);
+
+        cache.set(NS, "unit", Value::Blob(vec![1]), start);
+        // Asked for another kind, the store answers but the write stays
+        assert_eq!(cache.get(NS, "unit", ValueKind::U8), Ok(Some(Value::U8(0))));
+        assert_eq!(
+            cache.get(NS, "unit", ValueKind::Blob),
+            Ok(Some(Value::Blob(vec![1])))
+        );
+        assert_eq!(cache.pending(), 1);
+    }
+}